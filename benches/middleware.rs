@@ -0,0 +1,53 @@
+//! Middleware cost benchmarks
+//!
+//! Run with `cargo bench --bench middleware`. Everything is in-process, so no
+//! network access is needed.
+
+use axum::body::Body;
+use axum::http::Request;
+use axum::Router;
+use cloudflare_tunnel_example::config::SecurityConfig;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::AppBuilder;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tower::util::ServiceExt;
+
+async fn health(app: Router) {
+    let request = Request::builder()
+        .uri("/health")
+        .body(Body::empty())
+        .expect("Failed to build request");
+    let response = app.oneshot(request).await.expect("Failed to get response");
+    axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .expect("Failed to read response body");
+}
+
+fn router(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start runtime");
+    let full = AppBuilder::new(AppState::default()).build();
+    let baseline = AppBuilder::new(AppState::default())
+        .security_headers(false)
+        .build();
+
+    let mut group = c.benchmark_group("health");
+    group.bench_function("full_stack", |b| {
+        b.to_async(&runtime).iter(|| health(full.clone()))
+    });
+    group.bench_function("without_security_headers", |b| {
+        b.to_async(&runtime).iter(|| health(baseline.clone()))
+    });
+    group.finish();
+}
+
+fn security_config(c: &mut Criterion) {
+    let config = SecurityConfig::default();
+
+    c.bench_function("to_headers", |b| b.iter(|| black_box(&config).to_headers()));
+    c.bench_function("csp_header_value", |b| {
+        b.iter(|| black_box(&config).csp_header_value())
+    });
+}
+
+criterion_group!(benches, router, security_config);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+//! Embeds the short git commit as `GIT_SHA` for `X-Build-Id`, and
+//! precompresses the embedded assets.
+//!
+//! A `GIT_SHA` variable set for the build wins (Docker builds have no
+//! `.git`); otherwise `git rev-parse` is asked, and `unknown` is used when
+//! that fails.
+//!
+//! Each file in [`ASSETS`] is written to `OUT_DIR` gzip-compressed
+//! (`<name>.gz`) and Brotli-compressed (`<name>.br`), for `src/assets.rs`
+//! to embed next to the original.
+
+#[path = "build/compress.rs"]
+mod compress;
+
+use std::path::Path;
+use std::process::Command;
+
+/// Files under `assets/` served precompressed
+const ASSETS: &[&str] = &["favicon.ico"];
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+
+    let sha = std::env::var("GIT_SHA")
+        .ok()
+        .filter(|sha| !sha.trim().is_empty())
+        .or_else(|| {
+            let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+            output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=GIT_SHA={}", sha.trim());
+
+    compress_assets();
+}
+
+fn compress_assets() {
+    println!("cargo:rerun-if-changed=build/compress.rs");
+    let out_dir = std::env::var("OUT_DIR").expect("cargo sets OUT_DIR");
+    for name in ASSETS {
+        let source = Path::new("assets").join(name);
+        println!("cargo:rerun-if-changed={}", source.display());
+        let data = std::fs::read(&source).unwrap_or_else(|e| panic!("Failed to read {}: {}", source.display(), e));
+        for (extension, compressed) in [("gz", compress::gzip(&data)), ("br", compress::brotli(&data))] {
+            let target = Path::new(&out_dir).join(format!("{}.{}", name, extension));
+            std::fs::write(&target, compressed).unwrap_or_else(|e| panic!("Failed to write {}: {}", target.display(), e));
+        }
+    }
+}
@@ -0,0 +1,18 @@
+//! gzip and Brotli encoders for the embedded assets, at the highest
+//! compression levels since they run once per build.
+
+use std::io::{Read, Write};
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data).expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+pub fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    brotli::CompressorReader::new(data, 4096, 11, 22)
+        .read_to_end(&mut compressed)
+        .expect("reading from a slice cannot fail");
+    compressed
+}
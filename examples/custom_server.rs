@@ -0,0 +1,37 @@
+//! Embedding the service with a custom configuration.
+//!
+//! Builds the router from code instead of environment variables, relaxes a
+//! couple of security headers, enables the debug routes and serves on
+//! `127.0.0.1:3000`:
+//!
+//! ```sh
+//! cargo run --example custom_server --features debug-endpoints
+//! curl -i http://127.0.0.1:3000/whoami
+//! ```
+use cloudflare_tunnel_example::config::{AppConfig, SecurityConfig};
+use cloudflare_tunnel_example::state::AppState;
+use std::net::SocketAddr;
+
+#[tokio::main]
+async fn main() -> cloudflare_tunnel_example::Result<()> {
+    tracing_subscriber::fmt().init();
+
+    let mut security = SecurityConfig {
+        frame_options: "SAMEORIGIN".to_string(),
+        server_header: "custom-server".to_string(),
+        ..SecurityConfig::default()
+    };
+    security.hsts.preload = false;
+
+    let config = AppConfig {
+        security,
+        debug_endpoints: true,
+        ..AppConfig::default()
+    };
+
+    let app = cloudflare_tunnel_example::create_app(AppState::new(config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+    Ok(())
+}
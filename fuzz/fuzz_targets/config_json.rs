@@ -0,0 +1,35 @@
+//! Fuzz the JSON configuration parsers (`SecurityConfig` documents and
+//! `CACHE_POLICIES`): parsing must never panic, and anything that passes
+//! validation must only produce valid header values.
+//!
+//! ```sh
+//! cargo +nightly fuzz run config_json
+//! ```
+#![no_main]
+
+use axum::http::HeaderValue;
+use cloudflare_tunnel_example::cache::{CacheConfig, CacheRule};
+use cloudflare_tunnel_example::config::SecurityConfig;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(config) = serde_json::from_slice::<SecurityConfig>(data) {
+        if config.validate().is_ok() {
+            for (name, value) in config.to_headers() {
+                assert!(HeaderValue::from_str(&value).is_ok(), "{} emitted {:?}", name, value);
+            }
+        }
+    }
+
+    if let Ok(rules) = serde_json::from_slice::<Vec<CacheRule>>(data) {
+        let config = CacheConfig { rules };
+        if config.validate().is_ok() {
+            for rule in &config.rules {
+                if let Some(headers) = config.headers_for(&rule.prefix) {
+                    assert!(HeaderValue::from_str(&headers.cache_control).is_ok());
+                    assert!(HeaderValue::from_str(&headers.cdn_cache_control).is_ok());
+                }
+            }
+        }
+    }
+});
@@ -0,0 +1,20 @@
+# German
+homepage_heading = Hallo Welt
+homepage_tagline = Cloudflare-Tunnel-Beispiel - Rust-Axum-Dienst
+homepage_service = Dienst
+homepage_uptime = Laufzeit
+homepage_host = Host
+homepage_country = Land
+homepage_tunnel = Tunnel
+homepage_tunnel_starting = wird gestartet
+homepage_debug_endpoints = Debug-Endpunkte
+
+error_404_title = Nicht gefunden
+error_404_heading = Seite nicht gefunden
+error_404_text = Unter dieser Adresse gibt es nichts.
+error_500_title = Interner Serverfehler
+error_500_heading = Etwas ist schiefgelaufen
+error_500_text = Der Server konnte die Anfrage nicht abschließen.
+error_503_title = Dienst nicht verfügbar
+error_503_heading = Dienst nicht verfügbar
+error_503_text = Der Dienst ist vorübergehend nicht verfügbar. Bitte versuchen Sie es in Kürze erneut.
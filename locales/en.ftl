@@ -0,0 +1,21 @@
+# English, the default locale: every key must be here, and other catalogs
+# fall back to it for keys they lack
+homepage_heading = Hello World
+homepage_tagline = Cloudflare Tunnel Example - Rust Axum Service
+homepage_service = Service
+homepage_uptime = Uptime
+homepage_host = Host
+homepage_country = Country
+homepage_tunnel = Tunnel
+homepage_tunnel_starting = starting
+homepage_debug_endpoints = Debug endpoints
+
+error_404_title = Not Found
+error_404_heading = Page not found
+error_404_text = There is nothing at this address.
+error_500_title = Internal Server Error
+error_500_heading = Something went wrong
+error_500_text = The server could not complete the request.
+error_503_title = Service Unavailable
+error_503_heading = Service unavailable
+error_503_text = The service is temporarily unavailable. Please try again shortly.
@@ -0,0 +1,250 @@
+/*!
+ * Cloudflare Access identities
+ *
+ * Access puts a signed JWT in `Cf-Access-Jwt-Assertion` on every request it
+ * lets through. With `ACCESS_TEAM_DOMAIN` (e.g. `acme.cloudflareaccess.com`)
+ * and `ACCESS_AUD` (the application's audience tag) set, [`verify`] checks
+ * that token against the team's signing keys: RS256, the configured
+ * audience, the team as issuer and an unexpired `exp`. A request whose token
+ * passes carries an [`AccessIdentity`] extension with the email (or, for a
+ * service token, the common name) from its claims.
+ *
+ * Nothing else is trusted: `Cf-Access-Authenticated-User-Email` and an
+ * invalid or unverifiable token are ignored, so the request simply has no
+ * Access identity. Verification never rejects a request; routes that need
+ * authentication still check their own credentials.
+ *
+ * Keys come from `ACCESS_CERTS_URL` (default
+ * `https://<team>/cdn-cgi/access/certs`) through the shared [`HttpClient`].
+ * They are fetched on first use and again after [`KEYS_MAX_AGE`], or when a
+ * token names a key that is not known yet, at most once per
+ * [`MIN_REFRESH_INTERVAL`].
+ */
+use crate::http_client::HttpClient;
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, Method},
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// Header Cloudflare Access puts its signed token in
+pub const ACCESS_JWT: HeaderName = HeaderName::from_static("cf-access-jwt-assertion");
+
+/// How long fetched keys are used before fetching them again
+pub const KEYS_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Least time between fetches prompted by an unknown key ID
+pub const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The Access application whose tokens are trusted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessConfig {
+    /// Team domain, e.g. `acme.cloudflareaccess.com`; the tokens' issuer
+    pub team_domain: String,
+
+    /// Application audience (AUD) tag
+    pub audience: String,
+
+    /// Where the team's signing keys are published
+    pub certs_url: String,
+}
+
+impl AccessConfig {
+    /// Configuration for `team_domain` with the default certs URL
+    pub fn new(team_domain: impl Into<String>, audience: impl Into<String>) -> Self {
+        let team_domain = team_domain.into();
+        Self {
+            certs_url: format!("https://{}/cdn-cgi/access/certs", team_domain),
+            team_domain,
+            audience: audience.into(),
+        }
+    }
+
+    /// Load from `ACCESS_TEAM_DOMAIN`, `ACCESS_AUD` and `ACCESS_CERTS_URL`;
+    /// `None` unless both of the first two are set
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let var = |name: &str| std::env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let (team_domain, audience) = match (var("ACCESS_TEAM_DOMAIN"), var("ACCESS_AUD")) {
+            (Some(team_domain), Some(audience)) => (team_domain, audience),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(crate::ServerError::ConfigError(
+                    "ACCESS_TEAM_DOMAIN and ACCESS_AUD must be set together".to_string(),
+                ))
+            }
+        };
+        if team_domain.contains('/') {
+            return Err(crate::ServerError::ConfigError(format!(
+                "Invalid ACCESS_TEAM_DOMAIN {:?}: expected a host name such as acme.cloudflareaccess.com",
+                team_domain
+            )));
+        }
+
+        let mut config = Self::new(team_domain, audience);
+        if let Some(url) = var("ACCESS_CERTS_URL") {
+            config.certs_url = url;
+        }
+        crate::http_client::require_reachable("ACCESS_CERTS_URL", &config.certs_url)?;
+        Ok(Some(config))
+    }
+
+    /// The `iss` claim Access sets
+    fn issuer(&self) -> String {
+        format!("https://{}", self.team_domain)
+    }
+}
+
+/// Who Cloudflare Access authenticated, from a verified token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessIdentity {
+    /// User email, or the common name of a service token
+    pub name: String,
+}
+
+/// Why a token was not accepted
+#[derive(Debug, Error)]
+pub enum AccessError {
+    #[error("Invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+    #[error("Token is signed by unknown key {0:?}")]
+    UnknownKey(Option<String>),
+    #[error("Token names no user or service")]
+    NoSubject,
+    #[error("Failed to fetch signing keys: {0}")]
+    Keys(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    email: Option<String>,
+    common_name: Option<String>,
+}
+
+/// The certs document, as far as it is used
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kid: String,
+    kty: String,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+/// Signing keys by ID, as last fetched
+struct KeySet {
+    keys: HashMap<String, DecodingKey>,
+    fetched: Instant,
+}
+
+/// Verifies Access tokens; clones share the fetched keys
+#[derive(Clone)]
+pub struct AccessVerifier {
+    config: Arc<AccessConfig>,
+    http: HttpClient,
+    keys: Arc<Mutex<Option<KeySet>>>,
+}
+
+impl std::fmt::Debug for AccessVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessVerifier").field("config", &self.config).finish()
+    }
+}
+
+impl AccessVerifier {
+    pub fn new(config: AccessConfig, http: &HttpClient) -> Self {
+        Self { config: Arc::new(config), http: http.clone(), keys: Arc::default() }
+    }
+
+    /// The identity `token` proves, if it is valid for the configured
+    /// application
+    pub async fn verify(&self, token: &str) -> Result<AccessIdentity, AccessError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = self.key(header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[&self.config.audience]);
+        validation.set_issuer(&[self.config.issuer()]);
+        validation.set_required_spec_claims(&["exp", "aud", "iss"]);
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)?.claims;
+
+        claims
+            .email
+            .or(claims.common_name)
+            .filter(|name| !name.is_empty())
+            .map(|name| AccessIdentity { name })
+            .ok_or(AccessError::NoSubject)
+    }
+
+    /// The key `kid` names, fetching the keys when they are stale or do not
+    /// have it yet
+    async fn key(&self, kid: Option<&str>) -> Result<DecodingKey, AccessError> {
+        let unknown = || AccessError::UnknownKey(kid.map(str::to_string));
+        let kid = kid.ok_or_else(unknown)?;
+        let mut keys = self.keys.lock().await;
+
+        let fresh = keys.as_ref().is_some_and(|set| set.fetched.elapsed() < KEYS_MAX_AGE);
+        let known = keys.as_ref().is_some_and(|set| set.keys.contains_key(kid));
+        let may_refresh = keys.as_ref().is_none_or(|set| set.fetched.elapsed() >= MIN_REFRESH_INTERVAL);
+        if !fresh || (!known && may_refresh) {
+            match self.fetch().await {
+                Ok(fetched) => *keys = Some(KeySet { keys: fetched, fetched: Instant::now() }),
+                // Stale keys are better than none while the endpoint is down
+                Err(e) if keys.is_some() => warn!("Keeping Cloudflare Access keys: {}", e),
+                Err(e) => return Err(e),
+            }
+        }
+        keys.as_ref().and_then(|set| set.keys.get(kid)).cloned().ok_or_else(unknown)
+    }
+
+    async fn fetch(&self) -> Result<HashMap<String, DecodingKey>, AccessError> {
+        let response = self
+            .http
+            .send(Method::GET, &self.config.certs_url, Default::default(), Bytes::new())
+            .await
+            .map_err(|e| AccessError::Keys(e.to_string()))?;
+        if !response.status.is_success() {
+            return Err(AccessError::Keys(format!("certs endpoint answered {}", response.status)));
+        }
+        let set: JwkSet = serde_json::from_slice(&response.body).map_err(|e| AccessError::Keys(e.to_string()))?;
+
+        let mut keys = HashMap::new();
+        for jwk in set.keys.into_iter().filter(|jwk| jwk.kty == "RSA") {
+            if let (Some(n), Some(e)) = (&jwk.n, &jwk.e) {
+                let key = DecodingKey::from_rsa_components(n, e).map_err(|e| AccessError::Keys(e.to_string()))?;
+                keys.insert(jwk.kid, key);
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Middleware attaching the [`AccessIdentity`] a valid
+/// `Cf-Access-Jwt-Assertion` proves; a no-op without `ACCESS_TEAM_DOMAIN`
+pub async fn verify(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    request.extensions_mut().remove::<AccessIdentity>();
+    let token = request.headers().get(ACCESS_JWT).and_then(|v| v.to_str().ok()).map(str::trim);
+    if let (Some(verifier), Some(token)) = (&state.access, token) {
+        match verifier.verify(token).await {
+            Ok(identity) => {
+                request.extensions_mut().insert(identity);
+            }
+            Err(e) => warn!("Ignoring Cloudflare Access token: {}", e),
+        }
+    }
+    next.run(request).await
+}
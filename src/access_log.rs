@@ -0,0 +1,353 @@
+/*!
+ * Access log file with size-based rotation
+ *
+ * With `ACCESS_LOG_PATH` set, every request on the main listener is written
+ * to that file as one JSON object per line (time, method, URI, status,
+ * duration, request ID, client IP, user agent, Cloudflare colo, response
+ * length, and the proxy chain walked for the client IP when
+ * `TRUSTED_PROXY_*` is set). The events are emitted under the `access`
+ * tracing target, which only [`AccessLogSink::layer`] handles; `main` keeps
+ * that target out of the application log on stdout, and the sink ignores
+ * every other target.
+ *
+ * Lines are handed to a `tracing-appender` writer thread, so requests never
+ * wait on the disk. Before a line would take the file past
+ * `ACCESS_LOG_MAX_SIZE_MB` (default 100), the file is rotated: `access.log`
+ * becomes `access.log.1`, `.1` becomes `.2` and so on, keeping
+ * `ACCESS_LOG_KEEP` (default 5) rotated files; `tracing-appender` only
+ * rotates by time, so this module rotates by size. Only whole lines are
+ * ever written, so no line is split across files or interleaved with
+ * another. If the writer falls more than [`QUEUE_CAPACITY`] lines behind,
+ * further lines are dropped and counted rather than blocking requests.
+ */
+use crate::client_ip::{ClientIp, ProxyChain};
+use crate::canary::Assignments;
+use crate::cloudflare::meta::CloudflareMeta;
+use crate::request_id::RequestId;
+use crate::state::AppState;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing_appender::non_blocking::{NonBlocking, NonBlockingBuilder, WorkerGuard};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Tracing target of access-log events
+pub const TARGET: &str = "access";
+
+/// Lines waiting for the writer before new ones are dropped
+pub const QUEUE_CAPACITY: usize = 8192;
+
+/// `ACCESS_LOG_*` settings
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+
+    /// Size a file may reach before it is rotated
+    pub max_size_bytes: u64,
+
+    /// Rotated files kept besides the current one
+    pub keep: usize,
+}
+
+impl AccessLogConfig {
+    /// Load from `ACCESS_LOG_PATH`, `ACCESS_LOG_MAX_SIZE_MB` and
+    /// `ACCESS_LOG_KEEP`; `None` without a path
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let Ok(path) = std::env::var("ACCESS_LOG_PATH") else {
+            return Ok(None);
+        };
+        let number = |name: &str, default: u64| -> crate::Result<u64> {
+            match std::env::var(name) {
+                Ok(value) => value.trim().parse().map_err(|e| {
+                    crate::ServerError::ConfigError(format!("Invalid {} {:?}: {}", name, value, e))
+                }),
+                Err(_) => Ok(default),
+            }
+        };
+
+        let max_size_mb = number("ACCESS_LOG_MAX_SIZE_MB", 100)?;
+        if max_size_mb == 0 {
+            return Err(crate::ServerError::ConfigError(
+                "ACCESS_LOG_MAX_SIZE_MB must be at least 1".to_string(),
+            ));
+        }
+        Ok(Some(Self {
+            path: PathBuf::from(path),
+            max_size_bytes: max_size_mb * 1024 * 1024,
+            keep: number("ACCESS_LOG_KEEP", 5)? as usize,
+        }))
+    }
+}
+
+/// Handle to the writer thread
+struct Writer {
+    lines: NonBlocking,
+    /// Dropping this writes out the queue and stops the thread
+    guard: Mutex<Option<WorkerGuard>>,
+}
+
+impl std::fmt::Debug for Writer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Writer").field("dropped", &self.lines.error_counter().dropped_lines()).finish()
+    }
+}
+
+/// Where access-log events go once a file is opened; clones share the file.
+/// Install [`AccessLogSink::layer`] when tracing is set up, then
+/// [`AccessLogSink::open`] once the configuration is known.
+#[derive(Debug, Clone, Default)]
+pub struct AccessLogSink {
+    writer: Arc<OnceLock<Writer>>,
+}
+
+impl AccessLogSink {
+    /// Start writing to `config.path`, appending to an existing file.
+    /// A sink is opened at most once; later calls are ignored.
+    pub fn open(&self, config: &AccessLogConfig) -> crate::Result<()> {
+        if self.writer.get().is_some() {
+            return Ok(());
+        }
+        let file = RotatingFile::open(config.clone()).map_err(|e| {
+            crate::ServerError::ConfigError(format!("Cannot open ACCESS_LOG_PATH {}: {}", config.path.display(), e))
+        })?;
+        let (lines, guard) = NonBlockingBuilder::default()
+            .buffered_lines_limit(QUEUE_CAPACITY)
+            .lossy(true)
+            .thread_name("access-log")
+            .finish(file);
+        let _ = self.writer.set(Writer { lines, guard: Mutex::new(Some(guard)) });
+        Ok(())
+    }
+
+    /// Whether a file has been opened
+    pub fn is_open(&self) -> bool {
+        self.writer.get().is_some()
+    }
+
+    /// Lines dropped because the writer fell behind
+    pub fn dropped(&self) -> u64 {
+        self.writer.get().map_or(0, |writer| writer.lines.error_counter().dropped_lines() as u64)
+    }
+
+    /// Wait until every line sent so far is on disk, then stop writing;
+    /// called once at exit
+    pub fn flush(&self) {
+        if let Some(writer) = self.writer.get() {
+            drop(writer.guard.lock().unwrap_or_else(|e| e.into_inner()).take());
+        }
+    }
+
+    fn write(&self, mut line: String) {
+        if let Some(writer) = self.writer.get() {
+            // One write is one queued message, so lines stay whole
+            line.push('\n');
+            let _ = writer.lines.clone().write(line.as_bytes());
+        }
+    }
+
+    /// Layer writing `access_log` events to this sink, ignoring all others
+    pub fn layer<S>(&self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        AccessLogLayer { sink: self.clone() }
+            .with_filter(tracing_subscriber::filter::filter_fn(|metadata| metadata.target() == TARGET))
+    }
+}
+
+/// The file being written and its rotation state; each write is one line
+struct RotatingFile {
+    config: AccessLogConfig,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(config: AccessLogConfig) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { config, file: BufWriter::new(file), size })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        let len = line.len() as u64;
+        if self.size > 0 && self.size + len > self.config.max_size_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift `path.N` to `path.N+1`, dropping the oldest, and start a new file
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            std::fs::remove_file(path)?;
+        } else {
+            for index in (1..self.config.keep).rev() {
+                match std::fs::rename(rotated(path, index), rotated(path, index + 1)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            std::fs::rename(path, rotated(path, 1))?;
+        }
+        *self = Self::open(self.config.clone())?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, line: &[u8]) -> std::io::Result<usize> {
+        if let Err(e) = self.write_line(line) {
+            eprintln!("access log: cannot write {}: {}", self.config.path.display(), e);
+        }
+        // A line that could not be written is lost, not retried
+        Ok(line.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush().inspect_err(|e| {
+            eprintln!("access log: cannot write {}: {}", self.config.path.display(), e);
+        })
+    }
+}
+
+/// `path` with `.index` appended
+pub fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+struct AccessLogLayer {
+    sink: AccessLogSink,
+}
+
+impl<S: Subscriber> Layer<S> for AccessLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if !self.sink.is_open() {
+            return;
+        }
+        let mut fields = JsonFields(serde_json::Map::new());
+        fields.0.insert("time".to_string(), chrono::Utc::now().to_rfc3339().into());
+        event.record(&mut fields);
+        self.sink.write(serde_json::Value::Object(fields.0).to_string());
+    }
+}
+
+/// Event fields as JSON values, leaving out the message
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl Visit for JsonFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() != "message" {
+            self.0.insert(field.name().to_string(), format!("{:?}", value).into());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+/// Middleware emitting one `access_log` event per request when
+/// `ACCESS_LOG_PATH` is set
+pub async fn record(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    if state.config().access_log.is_none() {
+        return next.run(request).await;
+    }
+
+    let started = Instant::now();
+    let method = request.method().clone();
+    let uri = request.uri().to_string();
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let client_ip = match request.extensions().get::<ClientIp>() {
+        Some(client) => client.ip,
+        None => {
+            let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|info| info.0);
+            ClientIp::resolve(request.headers(), peer).ip
+        }
+    };
+    let client_ip = client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+    let proxy_chain = request
+        .extensions()
+        .get::<ProxyChain>()
+        .filter(|chain| !chain.0.is_empty())
+        .map(|chain| chain.to_string());
+    let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
+    let cloudflare = CloudflareMeta::of(&mut request);
+
+    let response = next.run(request).await;
+    let experiments = response.extensions().get::<Assignments>().map(|assignments| assignments.to_string());
+
+    let length = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    tracing::info!(
+        target: TARGET,
+        method = %method,
+        uri,
+        status = response.status().as_u16(),
+        duration_ms = started.elapsed().as_secs_f64() * 1000.0,
+        request_id,
+        client_ip,
+        user_agent,
+        cf_colo = cloudflare.colo,
+        cf_country = cloudflare.country,
+        cf_worker = cloudflare.worker,
+        cf_bot_score = cloudflare.bot_score,
+        experiments,
+        proxy_chain,
+        bytes = length,
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotated_names() {
+        assert_eq!(rotated(Path::new("/var/log/access.log"), 2), PathBuf::from("/var/log/access.log.2"));
+    }
+}
@@ -0,0 +1,689 @@
+/*!
+ * Admin listener
+ *
+ * Operational endpoints are served on a separate listener (`ADMIN_ADDR`,
+ * typically bound to localhost) so they are never reachable through the
+ * tunnel. Every admin route requires `Authorization: Bearer <ADMIN_TOKEN>`.
+ *
+ * `GET /admin/config` returns the configuration the process is running
+ * with, secrets redacted (see [`crate::config_view`]), and
+ * `/admin/config/history` and `/admin/config/rollback` undo changes to it
+ * (see [`crate::config_history`]). `POST /admin/config/preview` shows the
+ * headers a change to the security settings would produce without
+ * applying it. `GET /admin/slo` reports recent
+ * availability against `SLO_TARGET` (see [`crate::slo`]), and
+ * `/admin/capture` records matching requests in full (see [`crate::capture`]).
+ * `GET /admin/tasks` lists the background tasks and how their last runs went
+ * (see [`crate::tasks`]). `GET /admin/security-score` grades the security
+ * headers (see [`crate::security_score`]). `GET /admin/unknown-paths` lists
+ * the most requested paths that matched no route (see
+ * [`crate::unknown_paths`]). `GET /admin/pipeline` lists the main router's
+ * middleware stages in the order a request meets them (see
+ * [`crate::pipeline`]) and which optional route groups are on, and
+ * `PUT /admin/features` switches them (see [`crate::features`]).
+ * `/admin/log-level` shows and temporarily changes
+ * the log filter (see [`crate::log_level`]). `POST /admin/drain` and
+ * `/admin/undrain` withdraw and restore readiness ahead of a shutdown (see
+ * [`crate::drain`]). `/admin/faults` injects latency
+ * and errors for resilience drills (see `crate::faults`, built with the
+ * `fault-injection` feature). Retried `POST`s and `DELETE`s carrying an
+ * `Idempotency-Key` are answered with the first response (see
+ * [`crate::idempotency`]). Every state-changing request is recorded in the
+ * audit log, read back at `GET /admin/audit` (see [`crate::audit`]).
+ */
+#[cfg(feature = "cloudflare-api")]
+use crate::cloudflare::api::{CloudflareApiError, CloudflareClient, PurgeRequest, MAX_PURGE_URLS};
+use crate::access::AccessIdentity;
+use crate::audit::AuditReport;
+use crate::capture::{CaptureFilter, CaptureReport};
+use crate::config::{AppConfig, SecurityConfig};
+use crate::config_view::ConfigView;
+use crate::drain::{DrainStatus, ShuttingDown};
+use crate::error::ApiError;
+#[cfg(feature = "fault-injection")]
+use crate::faults::{FaultSpec, FaultStatus};
+use crate::log_level::LogLevelStatus;
+use crate::maintenance::{MaintenanceState, MaintenanceStatus};
+use crate::security_score::ScoreReport;
+use crate::slo::SloReport;
+use crate::state::AppState;
+use crate::unknown_paths::UnknownPathsReport;
+use axum::{
+    body::Bytes,
+    extract::{Query, Request, State},
+    http::{header, HeaderMap},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post, put},
+    Extension, Router,
+};
+use serde::Deserialize;
+use axum::http::StatusCode;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Admin listener address and credentials
+#[derive(Clone)]
+pub struct AdminConfig {
+    /// Address the admin listener binds to
+    pub addr: SocketAddr,
+
+    /// Bearer token required on every admin request
+    pub token: String,
+}
+
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("addr", &self.addr)
+            .field("token", &"<redacted>")
+            .finish()
+    }
+}
+
+impl AdminConfig {
+    /// Load from `ADMIN_ADDR` and `ADMIN_TOKEN` (or `ADMIN_TOKEN_FILE`).
+    /// The listener is disabled when `ADMIN_ADDR` is unset; a token is
+    /// mandatory once it is set.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let Ok(addr) = std::env::var("ADMIN_ADDR") else {
+            return Ok(None);
+        };
+
+        let addr = addr.parse()
+            .map_err(|e| crate::ServerError::ConfigError(
+                format!("Invalid ADMIN_ADDR: {}", e)
+            ))?;
+        let token = crate::config::read_secret("ADMIN_TOKEN")?
+            .filter(|t| !t.is_empty())
+            .ok_or_else(|| crate::ServerError::ConfigError(
+                "ADMIN_TOKEN must be set when ADMIN_ADDR is configured".to_string()
+            ))?;
+
+        Ok(Some(Self { addr, token }))
+    }
+}
+
+#[derive(Clone)]
+struct AdminState {
+    token: Arc<str>,
+    app: AppState,
+    maintenance: MaintenanceState,
+    #[cfg(feature = "cloudflare-api")]
+    cloudflare: Option<CloudflareClient>,
+}
+
+/// Build the admin router for the main service's `app` state, whose
+/// configuration supplies the credentials for calls to Cloudflare
+pub fn create_admin_app(config: &AdminConfig, app: &AppState) -> Router {
+    let state = AdminState {
+        token: Arc::from(config.token.as_str()),
+        app: app.clone(),
+        maintenance: app.maintenance.clone(),
+        #[cfg(feature = "cloudflare-api")]
+        cloudflare: app.config().cloudflare_api.clone().map(|api| CloudflareClient::new(api, &app.http)),
+    };
+
+    let router = Router::new()
+        .route("/admin/readyz", get(readiness))
+        .route("/admin/config", get(effective_config))
+        .route("/admin/config/history", get(config_history))
+        .route("/admin/config/rollback", post(rollback_config))
+        .route("/admin/config/preview", post(preview_config))
+        .route("/admin/stats", get(route_stats))
+        .route("/admin/unknown-paths", get(unknown_paths))
+        .route("/admin/slo", get(slo_report))
+        .route("/admin/pipeline", get(pipeline))
+        .route("/admin/security-score", get(security_score))
+        .route("/admin/capture", get(capture_report).post(start_capture).delete(stop_capture))
+        .route("/admin/tasks", get(task_statuses))
+        .route("/admin/audit", get(audit_log))
+        .route("/admin/log-level", get(log_level).put(set_log_level))
+        .route("/admin/features", put(set_features))
+        .route("/admin/maintenance", post(set_maintenance))
+        .route("/admin/drain", post(drain))
+        .route("/admin/undrain", post(undrain));
+
+    #[cfg(feature = "fault-injection")]
+    let router = router.route("/admin/faults", get(fault_status).post(start_fault).delete(stop_fault));
+
+    #[cfg(feature = "cloudflare-api")]
+    let router = router.route("/admin/purge-cache", post(purge_cache));
+
+    // Inside the token check, so unauthenticated requests never claim a key
+    // or reach the audit log; replayed actions are audited as well
+    router
+        .layer(middleware::from_fn_with_state(app.clone(), crate::idempotency::enforce))
+        .layer(middleware::from_fn_with_state(app.clone(), crate::audit::record))
+        .layer(middleware::from_fn_with_state(app.clone(), crate::access::verify))
+        .layer(middleware::from_fn_with_state(state.clone(), require_admin_token))
+        .with_state(state)
+}
+
+async fn require_admin_token(State(state): State<AdminState>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match presented {
+        Some(token) if constant_time_eq(token.as_bytes(), state.token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => {
+            warn!("Rejected unauthenticated admin request to {}", request.uri().path());
+            crate::auth::unauthorized("Bearer", "A valid admin bearer token is required")
+        }
+    }
+}
+
+/// Compare secrets without short-circuiting on the first differing byte
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// The main listener's `/readyz`, for probes that only reach the admin
+/// listener
+async fn readiness(State(state): State<AdminState>) -> Response {
+    crate::health::readyz(State(state.app)).await.into_response()
+}
+
+/// The live configuration, as last stored in the app state
+async fn effective_config(State(state): State<AdminState>) -> Json<ConfigView> {
+    Json(ConfigView::new(&state.app.config(), state.app.config_version(), state.app.reloaded_at()))
+}
+
+/// The kept security configuration versions, oldest first
+async fn config_history(State(state): State<AdminState>) -> Json<Value> {
+    let history = state.app.config_history();
+    Json(json!({
+        "current": history.current().version,
+        "versions": history.versions().collect::<Vec<_>>(),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RollbackBody {
+    version: u64,
+}
+
+/// Apply a kept version's security settings as a new version
+async fn rollback_config(
+    State(state): State<AdminState>,
+    Json(body): Json<RollbackBody>,
+) -> Result<Json<Value>, ApiError> {
+    let security = state.app.config_history().get(body.version).map(|kept| kept.security.clone()).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "unknown_config_version",
+            format!("Configuration version {} is not in the history", body.version),
+        )
+    })?;
+
+    let config = AppConfig { security, ..(*state.app.config()).clone() };
+    let version = state
+        .app
+        .apply_config(config, &format!("rollback to {}", body.version))
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_config", e.to_string()))?;
+    warn!("Configuration rolled back to version {} through the admin API (now version {})", body.version, version);
+    Ok(Json(json!({ "version": version, "rolled_back_to": body.version })))
+}
+
+/// The headers, validation result and score the live security settings
+/// would have with the body merged over them; nothing is applied
+async fn preview_config(State(state): State<AdminState>, body: Bytes) -> Result<Json<Value>, ApiError> {
+    let patch: Value = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_config", format!("Body is not JSON: {}", e)))?;
+    let security: SecurityConfig = crate::config::merge_patch(&state.app.config().security, &patch).map_err(|errors| {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_config", "The body does not fit the security configuration")
+            .with_details(json!({ "fields": errors }))
+    })?;
+
+    let errors: Vec<String> = security.validate().err().map(|e| e.to_string()).into_iter().collect();
+    Ok(Json(json!({
+        "headers": security
+            .to_headers()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect::<BTreeMap<_, _>>(),
+        "validation": { "valid": errors.is_empty(), "errors": errors },
+        "score": crate::security_score::score(&security),
+        "security": security,
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsQuery {
+    #[serde(default)]
+    reset: bool,
+}
+
+/// Per-route statistics, busiest first, and the size of each in-memory
+/// store; `?reset=true` zeroes the route statistics after taking the
+/// snapshot
+async fn route_stats(State(state): State<AdminState>, Query(query): Query<StatsQuery>) -> Json<Value> {
+    let routes = state.app.route_stats.snapshot();
+    if query.reset {
+        state.app.route_stats.reset();
+        warn!("Route statistics reset through the admin API");
+    }
+    let stores = crate::bounded::stats(&state.app);
+    Json(json!({ "routes": routes, "stores": stores, "reset": query.reset }))
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditQuery {
+    limit: Option<usize>,
+}
+
+/// Recent admin actions, newest first; `?limit=` caps the count (default 100)
+async fn audit_log(State(state): State<AdminState>, Query(query): Query<AuditQuery>) -> Json<AuditReport> {
+    Json(state.app.audit.recent(query.limit.unwrap_or(100)))
+}
+
+/// The most requested paths that matched no route
+async fn unknown_paths(State(state): State<AdminState>) -> Json<UnknownPathsReport> {
+    Json(state.app.unknown_paths.top(crate::unknown_paths::TOP_PATHS))
+}
+
+/// Middleware stages of the main router, outermost first
+async fn pipeline(State(state): State<AdminState>) -> Json<Value> {
+    // Computed from the configuration when the main router was not built
+    // from this state, as in tests of the admin app alone
+    let mut stages = state.app.pipeline();
+    if stages.is_empty() {
+        stages = crate::AppBuilder::new(state.app.clone()).pipeline().order();
+    }
+    Json(json!({ "stages": stages, "features": state.app.features.snapshot() }))
+}
+
+/// Availability over the reporting windows against `SLO_TARGET`
+async fn slo_report(State(state): State<AdminState>) -> Json<SloReport> {
+    Json(state.app.slo.report(&state.app.config().slo))
+}
+
+/// Grade of the live security headers against `security_score::RUBRIC`,
+/// with the header size budget
+async fn security_score(State(state): State<AdminState>) -> Json<ScoreReport> {
+    let config = state.app.config();
+    let mut report = crate::security_score::score(&config.security);
+    report.header_budget = Some(crate::header_budget::HeaderBudget::new(&config));
+    Json(report)
+}
+
+/// Last run and error of every supervised background task
+async fn task_statuses(State(state): State<AdminState>) -> Json<Value> {
+    Json(json!({ "tasks": state.app.tasks.statuses() }))
+}
+
+/// Start capturing requests matching the filter, replacing any running capture
+async fn start_capture(
+    State(state): State<AdminState>,
+    Json(filter): Json<CaptureFilter>,
+) -> Result<Json<CaptureReport>, ApiError> {
+    filter.validate()?;
+    warn!(
+        prefix = filter.prefix.as_deref(),
+        sample = filter.sample,
+        max = filter.max,
+        ttl_seconds = filter.ttl_seconds,
+        "Request capture started through the admin API"
+    );
+    Ok(Json(state.app.capture.start(filter)))
+}
+
+/// The running capture and what it has recorded
+async fn capture_report(State(state): State<AdminState>) -> Json<CaptureReport> {
+    Json(state.app.capture.report())
+}
+
+/// End the capture, returning what it recorded
+async fn stop_capture(State(state): State<AdminState>) -> Json<CaptureReport> {
+    warn!("Request capture stopped through the admin API");
+    Json(state.app.capture.stop())
+}
+
+/// Start injecting the fault, replacing any active one
+#[cfg(feature = "fault-injection")]
+async fn start_fault(
+    State(state): State<AdminState>,
+    Json(spec): Json<FaultSpec>,
+) -> Result<Json<FaultStatus>, ApiError> {
+    spec.validate()?;
+    warn!(
+        latency_ms = spec.latency_ms,
+        error_rate = spec.error_rate,
+        status = spec.status,
+        prefix = spec.prefix.as_deref(),
+        ttl_seconds = spec.ttl_seconds,
+        "Fault injection started through the admin API"
+    );
+    Ok(Json(state.app.faults.start(spec)))
+}
+
+/// The active fault and how many requests it has hit
+#[cfg(feature = "fault-injection")]
+async fn fault_status(State(state): State<AdminState>) -> Json<FaultStatus> {
+    Json(state.app.faults.status())
+}
+
+/// End the active fault
+#[cfg(feature = "fault-injection")]
+async fn stop_fault(State(state): State<AdminState>) -> Json<FaultStatus> {
+    warn!("Fault injection stopped through the admin API");
+    Json(state.app.faults.stop())
+}
+
+/// The log filter in force and when it reverts
+async fn log_level(State(state): State<AdminState>) -> Json<LogLevelStatus> {
+    Json(state.app.log_level.status())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LogLevelBody {
+    filter: String,
+}
+
+/// Replace the log filter until `LOG_LEVEL_REVERT_SECS` passes
+async fn set_log_level(
+    State(state): State<AdminState>,
+    Json(body): Json<LogLevelBody>,
+) -> Result<Json<LogLevelStatus>, ApiError> {
+    let revert_after = state.app.config().log_level.revert_after;
+    state.app.log_level.set(&body.filter, revert_after).map(Json).map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_filter",
+            format!("Invalid log filter {:?}: {}", body.filter, e),
+        )
+    })
+}
+
+/// Switch route groups on or off, e.g. `{"debug": false}`; groups left out
+/// keep their state
+async fn set_features(
+    State(state): State<AdminState>,
+    Json(changes): Json<BTreeMap<String, bool>>,
+) -> Result<Json<Value>, ApiError> {
+    let features = state.app.features.set(&changes).map_err(|e| {
+        ApiError::new(StatusCode::BAD_REQUEST, "unknown_feature", format!("Invalid feature toggles: {}", e))
+    })?;
+    Ok(Json(json!({ "features": features })))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaintenanceBody {
+    enabled: bool,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// Switch maintenance mode; a present `MAINTENANCE_FILE` keeps it on
+async fn set_maintenance(
+    State(state): State<AdminState>,
+    Json(body): Json<MaintenanceBody>,
+) -> Json<MaintenanceStatus> {
+    let was_enabled = state.maintenance.is_enabled();
+    state.maintenance.set(body.enabled, body.message.filter(|m| !m.trim().is_empty()));
+    let status = state.maintenance.status();
+    warn!(
+        "Maintenance mode switched {} through the admin API (now {})",
+        if body.enabled { "on" } else { "off" },
+        if status.enabled { "enabled" } else { "disabled" },
+    );
+    crate::notifications::maintenance_changed(&state.app, was_enabled);
+    Json(status)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DrainBody {
+    /// Overrides `DRAIN_DELAY_SECS`
+    delay_seconds: Option<u64>,
+}
+
+/// Withdraw readiness, now or after a delay, without shutting down; the
+/// body is optional
+async fn drain(
+    State(state): State<AdminState>,
+    access: Option<Extension<AccessIdentity>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<DrainStatus>, ApiError> {
+    let body: DrainBody = if body.is_empty() {
+        DrainBody::default()
+    } else {
+        serde_json::from_slice(&body)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", format!("Invalid drain request: {}", e)))?
+    };
+    let delay = body.delay_seconds.map(Duration::from_secs).unwrap_or(state.app.config().drain.delay);
+    let by = crate::audit::identify(access.as_ref().map(|Extension(access)| access), &headers, &state.app).to_string();
+    Ok(Json(state.app.drain.drain(&state.app, by, delay)))
+}
+
+/// Cancel a drain and restore readiness
+async fn undrain(State(state): State<AdminState>) -> Result<Json<DrainStatus>, ApiError> {
+    state.app.drain.undrain(&state.app).map(Json).map_err(|ShuttingDown| {
+        ApiError::new(StatusCode::CONFLICT, "shutting_down", "The service is shutting down; the drain cannot be undone")
+    })
+}
+
+#[cfg(feature = "cloudflare-api")]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PurgeBody {
+    #[serde(default)]
+    urls: Vec<String>,
+    #[serde(default)]
+    purge_everything: bool,
+}
+
+#[cfg(feature = "cloudflare-api")]
+async fn purge_cache(
+    State(state): State<AdminState>,
+    Json(body): Json<PurgeBody>,
+) -> Result<Json<Value>, ApiError> {
+    let client = state.cloudflare.ok_or_else(|| ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "cloudflare_api_not_configured",
+        "CLOUDFLARE_API_TOKEN and CLOUDFLARE_ZONE_ID are not set",
+    ))?;
+
+    let request = match (body.purge_everything, body.urls.is_empty()) {
+        (true, true) => PurgeRequest::Everything,
+        (false, false) if body.urls.len() <= MAX_PURGE_URLS => PurgeRequest::Urls(body.urls),
+        (false, false) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "too_many_urls",
+                format!("At most {} URLs can be purged per request", MAX_PURGE_URLS),
+            ))
+        }
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_purge_request",
+                "Provide either a non-empty urls list or purge_everything: true",
+            ))
+        }
+    };
+
+    let result = client.purge_cache(&request).await.map_err(|e| {
+        warn!("Cache purge failed: {}", e);
+        let code = match e {
+            CloudflareApiError::Unauthorized(_) => "cloudflare_auth_failed",
+            CloudflareApiError::RateLimited { .. } => "cloudflare_rate_limited",
+            _ => "cloudflare_api_error",
+        };
+        ApiError::new(StatusCode::BAD_GATEWAY, code, e.to_string())
+    })?;
+
+    Ok(Json(json!({ "success": true, "id": result.id })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::util::ServiceExt;
+
+    fn admin_config() -> AdminConfig {
+        AdminConfig {
+            addr: "127.0.0.1:0".parse().expect("Invalid address"),
+            token: "admin-secret".to_string(),
+        }
+    }
+
+    async fn post_purge(token: Option<&str>, body: &str) -> (StatusCode, serde_json::Value) {
+        post(&AppState::default(), "/admin/purge-cache", token, body).await
+    }
+
+    async fn post(app: &AppState, uri: &str, token: Option<&str>, body: &str) -> (StatusCode, serde_json::Value) {
+        let app = create_admin_app(&admin_config(), app);
+        let mut builder = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let response = app
+            .oneshot(builder.body(Body::from(body.to_string())).expect("Failed to build request"))
+            .await
+            .expect("Failed to get response");
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        // Extractor rejections are plain text
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_token() {
+        let (status, json) = post_purge(None, r#"{"purge_everything": true}"#).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(json["error"], "unauthorized");
+
+        let (status, _json) = post_purge(Some("wrong"), r#"{"purge_everything": true}"#).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[cfg(feature = "cloudflare-api")]
+    #[tokio::test]
+    async fn test_purge_without_cloudflare_config() {
+        let (status, json) = post_purge(Some("admin-secret"), r#"{"purge_everything": true}"#).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["error"], "cloudflare_api_not_configured");
+    }
+
+    #[cfg(feature = "cloudflare-api")]
+    #[tokio::test]
+    async fn test_purge_rejects_ambiguous_body() {
+        let config = AppConfig {
+            cloudflare_api: Some(crate::cloudflare::api::CloudflareApiConfig::new("t", "z")),
+            ..AppConfig::default()
+        };
+        let app = create_admin_app(&admin_config(), &AppState::new(config));
+        let request = Request::builder()
+            .method("POST")
+            .uri("/admin/purge-cache")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::AUTHORIZATION, "Bearer admin-secret")
+            .body(Body::from(r#"{"urls": ["https://a/"], "purge_everything": true}"#))
+            .expect("Failed to build request");
+
+        let response = app.oneshot(request).await.expect("Failed to get response");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_toggle() {
+        let app = AppState::default();
+
+        let (status, _) = post(&app, "/admin/maintenance", None, r#"{"enabled": true}"#).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!app.maintenance.is_enabled());
+
+        let body = r#"{"enabled": true, "message": "Database upgrade until 14:00 UTC"}"#;
+        let (status, json) = post(&app, "/admin/maintenance", Some("admin-secret"), body).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["enabled"], true);
+        assert_eq!(json["message"], "Database upgrade until 14:00 UTC");
+        assert!(app.maintenance.is_enabled());
+
+        // Turning it off through the API leaves the sentinel file in charge
+        app.maintenance.set_file(true);
+        let (_, json) = post(&app, "/admin/maintenance", Some("admin-secret"), r#"{"enabled": false}"#).await;
+        assert_eq!(json["enabled"], true);
+        assert_eq!(json["manual"], false);
+        app.maintenance.set_file(false);
+        assert!(!app.maintenance.is_enabled());
+
+        let (status, _) = post(&app, "/admin/maintenance", Some("admin-secret"), r#"{"on": true}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    async fn get_config(app: &AppState, token: Option<&str>) -> (StatusCode, serde_json::Value) {
+        let mut builder = Request::builder().uri("/admin/config");
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let response = create_admin_app(&admin_config(), app)
+            .oneshot(builder.body(Body::empty()).expect("Failed to build request"))
+            .await
+            .expect("Failed to get response");
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn test_config_is_redacted_and_follows_reloads() {
+        let app = AppState::new(crate::config::AppConfig {
+            admin: Some(admin_config()),
+            ..crate::config::AppConfig::default()
+        });
+
+        let (status, _) = get_config(&app, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, json) = get_config(&app, Some("admin-secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["secrets"]["admin_token"], "<redacted>");
+        assert!(!json.to_string().contains("admin-secret"));
+        assert_eq!(json["security"]["frame_options"], "DENY");
+        assert_eq!(json["sources"]["environment"], true);
+        assert_eq!(json["reloaded_at"], serde_json::Value::Null);
+
+        let mut reloaded = (*app.config()).clone();
+        reloaded.security.frame_options = "SAMEORIGIN".to_string();
+        app.replace_config(reloaded);
+
+        let (_, json) = get_config(&app, Some("admin-secret")).await;
+        assert_eq!(json["security"]["frame_options"], "SAMEORIGIN");
+        assert!(json["reloaded_at"].is_string());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
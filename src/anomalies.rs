@@ -0,0 +1,106 @@
+/*!
+ * Rejection of malformed request heads
+ *
+ * Requests reach the origin through Cloudflare's edge and then cloudflared,
+ * and two proxies that frame or route a request differently from the
+ * origin are what request smuggling feeds on. The [`reject`] middleware
+ * runs before anything reads a body or matches a route and answers `400`
+ * with a [`Denial`] for:
+ *
+ * - an HTTP/1.x absolute-form or authority-form target
+ *   (`GET http://host/path`, `CONNECT host:443`): proxies forward
+ *   origin-form targets, so one that names a host was not sent through them
+ *   (`absolute_form_target`). HTTP/2 requests always carry `:authority`
+ *   and are exempt
+ * - more than one `Host` header, which proxies and the origin may resolve
+ *   to different hosts (`duplicate_host`)
+ *
+ * Each rejection is logged and counted in
+ * `protocol_anomalies_total{reason="..."}`. They come before the
+ * `denial_signal` stage, so they never carry `DENIAL_SIGNAL_HEADER`.
+ *
+ * hyper deals with the other anomalies while parsing, before any
+ * middleware runs:
+ *
+ * - both `Transfer-Encoding` and `Content-Length`: the body is read as
+ *   chunked, `Content-Length` is dropped and the connection is closed after
+ *   the response, so nothing after the body is read as another request
+ * - `Transfer-Encoding` not ending in `chunked`, or on HTTP/1.0: `400`
+ * - `Content-Length` headers that disagree: `400`
+ * - obsolete line folding (a header line starting with whitespace): `400`
+ *
+ * Those `400`s have no body and no request ID, and reach neither the
+ * access log nor `/metrics`.
+ */
+use crate::denial::{Denial, DenialReason};
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{header, Version},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+/// What is wrong with `request`'s head, if anything
+pub fn anomaly(request: &Request) -> Option<Denial> {
+    if request.version() < Version::HTTP_2 && request.uri().authority().is_some() {
+        return Some(Denial::new(
+            DenialReason::AbsoluteFormTarget,
+            "The request target must be a path, not a URL",
+        ));
+    }
+    if request.headers().get_all(header::HOST).iter().nth(1).is_some() {
+        return Some(Denial::new(DenialReason::DuplicateHost, "The request has more than one Host header"));
+    }
+    None
+}
+
+/// Middleware answering malformed request heads with `400`
+pub async fn reject(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(denial) = anomaly(&request) else {
+        return next.run(request).await;
+    };
+
+    let reason = denial.reason.code();
+    warn!(reason, method = %request.method(), uri = %request.uri(), "Rejected a malformed request");
+    #[cfg(feature = "metrics")]
+    state
+        .metrics
+        .counter(
+            &format!("protocol_anomalies_total{{reason=\"{}\"}}", reason),
+            "Requests rejected for a malformed head",
+        )
+        .inc();
+    #[cfg(not(feature = "metrics"))]
+    let _ = state;
+    denial.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+
+    fn request(uri: &str, hosts: &[&str]) -> Request {
+        let mut builder = Request::builder().uri(uri);
+        for host in hosts {
+            builder = builder.header(header::HOST, *host);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_anomalies_are_told_apart() {
+        let reason = |request: Request| anomaly(&request).map(|denial| denial.reason);
+        assert_eq!(reason(request("/health?x=1", &["example.com"])), None);
+        assert_eq!(reason(request("*", &["example.com"])), None);
+        assert_eq!(reason(request("http://example.com/health", &["example.com"])), Some(DenialReason::AbsoluteFormTarget));
+        assert_eq!(reason(request("example.com:443", &["example.com"])), Some(DenialReason::AbsoluteFormTarget));
+        assert_eq!(reason(request("/health", &["example.com", "example.com"])), Some(DenialReason::DuplicateHost));
+
+        let mut http2 = request("http://example.com/health", &[]);
+        *http2.version_mut() = Version::HTTP_2;
+        assert_eq!(reason(http2), None);
+    }
+}
@@ -0,0 +1,344 @@
+/*!
+ * Named API keys for machine clients
+ *
+ * `API_KEYS` (or `API_KEYS_FILE`) lists keys as `name:key`, separated by
+ * commas or newlines, e.g. `reporting:abc123,backup:def456`. Requests under
+ * `API_KEY_PREFIXES` must send one of them in `X-Api-Key`; a missing key, or
+ * one that is not (or no longer) listed, gets 401. Keys are compared in
+ * constant time and never logged: only the key's name is kept, as an
+ * `ApiKeyName` request extension and as the `api_key` field of the request
+ * span.
+ *
+ * `API_KEY_RATE_LIMIT` caps the requests each key may make per
+ * `API_KEY_RATE_WINDOW_SECS`, and `API_KEY_<NAME>_RATE_LIMIT` overrides the
+ * cap for one key. Requests are counted per key name rather than per client
+ * IP, since one machine client may call from many addresses; over the cap
+ * they get 429 with `Retry-After`.
+ */
+use crate::admin::constant_time_eq;
+use crate::auth::{longest_prefix, unauthorized};
+use crate::bounded::{BoundedMap, StoreStats};
+use crate::clock::{Clock, SystemClock};
+use crate::denial::{Denial, DenialReason};
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Header carrying the key
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+const CHALLENGE: &str = "ApiKey header=\"X-Api-Key\"";
+
+/// A named key
+#[derive(Clone)]
+pub struct ApiKey {
+    /// Name from `API_KEYS`, used in logs and for rate limiting
+    pub name: String,
+
+    /// The secret sent in `X-Api-Key`
+    pub key: String,
+
+    /// Requests per window for this key, replacing `API_KEY_RATE_LIMIT`
+    pub rate_limit: Option<u32>,
+}
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .field("key", &"<redacted>")
+            .field("rate_limit", &self.rate_limit)
+            .finish()
+    }
+}
+
+impl ApiKey {
+    /// Key `name` with secret `key` and the default rate limit
+    pub fn new(name: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            key: key.into(),
+            rate_limit: None,
+        }
+    }
+}
+
+/// Keys, the prefixes they protect and their rate limits
+#[derive(Debug, Clone)]
+pub struct ApiKeyConfig {
+    /// Accepted keys
+    pub keys: Vec<ApiKey>,
+
+    /// Protected prefixes, matched on segment boundaries
+    pub prefixes: Vec<String>,
+
+    /// Requests per window for keys without their own limit; unlimited when unset
+    pub rate_limit: Option<u32>,
+
+    /// Length of a rate-limit window
+    pub rate_window: Duration,
+}
+
+impl ApiKeyConfig {
+    /// `keys` protecting `prefixes`, without rate limits
+    pub fn new<P>(keys: impl IntoIterator<Item = ApiKey>, prefixes: P) -> Self
+    where
+        P: IntoIterator,
+        P::Item: Into<String>,
+    {
+        Self {
+            keys: keys.into_iter().collect(),
+            prefixes: prefixes.into_iter().map(Into::into).collect(),
+            rate_limit: None,
+            rate_window: Duration::from_secs(60),
+        }
+    }
+
+    /// Load from `API_KEYS` (or `API_KEYS_FILE`), `API_KEY_PREFIXES`,
+    /// `API_KEY_RATE_LIMIT`, `API_KEY_RATE_WINDOW_SECS` and
+    /// `API_KEY_<NAME>_RATE_LIMIT`; disabled when no keys are set
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let Some(list) = crate::config::read_secret("API_KEYS")?.filter(|list| !list.trim().is_empty()) else {
+            return Ok(None);
+        };
+
+        let mut keys = parse_keys(&list)?;
+        for key in &mut keys {
+            let var = format!("API_KEY_{}_RATE_LIMIT", key.name.to_ascii_uppercase().replace('-', "_"));
+            key.rate_limit = positive_env(&var)?;
+        }
+
+        let prefixes = std::env::var("API_KEY_PREFIXES").unwrap_or_default();
+        let mut config = Self::new(keys, prefixes.split(',').map(str::trim).filter(|p| !p.is_empty()));
+        config.rate_limit = positive_env("API_KEY_RATE_LIMIT")?;
+        if let Some(secs) = positive_env("API_KEY_RATE_WINDOW_SECS")? {
+            config.rate_window = Duration::from_secs(secs.into());
+        }
+
+        if config.prefixes.is_empty() {
+            return Err(crate::ServerError::ConfigError(
+                "API_KEY_PREFIXES must be set when API_KEYS is configured".to_string()
+            ));
+        }
+        if let Some(bad) = config.prefixes.iter().find(|p| !p.starts_with('/')) {
+            return Err(crate::ServerError::ConfigError(format!(
+                "API key prefix {:?} must start with /",
+                bad
+            )));
+        }
+        Ok(Some(config))
+    }
+
+    /// The key matching `presented`; every key is compared so timing does
+    /// not reveal which one matched
+    pub(crate) fn resolve(&self, presented: &str) -> Option<&ApiKey> {
+        self.keys.iter().fold(None, |found, key| {
+            let matches = constant_time_eq(presented.as_bytes(), key.key.as_bytes());
+            found.or(matches.then_some(key))
+        })
+    }
+
+    /// Requests per window allowed for `key`, if limited
+    fn limit_for(&self, key: &ApiKey) -> Option<u32> {
+        key.rate_limit.or(self.rate_limit)
+    }
+}
+
+/// Parse `name:key` entries; errors name the entry but never show the key
+fn parse_keys(list: &str) -> crate::Result<Vec<ApiKey>> {
+    let mut keys: Vec<ApiKey> = Vec::new();
+    for (index, entry) in list.split([',', '\n']).map(str::trim).filter(|e| !e.is_empty()).enumerate() {
+        let (name, key) = entry
+            .split_once(':')
+            .map(|(name, key)| (name.trim(), key.trim()))
+            .filter(|(name, key)| !name.is_empty() && !key.is_empty())
+            .ok_or_else(|| crate::ServerError::ConfigError(format!(
+                "API_KEYS entry {} must be name:key",
+                index + 1
+            )))?;
+        if keys.iter().any(|existing| existing.name == name) {
+            return Err(crate::ServerError::ConfigError(format!("API key {:?} is listed twice", name)));
+        }
+        keys.push(ApiKey::new(name, key));
+    }
+    Ok(keys)
+}
+
+fn positive_env(var: &str) -> crate::Result<Option<u32>> {
+    let Ok(value) = std::env::var(var) else {
+        return Ok(None);
+    };
+    value.parse()
+        .ok()
+        .filter(|n| *n > 0)
+        .map(Some)
+        .ok_or_else(|| crate::ServerError::ConfigError(format!(
+            "Invalid {}: {:?} is not a positive number",
+            var, value
+        )))
+}
+
+/// Name of the key a request authenticated with, set by `require_api_key`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyName(pub Arc<str>);
+
+/// Fixed-window request counts per key name; clones share the counts.
+/// A key's window is forgotten once it ends, and at most one is kept per
+/// configured key.
+#[derive(Debug, Clone)]
+pub struct KeyRateLimiter {
+    clock: Arc<dyn Clock>,
+    windows: Arc<Mutex<BoundedMap<Window>>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+impl Default for KeyRateLimiter {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl KeyRateLimiter {
+    /// No windows open, with time read from `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let windows = BoundedMap::new("api_keys", 1, None, clock.clone());
+        Self { clock, windows: Arc::new(Mutex::new(windows)) }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(self, metrics: &crate::metrics::Metrics) -> Self {
+        self.windows.lock().unwrap_or_else(|e| e.into_inner()).set_metrics(metrics);
+        self
+    }
+
+    /// Count a request for `name` against `limit` per `config.rate_window`;
+    /// over the limit, the error is the time until the window resets
+    pub fn check(&self, name: &str, limit: u32, config: &ApiKeyConfig) -> Result<(), Duration> {
+        let (now, window) = (self.clock.monotonic(), config.rate_window);
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        windows.set_limits(config.keys.len(), Some(window));
+        let current = windows.get_or_insert_with(name, || Window { started: now, count: 0 });
+
+        let elapsed = now.saturating_duration_since(current.started);
+        if current.count >= limit {
+            return Err(window.saturating_sub(elapsed));
+        }
+        current.count += 1;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        self.windows.lock().unwrap_or_else(|e| e.into_inner()).stats()
+    }
+
+    /// Forget windows that have ended
+    pub fn sweep(&self) -> usize {
+        self.windows.lock().unwrap_or_else(|e| e.into_inner()).sweep()
+    }
+}
+
+/// Middleware requiring a known `X-Api-Key` on protected prefixes
+pub async fn require_api_key(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    let config = state.config();
+    let Some(api_keys) = &config.api_keys else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path();
+    if longest_prefix(&api_keys.prefixes, path).is_none() {
+        return next.run(request).await;
+    }
+
+    let presented = request.headers().get(API_KEY_HEADER).and_then(|v| v.to_str().ok()).map(str::trim);
+    let key = match presented.map(|presented| api_keys.resolve(presented)) {
+        Some(Some(key)) => key,
+        Some(None) => {
+            warn!("Rejected unknown API key for {}", path);
+            return unauthorized(CHALLENGE, "The API key is not valid");
+        }
+        None => {
+            warn!("Rejected request without an API key for {}", path);
+            return unauthorized(CHALLENGE, "An API key is required in X-Api-Key");
+        }
+    };
+
+    tracing::Span::current().record("api_key", key.name.as_str());
+    if let Some(limit) = api_keys.limit_for(key) {
+        if let Err(retry_in) = state.api_key_limiter.check(&key.name, limit, api_keys) {
+            warn!(api_key = %key.name, "API key exceeded {} requests per {:?}", limit, api_keys.rate_window);
+            return Denial::new(
+                DenialReason::RateLimited,
+                format!("API key {:?} is limited to {} requests per {} seconds", key.name, limit, api_keys.rate_window.as_secs()),
+            )
+            .with_retry_after(retry_in)
+            .into_response();
+        }
+    }
+
+    request.extensions_mut().insert(ApiKeyName(Arc::from(key.name.as_str())));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = parse_keys("reporting:abc123, backup:def456\nci:x:y\n").unwrap();
+        let names: Vec<_> = keys.iter().map(|k| k.name.as_str()).collect();
+        assert_eq!(names, ["reporting", "backup", "ci"]);
+        assert_eq!(keys[2].key, "x:y");
+
+        let error = parse_keys("reporting:abc123,secret-without-name").unwrap_err().to_string();
+        assert!(error.contains("entry 2"), "{}", error);
+        assert!(!error.contains("secret-without-name"), "{}", error);
+        assert!(parse_keys("a:1,a:2").is_err());
+    }
+
+    #[test]
+    fn test_resolve_returns_key_name() {
+        let config = ApiKeyConfig::new([ApiKey::new("reporting", "abc123"), ApiKey::new("backup", "def456")], ["/api"]);
+
+        assert_eq!(config.resolve("def456").map(|k| k.name.as_str()), Some("backup"));
+        assert_eq!(config.resolve("abc123").map(|k| k.name.as_str()), Some("reporting"));
+        assert!(config.resolve("abc12").is_none());
+        assert!(config.resolve("").is_none());
+    }
+
+    #[test]
+    fn test_debug_redacts_keys() {
+        let config = ApiKeyConfig::new([ApiKey::new("reporting", "abc123")], ["/api"]);
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("reporting"));
+        assert!(!debug.contains("abc123"));
+    }
+
+    #[test]
+    fn test_rate_limiter_windows() {
+        let clock = Arc::new(crate::testing::TestClock::new(std::time::SystemTime::UNIX_EPOCH));
+        let limiter = KeyRateLimiter::new(clock.clone());
+        let config = ApiKeyConfig::new([ApiKey::new("reporting", "abc123"), ApiKey::new("backup", "def456")], ["/api"]);
+        let window = config.rate_window;
+
+        assert!(limiter.check("reporting", 2, &config).is_ok());
+        assert!(limiter.check("reporting", 2, &config).is_ok());
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(limiter.check("reporting", 2, &config), Err(window - Duration::from_secs(20)));
+        // Counted per name
+        assert!(limiter.check("backup", 2, &config).is_ok());
+        // A new window starts once the old one has passed
+        clock.advance(window - Duration::from_secs(20));
+        assert!(limiter.check("reporting", 2, &config).is_ok());
+    }
+}
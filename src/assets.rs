@@ -0,0 +1,204 @@
+/*!
+ * Embedded assets, precompressed at build time
+ *
+ * The build script writes a gzip and a Brotli copy of each embedded asset
+ * to `OUT_DIR` (see `build.rs`), and `embedded_asset!` compiles all three
+ * into the binary as an [`EmbeddedAsset`], each variant with an ETag
+ * hashed at compile time. A request gets the variant `Accept-Encoding`
+ * ranks highest, the smallest of those on a tie, with `Content-Encoding`
+ * naming it; nothing is compressed while serving. Every variant is sent
+ * with `Vary: Accept-Encoding` so caches keep them apart.
+ *
+ * `identity` is acceptable unless the header refuses it, by name or
+ * through `*`; a request without the header gets the uncompressed bytes.
+ */
+use crate::conditional;
+use axum::{
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+
+/// A `Content-Encoding` an asset is stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    /// The token in `Accept-Encoding` and `Content-Encoding`
+    pub fn token(self) -> &'static str {
+        match self {
+            Self::Identity => "identity",
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+        }
+    }
+}
+
+/// One encoding of an asset
+#[derive(Debug, Clone, Copy)]
+pub struct Variant {
+    pub encoding: Encoding,
+    pub body: &'static [u8],
+
+    /// Strong ETag of `body`, quoted
+    pub etag: &'static str,
+}
+
+/// An asset in every encoding it is served in
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedAsset {
+    pub content_type: &'static str,
+    pub identity: Variant,
+    pub gzip: Variant,
+    pub br: Variant,
+}
+
+/// `EmbeddedAsset` for `assets/<name>`, with the compressed copies the build
+/// script wrote and every ETag computed at compile time
+macro_rules! embedded_asset {
+    ($name:literal, $content_type:expr) => {{
+        use $crate::assets::{EmbeddedAsset, Encoding, Variant};
+        use $crate::conditional::{etag_bytes, fnv1a};
+
+        const IDENTITY: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/", $name));
+        const GZIP: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".gz"));
+        const BR: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".br"));
+        const IDENTITY_ETAG: [u8; 18] = etag_bytes(fnv1a(IDENTITY));
+        const GZIP_ETAG: [u8; 18] = etag_bytes(fnv1a(GZIP));
+        const BR_ETAG: [u8; 18] = etag_bytes(fnv1a(BR));
+        const fn ascii(etag: &'static [u8]) -> &'static str {
+            match std::str::from_utf8(etag) {
+                Ok(etag) => etag,
+                Err(_) => panic!("ETag is not ASCII"),
+            }
+        }
+
+        EmbeddedAsset {
+            content_type: $content_type,
+            identity: Variant { encoding: Encoding::Identity, body: IDENTITY, etag: ascii(&IDENTITY_ETAG) },
+            gzip: Variant { encoding: Encoding::Gzip, body: GZIP, etag: ascii(&GZIP_ETAG) },
+            br: Variant { encoding: Encoding::Brotli, body: BR, etag: ascii(&BR_ETAG) },
+        }
+    }};
+}
+
+/// The default `/favicon.ico`
+pub const FAVICON: EmbeddedAsset = embedded_asset!("favicon.ico", "image/x-icon");
+
+impl EmbeddedAsset {
+    /// Every variant, smallest first; Brotli before gzip on a tie
+    pub fn variants(&self) -> [Variant; 3] {
+        let mut variants = [self.br, self.gzip, self.identity];
+        variants.sort_by_key(|variant| variant.body.len());
+        variants
+    }
+
+    /// The variant for a request with `headers`: the acceptable encoding
+    /// of highest quality, the smallest on a tie, `identity` when nothing
+    /// else is acceptable
+    pub fn negotiate(&self, headers: &HeaderMap) -> Variant {
+        self.variants()
+            .into_iter()
+            .filter_map(|variant| quality(headers, variant.encoding).map(|quality| (variant, quality)))
+            .filter(|(_, quality)| *quality > 0.0)
+            // Stable: keeps the smaller of equal qualities
+            .fold(None, |best: Option<(Variant, f32)>, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            })
+            .map_or(self.identity, |(variant, _)| variant)
+    }
+
+    /// The negotiated variant with `headers` (including `Cache-Control`
+    /// when given), or `304` when the request already holds it
+    pub fn response(&self, request: &HeaderMap, cache_control: Option<&'static str>) -> Response {
+        let variant = self.negotiate(request);
+        let mut response = (
+            [(header::CONTENT_TYPE, self.content_type), (header::VARY, "accept-encoding")],
+            variant.body,
+        )
+            .into_response();
+        let headers = response.headers_mut();
+        if let Some(cache_control) = cache_control {
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+        }
+        if variant.encoding != Encoding::Identity {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(variant.encoding.token()));
+        }
+        conditional::conditional_response(&HeaderValue::from_static(variant.etag), response, request)
+    }
+}
+
+/// Quality `Accept-Encoding` gives `encoding`: its own entry, else `*`.
+/// Without the header only `identity` is acceptable; `identity` is
+/// acceptable unless refused.
+fn quality(headers: &HeaderMap, encoding: Encoding) -> Option<f32> {
+    let mut values = headers.get_all(header::ACCEPT_ENCODING).iter().peekable();
+    if values.peek().is_none() {
+        return (encoding == Encoding::Identity).then_some(1.0);
+    }
+
+    // (coding, quality) in header order
+    let codings: Vec<(String, f32)> = values
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q=").and_then(|q| q.parse().ok()))
+                .unwrap_or(1.0);
+            (!name.is_empty()).then_some((name, quality))
+        })
+        .collect();
+    let find = |name: &str| codings.iter().find(|(coding, _)| coding == name).map(|(_, quality)| *quality);
+
+    find(encoding.token())
+        // `x-gzip` is the same coding
+        .or_else(|| (encoding == Encoding::Gzip).then(|| find("x-gzip")).flatten())
+        .or_else(|| find("*"))
+        .or_else(|| (encoding == Encoding::Identity).then_some(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept_encoding(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn negotiated(value: &str) -> Encoding {
+        FAVICON.negotiate(&accept_encoding(value)).encoding
+    }
+
+    #[test]
+    fn test_quality_decides_before_size() {
+        assert_eq!(negotiated("gzip;q=1, br;q=0.5"), Encoding::Gzip);
+        assert_eq!(negotiated("gzip;q=0.5, br"), Encoding::Brotli);
+        assert_eq!(negotiated("br;q=0, gzip;q=0"), Encoding::Identity);
+        assert_eq!(negotiated("x-gzip"), Encoding::Gzip);
+        assert_eq!(negotiated("deflate, zstd"), Encoding::Identity);
+        assert_eq!(FAVICON.negotiate(&HeaderMap::new()).encoding, Encoding::Identity);
+    }
+
+    #[test]
+    fn test_refused_identity_is_still_the_fallback() {
+        // Nothing acceptable: better the bytes than a 406
+        assert_eq!(negotiated("identity;q=0, zstd"), Encoding::Identity);
+        assert_eq!(quality(&accept_encoding("*;q=0"), Encoding::Identity), Some(0.0));
+        assert_eq!(quality(&accept_encoding("*;q=0, identity"), Encoding::Identity), Some(1.0));
+    }
+
+    #[test]
+    fn test_compressed_variants_are_smaller() {
+        for variant in [FAVICON.gzip, FAVICON.br] {
+            assert!(variant.body.len() < FAVICON.identity.body.len(), "{:?}", variant.encoding);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+/*!
+ * Audit log of admin actions
+ *
+ * Every state-changing request to the admin listener (`POST`, `PUT`,
+ * `PATCH`, `DELETE`) that passes the token check is recorded: who made
+ * it, the method and path, a summary of the body, the response status,
+ * the request ID and when. Entries go to a ring of the last
+ * `AUDIT_MAX_ENTRIES` (default 1000), served newest first at
+ * `GET /admin/audit`, and to the `audit` tracing target, so a log
+ * pipeline can keep them for longer (`RUST_LOG=info,audit=info`).
+ *
+ * The admin token only proves the caller may act; who they are comes from
+ * the request, first match wins:
+ * - the user of a Cloudflare Access token verified by [`crate::access`]
+ *   (never the bare `Cf-Access-Authenticated-User-Email` header, which
+ *   anyone holding the admin token could set)
+ * - an `X-Api-Key` naming one of the configured `API_KEYS`
+ * - otherwise the admin token itself, as `token:admin`
+ *
+ * Bodies are never stored verbatim. JSON bodies keep their shape with the
+ * values of secret-looking fields replaced and long strings cut; anything
+ * else is summarized as its size and content type. A failure to record
+ * never fails the action; it is counted instead
+ * (`audit_record_failures_total`, and `record_failures` in the report).
+ */
+use crate::access::AccessIdentity;
+use crate::request_id::{self, RequestId};
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+/// Largest body read for its summary; bigger or streamed bodies are only
+/// described
+const MAX_INSPECTED_BYTES: usize = 64 * 1024;
+
+/// Longest string kept in a body summary
+const MAX_STRING_CHARS: usize = 64;
+
+/// Longest body summary
+const MAX_SUMMARY_CHARS: usize = 1024;
+
+/// Field name fragments whose values never reach the log
+const SECRET_FIELDS: &[&str] = &["token", "secret", "password", "passwd", "key", "credential", "authorization", "cookie", "signature"];
+
+/// How many entries the ring keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AuditConfig {
+    pub max_entries: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self { max_entries: 1000 }
+    }
+}
+
+impl AuditConfig {
+    /// Load from `AUDIT_MAX_ENTRIES`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        if let Ok(value) = std::env::var("AUDIT_MAX_ENTRIES") {
+            config.max_entries = match value.trim().parse::<usize>() {
+                Ok(entries) if entries > 0 => entries,
+                _ => {
+                    return Err(crate::ServerError::ConfigError(format!(
+                        "Invalid AUDIT_MAX_ENTRIES {:?}: expected a positive number",
+                        value
+                    )))
+                }
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// Who made an admin request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "name", rename_all = "snake_case")]
+pub enum Identity {
+    /// User from a verified Cloudflare Access token
+    Access(String),
+
+    /// Name of a configured API key
+    ApiKey(String),
+
+    /// Name of the bearer token
+    Token(String),
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Access(email) => write!(f, "access:{}", email),
+            Self::ApiKey(name) => write!(f, "api_key:{}", name),
+            Self::Token(name) => write!(f, "token:{}", name),
+        }
+    }
+}
+
+/// Who sent an admin request with `headers`, verified as `access`
+pub fn identify(access: Option<&AccessIdentity>, headers: &HeaderMap, state: &AppState) -> Identity {
+    caller(access, headers, state).unwrap_or_else(|| Identity::Token("admin".to_string()))
+}
+
+/// The verified Cloudflare Access user, or else the configured API key
+/// `headers` carry, if any
+pub fn caller(access: Option<&AccessIdentity>, headers: &HeaderMap, state: &AppState) -> Option<Identity> {
+    if let Some(access) = access {
+        return Some(Identity::Access(access.name.clone()));
+    }
+    let config = state.config();
+    headers
+        .get(crate::api_keys::API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .zip(config.api_keys.as_ref())
+        .and_then(|(presented, api_keys)| api_keys.resolve(presented.trim()))
+        .map(|key| Identity::ApiKey(key.name.clone()))
+}
+
+/// One recorded admin action
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEntry {
+    /// Position in the log, counting from 1; gaps mean dropped entries
+    pub sequence: u64,
+    pub at: DateTime<Utc>,
+    pub identity: Identity,
+    pub method: String,
+    pub path: String,
+
+    /// Redacted summary of the request body; `None` without one
+    pub body: Option<String>,
+    pub status: u16,
+    pub request_id: String,
+}
+
+/// Recent entries, newest first
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditReport {
+    pub entries: Vec<AuditEntry>,
+
+    /// Entries that could not be added to the ring
+    pub record_failures: u64,
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+    entries: VecDeque<AuditEntry>,
+    sequence: u64,
+}
+
+/// The in-memory audit ring; clones share it
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    ring: Arc<Mutex<Ring>>,
+    failures: Arc<AtomicU64>,
+    #[cfg(feature = "metrics")]
+    failure_counter: Option<crate::metrics::Counter>,
+}
+
+impl AuditLog {
+    /// The same log, also counting failures in `metrics`; call before the
+    /// log is cloned
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: &crate::metrics::Metrics) -> Self {
+        self.failure_counter = Some(metrics.counter(
+            "audit_record_failures_total",
+            "Admin actions that could not be added to the audit log",
+        ));
+        self
+    }
+
+    /// Append `entry`, numbering it and dropping the oldest entries beyond
+    /// `config.max_entries`. A ring left poisoned by a panic is not written
+    /// to again; the failure is counted.
+    pub fn record(&self, mut entry: AuditEntry, config: &AuditConfig) -> Result<u64, AuditError> {
+        let Ok(mut ring) = self.ring.lock() else {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            if let Some(counter) = &self.failure_counter {
+                counter.inc();
+            }
+            return Err(AuditError);
+        };
+        ring.sequence += 1;
+        entry.sequence = ring.sequence;
+        ring.entries.push_back(entry);
+        while ring.entries.len() > config.max_entries {
+            ring.entries.pop_front();
+        }
+        Ok(ring.sequence)
+    }
+
+    /// Up to `limit` entries, newest first
+    pub fn recent(&self, limit: usize) -> AuditReport {
+        let entries = match self.ring.lock() {
+            Ok(ring) => ring.entries.iter().rev().take(limit).cloned().collect(),
+            Err(poisoned) => poisoned.into_inner().entries.iter().rev().take(limit).cloned().collect(),
+        };
+        AuditReport { entries, record_failures: self.failures.load(Ordering::Relaxed) }
+    }
+}
+
+/// The audit ring could not be written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditError;
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the audit log is unavailable")
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+/// A body summary safe to log: secret fields redacted, strings and the
+/// whole summary cut short
+pub fn summarize(headers: &HeaderMap, body: &[u8]) -> Option<String> {
+    if body.is_empty() {
+        return None;
+    }
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("no content type");
+    let summary = match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) if content_type.starts_with("application/json") || content_type.contains("+json") => {
+            redact(&mut value);
+            value.to_string()
+        }
+        _ => format!("<{} bytes of {}>", body.len(), content_type),
+    };
+    Some(truncate(summary, MAX_SUMMARY_CHARS))
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object.iter_mut() {
+                let name = name.to_ascii_lowercase();
+                if SECRET_FIELDS.iter().any(|secret| name.contains(secret)) {
+                    *value = Value::from(crate::redact::REDACTED);
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        Value::String(text) => *text = truncate(std::mem::take(text), MAX_STRING_CHARS),
+        _ => {}
+    }
+}
+
+fn truncate(text: String, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// Middleware recording state-changing admin requests
+pub async fn record(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !matches!(*request.method(), Method::POST | Method::PUT | Method::PATCH | Method::DELETE) {
+        return next.run(request).await;
+    }
+    let config = state.config();
+    let identity = identify(request.extensions().get(), request.headers(), &state);
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .or_else(|| {
+            let incoming = request.headers().get(&config.request_id.header)?.to_str().ok()?;
+            request_id::is_valid(incoming).then(|| incoming.to_string())
+        })
+        .unwrap_or_else(|| config.request_id.format.generate(state.clock.now()));
+
+    // Only bodies of known, modest size are read; the rest pass untouched
+    let (parts, body) = request.into_parts();
+    let exact_len = http_body::Body::size_hint(&body).exact();
+    let (summary, body) = match exact_len {
+        Some(0) => (None, body),
+        Some(len) if len <= MAX_INSPECTED_BYTES as u64 => match axum::body::to_bytes(body, MAX_INSPECTED_BYTES).await {
+            Ok(bytes) => (summarize(&parts.headers, &bytes), Body::from(bytes)),
+            Err(_) => {
+                return crate::error::ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_body",
+                    "The request body could not be read",
+                )
+                .into_response()
+            }
+        },
+        Some(len) => (Some(format!("<{} bytes, not inspected>", len)), body),
+        None => (Some("<streamed body, not inspected>".to_string()), body),
+    };
+
+    let mut response = next.run(Request::from_parts(parts, body)).await;
+
+    let status = response.status().as_u16();
+    info!(
+        target: "audit",
+        identity = %identity,
+        method = %method,
+        path = %path,
+        status,
+        request_id = %request_id,
+        body = summary.as_deref().unwrap_or(""),
+        "Admin action"
+    );
+    // Callers learn the ID their action was recorded under
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().entry(&config.request_id.header).or_insert(value);
+    }
+    let entry = AuditEntry { sequence: 0, at: state.clock.now_utc(), identity, method, path, body: summary, status, request_id };
+    if state.audit.record(entry, &config.audit).is_err() {
+        tracing::error!("Failed to add an admin action to the audit log");
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summaries_hide_secrets() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let body = serde_json::json!({
+            "zone": "example.com",
+            "api_token": "cf-secret-123",
+            "nested": [{ "Client-Secret": "hunter2", "note": "x".repeat(100) }],
+        });
+
+        let summary = summarize(&headers, body.to_string().as_bytes()).unwrap();
+        assert!(!summary.contains("cf-secret-123") && !summary.contains("hunter2"), "{}", summary);
+        assert!(summary.contains(r#""api_token":"<redacted>""#), "{}", summary);
+        assert!(summary.contains(&format!("{}...", "x".repeat(MAX_STRING_CHARS))), "{}", summary);
+
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        assert_eq!(summarize(&headers, b"password=hunter2").unwrap(), "<16 bytes of text/plain>");
+        assert_eq!(summarize(&headers, b""), None);
+    }
+
+    #[test]
+    fn test_poisoned_ring_is_a_counted_failure() {
+        let log = AuditLog::default();
+        let poisoner = log.clone();
+        let _ = std::thread::spawn(move || {
+            let _ring = poisoner.ring.lock().unwrap();
+            panic!("poison the ring");
+        })
+        .join();
+
+        let entry = AuditEntry {
+            sequence: 0,
+            at: Utc::now(),
+            identity: Identity::Token("admin".to_string()),
+            method: "POST".to_string(),
+            path: "/admin/maintenance".to_string(),
+            body: None,
+            status: 200,
+            request_id: "test".to_string(),
+        };
+        assert_eq!(log.record(entry, &AuditConfig::default()), Err(AuditError));
+        assert_eq!(log.recent(10).record_failures, 1);
+    }
+}
@@ -0,0 +1,208 @@
+/*!
+ * Bearer-token protection for path prefixes
+ *
+ * A stopgap for routes that should not be public before Cloudflare Access
+ * is in front of them. `AUTH_RULES` names groups of prefixes sharing a set
+ * of tokens (e.g. `debug,ops`); each is configured through `AUTH_<NAME>_*`,
+ * with `<NAME>` upper-cased and `-` turned into `_`:
+ *
+ * - `AUTH_<NAME>_PREFIXES`: comma-separated path prefixes, matched on
+ *   segment boundaries
+ * - `AUTH_<NAME>_TOKENS` (or `_TOKENS_FILE`): accepted tokens, separated by
+ *   commas or newlines; listing old and new together allows rotation
+ *
+ * A request under a protected prefix (the longest matching one, if several
+ * do) needs `Authorization: Bearer <token>` matching one of its tokens,
+ * compared in constant time; otherwise it gets 401 with
+ * `WWW-Authenticate: Bearer`. Other paths are untouched.
+ */
+use crate::admin::constant_time_eq;
+use crate::config::AppConfig;
+use crate::denial::{Denial, DenialReason};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Path prefixes guarded by a shared set of tokens
+#[derive(Clone)]
+pub struct AuthRule {
+    /// Group name from `AUTH_RULES`, used in logs
+    pub name: String,
+
+    /// Protected prefixes, e.g. `/whoami`
+    pub prefixes: Vec<String>,
+
+    /// Accepted bearer tokens
+    pub tokens: Vec<String>,
+}
+
+impl std::fmt::Debug for AuthRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthRule")
+            .field("name", &self.name)
+            .field("prefixes", &self.prefixes)
+            .field("tokens", &format_args!("<{} redacted>", self.tokens.len()))
+            .finish()
+    }
+}
+
+impl AuthRule {
+    /// Rule `name` protecting `prefixes` with `tokens`
+    pub fn new<P, T>(name: impl Into<String>, prefixes: P, tokens: T) -> Self
+    where
+        P: IntoIterator,
+        P::Item: Into<String>,
+        T: IntoIterator,
+        T::Item: Into<String>,
+    {
+        Self {
+            name: name.into(),
+            prefixes: prefixes.into_iter().map(Into::into).collect(),
+            tokens: tokens.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Load every rule named in `AUTH_RULES`
+    pub fn from_env() -> crate::Result<Vec<Self>> {
+        let Ok(names) = std::env::var("AUTH_RULES") else {
+            return Ok(Vec::new());
+        };
+
+        let mut rules: Vec<Self> = Vec::new();
+        for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            if rules.iter().any(|rule| rule.name == name) {
+                return Err(crate::ServerError::ConfigError(format!("Auth rule {:?} is listed twice", name)));
+            }
+            rules.push(Self::load(name)?);
+        }
+        Ok(rules)
+    }
+
+    fn load(name: &str) -> crate::Result<Self> {
+        let prefix = format!("AUTH_{}", name.to_ascii_uppercase().replace('-', "_"));
+
+        let prefixes = std::env::var(format!("{}_PREFIXES", prefix)).unwrap_or_default();
+        let tokens = crate::config::read_secret(&format!("{}_TOKENS", prefix))?.unwrap_or_default();
+        let rule = Self::new(
+            name,
+            prefixes.split(',').map(str::trim).filter(|p| !p.is_empty()),
+            tokens.split([',', '\n']).map(str::trim).filter(|t| !t.is_empty()),
+        );
+
+        if rule.prefixes.is_empty() {
+            return Err(crate::ServerError::ConfigError(format!(
+                "Auth rule {:?} needs {}_PREFIXES",
+                name, prefix
+            )));
+        }
+        if rule.tokens.is_empty() {
+            return Err(crate::ServerError::ConfigError(format!(
+                "Auth rule {:?} needs {}_TOKENS or {}_TOKENS_FILE",
+                name, prefix, prefix
+            )));
+        }
+        if let Some(bad) = rule.prefixes.iter().find(|p| !p.starts_with('/')) {
+            return Err(crate::ServerError::ConfigError(format!(
+                "Auth prefix {:?} must start with /",
+                bad
+            )));
+        }
+        Ok(rule)
+    }
+
+    /// Length of the longest prefix of this rule matching `path`
+    fn matched_len(&self, path: &str) -> Option<usize> {
+        longest_prefix(&self.prefixes, path)
+    }
+
+    /// Whether `token` is one of the rule's tokens; every token is compared
+    /// so timing does not reveal which one matched
+    fn accepts(&self, token: &str) -> bool {
+        self.tokens
+            .iter()
+            .fold(false, |accepted, candidate| constant_time_eq(token.as_bytes(), candidate.as_bytes()) | accepted)
+    }
+}
+
+/// Length of the longest of `prefixes` matching `path` on a segment boundary
+pub(crate) fn longest_prefix(prefixes: &[String], path: &str) -> Option<usize> {
+    prefixes
+        .iter()
+        .map(|prefix| prefix.trim_end_matches('/'))
+        .filter(|prefix| prefix.is_empty() || path == *prefix || path.starts_with(&format!("{}/", prefix)))
+        .map(str::len)
+        .max()
+}
+
+/// 401 denial with `WWW-Authenticate`, as RFC 6750 describes for bearer
+/// tokens
+pub fn unauthorized(challenge: &'static str, message: &str) -> Response {
+    let mut response = Denial::new(DenialReason::Unauthenticated, message).into_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+    response
+}
+
+/// Middleware requiring a bearer token on protected prefixes
+pub async fn require_bearer(State(config): State<Arc<AppConfig>>, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    let Some(rule) = config
+        .auth
+        .iter()
+        .filter_map(|rule| rule.matched_len(path).map(|len| (len, rule)))
+        .max_by_key(|(len, _)| *len)
+        .map(|(_, rule)| rule)
+    else {
+        return next.run(request).await;
+    };
+
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    match presented {
+        Some(token) if rule.accepts(token) => next.run(request).await,
+        Some(_) => {
+            warn!(rule = %rule.name, "Rejected invalid bearer token for {}", path);
+            unauthorized("Bearer error=\"invalid_token\"", "The bearer token is not valid")
+        }
+        None => {
+            warn!(rule = %rule.name, "Rejected request without a bearer token for {}", path);
+            unauthorized("Bearer", "A bearer token is required")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_prefix_on_segment_boundaries() {
+        let rule = AuthRule::new("debug", ["/echo", "/status/"], ["t"]);
+
+        assert_eq!(rule.matched_len("/echo"), Some(5));
+        assert_eq!(rule.matched_len("/echo/deeper"), Some(5));
+        assert_eq!(rule.matched_len("/echoes"), None);
+        assert_eq!(rule.matched_len("/status/418"), Some(7));
+    }
+
+    #[test]
+    fn test_accepts_any_listed_token() {
+        let rule = AuthRule::new("ops", ["/ops"], ["old-token", "new-token"]);
+
+        assert!(rule.accepts("old-token"));
+        assert!(rule.accepts("new-token"));
+        assert!(!rule.accepts("new-toke"));
+        assert!(!rule.accepts(""));
+    }
+}
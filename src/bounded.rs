@@ -0,0 +1,390 @@
+/*!
+ * Capacity- and TTL-bounded maps for per-key state
+ *
+ * Every store keyed by something a client controls holds its entries in a
+ * [`BoundedMap`], so its memory has a ceiling however many keys arrive:
+ *
+ * - `api_keys`: rate-limit windows, one per configured key, each dropped
+ *   when its window ends
+ * - `idempotency`: `IDEMPOTENCY_MAX_KEYS` keys, each kept for
+ *   `IDEMPOTENCY_TTL_SECS`
+ * - `response_cache`: `RESPONSE_CACHE_MAX_ENTRIES` entries within
+ *   `RESPONSE_CACHE_MAX_BYTES`, each kept until it is too stale to serve
+ * - `unknown_paths`: `UNKNOWN_PATHS_MAX` paths
+ *
+ * A full map evicts the least recently used entry (stores can pick another
+ * victim with [`BoundedMap::evict`]), and expired entries are dropped when
+ * they are next looked up. A task on the supervisor ([`SWEEP_TASK`]) also
+ * sweeps expired entries out every [`SWEEP_INTERVAL`], including a capture
+ * session past its TTL, so keys that are never looked up again do not
+ * linger. Canary assignments live in the visitor's cookie and route
+ * statistics are keyed by route template, so neither needs a map.
+ *
+ * Each store reports its entries, capacity, evictions and expirations at
+ * `GET /admin/stats` and as `store_entries` and `store_evictions_total`
+ * series at `/metrics`.
+ */
+use crate::clock::Clock;
+use crate::state::AppState;
+use crate::tasks::Schedule;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Name of the task sweeping expired entries
+pub const SWEEP_TASK: &str = "store-sweep";
+
+/// How often [`SWEEP_TASK`] runs
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Size and turnover of one store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    pub entries: usize,
+    pub capacity: usize,
+
+    /// Entries dropped to make room
+    pub evictions: u64,
+
+    /// Entries dropped because their TTL passed
+    pub expirations: u64,
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    value: V,
+    expires_at: Option<Instant>,
+
+    /// Position in `BoundedMap::recency`
+    used: u64,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Debug)]
+struct MapMetrics {
+    entries: crate::metrics::Gauge,
+    evictions: crate::metrics::Counter,
+    expirations: crate::metrics::Counter,
+}
+
+/// Map from string keys holding at most `capacity` entries, each for at
+/// most its TTL as measured by the clock. Not shared: stores keep it behind
+/// their own lock.
+#[derive(Debug)]
+pub struct BoundedMap<V> {
+    name: &'static str,
+    clock: Arc<dyn Clock>,
+    capacity: usize,
+    ttl: Option<Duration>,
+    entries: HashMap<String, Slot<V>>,
+
+    /// Keys by last use, oldest first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    evictions: u64,
+    expirations: u64,
+    #[cfg(feature = "metrics")]
+    metrics: Option<MapMetrics>,
+}
+
+impl<V> BoundedMap<V> {
+    /// Empty map for the store `name`; entries inserted without their own
+    /// TTL keep `ttl`, or never expire when it is `None`
+    pub fn new(name: &'static str, capacity: usize, ttl: Option<Duration>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            name,
+            clock,
+            capacity: capacity.max(1),
+            ttl,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            evictions: 0,
+            expirations: 0,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Report the entry count and evictions as `store_entries` and
+    /// `store_evictions_total` series labelled with the store's name
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: &crate::metrics::Metrics) {
+        let series = |reason: &str| format!("store_evictions_total{{store=\"{}\",reason=\"{}\"}}", self.name, reason);
+        let help = "Entries dropped from in-memory stores, to make room or because they expired";
+        let map_metrics = MapMetrics {
+            entries: metrics.gauge(&format!("store_entries{{store=\"{}\"}}", self.name), "Entries held by in-memory stores"),
+            evictions: metrics.counter(&series("capacity"), help),
+            expirations: metrics.counter(&series("expired"), help),
+        };
+        map_metrics.entries.set(self.entries.len() as i64);
+        self.metrics = Some(map_metrics);
+    }
+
+    /// Change the limits, as after a configuration reload; entries beyond
+    /// the new capacity are evicted, and the new TTL applies to later
+    /// inserts
+    pub fn set_limits(&mut self, capacity: usize, ttl: Option<Duration>) {
+        self.capacity = capacity.max(1);
+        self.ttl = ttl;
+        while self.entries.len() > self.capacity {
+            self.pop_oldest();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether an insert of a new key would evict
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= self.capacity
+    }
+
+    /// The live entry under `key`, which becomes the most recently used;
+    /// an expired one is dropped
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    /// [`get`](Self::get), mutably
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        let now = self.clock.monotonic();
+        if self.entries.get(key)?.expires_at.is_some_and(|at| at <= now) {
+            self.expire(key);
+            return None;
+        }
+        self.touch(key);
+        self.entries.get_mut(key).map(|slot| &mut slot.value)
+    }
+
+    /// The live entry under `key`, inserting `value()` with the map's TTL
+    /// when there is none
+    pub fn get_or_insert_with(&mut self, key: &str, value: impl FnOnce() -> V) -> &mut V {
+        if self.get(key).is_none() {
+            self.insert(key.to_string(), value());
+        }
+        &mut self.entries.get_mut(key).expect("present or just inserted").value
+    }
+
+    /// Mark `key` as the most recently used
+    pub fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(slot) = self.entries.get_mut(key) {
+            self.recency.remove(&slot.used);
+            slot.used = tick;
+            self.recency.insert(tick, key.to_string());
+        }
+    }
+
+    /// Restart the TTL of `key`, e.g. once a running operation completes
+    pub fn renew(&mut self, key: &str) {
+        let expires_at = self.expiry(self.ttl);
+        if let Some(slot) = self.entries.get_mut(key) {
+            slot.expires_at = expires_at;
+        }
+    }
+
+    /// Store `value` under `key` with the map's TTL, evicting the least
+    /// recently used entries if the map is full
+    pub fn insert(&mut self, key: String, value: V) {
+        let ttl = self.ttl;
+        self.insert_for(key, value, ttl);
+    }
+
+    /// [`insert`](Self::insert), expiring after `ttl` instead
+    pub fn insert_for(&mut self, key: String, value: V, ttl: Option<Duration>) {
+        self.remove(&key);
+        while self.is_full() {
+            self.pop_oldest();
+        }
+        self.tick += 1;
+        let slot = Slot { value, expires_at: self.expiry(ttl), used: self.tick };
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, slot);
+        self.update_gauge();
+    }
+
+    /// When an entry stored now with `ttl` expires; a TTL too long to
+    /// represent never does
+    fn expiry(&self, ttl: Option<Duration>) -> Option<Instant> {
+        ttl.and_then(|ttl| self.clock.monotonic().checked_add(ttl))
+    }
+
+    /// Drop `key`, as its store decided; not counted as an eviction
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let slot = self.entries.remove(key)?;
+        self.recency.remove(&slot.used);
+        self.update_gauge();
+        Some(slot.value)
+    }
+
+    /// Drop `key` to make room, counted as an eviction
+    pub fn evict(&mut self, key: &str) -> Option<V> {
+        let value = self.remove(key)?;
+        debug!(store = self.name, key, "Evicting an entry");
+        self.evictions += 1;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.evictions.inc();
+        }
+        Some(value)
+    }
+
+    /// Evict the least recently used entry
+    pub fn pop_oldest(&mut self) -> Option<(String, V)> {
+        let oldest = self.recency.first_key_value()?.1.clone();
+        self.evict(&oldest).map(|value| (oldest, value))
+    }
+
+    fn expire(&mut self, key: &str) {
+        if self.remove(key).is_some() {
+            self.expirations += 1;
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &self.metrics {
+                metrics.expirations.inc();
+            }
+        }
+    }
+
+    /// Drop every expired entry, returning how many went
+    pub fn sweep(&mut self) -> usize {
+        let now = self.clock.monotonic();
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, slot)| slot.expires_at.is_some_and(|at| at <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.expire(key);
+        }
+        expired.len()
+    }
+
+    /// Every entry, expired ones included until they are swept
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.entries.iter().map(|(key, slot)| (key.as_str(), &slot.value))
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats {
+            entries: self.entries.len(),
+            capacity: self.capacity,
+            evictions: self.evictions,
+            expirations: self.expirations,
+        }
+    }
+
+    fn update_gauge(&self) {
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.entries.set(self.entries.len() as i64);
+        }
+    }
+}
+
+/// Size and turnover of every per-key store in `state`, by name
+pub fn stats(state: &AppState) -> BTreeMap<&'static str, StoreStats> {
+    BTreeMap::from([
+        ("api_keys", state.api_key_limiter.stats()),
+        ("idempotency", state.idempotency.stats()),
+        ("response_cache", state.response_cache.stats()),
+        ("unknown_paths", state.unknown_paths.stats()),
+    ])
+}
+
+/// Drop expired entries from every store in `state`, returning how many
+/// went
+pub fn sweep(state: &AppState) -> usize {
+    state.capture.sweep();
+    state.api_key_limiter.sweep() + state.idempotency.sweep() + state.response_cache.sweep() + state.unknown_paths.sweep()
+}
+
+/// Register [`SWEEP_TASK`] with `state.tasks`
+pub fn watch(state: &AppState) {
+    let swept = state.clone();
+    state.tasks.spawn(SWEEP_TASK, Schedule::every(SWEEP_INTERVAL), move || {
+        let state = swept.clone();
+        async move {
+            let expired = sweep(&state);
+            if expired > 0 {
+                debug!(expired, "Swept expired entries from in-memory stores");
+            }
+            Ok::<_, std::convert::Infallible>(())
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestClock;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_least_recently_used_goes_first() {
+        let mut map = BoundedMap::new("test", 2, None, Arc::new(TestClock::new(SystemTime::UNIX_EPOCH)));
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        map.touch("a");
+        map.insert("c".to_string(), 3);
+
+        assert_eq!(map.get("b"), None);
+        assert_eq!(map.get("a"), Some(&1));
+        assert_eq!(map.get("c"), Some(&3));
+        assert_eq!(map.stats(), StoreStats { entries: 2, capacity: 2, evictions: 1, expirations: 0 });
+
+        map.set_limits(1, None);
+        assert_eq!((map.len(), map.stats().evictions), (1, 2));
+    }
+
+    #[test]
+    fn test_mutated_entries_are_recently_used() {
+        let mut map = BoundedMap::new("test", 2, None, Arc::new(TestClock::new(SystemTime::UNIX_EPOCH)));
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        *map.get_mut("a").unwrap() += 10;
+        map.insert("c".to_string(), 3);
+
+        assert_eq!(map.get("b"), None);
+        assert_eq!(map.get("a"), Some(&11));
+        assert_eq!(map.get("c"), Some(&3));
+    }
+
+    #[test]
+    fn test_huge_ttls_never_expire() {
+        let clock = Arc::new(TestClock::new(SystemTime::UNIX_EPOCH));
+        let mut map = BoundedMap::new("test", 10, Some(Duration::from_secs(u64::MAX)), clock.clone());
+        map.insert("a".to_string(), ());
+        map.insert_for("b".to_string(), (), Some(Duration::MAX));
+        map.renew("a");
+
+        clock.advance(Duration::from_secs(100 * 365 * 86_400));
+        assert_eq!(map.get("a"), Some(&()));
+        assert_eq!(map.get("b"), Some(&()));
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let clock = Arc::new(TestClock::new(SystemTime::UNIX_EPOCH));
+        let mut map = BoundedMap::new("test", 10, Some(Duration::from_secs(60)), clock.clone());
+        map.insert("short".to_string(), ());
+        map.insert_for("long".to_string(), (), Some(Duration::from_secs(600)));
+        map.insert_for("forever".to_string(), (), None);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(map.get("short"), None);
+        clock.advance(Duration::from_secs(540));
+        assert_eq!(map.sweep(), 1);
+        assert_eq!(map.iter().map(|(key, _)| key).collect::<Vec<_>>(), ["forever"]);
+        assert_eq!(map.stats().expirations, 2);
+    }
+}
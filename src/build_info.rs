@@ -0,0 +1,83 @@
+/*!
+ * Build and configuration fingerprint headers
+ *
+ * With `EXPOSE_BUILD_HEADERS=true` every response names the deployment
+ * that produced it, so a response a user reports can be matched to a
+ * commit and a security header policy:
+ *
+ * - `X-Build-Id`: short git commit, embedded at compile time by `build.rs`
+ * - `X-Config-Hash`: first 12 hex digits of the SHA-256 of the effective
+ *   `SecurityConfig` serialized as JSON. Struct fields serialize in
+ *   declaration order, so identical settings give the same hash on every
+ *   restart. `AppState` computes it when the configuration is created or
+ *   replaced, not per request.
+ *
+ * Both are off by default, since they tell anyone which build is running.
+ */
+use crate::config::SecurityConfig;
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+
+/// Short git commit the binary was built from, or `unknown`
+pub const BUILD_ID: &str = env!("GIT_SHA");
+
+pub const BUILD_ID_HEADER: HeaderName = HeaderName::from_static("x-build-id");
+pub const CONFIG_HASH_HEADER: HeaderName = HeaderName::from_static("x-config-hash");
+
+/// Whether `EXPOSE_BUILD_HEADERS` asks for the headers
+pub fn from_env() -> crate::Result<bool> {
+    match std::env::var("EXPOSE_BUILD_HEADERS") {
+        Ok(value) => value.parse().map_err(|e| crate::ServerError::ConfigError(
+            format!("Invalid EXPOSE_BUILD_HEADERS flag: {}", e)
+        )),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Stable short fingerprint of `security`
+pub fn config_hash(security: &SecurityConfig) -> String {
+    let json = serde_json::to_vec(security).unwrap_or_default();
+    Sha256::digest(&json)[..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Middleware adding `X-Build-Id` and `X-Config-Hash` when enabled
+pub async fn build_headers(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let enabled = state.config().expose_build_headers;
+    let mut response = next.run(request).await;
+    if !enabled {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    if let Ok(build_id) = HeaderValue::from_str(BUILD_ID) {
+        headers.insert(BUILD_ID_HEADER, build_id);
+    }
+    if let Ok(hash) = HeaderValue::from_str(&state.config_hash()) {
+        headers.insert(CONFIG_HASH_HEADER, hash);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_is_stable_and_follows_changes() {
+        let hash = config_hash(&SecurityConfig::default());
+        assert_eq!(hash.len(), 12);
+        assert_eq!(config_hash(&SecurityConfig::default()), hash);
+
+        let changed = SecurityConfig { frame_options: "SAMEORIGIN".to_string(), ..SecurityConfig::default() };
+        assert_ne!(config_hash(&changed), hash);
+    }
+}
@@ -0,0 +1,255 @@
+/*!
+ * Route-level cache policy
+ *
+ * Maps path prefixes to caching rules that are turned into `Cache-Control`
+ * (browsers) and `CDN-Cache-Control` (Cloudflare's edge) response headers.
+ * Health and metrics endpoints are always `no-store`, and a handler that sets
+ * its own `Cache-Control` is left alone.
+ *
+ * Rules are loaded from `CACHE_POLICIES` as a JSON array, e.g.
+ * `[{"prefix": "/", "browser_ttl": 60, "edge_ttl": 60}, {"prefix": "/api", "no_store": true}]`.
+ */
+use crate::config::AppConfig;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Cloudflare-specific cache header (RFC 9213 targeted cache control)
+pub const CDN_CACHE_CONTROL: HeaderName = HeaderName::from_static("cdn-cache-control");
+
+/// Paths that must never be cached, regardless of configuration
+pub const NO_STORE_PATHS: &[&str] = &["/health", "/readyz", "/status", "/metrics"];
+
+/// Longest TTL accepted for either cache (one year)
+const MAX_TTL: u32 = 31_536_000;
+
+/// Caching rule for one path prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheRule {
+    /// Path prefix, matched on segment boundaries (`/static` matches
+    /// `/static/app.css` but not `/statics`)
+    pub prefix: String,
+
+    /// Browser cache lifetime in seconds
+    #[serde(default)]
+    pub browser_ttl: u32,
+
+    /// Cloudflare edge cache lifetime in seconds
+    #[serde(default)]
+    pub edge_ttl: u32,
+
+    /// Forbid caching entirely
+    #[serde(default)]
+    pub no_store: bool,
+}
+
+impl CacheRule {
+    fn matches(&self, path: &str) -> bool {
+        if self.prefix == "/" {
+            return true;
+        }
+        let prefix = self.prefix.trim_end_matches('/');
+        path == prefix || path.starts_with(&format!("{}/", prefix))
+    }
+}
+
+/// Cache rules, matched by longest prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheConfig {
+    pub rules: Vec<CacheRule>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            rules: vec![CacheRule {
+                prefix: "/".to_string(),
+                browser_ttl: 60,
+                edge_ttl: 60,
+                no_store: false,
+            }],
+        }
+    }
+}
+
+/// Header values to apply for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheHeaders {
+    pub cache_control: String,
+    pub cdn_cache_control: String,
+}
+
+impl CacheConfig {
+    /// Load rules from `CACHE_POLICIES`, falling back to the defaults
+    pub fn from_env() -> crate::Result<Self> {
+        let config = match std::env::var("CACHE_POLICIES") {
+            Ok(value) => Self {
+                rules: serde_json::from_str(&value)
+                    .map_err(|e| crate::ServerError::ConfigError(
+                        format!("Invalid CACHE_POLICIES: {}", e)
+                    ))?,
+            },
+            Err(_) => Self::default(),
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reject malformed prefixes, duplicates, excessive TTLs and rules that
+    /// combine `no_store` with a TTL
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(crate::ServerError::ConfigError(msg));
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.prefix.starts_with('/') || rule.prefix.contains(['?', '#']) {
+                return invalid(format!("Cache rule prefix {:?} must be a path starting with /", rule.prefix));
+            }
+            if self.rules[..i].iter().any(|other| other.prefix == rule.prefix) {
+                return invalid(format!("Duplicate cache rule prefix {:?}", rule.prefix));
+            }
+            if rule.browser_ttl > MAX_TTL || rule.edge_ttl > MAX_TTL {
+                return invalid(format!("Cache rule {:?} TTL exceeds {} seconds", rule.prefix, MAX_TTL));
+            }
+            if rule.no_store && (rule.browser_ttl > 0 || rule.edge_ttl > 0) {
+                return invalid(format!("Cache rule {:?} sets no_store together with a TTL", rule.prefix));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Most specific rule matching `path`
+    pub fn rule_for(&self, path: &str) -> Option<&CacheRule> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(path))
+            .max_by_key(|rule| rule.prefix.len())
+    }
+
+    /// Headers to send for `path`, if any rule applies
+    pub fn headers_for(&self, path: &str) -> Option<CacheHeaders> {
+        if NO_STORE_PATHS.contains(&path) {
+            return Some(CacheHeaders::no_store());
+        }
+
+        let rule = self.rule_for(path)?;
+        if rule.no_store {
+            return Some(CacheHeaders::no_store());
+        }
+
+        Some(CacheHeaders {
+            cache_control: format!("public, max-age={}", rule.browser_ttl),
+            cdn_cache_control: format!("max-age={}", rule.edge_ttl),
+        })
+    }
+}
+
+impl CacheHeaders {
+    fn no_store() -> Self {
+        Self {
+            cache_control: "no-store".to_string(),
+            cdn_cache_control: "no-store".to_string(),
+        }
+    }
+}
+
+/// Middleware applying the cache policy to successful GET/HEAD responses
+pub async fn cache_policy(State(config): State<Arc<AppConfig>>, request: Request, next: Next) -> Response {
+    let cacheable_method = request.method() == Method::GET || request.method() == Method::HEAD;
+    let path = request.uri().path().to_string();
+
+    let mut response = next.run(request).await;
+
+    // A 304 carries the same freshness lifetime as the 200 it revalidates
+    let status = response.status();
+    if !cacheable_method || !(status.is_success() || status == StatusCode::NOT_MODIFIED) {
+        return response;
+    }
+    if response.headers().contains_key(header::CACHE_CONTROL) {
+        return response;
+    }
+
+    if let Some(values) = config.cache.headers_for(&path) {
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&values.cache_control) {
+            headers.insert(header::CACHE_CONTROL, value);
+        }
+        if let Ok(value) = HeaderValue::from_str(&values.cdn_cache_control) {
+            headers.insert(CDN_CACHE_CONTROL, value);
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(prefix: &str, browser_ttl: u32, edge_ttl: u32) -> CacheRule {
+        CacheRule {
+            prefix: prefix.to_string(),
+            browser_ttl,
+            edge_ttl,
+            no_store: false,
+        }
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let config = CacheConfig {
+            rules: vec![rule("/", 60, 60), rule("/static", 3600, 86400)],
+        };
+
+        assert_eq!(config.rule_for("/static/app.css").map(|r| r.edge_ttl), Some(86400));
+        assert_eq!(config.rule_for("/statics").map(|r| r.edge_ttl), Some(60));
+        assert_eq!(config.rule_for("/").map(|r| r.edge_ttl), Some(60));
+    }
+
+    #[test]
+    fn test_health_is_always_no_store() {
+        let config = CacheConfig {
+            rules: vec![rule("/health", 600, 600)],
+        };
+
+        let headers = config.headers_for("/health").expect("Missing cache headers");
+        assert_eq!(headers.cache_control, "no-store");
+        assert_eq!(headers.cdn_cache_control, "no-store");
+    }
+
+    #[test]
+    fn test_validation_rejects_bad_rules() {
+        let bad_prefix = CacheConfig { rules: vec![rule("static", 1, 1)] };
+        assert!(bad_prefix.validate().is_err());
+
+        let duplicate = CacheConfig { rules: vec![rule("/a", 1, 1), rule("/a", 2, 2)] };
+        assert!(duplicate.validate().is_err());
+
+        let too_long = CacheConfig { rules: vec![rule("/a", MAX_TTL + 1, 1)] };
+        assert!(too_long.validate().is_err());
+
+        let contradictory = CacheConfig {
+            rules: vec![CacheRule { no_store: true, ..rule("/a", 5, 0) }],
+        };
+        assert!(contradictory.validate().is_err());
+
+        assert!(CacheConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_rules_parse_from_json() {
+        let rules: Vec<CacheRule> = serde_json::from_str(
+            r#"[{"prefix": "/", "browser_ttl": 60, "edge_ttl": 120}, {"prefix": "/api", "no_store": true}]"#,
+        ).expect("Failed to parse rules");
+
+        assert_eq!(rules[0], rule("/", 60, 120));
+        assert!(rules[1].no_store);
+    }
+}
@@ -0,0 +1,335 @@
+/*!
+ * Weighted canary experiments
+ *
+ * `EXPERIMENTS` is a JSON list of experiments, each sending `percent` of
+ * visitors to its `canary` variant and the rest to `control`:
+ *
+ * ```text
+ * EXPERIMENTS='[{"name": "checkout", "percent": 10, "cookie_name": "exp_checkout"}]'
+ * ```
+ *
+ * A visitor keeps its variant in the experiment's cookie (`exp_<name>`
+ * unless `cookie_name` is given). A request without a valid cookie is
+ * assigned by hashing the client IP with the experiment's `salt` (its name
+ * unless given), and the response sets the cookie for 30 days; the
+ * `Set-Cookie` goes through the cookie hardening like any other. Requests
+ * with no client IP are assigned at random.
+ *
+ * Handlers read the request's variants through the [`Assignments`]
+ * extractor, and the access log records them. Each response names the
+ * variant in `X-Experiment-<name>` and carries `Vary: Cookie`, so caches
+ * that honor it (the response cache here, browsers) keep the variants
+ * apart. The probes in [`maintenance::EXEMPT_PATHS`] are left out.
+ */
+use crate::client_ip::ClientIp;
+use crate::conditional::fnv1a;
+use crate::maintenance;
+use crate::state::AppState;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::fmt;
+
+/// Lifetime of a variant cookie
+const COOKIE_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// Resolution of `percent`: buckets per visitor hash
+const BUCKETS: u64 = 10_000;
+
+/// The arm of an experiment a visitor is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    Control,
+    Canary,
+}
+
+impl Variant {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Control => "control",
+            Self::Canary => "canary",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "control" => Some(Self::Control),
+            "canary" => Some(Self::Canary),
+            _ => None,
+        }
+    }
+}
+
+/// An experiment as configured in `EXPERIMENTS`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExperimentSpec {
+    name: String,
+    percent: f64,
+    cookie_name: Option<String>,
+    salt: Option<String>,
+}
+
+/// One experiment
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Experiment {
+    pub name: String,
+
+    /// Share of visitors in the canary, 0 to 100
+    pub percent: f64,
+
+    /// Cookie keeping a visitor's variant
+    pub cookie_name: String,
+
+    /// Mixed into the hash that assigns visitors without a cookie
+    #[serde(skip)]
+    pub salt: String,
+}
+
+impl Experiment {
+    /// `percent` of visitors in the canary, with the default cookie and salt
+    pub fn new(name: impl Into<String>, percent: f64) -> Self {
+        let name = name.into();
+        Self { cookie_name: format!("exp_{}", name), salt: name.clone(), name, percent }
+    }
+
+    /// The variant for a visitor without a cookie, identified by `key`
+    pub fn bucket(&self, key: &str) -> Variant {
+        let hash = mix(fnv1a(format!("{}\0{}", self.salt, key).as_bytes()));
+        let threshold = (self.percent * (BUCKETS / 100) as f64).round() as u64;
+        if hash % BUCKETS < threshold {
+            Variant::Canary
+        } else {
+            Variant::Control
+        }
+    }
+
+    /// `X-Experiment-<name>`
+    fn header_name(&self) -> Option<HeaderName> {
+        HeaderName::try_from(format!("x-experiment-{}", self.name.to_ascii_lowercase())).ok()
+    }
+}
+
+/// Spread FNV-1a's output over the low bits the bucket is taken from
+/// (MurmurHash3's finalizer)
+fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// The configured experiments
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct CanaryConfig {
+    pub experiments: Vec<Experiment>,
+}
+
+impl CanaryConfig {
+    /// Load `EXPERIMENTS`
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("EXPERIMENTS") {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    /// Parse and check an `EXPERIMENTS` list
+    pub fn parse(value: &str) -> crate::Result<Self> {
+        let invalid = |message: String| crate::ServerError::ConfigError(format!("Invalid EXPERIMENTS: {}", message));
+        let specs: Vec<ExperimentSpec> = serde_json::from_str(value).map_err(|e| invalid(e.to_string()))?;
+
+        let mut experiments: Vec<Experiment> = Vec::new();
+        for spec in specs {
+            let token = |value: &str| {
+                !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            };
+            if !token(&spec.name) {
+                return Err(invalid(format!("experiment name {:?} may only use letters, digits, - and _", spec.name)));
+            }
+            if !(0.0..=100.0).contains(&spec.percent) {
+                return Err(invalid(format!("percent of {:?} must be between 0 and 100", spec.name)));
+            }
+            let mut experiment = Experiment::new(spec.name, spec.percent);
+            if let Some(cookie_name) = spec.cookie_name {
+                if !token(&cookie_name) {
+                    return Err(invalid(format!("cookie name {:?} may only use letters, digits, - and _", cookie_name)));
+                }
+                experiment.cookie_name = cookie_name;
+            }
+            if let Some(salt) = spec.salt {
+                experiment.salt = salt;
+            }
+            if experiments.iter().any(|other| other.name.eq_ignore_ascii_case(&experiment.name)) {
+                return Err(invalid(format!("experiment {:?} is listed twice", experiment.name)));
+            }
+            if experiments.iter().any(|other| other.cookie_name == experiment.cookie_name) {
+                return Err(invalid(format!("cookie {:?} is used twice", experiment.cookie_name)));
+            }
+            experiments.push(experiment);
+        }
+        Ok(Self { experiments })
+    }
+}
+
+/// The variants of a request, by experiment; empty when none apply
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Assignments(pub Vec<(String, Variant)>);
+
+impl Assignments {
+    /// The variant of `experiment`, if the request is in it
+    pub fn variant(&self, experiment: &str) -> Option<Variant> {
+        self.0.iter().find(|(name, _)| name == experiment).map(|(_, variant)| *variant)
+    }
+}
+
+/// `checkout=canary,banner=control`
+impl fmt::Display for Assignments {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, variant)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}={}", name, variant.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Assignments
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Self>().cloned().unwrap_or_default())
+    }
+}
+
+/// The value of the request's `name` cookie
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value.trim())
+}
+
+/// A key for a visitor with no known address, so one of its own
+fn random_key() -> String {
+    let mut bytes = [0u8; 8];
+    getrandom::fill(&mut bytes).expect("the operating system's random number generator failed");
+    u64::from_ne_bytes(bytes).to_string()
+}
+
+/// Middleware assigning each request its variants, naming them in the
+/// response and setting the cookie of every new assignment
+pub async fn assign(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    let config = state.config();
+    let experiments = &config.canary.experiments;
+    if experiments.is_empty() || maintenance::EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let key = match request.extensions().get::<ClientIp>().and_then(|client| client.ip) {
+        Some(ip) => ip.to_string(),
+        None => random_key(),
+    };
+    let mut assignments = Assignments::default();
+    let mut new_cookies = Vec::new();
+    for experiment in experiments {
+        let variant = match cookie(request.headers(), &experiment.cookie_name).and_then(Variant::parse) {
+            Some(variant) => variant,
+            None => {
+                let variant = experiment.bucket(&key);
+                new_cookies.push(format!(
+                    "{}={}; Path=/; Max-Age={}",
+                    experiment.cookie_name,
+                    variant.as_str(),
+                    COOKIE_MAX_AGE_SECS
+                ));
+                variant
+            }
+        };
+        assignments.0.push((experiment.name.clone(), variant));
+    }
+    request.extensions_mut().insert(assignments.clone());
+
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    for (experiment, (_, variant)) in experiments.iter().zip(&assignments.0) {
+        if let Some(name) = experiment.header_name() {
+            headers.insert(name, HeaderValue::from_static(variant.as_str()));
+        }
+    }
+    for set_cookie in new_cookies {
+        if let Ok(value) = HeaderValue::from_str(&set_cookie) {
+            headers.append(header::SET_COOKIE, value);
+        }
+    }
+    let varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("cookie") || name.trim() == "*");
+    if !varies {
+        headers.append(header::VARY, HeaderValue::from_static("cookie"));
+    }
+    response.extensions_mut().insert(assignments);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = CanaryConfig::parse(r#"[{"name": "checkout", "percent": 12.5}, {"name": "banner", "percent": 50, "cookie_name": "b", "salt": "s1"}]"#).unwrap();
+        assert_eq!(config.experiments[0], Experiment::new("checkout", 12.5));
+        assert_eq!((config.experiments[0].cookie_name.as_str(), config.experiments[0].salt.as_str()), ("exp_checkout", "checkout"));
+        assert_eq!((config.experiments[1].cookie_name.as_str(), config.experiments[1].salt.as_str()), ("b", "s1"));
+
+        for bad in [
+            r#"[{"name": "a b", "percent": 10}]"#,
+            r#"[{"name": "a", "percent": 101}]"#,
+            r#"[{"name": "a", "percent": 10, "cookie_name": "x;y"}]"#,
+            r#"[{"name": "a", "percent": 10}, {"name": "A", "percent": 20}]"#,
+            r#"[{"name": "a", "percent": 10, "cookie_name": "c"}, {"name": "b", "percent": 20, "cookie_name": "c"}]"#,
+            r#"[{"name": "a", "percent": 10, "weight": 2}]"#,
+        ] {
+            assert!(CanaryConfig::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_cookie_is_found_among_others() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("a=1; exp_checkout=canary; b=2"));
+        assert_eq!(cookie(&headers, "exp_checkout"), Some("canary"));
+        assert_eq!(cookie(&headers, "exp"), None);
+    }
+
+    #[test]
+    fn test_assignments_display() {
+        let assignments = Assignments(vec![("checkout".into(), Variant::Canary), ("banner".into(), Variant::Control)]);
+        assert_eq!(assignments.to_string(), "checkout=canary,banner=control");
+        assert_eq!(assignments.variant("banner"), Some(Variant::Control));
+        assert_eq!(assignments.variant("other"), None);
+    }
+}
@@ -0,0 +1,356 @@
+/*!
+ * On-demand capture of full requests and responses
+ *
+ * For chasing what Cloudflare changed on the way in or out, an operator
+ * can start a capture session on the admin listener:
+ *
+ * ```text
+ * POST /admin/capture {"prefix": "/api", "sample": 1, "max": 20, "ttl_seconds": 300}
+ * ```
+ *
+ * While it runs, requests matching the filter (a path prefix, a header
+ * and one-in-`sample` sampling) are recorded with method, URI, headers
+ * and body, together with the response's status, headers and body.
+ * `GET /admin/capture` returns them and `DELETE /admin/capture` ends the
+ * session early.
+ *
+ * Capturing is off until started and stays bounded: at most `max`
+ * exchanges are kept (oldest dropped first), bodies are cut at
+ * [`BODY_CAP`] bytes, and bodies of unknown length (streams, event
+ * streams) are not read at all. When the TTL passes the session and
+ * everything it captured is discarded. Credential headers are redacted as
+ * in the debug endpoints; bodies are stored as sent.
+ */
+use crate::auth::longest_prefix;
+use crate::clock::{Clock, SystemClock};
+use crate::error::ApiError;
+use crate::redact;
+use crate::state::AppState;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::info;
+
+/// Most bytes of each body kept
+pub const BODY_CAP: usize = 16 * 1024;
+
+/// Largest body of known length read for capturing; larger ones are passed
+/// through unread
+const READ_LIMIT: u64 = 2 * 1024 * 1024;
+
+/// Upper bound on `max`
+pub const MAX_EXCHANGES: usize = 100;
+
+/// Upper bound on `ttl_seconds`
+pub const MAX_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Header a request must carry to match
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderFilter {
+    pub name: String,
+
+    /// Exact value required; any value matches when absent
+    pub value: Option<String>,
+}
+
+/// Which requests a session captures, and for how long
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureFilter {
+    /// Path prefix, matched on segment boundaries
+    pub prefix: Option<String>,
+
+    pub header: Option<HeaderFilter>,
+
+    /// Capture one in this many matching requests
+    #[serde(default = "default_sample")]
+    pub sample: u64,
+
+    /// Exchanges kept
+    #[serde(default = "default_max")]
+    pub max: usize,
+
+    #[serde(default = "default_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+fn default_sample() -> u64 {
+    1
+}
+
+fn default_max() -> usize {
+    20
+}
+
+fn default_ttl_seconds() -> u64 {
+    300
+}
+
+impl CaptureFilter {
+    /// Reject values outside the limits
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let invalid = |message: String| ApiError::new(StatusCode::BAD_REQUEST, "invalid_capture", message);
+        if self.sample == 0 {
+            return Err(invalid("sample must be at least 1".to_string()));
+        }
+        if !(1..=MAX_EXCHANGES).contains(&self.max) {
+            return Err(invalid(format!("max must be between 1 and {}", MAX_EXCHANGES)));
+        }
+        if !(1..=MAX_TTL.as_secs()).contains(&self.ttl_seconds) {
+            return Err(invalid(format!("ttl_seconds must be between 1 and {}", MAX_TTL.as_secs())));
+        }
+        if let Some(header) = &self.header {
+            if axum::http::HeaderName::try_from(header.name.as_str()).is_err() {
+                return Err(invalid(format!("{:?} is not a header name", header.name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a request for `path` with `headers` passes the prefix and
+    /// header filters
+    pub fn matches(&self, path: &str, headers: &HeaderMap) -> bool {
+        let prefix_matches = self
+            .prefix
+            .as_ref()
+            .is_none_or(|prefix| longest_prefix(std::slice::from_ref(prefix), path).is_some());
+        let header_matches = self.header.as_ref().is_none_or(|filter| {
+            headers.get_all(filter.name.as_str()).iter().any(|value| {
+                filter.value.as_ref().is_none_or(|expected| value.as_bytes() == expected.as_bytes())
+            })
+        });
+        prefix_matches && header_matches
+    }
+}
+
+/// Body as captured
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedBody {
+    /// Up to [`BODY_CAP`] bytes, invalid UTF-8 replaced
+    pub text: String,
+
+    /// Full length of the body
+    pub bytes: usize,
+    pub truncated: bool,
+}
+
+impl CapturedBody {
+    fn new(body: &Bytes) -> Self {
+        let kept = &body[..body.len().min(BODY_CAP)];
+        Self {
+            text: String::from_utf8_lossy(kept).into_owned(),
+            bytes: body.len(),
+            truncated: body.len() > BODY_CAP,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub uri: String,
+    pub headers: Value,
+
+    /// `None` when the length was unknown or too large to read
+    pub body: Option<CapturedBody>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: Value,
+
+    /// `None` for streamed or very large bodies
+    pub body: Option<CapturedBody>,
+}
+
+/// One request and its response
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedExchange {
+    pub captured_at: DateTime<Utc>,
+    pub request: CapturedRequest,
+    pub response: CapturedResponse,
+}
+
+#[derive(Debug)]
+struct Session {
+    filter: CaptureFilter,
+    expires_at: SystemTime,
+
+    /// Requests that passed the filters, sampled or not
+    matched: u64,
+    exchanges: VecDeque<CapturedExchange>,
+}
+
+/// Body of the `/admin/capture` endpoints
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureReport {
+    pub active: bool,
+    pub filter: Option<CaptureFilter>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub matched: u64,
+    pub exchanges: Vec<CapturedExchange>,
+}
+
+impl CaptureReport {
+    fn inactive() -> Self {
+        Self { active: false, filter: None, expires_at: None, matched: 0, exchanges: Vec::new() }
+    }
+}
+
+/// The capture session, if one is running; clones share it
+#[derive(Debug, Clone)]
+pub struct CaptureState {
+    clock: Arc<dyn Clock>,
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+impl Default for CaptureState {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl CaptureState {
+    /// No session, with expiry measured by `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self { clock, session: Arc::default() }
+    }
+
+    /// The running session, after discarding it if it has expired
+    fn live<'a>(&self, session: &'a mut Option<Session>) -> Option<&'a mut Session> {
+        if session.as_ref().is_some_and(|live| self.clock.now() >= live.expires_at) {
+            info!("Request capture expired; discarding what it captured");
+            *session = None;
+        }
+        session.as_mut()
+    }
+
+    /// Start a session with `filter`, replacing any running one
+    pub fn start(&self, filter: CaptureFilter) -> CaptureReport {
+        let expires_at = self.clock.now() + Duration::from_secs(filter.ttl_seconds);
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        *session = Some(Session { filter, expires_at, matched: 0, exchanges: VecDeque::new() });
+        drop(session);
+        self.report()
+    }
+
+    /// End the session, returning what it captured
+    pub fn stop(&self) -> CaptureReport {
+        let report = self.report();
+        *self.session.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        report
+    }
+
+    /// The session and its exchanges, oldest first
+    pub fn report(&self) -> CaptureReport {
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(live) = self.live(&mut session) else {
+            return CaptureReport::inactive();
+        };
+        CaptureReport {
+            active: true,
+            filter: Some(live.filter.clone()),
+            expires_at: Some(live.expires_at.into()),
+            matched: live.matched,
+            exchanges: live.exchanges.iter().cloned().collect(),
+        }
+    }
+
+    /// Whether to capture a request for `path` with `headers`, counting it
+    /// towards sampling if it matches
+    pub fn should_capture(&self, path: &str, headers: &HeaderMap) -> bool {
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(live) = self.live(&mut session) else {
+            return false;
+        };
+        if !live.filter.matches(path, headers) {
+            return false;
+        }
+        live.matched += 1;
+        (live.matched - 1) % live.filter.sample == 0
+    }
+
+    /// Store `exchange`, dropping the oldest beyond the session's `max`
+    pub fn push(&self, exchange: CapturedExchange) {
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(live) = self.live(&mut session) else {
+            return;
+        };
+        if live.exchanges.len() >= live.filter.max {
+            live.exchanges.pop_front();
+        }
+        live.exchanges.push_back(exchange);
+    }
+
+    /// Discard the session if it has expired, so what it captured is not
+    /// held until the next request
+    pub fn sweep(&self) {
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        self.live(&mut session);
+    }
+}
+
+/// Read `body` if its length is known and small enough to hold
+async fn buffer(body: Body) -> Result<(Body, Option<Bytes>), axum::Error> {
+    let known = http_body::Body::size_hint(&body).exact();
+    if known.is_none_or(|len| len > READ_LIMIT) {
+        return Ok((body, None));
+    }
+    let bytes = axum::body::to_bytes(body, READ_LIMIT as usize).await?;
+    Ok((Body::from(bytes.clone()), Some(bytes)))
+}
+
+/// Middleware recording matching exchanges while a session runs
+pub async fn capture(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !state.capture.should_capture(request.uri().path(), request.headers()) {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let (body, request_body) = match buffer(body).await {
+        Ok(buffered) => buffered,
+        Err(e) => {
+            return ApiError::new(StatusCode::BAD_REQUEST, "invalid_body", format!("Failed to read the body: {}", e))
+                .into_response();
+        }
+    };
+    let captured_request = CapturedRequest {
+        method: parts.method.to_string(),
+        uri: parts.uri.to_string(),
+        headers: redact::headers_to_json(&parts.headers),
+        body: request_body.as_ref().map(CapturedBody::new),
+    };
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+
+    let (parts, body) = response.into_parts();
+    let (body, response_body) = match buffer(body).await {
+        Ok(buffered) => buffered,
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "response_failed", format!("Failed to read the response: {}", e))
+                .into_response();
+        }
+    };
+    state.capture.push(CapturedExchange {
+        captured_at: state.clock.now_utc(),
+        request: captured_request,
+        response: CapturedResponse {
+            status: parts.status.as_u16(),
+            headers: redact::headers_to_json(&parts.headers),
+            body: response_body.as_ref().map(CapturedBody::new),
+        },
+    });
+    Response::from_parts(parts, body)
+}
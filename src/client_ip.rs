@@ -0,0 +1,577 @@
+/*!
+ * Client IP resolution
+ *
+ * Behind Cloudflare the TCP peer is cloudflared, not the visitor. The real
+ * client address is taken from `CF-Connecting-IP` first, then the left-most
+ * valid `X-Forwarded-For` entry, and finally the connection's peer address.
+ *
+ * Header values are normalized so one client always gets one address, fit
+ * for use as a map key:
+ *
+ * - IPv4-mapped IPv6 (`::ffff:203.0.113.7`) becomes the IPv4 address
+ * - a port is dropped where it cannot be part of the address
+ *   (`203.0.113.7:443`, `[2001:db8::1]:443`); `2001:db8::1:443` is an
+ *   IPv6 address and kept whole
+ * - IPv6 is compared and printed in its canonical form, so `2001:db8::1`
+ *   and `2001:0db8:0000:0000:0000:0000:0000:0001` are the same client
+ *
+ * A `CF-Connecting-IP` that is still not an address after that is ignored
+ * in favour of the peer address (the `X-Forwarded-For` entries Cloudflare
+ * passes along may come from the client), logged at debug level and
+ * counted in `bad_client_ip_header_total`. The [`resolve`] middleware does
+ * this once per request and leaves the result in the request extensions
+ * for the extractor.
+ *
+ * When more proxies sit between cloudflared and the service (nginx, say),
+ * the left-most `X-Forwarded-For` entry is whatever the client sent.
+ * [`TrustedProxies`] (`TRUSTED_PROXY_HOPS`, `TRUSTED_PROXY_CIDRS`) then
+ * changes the rule: the chain of `X-Forwarded-For` entries followed by the
+ * peer is walked right to left, skipping the first `TRUSTED_PROXY_HOPS`
+ * addresses and any address in `TRUSTED_PROXY_CIDRS`, and the first
+ * address not skipped is the client. An entry that is not an address ends
+ * the walk at the last address before it, so nothing the client wrote is
+ * believed. The addresses walked are left in the request extensions as a
+ * [`ProxyChain`] for the access log. With `TRUSTED_PROXY_CIDRS` set,
+ * `CF-Connecting-IP` is only believed from a trusted peer.
+ */
+use crate::state::AppState;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Request, State},
+    http::{request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Serialize, Serializer};
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tracing::debug;
+
+/// Metric counting `CF-Connecting-IP` values that are not an address
+#[cfg(feature = "metrics")]
+pub const BAD_HEADER_METRIC: &str = "bad_client_ip_header_total";
+
+/// Where a resolved client IP came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientIpSource {
+    CfConnectingIp,
+    XForwardedFor,
+    Peer,
+}
+
+impl ClientIpSource {
+    /// Header name (or `peer`) the address was read from
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientIpSource::CfConnectingIp => "cf-connecting-ip",
+            ClientIpSource::XForwardedFor => "x-forwarded-for",
+            ClientIpSource::Peer => "peer",
+        }
+    }
+}
+
+/// The visitor's IP address, if one could be determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp {
+    pub ip: Option<IpAddr>,
+    pub source: Option<ClientIpSource>,
+
+    /// `CF-Connecting-IP` was present but not an address
+    pub bad_header: bool,
+}
+
+/// Addresses walked to find the client behind trusted proxies, from the
+/// client to the peer; empty without [`TrustedProxies`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyChain(pub Vec<IpAddr>);
+
+impl fmt::Display for ProxyChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, ip) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", ip)?;
+        }
+        Ok(())
+    }
+}
+
+impl ClientIp {
+    /// Resolve from request headers and the optional TCP peer address
+    pub fn resolve(headers: &HeaderMap, peer: Option<SocketAddr>) -> Self {
+        Self::resolve_with(headers, peer, &TrustedProxies::default()).0
+    }
+
+    /// Resolve behind `proxies`, also returning the chain walked
+    pub fn resolve_with(headers: &HeaderMap, peer: Option<SocketAddr>, proxies: &TrustedProxies) -> (Self, ProxyChain) {
+        let peer = peer.map(|addr| normalize(addr.ip()));
+        let from_peer = || match peer {
+            Some(ip) => Self::from_source(ip, ClientIpSource::Peer),
+            None => Self { ip: None, source: None, bad_header: false },
+        };
+        let forwarded: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .collect();
+
+        let (walked, chain) = if proxies.is_enabled() {
+            let (client, chain) = proxies.walk(&forwarded, peer);
+            (Some(client.map_or_else(from_peer, |(ip, source)| Self::from_source(ip, source))), chain)
+        } else {
+            (None, ProxyChain::default())
+        };
+
+        let cf_connecting_ip = headers.get("cf-connecting-ip").filter(|_| {
+            proxies.cidrs.is_empty() || peer.is_none_or(|peer| proxies.trusts(peer))
+        });
+        if let Some(value) = cf_connecting_ip {
+            let client = match value.to_str().ok().and_then(parse) {
+                Some(ip) => Self::from_source(ip, ClientIpSource::CfConnectingIp),
+                None => Self { bad_header: true, ..walked.unwrap_or_else(from_peer) },
+            };
+            return (client, chain);
+        }
+        if let Some(client) = walked {
+            return (client, chain);
+        }
+
+        match forwarded.into_iter().find_map(parse) {
+            Some(ip) => (Self::from_source(ip, ClientIpSource::XForwardedFor), chain),
+            None => (from_peer(), chain),
+        }
+    }
+
+    fn from_source(ip: IpAddr, source: ClientIpSource) -> Self {
+        Self {
+            ip: Some(ip),
+            source: Some(source),
+            bad_header: false,
+        }
+    }
+}
+
+/// An address range such as `10.0.0.0/8` or `2001:db8::/32`; a bare
+/// address is a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in the range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, normalize(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network = normalize(address.parse::<IpAddr>().map_err(|e| format!("{:?}: {}", value, e))?);
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("{:?}: prefix must be 0 to {}", value, max))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Proxies between cloudflared and the service whose `X-Forwarded-For`
+/// entries are believed (`TRUSTED_PROXY_HOPS`, `TRUSTED_PROXY_CIDRS`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TrustedProxies {
+    /// Addresses at the end of the chain, counting the peer, that are
+    /// always proxies
+    pub hops: usize,
+
+    /// Ranges whose addresses are proxies wherever they appear
+    pub cidrs: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    /// Load from `TRUSTED_PROXY_HOPS` and comma-separated
+    /// `TRUSTED_PROXY_CIDRS`
+    pub fn from_env() -> crate::Result<Self> {
+        let hops = match std::env::var("TRUSTED_PROXY_HOPS") {
+            Ok(value) => value.trim().parse().map_err(|e| {
+                crate::ServerError::ConfigError(format!("Invalid TRUSTED_PROXY_HOPS {:?}: {}", value, e))
+            })?,
+            Err(_) => 0,
+        };
+        let cidrs = match std::env::var("TRUSTED_PROXY_CIDRS") {
+            Ok(value) => value
+                .split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .map(|entry| {
+                    entry.parse().map_err(|e| {
+                        crate::ServerError::ConfigError(format!("Invalid TRUSTED_PROXY_CIDRS entry {}", e))
+                    })
+                })
+                .collect::<crate::Result<_>>()?,
+            Err(_) => Vec::new(),
+        };
+        Ok(Self { hops, cidrs })
+    }
+
+    /// Whether `X-Forwarded-For` is walked from the right
+    pub fn is_enabled(&self) -> bool {
+        self.hops > 0 || !self.cidrs.is_empty()
+    }
+
+    /// Whether `ip` is in a trusted range
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Walk `forwarded` entries and then `peer` from the right, returning
+    /// the first address that is not a proxy (or the last valid one
+    /// reached) and the addresses walked, in header order. Without a peer
+    /// address the peer is still counted as a hop.
+    fn walk(&self, forwarded: &[&str], peer: Option<IpAddr>) -> (Option<(IpAddr, ClientIpSource)>, ProxyChain) {
+        let hops = peer
+            .map(|ip| (Some(ip), ClientIpSource::Peer))
+            .into_iter()
+            .chain(forwarded.iter().rev().map(|entry| (parse(entry), ClientIpSource::XForwardedFor)));
+        let first = usize::from(peer.is_none());
+
+        let mut client = None;
+        let mut chain = Vec::new();
+        for (position, (ip, source)) in (first..).zip(hops) {
+            let Some(ip) = ip else {
+                break;
+            };
+            chain.push(ip);
+            client = Some((ip, source));
+            if position >= self.hops && !self.trusts(ip) {
+                break;
+            }
+        }
+        chain.reverse();
+        (client, ProxyChain(chain))
+    }
+}
+
+/// IPv4-mapped IPv6 addresses as IPv4; others unchanged
+pub fn normalize(ip: IpAddr) -> IpAddr {
+    ip.to_canonical()
+}
+
+/// Address in a client IP header value, without a port and normalized
+pub fn parse(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(normalize(ip));
+    }
+    // `203.0.113.7:443` or `[2001:db8::1]:443`; a bare IPv6 address never
+    // gets here, so a trailing `:443` is only taken as a port when it
+    // cannot be part of the address
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(normalize(addr.ip()));
+    }
+    let bracketed = value.strip_prefix('[')?.strip_suffix(']')?;
+    bracketed.parse::<Ipv6Addr>().ok().map(|ip| normalize(IpAddr::V6(ip)))
+}
+
+/// Middleware resolving the client address once, counting bad
+/// `CF-Connecting-IP` values, and storing it for [`ClientIp`] extractors
+/// along with the [`ProxyChain`]
+pub async fn resolve(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    let peer = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| *addr);
+    let (client, chain) = ClientIp::resolve_with(request.headers(), peer, &state.config().trusted_proxies);
+    if client.bad_header {
+        debug!(
+            value = ?request.headers().get("cf-connecting-ip"),
+            "Ignoring a CF-Connecting-IP that is not an address"
+        );
+        #[cfg(feature = "metrics")]
+        state.requests.bad_client_ip_headers.inc();
+    }
+
+    request.extensions_mut().insert(client);
+    request.extensions_mut().insert(chain);
+    next.run(request).await
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(client) = parts.extensions.get::<ClientIp>() {
+            return Ok(*client);
+        }
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        Ok(Self::resolve(&parts.headers, peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn peer() -> Option<SocketAddr> {
+        Some("10.0.0.2:51234".parse().expect("Invalid socket address"))
+    }
+
+    #[test]
+    fn test_cf_connecting_ip_wins() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-connecting-ip", HeaderValue::from_static("203.0.113.7"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.1"));
+
+        let client = ClientIp::resolve(&headers, peer());
+        assert_eq!(client.ip, Some("203.0.113.7".parse().expect("Invalid IP")));
+        assert_eq!(client.source, Some(ClientIpSource::CfConnectingIp));
+    }
+
+    #[test]
+    fn test_forwarded_for_skips_garbage() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("unknown, 198.51.100.1, 10.0.0.1"));
+
+        let client = ClientIp::resolve(&headers, peer());
+        assert_eq!(client.ip, Some("198.51.100.1".parse().expect("Invalid IP")));
+        assert_eq!(client.source, Some(ClientIpSource::XForwardedFor));
+    }
+
+    #[test]
+    fn test_falls_back_to_peer() {
+        let client = ClientIp::resolve(&HeaderMap::new(), peer());
+        assert_eq!(client.ip, Some("10.0.0.2".parse().expect("Invalid IP")));
+        assert_eq!(client.source, Some(ClientIpSource::Peer));
+
+        let client = ClientIp::resolve(&HeaderMap::new(), None);
+        assert_eq!(client.ip, None);
+    }
+
+    #[test]
+    fn test_bad_cf_connecting_ip_falls_back_to_peer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-connecting-ip", HeaderValue::from_static("203.0.113.7, 10.0.0.1"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.1"));
+
+        let client = ClientIp::resolve(&headers, peer());
+        assert_eq!(client.ip, Some("10.0.0.2".parse().expect("Invalid IP")));
+        assert_eq!(client.source, Some(ClientIpSource::Peer));
+        assert!(client.bad_header);
+    }
+
+    fn proxies(hops: usize, cidrs: &[&str]) -> TrustedProxies {
+        let cidrs = cidrs.iter().map(|cidr| cidr.parse().expect("Invalid CIDR")).collect();
+        TrustedProxies { hops, cidrs }
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cases = [
+            ("10.0.0.0/8", "10.255.0.1", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("10.1.2.3/8", "10.9.9.9", true),
+            ("10.0.0.2", "10.0.0.2", true),
+            ("10.0.0.2", "10.0.0.3", false),
+            ("0.0.0.0/0", "203.0.113.7", true),
+            ("0.0.0.0/0", "2001:db8::1", false),
+            ("10.0.0.0/8", "::ffff:10.0.0.1", true),
+            ("2001:db8::/32", "2001:db8:1::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("::/0", "::1", true),
+        ];
+        for (cidr, ip, expected) in cases {
+            let cidr: Cidr = cidr.parse().expect("Invalid CIDR");
+            assert_eq!(cidr.contains(ip.parse().expect("Invalid IP")), expected, "{} in {}", ip, cidr);
+        }
+
+        for invalid in ["", "10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "10.0.0.0/-1", "localhost/8"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    /// X-Forwarded-For, hops, trusted ranges, client, source, chain
+    type WalkCase = (&'static str, usize, &'static [&'static str], &'static str, ClientIpSource, &'static str);
+
+    #[test]
+    fn test_trusted_proxy_walk() {
+        let cases: &[WalkCase] = &[
+            // Disabled: the left-most valid entry, as before
+            ("1.1.1.1, 203.0.113.7", 0, &[], "1.1.1.1", ClientIpSource::XForwardedFor, ""),
+            // One hop: the peer is the proxy, the last entry the client
+            ("1.1.1.1, 203.0.113.7", 1, &[], "203.0.113.7", ClientIpSource::XForwardedFor, "203.0.113.7, 10.0.0.2"),
+            ("1.1.1.1, 203.0.113.7, 10.0.0.9", 2, &[], "203.0.113.7", ClientIpSource::XForwardedFor, "203.0.113.7, 10.0.0.9, 10.0.0.2"),
+            // Every trusted address is skipped, wherever it is
+            ("1.1.1.1, 203.0.113.7, 10.0.0.9, 10.1.0.1", 0, &["10.0.0.0/8"], "203.0.113.7", ClientIpSource::XForwardedFor, "203.0.113.7, 10.0.0.9, 10.1.0.1, 10.0.0.2"),
+            ("1.1.1.1, 203.0.113.7, 192.0.2.1", 1, &["10.0.0.0/8"], "192.0.2.1", ClientIpSource::XForwardedFor, "192.0.2.1, 10.0.0.2"),
+            ("203.0.113.7, 192.0.2.1", 2, &["10.0.0.0/8"], "203.0.113.7", ClientIpSource::XForwardedFor, "203.0.113.7, 192.0.2.1, 10.0.0.2"),
+            // An untrusted peer is the client
+            ("203.0.113.7", 0, &["192.168.0.0/16"], "10.0.0.2", ClientIpSource::Peer, "10.0.0.2"),
+            // Entries are normalized before they are compared
+            ("[2001:db8::7]:443, ::ffff:10.0.0.9", 0, &["10.0.0.0/8"], "2001:db8::7", ClientIpSource::XForwardedFor, "2001:db8::7, 10.0.0.9, 10.0.0.2"),
+            // Garbage ends the walk at the last address before it
+            ("203.0.113.7, unknown, 10.0.0.9", 0, &["10.0.0.0/8"], "10.0.0.9", ClientIpSource::XForwardedFor, "10.0.0.9, 10.0.0.2"),
+            ("203.0.113.7, , 10.0.0.9", 0, &["10.0.0.0/8"], "10.0.0.9", ClientIpSource::XForwardedFor, "10.0.0.9, 10.0.0.2"),
+            ("203.0.113.7, unknown", 3, &[], "10.0.0.2", ClientIpSource::Peer, "10.0.0.2"),
+            // Every hop trusted: the furthest one
+            ("10.0.0.8, 10.0.0.9", 0, &["10.0.0.0/8"], "10.0.0.8", ClientIpSource::XForwardedFor, "10.0.0.8, 10.0.0.9, 10.0.0.2"),
+            ("", 5, &[], "10.0.0.2", ClientIpSource::Peer, "10.0.0.2"),
+        ];
+        for (forwarded, hops, cidrs, client, source, chain) in cases {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", HeaderValue::from_str(forwarded).expect("Invalid header"));
+            let (resolved, walked) = ClientIp::resolve_with(&headers, peer(), &proxies(*hops, cidrs));
+            let case = format!("{:?} with {} hops and {:?}", forwarded, hops, cidrs);
+            assert_eq!(resolved.ip, Some(client.parse().expect("Invalid IP")), "{}", case);
+            assert_eq!(resolved.source, Some(*source), "{}", case);
+            assert_eq!(walked.to_string(), *chain, "{}", case);
+        }
+    }
+
+    #[test]
+    fn test_trusted_proxy_walk_without_peer() {
+        // The unknown peer still counts as the first hop
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", HeaderValue::from_static("1.1.1.1, 203.0.113.7"));
+        headers.append("x-forwarded-for", HeaderValue::from_static("10.0.0.9"));
+
+        let (client, chain) = ClientIp::resolve_with(&headers, None, &proxies(2, &[]));
+        assert_eq!(client.ip, Some("203.0.113.7".parse().expect("Invalid IP")));
+        assert_eq!(chain.to_string(), "203.0.113.7, 10.0.0.9");
+    }
+
+    #[test]
+    fn test_cf_connecting_ip_needs_a_trusted_peer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-connecting-ip", HeaderValue::from_static("198.51.100.1"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.7"));
+
+        // Through the trusted proxy at 10.0.0.2
+        let (client, _) = ClientIp::resolve_with(&headers, peer(), &proxies(0, &["10.0.0.0/8"]));
+        assert_eq!(client.source, Some(ClientIpSource::CfConnectingIp));
+        // Straight from an untrusted peer, which is then the client
+        let (client, _) = ClientIp::resolve_with(&headers, peer(), &proxies(0, &["192.168.0.0/16"]));
+        assert_eq!(client.ip, Some("10.0.0.2".parse().expect("Invalid IP")));
+        assert_eq!(client.source, Some(ClientIpSource::Peer));
+        // A hop count alone cannot tell, so the header is believed
+        let (client, _) = ClientIp::resolve_with(&headers, peer(), &proxies(1, &[]));
+        assert_eq!(client.source, Some(ClientIpSource::CfConnectingIp));
+
+        // A bad value falls back to the walk
+        headers.insert("cf-connecting-ip", HeaderValue::from_static("garbage"));
+        let (client, _) = ClientIp::resolve_with(&headers, peer(), &proxies(1, &[]));
+        assert_eq!(client.ip, Some("203.0.113.7".parse().expect("Invalid IP")));
+        assert!(client.bad_header);
+    }
+
+    #[test]
+    fn test_parse_corpus() {
+        let valid = [
+            ("203.0.113.7", "203.0.113.7"),
+            (" 203.0.113.7 ", "203.0.113.7"),
+            ("203.0.113.7:443", "203.0.113.7"),
+            ("::ffff:203.0.113.7", "203.0.113.7"),
+            ("::FFFF:cb00:7107", "203.0.113.7"),
+            ("[::ffff:203.0.113.7]:8080", "203.0.113.7"),
+            ("2001:db8::1", "2001:db8::1"),
+            ("2001:0DB8:0000:0000:0000:0000:0000:0001", "2001:db8::1"),
+            ("[2001:db8::1]", "2001:db8::1"),
+            ("[2001:db8::1]:443", "2001:db8::1"),
+            // Eight groups: an address, not an address and a port
+            ("2001:db8::1:443", "2001:db8::1:443"),
+            ("::1", "::1"),
+        ];
+        for (value, expected) in valid {
+            assert_eq!(parse(value).map(|ip| ip.to_string()).as_deref(), Some(expected), "{:?}", value);
+        }
+
+        let malformed = [
+            "",
+            "unknown",
+            "203.0.113",
+            "203.0.113.256",
+            "203.0.113.7:",
+            "203.0.113.7:99999",
+            "203.0.113.7:443:443",
+            "203.0.113.7, 10.0.0.1",
+            "[203.0.113.7]",
+            "[2001:db8::1",
+            "2001:db8:::1",
+            "fe80::1%eth0",
+            "http://203.0.113.7/",
+        ];
+        for value in malformed {
+            assert_eq!(parse(value), None, "{:?}", value);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_ipv4_forms_agree(ip: std::net::Ipv4Addr, port: u16) {
+            let expected = Some(IpAddr::V4(ip));
+            proptest::prop_assert_eq!(parse(&ip.to_string()), expected);
+            proptest::prop_assert_eq!(parse(&format!("{}:{}", ip, port)), expected);
+            proptest::prop_assert_eq!(parse(&ip.to_ipv6_mapped().to_string()), expected);
+            proptest::prop_assert_eq!(parse(&format!("[{}]:{}", ip.to_ipv6_mapped(), port)), expected);
+        }
+
+        #[test]
+        fn prop_ipv6_forms_agree(ip: Ipv6Addr, port: u16) {
+            let expected = Some(normalize(IpAddr::V6(ip)));
+            let expanded = ip.segments().map(|segment| format!("{:04x}", segment)).join(":");
+            proptest::prop_assert_eq!(parse(&ip.to_string()), expected);
+            proptest::prop_assert_eq!(parse(&expanded.to_uppercase()), expected);
+            proptest::prop_assert_eq!(parse(&format!("[{}]", expanded)), expected);
+            proptest::prop_assert_eq!(parse(&format!("[{}]:{}", ip, port)), expected);
+        }
+
+        #[test]
+        fn prop_garbage_is_rejected(value in "[g-z ,;/%]{0,20}[0-9a-f.:]{0,20}") {
+            // No letter from g to z or separator can be part of an address
+            proptest::prop_assume!(value.trim().chars().any(|c| !c.is_ascii_hexdigit() && c != '.' && c != ':'));
+            proptest::prop_assert_eq!(parse(&value), None);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+/*!
+ * Wall-clock and monotonic time behind a trait
+ *
+ * In-memory state that ages by the minute or expires after a TTL
+ * (`slo`, `capture`), the `/health` timestamp and uptime, and time-based
+ * request IDs read the time from a [`Clock`] rather than calling
+ * `SystemTime::now` or `Instant::now` directly, so tests can substitute
+ * `testing::TestClock` and move through hours without sleeping.
+ * `AppState::with_clock` hands one clock to every part of the state.
+ * Timers (drain delays, task backoff) run on tokio's clock instead, which
+ * tests pause with `#[tokio::test(start_paused = true)]`.
+ */
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::time::{Instant, SystemTime};
+
+/// Source of the current time
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> SystemTime;
+
+    /// Monotonic time, for measuring spans such as the uptime
+    fn monotonic(&self) -> Instant;
+
+    /// [`now`](Clock::now) as a UTC timestamp
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.now().into()
+    }
+}
+
+/// The system's wall clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
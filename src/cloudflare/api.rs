@@ -0,0 +1,356 @@
+/*!
+ * Minimal Cloudflare API client
+ *
+ * Covers the single call this service needs: `POST /zones/{zone}/purge_cache`
+ * for purging URLs (or everything) after new content is deployed. Credentials
+ * come from `CLOUDFLARE_API_TOKEN` and `CLOUDFLARE_ZONE_ID` (both also accept
+ * the `_FILE` variants). Rate-limited calls are retried, honoring
+ * `Retry-After`.
+ */
+use crate::http_client::{HttpClient, HttpClientError};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// Production Cloudflare API v4 endpoint
+pub const DEFAULT_BASE_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// Cloudflare accepts at most this many URLs per purge-by-URL call
+pub const MAX_PURGE_URLS: usize = 30;
+
+/// Credentials and retry policy for the Cloudflare API
+#[derive(Clone)]
+pub struct CloudflareApiConfig {
+    pub api_token: String,
+    pub zone_id: String,
+
+    /// API base URL, overridable for testing
+    pub base_url: String,
+
+    /// Retries after a 429 before giving up
+    pub max_retries: u32,
+
+    /// Upper bound on a single Retry-After wait
+    pub max_retry_wait: Duration,
+
+    pub timeout: Duration,
+}
+
+impl std::fmt::Debug for CloudflareApiConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CloudflareApiConfig")
+            .field("api_token", &"<redacted>")
+            .field("zone_id", &self.zone_id)
+            .field("base_url", &self.base_url)
+            .field("max_retries", &self.max_retries)
+            .field("max_retry_wait", &self.max_retry_wait)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl CloudflareApiConfig {
+    /// Configuration for `zone_id` with default base URL, retries and timeout
+    pub fn new(api_token: impl Into<String>, zone_id: impl Into<String>) -> Self {
+        Self {
+            api_token: api_token.into(),
+            zone_id: zone_id.into(),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            max_retries: 3,
+            max_retry_wait: Duration::from_secs(30),
+            timeout: Duration::from_secs(15),
+        }
+    }
+
+    /// Load from the environment; `None` unless both token and zone are set
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let token = crate::config::read_secret("CLOUDFLARE_API_TOKEN")?;
+        let zone = crate::config::read_secret("CLOUDFLARE_ZONE_ID")?;
+
+        let (token, zone) = match (token, zone) {
+            (Some(token), Some(zone)) => (token, zone),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(crate::ServerError::ConfigError(
+                    "CLOUDFLARE_API_TOKEN and CLOUDFLARE_ZONE_ID must be set together".to_string(),
+                ))
+            }
+        };
+
+        let mut config = Self::new(token, zone);
+        if let Ok(value) = std::env::var("CLOUDFLARE_API_BASE_URL") {
+            config.base_url = value.trim_end_matches('/').to_string();
+        }
+        crate::http_client::require_reachable("CLOUDFLARE_API_BASE_URL", &config.base_url)?;
+
+        Ok(Some(config))
+    }
+}
+
+/// What to purge from Cloudflare's cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PurgeRequest {
+    Urls(Vec<String>),
+    Everything,
+}
+
+/// Error entry in a Cloudflare API response envelope
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiMessage {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiMessage>,
+    #[serde(default)]
+    result: Option<PurgeResult>,
+}
+
+/// Successful purge result
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PurgeResult {
+    pub id: String,
+}
+
+/// Failures of a Cloudflare API call
+#[derive(Debug, Error)]
+pub enum CloudflareApiError {
+    #[error("Cloudflare rejected the API credentials: {}", format_errors(.0))]
+    Unauthorized(Vec<ApiMessage>),
+    #[error("Cloudflare rate limit persisted after {attempts} attempts")]
+    RateLimited { attempts: u32 },
+    #[error("Cloudflare API returned {status}: {}", format_errors(.errors))]
+    Api {
+        status: StatusCode,
+        errors: Vec<ApiMessage>,
+    },
+    #[error("Cloudflare API request failed: {0}")]
+    Transport(#[from] HttpClientError),
+    #[error("Unexpected Cloudflare API response: {0}")]
+    InvalidResponse(String),
+}
+
+fn format_errors(errors: &[ApiMessage]) -> String {
+    errors
+        .iter()
+        .map(|e| format!("{} ({})", e.message, e.code))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Client for the zone configured in `CloudflareApiConfig`
+#[derive(Debug, Clone)]
+pub struct CloudflareClient {
+    config: CloudflareApiConfig,
+    http: HttpClient,
+}
+
+impl CloudflareClient {
+    /// Create a client; no request is made until an operation is called
+    pub fn new(config: CloudflareApiConfig, http: &HttpClient) -> Self {
+        let http = http.with_timeout(config.timeout);
+        Self { config, http }
+    }
+
+    /// Purge URLs, or the whole zone, from Cloudflare's cache
+    pub async fn purge_cache(&self, request: &PurgeRequest) -> Result<PurgeResult, CloudflareApiError> {
+        let body = match request {
+            PurgeRequest::Urls(urls) => json!({ "files": urls }),
+            PurgeRequest::Everything => json!({ "purge_everything": true }),
+        };
+        let url = format!("{}/zones/{}/purge_cache", self.config.base_url, self.config.zone_id);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let auth = HeaderValue::from_str(&format!("Bearer {}", self.config.api_token))
+            .map_err(|_| CloudflareApiError::InvalidResponse("API token is not a valid header value".to_string()))?;
+        headers.insert(header::AUTHORIZATION, auth);
+
+        let body = Bytes::from(body.to_string());
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let response = self.http
+                .send(Method::POST, &url, headers.clone(), body.clone())
+                .await?;
+
+            if response.status == StatusCode::TOO_MANY_REQUESTS {
+                if attempt > self.config.max_retries {
+                    return Err(CloudflareApiError::RateLimited { attempts: attempt });
+                }
+                let wait = retry_after(&response.headers)
+                    .unwrap_or(Duration::from_secs(1))
+                    .min(self.config.max_retry_wait);
+                warn!("Cloudflare API rate limited, retrying in {:?} (attempt {})", wait, attempt);
+                tokio::time::sleep(wait).await;
+                continue;
+            }
+
+            let envelope: Envelope = serde_json::from_slice(&response.body)
+                .map_err(|e| CloudflareApiError::InvalidResponse(e.to_string()))?;
+
+            if response.status == StatusCode::UNAUTHORIZED || response.status == StatusCode::FORBIDDEN {
+                return Err(CloudflareApiError::Unauthorized(envelope.errors));
+            }
+            if !response.status.is_success() || !envelope.success {
+                return Err(CloudflareApiError::Api {
+                    status: response.status,
+                    errors: envelope.errors,
+                });
+            }
+
+            let result = envelope.result
+                .ok_or_else(|| CloudflareApiError::InvalidResponse("missing result".to_string()))?;
+            info!("Cloudflare cache purge {} accepted", result.id);
+            return Ok(result);
+        }
+    }
+}
+
+/// Parse a `Retry-After` header given in seconds
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::State,
+        response::{IntoResponse, Json, Response},
+        routing::post,
+        Router,
+    };
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone)]
+    struct Stub {
+        calls: Arc<AtomicU32>,
+        rate_limited_calls: u32,
+    }
+
+    async fn purge_stub(State(stub): State<Stub>, headers: HeaderMap, Json(body): Json<Value>) -> Response {
+        let call = stub.calls.fetch_add(1, Ordering::SeqCst) + 1;
+
+        if headers.get(header::AUTHORIZATION).map(|v| v.as_bytes()) != Some(b"Bearer good-token") {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "success": false,
+                    "errors": [{ "code": 10000, "message": "Authentication error" }],
+                    "result": null,
+                })),
+            ).into_response();
+        }
+
+        if call <= stub.rate_limited_calls {
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, "1")],
+                Json(json!({ "success": false, "errors": [{ "code": 971, "message": "Please wait" }] })),
+            ).into_response();
+        }
+
+        assert!(body.get("files").is_some() || body.get("purge_everything").is_some());
+        Json(json!({
+            "success": true,
+            "errors": [],
+            "messages": [],
+            "result": { "id": "purge-123" },
+        })).into_response()
+    }
+
+    async fn spawn_stub(rate_limited_calls: u32) -> (String, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let stub = Stub { calls: calls.clone(), rate_limited_calls };
+        let app = Router::new()
+            .route("/zones/zone-1/purge_cache", post(purge_stub))
+            .with_state(stub);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind stub server");
+        let addr = listener.local_addr().expect("Stub server has no address");
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.expect("Stub server failed");
+        });
+        (format!("http://{}", addr), calls)
+    }
+
+    fn test_config(base_url: String, token: &str) -> CloudflareApiConfig {
+        let mut config = CloudflareApiConfig::new(token, "zone-1");
+        config.base_url = base_url;
+        config.max_retry_wait = Duration::from_millis(20);
+        config.timeout = Duration::from_secs(2);
+        config
+    }
+
+    #[tokio::test]
+    async fn test_purge_urls_success() {
+        let (base_url, calls) = spawn_stub(0).await;
+        let client = CloudflareClient::new(test_config(base_url, "good-token"), &HttpClient::default());
+
+        let result = client
+            .purge_cache(&PurgeRequest::Urls(vec!["https://hello.halibut.cc/".to_string()]))
+            .await
+            .expect("Purge failed");
+
+        assert_eq!(result.id, "purge-123");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_purge_auth_failure() {
+        let (base_url, _calls) = spawn_stub(0).await;
+        let client = CloudflareClient::new(test_config(base_url, "bad-token"), &HttpClient::default());
+
+        let result = client.purge_cache(&PurgeRequest::Everything).await;
+
+        match result {
+            Err(CloudflareApiError::Unauthorized(errors)) => assert_eq!(errors[0].code, 10000),
+            other => panic!("Expected Unauthorized, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge_retries_rate_limit() {
+        let (base_url, calls) = spawn_stub(2).await;
+        let client = CloudflareClient::new(test_config(base_url, "good-token"), &HttpClient::default());
+
+        let result = client.purge_cache(&PurgeRequest::Everything).await.expect("Purge failed");
+
+        assert_eq!(result.id, "purge-123");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_purge_gives_up_after_max_retries() {
+        let (base_url, calls) = spawn_stub(10).await;
+        let mut config = test_config(base_url, "good-token");
+        config.max_retries = 1;
+        let client = CloudflareClient::new(config, &HttpClient::default());
+
+        let result = client.purge_cache(&PurgeRequest::Everything).await;
+
+        assert!(matches!(result, Err(CloudflareApiError::RateLimited { attempts: 2 })));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,195 @@
+/*!
+ * Cloudflare's request headers as one typed value
+ *
+ * Requests through the tunnel carry Cloudflare's view of the visitor:
+ * `CF-Ray` (with the colo), `CF-IPCountry`, `CF-Visitor`,
+ * `CF-Connecting-IP`, `CF-Worker` when a Worker made the subrequest, and
+ * `CF-Bot-Score` when the zone has Bot Management and adds it (the "Add bot
+ * protection headers" managed transform). [`CloudflareMeta`] parses them
+ * all; a header that is missing or malformed leaves its field `None` and
+ * never fails the request.
+ *
+ * Handlers take it as an extractor. The first consumer parses the headers
+ * and keeps the result in the request extensions, so the access log,
+ * `/whoami` and the homepage share one parse.
+ */
+use crate::cloudflare::ray::RayId;
+use crate::scheme::{self, RequestScheme};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderMap},
+};
+use serde::Serialize;
+use std::convert::Infallible;
+use std::net::IpAddr;
+
+/// Longest `CF-Worker` accepted, that of a DNS name
+const MAX_WORKER_LENGTH: usize = 253;
+
+/// What Cloudflare's request headers say about the request
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CloudflareMeta {
+    /// `CF-Ray` ID, lowercase hex without the colo
+    pub ray: Option<String>,
+
+    /// Datacenter code from `CF-Ray`, uppercase, e.g. `SJC`
+    pub colo: Option<String>,
+
+    /// `CF-IPCountry`: ISO 3166-1 alpha-2, or `XX` (unknown) and `T1` (Tor)
+    pub country: Option<String>,
+
+    /// Scheme between the visitor and Cloudflare, from `CF-Visitor`
+    pub visitor_scheme: Option<RequestScheme>,
+
+    /// Zone of the Worker that sent the request, from `CF-Worker`
+    pub worker: Option<String>,
+
+    /// `CF-Connecting-IP`, IPv4-mapped addresses as IPv4
+    pub connecting_ip: Option<IpAddr>,
+
+    /// Bot Management score from `CF-Bot-Score`, 1 (bot) to 99 (human)
+    pub bot_score: Option<u8>,
+}
+
+impl CloudflareMeta {
+    /// Parse every known header in `headers`
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        let ray = RayId::from_headers(headers);
+        Self {
+            colo: ray.as_ref().and_then(|ray| ray.colo.clone()),
+            ray: ray.map(|ray| ray.id),
+            country: header("cf-ipcountry").and_then(parse_country),
+            visitor_scheme: scheme::cf_visitor_scheme(headers),
+            worker: header("cf-worker").and_then(parse_worker),
+            connecting_ip: header("cf-connecting-ip")
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .map(|ip| ip.to_canonical()),
+            bot_score: header("cf-bot-score")
+                .and_then(|score| score.parse().ok())
+                .filter(|score| (1..=99).contains(score)),
+        }
+    }
+
+    /// The metadata of `request`, parsed on first use and then taken from
+    /// its extensions
+    pub fn of(request: &mut Request) -> Self {
+        if let Some(meta) = request.extensions().get::<Self>() {
+            return meta.clone();
+        }
+        let meta = Self::from_headers(request.headers());
+        request.extensions_mut().insert(meta.clone());
+        meta
+    }
+}
+
+/// Two letters or digits, uppercased
+fn parse_country(value: &str) -> Option<String> {
+    (value.len() == 2 && value.bytes().all(|b| b.is_ascii_alphanumeric())).then(|| value.to_ascii_uppercase())
+}
+
+/// A hostname, lowercased
+fn parse_worker(value: &str) -> Option<String> {
+    let valid = !value.is_empty()
+        && value.len() <= MAX_WORKER_LENGTH
+        && value.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        });
+    valid.then(|| value.to_ascii_lowercase())
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CloudflareMeta
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(meta) = parts.extensions.get::<Self>() {
+            return Ok(meta.clone());
+        }
+        let meta = Self::from_headers(&parts.headers);
+        parts.extensions.insert(meta.clone());
+        Ok(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn meta(name: &'static str, value: &str) -> CloudflareMeta {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        CloudflareMeta::from_headers(&headers)
+    }
+
+    #[test]
+    fn test_ray_and_colo() {
+        let parsed = meta("cf-ray", "8A1B2C3D4E5F0ABC-sjc");
+        assert_eq!((parsed.ray.as_deref(), parsed.colo.as_deref()), (Some("8a1b2c3d4e5f0abc"), Some("SJC")));
+        let parsed = meta("cf-ray", "8a1b2c3d4e5f0abc-not-a-colo");
+        assert_eq!((parsed.ray.as_deref(), parsed.colo), (Some("8a1b2c3d4e5f0abc"), None));
+        assert_eq!(meta("cf-ray", "not hex").ray, None);
+    }
+
+    #[test]
+    fn test_country() {
+        assert_eq!(meta("cf-ipcountry", "de").country.as_deref(), Some("DE"));
+        assert_eq!(meta("cf-ipcountry", "T1").country.as_deref(), Some("T1"));
+        assert_eq!(meta("cf-ipcountry", "USA").country, None);
+        assert_eq!(meta("cf-ipcountry", "<b").country, None);
+    }
+
+    #[test]
+    fn test_visitor_scheme() {
+        assert_eq!(meta("cf-visitor", r#"{"scheme":"https"}"#).visitor_scheme, Some(RequestScheme::Https));
+        assert_eq!(meta("cf-visitor", "https").visitor_scheme, None);
+    }
+
+    #[test]
+    fn test_worker() {
+        assert_eq!(meta("cf-worker", "Example.com").worker.as_deref(), Some("example.com"));
+        assert_eq!(meta("cf-worker", "a..b").worker, None);
+        assert_eq!(meta("cf-worker", "-bad.example").worker, None);
+        assert_eq!(meta("cf-worker", "has space.example").worker, None);
+    }
+
+    #[test]
+    fn test_connecting_ip() {
+        assert_eq!(meta("cf-connecting-ip", " 203.0.113.7 ").connecting_ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(meta("cf-connecting-ip", "::ffff:203.0.113.7").connecting_ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(meta("cf-connecting-ip", "203.0.113.7:443").connecting_ip, None);
+    }
+
+    #[test]
+    fn test_bot_score() {
+        assert_eq!(meta("cf-bot-score", "1").bot_score, Some(1));
+        assert_eq!(meta("cf-bot-score", "99").bot_score, Some(99));
+        for value in ["0", "100", "-5", "high"] {
+            assert_eq!(meta("cf-bot-score", value).bot_score, None, "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extractor_parses_once() {
+        let (mut parts, _) = axum::http::Request::builder()
+            .header("cf-ipcountry", "US")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let first = CloudflareMeta::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(first.country.as_deref(), Some("US"));
+
+        // Later consumers get the stored value, not a fresh parse
+        parts.headers.insert("cf-ipcountry", HeaderValue::from_static("DE"));
+        let second = CloudflareMeta::from_request_parts(&mut parts, &()).await.unwrap();
+        assert_eq!(second, first);
+    }
+}
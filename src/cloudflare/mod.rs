@@ -0,0 +1,7 @@
+/*!
+ * Integrations with Cloudflare services
+ */
+#[cfg(feature = "cloudflare-api")]
+pub mod api;
+pub mod meta;
+pub mod ray;
@@ -0,0 +1,182 @@
+/*!
+ * Cloudflare Ray IDs and the colo that served the request
+ *
+ * Cloudflare sends every proxied request with a `CF-Ray` header such as
+ * `8a1b2c3d4e5f0abc-SJC`: a hex ID followed by the IATA code of the
+ * datacenter ("colo") that handled it. Knowing the colo narrows down
+ * regional tunnel problems. [`RayId::parse`] splits the header, accepting an
+ * ID without a colo and giving up on anything else malformed.
+ *
+ * [`record`] parses the header once per request, stores the [`RayId`] in the
+ * request extensions (the access log and `/whoami` read it from there) and
+ * records the `cf_colo` span field. With the `metrics` feature it also
+ * counts requests per colo in `cf_colo_requests_total{colo="..."}`. To keep
+ * label cardinality bounded, only the first [`MAX_COLO_LABELS`] colos seen
+ * get their own series and the rest are counted under `colo="other"`: a
+ * counter cannot move from one label to another without breaking
+ * Prometheus' monotonicity, and the colos cloudflared is connected to
+ * show up first.
+ */
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+
+/// Colos counted under their own label before the rest share `other`
+pub const MAX_COLO_LABELS: usize = 20;
+
+/// Longest ID accepted; Cloudflare's are 16 hex digits
+const MAX_ID_LENGTH: usize = 32;
+
+/// A parsed `CF-Ray` value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RayId {
+    pub id: String,
+
+    /// Datacenter code, uppercase, e.g. `SJC`
+    pub colo: Option<String>,
+}
+
+impl RayId {
+    /// Parse `8a1b2c3d4e5f0abc-SJC` or a bare `8a1b2c3d4e5f0abc`. A suffix
+    /// that is not a three-letter code leaves `colo` empty; a value whose ID
+    /// is not hex gives `None`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (id, suffix) = match value.split_once('-') {
+            Some((id, suffix)) => (id, Some(suffix)),
+            None => (value, None),
+        };
+        if id.is_empty() || id.len() > MAX_ID_LENGTH || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let colo = suffix
+            .filter(|colo| colo.len() == 3 && colo.bytes().all(|b| b.is_ascii_alphabetic()))
+            .map(str::to_ascii_uppercase);
+        Some(Self { id: id.to_ascii_lowercase(), colo })
+    }
+
+    /// The request's `CF-Ray`, if present and well-formed
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        headers.get("cf-ray").and_then(|v| v.to_str().ok()).and_then(Self::parse)
+    }
+}
+
+/// Requests per colo, with at most [`MAX_COLO_LABELS`] labelled series;
+/// clones share the counts
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone)]
+pub struct ColoCounter {
+    metrics: crate::metrics::Metrics,
+    limit: usize,
+    labelled: std::sync::Arc<std::sync::RwLock<std::collections::HashMap<String, crate::metrics::Counter>>>,
+    other: crate::metrics::Counter,
+}
+
+#[cfg(feature = "metrics")]
+impl ColoCounter {
+    const NAME: &'static str = "cf_colo_requests_total";
+    const HELP: &'static str = "Requests by the Cloudflare colo in their CF-Ray header";
+
+    /// Counter registering its series in `metrics`, labelling up to `limit`
+    /// colos
+    pub fn new(metrics: &crate::metrics::Metrics, limit: usize) -> Self {
+        Self {
+            metrics: metrics.clone(),
+            limit,
+            labelled: Default::default(),
+            other: metrics.counter(&format!("{}{{colo=\"other\"}}", Self::NAME), Self::HELP),
+        }
+    }
+
+    /// Count one request from `colo`
+    pub fn record(&self, colo: &str) {
+        if let Some(counter) = self.labelled.read().ok().and_then(|labelled| labelled.get(colo).cloned()) {
+            return counter.inc();
+        }
+        let Ok(mut labelled) = self.labelled.write() else {
+            return;
+        };
+        if let Some(counter) = labelled.get(colo) {
+            counter.inc();
+        } else if labelled.len() < self.limit {
+            let counter = self.metrics.counter(&format!("{}{{colo=\"{}\"}}", Self::NAME, colo), Self::HELP);
+            counter.inc();
+            labelled.insert(colo.to_string(), counter);
+        } else {
+            self.other.inc();
+        }
+    }
+}
+
+/// Middleware parsing `CF-Ray` into the request extensions and the
+/// `cf_colo` span field, and counting the colo
+pub async fn record(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    #[cfg(not(feature = "metrics"))]
+    let _ = state;
+    if let Some(ray) = RayId::from_headers(request.headers()) {
+        if let Some(colo) = &ray.colo {
+            tracing::Span::current().record("cf_colo", colo.as_str());
+            #[cfg(feature = "metrics")]
+            state.colos.record(colo);
+        }
+        request.extensions_mut().insert(ray);
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(value: &str) -> Option<(String, Option<String>)> {
+        RayId::parse(value).map(|ray| (ray.id, ray.colo))
+    }
+
+    #[test]
+    fn test_parse() {
+        let ray = |id: &str, colo: Option<&str>| Some((id.to_string(), colo.map(str::to_string)));
+        assert_eq!(parse("8a1b2c3d4e5f0abc-SJC"), ray("8a1b2c3d4e5f0abc", Some("SJC")));
+        assert_eq!(parse(" 8A1B2C3D4E5F0ABC-lhr "), ray("8a1b2c3d4e5f0abc", Some("LHR")));
+
+        // Missing or malformed suffixes keep the ID
+        assert_eq!(parse("8a1b2c3d4e5f0abc"), ray("8a1b2c3d4e5f0abc", None));
+        assert_eq!(parse("8a1b2c3d4e5f0abc-"), ray("8a1b2c3d4e5f0abc", None));
+        assert_eq!(parse("8a1b2c3d4e5f0abc-SJ"), ray("8a1b2c3d4e5f0abc", None));
+        assert_eq!(parse("8a1b2c3d4e5f0abc-SJC1"), ray("8a1b2c3d4e5f0abc", None));
+        assert_eq!(parse("8a1b2c3d4e5f0abc-S\"C"), ray("8a1b2c3d4e5f0abc", None));
+        assert_eq!(parse("8a1b2c3d4e5f0abc-SJC-LHR"), ray("8a1b2c3d4e5f0abc", None));
+
+        // Malformed IDs give nothing
+        for bad in ["", "-SJC", "not-hex-SJC", "xyz-SJC", "8a1b 2c3d-SJC", &"a".repeat(33)] {
+            assert_eq!(parse(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_colo_labels_are_bounded() {
+        let metrics = crate::metrics::Metrics::default();
+        let colos = ColoCounter::new(&metrics, 3);
+        for colo in ["SJC", "LHR", "SJC", "FRA", "AMS", "NRT", "SJC", "AMS"] {
+            colos.record(colo);
+        }
+
+        let rendered = metrics.render();
+        let series: Vec<&str> = rendered.lines().filter(|line| line.starts_with("cf_colo_requests_total{")).collect();
+        assert_eq!(
+            series,
+            [
+                "cf_colo_requests_total{colo=\"FRA\"} 1",
+                "cf_colo_requests_total{colo=\"LHR\"} 1",
+                "cf_colo_requests_total{colo=\"SJC\"} 3",
+                "cf_colo_requests_total{colo=\"other\"} 3",
+            ]
+        );
+    }
+}
@@ -0,0 +1,233 @@
+/*!
+ * Concurrency limit and backpressure
+ *
+ * `MAX_CONCURRENT_REQUESTS` caps how many requests the main router handles
+ * at once; the rest wait for a slot in arrival order. Waiting counts
+ * towards the request timeout. The probes in `maintenance::EXEMPT_PATHS`
+ * bypass the limit, so `/readyz` and `/metrics` still answer when it is
+ * full. Without a limit, requests are still counted.
+ *
+ * The limiter exposes how many requests are running and how many are
+ * queued, as the `http_requests_running` and `http_requests_queued` gauges
+ * on `/metrics` and as the detail of the `backpressure` readiness check.
+ * Once running plus queued requests reach `BACKPRESSURE_THRESHOLD`, that
+ * check turns to a warning: `/readyz` stays ready, but dashboards see the
+ * pressure before users do. Both settings are read at startup only.
+ */
+use crate::health::{CheckResult, HealthCheck};
+use crate::state::AppState;
+use axum::{
+    async_trait,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Name of the readiness check
+pub const CHECK_NAME: &str = "backpressure";
+
+/// `MAX_CONCURRENT_REQUESTS` and `BACKPRESSURE_THRESHOLD`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ConcurrencyConfig {
+    /// Requests handled at once; unlimited when unset
+    pub max_concurrent: Option<usize>,
+
+    /// Running plus queued requests that turn the check to a warning
+    pub pressure_threshold: Option<usize>,
+}
+
+impl ConcurrencyConfig {
+    /// Load from `MAX_CONCURRENT_REQUESTS` and `BACKPRESSURE_THRESHOLD`
+    pub fn from_env() -> crate::Result<Self> {
+        let positive = |name: &str| -> crate::Result<Option<usize>> {
+            match std::env::var(name) {
+                Ok(value) => match value.trim().parse::<usize>() {
+                    Ok(0) | Err(_) => Err(crate::ServerError::ConfigError(format!(
+                        "Invalid {} {:?}: expected a positive number",
+                        name, value
+                    ))),
+                    Ok(count) => Ok(Some(count)),
+                },
+                Err(_) => Ok(None),
+            }
+        };
+
+        Ok(Self {
+            max_concurrent: positive("MAX_CONCURRENT_REQUESTS")?,
+            pressure_threshold: positive("BACKPRESSURE_THRESHOLD")?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: ConcurrencyConfig,
+    semaphore: Option<Semaphore>,
+    running: AtomicUsize,
+    queued: AtomicUsize,
+    #[cfg(feature = "metrics")]
+    gauges: Option<(crate::metrics::Gauge, crate::metrics::Gauge)>,
+}
+
+/// Slots for requests and the counts of running and waiting ones; clones
+/// share the same slots
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    inner: Arc<Inner>,
+}
+
+impl Default for ConcurrencyLimiter {
+    fn default() -> Self {
+        Self::new(ConcurrencyConfig::default())
+    }
+}
+
+/// One count held until dropped, so cancelled requests are not counted
+/// forever
+struct Held<'a> {
+    count: &'a AtomicUsize,
+    #[cfg(feature = "metrics")]
+    gauge: Option<&'a crate::metrics::Gauge>,
+}
+
+impl Held<'_> {
+    fn start(self) -> Self {
+        self.count.fetch_add(1, Ordering::AcqRel);
+        #[cfg(feature = "metrics")]
+        if let Some(gauge) = self.gauge {
+            gauge.inc();
+        }
+        self
+    }
+}
+
+impl Drop for Held<'_> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+        #[cfg(feature = "metrics")]
+        if let Some(gauge) = self.gauge {
+            gauge.dec();
+        }
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                semaphore: config.max_concurrent.map(Semaphore::new),
+                running: AtomicUsize::new(0),
+                queued: AtomicUsize::new(0),
+                #[cfg(feature = "metrics")]
+                gauges: None,
+            }),
+        }
+    }
+
+    /// The same limiter, mirroring its counts into gauges in `metrics`;
+    /// call before the limiter is cloned
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: &crate::metrics::Metrics) -> Self {
+        if let Some(inner) = Arc::get_mut(&mut self.inner) {
+            inner.gauges = Some((
+                metrics.gauge("http_requests_running", "Requests holding a concurrency slot"),
+                metrics.gauge("http_requests_queued", "Requests waiting for a concurrency slot"),
+            ));
+        }
+        self
+    }
+
+    pub fn config(&self) -> ConcurrencyConfig {
+        self.inner.config
+    }
+
+    /// Requests holding a slot
+    pub fn running(&self) -> usize {
+        self.inner.running.load(Ordering::Acquire)
+    }
+
+    /// Requests waiting for a slot
+    pub fn queued(&self) -> usize {
+        self.inner.queued.load(Ordering::Acquire)
+    }
+
+    fn hold_running(&self) -> Held<'_> {
+        Held {
+            count: &self.inner.running,
+            #[cfg(feature = "metrics")]
+            gauge: self.inner.gauges.as_ref().map(|(running, _)| running),
+        }
+        .start()
+    }
+
+    fn hold_queued(&self) -> Held<'_> {
+        Held {
+            count: &self.inner.queued,
+            #[cfg(feature = "metrics")]
+            gauge: self.inner.gauges.as_ref().map(|(_, queued)| queued),
+        }
+        .start()
+    }
+
+    /// Run `request` once a slot is free
+    async fn run(&self, request: Request, next: Next) -> Response {
+        let permit = match &self.inner.semaphore {
+            Some(semaphore) => {
+                let _queued = self.hold_queued();
+                // The semaphore is never closed
+                semaphore.acquire().await.ok()
+            }
+            None => None,
+        };
+        let _running = self.hold_running();
+        let response = next.run(request).await;
+        drop(permit);
+        response
+    }
+}
+
+/// Middleware holding each non-probe request until a slot is free
+pub async fn limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if crate::maintenance::EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    state.concurrency.run(request, next).await
+}
+
+/// Readiness check reporting the running and queued requests, warning at
+/// `BACKPRESSURE_THRESHOLD`
+pub struct BackpressureCheck {
+    limiter: ConcurrencyLimiter,
+}
+
+impl BackpressureCheck {
+    pub fn new(limiter: &ConcurrencyLimiter) -> Self {
+        Self { limiter: limiter.clone() }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for BackpressureCheck {
+    fn name(&self) -> &str {
+        CHECK_NAME
+    }
+
+    async fn check(&self) -> CheckResult {
+        let config = self.limiter.config();
+        let (running, queued) = (self.limiter.running(), self.limiter.queued());
+        let limit = config.max_concurrent.map_or("unlimited".to_string(), |limit| format!("limit {}", limit));
+        let detail = format!("{} running, {} queued ({})", running, queued, limit);
+        match config.pressure_threshold {
+            Some(threshold) if running + queued >= threshold => CheckResult::warning(
+                CHECK_NAME,
+                format!("{}; at or above BACKPRESSURE_THRESHOLD {}", detail, threshold),
+            ),
+            _ => CheckResult::healthy(CHECK_NAME, detail),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+/*!
+ * Conditional GET for content whose bytes are known up front
+ *
+ * Handlers serving fixed content (the favicon, `robots.txt`) send a strong
+ * `ETag` and, when they know one, a `Last-Modified` timestamp; the homepage
+ * sends a weak one. [`conditional_response`] answers a matching
+ * `If-None-Match` (or, without one, a satisfied `If-Modified-Since`) with
+ * `304 Not Modified`, so Cloudflare revalidates its copy instead of
+ * refetching the body.
+ */
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use std::time::SystemTime;
+
+/// FNV-1a; a `const fn` so embedded content can be hashed at compile time
+pub const fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+/// `hash` as a quoted 16-digit hex strong ETag
+pub const fn etag_bytes(hash: u64) -> [u8; 18] {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+
+    let mut out = [b'"'; 18];
+    let mut i = 0;
+    while i < 16 {
+        out[i + 1] = HEX[((hash >> (60 - 4 * i)) & 0xf) as usize];
+        i += 1;
+    }
+    out
+}
+
+/// Strong ETag for `body`, computed at runtime
+pub fn strong_etag(body: &[u8]) -> HeaderValue {
+    HeaderValue::from_bytes(&etag_bytes(fnv1a(body))).expect("hex ETag is a valid header value")
+}
+
+/// Weak ETag for `body`, for a response whose bytes may differ in ways that
+/// don't matter to caches (the homepage's uptime)
+pub fn weak_etag(body: &[u8]) -> HeaderValue {
+    let mut value = b"W/".to_vec();
+    value.extend_from_slice(&etag_bytes(fnv1a(body)));
+    HeaderValue::from_bytes(&value).expect("hex ETag is a valid header value")
+}
+
+/// Whether an `If-None-Match` value lists `etag` or is `*`. Uses the weak
+/// comparison RFC 9110 prescribes for this header, so `W/"x"` matches `"x"`.
+pub fn etag_matches(if_none_match: &str, etag: &HeaderValue) -> bool {
+    let etag = etag.as_bytes();
+    let etag = etag.strip_prefix(b"W/").unwrap_or(etag);
+
+    if_none_match.split(',').map(str::trim).any(|tag| {
+        tag == "*" || tag.strip_prefix("W/").unwrap_or(tag).as_bytes() == etag
+    })
+}
+
+/// `time` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value).ok().map(|date| date.with_timezone(&Utc))
+}
+
+/// `body` with `ETag`, or `304 Not Modified` carrying the same headers
+/// (`Cache-Control` included) but no body when the request's
+/// `If-None-Match` matches
+pub fn conditional_response(etag: &HeaderValue, body: impl IntoResponse, headers: &HeaderMap) -> Response {
+    conditional_response_modified_at(etag, None, body, headers)
+}
+
+/// [`conditional_response`] that also sends `Last-Modified` and honors
+/// `If-Modified-Since`. As RFC 9110 requires, `If-Modified-Since` is ignored
+/// when the request carries `If-None-Match`.
+pub fn conditional_response_modified_at(
+    etag: &HeaderValue,
+    last_modified: Option<SystemTime>,
+    body: impl IntoResponse,
+    headers: &HeaderMap,
+) -> Response {
+    let header_str = |name| headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok());
+
+    let not_modified = match header_str(header::IF_NONE_MATCH) {
+        Some(if_none_match) => etag_matches(if_none_match, etag),
+        None => match (last_modified, header_str(header::IF_MODIFIED_SINCE).and_then(parse_http_date)) {
+            // HTTP dates have whole-second precision
+            (Some(modified), Some(since)) => DateTime::<Utc>::from(modified).timestamp() <= since.timestamp(),
+            _ => false,
+        },
+    };
+
+    let mut response = body.into_response();
+    if not_modified {
+        let (mut parts, _) = response.into_parts();
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        response = Response::from_parts(parts, Body::empty());
+    }
+
+    let response_headers = response.headers_mut();
+    response_headers.insert(header::ETAG, etag.clone());
+    if let Some(modified) = last_modified {
+        if let Ok(value) = HeaderValue::from_str(&http_date(modified)) {
+            response_headers.insert(header::LAST_MODIFIED, value);
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const ETAG: HeaderValue = HeaderValue::from_static("\"0123456789abcdef\"");
+
+    fn request(name: header::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_strong_etag_matches_const_hash() {
+        assert_eq!(strong_etag(b"hello").as_bytes(), &etag_bytes(fnv1a(b"hello")));
+        assert_ne!(strong_etag(b"hello"), strong_etag(b"hello!"));
+    }
+
+    #[test]
+    fn test_if_none_match_forms() {
+        assert!(etag_matches("\"0123456789abcdef\"", &ETAG));
+        assert!(etag_matches("W/\"0123456789abcdef\"", &ETAG));
+        assert!(etag_matches("\"other\", \"0123456789abcdef\"", &ETAG));
+        assert!(etag_matches("*", &ETAG));
+        assert!(!etag_matches("\"other\"", &ETAG));
+        assert!(!etag_matches("", &ETAG));
+    }
+
+    #[test]
+    fn test_fresh_and_stale_etag() {
+        let body = ([(header::CACHE_CONTROL, "max-age=60")], "body");
+        let response = conditional_response(&ETAG, body, &request(header::IF_NONE_MATCH, "\"0123456789abcdef\""));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], ETAG);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=60");
+
+        let response = conditional_response(&ETAG, "body", &request(header::IF_NONE_MATCH, "\"stale\""));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], ETAG);
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let at = |value: &str| {
+            conditional_response_modified_at(&ETAG, Some(modified), "body", &request(header::IF_MODIFIED_SINCE, value))
+                .status()
+        };
+
+        assert_eq!(http_date(modified), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(at("Sun, 06 Nov 1994 08:49:37 GMT"), StatusCode::NOT_MODIFIED);
+        assert_eq!(at("Mon, 07 Nov 1994 00:00:00 GMT"), StatusCode::NOT_MODIFIED);
+        assert_eq!(at("Sat, 05 Nov 1994 00:00:00 GMT"), StatusCode::OK);
+        assert_eq!(at("not a date"), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_if_none_match_takes_precedence() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let mut headers = request(header::IF_NONE_MATCH, "\"stale\"");
+        headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_static("Mon, 07 Nov 1994 00:00:00 GMT"));
+
+        let response = conditional_response_modified_at(&ETAG, Some(modified), "body", &headers);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::LAST_MODIFIED], "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
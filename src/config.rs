@@ -4,11 +4,639 @@
  * Provides configurable security policies that can be set via environment variables
  * or configuration files, with sensible defaults for production deployment.
  */
+use crate::admin::AdminConfig;
+use crate::access::AccessConfig;
+use crate::api_keys::ApiKeyConfig;
+use crate::audit::AuditConfig;
+use crate::auth::AuthRule;
+use crate::access_log::AccessLogConfig;
+use crate::cache::CacheConfig;
+use crate::canary::CanaryConfig;
+use crate::notifications::NotificationsConfig;
+#[cfg(feature = "cloudflare-api")]
+use crate::cloudflare::api::CloudflareApiConfig;
+use crate::cookies::CookiePolicy;
+use crate::csrf::CsrfConfig;
+use crate::http_client::HttpClientConfig;
+use crate::request_id::RequestIdConfig;
+use crate::timeouts::TimeoutPolicy;
+use crate::concurrency::ConcurrencyConfig;
+use crate::response_cache::ResponseCacheConfig;
+use crate::content_digest::ResponseDigestConfig;
+use crate::decompression::DecompressionConfig;
+use crate::denial::DenialConfig;
+use crate::direct_access::DirectAccessPolicy;
+use crate::drain::DrainConfig;
+use crate::error_pages::ErrorPages;
+use crate::events::EventsConfig;
+use crate::favicon::Favicon;
+use crate::header_budget::{HeaderBudget, HeaderBudgetConfig};
+use crate::header_limits::HeaderLimits;
+use crate::idempotency::IdempotencyConfig;
+#[cfg(feature = "debug-endpoints")]
+use crate::json_schema::Schemas;
+use crate::maintenance::MaintenanceConfig;
+use crate::normalize::PathNormalization;
+use crate::protocol::ProtocolConfig;
+#[cfg(feature = "proxy")]
+use crate::proxy::ProxyConfig;
+use crate::robots::RobotsPolicy;
+use crate::slo::SloConfig;
+use crate::static_files::StaticConfig;
+use crate::tenants::Tenants;
+use crate::tunnel::TunnelConfig;
+use crate::tunnel_health::TunnelHealthConfig;
+#[cfg(feature = "turnstile")]
+use crate::turnstile::TurnstileConfig;
+use crate::unknown_paths::UnknownPathsConfig;
+use crate::webhooks::WebhookConfig;
+use crate::headers as names;
+use crate::headers::HeaderModes;
+use crate::log_level::LogLevelConfig;
+use crate::request_events::RequestEventsConfig;
+use crate::client_ip::TrustedProxies;
+use axum::http::{HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+#[cfg(feature = "debug-endpoints")]
+use std::time::Duration;
+use tracing::warn;
 
+/// Everything `create_app` needs to build the router
+#[derive(Debug, Clone, Default)]
+pub struct AppConfig {
+    /// Security header policy
+    pub security: SecurityConfig,
+    
+    /// Whether each security header replaces a handler's value
+    /// (`SECURITY_HEADER_MODE`, `SECURITY_HEADER_MODES`)
+    pub header_modes: HeaderModes,
+    
+    /// Cache-Control / CDN-Cache-Control rules per path prefix
+    pub cache: CacheConfig,
+    
+    /// Turnstile verification, enabled when a secret key is configured
+    #[cfg(feature = "turnstile")]
+    pub turnstile: Option<TurnstileConfig>,
+    
+    /// Mount debugging endpoints such as `/whoami` (`DEBUG_ENDPOINTS`)
+    #[cfg(feature = "debug-endpoints")]
+    pub debug_endpoints: bool,
+    
+    /// Limits for the debug endpoints
+    #[cfg(feature = "debug-endpoints")]
+    pub debug: DebugConfig,
+    
+    /// Route groups switched on or off at startup, overriding their defaults
+    /// (`FEATURE_TOGGLES`)
+    pub feature_toggles: BTreeMap<String, bool>,
+    
+    /// Redirect plain-HTTP visitors to https (`FORCE_HTTPS_REDIRECT`)
+    pub force_https_redirect: bool,
+    
+    /// Run `self_test::CHECKS` through the router before binding
+    /// (`SELF_TEST_ON_STARTUP`)
+    pub self_test_on_startup: bool,
+    
+    /// Send `X-Build-Id` and `X-Config-Hash` (`EXPOSE_BUILD_HEADERS`)
+    pub expose_build_headers: bool,
+    
+    /// Send `Server-Timing` with the time spent on each request
+    /// (`SERVER_TIMING`)
+    pub server_timing: bool,
+    
+    /// Proxies between cloudflared and the service (`TRUSTED_PROXY_HOPS`,
+    /// `TRUSTED_PROXY_CIDRS`)
+    pub trusted_proxies: TrustedProxies,
+    
+    /// Handling of requests that bypassed Cloudflare (`DIRECT_ACCESS_POLICY`)
+    pub direct_access: DirectAccessPolicy,
+    
+    /// Header marking blocked requests for Cloudflare rules
+    /// (`DENIAL_SIGNAL_HEADER`, `DENIAL_SIGNAL_VALUE`)
+    pub denial: DenialConfig,
+    
+    /// Delay before an admin drain withdraws readiness (`DRAIN_DELAY_SECS`)
+    pub drain: DrainConfig,
+    
+    /// Handling of `/health/` and `//health` style paths (`PATH_NORMALIZATION`)
+    pub path_normalization: PathNormalization,
+    
+    /// Request header count and size limits answered with 431
+    pub header_limits: HeaderLimits,
+    
+    /// Extra response headers and the response header size limits
+    /// (`EXTRA_RESPONSE_HEADERS`, `RESPONSE_HEADER_*_BYTES`)
+    pub header_budget: HeaderBudgetConfig,
+    
+    /// Caps on gzip request bodies, before and after inflating
+    pub decompression: DecompressionConfig,
+    
+    /// Largest response body sent before it is cut off (`MAX_RESPONSE_BODY_BYTES`)
+    pub max_response_body_bytes: Option<u64>,
+    
+    /// Routes whose responses carry a `Content-Digest` (`RESPONSE_DIGEST_*`)
+    pub response_digest: Option<ResponseDigestConfig>,
+    
+    /// HTML pages for browsers hitting an error (`ERROR_PAGES_DIR`)
+    pub error_pages: ErrorPages,
+    
+    /// Attributes enforced on outgoing `Set-Cookie` headers
+    pub cookies: CookiePolicy,
+    
+    /// Availability objective reported against at `/admin/slo` (`SLO_TARGET`)
+    pub slo: SloConfig,
+    
+    /// Separate admin listener, enabled by `ADMIN_ADDR`
+    pub admin: Option<AdminConfig>,
+    
+    /// Cloudflare API credentials used by admin operations
+    #[cfg(feature = "cloudflare-api")]
+    pub cloudflare_api: Option<CloudflareApiConfig>,
+    
+    /// Embedded cloudflared quick tunnel (`RUN_CLOUDFLARED=quick`)
+    pub tunnel: Option<TunnelConfig>,
+    
+    /// cloudflared `/ready` polling for `/readyz` (`CLOUDFLARED_METRICS_URL`)
+    pub tunnel_health: Option<TunnelHealthConfig>,
+    
+    /// Static files served from `STATIC_DIR`
+    pub static_files: Option<StaticConfig>,
+    
+    /// Path prefixes forwarded to upstream services (`PROXY_ROUTES`)
+    #[cfg(feature = "proxy")]
+    pub proxy: Option<ProxyConfig>,
+    
+    /// Icon served at `/favicon.ico`, embedded unless `FAVICON_PATH` is set
+    pub favicon: Favicon,
+    
+    /// Body of `/robots.txt` (`ROBOTS_POLICY`)
+    pub robots: RobotsPolicy,
+    
+    /// `/events` stream timing and limits
+    pub events: EventsConfig,
+    
+    /// Signed webhooks accepted at `/webhooks/{name}` (`WEBHOOKS`)
+    pub webhooks: Vec<WebhookConfig>,
+    
+    /// Maintenance mode at startup, `Retry-After` and the sentinel file
+    pub maintenance: MaintenanceConfig,
+    
+    /// Path prefixes that require a bearer token (`AUTH_RULES`)
+    pub auth: Vec<AuthRule>,
+    
+    /// Path prefixes that require a named `X-Api-Key` (`API_KEYS`)
+    pub api_keys: Option<ApiKeyConfig>,
+    
+    /// Cloudflare Access application whose tokens identify users
+    /// (`ACCESS_TEAM_DOMAIN`, `ACCESS_AUD`)
+    pub access: Option<AccessConfig>,
+    
+    /// Path prefixes whose form submissions need a CSRF token (`CSRF_PREFIXES`)
+    pub csrf: Option<CsrfConfig>,
+    
+    /// Routes cached in memory at the origin (`RESPONSE_CACHE`)
+    pub response_cache: ResponseCacheConfig,
+    
+    /// Timeouts, pooling and proxy for outbound calls (`HTTP_CLIENT_*`)
+    pub http_client: HttpClientConfig,
+    
+    /// Request ID header, trust and format (`REQUEST_ID_*`)
+    pub request_id: RequestIdConfig,
+    
+    /// Global and per-prefix request timeouts (`REQUEST_TIMEOUT_SECS`,
+    /// `REQUEST_TIMEOUTS`)
+    pub timeouts: TimeoutPolicy,
+    
+    /// Concurrency limit and backpressure warning (`MAX_CONCURRENT_REQUESTS`,
+    /// `BACKPRESSURE_THRESHOLD`)
+    pub concurrency: ConcurrencyConfig,
+    
+    /// `Idempotency-Key` prefixes, requirement and retention (`IDEMPOTENCY_*`)
+    pub idempotency: IdempotencyConfig,
+    
+    /// Access log file and its rotation (`ACCESS_LOG_*`)
+    pub access_log: Option<AccessLogConfig>,
+    
+    /// Share of requests whose full headers are logged (`HEADER_SAMPLE_RATE`,
+    /// 0 disables)
+    pub header_sample_rate: f64,
+    
+    /// Security header profiles per hostname (`TENANTS`)
+    pub tenants: Tenants,
+    
+    /// HTTP/1.1 and h2c settings on the listeners (`HTTP1_*`, `HTTP2_*`)
+    pub protocol: ProtocolConfig,
+    
+    /// Tracking and logging of requests that matched no route
+    /// (`UNKNOWN_PATHS_*`)
+    pub unknown_paths: UnknownPathsConfig,
+    
+    /// Admin actions kept in memory (`AUDIT_MAX_ENTRIES`)
+    pub audit: AuditConfig,
+    
+    /// How long a log filter set at runtime lasts (`LOG_LEVEL_REVERT_SECS`)
+    pub log_level: LogLevelConfig,
+    
+    /// How far request event subscribers may fall behind
+    /// (`REQUEST_EVENTS_CAPACITY`)
+    pub request_events: RequestEventsConfig,
+    
+    /// Experiments splitting visitors between control and canary
+    /// (`EXPERIMENTS`)
+    pub canary: CanaryConfig,
+    
+    /// Webhooks told about lifecycle, health and maintenance changes
+    /// (`NOTIFY_*`)
+    pub notifications: Option<NotificationsConfig>,
+}
+
+impl AppConfig {
+    /// Load every configuration section from the environment
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self {
+            security: SecurityConfig::from_env()?,
+            header_modes: HeaderModes::from_env()?,
+            cache: CacheConfig::from_env()?,
+            #[cfg(feature = "turnstile")]
+            turnstile: TurnstileConfig::from_env()?,
+            trusted_proxies: TrustedProxies::from_env()?,
+            direct_access: DirectAccessPolicy::from_env()?,
+            denial: DenialConfig::from_env()?,
+            drain: DrainConfig::from_env()?,
+            path_normalization: PathNormalization::from_env()?,
+            header_limits: HeaderLimits::from_env()?,
+            header_budget: HeaderBudgetConfig::from_env()?,
+            decompression: DecompressionConfig::from_env()?,
+            max_response_body_bytes: crate::response_size::from_env()?,
+            response_digest: ResponseDigestConfig::from_env()?,
+            expose_build_headers: crate::build_info::from_env()?,
+            server_timing: crate::server_timing::from_env()?,
+            error_pages: ErrorPages::from_env()?,
+            slo: SloConfig::from_env()?,
+            cookies: CookiePolicy::from_env()?,
+            admin: AdminConfig::from_env()?,
+            #[cfg(feature = "cloudflare-api")]
+            cloudflare_api: CloudflareApiConfig::from_env()?,
+            tunnel: TunnelConfig::from_env()?,
+            tunnel_health: TunnelHealthConfig::from_env()?,
+            static_files: StaticConfig::from_env()?,
+            #[cfg(feature = "proxy")]
+            proxy: ProxyConfig::from_env()?,
+            favicon: Favicon::from_env()?,
+            robots: RobotsPolicy::from_env()?,
+            events: EventsConfig::from_env()?,
+            webhooks: WebhookConfig::from_env()?,
+            maintenance: MaintenanceConfig::from_env()?,
+            auth: AuthRule::from_env()?,
+            api_keys: ApiKeyConfig::from_env()?,
+            access: AccessConfig::from_env()?,
+            csrf: CsrfConfig::from_env()?,
+            response_cache: ResponseCacheConfig::from_env()?,
+            http_client: HttpClientConfig::from_env()?,
+            request_id: RequestIdConfig::from_env()?,
+            timeouts: TimeoutPolicy::from_env()?,
+            concurrency: ConcurrencyConfig::from_env()?,
+            idempotency: IdempotencyConfig::from_env()?,
+            access_log: AccessLogConfig::from_env()?,
+            header_sample_rate: crate::header_sampling::from_env()?,
+            protocol: ProtocolConfig::from_env()?,
+            unknown_paths: UnknownPathsConfig::from_env()?,
+            audit: AuditConfig::from_env()?,
+            log_level: LogLevelConfig::from_env()?,
+            request_events: RequestEventsConfig::from_env()?,
+            canary: CanaryConfig::from_env()?,
+            notifications: NotificationsConfig::from_env()?,
+            feature_toggles: crate::features::from_env()?,
+            ..Self::default()
+        };
+        
+        config.tenants = Tenants::from_env(&config.security)?;
+        for warning in HeaderBudget::new(&config).warnings() {
+            warn!("{}", warning);
+        }
+        
+        #[cfg(feature = "debug-endpoints")]
+        if let Ok(value) = std::env::var("DEBUG_ENDPOINTS") {
+            config.debug_endpoints = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid debug endpoints flag: {}", e)
+                ))?;
+        }
+        
+        #[cfg(feature = "debug-endpoints")]
+        {
+            config.debug = DebugConfig::from_env()?;
+        }
+        
+        if let Ok(value) = std::env::var("FORCE_HTTPS_REDIRECT") {
+            config.force_https_redirect = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid HTTPS redirect flag: {}", e)
+                ))?;
+        }
+        
+        if let Ok(value) = std::env::var("SELF_TEST_ON_STARTUP") {
+            config.self_test_on_startup = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid SELF_TEST_ON_STARTUP flag: {}", e)
+                ))?;
+        }
+        
+        warn_disabled_features();
+        
+        Ok(config)
+    }
+}
+
+/// Limits for the debug endpoints
+#[cfg(feature = "debug-endpoints")]
+#[derive(Debug, Clone)]
+pub struct DebugConfig {
+    /// Longest delay `/delay/{seconds}` will honor
+    pub max_delay: Duration,
+    
+    /// Largest message `/ws` accepts; bigger ones close the socket with 1009
+    pub ws_max_message_bytes: usize,
+    
+    /// `/ws` connections silent for this long are closed
+    pub ws_idle_timeout: Duration,
+    
+    /// Named schemas for `POST /validate`, compiled from `SCHEMAS_DIR`
+    pub schemas: Schemas,
+}
+
+#[cfg(feature = "debug-endpoints")]
+impl Default for DebugConfig {
+    fn default() -> Self {
+        Self {
+            max_delay: Duration::from_secs(30),
+            ws_max_message_bytes: 64 * 1024,
+            ws_idle_timeout: Duration::from_secs(60),
+            schemas: Schemas::default(),
+        }
+    }
+}
+
+#[cfg(feature = "debug-endpoints")]
+impl DebugConfig {
+    /// Load from `DEBUG_MAX_DELAY_SECS`, `DEBUG_WS_MAX_MESSAGE_BYTES`,
+    /// `DEBUG_WS_IDLE_TIMEOUT_SECS` and `SCHEMAS_DIR`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        
+        if let Ok(value) = std::env::var("DEBUG_MAX_DELAY_SECS") {
+            let secs: u64 = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid DEBUG_MAX_DELAY_SECS: {}", e)
+                ))?;
+            config.max_delay = Duration::from_secs(secs);
+        }
+        
+        if let Ok(value) = std::env::var("DEBUG_WS_MAX_MESSAGE_BYTES") {
+            config.ws_max_message_bytes = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid DEBUG_WS_MAX_MESSAGE_BYTES: {}", e)
+                ))?;
+        }
+        
+        if let Ok(value) = std::env::var("DEBUG_WS_IDLE_TIMEOUT_SECS") {
+            let secs: u64 = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid DEBUG_WS_IDLE_TIMEOUT_SECS: {}", e)
+                ))?;
+            config.ws_idle_timeout = Duration::from_secs(secs);
+        }
+        
+        config.schemas = Schemas::from_env()?;
+        
+        Ok(config)
+    }
+}
+
+/// Environment variables belonging to cargo features left out of this build
+const DISABLED_FEATURE_VARS: &[(&str, &[&str])] = &[
+    #[cfg(not(feature = "debug-endpoints"))]
+    ("debug-endpoints", &[
+        "DEBUG_ENDPOINTS",
+        "DEBUG_MAX_DELAY_SECS",
+        "DEBUG_WS_MAX_MESSAGE_BYTES",
+        "DEBUG_WS_IDLE_TIMEOUT_SECS",
+        "SCHEMAS_DIR",
+    ]),
+    #[cfg(not(feature = "cloudflare-api"))]
+    ("cloudflare-api", &[
+        "CLOUDFLARE_API_TOKEN",
+        "CLOUDFLARE_API_TOKEN_FILE",
+        "CLOUDFLARE_ZONE_ID",
+        "CLOUDFLARE_ZONE_ID_FILE",
+        "CLOUDFLARE_API_BASE_URL",
+    ]),
+    #[cfg(not(feature = "proxy"))]
+    ("proxy", &["PROXY_ROUTES", "PROXY_TIMEOUT_SECS"]),
+    // The OpenTelemetry SDK's own variables, for deployments that set them
+    // expecting trace export
+    #[cfg(not(feature = "otel"))]
+    ("otel", &["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_SERVICE_NAME"]),
+    #[cfg(not(feature = "turnstile"))]
+    ("turnstile", &[
+        "TURNSTILE_SECRET_KEY",
+        "TURNSTILE_SECRET_KEY_FILE",
+        "TURNSTILE_VERIFY_URL",
+        "TURNSTILE_TIMEOUT_MS",
+    ]),
+];
+
+/// Settings for compiled-out features are ignored rather than rejected, so
+/// one environment file works with every build of the image
+fn warn_disabled_features() {
+    for (feature, vars) in DISABLED_FEATURE_VARS {
+        for var in vars.iter().filter(|var| std::env::var_os(var).is_some()) {
+            warn!("{} is set but this build lacks the `{}` feature; ignoring it", var, feature);
+        }
+    }
+}
+
+/// Environment variables that have been renamed, as `(legacy, current)`.
+/// Deployments may still set the legacy name; this table is the only place
+/// that knows about it.
+pub const LEGACY_ENV_VARS: &[(&str, &str)] = &[
+    ("SERVER_HEADER", "SECURITY_SERVER_HEADER"),
+];
+
+/// The legacy name of `current`, if it was renamed
+pub fn legacy_env_var(current: &str) -> Option<&'static str> {
+    LEGACY_ENV_VARS.iter().find(|(_, name)| *name == current).map(|(legacy, _)| *legacy)
+}
+
+/// The value of `name` as seen through `lookup`, falling back to its legacy
+/// name with a deprecation warning. A legacy name set to a different value
+/// than its current name is an error, since neither can be said to win.
+pub fn env_var_with(name: &str, lookup: impl Fn(&str) -> Option<String>) -> crate::Result<Option<String>> {
+    let current = lookup(name);
+    let Some(legacy) = legacy_env_var(name) else {
+        return Ok(current);
+    };
+    match (current, lookup(legacy)) {
+        (Some(current), Some(value)) if current != value => Err(crate::ServerError::ConfigError(format!(
+            "{} is {:?} but its legacy name {} is {:?}; unset {}",
+            name, current, legacy, value, legacy
+        ))),
+        (None, Some(value)) => {
+            warn!("{} is deprecated; set {} instead", legacy, name);
+            Ok(Some(value))
+        }
+        (current, _) => Ok(current),
+    }
+}
+
+/// [`env_var_with`] over the process environment
+pub fn env_var(name: &str) -> crate::Result<Option<String>> {
+    env_var_with(name, |name| std::env::var(name).ok())
+}
+
+impl From<SecurityConfig> for AppConfig {
+    fn from(security: SecurityConfig) -> Self {
+        Self {
+            security,
+            ..Self::default()
+        }
+    }
+}
+
+/// Read a secret from the `name` environment variable, or from the file named
+/// by `<name>_FILE` (Docker/Kubernetes secrets style). The direct variable wins
+/// when both are set; surrounding whitespace is trimmed from file contents.
+pub fn read_secret(name: &str) -> crate::Result<Option<String>> {
+    if let Ok(value) = std::env::var(name) {
+        return Ok(Some(value));
+    }
+    
+    let file_var = format!("{}_FILE", name);
+    match std::env::var(&file_var) {
+        Ok(path) => std::fs::read_to_string(&path)
+            .map(|contents| Some(contents.trim().to_string()))
+            .map_err(|e| crate::ServerError::ConfigError(
+                format!("Failed to read {} from {}: {}", file_var, path, e)
+            )),
+        Err(_) => Ok(None),
+    }
+}
+
+/// A field of a configuration patch that could not be applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    /// Dotted path to the field, e.g. `csp.script_src`; empty for the
+    /// whole body
+    pub path: String,
+    pub message: String,
+}
+
+/// Apply `patch`, a JSON object with some or all of `base`'s fields, over
+/// `base`. Nested objects are merged key by key and any other value
+/// replaces the one in `base`, so `{"csp": {"script_src": "'self'"}}`
+/// changes one directive and keeps the rest. Keys `base` does not have and
+/// values of the wrong type are reported per field.
+pub fn merge_patch<T>(base: &T, patch: &serde_json::Value) -> Result<T, Vec<FieldError>>
+where
+    T: Serialize + serde::de::DeserializeOwned,
+{
+    fn merge(target: &mut serde_json::Value, patch: &serde_json::Value, path: &str, errors: &mut Vec<FieldError>) {
+        let (Some(target), Some(patch)) = (target.as_object_mut(), patch.as_object()) else {
+            return errors.push(FieldError { path: path.to_string(), message: "expected an object".to_string() });
+        };
+        for (key, value) in patch {
+            let field = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+            match target.get_mut(key) {
+                Some(existing) if existing.is_object() && value.is_object() => merge(existing, value, &field, errors),
+                Some(existing) => *existing = value.clone(),
+                None => errors.push(FieldError { path: field, message: "unknown field".to_string() }),
+            }
+        }
+    }
+
+    let mut merged = serde_json::to_value(base)
+        .map_err(|e| vec![FieldError { path: String::new(), message: e.to_string() }])?;
+    let mut errors = Vec::new();
+    merge(&mut merged, patch, "", &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    serde_path_to_error::deserialize(merged).map_err(|e| {
+        let path = e.path().to_string();
+        vec![FieldError {
+            path: if path == "." { String::new() } else { path },
+            message: e.into_inner().to_string(),
+        }]
+    })
+}
+
+/// Named starting points for `SecurityConfig`, selected with
+/// `SECURITY_PRESET`; individual `SECURITY_*` variables still override them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityPreset {
+    /// Balanced defaults for a small HTML site
+    #[default]
+    Default,
+    
+    /// No inline styles, no referrer, two-year HSTS
+    Strict,
+    
+    /// JSON APIs: nothing may be loaded, framed or submitted
+    Api,
+    
+    /// Allows https: subresources and same-origin framing; short HSTS
+    Relaxed,
+    
+    /// Local development: no HSTS, inline and eval scripts, websocket HMR
+    Dev,
+}
+
+impl SecurityPreset {
+    /// Every preset, in documentation order
+    pub const ALL: [SecurityPreset; 5] = [
+        SecurityPreset::Default,
+        SecurityPreset::Strict,
+        SecurityPreset::Api,
+        SecurityPreset::Relaxed,
+        SecurityPreset::Dev,
+    ];
+    
+    /// Name accepted by `SECURITY_PRESET`
+    pub fn name(self) -> &'static str {
+        match self {
+            SecurityPreset::Default => "default",
+            SecurityPreset::Strict => "strict",
+            SecurityPreset::Api => "api",
+            SecurityPreset::Relaxed => "relaxed",
+            SecurityPreset::Dev => "dev",
+        }
+    }
+}
+
+impl std::str::FromStr for SecurityPreset {
+    type Err = crate::ServerError;
+    
+    fn from_str(value: &str) -> crate::Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|preset| preset.name().eq_ignore_ascii_case(value.trim()))
+            .ok_or_else(|| crate::ServerError::ConfigError(format!(
+                "Invalid SECURITY_PRESET {:?}: expected default, strict, api, relaxed or dev",
+                value
+            )))
+    }
+}
+
+/// Values for the security headers added to every response
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityConfig {
+    /// Preset the values started from; informational once fields are overridden
+    #[serde(default)]
+    pub preset: SecurityPreset,
+    
     /// X-Content-Type-Options header value
     pub content_type_options: String,
     
@@ -30,10 +658,16 @@ pub struct SecurityConfig {
     /// Permissions-Policy header value
     pub permissions_policy: String,
     
+    /// Reporting API endpoint group that CSP and Permissions-Policy
+    /// violations are reported to; `Reporting-Endpoints` points it at
+    /// `/reports`. No reporting when unset.
+    pub report_to: Option<String>,
+    
     /// Server header value
     pub server_header: String,
 }
 
+/// Parts of the `Strict-Transport-Security` header
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HstsConfig {
     /// Max age in seconds
@@ -46,6 +680,7 @@ pub struct HstsConfig {
     pub preload: bool,
 }
 
+/// Directives of the `Content-Security-Policy` header
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CspConfig {
     /// Default source directive
@@ -91,6 +726,7 @@ pub struct CspConfig {
 impl Default for SecurityConfig {
     fn default() -> Self {
         Self {
+            preset: SecurityPreset::Default,
             content_type_options: "nosniff".to_string(),
             frame_options: "DENY".to_string(),
             xss_protection: "1; mode=block".to_string(),
@@ -98,6 +734,7 @@ impl Default for SecurityConfig {
             csp: CspConfig::default(),
             referrer_policy: "strict-origin-when-cross-origin".to_string(),
             permissions_policy: "geolocation=(), microphone=(), camera=()".to_string(),
+            report_to: None,
             server_header: "cloudflare-tunnel-example".to_string(),
         }
     }
@@ -133,10 +770,112 @@ impl Default for CspConfig {
     }
 }
 
+impl CspConfig {
+    /// Directive names paired with their source lists, in header order
+    pub fn directives(&self) -> [(&'static str, &str); 13] {
+        [
+            ("default-src", &self.default_src),
+            ("script-src", &self.script_src),
+            ("style-src", &self.style_src),
+            ("img-src", &self.img_src),
+            ("connect-src", &self.connect_src),
+            ("font-src", &self.font_src),
+            ("object-src", &self.object_src),
+            ("media-src", &self.media_src),
+            ("frame-src", &self.frame_src),
+            ("child-src", &self.child_src),
+            ("worker-src", &self.worker_src),
+            ("base-uri", &self.base_uri),
+            ("form-action", &self.form_action),
+        ]
+    }
+}
+
 impl SecurityConfig {
+    /// Header values for `preset`
+    pub fn preset(preset: SecurityPreset) -> Self {
+        let default = Self::default();
+        let none = || "'none'".to_string();
+        
+        match preset {
+            SecurityPreset::Default => default,
+            SecurityPreset::Strict => Self {
+                preset,
+                hsts: HstsConfig { max_age: 63072000, ..HstsConfig::default() },
+                csp: CspConfig {
+                    default_src: none(),
+                    style_src: "'self'".to_string(),
+                    img_src: "'self'".to_string(),
+                    media_src: none(),
+                    base_uri: none(),
+                    ..CspConfig::default()
+                },
+                referrer_policy: "no-referrer".to_string(),
+                permissions_policy: "accelerometer=(), camera=(), geolocation=(), gyroscope=(), \
+                    magnetometer=(), microphone=(), payment=(), usb=()".to_string(),
+                ..default
+            },
+            SecurityPreset::Api => Self {
+                preset,
+                csp: CspConfig {
+                    default_src: none(),
+                    script_src: none(),
+                    style_src: none(),
+                    img_src: none(),
+                    connect_src: none(),
+                    font_src: none(),
+                    object_src: none(),
+                    media_src: none(),
+                    frame_src: none(),
+                    child_src: none(),
+                    worker_src: none(),
+                    base_uri: none(),
+                    form_action: none(),
+                },
+                referrer_policy: "no-referrer".to_string(),
+                ..default
+            },
+            SecurityPreset::Relaxed => Self {
+                preset,
+                frame_options: "SAMEORIGIN".to_string(),
+                hsts: HstsConfig { max_age: 86400, include_subdomains: false, preload: false },
+                csp: CspConfig {
+                    script_src: "'self' https:".to_string(),
+                    style_src: "'self' 'unsafe-inline' https:".to_string(),
+                    img_src: "'self' data: https:".to_string(),
+                    connect_src: "'self' https:".to_string(),
+                    font_src: "'self' data: https:".to_string(),
+                    media_src: "'self' https:".to_string(),
+                    frame_src: "'self' https:".to_string(),
+                    child_src: "'self'".to_string(),
+                    worker_src: "'self'".to_string(),
+                    ..CspConfig::default()
+                },
+                ..default
+            },
+            SecurityPreset::Dev => Self {
+                preset,
+                frame_options: "SAMEORIGIN".to_string(),
+                // HSTS on localhost sticks to every other local project
+                hsts: HstsConfig { max_age: 0, include_subdomains: false, preload: false },
+                csp: CspConfig {
+                    script_src: "'self' 'unsafe-inline' 'unsafe-eval'".to_string(),
+                    connect_src: "'self' ws: wss:".to_string(),
+                    img_src: "'self' data: blob:".to_string(),
+                    worker_src: "'self' blob:".to_string(),
+                    ..CspConfig::default()
+                },
+                ..default
+            },
+        }
+    }
+    
     /// Load configuration from environment variables with fallback to defaults
     pub fn from_env() -> crate::Result<Self> {
-        let mut config = Self::default();
+        let mut config = match std::env::var("SECURITY_PRESET") {
+            Ok(value) => Self::preset(value.parse()?),
+            Err(_) => Self::default(),
+        };
         
         // Override with environment variables if present
         if let Ok(value) = std::env::var("SECURITY_CONTENT_TYPE_OPTIONS") {
@@ -192,10 +931,15 @@ impl SecurityConfig {
             config.permissions_policy = value;
         }
         
-        if let Ok(value) = std::env::var("SERVER_HEADER") {
+        if let Ok(value) = std::env::var("SECURITY_REPORT_TO") {
+            config.report_to = Some(value.trim().to_string()).filter(|group| !group.is_empty());
+        }
+        
+        if let Some(value) = env_var("SECURITY_SERVER_HEADER")? {
             config.server_header = value;
         }
         
+        config.validate()?;
         Ok(config)
     }
     
@@ -216,35 +960,120 @@ impl SecurityConfig {
     
     /// Generate CSP header value from configuration
     pub fn csp_header_value(&self) -> String {
-        vec![
-            format!("default-src {}", self.csp.default_src),
-            format!("script-src {}", self.csp.script_src),
-            format!("style-src {}", self.csp.style_src),
-            format!("img-src {}", self.csp.img_src),
-            format!("connect-src {}", self.csp.connect_src),
-            format!("font-src {}", self.csp.font_src),
-            format!("object-src {}", self.csp.object_src),
-            format!("media-src {}", self.csp.media_src),
-            format!("frame-src {}", self.csp.frame_src),
-            format!("child-src {}", self.csp.child_src),
-            format!("worker-src {}", self.csp.worker_src),
-            format!("base-uri {}", self.csp.base_uri),
-            format!("form-action {}", self.csp.form_action),
-        ].join("; ")
-    }
-    
-    /// Get all headers as a HashMap for easy iteration
-    pub fn to_headers(&self) -> HashMap<String, String> {
-        let mut headers = HashMap::new();
-        
-        headers.insert("X-Content-Type-Options".to_string(), self.content_type_options.clone());
-        headers.insert("X-Frame-Options".to_string(), self.frame_options.clone());
-        headers.insert("X-XSS-Protection".to_string(), self.xss_protection.clone());
-        headers.insert("Strict-Transport-Security".to_string(), self.hsts_header_value());
-        headers.insert("Content-Security-Policy".to_string(), self.csp_header_value());
-        headers.insert("Referrer-Policy".to_string(), self.referrer_policy.clone());
-        headers.insert("Permissions-Policy".to_string(), self.permissions_policy.clone());
+        let mut directives: Vec<String> = self.csp
+            .directives()
+            .iter()
+            .map(|(name, sources)| format!("{} {}", name, sources))
+            .collect();
+        if let Some(group) = &self.report_to {
+            directives.push(format!("report-to {}", group));
+        }
+        directives.join("; ")
+    }
+    
+    /// Generate Permissions-Policy header value, adding a `report-to`
+    /// parameter to every feature that does not name its own group
+    pub fn permissions_policy_header_value(&self) -> String {
+        let Some(group) = &self.report_to else {
+            return self.permissions_policy.clone();
+        };
+        self.permissions_policy
+            .split(',')
+            .map(str::trim)
+            .filter(|feature| !feature.is_empty())
+            .map(|feature| {
+                if feature.contains(";report-to=") {
+                    feature.to_string()
+                } else {
+                    format!("{};report-to={}", feature, group)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+    
+    /// `Reporting-Endpoints` value sending the `report_to` group to
+    /// `/reports`, if reporting is on
+    pub fn reporting_endpoints_header_value(&self) -> Option<String> {
+        self.report_to
+            .as_ref()
+            .map(|group| format!("{}=\"{}\"", group, crate::reports::PATH))
+    }
+    
+    /// Reject values that cannot be sent as a header (control characters
+    /// such as CR/LF) and CSP source lists that would smuggle in extra
+    /// directives through `;` or `,`
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(crate::ServerError::ConfigError(msg));
+        
+        let values = [
+            ("content type options", &self.content_type_options),
+            ("frame options", &self.frame_options),
+            ("XSS protection", &self.xss_protection),
+            ("referrer policy", &self.referrer_policy),
+            ("permissions policy", &self.permissions_policy),
+            ("server header", &self.server_header),
+        ];
+        for (setting, value) in values {
+            if HeaderValue::from_str(value).is_err() {
+                return invalid(format!("Invalid {}: {:?} is not a valid header value", setting, value));
+            }
+        }
+        
+        if let Some(group) = &self.report_to {
+            if group.is_empty() || !group.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+                return invalid(format!(
+                    "Invalid report-to group {:?}: use letters, digits, - and _",
+                    group
+                ));
+            }
+        }
+        
+        for (directive, sources) in self.csp.directives() {
+            if HeaderValue::from_str(sources).is_err() || sources.contains([';', ',']) {
+                return invalid(format!(
+                    "Invalid CSP {} sources {:?}: control characters, ';' and ',' are not allowed",
+                    directive, sources
+                ));
+            }
+        }
+        
+        Ok(())
+    }
+    
+    /// The enabled headers (those with a non-empty value), in the order
+    /// they are sent. A value that is not a valid header value is left
+    /// out; `validate` rejects those.
+    pub fn to_headers(&self) -> Vec<(HeaderName, HeaderValue)> {
+        self.header_strings()
+            .into_iter()
+            .filter_map(|(name, value)| HeaderValue::from_str(&value).ok().map(|value| (name, value)))
+            .collect()
+    }
+    
+    /// Names of the enabled headers, including any whose value is invalid
+    /// and so would not be sent
+    pub fn enabled_headers(&self) -> Vec<HeaderName> {
+        self.header_strings().into_iter().map(|(name, _)| name).collect()
+    }
+    
+    /// The enabled headers with their values as configured
+    fn header_strings(&self) -> Vec<(HeaderName, String)> {
+        let mut headers = vec![
+            (names::X_CONTENT_TYPE_OPTIONS, self.content_type_options.clone()),
+            (names::X_FRAME_OPTIONS, self.frame_options.clone()),
+            (names::X_XSS_PROTECTION, self.xss_protection.clone()),
+            (names::STRICT_TRANSPORT_SECURITY, self.hsts_header_value()),
+            (names::CONTENT_SECURITY_POLICY, self.csp_header_value()),
+            (names::REFERRER_POLICY, self.referrer_policy.clone()),
+            (names::PERMISSIONS_POLICY, self.permissions_policy_header_value()),
+        ];
+        if let Some(endpoints) = self.reporting_endpoints_header_value() {
+            headers.push((names::REPORTING_ENDPOINTS, endpoints));
+        }
         
+        // An empty value turns the header off
+        headers.retain(|(_, value)| !value.is_empty());
         headers
     }
 }
@@ -297,11 +1126,238 @@ mod tests {
     #[test]
     fn test_headers_conversion() {
         let config = SecurityConfig::default();
-        let headers = config.to_headers();
+        let headers: Vec<HeaderName> = config.to_headers().into_iter().map(|(name, _)| name).collect();
+        
+        // All security headers included, in order
+        assert_eq!(headers, names::SECURITY_HEADERS);
+    }
+    
+    #[test]
+    fn test_read_secret_from_file() {
+        let path = std::env::temp_dir().join(format!("read-secret-test-{}", std::process::id()));
+        std::fs::write(&path, "file-secret\n").expect("Failed to write secret file");
+        std::env::set_var("READ_SECRET_TEST_FILE", &path);
+        
+        let secret = read_secret("READ_SECRET_TEST").expect("Failed to read secret");
+        assert_eq!(secret.as_deref(), Some("file-secret"));
+        
+        std::env::set_var("READ_SECRET_TEST", "direct-secret");
+        let secret = read_secret("READ_SECRET_TEST").expect("Failed to read secret");
+        assert_eq!(secret.as_deref(), Some("direct-secret"));
+        
+        std::env::remove_var("READ_SECRET_TEST");
+        std::env::remove_var("READ_SECRET_TEST_FILE");
+        let _ = std::fs::remove_file(&path);
+    }
+    
+    #[test]
+    fn test_read_secret_missing() {
+        assert!(read_secret("READ_SECRET_MISSING_TEST").expect("Lookup failed").is_none());
+    }
+    
+    #[test]
+    fn test_legacy_env_vars() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string())
+        };
+        
+        // Only the legacy name: its value is read under the current one
+        let value = env_var_with("SECURITY_SERVER_HEADER", env(&[("SERVER_HEADER", "edge")])).unwrap();
+        assert_eq!(value.as_deref(), Some("edge"));
+        
+        // Both, agreeing, or only the current name
+        let value = env_var_with("SECURITY_SERVER_HEADER", env(&[("SERVER_HEADER", "edge"), ("SECURITY_SERVER_HEADER", "edge")])).unwrap();
+        assert_eq!(value.as_deref(), Some("edge"));
+        let value = env_var_with("SECURITY_SERVER_HEADER", env(&[("SECURITY_SERVER_HEADER", "edge")])).unwrap();
+        assert_eq!(value.as_deref(), Some("edge"));
+        
+        // A name that was never renamed ignores the legacy table
+        assert_eq!(env_var_with("SERVER_HEADER", env(&[])).unwrap(), None);
+        
+        // Both, disagreeing: each value is quoted
+        let err = env_var_with("SECURITY_SERVER_HEADER", env(&[("SERVER_HEADER", "old"), ("SECURITY_SERVER_HEADER", "new")])).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains(r#"SECURITY_SERVER_HEADER is "new" but its legacy name SERVER_HEADER is "old""#), "{}", message);
+    }
+    
+    #[test]
+    fn test_renamed_env_vars_are_known() {
+        assert_eq!(legacy_env_var("SECURITY_SERVER_HEADER"), Some("SERVER_HEADER"));
+        assert_eq!(legacy_env_var("SERVER_HEADER"), None);
+        for (legacy, current) in LEGACY_ENV_VARS {
+            assert_ne!(legacy, current);
+            // A legacy name is never reused as a current one
+            assert_eq!(legacy_env_var(legacy), None, "{} is both current and legacy", legacy);
+        }
+    }
+    
+    #[test]
+    fn test_presets_parse_and_validate() {
+        for preset in SecurityPreset::ALL {
+            assert_eq!(preset.name().parse::<SecurityPreset>().ok(), Some(preset));
+            
+            let config = SecurityConfig::preset(preset);
+            assert_eq!(config.preset, preset);
+            assert!(config.validate().is_ok(), "{} preset is invalid", preset.name());
+        }
+        
+        assert_eq!("STRICT".parse::<SecurityPreset>().ok(), Some(SecurityPreset::Strict));
+        assert!("paranoid".parse::<SecurityPreset>().is_err());
+    }
+    
+    #[test]
+    fn test_validate_rejects_header_injection() {
+        let config = SecurityConfig {
+            frame_options: "DENY\r\nSet-Cookie: session=stolen".to_string(),
+            ..SecurityConfig::default()
+        };
+        assert!(config.validate().is_err());
+        
+        let mut config = SecurityConfig::default();
+        config.csp.script_src = "'self'; report-uri https://evil.example".to_string();
+        assert!(config.validate().is_err());
+        
+        assert!(SecurityConfig::default().validate().is_ok());
+    }
+    
+    #[test]
+    fn test_merge_patch() {
+        let base = SecurityConfig::default();
+        let merged = merge_patch(&base, &serde_json::json!({
+            "csp": {"script_src": "'self' https://cdn.example"},
+            "report_to": "default",
+        }))
+        .unwrap();
+        assert_eq!(merged.csp.script_src, "'self' https://cdn.example");
+        assert_eq!(merged.csp.style_src, base.csp.style_src);
+        assert_eq!(merged.report_to.as_deref(), Some("default"));
+        assert_eq!(merged.frame_options, base.frame_options);
+        
+        let errors = merge_patch(&base, &serde_json::json!({"csp": {"script": "x"}, "hsts": 1, "framing": "x"})).unwrap_err();
+        let paths: Vec<&str> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["csp.script", "framing"]);
+        
+        let errors = merge_patch(&base, &serde_json::json!({"hsts": {"max_age": "a year"}})).unwrap_err();
+        assert_eq!(errors[0].path, "hsts.max_age");
+        assert!(errors[0].message.contains("invalid type"), "{}", errors[0].message);
+        
+        assert_eq!(merge_patch(&base, &serde_json::json!([1]))
+            .unwrap_err()[0], FieldError { path: String::new(), message: "expected an object".to_string() });
+    }
+    
+    #[test]
+    fn test_report_to_keeps_explicit_groups() {
+        let config = SecurityConfig {
+            permissions_policy: "camera=();report-to=cams,, usb=(self)".to_string(),
+            report_to: Some("default".to_string()),
+            ..SecurityConfig::default()
+        };
+        assert_eq!(
+            config.permissions_policy_header_value(),
+            "camera=();report-to=cams, usb=(self);report-to=default"
+        );
+        assert!(config.validate().is_ok());
         
-        assert!(headers.contains_key("X-Content-Type-Options"));
-        assert!(headers.contains_key("Content-Security-Policy"));
-        assert!(headers.contains_key("Strict-Transport-Security"));
-        assert_eq!(headers.len(), 7); // All security headers included
+        let config = SecurityConfig { report_to: Some("bad group".to_string()), ..SecurityConfig::default() };
+        assert!(config.validate().is_err());
     }
-}
\ No newline at end of file
+    
+    /// Split a CSP header back into (directive, sources) pairs
+    fn parse_csp(value: &str) -> Vec<(String, String)> {
+        value
+            .split("; ")
+            .map(|directive| match directive.split_once(' ') {
+                Some((name, sources)) => (name.to_string(), sources.to_string()),
+                None => (directive.to_string(), String::new()),
+            })
+            .collect()
+    }
+    
+    fn csp_with(sources: &[String]) -> SecurityConfig {
+        let mut config = SecurityConfig::default();
+        let csp = &mut config.csp;
+        let fields = [
+            &mut csp.default_src, &mut csp.script_src, &mut csp.style_src,
+            &mut csp.img_src, &mut csp.connect_src, &mut csp.font_src,
+            &mut csp.object_src, &mut csp.media_src, &mut csp.frame_src,
+            &mut csp.child_src, &mut csp.worker_src, &mut csp.base_uri,
+            &mut csp.form_action,
+        ];
+        for (field, value) in fields.into_iter().zip(sources) {
+            *field = value.clone();
+        }
+        config
+    }
+    
+    proptest::proptest! {
+        #[test]
+        fn prop_printable_csp_sources_round_trip(
+            sources in proptest::collection::vec("[ -+\\--:<-~]{1,40}", 13)
+        ) {
+            // Printable ASCII without ';' and ','
+            let sources: Vec<String> = sources.into_iter().map(|s| s.trim().to_string()).collect();
+            proptest::prop_assume!(sources.iter().all(|s| !s.is_empty()));
+            let config = csp_with(&sources);
+            proptest::prop_assert!(config.validate().is_ok());
+            
+            let value = config.csp_header_value();
+            proptest::prop_assert!(!value.contains(['\r', '\n']));
+            proptest::prop_assert!(HeaderValue::from_str(&value).is_ok());
+            
+            let parsed = parse_csp(&value);
+            let expected: Vec<(String, String)> = config.csp
+                .directives()
+                .iter()
+                .map(|(name, sources)| (name.to_string(), sources.to_string()))
+                .collect();
+            proptest::prop_assert_eq!(parsed, expected);
+        }
+        
+        #[test]
+        fn prop_validated_config_emits_valid_headers(
+            sources in proptest::collection::vec(".{0,40}", 13),
+            permissions_policy in ".{0,40}",
+            server_header in ".{0,40}",
+        ) {
+            let mut config = csp_with(&sources);
+            config.permissions_policy = permissions_policy;
+            config.server_header = server_header;
+            
+            if config.validate().is_ok() {
+                for (name, value) in config.header_strings() {
+                    proptest::prop_assert!(!value.contains(['\r', '\n']), "{} contains CR/LF", name);
+                    proptest::prop_assert!(HeaderValue::from_str(&value).is_ok(), "{} is not a header value", name);
+                }
+                proptest::prop_assert_eq!(parse_csp(&config.csp_header_value()).len(), 13);
+            }
+        }
+        
+        #[test]
+        fn prop_line_breaks_are_rejected(prefix in "[a-z' ]{0,10}", suffix in "[a-z' ]{0,10}") {
+            let config = SecurityConfig {
+                referrer_policy: format!("{}\n{}", prefix, suffix),
+                ..SecurityConfig::default()
+            };
+            proptest::prop_assert!(config.validate().is_err());
+            
+            let mut config = SecurityConfig::default();
+            config.csp.img_src = format!("{}\r{}", prefix, suffix);
+            proptest::prop_assert!(config.validate().is_err());
+        }
+        
+        #[test]
+        fn prop_hsts_round_trips(max_age: u32, include_subdomains: bool, preload: bool) {
+            let config = SecurityConfig {
+                hsts: HstsConfig { max_age, include_subdomains, preload },
+                ..SecurityConfig::default()
+            };
+            let value = config.hsts_header_value();
+            proptest::prop_assert!(HeaderValue::from_str(&value).is_ok());
+            
+            let parts: Vec<&str> = value.split("; ").collect();
+            proptest::prop_assert_eq!(parts[0].strip_prefix("max-age=").and_then(|v| v.parse().ok()), Some(max_age));
+            proptest::prop_assert_eq!(parts.contains(&"includeSubDomains"), include_subdomains);
+            proptest::prop_assert_eq!(parts.contains(&"preload"), preload);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+/*!
+ * Versions of the security configuration
+ *
+ * Every configuration stored in `AppState` gets the next version number,
+ * starting with 1 for the one the process started with. The last
+ * [`HISTORY_LIMIT`] versions are kept in memory with their `SecurityConfig`,
+ * when they were applied and why, so a valid but unwanted change (a CSP
+ * that breaks the site, say) can be undone through the admin API:
+ *
+ * - `GET /admin/config/history` lists the kept versions, oldest first
+ * - `POST /admin/config/rollback {"version": 3}` applies version 3's
+ *   security settings to the running configuration through
+ *   `AppState::apply_config`, the same validated path as any other reload
+ *
+ * A rollback is itself a new version, so it can be rolled back too. The
+ * history starts over when the process restarts.
+ */
+use crate::config::SecurityConfig;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Versions kept, including the current one
+pub const HISTORY_LIMIT: usize = 10;
+
+/// One applied configuration
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigVersion {
+    pub version: u64,
+    pub applied_at: DateTime<Utc>,
+
+    /// What applied it, e.g. `startup` or `rollback to 3`
+    pub source: String,
+
+    /// `build_info::config_hash` of `security`
+    pub config_hash: String,
+
+    pub security: SecurityConfig,
+}
+
+/// The most recent versions, oldest first
+#[derive(Debug, Clone)]
+pub struct ConfigHistory {
+    versions: VecDeque<ConfigVersion>,
+}
+
+impl ConfigHistory {
+    /// History holding `security` as version 1, applied at startup
+    pub fn new(security: &SecurityConfig) -> Self {
+        let mut history = Self { versions: VecDeque::with_capacity(HISTORY_LIMIT) };
+        history.push(1, security, "startup");
+        history
+    }
+
+    fn push(&mut self, version: u64, security: &SecurityConfig, source: &str) {
+        if self.versions.len() == HISTORY_LIMIT {
+            self.versions.pop_front();
+        }
+        self.versions.push_back(ConfigVersion {
+            version,
+            applied_at: Utc::now(),
+            source: source.to_string(),
+            config_hash: crate::build_info::config_hash(security),
+            security: security.clone(),
+        });
+    }
+
+    /// Record `security` as the new current version, returning its number
+    pub fn record(&mut self, security: &SecurityConfig, source: &str) -> u64 {
+        let version = self.current().version + 1;
+        self.push(version, security, source);
+        version
+    }
+
+    /// The version in effect
+    pub fn current(&self) -> &ConfigVersion {
+        self.versions.back().expect("the history always holds the current version")
+    }
+
+    /// A kept version by number
+    pub fn get(&self, version: u64) -> Option<&ConfigVersion> {
+        self.versions.iter().find(|kept| kept.version == version)
+    }
+
+    /// Every kept version, oldest first
+    pub fn versions(&self) -> impl Iterator<Item = &ConfigVersion> {
+        self.versions.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_versions_are_dropped() {
+        let mut history = ConfigHistory::new(&SecurityConfig::default());
+        let changed = SecurityConfig { frame_options: "SAMEORIGIN".to_string(), ..SecurityConfig::default() };
+        for _ in 0..HISTORY_LIMIT {
+            history.record(&changed, "reload");
+        }
+
+        assert_eq!(history.current().version, HISTORY_LIMIT as u64 + 1);
+        assert_eq!(history.versions().count(), HISTORY_LIMIT);
+        assert!(history.get(1).is_none());
+        assert_eq!(history.get(2).map(|kept| kept.source.as_str()), Some("reload"));
+        assert_eq!(history.current().config_hash, crate::build_info::config_hash(&changed));
+    }
+}
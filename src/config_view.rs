@@ -0,0 +1,365 @@
+/*!
+ * Redacted view of the effective configuration
+ *
+ * `GET /admin/config` reports what the running process is using, which
+ * after `AppState::replace_config` may differ from the environment. The
+ * configuration types holding secrets have no `Serialize` impl; this view
+ * copies the harmless fields and puts a [`Redacted`] marker wherever a
+ * secret is configured, so there is no value to leak.
+ */
+use crate::cache::CacheRule;
+use crate::client_ip::TrustedProxies;
+use crate::config::{AppConfig, SecurityConfig};
+use crate::canary::CanaryConfig;
+use crate::denial::DenialConfig;
+use crate::drain::DrainConfig;
+use crate::notifications::NotificationsConfig;
+use crate::cookies::CookiePolicy;
+use crate::csrf::CsrfConfig;
+use crate::content_digest::ResponseDigestConfig;
+use crate::decompression::DecompressionConfig;
+use crate::header_limits::HeaderLimits;
+use crate::headers::HeaderModes;
+use crate::request_id::RequestIdConfig;
+use crate::response_cache::ResponseCacheConfig;
+use crate::timeouts::TimeoutPolicy;
+use crate::concurrency::ConcurrencyConfig;
+use crate::access::AccessConfig;
+use crate::audit::AuditConfig;
+use crate::idempotency::IdempotencyConfig;
+use crate::unknown_paths::UnknownPathsConfig;
+use crate::access_log::AccessLogConfig;
+use crate::tenants::Tenants;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Serializes as [`crate::redact::REDACTED`] in place of a configured secret
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redacted;
+
+impl Serialize for Redacted {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(crate::redact::REDACTED)
+    }
+}
+
+/// Body of `GET /admin/config`
+#[derive(Debug, Serialize)]
+pub struct ConfigView {
+    /// Security header values, as sent
+    pub security: SecurityConfig,
+
+    /// Per-host profiles, each with its merged security settings
+    pub tenants: Tenants,
+
+    pub server: ServerView,
+
+    /// Settings that carry credentials, with the credentials redacted
+    pub secrets: SecretsView,
+
+    pub sources: SourcesView,
+
+    /// Version number in `/admin/config/history`; 1 until first replaced
+    pub version: u64,
+
+    /// When the configuration was last replaced at runtime, if ever
+    pub reloaded_at: Option<DateTime<Utc>>,
+}
+
+/// Request handling settings
+#[derive(Debug, Serialize)]
+pub struct ServerView {
+    pub admin_addr: Option<SocketAddr>,
+    pub force_https_redirect: bool,
+    pub expose_build_headers: bool,
+    pub server_timing: bool,
+    pub trusted_proxies: TrustedProxies,
+    pub direct_access: String,
+    pub denial: DenialConfig,
+    pub drain: DrainConfig,
+    pub notifications: Option<NotificationsView>,
+    pub path_normalization: String,
+    pub header_limits: HeaderLimits,
+    pub header_modes: HeaderModes,
+    pub decompression: DecompressionConfig,
+    pub max_response_body_bytes: Option<u64>,
+    pub response_digest: Option<ResponseDigestConfig>,
+    pub error_pages_dir: Option<PathBuf>,
+    pub log_level_revert_seconds: u64,
+    pub request_events_capacity: usize,
+    pub experiments: CanaryConfig,
+
+    /// Status codes whose page comes from `error_pages_dir`
+    pub error_page_overrides: Vec<u16>,
+    pub cache_rules: Vec<CacheRule>,
+    pub response_cache: ResponseCacheConfig,
+    pub slo_target: f64,
+    pub cookies: CookiePolicy,
+    pub csrf: Option<CsrfConfig>,
+    pub request_id: RequestIdConfig,
+    pub timeouts: TimeoutPolicy,
+    pub concurrency: ConcurrencyConfig,
+    pub idempotency: IdempotencyConfig,
+    pub unknown_paths: UnknownPathsConfig,
+    pub audit: AuditConfig,
+    pub access: Option<AccessConfig>,
+    pub access_log: Option<AccessLogConfig>,
+    pub header_sample_rate: f64,
+    #[cfg(feature = "debug-endpoints")]
+    pub debug_endpoints: bool,
+    pub feature_toggles: BTreeMap<String, bool>,
+    pub static_files: Option<StaticFilesView>,
+    #[cfg(feature = "proxy")]
+    pub proxy_routes: BTreeMap<String, String>,
+    pub quick_tunnel: bool,
+    pub maintenance_file: Option<PathBuf>,
+}
+
+/// Notification settings; the URLs are under `secrets`
+#[derive(Debug, Serialize)]
+pub struct NotificationsView {
+    pub events: Vec<&'static str>,
+    pub service: String,
+    pub health_interval_seconds: u64,
+    pub flap_seconds: u64,
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+
+    /// Events with their own payload template
+    pub templated_events: Vec<&'static str>,
+}
+
+impl From<&NotificationsConfig> for NotificationsView {
+    fn from(config: &NotificationsConfig) -> Self {
+        Self {
+            events: config.events.iter().map(|event| event.as_str()).collect(),
+            service: config.service.clone(),
+            health_interval_seconds: config.health_interval.as_secs(),
+            flap_seconds: config.flap_period.as_secs(),
+            max_retries: config.max_retries,
+            retry_backoff_ms: config.retry_backoff.as_millis() as u64,
+            templated_events: config.templates.keys().map(|event| event.as_str()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StaticFilesView {
+    pub dir: PathBuf,
+    pub prefix: String,
+    pub index: Option<String>,
+}
+
+/// Credential-bearing settings
+#[derive(Debug, Serialize)]
+pub struct SecretsView {
+    pub admin_token: Option<Redacted>,
+    #[cfg(feature = "turnstile")]
+    pub turnstile_secret_key: Option<Redacted>,
+    #[cfg(feature = "cloudflare-api")]
+    pub cloudflare_api: Option<CloudflareApiView>,
+    pub webhooks: Vec<WebhookView>,
+    pub auth_rules: Vec<AuthRuleView>,
+    pub api_keys: Option<ApiKeysView>,
+    pub notification_urls: Vec<Redacted>,
+}
+
+#[cfg(feature = "cloudflare-api")]
+#[derive(Debug, Serialize)]
+pub struct CloudflareApiView {
+    pub api_token: Redacted,
+    pub zone_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookView {
+    pub name: String,
+    pub scheme: &'static str,
+    pub header: String,
+    pub secret: Redacted,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthRuleView {
+    pub name: String,
+    pub prefixes: Vec<String>,
+    pub tokens: Vec<Redacted>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeysView {
+    pub prefixes: Vec<String>,
+    pub rate_limit: Option<u32>,
+    pub rate_window_secs: u64,
+    pub keys: Vec<ApiKeyView>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyView {
+    pub name: String,
+    pub key: Redacted,
+    pub rate_limit: Option<u32>,
+}
+
+/// Where settings were read from at startup
+#[derive(Debug, Serialize)]
+pub struct SourcesView {
+    pub environment: bool,
+
+    /// `*_FILE` variables secrets were read from, with their paths
+    pub secret_files: BTreeMap<String, String>,
+}
+
+impl ConfigView {
+    /// View of `config`, applied as `version` at `reloaded_at`
+    pub fn new(config: &AppConfig, version: u64, reloaded_at: Option<DateTime<Utc>>) -> Self {
+        Self {
+            security: config.security.clone(),
+            tenants: config.tenants.clone(),
+            server: ServerView {
+                admin_addr: config.admin.as_ref().map(|admin| admin.addr),
+                force_https_redirect: config.force_https_redirect,
+                expose_build_headers: config.expose_build_headers,
+                server_timing: config.server_timing,
+                trusted_proxies: config.trusted_proxies.clone(),
+                direct_access: format!("{:?}", config.direct_access).to_lowercase(),
+                denial: config.denial.clone(),
+                drain: config.drain,
+                notifications: config.notifications.as_ref().map(NotificationsView::from),
+                path_normalization: format!("{:?}", config.path_normalization).to_lowercase(),
+                header_limits: config.header_limits,
+                header_modes: config.header_modes.clone(),
+                decompression: config.decompression,
+                max_response_body_bytes: config.max_response_body_bytes,
+                response_digest: config.response_digest.clone(),
+                error_pages_dir: config.error_pages.dir().map(PathBuf::from),
+                log_level_revert_seconds: config.log_level.revert_after.as_secs(),
+                request_events_capacity: config.request_events.capacity,
+                experiments: config.canary.clone(),
+                error_page_overrides: config.error_pages.overridden(),
+                cache_rules: config.cache.rules.clone(),
+                response_cache: config.response_cache.clone(),
+                slo_target: config.slo.target,
+                cookies: config.cookies.clone(),
+                csrf: config.csrf.clone(),
+                request_id: config.request_id.clone(),
+                timeouts: config.timeouts.clone(),
+                concurrency: config.concurrency,
+                idempotency: config.idempotency.clone(),
+                unknown_paths: config.unknown_paths,
+                audit: config.audit,
+                access: config.access.clone(),
+                access_log: config.access_log.clone(),
+                header_sample_rate: config.header_sample_rate,
+                #[cfg(feature = "debug-endpoints")]
+                debug_endpoints: config.debug_endpoints,
+                feature_toggles: config.feature_toggles.clone(),
+                static_files: config.static_files.as_ref().map(|files| StaticFilesView {
+                    dir: files.dir.clone(),
+                    prefix: files.prefix.clone(),
+                    index: files.index.clone(),
+                }),
+                #[cfg(feature = "proxy")]
+                proxy_routes: config
+                    .proxy
+                    .iter()
+                    .flat_map(|proxy| &proxy.routes)
+                    .map(|route| (route.prefix.clone(), route.upstream.to_string()))
+                    .collect(),
+                quick_tunnel: config.tunnel.is_some(),
+                maintenance_file: config.maintenance.sentinel.clone(),
+            },
+            secrets: SecretsView {
+                admin_token: config.admin.as_ref().map(|_| Redacted),
+                #[cfg(feature = "turnstile")]
+                turnstile_secret_key: config.turnstile.as_ref().map(|_| Redacted),
+                #[cfg(feature = "cloudflare-api")]
+                cloudflare_api: config.cloudflare_api.as_ref().map(|api| CloudflareApiView {
+                    api_token: Redacted,
+                    zone_id: api.zone_id.clone(),
+                }),
+                webhooks: config
+                    .webhooks
+                    .iter()
+                    .map(|webhook| WebhookView {
+                        name: webhook.name.clone(),
+                        scheme: webhook.scheme.as_str(),
+                        header: webhook.header.to_string(),
+                        secret: Redacted,
+                    })
+                    .collect(),
+                auth_rules: config
+                    .auth
+                    .iter()
+                    .map(|rule| AuthRuleView {
+                        name: rule.name.clone(),
+                        prefixes: rule.prefixes.clone(),
+                        tokens: vec![Redacted; rule.tokens.len()],
+                    })
+                    .collect(),
+                api_keys: config.api_keys.as_ref().map(|api_keys| ApiKeysView {
+                    prefixes: api_keys.prefixes.clone(),
+                    rate_limit: api_keys.rate_limit,
+                    rate_window_secs: api_keys.rate_window.as_secs(),
+                    keys: api_keys
+                        .keys
+                        .iter()
+                        .map(|key| ApiKeyView {
+                            name: key.name.clone(),
+                            key: Redacted,
+                            rate_limit: key.rate_limit,
+                        })
+                        .collect(),
+                }),
+                notification_urls: vec![Redacted; config.notifications.as_ref().map_or(0, |n| n.urls.len())],
+            },
+            sources: SourcesView {
+                environment: true,
+                secret_files: crate::startup::secret_files().into_iter().collect(),
+            },
+            version,
+            reloaded_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::{ApiKey, ApiKeyConfig};
+    use crate::auth::AuthRule;
+    #[cfg(feature = "turnstile")]
+    use crate::turnstile::TurnstileConfig;
+    use crate::webhooks::{WebhookConfig, WebhookScheme};
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        let config = AppConfig {
+            #[cfg(feature = "turnstile")]
+            turnstile: Some(TurnstileConfig::new("turnstile-secret")),
+            webhooks: vec![WebhookConfig::new("github", "webhook-secret", WebhookScheme::GithubSha256)],
+            auth: vec![AuthRule::new("ops", ["/whoami"], ["bearer-secret"])],
+            api_keys: Some(ApiKeyConfig::new([ApiKey::new("reporting", "key-secret")], ["/reports"])),
+            ..AppConfig::default()
+        };
+
+        let json = serde_json::to_value(ConfigView::new(&config, 1, None)).unwrap();
+        let text = json.to_string();
+        for secret in ["turnstile-secret", "webhook-secret", "bearer-secret", "key-secret"] {
+            assert!(!text.contains(secret), "{} leaked: {}", secret, text);
+        }
+
+        let secrets = &json["secrets"];
+        #[cfg(feature = "turnstile")]
+        assert_eq!(secrets["turnstile_secret_key"], "<redacted>");
+        assert_eq!(secrets["webhooks"][0]["name"], "github");
+        assert_eq!(secrets["webhooks"][0]["secret"], "<redacted>");
+        assert_eq!(secrets["auth_rules"][0]["tokens"], serde_json::json!(["<redacted>"]));
+        assert_eq!(secrets["api_keys"]["keys"][0]["name"], "reporting");
+        assert_eq!(secrets["api_keys"]["keys"][0]["key"], "<redacted>");
+        assert_eq!(secrets["admin_token"], serde_json::Value::Null);
+    }
+}
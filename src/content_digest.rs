@@ -0,0 +1,442 @@
+/*!
+ * Request body integrity checks and response digests
+ *
+ * A request carrying `Content-Digest` (RFC 9530) or the legacy
+ * `Content-MD5` header has its body hashed as the handler reads it, with
+ * nothing buffered beyond what the handler reads anyway. When the last byte
+ * arrives the digests are compared: on a mismatch the body read fails and
+ * the response is replaced by `422 digest_mismatch`, so a handler never acts
+ * on a body that was altered in transit. A handler that never reads the body
+ * does not wait for it to be checked.
+ *
+ * `Content-Digest` may list several algorithms; every supported one
+ * (`sha-256`, `sha-512`) is checked and the others are ignored. A header
+ * naming none of them gets `400 unsupported_digest_algorithm`, with a
+ * `Want-Content-Digest` header naming the supported ones, and a header that
+ * does not parse gets `400 invalid_digest`.
+ *
+ * Handlers can log what was computed through the [`BodyDigest`] extension,
+ * filled in once the body has been read.
+ *
+ * Responses under `RESPONSE_DIGEST_PREFIXES` get a `Content-Digest` of
+ * their body (`RESPONSE_DIGEST_ALGORITHM`, `sha-256` by default). The header
+ * goes out before the body, so only bodies whose length is known up front
+ * are hashed; streamed bodies are sent without one.
+ */
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http_body::{Frame, SizeHint};
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256, Sha512};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use tracing::warn;
+
+pub const CONTENT_DIGEST: HeaderName = HeaderName::from_static("content-digest");
+pub const CONTENT_MD5: HeaderName = HeaderName::from_static("content-md5");
+pub const WANT_CONTENT_DIGEST: HeaderName = HeaderName::from_static("want-content-digest");
+
+/// Algorithms accepted in `Content-Digest`, as listed in error messages
+const SUPPORTED: &str = "sha-256, sha-512";
+
+/// A digest algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum Algorithm {
+    #[serde(rename = "sha-256")]
+    Sha256,
+    #[serde(rename = "sha-512")]
+    Sha512,
+    /// Only for the legacy `Content-MD5` header
+    #[serde(rename = "md5")]
+    Md5,
+}
+
+impl Algorithm {
+    /// The key used in `Content-Digest`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha-256",
+            Algorithm::Sha512 => "sha-512",
+            Algorithm::Md5 => "md5",
+        }
+    }
+
+    /// A `Content-Digest` key this server checks
+    fn parse(key: &str) -> Option<Self> {
+        match key {
+            "sha-256" => Some(Algorithm::Sha256),
+            "sha-512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Digest of `data` in one go
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = Hasher::new(*self);
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Box<Sha512>),
+    Md5(Md5),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::default()),
+            Algorithm::Sha512 => Hasher::Sha512(Box::default()),
+            Algorithm::Md5 => Hasher::Md5(Md5::default()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+/// A digest of a body, shown as a `Content-Digest` member
+/// (`sha-256=:<base64>:`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    pub algorithm: Algorithm,
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=:{}:", self.algorithm.as_str(), STANDARD.encode(&self.bytes))
+    }
+}
+
+/// Digests of the request body computed while it was read, present in the
+/// request extensions of requests that carried a digest
+#[derive(Debug, Clone, Default)]
+pub struct BodyDigest {
+    computed: Arc<OnceLock<Vec<Digest>>>,
+}
+
+impl BodyDigest {
+    /// The digests, once the whole body has been read
+    pub fn get(&self) -> Option<&[Digest]> {
+        self.computed.get().map(Vec::as_slice)
+    }
+}
+
+/// Why a request's digest headers were turned away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DigestError {
+    /// Not a dictionary of byte sequences, or not base64
+    Invalid(String),
+
+    /// Only algorithms this server does not check, as listed
+    Unsupported(Vec<String>),
+}
+
+/// The digests a request claims for its body
+pub fn expected(headers: &HeaderMap) -> Result<Vec<Digest>, DigestError> {
+    let mut digests = Vec::new();
+
+    if let Some(value) = headers.get(&CONTENT_MD5) {
+        let bytes = value
+            .to_str()
+            .ok()
+            .and_then(|v| STANDARD.decode(v.trim()).ok())
+            .filter(|bytes| bytes.len() == 16)
+            .ok_or_else(|| DigestError::Invalid("Content-MD5 is not a base64 MD5 digest".to_string()))?;
+        digests.push(Digest { algorithm: Algorithm::Md5, bytes });
+    }
+
+    let values: Vec<&HeaderValue> = headers.get_all(&CONTENT_DIGEST).iter().collect();
+    if values.is_empty() {
+        return Ok(digests);
+    }
+
+    let mut unsupported = Vec::new();
+    for value in values {
+        let value = value.to_str().map_err(|_| invalid_dictionary())?;
+        for member in value.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            // Parameters are allowed after the value and mean nothing here
+            let member = member.split(';').next().unwrap_or_default();
+            let (key, value) = member.split_once('=').ok_or_else(invalid_dictionary)?;
+            let key = key.trim();
+            let encoded = value.trim().strip_prefix(':').and_then(|v| v.strip_suffix(':')).ok_or_else(invalid_dictionary)?;
+
+            let Some(algorithm) = Algorithm::parse(key) else {
+                unsupported.push(key.to_string());
+                continue;
+            };
+            let bytes = STANDARD
+                .decode(encoded)
+                .map_err(|_| DigestError::Invalid(format!("The {} digest in Content-Digest is not base64", key)))?;
+            digests.push(Digest { algorithm, bytes });
+        }
+    }
+
+    if !digests.iter().any(|d| d.algorithm != Algorithm::Md5) {
+        return Err(if unsupported.is_empty() {
+            invalid_dictionary()
+        } else {
+            DigestError::Unsupported(unsupported)
+        });
+    }
+    Ok(digests)
+}
+
+fn invalid_dictionary() -> DigestError {
+    DigestError::Invalid("Content-Digest must look like sha-256=:<base64>:".to_string())
+}
+
+impl IntoResponse for DigestError {
+    fn into_response(self) -> Response {
+        match self {
+            DigestError::Invalid(message) => ApiError::new(StatusCode::BAD_REQUEST, "invalid_digest", message).into_response(),
+            DigestError::Unsupported(keys) => {
+                let mut response = ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "unsupported_digest_algorithm",
+                    format!("Content-Digest uses {}; supported algorithms are {}", keys.join(", "), SUPPORTED),
+                )
+                .into_response();
+                response
+                    .headers_mut()
+                    .insert(WANT_CONTENT_DIGEST, HeaderValue::from_static("sha-256=10, sha-512=5"));
+                response
+            }
+        }
+    }
+}
+
+/// Request body hashing what passes through it and failing at the end of
+/// the stream when a digest does not match
+struct VerifyingBody {
+    inner: Body,
+    hashers: Option<Vec<Hasher>>,
+    expected: Arc<Vec<Digest>>,
+    digest: BodyDigest,
+}
+
+impl http_body::Body for VerifyingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        let this = &mut *self;
+        let Some(hashers) = this.hashers.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let frame = match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(None) => None,
+            Poll::Ready(Some(Ok(frame))) => Some(frame),
+            other => return other,
+        };
+        if let Some(frame) = frame {
+            if let Some(data) = frame.data_ref() {
+                for hasher in hashers.iter_mut() {
+                    hasher.update(data);
+                }
+            }
+            return Poll::Ready(Some(Ok(frame)));
+        }
+
+        let computed: Vec<Digest> = self
+            .hashers
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .zip(self.expected.iter())
+            .map(|(hasher, expected)| Digest { algorithm: expected.algorithm, bytes: hasher.finalize() })
+            .collect();
+        let matches = computed == *self.expected;
+        let _ = self.digest.computed.set(computed);
+        if matches {
+            Poll::Ready(None)
+        } else {
+            Poll::Ready(Some(Err(axum::Error::new("request body does not match its digest"))))
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.hashers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Middleware checking request bodies against `Content-Digest` and
+/// `Content-MD5`
+pub async fn verify(mut request: Request, next: Next) -> Response {
+    let expected = match expected(request.headers()) {
+        Ok(expected) if expected.is_empty() => return next.run(request).await,
+        Ok(expected) => Arc::new(expected),
+        Err(err) => return err.into_response(),
+    };
+
+    let digest = BodyDigest::default();
+    request.extensions_mut().insert(digest.clone());
+    let path = request.uri().path().to_string();
+    let request = request.map(|inner| {
+        Body::new(VerifyingBody {
+            inner,
+            hashers: Some(expected.iter().map(|d| Hasher::new(d.algorithm)).collect()),
+            expected: expected.clone(),
+            digest: digest.clone(),
+        })
+    });
+
+    let response = next.run(request).await;
+    let Some(computed) = digest.get() else {
+        return response;
+    };
+    let Some(mismatch) = computed.iter().zip(expected.iter()).find(|(computed, expected)| computed != expected) else {
+        return response;
+    };
+    warn!(
+        path = %path,
+        algorithm = mismatch.0.algorithm.as_str(),
+        "Rejected a request body not matching its digest"
+    );
+    ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "digest_mismatch",
+        format!("The request body does not match its {} digest", mismatch.0.algorithm.as_str()),
+    )
+    .into_response()
+}
+
+/// Routes whose responses carry a `Content-Digest`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResponseDigestConfig {
+    /// Path prefixes, matched on segment boundaries
+    pub prefixes: Vec<String>,
+    pub algorithm: Algorithm,
+}
+
+impl ResponseDigestConfig {
+    /// Load from `RESPONSE_DIGEST_PREFIXES` and `RESPONSE_DIGEST_ALGORITHM`;
+    /// `None` when no prefixes are set
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let prefixes: Vec<String> = std::env::var("RESPONSE_DIGEST_PREFIXES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+        if prefixes.is_empty() {
+            return Ok(None);
+        }
+        if let Some(bad) = prefixes.iter().find(|p| !p.starts_with('/')) {
+            return Err(crate::ServerError::ConfigError(format!(
+                "Response digest prefix {:?} must start with /",
+                bad
+            )));
+        }
+
+        let algorithm = match std::env::var("RESPONSE_DIGEST_ALGORITHM") {
+            Err(_) => Algorithm::Sha256,
+            Ok(value) => Algorithm::parse(&value.trim().to_ascii_lowercase()).ok_or_else(|| {
+                crate::ServerError::ConfigError(format!(
+                    "Invalid RESPONSE_DIGEST_ALGORITHM: {:?} (expected {})",
+                    value, SUPPORTED
+                ))
+            })?,
+        };
+        Ok(Some(Self { prefixes, algorithm }))
+    }
+}
+
+/// Middleware adding `Content-Digest` to responses under the configured
+/// prefixes
+pub async fn digest_response(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let config = state.config();
+    let algorithm = config
+        .response_digest
+        .as_ref()
+        .filter(|digest| crate::auth::longest_prefix(&digest.prefixes, request.uri().path()).is_some())
+        .map(|digest| digest.algorithm);
+    let head = request.method() == Method::HEAD;
+    let response = next.run(request).await;
+
+    let Some(algorithm) = algorithm else {
+        return response;
+    };
+    if head
+        || matches!(response.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED)
+        || response.headers().contains_key(&CONTENT_DIGEST)
+    {
+        return response;
+    }
+    let Some(size) = http_body::Body::size_hint(response.body()).exact() else {
+        return response;
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, size as usize).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            warn!(error = %err, "Failed to read a response body for its digest");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+        }
+    };
+    let digest = Digest { algorithm, bytes: algorithm.digest(&bytes) };
+    if let Ok(value) = HeaderValue::from_str(&digest.to_string()) {
+        parts.headers.insert(CONTENT_DIGEST, value);
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&HeaderName, &str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| ((*name).clone(), HeaderValue::from_str(value).unwrap())).collect()
+    }
+
+    #[test]
+    fn test_parses_content_digest() {
+        let sha256 = Algorithm::Sha256.digest(b"hello");
+        let value = format!("sha-256=:{}:;q=1, unixsum=:AAAA:, id-sha-256=:x:", STANDARD.encode(&sha256));
+        let digests = expected(&headers(&[(&CONTENT_DIGEST, &value)])).unwrap();
+        assert_eq!(digests, [Digest { algorithm: Algorithm::Sha256, bytes: sha256 }]);
+        assert_eq!(digests[0].to_string(), "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:");
+
+        assert_eq!(
+            expected(&headers(&[(&CONTENT_DIGEST, "sha=:AAAA:, md5=:AAAA:")])),
+            Err(DigestError::Unsupported(vec!["sha".to_string(), "md5".to_string()]))
+        );
+        assert!(matches!(expected(&headers(&[(&CONTENT_DIGEST, "sha-256=abc")])), Err(DigestError::Invalid(_))));
+        assert!(matches!(expected(&headers(&[(&CONTENT_DIGEST, "sha-256=:!!:")])), Err(DigestError::Invalid(_))));
+        assert!(matches!(expected(&headers(&[(&CONTENT_MD5, "AAAA")])), Err(DigestError::Invalid(_))));
+        assert_eq!(expected(&HeaderMap::new()), Ok(vec![]));
+    }
+}
@@ -0,0 +1,234 @@
+/*!
+ * `Set-Cookie` hardening
+ *
+ * The service sets no cookies today, but a handler added later (a session,
+ * a CSRF token) should not be able to ship one without the basics. This
+ * middleware checks every outgoing `Set-Cookie` and appends whatever is
+ * missing of `Secure`, `HttpOnly` and `SameSite=<COOKIE_SAME_SITE>`,
+ * logging a warning that names the cookie. Attributes already present are
+ * kept as written, including a different `SameSite`.
+ *
+ * With `COOKIE_HOST_PREFIX_CHECK=true`, `__Host-` cookies are also brought
+ * in line with what browsers require of the prefix: `Domain` is dropped
+ * and `Path` becomes `/`.
+ *
+ * Cookies named in `COOKIE_EXEMPT` are left alone, e.g. one that scripts
+ * must read. A header that needs no change is passed through byte for
+ * byte, and values are never touched: only the text after the first `;`
+ * is treated as attributes, so values may contain `=`.
+ */
+use crate::config::AppConfig;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::warn;
+
+/// `SameSite` value added to cookies without one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        }
+    }
+}
+
+impl FromStr for SameSite {
+    type Err = crate::ServerError;
+
+    fn from_str(value: &str) -> crate::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lax" => Ok(Self::Lax),
+            "none" => Ok(Self::None),
+            other => Err(crate::ServerError::ConfigError(format!(
+                "Invalid COOKIE_SAME_SITE {:?} (expected strict, lax or none)",
+                other
+            ))),
+        }
+    }
+}
+
+/// What outgoing cookies are held to
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CookiePolicy {
+    pub same_site: SameSite,
+
+    /// Enforce the `__Host-` prefix rules (no `Domain`, `Path=/`)
+    pub host_prefix_check: bool,
+
+    /// Cookie names left as the handler set them
+    pub exempt: Vec<String>,
+}
+
+impl CookiePolicy {
+    /// Load from `COOKIE_SAME_SITE`, `COOKIE_HOST_PREFIX_CHECK` and `COOKIE_EXEMPT`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut policy = Self::default();
+        if let Ok(value) = std::env::var("COOKIE_SAME_SITE") {
+            policy.same_site = value.parse()?;
+        }
+        if let Ok(value) = std::env::var("COOKIE_HOST_PREFIX_CHECK") {
+            policy.host_prefix_check = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid COOKIE_HOST_PREFIX_CHECK: {}", e)
+                ))?;
+        }
+        if let Ok(value) = std::env::var("COOKIE_EXEMPT") {
+            policy.exempt = value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        Ok(policy)
+    }
+
+    /// `set_cookie` brought up to the policy, with the names of the fixes
+    /// made; `None` when it already complies or is exempt
+    pub fn harden(&self, set_cookie: &str) -> Option<(String, Vec<&'static str>)> {
+        let (pair, attributes) = set_cookie.split_once(';').unwrap_or((set_cookie, ""));
+        let name = pair.split_once('=').map_or(pair, |(name, _)| name).trim();
+        if self.exempt.iter().any(|exempt| exempt == name) {
+            return None;
+        }
+
+        let mut attributes: Vec<&str> = attributes.split(';').map(str::trim).filter(|a| !a.is_empty()).collect();
+        let has = |attributes: &[&str], wanted: &str| attributes.iter().any(|a| key(a).eq_ignore_ascii_case(wanted));
+
+        let mut fixes = Vec::new();
+        let mut rebuild = false;
+        if self.host_prefix_check && name.starts_with("__Host-") {
+            if has(&attributes, "domain") {
+                attributes.retain(|a| !key(a).eq_ignore_ascii_case("domain"));
+                fixes.push("Domain removed");
+            }
+            let paths: Vec<&str> = attributes.iter().copied().filter(|a| key(a).eq_ignore_ascii_case("path")).collect();
+            if paths.len() != 1 || paths[0].split_once('=').is_none_or(|(_, value)| value.trim() != "/") {
+                attributes.retain(|a| !key(a).eq_ignore_ascii_case("path"));
+                attributes.push("Path=/");
+                fixes.push("Path=/");
+            }
+            rebuild = !fixes.is_empty();
+        }
+
+        let same_site = format!("SameSite={}", self.same_site.as_str());
+        let mut added = Vec::new();
+        if !has(&attributes, "secure") {
+            added.push("Secure");
+            fixes.push("Secure");
+        }
+        if !has(&attributes, "httponly") {
+            added.push("HttpOnly");
+            fixes.push("HttpOnly");
+        }
+        if !has(&attributes, "samesite") {
+            fixes.push("SameSite");
+        }
+        if fixes.is_empty() {
+            return None;
+        }
+
+        // Only appending keeps the header as written; removals rebuild it
+        let mut hardened = if rebuild {
+            std::iter::once(pair.trim()).chain(attributes.iter().copied()).collect::<Vec<_>>().join("; ")
+        } else {
+            set_cookie.trim_end().trim_end_matches(';').to_string()
+        };
+        for attribute in added {
+            hardened.push_str("; ");
+            hardened.push_str(attribute);
+        }
+        if fixes.contains(&"SameSite") {
+            hardened.push_str("; ");
+            hardened.push_str(&same_site);
+        }
+        Some((hardened, fixes))
+    }
+}
+
+/// Attribute name, without its value
+fn key(attribute: &str) -> &str {
+    attribute.split_once('=').map_or(attribute, |(key, _)| key).trim()
+}
+
+/// Middleware applying the cookie policy to every `Set-Cookie`
+pub async fn harden_cookies(State(config): State<Arc<AppConfig>>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if !response.headers().contains_key(header::SET_COOKIE) {
+        return response;
+    }
+
+    let headers = response.headers_mut();
+    let cookies: Vec<HeaderValue> = headers.get_all(header::SET_COOKIE).iter().cloned().collect();
+    headers.remove(header::SET_COOKIE);
+    for cookie in cookies {
+        let hardened = cookie
+            .to_str()
+            .ok()
+            .and_then(|text| config.cookies.harden(text).map(|fixed| (text, fixed)))
+            .and_then(|(text, (hardened, fixes))| {
+                let name = text.split(['=', ';']).next().unwrap_or_default().trim();
+                warn!(cookie = name, fixed = %fixes.join(", "), "Hardened an outgoing cookie");
+                HeaderValue::from_str(&hardened).ok()
+            });
+        headers.append(header::SET_COOKIE, hardened.unwrap_or(cookie));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_attributes_are_appended() {
+        let policy = CookiePolicy::default();
+        let (hardened, fixes) = policy.harden("session=a=b==; Path=/app; Max-Age=60").unwrap();
+        assert_eq!(hardened, "session=a=b==; Path=/app; Max-Age=60; Secure; HttpOnly; SameSite=Lax");
+        assert_eq!(fixes, ["Secure", "HttpOnly", "SameSite"]);
+
+        // Existing attributes are kept as written, in any case
+        let policy = CookiePolicy { same_site: SameSite::Strict, ..CookiePolicy::default() };
+        let (hardened, fixes) = policy.harden("csrf=x; samesite=none; secure").unwrap();
+        assert_eq!(hardened, "csrf=x; samesite=none; secure; HttpOnly");
+        assert_eq!(fixes, ["HttpOnly"]);
+    }
+
+    #[test]
+    fn test_compliant_and_exempt_cookies_are_untouched() {
+        let policy = CookiePolicy { exempt: vec!["ui_theme".to_string()], ..CookiePolicy::default() };
+        assert_eq!(policy.harden("id=1; Secure; HttpOnly; SameSite=Strict"), None);
+        assert_eq!(policy.harden("ui_theme=dark"), None);
+    }
+
+    #[test]
+    fn test_host_prefix_rules() {
+        let policy = CookiePolicy { host_prefix_check: true, ..CookiePolicy::default() };
+        let (hardened, fixes) = policy
+            .harden("__Host-id=v; Domain=example.com; Path=/app; Secure; HttpOnly; SameSite=Lax")
+            .unwrap();
+        assert_eq!(hardened, "__Host-id=v; Secure; HttpOnly; SameSite=Lax; Path=/");
+        assert_eq!(fixes, ["Domain removed", "Path=/"]);
+
+        assert_eq!(policy.harden("__Host-id=v; Path=/; Secure; HttpOnly; SameSite=Lax"), None);
+        // Off by default
+        assert_eq!(CookiePolicy::default().harden("__Host-id=v; Path=/a; Secure; HttpOnly; SameSite=Lax"), None);
+    }
+}
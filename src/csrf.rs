@@ -0,0 +1,324 @@
+/*!
+ * CSRF protection with a double-submit cookie
+ *
+ * Browser forms (the Turnstile page, later admin pages) are authenticated
+ * by whatever cookies the browser attaches, so a page on another site can
+ * submit them too. `CSRF_PREFIXES` lists the path prefixes to protect:
+ *
+ * - a safe request (GET, HEAD, ...) under a protected prefix without a
+ *   valid token cookie is answered with a new `__Host-csrf` cookie holding
+ *   a random token
+ * - a POST, PUT, PATCH or DELETE there must present the cookie's token
+ *   again, in an `X-Csrf-Token` header or, for urlencoded forms, a
+ *   `csrf_token` field; the two are compared in constant time and a
+ *   missing or different token is answered with 403
+ *
+ * A cross-site page can make the browser send the cookie but cannot read
+ * it, so it cannot supply the matching value. Pages get the token from
+ * `GET /csrf-token`, which issues the cookie when needed, or from the
+ * [`CsrfToken`] extractor when a handler renders the form itself.
+ *
+ * With `CSRF_ROTATE=true` every accepted submission replaces the token: the
+ * response carries the new cookie and the new value in `X-Csrf-Token`.
+ */
+use crate::admin::constant_time_eq;
+use crate::config::AppConfig;
+use crate::denial::{Denial, DenialReason};
+use crate::error::ApiError;
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// Cookie holding the token; the prefix binds it to this host and `/`
+pub const COOKIE_NAME: &str = "__Host-csrf";
+
+/// Request header presenting the token, and response header announcing a
+/// rotated one
+pub const HEADER_NAME: HeaderName = HeaderName::from_static("x-csrf-token");
+
+/// Urlencoded form field presenting the token
+pub const FORM_FIELD: &str = "csrf_token";
+
+/// Largest form body searched for [`FORM_FIELD`]
+const MAX_FORM_BYTES: usize = 64 * 1024;
+
+/// Random bytes in a token, hex encoded
+const TOKEN_BYTES: usize = 32;
+
+/// Which paths are protected and how tokens are handled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CsrfConfig {
+    /// Protected prefixes, matched on segment boundaries
+    pub prefixes: Vec<String>,
+
+    /// Replace the token after every accepted submission
+    pub rotate: bool,
+}
+
+impl CsrfConfig {
+    /// Protection for `prefixes`, without rotation
+    pub fn new<P>(prefixes: P) -> Self
+    where
+        P: IntoIterator,
+        P::Item: Into<String>,
+    {
+        Self {
+            prefixes: prefixes.into_iter().map(Into::into).collect(),
+            rotate: false,
+        }
+    }
+
+    /// Load from `CSRF_PREFIXES` and `CSRF_ROTATE`; `None` when no prefix
+    /// is protected
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let prefixes = std::env::var("CSRF_PREFIXES").unwrap_or_default();
+        let mut config = Self::new(prefixes.split(',').map(str::trim).filter(|p| !p.is_empty()));
+        if config.prefixes.is_empty() {
+            return Ok(None);
+        }
+        if let Some(bad) = config.prefixes.iter().find(|p| !p.starts_with('/')) {
+            return Err(crate::ServerError::ConfigError(format!(
+                "CSRF prefix {:?} must start with /",
+                bad
+            )));
+        }
+
+        if let Ok(value) = std::env::var("CSRF_ROTATE") {
+            config.rotate = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid CSRF_ROTATE: {}", e)
+                ))?;
+        }
+        Ok(Some(config))
+    }
+
+    fn protects(&self, path: &str) -> bool {
+        crate::auth::longest_prefix(&self.prefixes, path).is_some()
+    }
+}
+
+/// The token valid for this request, for handlers rendering a form
+///
+/// Only available on protected paths; elsewhere the extractor fails with 500.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CsrfToken {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<Self>().cloned().ok_or_else(|| {
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "csrf_not_configured",
+                "This path is not covered by CSRF_PREFIXES",
+            )
+        })
+    }
+}
+
+/// A fresh random token
+fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    getrandom::fill(&mut bytes).expect("the operating system's random number generator failed");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether `token` looks like one [`generate_token`] made
+fn well_formed(token: &str) -> bool {
+    token.len() == TOKEN_BYTES * 2 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The token in the request's `__Host-csrf` cookie, if well formed
+fn cookie_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|token| well_formed(token))
+}
+
+/// Add the cookie for `token` to `response`
+fn set_cookie(response: &mut Response, token: &str) {
+    let cookie = format!("{}={}; Path=/; Secure; HttpOnly; SameSite=Strict", COOKIE_NAME, token);
+    if let Ok(value) = HeaderValue::from_str(&cookie) {
+        response.headers_mut().append(header::SET_COOKIE, value);
+    }
+}
+
+fn is_state_changing(method: &Method) -> bool {
+    [Method::POST, Method::PUT, Method::PATCH, Method::DELETE].contains(method)
+}
+
+fn deny(reason: DenialReason, message: &str) -> Response {
+    Denial::new(reason, message).into_response()
+}
+
+/// The token the request presents, from the header or a form field; the
+/// body is buffered to look for the field and put back unchanged
+async fn presented_token(request: Request) -> Result<(Request, Option<String>), Response> {
+    if let Some(value) = request.headers().get(&HEADER_NAME) {
+        let token = value.to_str().ok().map(|v| v.trim().to_string());
+        return Ok((request, token));
+    }
+
+    let is_form = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((request, None));
+    }
+
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_FORM_BYTES).await else {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_too_large",
+            format!("Form bodies on CSRF-protected paths are limited to {} bytes", MAX_FORM_BYTES),
+        )
+        .into_response());
+    };
+    let token = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&bytes)
+        .ok()
+        .and_then(|fields| fields.into_iter().find(|(name, _)| name == FORM_FIELD))
+        .map(|(_, value)| value);
+    Ok((Request::from_parts(parts, Body::from(bytes)), token))
+}
+
+/// Middleware issuing tokens and checking submissions on protected prefixes
+pub async fn protect(State(config): State<Arc<AppConfig>>, mut request: Request, next: Next) -> Response {
+    let Some(csrf) = config.csrf.as_ref().filter(|csrf| csrf.protects(request.uri().path())) else {
+        return next.run(request).await;
+    };
+    let cookie = cookie_token(request.headers());
+
+    if !is_state_changing(request.method()) {
+        let token = cookie.clone().unwrap_or_else(generate_token);
+        request.extensions_mut().insert(CsrfToken(token.clone()));
+        let mut response = next.run(request).await;
+        if cookie.is_none() {
+            set_cookie(&mut response, &token);
+        }
+        return response;
+    }
+
+    let path = request.uri().path().to_string();
+    let Some(expected) = cookie else {
+        warn!("Rejected {} without a CSRF cookie", path);
+        return deny(DenialReason::CsrfTokenMissing, "The CSRF cookie is missing; fetch a token first");
+    };
+    let (mut request, presented) = match presented_token(request).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    match presented {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {}
+        Some(_) => {
+            warn!("Rejected {} with a mismatched CSRF token", path);
+            return deny(DenialReason::CsrfTokenMismatch, "The CSRF token does not match the cookie");
+        }
+        None => {
+            warn!("Rejected {} without a CSRF token", path);
+            return deny(
+                DenialReason::CsrfTokenMissing,
+                "A CSRF token is required in the X-Csrf-Token header or the csrf_token form field",
+            );
+        }
+    }
+
+    if !csrf.rotate {
+        request.extensions_mut().insert(CsrfToken(expected));
+        return next.run(request).await;
+    }
+
+    let token = generate_token();
+    request.extensions_mut().insert(CsrfToken(token.clone()));
+    let mut response = next.run(request).await;
+    set_cookie(&mut response, &token);
+    if let Ok(value) = HeaderValue::from_str(&token) {
+        response.headers_mut().insert(HEADER_NAME, value);
+    }
+    response
+}
+
+/// Route issuing the token: `GET /csrf-token`
+pub fn routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new().route("/csrf-token", get(issue_token))
+}
+
+/// Body of `GET /csrf-token`
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = CsrfToken)]
+struct TokenBody {
+    token: String,
+}
+
+/// CSRF token for form submissions, issuing the __Host-csrf cookie if needed
+///
+/// Answers with the current token, or a new one with its cookie.
+#[utoipa::path(
+    get,
+    path = "/csrf-token",
+    responses((status = OK, description = "The token", body = TokenBody)),
+)]
+async fn issue_token(headers: HeaderMap) -> Response {
+    let cookie = cookie_token(&headers);
+    let token = cookie.clone().unwrap_or_else(generate_token);
+    let mut response = (
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(TokenBody { token: token.clone() }),
+    )
+        .into_response();
+    if cookie.is_none() {
+        set_cookie(&mut response, &token);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_are_random_and_well_formed() {
+        let (a, b) = (generate_token(), generate_token());
+        assert!(well_formed(&a), "{}", a);
+        assert_ne!(a, b);
+        assert!(!well_formed("short"));
+        assert!(!well_formed(&"z".repeat(TOKEN_BYTES * 2)));
+    }
+
+    #[test]
+    fn test_cookie_token_is_found_among_others() {
+        let token = generate_token();
+        let mut headers = HeaderMap::new();
+        headers.append(header::COOKIE, HeaderValue::from_static("theme=dark"));
+        headers.append(
+            header::COOKIE,
+            HeaderValue::from_str(&format!("a=1; {}={}; b=2", COOKIE_NAME, token)).unwrap(),
+        );
+        assert_eq!(cookie_token(&headers), Some(token));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("__Host-csrf=tampered"));
+        assert_eq!(cookie_token(&headers), None);
+    }
+}
@@ -0,0 +1,354 @@
+/*!
+ * Opt-in debugging endpoints
+ *
+ * These routes reveal details about incoming requests and answer 404 unless
+ * `DEBUG_ENDPOINTS=true` or their group is switched on (see
+ * [`crate::features`]). `POST /validate` checks JSON bodies against
+ * the schemas in `SCHEMAS_DIR` (see [`crate::json_schema`]).
+ */
+use crate::client_ip::ClientIp;
+use crate::cloudflare::meta::CloudflareMeta;
+use crate::config::AppConfig;
+use crate::error::{ApiError, ErrorBody};
+use crate::features::{self, FeatureToggles};
+use crate::json_schema::{SchemaInfo, DEFAULT_SCHEMA, SCHEMA_HEADER};
+use crate::redact;
+use crate::scheme::RequestScheme;
+use crate::state::AppState;
+use crate::websocket;
+use axum::{
+    body::Bytes,
+    extract::{FromRef, Path, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri, Version},
+    response::{IntoResponse, Json, Response},
+    routing::{any, get, post},
+    Router,
+};
+use base64::Engine;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+/// The `debug`, `echo` and `ws` route groups, each answering 404 while its
+/// toggle in `features` is off
+pub fn routes<S>(features: &FeatureToggles) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    Arc<AppConfig>: FromRef<S>,
+    AppState: FromRef<S>,
+{
+    let debug = Router::new()
+        .route("/whoami", get(whoami))
+        .route("/status/:code", get(status))
+        .route("/delay/:seconds", get(delay))
+        .route("/validate", post(validate))
+        .route("/validate/schemas", get(list_schemas))
+        .route("/docs", get(crate::openapi::docs));
+    let echo = Router::new().route("/echo", any(echo));
+    let ws = Router::new().route("/ws", get(websocket::upgrade));
+
+    features::guard(debug, features, features::DEBUG)
+        .merge(features::guard(echo, features, features::ECHO))
+        .merge(features::guard(ws, features, features::WS))
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Client address and Cloudflare headers as seen by the origin
+#[utoipa::path(
+    get,
+    path = "/whoami",
+    responses((status = OK, description = "Request details", body = Object)),
+)]
+async fn whoami(
+    cloudflare: CloudflareMeta,
+    client: ClientIp,
+    scheme: RequestScheme,
+    version: Version,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    // Per-request output must never be cached by Cloudflare
+    let body = Json(json!({
+        "client_ip": client.ip.map(|ip| ip.to_string()),
+        "client_ip_source": client.source.map(|s| s.as_str()),
+        "cf_ray": header_str(&headers, "cf-ray"),
+        "cf_colo": cloudflare.colo,
+        "cf_ipcountry": cloudflare.country,
+        "cf_visitor_scheme": cloudflare.visitor_scheme.map(|s| s.as_str()),
+        "scheme": scheme.as_str(),
+        "cf_worker": cloudflare.worker,
+        "cf_bot_score": cloudflare.bot_score,
+        "host": header_str(&headers, "host"),
+        "http_version": format!("{:?}", version),
+        "headers": redact::headers_to_json(&headers),
+    }));
+    ([(header::CACHE_CONTROL, "no-store")], body)
+}
+
+/// Body of `/status/{code}`
+#[derive(Debug, Serialize, ToSchema)]
+struct StatusEcho {
+    status: u16,
+}
+
+/// Respond with the given status, httpbin style
+#[utoipa::path(
+    get,
+    path = "/status/{code}",
+    params(("code" = String, Path, description = "Status code, 100-599")),
+    responses(
+        (status = "default", description = "The requested status", body = StatusEcho),
+        (status = BAD_REQUEST, description = "Not a status code", body = ErrorBody),
+    ),
+)]
+async fn status(Path(code): Path<String>) -> Result<Response, ApiError> {
+    let status = code
+        .parse::<u16>()
+        .ok()
+        .filter(|code| (100..=599).contains(code))
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_status_code",
+            format!("{:?} is not a status code between 100 and 599", code),
+        ))?;
+
+    // hyper cannot send a 1xx as the final response and would turn it into a 500
+    if status.is_informational() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "unsupported_status_code",
+            format!("{} is informational and cannot be a final response", status.as_u16()),
+        ));
+    }
+
+    let mut response = if status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        // These responses must not carry a body
+        status.into_response()
+    } else {
+        (status, Json(StatusEcho { status: status.as_u16() })).into_response()
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+    if status.is_redirection() {
+        headers.insert(header::LOCATION, header::HeaderValue::from_static("/"));
+    }
+
+    Ok(response)
+}
+
+/// Requested and actual delay
+#[derive(Debug, Serialize, ToSchema)]
+struct DelayReport {
+    requested_seconds: f64,
+    elapsed_seconds: f64,
+}
+
+/// Respond after a delay
+///
+/// Sleeps for the requested number of seconds (fractions allowed), up to
+/// `DEBUG_MAX_DELAY_SECS`. A pending delay is an in-flight request, so
+/// graceful shutdown waits for it to finish.
+#[utoipa::path(
+    get,
+    path = "/delay/{seconds}",
+    params(("seconds" = String, Path, description = "Delay in seconds, fractions allowed")),
+    responses(
+        (status = OK, description = "Requested and actual delay", body = DelayReport),
+        (status = BAD_REQUEST, description = "Invalid or too long delay", body = ErrorBody),
+    ),
+)]
+async fn delay(
+    State(config): State<Arc<AppConfig>>,
+    Path(seconds): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let requested = seconds
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_delay",
+            format!("{:?} is not a non-negative number of seconds", seconds),
+        ))?;
+
+    let max_delay = config.debug.max_delay;
+    if requested > max_delay {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "delay_too_long",
+            format!("Delays are capped at {} seconds", max_delay.as_secs_f64()),
+        ));
+    }
+
+    let started = Instant::now();
+    tokio::time::sleep(requested).await;
+
+    Ok((
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(DelayReport {
+            requested_seconds: requested.as_secs_f64(),
+            elapsed_seconds: started.elapsed().as_secs_f64(),
+        }),
+    ))
+}
+
+/// Reflect the request
+///
+/// The request is shown as it reached the origin, after any Cloudflare
+/// transforms. The body is bounded by axum's default request body limit.
+#[utoipa::path(
+    post,
+    path = "/echo",
+    request_body(content = String, content_type = "*/*"),
+    responses((status = OK, description = "The request as received", body = Object)),
+)]
+async fn echo(
+    method: Method,
+    uri: Uri,
+    client: ClientIp,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let (body, encoding) = echo_body(&headers, &body);
+    let body = Json(json!({
+        "method": method.as_str(),
+        "uri": uri.to_string(),
+        "query": query_to_json(uri.query().unwrap_or_default()),
+        "headers": redact::headers_to_json(&headers),
+        "client_ip": client.ip.map(|ip| ip.to_string()),
+        "body": body,
+        "body_encoding": encoding,
+    }));
+    ([(header::CACHE_CONTROL, "no-store")], body)
+}
+
+/// Query parameters as a JSON object; repeated keys become arrays
+fn query_to_json(query: &str) -> Value {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+
+    let mut map = Map::new();
+    for (key, value) in pairs {
+        let value = Value::String(value);
+        match map.get_mut(&key) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                map.insert(key, value);
+            }
+        }
+    }
+
+    Value::Object(map)
+}
+
+/// The body as parsed JSON (for JSON content types), UTF-8 text, or base64
+fn echo_body(headers: &HeaderMap, body: &[u8]) -> (Value, &'static str) {
+    if body.is_empty() {
+        return (Value::Null, "empty");
+    }
+
+    if is_json(headers) {
+        if let Ok(value) = serde_json::from_slice(body) {
+            return (value, "json");
+        }
+    }
+
+    match std::str::from_utf8(body) {
+        Ok(text) => (Value::String(text.to_string()), "text"),
+        Err(_) => (
+            Value::String(base64::engine::general_purpose::STANDARD.encode(body)),
+            "base64",
+        ),
+    }
+}
+
+/// Whether the request declares a JSON body
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/json") || v.contains("+json"))
+        .unwrap_or(false)
+}
+
+/// Check a JSON body against the schema named by X-Schema (default: "default")
+///
+/// The body is bounded by axum's default request body limit.
+#[utoipa::path(
+    post,
+    path = "/validate",
+    params(("X-Schema" = Option<String>, Header, description = "Schema loaded from SCHEMAS_DIR")),
+    request_body(content = Object, content_type = "application/json"),
+    responses(
+        (status = OK, description = "The parsed document and an empty error list", body = Object),
+        (status = BAD_REQUEST, description = "Unknown schema or malformed JSON", body = ErrorBody),
+        (status = UNSUPPORTED_MEDIA_TYPE, description = "Not a JSON body", body = ErrorBody),
+        (status = UNPROCESSABLE_ENTITY, description = "The document breaks the schema; details.errors lists each violation", body = ErrorBody),
+    ),
+)]
+async fn validate(
+    State(config): State<Arc<AppConfig>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, ApiError> {
+    if !is_json(&headers) {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            "Send the document with Content-Type: application/json",
+        ));
+    }
+
+    let schemas = &config.debug.schemas;
+    let name = header_str(&headers, SCHEMA_HEADER.as_str()).unwrap_or(DEFAULT_SCHEMA);
+    let schema = schemas.get(name).ok_or_else(|| {
+        let available: Vec<String> = schemas.list().into_iter().map(|info| info.name).collect();
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "unknown_schema",
+            format!("No schema named {:?}; name one with the X-Schema header", name),
+        )
+        .with_details(json!({ "schemas": available }))
+    })?;
+
+    let document: Value = serde_json::from_slice(&body).map_err(|e| ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_json",
+        format!("The body is not valid JSON: {}", e),
+    ))?;
+
+    let violations = schema.validate(&document);
+    if !violations.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "schema_violation",
+            format!("The document does not match schema {:?}", name),
+        )
+        .with_details(json!({ "schema": name, "errors": violations })));
+    }
+
+    let body = Json(json!({ "valid": true, "schema": name, "document": document, "errors": [] }));
+    Ok(([(header::CACHE_CONTROL, "no-store")], body).into_response())
+}
+
+/// Body of `/validate/schemas`
+#[derive(Debug, Serialize, ToSchema)]
+struct SchemaList {
+    schemas: Vec<SchemaInfo>,
+}
+
+/// Schemas loaded from SCHEMAS_DIR
+#[utoipa::path(
+    get,
+    path = "/validate/schemas",
+    responses((status = OK, description = "Schema names and titles", body = SchemaList)),
+)]
+async fn list_schemas(State(config): State<Arc<AppConfig>>) -> impl IntoResponse {
+    Json(SchemaList { schemas: config.debug.schemas.list() })
+}
@@ -0,0 +1,247 @@
+/*!
+ * Compressed request bodies
+ *
+ * Some webhook senders compress their payloads. A request with
+ * `Content-Encoding: gzip` or `br` is read (at most `REQUEST_BODY_MAX_BYTES`
+ * of it), decompressed (at most `REQUEST_BODY_MAX_DECOMPRESSED_BYTES`), and
+ * handed on as a plain body without the `Content-Encoding` header, so
+ * handlers never see compressed bytes. Both caps answer `413`, so a few
+ * kilobytes that would inflate to gigabytes are stopped once the
+ * decompressed cap is reached rather than after inflating everything. A body
+ * that does not decode gets `400`, and any other encoding `415`.
+ */
+use crate::config::AppConfig;
+use crate::error::ApiError;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::io::Read;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Caps on compressed request bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct DecompressionConfig {
+    /// Most bytes read from a compressed body
+    pub max_compressed: usize,
+
+    /// Most bytes a body may inflate to
+    pub max_decompressed: usize,
+}
+
+impl Default for DecompressionConfig {
+    fn default() -> Self {
+        // axum's own limit for body extractors
+        Self {
+            max_compressed: 2 * 1024 * 1024,
+            max_decompressed: 2 * 1024 * 1024,
+        }
+    }
+}
+
+impl DecompressionConfig {
+    /// Load from `REQUEST_BODY_MAX_BYTES` and
+    /// `REQUEST_BODY_MAX_DECOMPRESSED_BYTES`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        if let Some(bytes) = positive_env("REQUEST_BODY_MAX_BYTES")? {
+            config.max_compressed = bytes;
+        }
+        if let Some(bytes) = positive_env("REQUEST_BODY_MAX_DECOMPRESSED_BYTES")? {
+            config.max_decompressed = bytes;
+        }
+        Ok(config)
+    }
+}
+
+fn positive_env(var: &str) -> crate::Result<Option<usize>> {
+    let Ok(value) = std::env::var(var) else {
+        return Ok(None);
+    };
+    value.parse()
+        .ok()
+        .filter(|n| *n > 0)
+        .map(Some)
+        .ok_or_else(|| crate::ServerError::ConfigError(format!(
+            "Invalid {}: {:?} is not a positive number",
+            var, value
+        )))
+}
+
+/// Middleware replacing compressed request bodies with their decompressed
+/// bytes
+pub async fn decompress_request(State(config): State<Arc<AppConfig>>, request: Request, next: Next) -> Response {
+    let encoding = request
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .map(|v| v.to_str().unwrap_or_default().trim().to_ascii_lowercase());
+
+    let encoding = match encoding.as_deref() {
+        None | Some("identity") => return next.run(request).await,
+        Some("gzip" | "x-gzip") => ContentEncoding::Gzip,
+        Some("br") => ContentEncoding::Brotli,
+        Some(other) => {
+            return ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_content_encoding",
+                format!("Content-Encoding {:?} is not supported; use gzip, br or identity", other),
+            )
+            .into_response()
+        }
+    };
+
+    let limits = config.decompression;
+    let (mut parts, body) = request.into_parts();
+    let Ok(compressed) = axum::body::to_bytes(body, limits.max_compressed).await else {
+        return ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_too_large",
+            format!("Compressed request bodies may be at most {} bytes", limits.max_compressed),
+        )
+        .into_response();
+    };
+
+    let decompressed = match decompress(encoding, &compressed, limits.max_decompressed) {
+        Ok(decompressed) => decompressed,
+        Err(InflateError::TooLarge) => {
+            warn!(
+                "Rejected {} body to {} inflating past {} bytes",
+                encoding.name(),
+                parts.uri.path(),
+                limits.max_decompressed
+            );
+            return ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "decompressed_body_too_large",
+                format!("Request bodies may decompress to at most {} bytes", limits.max_decompressed),
+            )
+            .into_response();
+        }
+        Err(InflateError::Invalid(reason)) => {
+            return ApiError::new(
+                StatusCode::BAD_REQUEST,
+                match encoding {
+                    ContentEncoding::Gzip => "invalid_gzip_body",
+                    ContentEncoding::Brotli => "invalid_br_body",
+                },
+                format!("The request body is not valid {}: {}", encoding.name(), reason),
+            )
+            .into_response();
+        }
+    };
+
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(decompressed.len()));
+    next.run(Request::from_parts(parts, Body::from(decompressed))).await
+}
+
+/// A request body coding this module decodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// The `Content-Encoding` token
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+        }
+    }
+}
+
+/// Why a body could not be inflated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InflateError {
+    /// The output would exceed the cap
+    TooLarge,
+
+    /// Malformed input
+    Invalid(String),
+}
+
+/// Decode `data`, producing at most `max_output` bytes
+pub fn decompress(encoding: ContentEncoding, data: &[u8], max_output: usize) -> Result<Vec<u8>, InflateError> {
+    match encoding {
+        ContentEncoding::Gzip => read_capped(flate2::read::MultiGzDecoder::new(data), max_output),
+        ContentEncoding::Brotli => read_capped(brotli::Decompressor::new(data, 4096), max_output),
+    }
+}
+
+/// Decompress one or more concatenated gzip members, producing at most
+/// `max_output` bytes
+pub fn gunzip(data: &[u8], max_output: usize) -> Result<Vec<u8>, InflateError> {
+    decompress(ContentEncoding::Gzip, data, max_output)
+}
+
+/// Read `decoder` to the end, stopping one byte past `max_output`
+fn read_capped(decoder: impl Read, max_output: usize) -> Result<Vec<u8>, InflateError> {
+    let mut out = Vec::new();
+    decoder
+        .take((max_output as u64).saturating_add(1))
+        .read_to_end(&mut out)
+        .map_err(|e| InflateError::Invalid(e.to_string()))?;
+    if out.len() > max_output {
+        return Err(InflateError::TooLarge);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        brotli::CompressorReader::new(data, 4096, 11, 22).read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_gunzip_concatenated_members() {
+        let mut data = gzip(b"{\"a\":");
+        data.extend(gzip(b"1}"));
+        assert_eq!(gunzip(&data, 1024).unwrap(), b"{\"a\":1}");
+    }
+
+    #[test]
+    fn test_output_cap_is_enforced() {
+        let data = b"hello hello hello hello\n";
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Brotli] {
+            let compressed = match encoding {
+                ContentEncoding::Gzip => gzip(data),
+                ContentEncoding::Brotli => brotli(data),
+            };
+            assert_eq!(decompress(encoding, &compressed, data.len()).unwrap(), data);
+            assert_eq!(decompress(encoding, &compressed, 10), Err(InflateError::TooLarge));
+        }
+    }
+
+    #[test]
+    fn test_corruption_is_rejected() {
+        let mut corrupt = gzip(b"hello hello hello hello\n");
+        let crc_at = corrupt.len() - 8;
+        corrupt[crc_at] ^= 1;
+        assert!(matches!(gunzip(&corrupt, 1024), Err(InflateError::Invalid(_))));
+        assert!(matches!(gunzip(b"not gzip at all, really", 1024), Err(InflateError::Invalid(_))));
+        let truncated = brotli(b"hello hello hello hello\n");
+        assert!(matches!(
+            decompress(ContentEncoding::Brotli, &truncated[..truncated.len() / 2], 1024),
+            Err(InflateError::Invalid(_))
+        ));
+    }
+}
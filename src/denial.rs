@@ -0,0 +1,235 @@
+/*!
+ * One response shape for every blocked request
+ *
+ * Middleware that turns a request away on policy (direct-to-origin hits,
+ * missing or invalid credentials, API key rate limits, failed CSRF checks,
+ * oversized or malformed request heads, maintenance mode) answers with a
+ * [`Denial`] rather than a bare `ApiError`. Every denial renders the same
+ * way:
+ *
+ * - the usual `{"error": "<reason>", "message": "..."}` body, with
+ *   `details.retry_after` when the client may try again later
+ * - `X-Denial-Reason: <reason>`, the same code, for clients and logs that
+ *   do not read bodies
+ * - `Retry-After` in whole seconds when there is a wait
+ *
+ * Cloudflare cannot turn an origin response into a challenge by itself,
+ * but its rules can match response headers: with `DENIAL_SIGNAL_HEADER`
+ * set, the [`signal`] middleware adds that header (valued
+ * `DENIAL_SIGNAL_VALUE`) to every denial, so a rate limiting rule counting
+ * responses that carry it can challenge the client at the edge, and
+ * custom error rules can swap in their own page.
+ */
+use crate::config::AppConfig;
+use crate::error::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Header naming the reason on every denial
+pub const DENIAL_REASON_HEADER: HeaderName = HeaderName::from_static("x-denial-reason");
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenialReason {
+    /// Arrived without Cloudflare's headers under `DIRECT_ACCESS_POLICY=block`
+    DirectAccess,
+
+    /// API key over its `API_KEY_RATE_LIMIT`
+    RateLimited,
+
+    /// State-changing request on a CSRF-protected path without a token
+    CsrfTokenMissing,
+
+    /// CSRF token that does not match the cookie
+    CsrfTokenMismatch,
+
+    /// Request target naming a host instead of a path
+    AbsoluteFormTarget,
+
+    /// More than one `Host` header
+    DuplicateHost,
+
+    /// Missing or invalid bearer token or API key
+    Unauthenticated,
+
+    /// More request headers than `MAX_REQUEST_HEADERS`
+    TooManyHeaders,
+
+    /// Request headers over `MAX_REQUEST_HEADER_BYTES`
+    HeadersTooLarge,
+
+    /// Cookies over `MAX_COOKIE_BYTES`
+    CookieTooLarge,
+
+    /// Maintenance mode is on
+    Maintenance,
+}
+
+impl DenialReason {
+    /// Machine-readable code, sent as `error` and `X-Denial-Reason`
+    pub fn code(self) -> &'static str {
+        match self {
+            DenialReason::DirectAccess => "direct_access_forbidden",
+            DenialReason::RateLimited => "rate_limited",
+            DenialReason::CsrfTokenMissing => "csrf_token_missing",
+            DenialReason::CsrfTokenMismatch => "csrf_token_mismatch",
+            DenialReason::AbsoluteFormTarget => "absolute_form_target",
+            DenialReason::DuplicateHost => "duplicate_host",
+            DenialReason::Unauthenticated => "unauthorized",
+            DenialReason::TooManyHeaders => "too_many_headers",
+            DenialReason::HeadersTooLarge => "headers_too_large",
+            DenialReason::CookieTooLarge => "cookie_too_large",
+            DenialReason::Maintenance => "maintenance",
+        }
+    }
+
+    /// Status the denial is sent with
+    pub fn status(self) -> StatusCode {
+        match self {
+            DenialReason::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            DenialReason::AbsoluteFormTarget | DenialReason::DuplicateHost => StatusCode::BAD_REQUEST,
+            DenialReason::DirectAccess | DenialReason::CsrfTokenMissing | DenialReason::CsrfTokenMismatch => {
+                StatusCode::FORBIDDEN
+            }
+            DenialReason::Unauthenticated => StatusCode::UNAUTHORIZED,
+            DenialReason::TooManyHeaders | DenialReason::HeadersTooLarge | DenialReason::CookieTooLarge => {
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+            }
+            DenialReason::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// A blocked request's response
+#[derive(Debug, Clone)]
+pub struct Denial {
+    pub reason: DenialReason,
+    pub message: String,
+
+    /// How long until the client may try again, sent as `Retry-After`
+    pub retry_after: Option<Duration>,
+}
+
+impl Denial {
+    /// Denial for `reason`, explained by `message`
+    pub fn new(reason: DenialReason, message: impl Into<String>) -> Self {
+        Self { reason, message: message.into(), retry_after: None }
+    }
+
+    /// The same denial, telling the client to wait `wait`
+    pub fn with_retry_after(mut self, wait: Duration) -> Self {
+        self.retry_after = Some(wait);
+        self
+    }
+}
+
+impl IntoResponse for Denial {
+    fn into_response(self) -> Response {
+        let reason = self.reason;
+        // Rounded up so a client waiting as told is not turned away again
+        let retry_after = self
+            .retry_after
+            .map(|wait| (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1));
+
+        let mut error = ApiError::new(reason.status(), reason.code(), self.message);
+        if let Some(secs) = retry_after {
+            error = error.with_details(json!({ "retry_after": secs }));
+        }
+        let mut response = error.into_response();
+        let headers = response.headers_mut();
+        headers.insert(DENIAL_REASON_HEADER, HeaderValue::from_static(reason.code()));
+        if let Some(secs) = retry_after {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        response.extensions_mut().insert(reason);
+        response
+    }
+}
+
+/// Header added to denials for Cloudflare rules to match
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DenialSignal {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl Serialize for DenialSignal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut signal = serializer.serialize_struct("DenialSignal", 2)?;
+        signal.serialize_field("name", self.name.as_str())?;
+        signal.serialize_field("value", self.value.to_str().unwrap_or_default())?;
+        signal.end()
+    }
+}
+
+/// How denials are marked for the edge
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DenialConfig {
+    /// Extra header on every denial, if any
+    pub signal: Option<DenialSignal>,
+}
+
+impl DenialConfig {
+    /// Load from `DENIAL_SIGNAL_HEADER` and `DENIAL_SIGNAL_VALUE` (default `1`)
+    pub fn from_env() -> crate::Result<Self> {
+        let invalid = |msg: String| crate::ServerError::ConfigError(msg);
+        let Ok(name) = std::env::var("DENIAL_SIGNAL_HEADER") else {
+            if std::env::var("DENIAL_SIGNAL_VALUE").is_ok() {
+                return Err(invalid("DENIAL_SIGNAL_VALUE requires DENIAL_SIGNAL_HEADER".to_string()));
+            }
+            return Ok(Self::default());
+        };
+
+        let name = HeaderName::try_from(name.trim())
+            .map_err(|_| invalid(format!("Invalid DENIAL_SIGNAL_HEADER {:?}", name)))?;
+        if [DENIAL_REASON_HEADER, header::RETRY_AFTER, header::CONTENT_TYPE, header::CONTENT_LENGTH].contains(&name) {
+            return Err(invalid(format!("DENIAL_SIGNAL_HEADER cannot be {}, which denials already set", name)));
+        }
+        let value = std::env::var("DENIAL_SIGNAL_VALUE").unwrap_or_else(|_| "1".to_string());
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|_| invalid(format!("Invalid DENIAL_SIGNAL_VALUE {:?}", value)))?;
+        Ok(Self { signal: Some(DenialSignal { name, value }) })
+    }
+}
+
+/// Middleware adding the configured signal header to denials from the
+/// layers inside it
+pub async fn signal(State(config): State<Arc<AppConfig>>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if let Some(signal) = &config.denial.signal {
+        if response.extensions().get::<DenialReason>().is_some() {
+            response.headers_mut().insert(signal.name.clone(), signal.value.clone());
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retry_after_rounds_up() {
+        let response = Denial::new(DenialReason::RateLimited, "slow down")
+            .with_retry_after(Duration::from_millis(2_100))
+            .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "3");
+        assert_eq!(response.headers()[DENIAL_REASON_HEADER], "rate_limited");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({"error": "rate_limited", "message": "slow down", "details": {"retry_after": 3}}));
+
+        let response = Denial::new(DenialReason::RateLimited, "").with_retry_after(Duration::ZERO).into_response();
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    }
+}
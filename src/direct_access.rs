@@ -0,0 +1,183 @@
+/*!
+ * Direct-to-origin detection
+ *
+ * Everything that comes through the tunnel carries `CF-Ray` and
+ * `CF-Connecting-IP`. A request without them reached the origin some other
+ * way and skipped Cloudflare's WAF and DDoS protection. `DIRECT_ACCESS_POLICY`
+ * decides what happens to such requests:
+ *
+ * - `allow` (default): nothing
+ * - `log`: warn with the peer address and count it in `direct_hits_total`
+ * - `block`: the same, then answer 403
+ *
+ * Loopback peers are exempt so local curl and container health probes keep
+ * working. The admin listener never runs this middleware.
+ */
+use crate::denial::{Denial, DenialReason};
+use crate::state::AppState;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tracing::warn;
+
+/// Metric counting requests that arrived without Cloudflare headers
+#[cfg(feature = "metrics")]
+pub const DIRECT_HITS_METRIC: &str = "direct_hits_total";
+
+/// What to do with a request that did not come through Cloudflare
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DirectAccessPolicy {
+    #[default]
+    Allow,
+    Log,
+    Block,
+}
+
+impl FromStr for DirectAccessPolicy {
+    type Err = crate::ServerError;
+
+    fn from_str(value: &str) -> crate::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "allow" => Ok(DirectAccessPolicy::Allow),
+            "log" => Ok(DirectAccessPolicy::Log),
+            "block" => Ok(DirectAccessPolicy::Block),
+            other => Err(crate::ServerError::ConfigError(format!(
+                "Invalid DIRECT_ACCESS_POLICY {:?} (expected allow, log or block)",
+                other
+            ))),
+        }
+    }
+}
+
+impl DirectAccessPolicy {
+    /// Load from `DIRECT_ACCESS_POLICY`, defaulting to `allow`
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("DIRECT_ACCESS_POLICY") {
+            Ok(value) => value.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Whether the request carries the headers cloudflared always forwards
+pub(crate) fn came_through_cloudflare(request: &Request) -> bool {
+    let headers = request.headers();
+    headers.contains_key("cf-ray") && headers.contains_key("cf-connecting-ip")
+}
+
+/// Middleware applying `policy` to requests that bypassed Cloudflare.
+///
+/// Requests without a socket peer (in-process calls) are never flagged.
+pub async fn enforce(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let policy = state.config().direct_access;
+    if policy == DirectAccessPolicy::Allow || came_through_cloudflare(&request) {
+        return next.run(request).await;
+    }
+
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let Some(peer) = peer.filter(|addr| !addr.ip().is_loopback()) else {
+        return next.run(request).await;
+    };
+
+    #[cfg(feature = "metrics")]
+    state.requests.direct_hits.inc();
+    warn!(
+        "Direct request from {} to {} without Cloudflare headers",
+        peer,
+        request.uri().path()
+    );
+
+    if policy == DirectAccessPolicy::Block {
+        return Denial::new(DenialReason::DirectAccess, "Requests must arrive through Cloudflare").into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::util::ServiceExt;
+
+    async fn send_with_state(state: &AppState, peer: &str, cloudflare: bool) -> StatusCode {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(state.clone(), enforce));
+
+        let mut builder = Request::builder().uri("/");
+        if cloudflare {
+            builder = builder
+                .header("cf-ray", "8a1b2c3d4e5f0abc-SJC")
+                .header("cf-connecting-ip", "203.0.113.7");
+        }
+        let mut request = builder.body(Body::empty()).expect("Failed to build request");
+        let peer: SocketAddr = peer.parse().expect("Invalid peer address");
+        request.extensions_mut().insert(ConnectInfo(peer));
+
+        app.oneshot(request).await.expect("Failed to get response").status()
+    }
+
+    fn state_with(policy: DirectAccessPolicy) -> AppState {
+        AppState::new(AppConfig { direct_access: policy, ..AppConfig::default() })
+    }
+
+    async fn send(policy: DirectAccessPolicy, peer: &str, cloudflare: bool) -> StatusCode {
+        send_with_state(&state_with(policy), peer, cloudflare).await
+    }
+
+    #[tokio::test]
+    async fn test_allow_ignores_direct_hits() {
+        assert_eq!(send(DirectAccessPolicy::Allow, "198.51.100.9:4000", false).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_log_passes_direct_hits() {
+        assert_eq!(send(DirectAccessPolicy::Log, "198.51.100.9:4000", false).await, StatusCode::OK);
+        assert_eq!(send(DirectAccessPolicy::Log, "198.51.100.9:4000", true).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_block_rejects_direct_hits() {
+        assert_eq!(send(DirectAccessPolicy::Block, "198.51.100.9:4000", false).await, StatusCode::FORBIDDEN);
+        assert_eq!(send(DirectAccessPolicy::Block, "172.20.0.3:4000", true).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_localhost_is_exempt() {
+        assert_eq!(send(DirectAccessPolicy::Block, "127.0.0.1:4000", false).await, StatusCode::OK);
+        assert_eq!(send(DirectAccessPolicy::Block, "[::1]:4000", false).await, StatusCode::OK);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_direct_hits_are_counted() {
+        let allow = state_with(DirectAccessPolicy::Allow);
+        send_with_state(&allow, "198.51.100.9:4000", false).await;
+        assert_eq!(allow.requests.direct_hits.get(), 0);
+
+        let log = state_with(DirectAccessPolicy::Log);
+        send_with_state(&log, "198.51.100.9:4000", false).await;
+        send_with_state(&log, "198.51.100.9:4000", true).await;
+        send_with_state(&log, "127.0.0.1:4000", false).await;
+        assert_eq!(log.requests.direct_hits.get(), 1);
+
+        let block = state_with(DirectAccessPolicy::Block);
+        send_with_state(&block, "198.51.100.9:4000", false).await;
+        assert_eq!(block.requests.direct_hits.get(), 1);
+    }
+
+    #[test]
+    fn test_policy_parsing() {
+        assert_eq!("LOG".parse::<DirectAccessPolicy>().ok(), Some(DirectAccessPolicy::Log));
+        assert!("deny".parse::<DirectAccessPolicy>().is_err());
+    }
+}
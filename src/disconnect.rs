@@ -0,0 +1,74 @@
+/*!
+ * Requests abandoned by the client
+ *
+ * When a client goes away mid-request (including Cloudflare giving up
+ * after its 100 second origin timeout), hyper drops the request's future,
+ * so the handler stops at its next `.await`: a `/delay` sleep ends, and a
+ * proxied request closes its upstream connection. Nothing would be logged,
+ * since the trace layer only reports responses.
+ *
+ * This middleware notices the drop: a request whose response head was
+ * never produced is logged in its request span with the nginx-style status
+ * 499 and counted in `client_disconnects_total`. Streams that already sent
+ * their head (`/events`, proxied bodies) ending early are not counted,
+ * since closing them is how they normally finish.
+ */
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::time::Instant;
+use tracing::info;
+
+/// Status logged for requests the client abandoned
+pub const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+/// Logs and counts the request when dropped before `done` is set
+struct Pending {
+    #[cfg(feature = "metrics")]
+    disconnects: crate::metrics::Counter,
+    span: tracing::Span,
+    started: Instant,
+    done: bool,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        #[cfg(feature = "metrics")]
+        self.disconnects.inc();
+
+        let _entered = self.span.enter();
+        info!(
+            status = CLIENT_CLOSED_REQUEST,
+            latency = %format_args!("{} ms", self.started.elapsed().as_millis()),
+            "client disconnected before the response was ready"
+        );
+    }
+}
+
+/// Middleware recording requests cancelled by a client disconnect
+pub async fn detect_disconnects(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    #[cfg(not(feature = "metrics"))]
+    let _ = state;
+    let mut pending = Pending {
+        #[cfg(feature = "metrics")]
+        disconnects: state.requests.client_disconnects.clone(),
+        span: tracing::Span::current(),
+        started: Instant::now(),
+        done: false,
+    };
+
+    let response = next.run(request).await;
+    pending.done = true;
+    response
+}
@@ -0,0 +1,178 @@
+/*!
+ * Draining on request, ahead of shutdown
+ *
+ * A preStop hook or deploy script calls `POST /admin/drain` on the admin
+ * listener to withdraw readiness while the process keeps serving: `/readyz`
+ * answers 503, so Cloudflare's health checks and load balancers move
+ * traffic elsewhere before `SIGTERM` arrives. With a delay (`delay_seconds`
+ * in the body, or `DRAIN_DELAY_SECS`) readiness is withdrawn only once it
+ * has passed. `POST /admin/undrain` cancels a pending drain or restores
+ * readiness.
+ *
+ * `/readyz` and `/status` show the drain and who asked for it; both
+ * requests are in the audit log like every admin action. Long-lived
+ * connections (WebSockets, event streams) close as soon as readiness is
+ * withdrawn, as they do at shutdown.
+ *
+ * When the shutdown signal comes, [`DrainState::shutdown`] cancels a pending
+ * drain and reports whether readiness is already withdrawn, so shutdown
+ * goes straight to draining connections. A drain cannot be undone once
+ * shutdown has started.
+ */
+use crate::state::AppState;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+/// Delay used when a drain request does not give one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DrainConfig {
+    #[serde(rename = "delay_seconds", serialize_with = "serialize_secs")]
+    pub delay: Duration,
+}
+
+fn serialize_secs<S: serde::Serializer>(delay: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(delay.as_secs())
+}
+
+impl DrainConfig {
+    /// Load from `DRAIN_DELAY_SECS`, defaulting to no delay
+    pub fn from_env() -> crate::Result<Self> {
+        let Ok(value) = std::env::var("DRAIN_DELAY_SECS") else {
+            return Ok(Self::default());
+        };
+        let secs: u64 = value.trim().parse().map_err(|_| {
+            crate::ServerError::ConfigError(format!("Invalid DRAIN_DELAY_SECS: {:?} is not a number of seconds", value))
+        })?;
+        Ok(Self { delay: Duration::from_secs(secs) })
+    }
+}
+
+/// A drain requested through the admin API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct Drain {
+    /// Audit identity of the caller, e.g. `token:admin`
+    pub by: String,
+    pub requested_at: DateTime<Utc>,
+
+    /// When readiness is (or was) withdrawn
+    pub effective_at: DateTime<Utc>,
+}
+
+/// Drain state reported by `/readyz` and the admin API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct DrainStatus {
+    /// Readiness has been withdrawn by a drain
+    pub draining: bool,
+
+    /// A drain is waiting for its delay to pass
+    pub pending: bool,
+
+    /// Shutdown has started; the drain can no longer be undone
+    pub shutting_down: bool,
+
+    /// The current drain, if any
+    pub drain: Option<Drain>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    drain: Option<Drain>,
+
+    /// Withdraws readiness once the delay has passed
+    pending: Option<AbortHandle>,
+    shutting_down: bool,
+}
+
+/// Drain requested through the admin API, shared by the state's clones
+#[derive(Debug, Clone, Default)]
+pub struct DrainState {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// A drain that cannot be undone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShuttingDown;
+
+impl DrainState {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Withdraw `state`'s readiness after `delay`, on behalf of `by`. A drain
+    /// already requested is kept as it is.
+    pub fn drain(&self, state: &AppState, by: String, delay: Duration) -> DrainStatus {
+        let mut inner = self.lock();
+        if inner.drain.is_some() || inner.shutting_down {
+            return status(&inner);
+        }
+
+        let requested_at = state.clock.now_utc();
+        let effective_at = requested_at + chrono::Duration::from_std(delay).unwrap_or_default();
+        if delay.is_zero() {
+            state.set_ready(false);
+            warn!(by = %by, "Drained through the admin API; readiness withdrawn");
+        } else {
+            warn!(by = %by, "Drain requested through the admin API; readiness withdrawn in {:?}", delay);
+            let drained = state.clone();
+            let task = tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let mut inner = drained.drain.lock();
+                if inner.pending.take().is_some() {
+                    drained.set_ready(false);
+                    info!("Drain delay passed; readiness withdrawn");
+                }
+            });
+            inner.pending = Some(task.abort_handle());
+        }
+        inner.drain = Some(Drain { by, requested_at, effective_at });
+        status(&inner)
+    }
+
+    /// Cancel the drain and make `state` ready again, unless shutdown has
+    /// started
+    pub fn undrain(&self, state: &AppState) -> Result<DrainStatus, ShuttingDown> {
+        let mut inner = self.lock();
+        if inner.shutting_down {
+            return Err(ShuttingDown);
+        }
+        if let Some(pending) = inner.pending.take() {
+            pending.abort();
+        }
+        if inner.drain.take().is_some() {
+            state.set_ready(true);
+            warn!("Undrained through the admin API; readiness restored");
+        }
+        Ok(status(&inner))
+    }
+
+    /// Record that shutdown has started, cancelling a pending drain. True
+    /// when a drain has already withdrawn readiness.
+    pub fn shutdown(&self) -> bool {
+        let mut inner = self.lock();
+        inner.shutting_down = true;
+        if let Some(pending) = inner.pending.take() {
+            pending.abort();
+            return false;
+        }
+        inner.drain.is_some()
+    }
+
+    /// The drain, if any, and whether it has taken effect
+    pub fn status(&self) -> DrainStatus {
+        status(&self.lock())
+    }
+}
+
+fn status(inner: &Inner) -> DrainStatus {
+    DrainStatus {
+        draining: inner.drain.is_some() && inner.pending.is_none(),
+        pending: inner.pending.is_some(),
+        shutting_down: inner.shutting_down,
+        drain: inner.drain.clone(),
+    }
+}
@@ -0,0 +1,70 @@
+/*!
+ * JSON error responses returned to HTTP clients
+ *
+ * Handlers and extractors that reject a request return an `ApiError`, which
+ * renders as `{"error": "<code>", "message": "<text>"}` with the matching
+ * status code so every error body has the same shape. Errors that need to
+ * say more (e.g. which fields of a body were wrong) add a `details` value. The error itself is
+ * kept in the response extensions for `error_pages` to render as HTML.
+ */
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Error response with a machine-readable code and a human-readable message
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+
+    /// Extra structured information, rendered as `details`
+    pub details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    /// Error rendered with `status`, e.g. `ApiError::new(StatusCode::FORBIDDEN, "forbidden", "...")`
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    /// The same error with `details` added to its body
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+/// JSON body of an [`ApiError`]
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = Error)]
+pub struct ErrorBody<'a> {
+    /// Machine-readable code
+    pub error: &'a str,
+    pub message: &'a str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<&'a serde_json::Value>,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.code,
+            message: &self.message,
+            details: self.details.as_ref(),
+        };
+        let mut response = (self.status, Json(body)).into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
@@ -0,0 +1,283 @@
+/*!
+ * HTML error pages for browsers
+ *
+ * Error responses are JSON, which suits API clients but not a visitor who
+ * lands on a 404 or a maintenance 503. When `Accept` ranks `text/html` above
+ * `application/json`, this middleware swaps the JSON body of an error it
+ * has a page for (or the empty body of axum's 404 fallback) for HTML
+ * rendered with TinyTemplate, keeping the status and other headers.
+ *
+ * Pages for 404, 500 and 503 are compiled in from `templates/errors/`.
+ * `ERROR_PAGES_DIR` may hold `<status>.html` files replacing them or adding
+ * pages for other 4xx/5xx codes. The files are read and test-rendered when
+ * the configuration is loaded, so a broken template fails startup rather
+ * than a request, and a configuration reload picks up edited files.
+ *
+ * Templates see `status`, `reason`, `message` (the JSON `message`, if any)
+ * and `request_id`, all HTML-escaped, plus `lang` and the strings `t` of
+ * the request's [`Locale`] (see [`crate::i18n`]); the built-in pages take
+ * their text from there and the response carries `Content-Language`. The request id, taken from `CF-Ray`
+ * or else the ID assigned by [`crate::request_id`], is also appended to every page as a
+ * `<!-- request-id: ... -->` comment so support can find the request in
+ * the logs from a saved page.
+ */
+use crate::error::ApiError;
+use crate::i18n::{Locale, Strings};
+use crate::request_id::RequestId;
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tinytemplate::TinyTemplate;
+use tracing::error;
+
+/// Pages compiled into the binary
+const BUILT_IN: &[(u16, &str)] = &[
+    (404, include_str!("../templates/errors/404.html")),
+    (500, include_str!("../templates/errors/500.html")),
+    (503, include_str!("../templates/errors/503.html")),
+];
+
+/// Values available to the templates
+#[derive(Debug, Serialize)]
+struct Context<'a> {
+    lang: &'static str,
+    t: &'static Strings,
+    status: u16,
+    reason: &'static str,
+    message: Option<&'a str>,
+    request_id: Option<&'a str>,
+}
+
+/// Page templates by status code: the built-in ones plus `ERROR_PAGES_DIR`
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    dir: Option<PathBuf>,
+    overrides: BTreeMap<u16, String>,
+}
+
+impl ErrorPages {
+    /// Built-in pages, overridden by the `<status>.html` files in `dir`
+    pub fn load(dir: impl AsRef<Path>) -> crate::Result<Self> {
+        let dir = dir.as_ref();
+        let config_error = |e: std::io::Error| crate::ServerError::ConfigError(
+            format!("Failed to read ERROR_PAGES_DIR {}: {}", dir.display(), e)
+        );
+
+        let mut overrides = BTreeMap::new();
+        for entry in std::fs::read_dir(dir).map_err(config_error)? {
+            let path = entry.map_err(config_error)?.path();
+            let Some(status) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".html"))
+                .and_then(|code| code.parse::<u16>().ok())
+                .filter(|code| (400..600).contains(code))
+            else {
+                continue;
+            };
+
+            let template = std::fs::read_to_string(&path).map_err(|e| crate::ServerError::ConfigError(
+                format!("Failed to read error page {}: {}", path.display(), e)
+            ))?;
+            let status_code = StatusCode::from_u16(status).unwrap_or_default();
+            render_template(&template, status_code, Some("message"), Some("request-id"), Locale::default())
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid error page {}: {}", path.display(), e)
+                ))?;
+            overrides.insert(status, template);
+        }
+
+        Ok(Self { dir: Some(dir.to_path_buf()), overrides })
+    }
+
+    /// Pages from `ERROR_PAGES_DIR` if set, otherwise just the built-in ones
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("ERROR_PAGES_DIR") {
+            Ok(dir) if !dir.is_empty() => Self::load(dir),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// Directory the overrides were read from
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// Status codes with a page from the directory
+    pub fn overridden(&self) -> Vec<u16> {
+        self.overrides.keys().copied().collect()
+    }
+
+    fn template(&self, status: StatusCode) -> Option<&str> {
+        let code = status.as_u16();
+        self.overrides.get(&code).map(String::as_str).or_else(|| {
+            BUILT_IN.iter().find(|(built_in, _)| *built_in == code).map(|(_, template)| *template)
+        })
+    }
+
+    /// Whether there is a page for `status`
+    pub fn has_page(&self, status: StatusCode) -> bool {
+        self.template(status).is_some()
+    }
+
+    /// The page for `status` in `locale`, if there is one
+    pub fn render(
+        &self,
+        status: StatusCode,
+        message: Option<&str>,
+        request_id: Option<&str>,
+        locale: Locale,
+    ) -> Option<String> {
+        let template = self.template(status)?;
+        render_template(template, status, message, request_id, locale)
+            .map_err(|e| error!("Failed to render the {} error page: {}", status.as_u16(), e))
+            .ok()
+    }
+}
+
+fn render_template(
+    template: &str,
+    status: StatusCode,
+    message: Option<&str>,
+    request_id: Option<&str>,
+    locale: Locale,
+) -> Result<String, tinytemplate::error::Error> {
+    let context = Context {
+        lang: locale.code(),
+        t: locale.strings(),
+        status: status.as_u16(),
+        reason: status.canonical_reason().unwrap_or_default(),
+        message,
+        request_id,
+    };
+
+    let mut templates = TinyTemplate::new();
+    templates.add_template("page", template)?;
+    let mut page = templates.render("page", &context)?;
+
+    if let Some(request_id) = request_id {
+        // Escaping `>` also keeps the id from closing the comment early
+        page.push_str("<!-- request-id: ");
+        tinytemplate::escape(request_id, &mut page);
+        page.push_str(" -->\n");
+    }
+    Ok(page)
+}
+
+/// Cloudflare's `CF-Ray`, or the ID `request_id::assign` gave the request
+fn request_id(request: &Request) -> Option<&str> {
+    match request.headers().get("cf-ray").and_then(|v| v.to_str().ok()) {
+        Some(ray) => Some(ray),
+        None => request.extensions().get::<RequestId>().map(|id| id.0.as_str()),
+    }
+}
+
+/// Whether `Accept` ranks `text/html` above `application/json`; ties go to
+/// whichever is listed first
+pub(crate) fn prefers_html(headers: &HeaderMap) -> bool {
+    crate::negotiate::preferred(headers, &["application/json", "text/html"]) == Some("text/html")
+}
+
+/// Middleware rendering error pages for clients that prefer HTML
+pub async fn render_html(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let wants_html = prefers_html(request.headers());
+    let locale = Locale::negotiate(request.headers(), request.uri().query());
+    let request_id = request_id(&request).map(str::to_owned);
+    let mut response = next.run(request).await;
+
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+    // Only our own errors: bodies from handlers, static files or upstreams
+    // are left alone
+    let message = match response.extensions().get::<ApiError>() {
+        Some(api_error) => Some(api_error.message.clone()),
+        None if is_empty(&response) => None,
+        None => return response,
+    };
+
+    let config = state.config();
+    if !config.error_pages.has_page(status) {
+        return response;
+    }
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("accept"));
+    if !wants_html {
+        return response;
+    }
+
+    let Some(page) = config.error_pages.render(status, message.as_deref(), request_id.as_deref(), locale) else {
+        return response;
+    };
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    parts.headers.insert(header::CONTENT_LANGUAGE, locale.header_value());
+    parts.headers.append(header::VARY, HeaderValue::from_static("accept-language"));
+    Response::from_parts(parts, Body::from(page))
+}
+
+/// A bare status such as axum's 404 fallback
+fn is_empty(response: &Response) -> bool {
+    !response.headers().contains_key(header::CONTENT_TYPE)
+        && http_body::Body::size_hint(response.body()).exact() == Some(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_prefers_html() {
+        assert!(prefers_html(&accept("text/html,application/xhtml+xml,*/*;q=0.8")));
+        assert!(prefers_html(&accept("text/html, application/json")));
+        assert!(!prefers_html(&accept("application/json, text/html")));
+        assert!(!prefers_html(&accept("text/html;q=0.5, application/json")));
+        assert!(!prefers_html(&accept("text/html;q=0")));
+        assert!(!prefers_html(&accept("*/*")));
+        assert!(!prefers_html(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_built_in_pages_render() {
+        let pages = ErrorPages::default();
+        for (status, _) in BUILT_IN {
+            let status = StatusCode::from_u16(*status).unwrap();
+            let page = pages.render(status, None, None, Locale::default()).unwrap();
+            assert!(page.contains(&format!("<title>{}", status.as_u16())), "{}", page);
+            assert!(!page.contains("request-id"));
+        }
+        assert!(!pages.has_page(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn test_interpolation_is_escaped() {
+        let page = ErrorPages::default()
+            .render(
+                StatusCode::SERVICE_UNAVAILABLE,
+                Some("Back at <b>14:00</b>"),
+                Some("abc --><script>alert(1)</script>"),
+                Locale::default(),
+            )
+            .unwrap();
+
+        assert!(page.contains("<p>Back at &lt;b&gt;14:00&lt;/b&gt;</p>"), "{}", page);
+        assert!(page.ends_with("<!-- request-id: abc --&gt;&lt;script&gt;alert(1)&lt;/script&gt; -->\n"), "{}", page);
+        assert!(!page.contains("<script>"));
+        assert_eq!(page.matches("-->").count(), 1, "{}", page);
+    }
+}
@@ -0,0 +1,187 @@
+/*!
+ * `GET /events`: a Server-Sent Events stream of service stats
+ *
+ * A long-lived streaming response for checking how the tunnel and
+ * Cloudflare treat streams. Every `EVENTS_INTERVAL_SECS` the stream sends a
+ * `stats` event with a JSON body; the event ID counts up from 1, or from
+ * one past the `Last-Event-ID` a reconnecting client sends. Keep-alive
+ * comments go out every `EVENTS_KEEPALIVE_SECS` so idle intermediaries do
+ * not cut the connection.
+ *
+ * Streams end when the client disconnects or when the service starts
+ * draining, so graceful shutdown is not held up by them. At most
+ * `EVENTS_MAX_STREAMS` are open at once; further requests get 503.
+ */
+use crate::error::{ApiError, ErrorBody};
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Router,
+};
+use futures_util::{stream, StreamExt};
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Stream timing and limits
+#[derive(Debug, Clone)]
+pub struct EventsConfig {
+    /// Time between `stats` events
+    pub interval: Duration,
+
+    /// Time between keep-alive comments
+    pub keep_alive: Duration,
+
+    /// Most streams open at once
+    pub max_streams: usize,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            keep_alive: Duration::from_secs(15),
+            max_streams: 16,
+        }
+    }
+}
+
+impl EventsConfig {
+    /// Load from `EVENTS_INTERVAL_SECS`, `EVENTS_KEEPALIVE_SECS` and
+    /// `EVENTS_MAX_STREAMS`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+
+        let secs = |name: &str| -> crate::Result<Option<Duration>> {
+            let Ok(value) = std::env::var(name) else {
+                return Ok(None);
+            };
+            match value.parse::<u64>() {
+                Ok(secs) if secs > 0 => Ok(Some(Duration::from_secs(secs))),
+                _ => Err(crate::ServerError::ConfigError(format!(
+                    "Invalid {}: {:?} is not a positive number of seconds",
+                    name, value
+                ))),
+            }
+        };
+        if let Some(interval) = secs("EVENTS_INTERVAL_SECS")? {
+            config.interval = interval;
+        }
+        if let Some(keep_alive) = secs("EVENTS_KEEPALIVE_SECS")? {
+            config.keep_alive = keep_alive;
+        }
+
+        if let Ok(value) = std::env::var("EVENTS_MAX_STREAMS") {
+            config.max_streams = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid EVENTS_MAX_STREAMS: {}", e)
+                ))?;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Number of open streams, shared by every clone of the state
+#[derive(Debug, Clone, Default)]
+pub struct StreamCount(Arc<AtomicUsize>);
+
+impl StreamCount {
+    /// Streams currently open
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Take a slot if fewer than `max` are in use
+    fn acquire(&self, max: usize) -> Option<StreamSlot> {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| (open < max).then_some(open + 1))
+            .ok()
+            .map(|_| StreamSlot(self.0.clone()))
+    }
+}
+
+/// Releases its stream slot when the stream is dropped
+struct StreamSlot(Arc<AtomicUsize>);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// `GET /events`; `HEAD` gets 405 rather than opening a stream
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/events", get(events).head(crate::methods::reject_head))
+}
+
+/// Server-sent event stream
+#[utoipa::path(
+    get,
+    path = "/events",
+    responses(
+        (status = OK, description = "Open-ended event stream", body = String, content_type = "text/event-stream"),
+        (status = SERVICE_UNAVAILABLE, description = "Too many open streams", body = ErrorBody),
+    ),
+)]
+async fn events(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let config = state.config().events.clone();
+    let Some(slot) = state.event_streams.acquire(config.max_streams) else {
+        return ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too_many_streams",
+            format!("At most {} event streams may be open", config.max_streams),
+        )
+        .into_response();
+    };
+
+    // Resume numbering after the last event a reconnecting client saw
+    let first_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map_or(1, |id| id.saturating_add(1));
+
+    let mut ticks = tokio::time::interval(config.interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    let draining = state.clone();
+    let stats = stream::unfold((state, ticks, first_id, slot), |(state, mut ticks, id, slot)| async move {
+        ticks.tick().await;
+        let event = Event::default().event("stats").id(id.to_string()).json_data(stats(&state));
+        let event = event.unwrap_or_else(|_| Event::default().comment("stats unavailable"));
+        Some((Ok::<_, Infallible>(event), (state, ticks, id.saturating_add(1), slot)))
+    })
+    .take_until(async move { draining.draining().await });
+
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        Sse::new(stats).keep_alive(KeepAlive::new().interval(config.keep_alive)),
+    )
+        .into_response()
+}
+
+fn stats(state: &AppState) -> serde_json::Value {
+    let stats = json!({
+        "uptime_seconds": state.uptime().as_secs(),
+        "open_streams": state.event_streams.get(),
+    });
+
+    #[cfg(feature = "metrics")]
+    let stats = {
+        let mut stats = stats;
+        stats["requests_total"] = json!(state.requests.total.get());
+        stats["requests_in_flight"] = json!(state.requests.in_flight.get());
+        stats
+    };
+
+    stats
+}
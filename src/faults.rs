@@ -0,0 +1,266 @@
+/*!
+ * Fault injection for resilience drills
+ *
+ * To see how Cloudflare and clients cope with a degraded origin, an
+ * operator can make the service slow or flaky for a while from the admin
+ * listener:
+ *
+ * ```text
+ * POST /admin/faults {"latency_ms": 500, "error_rate": 0.1, "status": 503, "prefix": "/api", "ttl_seconds": 120}
+ * ```
+ *
+ * While the fault is active, requests under `prefix` (every path when it
+ * is absent) wait `latency_ms` before being handled, and each one is
+ * answered with `status` instead with probability `error_rate`, as
+ * `{"error": "injected_fault", ...}`. The probes in
+ * [`crate::maintenance::EXEMPT_PATHS`] are never touched, so `/readyz`
+ * keeps reporting the fault. `GET /admin/faults` shows it and
+ * `DELETE /admin/faults` ends it early; otherwise it expires after the
+ * TTL. Nothing is injected at startup.
+ *
+ * The errors are drawn from a [`Sampler`] (seeded, in tests) and the
+ * expiry read from a [`Clock`]. Builds without the `fault-injection`
+ * feature leave all of this out, admin route included.
+ */
+use crate::auth::longest_prefix;
+use crate::clock::{Clock, SystemClock};
+use crate::error::ApiError;
+use crate::header_sampling::{RandomSampler, Sampler};
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::info;
+use utoipa::ToSchema;
+
+/// Upper bound on `latency_ms`
+pub const MAX_LATENCY: Duration = Duration::from_secs(60);
+
+/// Upper bound on `ttl_seconds`
+pub const MAX_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// What to inject, where and for how long
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FaultSpec {
+    /// Delay before each matching request is handled
+    #[serde(default)]
+    pub latency_ms: u64,
+
+    /// Share of matching requests answered with `status`, from 0 to 1
+    #[serde(default)]
+    pub error_rate: f64,
+
+    #[serde(default = "default_status")]
+    pub status: u16,
+
+    /// Path prefix, matched on segment boundaries
+    pub prefix: Option<String>,
+
+    #[serde(default = "default_ttl_seconds")]
+    pub ttl_seconds: u64,
+}
+
+fn default_status() -> u16 {
+    503
+}
+
+fn default_ttl_seconds() -> u64 {
+    300
+}
+
+impl FaultSpec {
+    /// Reject values outside the limits, and specs that would do nothing
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let invalid = |message: String| ApiError::new(StatusCode::BAD_REQUEST, "invalid_fault", message);
+        if self.latency_ms > MAX_LATENCY.as_millis() as u64 {
+            return Err(invalid(format!("latency_ms must be at most {}", MAX_LATENCY.as_millis())));
+        }
+        if !(0.0..=1.0).contains(&self.error_rate) {
+            return Err(invalid("error_rate must be between 0 and 1".to_string()));
+        }
+        if !(400..=599).contains(&self.status) {
+            return Err(invalid("status must be between 400 and 599".to_string()));
+        }
+        if !(1..=MAX_TTL.as_secs()).contains(&self.ttl_seconds) {
+            return Err(invalid(format!("ttl_seconds must be between 1 and {}", MAX_TTL.as_secs())));
+        }
+        if self.latency_ms == 0 && self.error_rate == 0.0 {
+            return Err(invalid("Set latency_ms or error_rate".to_string()));
+        }
+        if self.prefix.as_ref().is_some_and(|prefix| !prefix.starts_with('/')) {
+            return Err(invalid("prefix must start with /".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Whether requests for `path` are affected
+    pub fn matches(&self, path: &str) -> bool {
+        if crate::maintenance::EXEMPT_PATHS.contains(&path) {
+            return false;
+        }
+        self.prefix
+            .as_ref()
+            .is_none_or(|prefix| longest_prefix(std::slice::from_ref(prefix), path).is_some())
+    }
+}
+
+#[derive(Debug)]
+struct ActiveFault {
+    spec: FaultSpec,
+    expires_at: SystemTime,
+}
+
+/// Body of `/admin/faults`, also shown by `/readyz`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FaultStatus {
+    pub active: bool,
+    pub spec: Option<FaultSpec>,
+    pub expires_at: Option<DateTime<Utc>>,
+
+    /// Requests delayed since the fault was set
+    pub delayed: u64,
+
+    /// Requests answered with the injected status since the fault was set
+    pub failed: u64,
+}
+
+/// The active fault, if any; clones share it
+#[derive(Debug, Clone)]
+pub struct FaultState {
+    clock: Arc<dyn Clock>,
+    sampler: Arc<dyn Sampler>,
+    active: Arc<Mutex<Option<ActiveFault>>>,
+    delayed: Arc<AtomicU64>,
+    failed: Arc<AtomicU64>,
+}
+
+impl Default for FaultState {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock), Arc::new(RandomSampler::default()))
+    }
+}
+
+impl FaultState {
+    /// No fault, with expiry measured by `clock` and errors drawn from
+    /// `sampler`
+    pub fn new(clock: Arc<dyn Clock>, sampler: Arc<dyn Sampler>) -> Self {
+        Self {
+            clock,
+            sampler,
+            active: Arc::default(),
+            delayed: Arc::default(),
+            failed: Arc::default(),
+        }
+    }
+
+    /// The active fault, after dropping it if it has expired
+    fn live<'a>(&self, active: &'a mut Option<ActiveFault>) -> Option<&'a ActiveFault> {
+        if active.as_ref().is_some_and(|fault| self.clock.now() >= fault.expires_at) {
+            info!("Injected fault expired");
+            *active = None;
+        }
+        active.as_ref()
+    }
+
+    /// Inject `spec`, replacing any active fault
+    pub fn start(&self, spec: FaultSpec) -> FaultStatus {
+        let expires_at = self.clock.now() + Duration::from_secs(spec.ttl_seconds);
+        *self.active.lock().unwrap_or_else(|e| e.into_inner()) = Some(ActiveFault { spec, expires_at });
+        self.delayed.store(0, Ordering::Relaxed);
+        self.failed.store(0, Ordering::Relaxed);
+        self.status()
+    }
+
+    /// End the active fault, returning how it went
+    pub fn stop(&self) -> FaultStatus {
+        let status = self.status();
+        *self.active.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        status
+    }
+
+    /// The active fault, if any, and the requests it has hit
+    pub fn status(&self) -> FaultStatus {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        let live = self.live(&mut active);
+        FaultStatus {
+            active: live.is_some(),
+            spec: live.map(|fault| fault.spec.clone()),
+            expires_at: live.map(|fault| fault.expires_at.into()),
+            delayed: self.delayed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The fault to apply to a request for `path`, if one is active and
+    /// matches
+    pub fn fault_for(&self, path: &str) -> Option<FaultSpec> {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        self.live(&mut active).map(|fault| &fault.spec).filter(|spec| spec.matches(path)).cloned()
+    }
+}
+
+/// Middleware delaying and failing matching requests while a fault is active
+pub async fn inject(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(fault) = state.faults.fault_for(request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    if fault.latency_ms > 0 {
+        state.faults.delayed.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(fault.latency_ms)).await;
+    }
+    if fault.error_rate > 0.0 && state.faults.sampler.sample(fault.error_rate) {
+        state.faults.failed.fetch_add(1, Ordering::Relaxed);
+        let status = StatusCode::from_u16(fault.status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+        let mut response =
+            ApiError::new(status, "injected_fault", "Fault injected through /admin/faults").into_response();
+        response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        return response;
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(json: &str) -> FaultSpec {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(spec(r#"{"latency_ms": 500}"#).validate().is_ok());
+        for bad in [
+            r#"{}"#,
+            r#"{"error_rate": 1.5}"#,
+            r#"{"error_rate": 0.5, "status": 200}"#,
+            r#"{"latency_ms": 60001}"#,
+            r#"{"latency_ms": 5, "ttl_seconds": 0}"#,
+            r#"{"latency_ms": 5, "prefix": "api"}"#,
+        ] {
+            assert!(spec(bad).validate().is_err(), "{}", bad);
+        }
+        assert!(serde_json::from_str::<FaultSpec>(r#"{"latency": 5}"#).is_err());
+    }
+
+    #[test]
+    fn test_prefix_and_probes() {
+        let api = spec(r#"{"latency_ms": 5, "prefix": "/api"}"#);
+        assert!(api.matches("/api") && api.matches("/api/users"));
+        assert!(!api.matches("/apix") && !api.matches("/"));
+
+        let everything = spec(r#"{"latency_ms": 5}"#);
+        assert!(everything.matches("/whoami"));
+        assert!(!everything.matches("/readyz"));
+    }
+}
@@ -0,0 +1,146 @@
+/*!
+ * `/favicon.ico`
+ *
+ * Browsers request the icon on every first visit; answering it keeps those
+ * requests out of the 404 logs and the error counter. A default icon is
+ * embedded in the binary, precompressed with gzip and Brotli at build time
+ * (see [`crate::assets`]), each variant with its ETag computed at compile
+ * time. `FAVICON_PATH` replaces it with a file read once at startup and
+ * served as it is.
+ */
+use crate::assets::{EmbeddedAsset, FAVICON};
+use crate::conditional;
+use crate::config::AppConfig;
+use axum::{
+    body::Bytes,
+    extract::{FromRef, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::Response,
+    routing::get,
+    Router,
+};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Path browsers request the icon from
+pub const FAVICON_PATH: &str = "/favicon.ico";
+
+/// One week; the ETag makes revalidation cheap once it expires
+const CACHE_CONTROL: &str = "public, max-age=604800";
+
+/// Icon bytes and their validators
+#[derive(Debug, Clone)]
+pub struct Favicon {
+    body: Bytes,
+    etag: HeaderValue,
+    last_modified: Option<SystemTime>,
+
+    /// Compressed variants, for the embedded icon
+    precompressed: Option<&'static EmbeddedAsset>,
+}
+
+impl Favicon {
+    /// The icon compiled into the binary
+    pub fn embedded() -> Self {
+        Self {
+            body: Bytes::from_static(FAVICON.identity.body),
+            etag: HeaderValue::from_static(FAVICON.identity.etag),
+            last_modified: None,
+            precompressed: Some(&FAVICON),
+        }
+    }
+
+    /// Icon read from `path`, sent with the file's modification time
+    pub fn load(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let body = std::fs::read(path)
+            .map_err(|e| crate::ServerError::ConfigError(
+                format!("Failed to read FAVICON_PATH {}: {}", path.display(), e)
+            ))?;
+
+        Ok(Self {
+            etag: conditional::strong_etag(&body),
+            last_modified: std::fs::metadata(path).and_then(|m| m.modified()).ok(),
+            body: Bytes::from(body),
+            precompressed: None,
+        })
+    }
+
+    /// `FAVICON_PATH` if set, otherwise the embedded icon
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("FAVICON_PATH") {
+            Ok(path) if !path.is_empty() => Self::load(path),
+            _ => Ok(Self::embedded()),
+        }
+    }
+
+    /// Quoted ETag sent with the uncompressed icon
+    pub fn etag(&self) -> &str {
+        self.etag.to_str().unwrap_or_default()
+    }
+}
+
+impl Default for Favicon {
+    fn default() -> Self {
+        Self::embedded()
+    }
+}
+
+/// A 404 for the icon is browser noise rather than an error worth counting,
+/// even when a custom router left the route out
+pub fn is_favicon_miss(path: &str, status: StatusCode) -> bool {
+    path == FAVICON_PATH && status == StatusCode::NOT_FOUND
+}
+
+/// `GET /favicon.ico`
+pub fn routes<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    Arc<AppConfig>: FromRef<S>,
+{
+    Router::new().route(FAVICON_PATH, get(favicon))
+}
+
+/// Site icon
+#[utoipa::path(
+    get,
+    path = FAVICON_PATH,
+    responses((status = OK, description = "The icon", body = [u8], content_type = "image/x-icon")),
+)]
+async fn favicon(State(config): State<Arc<AppConfig>>, headers: HeaderMap) -> Response {
+    let icon = &config.favicon;
+    if let Some(asset) = icon.precompressed {
+        return asset.response(&headers, Some(CACHE_CONTROL));
+    }
+    let body = (
+        [
+            (header::CONTENT_TYPE, "image/x-icon"),
+            (header::CACHE_CONTROL, CACHE_CONTROL),
+        ],
+        icon.body.clone(),
+    );
+
+    conditional::conditional_response_modified_at(&icon.etag, icon.last_modified, body, &headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::conditional::{etag_bytes, fnv1a};
+
+    #[test]
+    fn test_embedded_etag_matches_runtime_hash() {
+        let hashed = etag_bytes(fnv1a(include_bytes!("../assets/favicon.ico")));
+        assert_eq!(Favicon::embedded().etag().as_bytes(), &hashed);
+        assert_eq!(Favicon::embedded().etag().len(), 18);
+    }
+
+    #[test]
+    fn test_only_favicon_404s_are_ignored() {
+        assert!(is_favicon_miss("/favicon.ico", StatusCode::NOT_FOUND));
+        assert!(!is_favicon_miss("/favicon.ico", StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!is_favicon_miss("/missing", StatusCode::NOT_FOUND));
+    }
+}
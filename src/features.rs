@@ -0,0 +1,162 @@
+/*!
+ * Optional route groups switched on and off at runtime
+ *
+ * Each optional group of routes has a toggle: `debug` (`/whoami`,
+ * `/status/{code}`, `/delay/{seconds}`, `/validate`, `/docs`), `echo`
+ * (`/echo`) and `ws` (`/ws`), which exist with the `debug-endpoints`
+ * feature, and `events` (`/events`) and `status` (`/status`). The debug
+ * groups start as `DEBUG_ENDPOINTS` says and the others start on;
+ * `FEATURE_TOGGLES` overrides any of them as `name=bool` pairs, e.g.
+ * `events=false,echo=true`.
+ *
+ * `PUT /admin/features` with e.g. `{"debug": false}` switches groups while
+ * the service runs, and is audited like every admin action. A group that is
+ * off answers `404` exactly like a path with no route, so its routes are
+ * not advertised. Toggles live in [`AppState`](crate::state::AppState) and
+ * survive configuration reloads; `/admin/pipeline` and the startup summary
+ * show them.
+ */
+use crate::config::AppConfig;
+use axum::{extract::Request, http::StatusCode, middleware, middleware::Next, response::IntoResponse, Router};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+#[cfg(feature = "debug-endpoints")]
+pub const DEBUG: &str = "debug";
+#[cfg(feature = "debug-endpoints")]
+pub const ECHO: &str = "echo";
+#[cfg(feature = "debug-endpoints")]
+pub const WS: &str = "ws";
+pub const EVENTS: &str = "events";
+pub const STATUS: &str = "status";
+
+/// Every route group of this build
+pub const GROUPS: &[&str] = &[
+    #[cfg(feature = "debug-endpoints")]
+    DEBUG,
+    #[cfg(feature = "debug-endpoints")]
+    ECHO,
+    #[cfg(feature = "debug-endpoints")]
+    WS,
+    EVENTS,
+    STATUS,
+];
+
+fn group(name: &str) -> Option<&'static str> {
+    GROUPS.iter().copied().find(|group| *group == name)
+}
+
+/// Load `FEATURE_TOGGLES`, the groups whose startup state is set explicitly
+pub fn from_env() -> crate::Result<BTreeMap<String, bool>> {
+    let Ok(value) = std::env::var("FEATURE_TOGGLES") else {
+        return Ok(BTreeMap::new());
+    };
+    parse(&value).map_err(|e| crate::ServerError::ConfigError(format!("Invalid FEATURE_TOGGLES: {}", e)))
+}
+
+fn parse(value: &str) -> Result<BTreeMap<String, bool>, String> {
+    let mut toggles = BTreeMap::new();
+    for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (name, enabled) = pair.split_once('=').ok_or_else(|| format!("{:?} is not a name=bool pair", pair))?;
+        let name = group(name.trim()).ok_or_else(|| unknown(name.trim()))?;
+        let enabled = enabled.trim().parse().map_err(|_| format!("{:?} is not true or false", enabled.trim()))?;
+        toggles.insert(name.to_string(), enabled);
+    }
+    Ok(toggles)
+}
+
+fn unknown(name: &str) -> String {
+    format!("unknown route group {:?} (expected one of {})", name, GROUPS.join(", "))
+}
+
+/// The state of every group at startup for `config`
+pub fn defaults(config: &AppConfig) -> BTreeMap<&'static str, bool> {
+    GROUPS
+        .iter()
+        .map(|group| {
+            #[cfg(feature = "debug-endpoints")]
+            let default = !matches!(*group, DEBUG | ECHO | WS) || config.debug_endpoints;
+            #[cfg(not(feature = "debug-endpoints"))]
+            let default = true;
+            (*group, config.feature_toggles.get(*group).copied().unwrap_or(default))
+        })
+        .collect()
+}
+
+/// Route group toggles, shared by the state's clones
+#[derive(Debug, Clone, Default)]
+pub struct FeatureToggles {
+    groups: Arc<RwLock<BTreeMap<&'static str, bool>>>,
+}
+
+impl FeatureToggles {
+    /// Toggles starting from [`defaults`] for `config`
+    pub fn new(config: &AppConfig) -> Self {
+        Self { groups: Arc::new(RwLock::new(defaults(config))) }
+    }
+
+    /// Whether `group` is on; groups without a toggle are off
+    pub fn is_enabled(&self, group: &str) -> bool {
+        self.groups.read().unwrap_or_else(|e| e.into_inner()).get(group).copied().unwrap_or(false)
+    }
+
+    /// Switch the named groups, all or none of them
+    pub fn set(&self, changes: &BTreeMap<String, bool>) -> Result<BTreeMap<&'static str, bool>, String> {
+        let changes = changes
+            .iter()
+            .map(|(name, enabled)| group(name).map(|group| (group, *enabled)).ok_or_else(|| unknown(name)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut groups = self.groups.write().unwrap_or_else(|e| e.into_inner());
+        for (group, enabled) in changes {
+            if groups.insert(group, enabled) != Some(enabled) {
+                warn!(group, "Route group switched {} through the admin API", if enabled { "on" } else { "off" });
+            }
+        }
+        Ok(groups.clone())
+    }
+
+    /// Every group and whether it is on
+    pub fn snapshot(&self) -> BTreeMap<&'static str, bool> {
+        self.groups.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// `router`'s routes, answering 404 while `group` is off
+pub fn guard<S>(router: Router<S>, toggles: &FeatureToggles, group: &'static str) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let toggles = toggles.clone();
+    router.route_layer(middleware::from_fn(move |request: Request, next: Next| {
+        let enabled = toggles.is_enabled(group);
+        async move {
+            if enabled {
+                next.run(request).await
+            } else {
+                StatusCode::NOT_FOUND.into_response()
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_defaults() {
+        let toggles = parse("events=false, status = true").unwrap();
+        assert_eq!(toggles, BTreeMap::from([("events".to_string(), false), ("status".to_string(), true)]));
+        assert!(parse("events").is_err());
+        assert!(parse("events=off").is_err());
+        assert!(parse("metrics=true").unwrap_err().contains("expected one of"));
+
+        let config = AppConfig { feature_toggles: toggles, ..AppConfig::default() };
+        let defaults = defaults(&config);
+        assert_eq!((defaults[EVENTS], defaults[STATUS]), (false, true));
+        #[cfg(feature = "debug-endpoints")]
+        assert!(!defaults[DEBUG] && !defaults[ECHO] && !defaults[WS]);
+    }
+}
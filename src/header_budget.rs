@@ -0,0 +1,267 @@
+/*!
+ * Response header size budget
+ *
+ * Cloudflare rejects responses whose headers are too large: about 32 KB in
+ * total and 16 KB for any one header. A long CSP, a Permissions-Policy
+ * listing every feature and `Reporting-Endpoints` can creep towards that,
+ * and `EXTRA_RESPONSE_HEADERS` adds more on every response:
+ *
+ * ```text
+ * EXTRA_RESPONSE_HEADERS='[{"name": "X-Robots-Tag", "value": "noindex"}, {"name": "X-Team", "value": "platform"}]'
+ * ```
+ *
+ * [`HeaderBudget::new`] adds up the configured headers (the security
+ * headers of the largest tenant profile, `Server` and the extra headers),
+ * and loading the configuration warns once that passes
+ * `RESPONSE_HEADER_WARN_BYTES` (default 24 KiB) or a single header passes
+ * [`PER_HEADER_LIMIT`]. `/admin/security-score` shows the numbers.
+ *
+ * At runtime the security header middleware measures each response's
+ * headers. Over `RESPONSE_HEADER_MAX_BYTES` (default 32 KiB) it logs an
+ * error naming the largest headers and drops extra headers, last
+ * configured first, until the response fits. Security headers and headers
+ * set by handlers are never dropped.
+ */
+use crate::config::{AppConfig, SecurityConfig};
+use crate::headers::{self as names, SECURITY_HEADERS};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// Largest single header Cloudflare passes on
+pub const PER_HEADER_LIMIT: usize = 16 * 1024;
+
+const DEFAULT_WARN_BYTES: usize = 24 * 1024;
+
+const DEFAULT_MAX_BYTES: usize = 32 * 1024;
+
+/// Bytes a header takes on the wire: name, `": "`, value and CRLF
+pub fn header_size(name: &HeaderName, value: &HeaderValue) -> usize {
+    name.as_str().len() + value.len() + 4
+}
+
+/// Bytes taken by every header in `headers`
+pub fn total_size(headers: &HeaderMap) -> usize {
+    headers.iter().map(|(name, value)| header_size(name, value)).sum()
+}
+
+/// Headers added to every response, and the size limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderBudgetConfig {
+    /// Sent on every response unless the handler set them; dropped last
+    /// first when a response is over `max_bytes`
+    pub extra_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Configured header bytes at which loading the config warns
+    pub warn_bytes: usize,
+
+    /// Response header bytes beyond which extra headers are dropped
+    pub max_bytes: usize,
+}
+
+impl Default for HeaderBudgetConfig {
+    fn default() -> Self {
+        Self { extra_headers: Vec::new(), warn_bytes: DEFAULT_WARN_BYTES, max_bytes: DEFAULT_MAX_BYTES }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExtraHeader {
+    name: String,
+    value: String,
+}
+
+impl HeaderBudgetConfig {
+    /// Load from `EXTRA_RESPONSE_HEADERS`, `RESPONSE_HEADER_WARN_BYTES` and
+    /// `RESPONSE_HEADER_MAX_BYTES`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        let invalid = |msg: String| crate::ServerError::ConfigError(msg);
+
+        if let Ok(value) = std::env::var("EXTRA_RESPONSE_HEADERS") {
+            let extras: Vec<ExtraHeader> = serde_json::from_str(&value)
+                .map_err(|e| invalid(format!("Invalid EXTRA_RESPONSE_HEADERS: {}", e)))?;
+            config.extra_headers = extras
+                .into_iter()
+                .map(|extra| {
+                    let name = HeaderName::try_from(extra.name.as_str())
+                        .map_err(|_| invalid(format!("Invalid extra response header name {:?}", extra.name)))?;
+                    let value = HeaderValue::from_str(&extra.value)
+                        .map_err(|_| invalid(format!("Invalid value for extra response header {}", name)))?;
+                    Ok((name, value))
+                })
+                .collect::<crate::Result<_>>()?;
+        }
+        for (var, field) in [("RESPONSE_HEADER_WARN_BYTES", &mut config.warn_bytes), ("RESPONSE_HEADER_MAX_BYTES", &mut config.max_bytes)] {
+            if let Ok(value) = std::env::var(var) {
+                *field = value
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|bytes| *bytes > 0)
+                    .ok_or_else(|| invalid(format!("Invalid {}: {:?} is not a positive number", var, value)))?;
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reject extra headers that would replace a security header, repeat
+    /// one another, and a warning threshold above the cap
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(crate::ServerError::ConfigError(msg));
+        for (i, (name, _)) in self.extra_headers.iter().enumerate() {
+            if SECURITY_HEADERS.contains(name) || [names::REPORTING_ENDPOINTS, header::SERVER].contains(name) {
+                return invalid(format!("{} is configured with the security settings, not EXTRA_RESPONSE_HEADERS", name));
+            }
+            if self.extra_headers[..i].iter().any(|(other, _)| other == name) {
+                return invalid(format!("Duplicate extra response header {}", name));
+            }
+        }
+        if self.warn_bytes > self.max_bytes {
+            return invalid("RESPONSE_HEADER_WARN_BYTES must not exceed RESPONSE_HEADER_MAX_BYTES".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A header and the bytes it takes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeaderSize {
+    #[serde(serialize_with = "crate::headers::serialize_name")]
+    pub header: HeaderName,
+    pub bytes: usize,
+}
+
+/// Worst-case header bytes of a configuration against the limits
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeaderBudget {
+    /// Security headers and `Server`, for the largest profile
+    pub security_bytes: usize,
+    pub extra_bytes: usize,
+    pub total_bytes: usize,
+    pub warn_bytes: usize,
+    pub max_bytes: usize,
+
+    /// Largest configured header
+    pub largest: Option<HeaderSize>,
+}
+
+impl HeaderBudget {
+    /// Budget for the headers `config` sends
+    pub fn new(config: &AppConfig) -> Self {
+        let profiles = std::iter::once(&config.security).chain(config.tenants.iter().map(|tenant| &tenant.security));
+        let (security_bytes, mut largest) = profiles
+            .map(security_sizes)
+            .max_by_key(|(bytes, _)| *bytes)
+            .unwrap_or_default();
+        let budget = &config.header_budget;
+        let extra: Vec<HeaderSize> = budget
+            .extra_headers
+            .iter()
+            .map(|(name, value)| HeaderSize { header: name.clone(), bytes: header_size(name, value) })
+            .collect();
+        let extra_bytes = extra.iter().map(|size| size.bytes).sum();
+        largest = largest.into_iter().chain(extra).max_by_key(|size| size.bytes);
+
+        Self {
+            security_bytes,
+            extra_bytes,
+            total_bytes: security_bytes + extra_bytes,
+            warn_bytes: budget.warn_bytes,
+            max_bytes: budget.max_bytes,
+            largest,
+        }
+    }
+
+    /// Why the configured headers risk Cloudflare's limits, if they do
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.total_bytes >= self.warn_bytes {
+            warnings.push(format!(
+                "Configured response headers take {} bytes, over RESPONSE_HEADER_WARN_BYTES ({}); Cloudflare rejects responses with about 32 KB of headers",
+                self.total_bytes, self.warn_bytes
+            ));
+        }
+        if let Some(largest) = self.largest.as_ref().filter(|largest| largest.bytes > PER_HEADER_LIMIT) {
+            warnings.push(format!(
+                "The {} header takes {} bytes, over Cloudflare's {} byte limit for one header",
+                largest.header, largest.bytes, PER_HEADER_LIMIT
+            ));
+        }
+        warnings
+    }
+}
+
+/// Bytes taken by the security headers and `Server` of one profile, and
+/// the largest of them
+fn security_sizes(security: &SecurityConfig) -> (usize, Option<HeaderSize>) {
+    let mut sizes: Vec<HeaderSize> = security
+        .to_headers()
+        .iter()
+        .map(|(name, value)| HeaderSize { header: name.clone(), bytes: header_size(name, value) })
+        .collect();
+    if let Ok(value) = HeaderValue::from_str(&security.server_header) {
+        sizes.push(HeaderSize { header: header::SERVER, bytes: header_size(&header::SERVER, &value) });
+    }
+    (sizes.iter().map(|size| size.bytes).sum(), sizes.into_iter().max_by_key(|size| size.bytes))
+}
+
+/// Add the extra headers to `headers`, then drop them again, last first,
+/// while the total is over the cap
+pub fn apply(headers: &mut HeaderMap, config: &HeaderBudgetConfig) {
+    let mut added = Vec::new();
+    for (name, value) in &config.extra_headers {
+        if !headers.contains_key(name) {
+            headers.insert(name.clone(), value.clone());
+            added.push(name);
+        }
+    }
+
+    let total = total_size(headers);
+    if total <= config.max_bytes {
+        return;
+    }
+    let mut largest: Vec<(&HeaderName, usize)> =
+        headers.iter().map(|(name, value)| (name, header_size(name, value))).collect();
+    largest.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+    let largest: Vec<String> = largest.iter().take(3).map(|(name, bytes)| format!("{} ({} bytes)", name, bytes)).collect();
+
+    let mut dropped = Vec::new();
+    let mut total = total;
+    while total > config.max_bytes {
+        let Some(name) = added.pop() else {
+            break;
+        };
+        if let Some(value) = headers.remove(name) {
+            total -= header_size(name, &value);
+            dropped.push(name.as_str());
+        }
+    }
+    error!(
+        largest = %largest.join(", "),
+        dropped = %dropped.join(", "),
+        "Response headers exceed RESPONSE_HEADER_MAX_BYTES ({} bytes); now {} bytes",
+        config.max_bytes,
+        total
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extra_headers_cannot_replace_security_headers() {
+        let config = |name: HeaderName| HeaderBudgetConfig {
+            extra_headers: vec![(name, HeaderValue::from_static("x"))],
+            ..HeaderBudgetConfig::default()
+        };
+        assert!(config(HeaderName::from_static("x-robots-tag")).validate().is_ok());
+        assert!(config(names::CONTENT_SECURITY_POLICY).validate().is_err());
+        assert!(config(header::SERVER).validate().is_err());
+        assert!(HeaderBudgetConfig { warn_bytes: 40_000, ..HeaderBudgetConfig::default() }.validate().is_err());
+    }
+}
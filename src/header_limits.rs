@@ -0,0 +1,193 @@
+/*!
+ * Request header count and size limits
+ *
+ * hyper already refuses a request head that does not fit its read buffer,
+ * but `axum::serve` does not expose that size, and a request well inside it
+ * can still carry hundreds of headers or one enormous cookie. These limits
+ * are checked before routing and answered with
+ * `431 Request Header Fields Too Large`, naming the limit that tripped:
+ *
+ * - `MAX_REQUEST_HEADERS`: number of header fields (default 100)
+ * - `MAX_REQUEST_HEADER_BYTES`: names plus values of all fields (default 32 KiB)
+ * - `MAX_COOKIE_BYTES`: when set, `Cookie` fields are measured against this
+ *   limit instead of the total, so a giant cookie can be rejected while an
+ *   otherwise large set of headers passes
+ */
+use crate::config::AppConfig;
+use crate::denial::{Denial, DenialReason};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Limits on the request header section
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HeaderLimits {
+    /// Most header fields a request may carry
+    pub max_count: usize,
+
+    /// Most bytes of names and values across all fields
+    pub max_bytes: usize,
+
+    /// Separate budget for `Cookie` fields, excluded from `max_bytes` when set
+    pub max_cookie_bytes: Option<usize>,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_count: 100,
+            max_bytes: 32 * 1024,
+            max_cookie_bytes: None,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Load from `MAX_REQUEST_HEADERS`, `MAX_REQUEST_HEADER_BYTES` and
+    /// `MAX_COOKIE_BYTES`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut limits = Self::default();
+        if let Some(count) = positive_env("MAX_REQUEST_HEADERS")? {
+            limits.max_count = count;
+        }
+        if let Some(bytes) = positive_env("MAX_REQUEST_HEADER_BYTES")? {
+            limits.max_bytes = bytes;
+        }
+        limits.max_cookie_bytes = positive_env("MAX_COOKIE_BYTES")?;
+        Ok(limits)
+    }
+
+    /// The limit `headers` exceed, if any
+    pub fn check(&self, headers: &HeaderMap) -> Option<Exceeded> {
+        if headers.len() > self.max_count {
+            return Some(Exceeded::Count(self.max_count));
+        }
+
+        let mut bytes = 0;
+        let mut cookie_bytes = 0;
+        for (name, value) in headers {
+            let size = name.as_str().len() + value.len();
+            if name == header::COOKIE && self.max_cookie_bytes.is_some() {
+                cookie_bytes += size;
+            } else {
+                bytes += size;
+            }
+        }
+
+        if let Some(max) = self.max_cookie_bytes.filter(|max| cookie_bytes > *max) {
+            return Some(Exceeded::CookieBytes(max));
+        }
+        (bytes > self.max_bytes).then_some(Exceeded::Bytes(self.max_bytes))
+    }
+}
+
+fn positive_env(var: &str) -> crate::Result<Option<usize>> {
+    let Ok(value) = std::env::var(var) else {
+        return Ok(None);
+    };
+    value.parse()
+        .ok()
+        .filter(|n| *n > 0)
+        .map(Some)
+        .ok_or_else(|| crate::ServerError::ConfigError(format!(
+            "Invalid {}: {:?} is not a positive number",
+            var, value
+        )))
+}
+
+/// Which limit a request went over, with its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exceeded {
+    Count(usize),
+    Bytes(usize),
+    CookieBytes(usize),
+}
+
+impl Exceeded {
+    /// Reason of the 431 denial
+    pub fn reason(&self) -> DenialReason {
+        match self {
+            Exceeded::Count(_) => DenialReason::TooManyHeaders,
+            Exceeded::Bytes(_) => DenialReason::HeadersTooLarge,
+            Exceeded::CookieBytes(_) => DenialReason::CookieTooLarge,
+        }
+    }
+
+    /// Error code in the 431 body
+    pub fn code(&self) -> &'static str {
+        self.reason().code()
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Exceeded::Count(max) => format!("At most {} request headers are allowed", max),
+            Exceeded::Bytes(max) => format!("Request headers may total at most {} bytes", max),
+            Exceeded::CookieBytes(max) => format!("Cookies may total at most {} bytes", max),
+        }
+    }
+}
+
+/// Middleware answering 431 when the request headers exceed the limits
+pub async fn enforce(State(config): State<Arc<AppConfig>>, request: Request, next: Next) -> Response {
+    let Some(exceeded) = config.header_limits.check(request.headers()) else {
+        return next.run(request).await;
+    };
+
+    warn!("Rejected request to {} with oversized headers ({})", request.uri().path(), exceeded.code());
+    Denial::new(exceeded.reason(), exceeded.message()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(count: usize, value_len: usize) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for i in 0..count {
+            headers.insert(
+                header::HeaderName::from_bytes(format!("x-h{:02}", i).as_bytes()).unwrap(),
+                HeaderValue::from_str(&"v".repeat(value_len)).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn test_count_limit() {
+        let limits = HeaderLimits { max_count: 3, ..HeaderLimits::default() };
+
+        assert_eq!(limits.check(&headers(3, 1)), None);
+        assert_eq!(limits.check(&headers(4, 1)), Some(Exceeded::Count(3)));
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        // Each field is a 5-byte name plus a 5-byte value
+        let limits = HeaderLimits { max_bytes: 20, ..HeaderLimits::default() };
+
+        assert_eq!(limits.check(&headers(2, 5)), None);
+        assert_eq!(limits.check(&headers(2, 6)), Some(Exceeded::Bytes(20)));
+    }
+
+    #[test]
+    fn test_cookies_have_their_own_budget() {
+        let mut request = headers(1, 10);
+        request.insert(header::COOKIE, HeaderValue::from_str(&"c".repeat(100)).unwrap());
+
+        let shared = HeaderLimits { max_bytes: 50, ..HeaderLimits::default() };
+        assert_eq!(shared.check(&request), Some(Exceeded::Bytes(50)));
+
+        let separate = HeaderLimits { max_bytes: 50, max_cookie_bytes: Some(106), ..HeaderLimits::default() };
+        assert_eq!(separate.check(&request), None);
+
+        let tight = HeaderLimits { max_cookie_bytes: Some(105), ..separate };
+        assert_eq!(tight.check(&request), Some(Exceeded::CookieBytes(105)));
+    }
+}
@@ -0,0 +1,138 @@
+/*!
+ * Sampled logging of full request and response headers
+ *
+ * Logging every header of every request is too noisy to leave on, but a
+ * small sample catches drift (a proxy that starts stripping a header, a
+ * client sending something new). With `HEADER_SAMPLE_RATE` above 0 (e.g.
+ * `0.001` for 1 in 1000), each request is sampled with that probability.
+ * A sampled request gets one `debug` event, `Sampled request headers`,
+ * with its request ID and both header sets as redacted JSON (see
+ * [`crate::redact`]). Its response carries `X-Debug-Sampled: 1`, so a user
+ * reporting a problem can tell whether their request was captured.
+ *
+ * The decision comes from a [`Sampler`] in `AppState::header_sampler`.
+ * The default [`RandomSampler`] hashes an atomic counter with a seed read
+ * once at startup, so sampling costs no syscall; tests substitute
+ * `testing::FixedSampler`.
+ */
+use crate::request_id::RequestId;
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::debug;
+
+/// Marker on the response of a sampled request
+pub const SAMPLED_HEADER: HeaderName = HeaderName::from_static("x-debug-sampled");
+
+/// Decides whether a request is sampled
+pub trait Sampler: Send + Sync + fmt::Debug {
+    /// Whether to sample the next request, given `rate` in `(0, 1]`
+    fn sample(&self, rate: f64) -> bool;
+}
+
+/// SplitMix64 over an atomic counter: uniform, cheap and lock-free
+#[derive(Debug)]
+pub struct RandomSampler {
+    seed: u64,
+    counter: AtomicU64,
+}
+
+impl RandomSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, counter: AtomicU64::new(0) }
+    }
+}
+
+impl Default for RandomSampler {
+    /// Seeded from the operating system's random number generator
+    fn default() -> Self {
+        let mut seed = [0u8; 8];
+        if getrandom::fill(&mut seed).is_err() {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+            seed = nanos.to_le_bytes();
+        }
+        Self::new(u64::from_le_bytes(seed))
+    }
+}
+
+impl Sampler for RandomSampler {
+    fn sample(&self, rate: f64) -> bool {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut z = self.seed.wrapping_add(n.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        // The top 53 bits as a uniform value in [0, 1)
+        ((z >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+}
+
+/// Load `HEADER_SAMPLE_RATE`, a probability from 0 (off, the default) to 1
+pub fn from_env() -> crate::Result<f64> {
+    let Ok(value) = std::env::var("HEADER_SAMPLE_RATE") else {
+        return Ok(0.0);
+    };
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| (0.0..=1.0).contains(rate))
+        .ok_or_else(|| {
+            crate::ServerError::ConfigError(format!(
+                "Invalid HEADER_SAMPLE_RATE: {:?} is not a number from 0 to 1",
+                value
+            ))
+        })
+}
+
+/// Middleware logging the headers of sampled requests
+pub async fn sample_headers(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let rate = state.config().header_sample_rate;
+    if rate <= 0.0 || !state.header_sampler.sample(rate) {
+        return next.run(request).await;
+    }
+
+    let method = request.method().clone();
+    let uri = request.uri().to_string();
+    let request_id = request.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
+    let request_headers = crate::redact::headers_to_json(request.headers());
+
+    let mut response = next.run(request).await;
+
+    debug!(
+        request_id,
+        method = %method,
+        uri,
+        status = response.status().as_u16(),
+        request_headers = %request_headers,
+        response_headers = %crate::redact::headers_to_json(response.headers()),
+        "Sampled request headers"
+    );
+    response.headers_mut().insert(SAMPLED_HEADER, HeaderValue::from_static("1"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_sampler_matches_the_rate() {
+        let sampler = RandomSampler::new(42);
+        for (rate, expected) in [(0.001, 1_000), (0.25, 250_000)] {
+            let sampled = (0..1_000_000).filter(|_| sampler.sample(rate)).count();
+            let tolerance = expected / 5;
+            assert!(sampled.abs_diff(expected) < tolerance, "{} sampled at {}", sampled, rate);
+        }
+        assert!((0..1000).all(|_| sampler.sample(1.0)));
+        assert!(!(0..1000).any(|_| sampler.sample(0.0)));
+    }
+}
@@ -0,0 +1,178 @@
+/*!
+ * Security header names
+ *
+ * Every security header the crate sends or grades, as a [`HeaderName`]
+ * constant, so a misspelt name fails to compile instead of silently never
+ * matching. [`SecurityConfig::to_headers`](crate::config::SecurityConfig::to_headers)
+ * pairs them with their values, and the security header middleware, the
+ * startup summary and [`crate::security_score`] name headers only through
+ * these. Names are lowercase, as HTTP/2 sends them. Headers belonging to a
+ * single feature are defined next to it instead (`cache::CDN_CACHE_CONTROL`,
+ * `csrf::HEADER_NAME`, ...).
+ *
+ * [`HeaderModes`] decides, per header, whether the middleware replaces a
+ * value the handler set (`force`, the default for security headers) or
+ * only fills in a missing one (`default`, always the case for `Server`
+ * unless configured otherwise):
+ *
+ * ```text
+ * SECURITY_HEADER_MODE=force
+ * SECURITY_HEADER_MODES=x-frame-options=default,content-security-policy=default
+ * ```
+ */
+use axum::http::{header, HeaderName};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+pub const X_CONTENT_TYPE_OPTIONS: HeaderName = HeaderName::from_static("x-content-type-options");
+pub const X_FRAME_OPTIONS: HeaderName = HeaderName::from_static("x-frame-options");
+pub const X_XSS_PROTECTION: HeaderName = HeaderName::from_static("x-xss-protection");
+pub const STRICT_TRANSPORT_SECURITY: HeaderName = HeaderName::from_static("strict-transport-security");
+pub const CONTENT_SECURITY_POLICY: HeaderName = HeaderName::from_static("content-security-policy");
+pub const REFERRER_POLICY: HeaderName = HeaderName::from_static("referrer-policy");
+pub const PERMISSIONS_POLICY: HeaderName = HeaderName::from_static("permissions-policy");
+
+/// Sent only with `SECURITY_REPORT_TO`
+pub const REPORTING_ENDPOINTS: HeaderName = HeaderName::from_static("reporting-endpoints");
+
+/// Never sent; the security score notes its absence
+pub const CROSS_ORIGIN_OPENER_POLICY: HeaderName = HeaderName::from_static("cross-origin-opener-policy");
+
+/// Headers every `SecurityConfig` sends unless configured empty, in the
+/// order they are sent and reported
+pub const SECURITY_HEADERS: [HeaderName; 7] = [
+    X_CONTENT_TYPE_OPTIONS,
+    X_FRAME_OPTIONS,
+    X_XSS_PROTECTION,
+    STRICT_TRANSPORT_SECURITY,
+    CONTENT_SECURITY_POLICY,
+    REFERRER_POLICY,
+    PERMISSIONS_POLICY,
+];
+
+/// Whether the security header middleware replaces a value set by the
+/// handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderMode {
+    /// Send the configured value, replacing the handler's
+    Force,
+
+    /// Send the configured value only when the handler set none
+    Default,
+}
+
+impl std::str::FromStr for HeaderMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "force" => Ok(Self::Force),
+            "default" => Ok(Self::Default),
+            _ => Err(format!("{:?} is not force or default", value)),
+        }
+    }
+}
+
+/// How each header the middleware sends treats handler-set values
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeaderModes {
+    /// Mode of every security header without an exception
+    pub global: HeaderMode,
+
+    /// Modes of single headers, by lowercase name
+    pub exceptions: BTreeMap<String, HeaderMode>,
+}
+
+impl Default for HeaderModes {
+    fn default() -> Self {
+        Self { global: HeaderMode::Force, exceptions: BTreeMap::new() }
+    }
+}
+
+impl HeaderModes {
+    /// Load from `SECURITY_HEADER_MODE` and `SECURITY_HEADER_MODES`
+    /// (`name=mode` pairs separated by commas)
+    pub fn from_env() -> crate::Result<Self> {
+        let mut modes = Self::default();
+        let invalid = |var: &str, e: String| crate::ServerError::ConfigError(format!("Invalid {}: {}", var, e));
+
+        if let Ok(value) = std::env::var("SECURITY_HEADER_MODE") {
+            modes.global = value.parse().map_err(|e| invalid("SECURITY_HEADER_MODE", e))?;
+        }
+        if let Ok(value) = std::env::var("SECURITY_HEADER_MODES") {
+            for pair in value.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                let (name, mode) = pair
+                    .split_once('=')
+                    .ok_or_else(|| invalid("SECURITY_HEADER_MODES", format!("{:?} is not name=mode", pair)))?;
+                let mode = mode.parse().map_err(|e| invalid("SECURITY_HEADER_MODES", e))?;
+                modes.exceptions.insert(name.trim().to_ascii_lowercase(), mode);
+            }
+        }
+        modes.validate()?;
+        Ok(modes)
+    }
+
+    /// Reject exceptions for headers the middleware does not send
+    pub fn validate(&self) -> crate::Result<()> {
+        for name in self.exceptions.keys() {
+            let sent = SECURITY_HEADERS.iter().chain(&[REPORTING_ENDPOINTS, header::SERVER]).any(|sent| sent.as_str() == name);
+            if !sent {
+                return Err(crate::ServerError::ConfigError(format!(
+                    "Invalid SECURITY_HEADER_MODES: {:?} is not a header the security middleware sends",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Mode for `name`: its exception, else `default` for `Server` and the
+    /// global mode for the rest
+    pub fn mode_for(&self, name: &HeaderName) -> HeaderMode {
+        match self.exceptions.get(name.as_str()) {
+            Some(mode) => *mode,
+            None if name == header::SERVER => HeaderMode::Default,
+            None => self.global,
+        }
+    }
+}
+
+/// Serialize a header name as its lowercase string, for
+/// `#[serde(serialize_with)]`
+pub fn serialize_name<S: Serializer>(name: &HeaderName, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Security header names spelt out anywhere else in `src` would
+    /// bypass these constants
+    #[test]
+    fn test_names_are_only_spelt_out_here() {
+        let names: Vec<String> = SECURITY_HEADERS
+            .iter()
+            .chain([&REPORTING_ENDPOINTS, &CROSS_ORIGIN_OPENER_POLICY])
+            .map(|name| format!("\"{}\"", name))
+            .collect();
+        let mut dirs = vec![std::path::PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/src"))];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                if path.ends_with("headers.rs") || path.extension().is_none_or(|ext| ext != "rs") {
+                    continue;
+                }
+                let source = std::fs::read_to_string(&path).unwrap().to_ascii_lowercase();
+                for name in &names {
+                    assert!(!source.contains(name.as_str()), "{} spells out {}", path.display(), name);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+/*!
+ * Readiness checks
+ *
+ * `/health` only says the process is up. `/readyz` asks every registered
+ * `HealthCheck` whether the service can actually take traffic and answers
+ * 503 if any of them says no, so orchestrators and load balancers stop
+ * routing to an origin that Cloudflare cannot reach. It also fails while
+ * the service is draining (`AppState::set_ready(false)`). Maintenance mode
+ * is reported alongside the checks but does not make the service unready.
+ */
+use crate::drain::DrainStatus;
+#[cfg(feature = "fault-injection")]
+use crate::faults::FaultStatus;
+use crate::maintenance::MaintenanceStatus;
+use crate::state::AppState;
+use crate::status::StatusSnapshot;
+use axum::{
+    async_trait,
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Json},
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Instant;
+use utoipa::ToSchema;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct CheckResult {
+    pub name: String,
+    pub healthy: bool,
+
+    /// Passing, but close to trouble; reported without failing `/readyz`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub warning: bool,
+
+    /// Human-readable detail, e.g. a connection count or an error
+    pub detail: Option<String>,
+
+    /// How long the check took, filled in by [`HealthRegistry::run`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<f64>,
+}
+
+impl CheckResult {
+    /// Passing result with `detail`
+    pub fn healthy(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            healthy: true,
+            warning: false,
+            detail: Some(detail.into()),
+            latency_ms: None,
+        }
+    }
+
+    /// Passing result flagged as a warning, explained by `detail`
+    pub fn warning(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            warning: true,
+            ..Self::healthy(name, detail)
+        }
+    }
+
+    /// Failing result explained by `detail`
+    pub fn unhealthy(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            healthy: false,
+            warning: false,
+            detail: Some(detail.into()),
+            latency_ms: None,
+        }
+    }
+}
+
+/// A dependency that must be available for the service to be ready
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    /// Name reported in `/readyz` output
+    fn name(&self) -> &str;
+
+    /// Probe the dependency; failures are reported, not returned as errors
+    async fn check(&self) -> CheckResult;
+}
+
+/// Set of checks consulted by `/readyz`
+#[derive(Clone, Default)]
+pub struct HealthRegistry {
+    checks: Vec<Arc<dyn HealthCheck>>,
+}
+
+impl std::fmt::Debug for HealthRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.checks.iter().map(|check| check.name()))
+            .finish()
+    }
+}
+
+impl HealthRegistry {
+    /// Add a check consulted on every `/readyz` request
+    pub fn register(&mut self, check: impl HealthCheck + 'static) {
+        self.checks.push(Arc::new(check));
+    }
+
+    /// Run every check concurrently, timing each
+    pub async fn run(&self) -> Vec<CheckResult> {
+        let handles: Vec<_> = self
+            .checks
+            .iter()
+            .map(|check| {
+                let check = check.clone();
+                tokio::spawn(async move {
+                    let started = Instant::now();
+                    let mut result = check.check().await;
+                    result.latency_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+                    result
+                })
+            })
+            .collect();
+
+        let mut results = Vec::with_capacity(handles.len());
+        for (handle, check) in handles.into_iter().zip(&self.checks) {
+            results.push(match handle.await {
+                Ok(result) => result,
+                Err(e) => CheckResult::unhealthy(check.name(), format!("check panicked: {}", e)),
+            });
+        }
+        results
+    }
+}
+
+/// Whether `/readyz` considers the service ready
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum ReadinessStatus {
+    Ready,
+    NotReady,
+}
+
+/// Body of `/readyz`
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = Readiness)]
+struct ReadinessBody {
+    status: ReadinessStatus,
+    checks: Vec<CheckResult>,
+    maintenance: MaintenanceStatus,
+    drain: DrainStatus,
+
+    /// Reported so an injected fault is not forgotten; it never fails readiness
+    #[cfg(feature = "fault-injection")]
+    faults: FaultStatus,
+}
+
+/// Readiness check
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = OK, description = "Ready for traffic", body = ReadinessBody),
+        (status = SERVICE_UNAVAILABLE, description = "Draining or a check failed", body = ReadinessBody),
+    ),
+)]
+pub(crate) async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let snapshot = StatusSnapshot::collect(&state).await;
+    let status = if snapshot.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+
+    let body = ReadinessBody {
+        status: if snapshot.ready { ReadinessStatus::Ready } else { ReadinessStatus::NotReady },
+        checks: snapshot.checks,
+        maintenance: snapshot.maintenance,
+        drain: snapshot.drain,
+        #[cfg(feature = "fault-injection")]
+        faults: state.faults.status(),
+    };
+    (status, [(header::CACHE_CONTROL, "no-store")], Json(body))
+}
+
+/// `/readyz`, backed by the state's registry and readiness flag
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/readyz", get(readyz))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestClient;
+
+    struct Fixed(bool);
+
+    #[async_trait]
+    impl HealthCheck for Fixed {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        async fn check(&self) -> CheckResult {
+            CheckResult {
+                name: "fixed".to_string(),
+                healthy: self.0,
+                warning: false,
+                detail: None,
+                latency_ms: None,
+            }
+        }
+    }
+
+    async fn readyz_status(state: AppState) -> (StatusCode, serde_json::Value) {
+        let response = TestClient::from_router(routes().with_state(state)).get("/readyz").await;
+        (response.status(), response.json())
+    }
+
+    #[tokio::test]
+    async fn test_empty_registry_is_ready() {
+        let (status, json) = readyz_status(AppState::default()).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["status"], "ready");
+        assert_eq!(json["maintenance"]["enabled"], false);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_is_reported_but_ready() {
+        let state = AppState::default();
+        state.maintenance.set(true, Some("database upgrade".to_string()));
+
+        let (status, json) = readyz_status(state).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["maintenance"]["enabled"], true);
+        assert_eq!(json["maintenance"]["message"], "database upgrade");
+    }
+
+    #[tokio::test]
+    async fn test_draining_is_not_ready() {
+        let state = AppState::default();
+        state.set_ready(false);
+
+        let (status, json) = readyz_status(state).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["status"], "not_ready");
+    }
+
+    #[tokio::test]
+    async fn test_failing_check_is_not_ready() {
+        let mut state = AppState::default();
+        state.health.register(Fixed(true));
+        state.health.register(Fixed(false));
+
+        let (status, json) = readyz_status(state).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["status"], "not_ready");
+        // Alongside the built-in `tasks` and `backpressure` checks
+        assert_eq!(json["checks"].as_array().map(Vec::len), Some(4));
+    }
+}
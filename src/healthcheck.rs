@@ -0,0 +1,134 @@
+/*!
+ * `healthcheck` subcommand for container health probes
+ *
+ * The distroless image has no curl or wget, so the binary probes itself:
+ * `cloudflare-tunnel-example healthcheck` sends `GET /readyz` to the main
+ * listener and exits `0` on a `2xx` answer and `1` otherwise, with a
+ * one-line reason on stderr. It is meant for a Dockerfile `HEALTHCHECK`:
+ *
+ * ```text
+ * HEALTHCHECK CMD ["/app/cloudflare-tunnel-example", "healthcheck"]
+ * ```
+ *
+ * - `--listen <ADDR>`: the address the server was started with (default
+ *   `0.0.0.0:8080`); an unspecified address is probed on loopback
+ * - `--url <URL>`: probe this `http://` URL instead
+ * - `--admin`: probe `GET /admin/readyz` on the admin listener, with the
+ *   address and token from `ADMIN_ADDR` and `ADMIN_TOKEN` (or
+ *   `ADMIN_TOKEN_FILE`)
+ *
+ * The probe gives up after [`TIMEOUT`], connecting included. It reads no
+ * other configuration and sets up no logging, so it stays cheap when run
+ * every few seconds.
+ */
+use crate::admin::AdminConfig;
+use crate::http_client::{HttpClient, HttpClientConfig};
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+use bytes::Bytes;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// How long a probe may take, connecting included
+pub const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A readiness request to send
+#[derive(Clone)]
+pub struct Probe {
+    pub url: String,
+
+    /// Bearer token sent with the request
+    pub token: Option<String>,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Debug for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Probe")
+            .field("url", &self.url)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Probe {
+    /// `GET url` without credentials
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { url: url.into(), token: None, timeout: TIMEOUT }
+    }
+
+    /// `/readyz` on the main listener bound to `listen`
+    pub fn listener(listen: SocketAddr) -> Self {
+        Self::new(format!("http://{}/readyz", reachable(listen)))
+    }
+
+    /// `/admin/readyz` on the admin listener, with its token
+    pub fn admin(config: &AdminConfig) -> Self {
+        Self {
+            token: Some(config.token.clone()),
+            ..Self::new(format!("http://{}/admin/readyz", reachable(config.addr)))
+        }
+    }
+
+    /// Probe of the admin listener configured by `ADMIN_ADDR` and
+    /// `ADMIN_TOKEN`
+    pub fn admin_from_env() -> crate::Result<Self> {
+        let config = AdminConfig::from_env()?.ok_or_else(|| {
+            crate::ServerError::ConfigError("--admin requires ADMIN_ADDR to be set".to_string())
+        })?;
+        Ok(Self::admin(&config))
+    }
+
+    /// Send the request; `Err` says why the service is not healthy
+    pub async fn run(&self) -> Result<(), String> {
+        let config = HttpClientConfig {
+            connect_timeout: self.timeout,
+            timeout: self.timeout,
+            pool_idle_timeout: Duration::ZERO,
+            proxy: None,
+        };
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &self.token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| "ADMIN_TOKEN is not a valid header value".to_string())?;
+            headers.insert(header::AUTHORIZATION, value);
+        }
+
+        let response = HttpClient::new(config)
+            .send(Method::GET, &self.url, headers, Bytes::new())
+            .await
+            .map_err(|e| format!("GET {} failed: {}", self.url, e))?;
+        if response.status.is_success() {
+            Ok(())
+        } else {
+            Err(format!("GET {} answered {}", self.url, response.status))
+        }
+    }
+}
+
+/// `addr` with an unspecified IP replaced by loopback, so a server bound
+/// to every interface is probed locally
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) if v4.ip().is_unspecified() => SocketAddr::from((Ipv4Addr::LOCALHOST, v4.port())),
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => SocketAddr::from((Ipv6Addr::LOCALHOST, v6.port())),
+        addr => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unspecified_addresses_are_probed_on_loopback() {
+        assert_eq!(Probe::listener("0.0.0.0:8080".parse().unwrap()).url, "http://127.0.0.1:8080/readyz");
+        assert_eq!(Probe::listener("[::]:8080".parse().unwrap()).url, "http://[::1]:8080/readyz");
+        assert_eq!(Probe::listener("10.0.0.5:3000".parse().unwrap()).url, "http://10.0.0.5:3000/readyz");
+
+        let admin = AdminConfig { addr: "0.0.0.0:9090".parse().unwrap(), token: "secret".to_string() };
+        let probe = Probe::admin(&admin);
+        assert_eq!(probe.url, "http://127.0.0.1:9090/admin/readyz");
+        assert!(!format!("{:?}", probe).contains("secret"));
+    }
+}
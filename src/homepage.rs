@@ -0,0 +1,133 @@
+/*!
+ * The `/` page
+ *
+ * Rendered per request from `templates/index.html` with TinyTemplate, whose
+ * default formatter HTML-escapes every interpolated value, so request
+ * headers such as `CF-Ray` can be shown verbatim. Cloudflare's other headers
+ * reach it parsed, as `cloudflare` ([`CloudflareMeta`]). The page has no
+ * inline scripts or styles and so renders under any of the CSP presets.
+ * Its text comes from the request's [`Locale`] catalog.
+ *
+ * The ETag is weak and hashes the page rendered without the uptime, so it
+ * changes only when the content does. A page showing the visitor's country
+ * is `private, no-store`. The other fields name the host, colo or request,
+ * not the visitor, so without a country the page stays cacheable.
+ */
+use crate::cloudflare::meta::CloudflareMeta;
+use crate::conditional;
+use crate::error::ApiError;
+use crate::i18n::{Locale, Strings};
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, Response},
+};
+use serde::Serialize;
+use tinytemplate::TinyTemplate;
+
+const TEMPLATE: &str = include_str!("../templates/index.html");
+
+/// Values available to the template
+#[derive(Debug, Serialize)]
+struct Context<'a> {
+    lang: &'static str,
+
+    /// Strings of the request's locale
+    t: &'static Strings,
+    service: &'static str,
+    version: &'static str,
+    uptime_seconds: u64,
+    host: Option<&'a str>,
+    cf_ray: Option<&'a str>,
+    cloudflare: &'a CloudflareMeta,
+    tunnel: Option<TunnelContext>,
+    debug_links: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct TunnelContext {
+    url: Option<String>,
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Debug routes linked from the page while their group is on
+#[cfg(feature = "debug-endpoints")]
+const DEBUG_LINKS: &[(&str, &str)] = &[
+    (crate::features::DEBUG, "/whoami"),
+    (crate::features::ECHO, "/echo"),
+    (crate::features::DEBUG, "/status/418"),
+    (crate::features::DEBUG, "/delay/1"),
+];
+
+fn render(
+    state: &AppState,
+    headers: &HeaderMap,
+    cloudflare: &CloudflareMeta,
+    locale: Locale,
+    uptime_seconds: u64,
+) -> Result<String, tinytemplate::error::Error> {
+
+    #[cfg(feature = "debug-endpoints")]
+    let debug_links = DEBUG_LINKS
+        .iter()
+        .filter(|(group, _)| state.features.is_enabled(group))
+        .map(|(_, path)| *path)
+        .collect();
+    #[cfg(not(feature = "debug-endpoints"))]
+    let debug_links = Vec::new();
+
+    let context = Context {
+        lang: locale.code(),
+        t: locale.strings(),
+        service: "cloudflare-tunnel-example",
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_seconds,
+        host: header_str(headers, "host"),
+        cf_ray: header_str(headers, "cf-ray"),
+        cloudflare,
+        tunnel: state.tunnel.as_ref().map(|tunnel| TunnelContext { url: tunnel.url() }),
+        debug_links,
+    };
+
+    let mut templates = TinyTemplate::new();
+    templates.add_template("index", TEMPLATE)?;
+    templates.render("index", &context)
+}
+
+/// Homepage
+///
+/// In the request's locale, with a weak ETag over the page minus its uptime.
+/// A page showing the visitor's country is sent `private, no-store` so it is
+/// never shared; otherwise the route's cache policy applies.
+#[utoipa::path(
+    get,
+    path = "/",
+    responses((status = OK, description = "HTML page describing the service and the request", body = String, content_type = "text/html")),
+)]
+pub async fn homepage(
+    State(state): State<AppState>,
+    locale: Locale,
+    cloudflare: CloudflareMeta,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let render = |uptime_seconds| {
+        render(&state, &headers, &cloudflare, locale, uptime_seconds).map_err(|e| {
+            tracing::error!("Failed to render homepage: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "render_failed", "Failed to render page")
+        })
+    };
+    let page = render(state.uptime().as_secs())?;
+    let etag = conditional::weak_etag(render(0)?.as_bytes());
+
+    let mut response = conditional::conditional_response(&etag, Html(page), &headers);
+    if cloudflare.country.is_some() {
+        response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("private, no-store"));
+    }
+    response.headers_mut().insert(header::CONTENT_LANGUAGE, locale.header_value());
+    response.headers_mut().append(header::VARY, HeaderValue::from_static("accept-language"));
+    Ok(response)
+}
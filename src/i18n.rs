@@ -0,0 +1,251 @@
+/*!
+ * Localized strings for the HTML pages
+ *
+ * The homepage and the HTML error pages are shown in English or German.
+ * Each locale is a catalog of `key = value` lines in `locales/<code>.ftl`
+ * (a small subset of Fluent: no variables, `#` comments), compiled in
+ * through [`CATALOGS`]; adding a language means adding its file there.
+ * A catalog lacking a key falls back to the English one.
+ *
+ * [`Locale`] is picked per request: a supported `?lang=` value wins,
+ * otherwise the best supported match for `Accept-Language` by q-value
+ * (`de-AT` matches `de`, `*` matches anything), otherwise English. Pages
+ * rendered in a locale say so with `Content-Language`.
+ */
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderValue},
+};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::OnceLock;
+
+/// Locale used when nothing else matches; its catalog has every key
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Bundled catalogs by language code, the default first
+const CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.ftl")),
+    ("de", include_str!("../locales/de.ftl")),
+];
+
+/// Strings of one locale by key
+pub type Strings = BTreeMap<&'static str, &'static str>;
+
+/// `key = value` lines of `source`, skipping blank lines and comments
+fn parse(source: &'static str) -> Result<Strings, String> {
+    let mut strings = Strings::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected key = value", number + 1))?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("line {}: invalid key {:?}", number + 1, key));
+        }
+        strings.insert(key, value.trim());
+    }
+    Ok(strings)
+}
+
+/// Every catalog, each completed with the default's strings
+fn catalogs() -> &'static [(&'static str, Strings)] {
+    static CATALOGS_PARSED: OnceLock<Vec<(&'static str, Strings)>> = OnceLock::new();
+    CATALOGS_PARSED.get_or_init(|| {
+        let parse = |code: &str, source| parse(source).unwrap_or_else(|e| panic!("Invalid catalog {}: {}", code, e));
+        let default = parse(DEFAULT_LOCALE, CATALOGS[0].1);
+        CATALOGS
+            .iter()
+            .map(|(code, source)| {
+                let mut strings = default.clone();
+                strings.extend(parse(code, source));
+                (*code, strings)
+            })
+            .collect()
+    })
+}
+
+/// A supported locale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale(&'static str);
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self(DEFAULT_LOCALE)
+    }
+}
+
+impl Locale {
+    /// Every supported locale, the default first
+    pub fn supported() -> impl Iterator<Item = Locale> {
+        CATALOGS.iter().map(|(code, _)| Locale(code))
+    }
+
+    /// Locale for a language tag such as `de` or `de-AT`, if supported
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let tag = tag.trim();
+        let language = tag.split(['-', '_']).next().unwrap_or_default();
+        Self::supported().find(|locale| locale.0.eq_ignore_ascii_case(language))
+    }
+
+    /// The supported locale `Accept-Language` ranks highest, if any. Ties
+    /// go to whichever is listed first in the header, then to the order of
+    /// [`CATALOGS`].
+    pub fn from_accept_language(headers: &HeaderMap) -> Option<Self> {
+        // (locale, or `None` for `*`; quality; position) in header order
+        let ranges: Vec<(Option<Locale>, f32, usize)> = headers
+            .get_all(header::ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .enumerate()
+            .filter_map(|(position, range)| {
+                let mut params = range.split(';');
+                let tag = params.next().unwrap_or_default().trim();
+                let quality = params
+                    .find_map(|param| param.trim().strip_prefix("q=").and_then(|q| q.parse().ok()))
+                    .unwrap_or(1.0);
+                match tag {
+                    "*" => Some((None, quality, position)),
+                    tag => Self::from_tag(tag).map(|locale| (Some(locale), quality, position)),
+                }
+            })
+            .collect();
+
+        Self::supported()
+            .filter_map(|locale| {
+                // The first range naming the locale, else the wildcard
+                ranges
+                    .iter()
+                    .find(|(range, _, _)| *range == Some(locale))
+                    .or_else(|| ranges.iter().find(|(range, _, _)| range.is_none()))
+                    .map(|(_, quality, position)| (locale, *quality, *position))
+            })
+            .filter(|(_, quality, _)| *quality > 0.0)
+            .fold(None, |best: Option<(Locale, f32, usize)>, candidate| match best {
+                Some(best) if best.1 > candidate.1 || (best.1 == candidate.1 && best.2 <= candidate.2) => Some(best),
+                _ => Some(candidate),
+            })
+            .map(|(locale, _, _)| locale)
+    }
+
+    /// Locale for a request: a supported `?lang=` value, else
+    /// `Accept-Language`, else the default
+    pub fn negotiate(headers: &HeaderMap, query: Option<&str>) -> Self {
+        lang_param(query)
+            .and_then(|lang| Self::from_tag(&lang))
+            .or_else(|| Self::from_accept_language(headers))
+            .unwrap_or_default()
+    }
+
+    /// Language code, as sent in `Content-Language`
+    pub fn code(self) -> &'static str {
+        self.0
+    }
+
+    /// `Content-Language` value
+    pub fn header_value(self) -> HeaderValue {
+        HeaderValue::from_static(self.0)
+    }
+
+    /// Every string in this locale, by key
+    pub fn strings(self) -> &'static Strings {
+        let catalogs = catalogs();
+        catalogs
+            .iter()
+            .find(|(code, _)| *code == self.0)
+            .map(|(_, strings)| strings)
+            .unwrap_or(&catalogs[0].1)
+    }
+
+    /// The string for `key`, or the key itself if no catalog has it
+    pub fn get(self, key: &'static str) -> &'static str {
+        self.strings().get(key).copied().unwrap_or(key)
+    }
+}
+
+/// The `lang` parameter of a query string
+fn lang_param(query: Option<&str>) -> Option<String> {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query?).ok()?;
+    pairs.into_iter().find(|(name, _)| name == "lang").map(|(_, value)| value)
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Locale
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::negotiate(&parts.headers, parts.uri.query()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept_language(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn negotiated(value: &str) -> &'static str {
+        Locale::negotiate(&accept_language(value), None).code()
+    }
+
+    #[test]
+    fn test_catalogs_are_complete() {
+        let default = parse(CATALOGS[0].1).unwrap();
+        for (code, source) in CATALOGS {
+            let strings = parse(source).unwrap_or_else(|e| panic!("{}: {}", code, e));
+            let missing: Vec<_> = default.keys().filter(|key| !strings.contains_key(*key)).collect();
+            let unknown: Vec<_> = strings.keys().filter(|key| !default.contains_key(*key)).collect();
+            assert!(missing.is_empty() && unknown.is_empty(), "{}: missing {:?}, unknown {:?}", code, missing, unknown);
+        }
+        assert!(parse("no equals sign").is_err());
+        assert!(parse("bad key = value").is_err());
+    }
+
+    #[test]
+    fn test_q_values() {
+        assert_eq!(negotiated("de;q=0.9, en;q=0.8"), "de");
+        assert_eq!(negotiated("en;q=0.8, de;q=0.9"), "de");
+        assert_eq!(negotiated("de-AT, en;q=0.5"), "de");
+        assert_eq!(negotiated("fr, de;q=0.3"), "de");
+        assert_eq!(negotiated("de;q=0, *"), "en");
+        // Equal qualities go to the first listed
+        assert_eq!(negotiated("de, en"), "de");
+        assert_eq!(negotiated("en, de"), "en");
+    }
+
+    #[test]
+    fn test_fallback_to_default() {
+        assert_eq!(negotiated("fr-CH, fr;q=0.9"), "en");
+        assert_eq!(negotiated("not a tag;q=oops"), "en");
+        assert_eq!(Locale::negotiate(&HeaderMap::new(), None).code(), "en");
+    }
+
+    #[test]
+    fn test_query_override() {
+        let headers = accept_language("en");
+        assert_eq!(Locale::negotiate(&headers, Some("lang=de")).code(), "de");
+        assert_eq!(Locale::negotiate(&headers, Some("a=1&lang=DE-ch")).code(), "de");
+        // An unsupported override leaves the choice to the header
+        assert_eq!(Locale::negotiate(&accept_language("de"), Some("lang=fr")).code(), "de");
+    }
+
+    #[test]
+    fn test_missing_keys_fall_back() {
+        let de = Locale::from_tag("de").unwrap();
+        assert_eq!(de.get("homepage_heading"), "Hallo Welt");
+        assert_eq!(de.get("no_such_key"), "no_such_key");
+    }
+}
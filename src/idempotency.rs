@@ -0,0 +1,359 @@
+/*!
+ * `Idempotency-Key` handling for state-changing requests
+ *
+ * Automation retrying an admin call (a cache purge, a maintenance toggle,
+ * a capture) after a timeout should not apply it twice. A `POST`, `PUT`,
+ * `PATCH` or `DELETE` under one of `IDEMPOTENCY_PREFIXES` (default
+ * `/admin`) carrying `Idempotency-Key: <key>` is handled once; until
+ * `IDEMPOTENCY_TTL_SECS` (default a day) has passed, a repeat of the same
+ * key on the same path gets the stored status, headers and body back with
+ * `Idempotent-Replay: true` instead of reaching the handler. A repeat that
+ * arrives while the first request is still running waits for it and is
+ * answered with its response.
+ *
+ * Only responses below 500 and up to [`MAX_BODY_BYTES`] are stored, so a
+ * request that failed on the server's side can be retried for real. Keys
+ * are 1 to 255 visible ASCII characters. `IDEMPOTENCY_REQUIRED=true`
+ * answers state-changing requests without one with `400
+ * idempotency_key_required`. At most `IDEMPOTENCY_MAX_KEYS` (default
+ * 1000) keys are remembered, the oldest being forgotten first.
+ */
+use crate::auth::longest_prefix;
+use crate::bounded::{BoundedMap, StoreStats};
+use crate::clock::{Clock, SystemClock};
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::debug;
+
+/// Request header naming the operation
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Response header set on replayed responses
+pub const IDEMPOTENT_REPLAY: HeaderName = HeaderName::from_static("idempotent-replay");
+
+/// Largest response body stored for replay
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+const MAX_KEY_LEN: usize = 255;
+
+/// Where keys are honoured, whether they are required, and for how long
+/// responses are kept
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IdempotencyConfig {
+    /// Path prefixes, matched on segment boundaries
+    pub prefixes: Vec<String>,
+
+    /// Reject state-changing requests without a key
+    pub required: bool,
+
+    /// How long a completed response is replayed
+    #[serde(rename = "ttl_seconds", serialize_with = "serialize_secs")]
+    pub ttl: Duration,
+
+    /// Keys remembered at once
+    pub max_keys: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            prefixes: vec!["/admin".to_string()],
+            required: false,
+            ttl: Duration::from_secs(24 * 60 * 60),
+            max_keys: 1000,
+        }
+    }
+}
+
+fn serialize_secs<S: serde::Serializer>(ttl: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(ttl.as_secs())
+}
+
+impl IdempotencyConfig {
+    /// Load from `IDEMPOTENCY_PREFIXES`, `IDEMPOTENCY_REQUIRED`,
+    /// `IDEMPOTENCY_TTL_SECS` and `IDEMPOTENCY_MAX_KEYS`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        let invalid = |name: &str, value: &str, expected: &str| {
+            crate::ServerError::ConfigError(format!("Invalid {} {:?}: expected {}", name, value, expected))
+        };
+
+        if let Ok(value) = std::env::var("IDEMPOTENCY_PREFIXES") {
+            config.prefixes = value.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect();
+            if let Some(prefix) = config.prefixes.iter().find(|prefix| !prefix.starts_with('/')) {
+                return Err(invalid("IDEMPOTENCY_PREFIXES", prefix, "paths starting with /"));
+            }
+        }
+        if let Ok(value) = std::env::var("IDEMPOTENCY_REQUIRED") {
+            config.required = value.trim().parse().map_err(|_| invalid("IDEMPOTENCY_REQUIRED", &value, "true or false"))?;
+        }
+        if let Ok(value) = std::env::var("IDEMPOTENCY_TTL_SECS") {
+            config.ttl = match value.trim().parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(invalid("IDEMPOTENCY_TTL_SECS", &value, "a positive number of seconds")),
+            };
+        }
+        if let Ok(value) = std::env::var("IDEMPOTENCY_MAX_KEYS") {
+            config.max_keys = match value.trim().parse::<usize>() {
+                Ok(keys) if keys > 0 => keys,
+                _ => return Err(invalid("IDEMPOTENCY_MAX_KEYS", &value, "a positive number")),
+            };
+        }
+        Ok(config)
+    }
+
+    /// Whether requests for `path` are covered
+    pub fn covers(&self, path: &str) -> bool {
+        longest_prefix(&self.prefixes, path).is_some()
+    }
+}
+
+/// A response as it is replayed
+#[derive(Debug, Clone)]
+struct Completed {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: SystemTime,
+}
+
+impl Completed {
+    fn replay(&self) -> Response {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response.headers_mut().insert(IDEMPOTENT_REPLAY, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+#[derive(Debug)]
+enum Slot {
+    /// The first request is being handled; the sender is dropped when it
+    /// finishes either way
+    Running(watch::Receiver<()>),
+    Done(Completed),
+}
+
+
+enum Lookup {
+    Replay(Completed),
+    Wait(watch::Receiver<()>),
+
+    /// This request runs; whatever it produces is offered to `finish`
+    Run(watch::Sender<()>),
+}
+
+/// Keys seen recently and their responses; clones share them. Keys are
+/// never touched, so the oldest is forgotten first.
+#[derive(Debug, Clone)]
+pub struct IdempotencyStore {
+    clock: Arc<dyn Clock>,
+    store: Arc<Mutex<BoundedMap<Slot>>>,
+}
+
+impl Default for IdempotencyStore {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl IdempotencyStore {
+    /// Empty store, with expiry measured by `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let defaults = IdempotencyConfig::default();
+        let store = BoundedMap::new("idempotency", defaults.max_keys, Some(defaults.ttl), clock.clone());
+        Self { clock, store: Arc::new(Mutex::new(store)) }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(self, metrics: &crate::metrics::Metrics) -> Self {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).set_metrics(metrics);
+        self
+    }
+
+    /// Number of keys remembered, running or completed
+    pub fn len(&self) -> usize {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Whether no key is remembered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lookup(&self, key: &str, config: &IdempotencyConfig) -> Lookup {
+        let now = self.clock.now();
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        store.set_limits(config.max_keys, Some(config.ttl));
+        match store.get(key) {
+            Some(Slot::Running(receiver)) => return Lookup::Wait(receiver.clone()),
+            Some(Slot::Done(completed)) if now.duration_since(completed.stored_at).unwrap_or_default() < config.ttl => {
+                return Lookup::Replay(completed.clone());
+            }
+            Some(Slot::Done(_)) => {
+                store.remove(key);
+            }
+            None => {}
+        }
+
+        let (sender, receiver) = watch::channel(());
+        store.insert(key.to_string(), Slot::Running(receiver));
+        Lookup::Run(sender)
+    }
+
+    /// Record the outcome of the request that ran `key`: its response, or
+    /// `None` to let the next attempt run again
+    fn finish(&self, key: &str, completed: Option<Completed>) {
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        match completed {
+            Some(completed) => {
+                if let Some(slot) = store.get_mut(key) {
+                    *slot = Slot::Done(completed);
+                    store.renew(key);
+                }
+            }
+            None => {
+                store.remove(key);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).stats()
+    }
+
+    /// Forget keys past `IDEMPOTENCY_TTL_SECS`
+    pub fn sweep(&self) -> usize {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).sweep()
+    }
+}
+
+/// Forgets a running key if its request is dropped before finishing, so
+/// waiting and later requests run it themselves
+struct Running<'a> {
+    store: &'a IdempotencyStore,
+    key: &'a str,
+    finished: bool,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.store.finish(self.key, None);
+        }
+    }
+}
+
+fn bad_request(code: &'static str, message: &str) -> Response {
+    ApiError::new(StatusCode::BAD_REQUEST, code, message.to_string()).into_response()
+}
+
+/// Middleware running each keyed request once and replaying its response
+pub async fn enforce(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let config = state.config();
+    let config = &config.idempotency;
+    let state_changing = matches!(*request.method(), Method::POST | Method::PUT | Method::PATCH | Method::DELETE);
+    if !state_changing || !config.covers(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let key = match request.headers().get(IDEMPOTENCY_KEY) {
+        None if config.required => {
+            return bad_request("idempotency_key_required", "Send an Idempotency-Key header with this request");
+        }
+        None => return next.run(request).await,
+        Some(value) => match value.to_str() {
+            Ok(key) if (1..=MAX_KEY_LEN).contains(&key.len()) && key.bytes().all(|b| b.is_ascii_graphic()) => key,
+            _ => {
+                return bad_request(
+                    "invalid_idempotency_key",
+                    "Idempotency-Key must be 1 to 255 visible ASCII characters",
+                )
+            }
+        },
+    };
+    let key = format!("{} {}", request.uri().path(), key);
+
+    let store = &state.idempotency;
+    let _sender = loop {
+        match store.lookup(&key, config) {
+            Lookup::Replay(completed) => {
+                debug!(key = %key, "Replaying an idempotent response");
+                return completed.replay();
+            }
+            // Resolves when the first request's sender is dropped
+            Lookup::Wait(mut receiver) => while receiver.changed().await.is_ok() {},
+            Lookup::Run(sender) => break sender,
+        }
+    };
+    let mut running = Running { store, key: &key, finished: false };
+
+    let response = next.run(request).await;
+    if response.status().is_server_error()
+        || http_body::Body::size_hint(response.body()).upper().is_none_or(|len| len > MAX_BODY_BYTES as u64)
+    {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(e) => {
+            return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "response_failed", format!("Failed to read the response: {}", e))
+                .into_response();
+        }
+    };
+    running.finished = true;
+    store.finish(
+        &key,
+        Some(Completed {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            stored_at: store.clock.now(),
+        }),
+    );
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_key_is_forgotten() {
+        let store = IdempotencyStore::default();
+        let config = IdempotencyConfig { max_keys: 2, ..IdempotencyConfig::default() };
+        for key in ["a", "b", "c"] {
+            assert!(matches!(store.lookup(key, &config), Lookup::Run(_)));
+            store.finish(key, Some(Completed {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: Bytes::new(),
+                stored_at: SystemTime::now(),
+            }));
+        }
+        assert_eq!(store.len(), 2);
+        assert!(matches!(store.lookup("c", &config), Lookup::Replay(_)));
+        assert!(matches!(store.lookup("a", &config), Lookup::Run(_)));
+    }
+
+    #[test]
+    fn test_covered_paths() {
+        let config = IdempotencyConfig::default();
+        assert!(config.covers("/admin") && config.covers("/admin/maintenance"));
+        assert!(!config.covers("/administrator") && !config.covers("/api"));
+    }
+}
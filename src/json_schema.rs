@@ -0,0 +1,167 @@
+/*!
+ * JSON Schema validation for `POST /validate`
+ *
+ * Named schemas are loaded once at startup from the `*.json` files in
+ * `SCHEMAS_DIR` (the file stem is the name) and compiled, so a schema
+ * that cannot be used stops the process instead of failing a request.
+ * `POST /validate` checks the body against the schema named by the
+ * `X-Schema` header, or the one named `default` without it; `GET
+ * /validate/schemas` lists them. Useful for seeing what a WAF JSON rule
+ * lets through to the origin.
+ *
+ * Validation is done by the `jsonschema` crate, which picks the draft from
+ * `$schema` (2020-12 when absent). Remote `$ref`s are not fetched, so a
+ * schema must be self-contained.
+ */
+use axum::http::HeaderName;
+use jsonschema::Validator;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Request header naming the schema to validate against
+pub const SCHEMA_HEADER: HeaderName = HeaderName::from_static("x-schema");
+
+/// Schema used when the request names none
+pub const DEFAULT_SCHEMA: &str = "default";
+
+/// A document location that broke a rule of the schema
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    /// JSON Pointer into the document; empty for the document itself
+    pub path: String,
+    pub keyword: String,
+    pub message: String,
+}
+
+/// A compiled schema
+#[derive(Debug)]
+pub struct Schema {
+    validator: Validator,
+    title: Option<String>,
+}
+
+impl Schema {
+    /// Compile `schema`, or say why it is unusable
+    pub fn compile(schema: &Value) -> Result<Self, String> {
+        let validator = jsonschema::validator_for(schema).map_err(|e| {
+            let at = e.instance_path().as_str();
+            if at.is_empty() { e.to_string() } else { format!("#{}: {}", at, e) }
+        })?;
+        let title = schema.get("title").and_then(Value::as_str).map(String::from);
+        Ok(Self { validator, title })
+    }
+
+    /// The schema's `title`, if it has one
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Every way `document` breaks the schema; empty when it is valid
+    pub fn validate(&self, document: &Value) -> Vec<Violation> {
+        self.validator
+            .iter_errors(document)
+            .map(|error| Violation {
+                path: error.instance_path().as_str().to_string(),
+                keyword: error.kind().keyword().to_string(),
+                message: error.to_string(),
+            })
+            .collect()
+    }
+}
+
+/// A loaded schema's name and title, as listed at `/validate/schemas`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SchemaInfo {
+    pub name: String,
+    pub title: Option<String>,
+}
+
+/// Named schemas; clones share them
+#[derive(Debug, Clone, Default)]
+pub struct Schemas {
+    schemas: Arc<BTreeMap<String, Schema>>,
+}
+
+impl Schemas {
+    /// Schemas under the given names
+    pub fn new(schemas: impl IntoIterator<Item = (String, Schema)>) -> Self {
+        Self { schemas: Arc::new(schemas.into_iter().collect()) }
+    }
+
+    /// Compile every `*.json` file in `dir`, named by its stem
+    pub fn load(dir: impl AsRef<Path>) -> crate::Result<Self> {
+        let dir = dir.as_ref();
+        let config_error = |e: std::io::Error| crate::ServerError::ConfigError(
+            format!("Failed to read SCHEMAS_DIR {}: {}", dir.display(), e)
+        );
+
+        let mut schemas = BTreeMap::new();
+        for entry in std::fs::read_dir(dir).map_err(config_error)? {
+            let path = entry.map_err(config_error)?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let invalid = |e: String| crate::ServerError::ConfigError(format!("Invalid schema {}: {}", path.display(), e));
+            let name = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|stem| stem.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'))
+                .ok_or_else(|| invalid("names may only use letters, digits, '-' and '_'".to_string()))?;
+            let text = std::fs::read_to_string(&path).map_err(|e| invalid(e.to_string()))?;
+            let schema: Value = serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+            schemas.insert(name.to_string(), Schema::compile(&schema).map_err(invalid)?);
+        }
+        Ok(Self { schemas: Arc::new(schemas) })
+    }
+
+    /// Load from `SCHEMAS_DIR`; no schemas when it is unset
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("SCHEMAS_DIR") {
+            Ok(dir) if !dir.is_empty() => Self::load(dir),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Schema> {
+        self.schemas.get(name)
+    }
+
+    /// Every schema, by name
+    pub fn list(&self) -> Vec<SchemaInfo> {
+        self.schemas
+            .iter()
+            .map(|(name, schema)| SchemaInfo { name: name.clone(), title: schema.title.clone() })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_unusable_schemas_are_rejected() {
+        assert!(Schema::compile(&json!({ "type": "objekt" })).is_err());
+        assert!(Schema::compile(&json!({ "$ref": "#/$defs/missing" })).is_err());
+        assert!(Schema::compile(&json!({ "$ref": "https://example.com/order.json" })).is_err());
+    }
+
+    #[test]
+    fn test_recursive_references() {
+        let schema = Schema::compile(&json!({
+            "$defs": { "node": { "type": "object", "properties": { "children": { "type": "array", "items": { "$ref": "#/$defs/node" } } } } },
+            "$ref": "#/$defs/node",
+        }))
+        .unwrap();
+
+        assert!(schema.validate(&json!({ "children": [{ "children": [] }] })).is_empty());
+        let violations = schema.validate(&json!({ "children": [{ "children": [1] }] }));
+        assert_eq!(violations[0].path, "/children/0/children/0");
+        assert_eq!(violations[0].keyword, "type");
+    }
+}
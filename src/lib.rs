@@ -0,0 +1,618 @@
+/*!
+ * Axum service designed to sit behind a Cloudflare Tunnel
+ *
+ * The binary is a thin wrapper around this library: [`create_app`] builds
+ * the router (routes, security headers, cache policy and the Cloudflare
+ * specific middleware) from an [`state::AppState`], and [`serve`] runs it on
+ * an already-bound listener together with the optional admin listener and
+ * embedded quick tunnel, until Ctrl+C or SIGTERM. [`ServerHandle`] runs a
+ * router in the background with programmatic shutdown, for tests and
+ * embedding in a larger binary.
+ *
+ * ```no_run
+ * use cloudflare_tunnel_example::config::AppConfig;
+ *
+ * # async fn run() -> cloudflare_tunnel_example::Result<()> {
+ * let config = AppConfig::from_env()?;
+ * let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
+ * cloudflare_tunnel_example::serve(config, listener).await
+ * # }
+ * ```
+ */
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, Request},
+    middleware,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+    Router,
+};
+#[cfg(feature = "turnstile")]
+use axum::Extension;
+use negotiate::Format;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use thiserror::Error;
+use tower_http::trace::TraceLayer;
+use utoipa::{IntoParams, ToSchema};
+
+pub mod access;
+pub mod access_log;
+pub mod admin;
+pub mod anomalies;
+pub mod api_keys;
+pub mod assets;
+pub mod audit;
+pub mod auth;
+pub mod bounded;
+pub mod build_info;
+pub mod cache;
+pub mod canary;
+pub mod capture;
+pub mod client_ip;
+pub mod clock;
+pub mod concurrency;
+pub mod conditional;
+pub mod content_digest;
+pub mod cookies;
+pub mod csrf;
+pub mod decompression;
+pub mod denial;
+pub mod cloudflare;
+pub mod config;
+pub mod config_history;
+pub mod config_view;
+#[cfg(feature = "debug-endpoints")]
+mod debug;
+pub mod direct_access;
+pub mod disconnect;
+pub mod drain;
+pub mod error;
+pub mod error_pages;
+pub mod events;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+pub mod favicon;
+pub mod features;
+pub mod header_budget;
+pub mod header_limits;
+pub mod headers;
+pub mod header_sampling;
+pub mod health;
+pub mod healthcheck;
+mod homepage;
+pub mod log_level;
+pub mod logging;
+pub mod maintenance;
+pub mod http_client;
+pub mod i18n;
+pub mod idempotency;
+#[cfg(feature = "debug-endpoints")]
+pub mod json_schema;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod methods;
+pub mod protocol;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+pub mod negotiate;
+pub mod normalize;
+pub mod notifications;
+pub mod openapi;
+pub mod pipeline;
+pub mod redact;
+pub mod reports;
+pub mod request_events;
+pub mod request_id;
+pub mod response_cache;
+pub mod response_size;
+pub mod robots;
+pub mod route_stats;
+pub mod scheme;
+pub mod security_score;
+pub mod self_test;
+pub mod server_timing;
+pub mod slo;
+mod server;
+pub mod shutdown;
+pub mod state;
+pub mod startup;
+pub mod static_files;
+pub mod status;
+pub mod tasks;
+pub mod tenants;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+pub mod timeouts;
+pub mod tunnel;
+pub mod tunnel_health;
+#[cfg(feature = "turnstile")]
+pub mod turnstile;
+pub mod unknown_paths;
+pub mod webhooks;
+#[cfg(feature = "debug-endpoints")]
+mod websocket;
+use config::AppConfig;
+use pipeline::Pipeline;
+use headers::HeaderMode;
+use state::AppState;
+#[cfg(feature = "turnstile")]
+use turnstile::TurnstileVerifier;
+
+pub use server::{serve, serve_with_listener, serve_with_protocol, serve_with_token, ServerHandle};
+
+/// Errors that stop the server from starting or running
+#[derive(Debug, Error)]
+pub enum ServerError {
+    /// A listener could not be bound
+    #[error("Failed to bind to address {addr}: {source}{}", startup::bind_hint(.addr, .source))]
+    BindError {
+        addr: SocketAddr,
+        #[source]
+        source: std::io::Error,
+    },
+    /// I/O failure while serving
+    #[error("Server runtime error: {0}")]
+    RuntimeError(#[from] std::io::Error),
+    /// Invalid or inconsistent configuration
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+}
+
+/// Result type used throughout the crate
+pub type Result<T> = std::result::Result<T, ServerError>;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct HealthQuery {
+    /// `json`, `text` or `prometheus`; overrides Accept, unknown values give JSON
+    format: Option<String>,
+}
+
+/// Overall state reported by `/health`
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum HealthStatus {
+    Healthy,
+    Degraded,
+}
+
+/// JSON body of `/health`
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = Health)]
+struct HealthBody {
+    status: HealthStatus,
+
+    /// `draining` and `maintenance`, when degraded
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    degraded: Vec<&'static str>,
+    service: &'static str,
+    #[schema(format = DateTime)]
+    timestamp: String,
+    uptime_seconds: u64,
+
+    /// Quick tunnel only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    tunnel_url: Option<Option<String>>,
+
+    /// Quick tunnel only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel_restarts: Option<u32>,
+}
+
+/// Liveness check
+///
+/// Always 200 while the process can answer, as JSON, a single
+/// `ok`/`degraded: ...` line or Prometheus gauges.
+#[utoipa::path(
+    get,
+    path = "/health",
+    params(HealthQuery),
+    responses(
+        (status = OK, description = "The process is up", content(
+            (HealthBody = "application/json"),
+            (String = "text/plain", example = "ok"),
+        )),
+    ),
+)]
+async fn health_check(
+    State(state): State<AppState>,
+    Query(query): Query<HealthQuery>,
+    headers: HeaderMap,
+) -> Response {
+    // Conditions worth a probe's attention that do not make the process unhealthy
+    let mut degraded = Vec::new();
+    if !state.is_ready() {
+        degraded.push("draining");
+    }
+    if state.maintenance.is_enabled() {
+        degraded.push("maintenance");
+    }
+    
+    let format = Format::negotiate(
+        &headers,
+        query.format.as_deref(),
+        &[Format::Json, Format::Text, Format::Prometheus],
+    );
+    let vary = [(header::VARY, "accept")];
+    match format {
+        Format::Text => {
+            let line = if degraded.is_empty() { "ok\n".to_string() } else { format!("degraded: {}\n", degraded.join(", ")) };
+            (vary, [(header::CONTENT_TYPE, "text/plain; charset=utf-8")], line).into_response()
+        }
+        Format::Prometheus => {
+            let body = format!(
+                "# HELP health_status 1 when healthy, 0 when degraded\n# TYPE health_status gauge\nhealth_status {}\n\
+                 # HELP health_uptime_seconds Seconds since the service started\n# TYPE health_uptime_seconds gauge\nhealth_uptime_seconds {}\n",
+                u8::from(degraded.is_empty()),
+                state.uptime().as_secs()
+            );
+            (vary, [(header::CONTENT_TYPE, format.media_type())], body).into_response()
+        }
+        Format::Json => {
+            let body = HealthBody {
+                status: if degraded.is_empty() { HealthStatus::Healthy } else { HealthStatus::Degraded },
+                degraded,
+                service: "cloudflare-tunnel-example",
+                timestamp: state.clock.now_utc().to_rfc3339(),
+                uptime_seconds: state.uptime().as_secs(),
+                // Only present when the embedded quick tunnel is running
+                tunnel_url: state.tunnel.as_ref().map(|tunnel| tunnel.url()),
+                tunnel_restarts: state.tunnel.as_ref().map(|tunnel| tunnel.restarts()),
+            };
+            (vary, Json(body)).into_response()
+        }
+    }
+}
+
+/// Build the router with the default configuration
+pub fn create_app_with_defaults() -> Router {
+    create_app(AppState::default())
+}
+
+/// Build the main router around `state`.
+///
+/// Which routes exist (Turnstile, the https redirect) is decided from the
+/// configuration at call time; the optional route groups follow
+/// `state.features`, and header and cache policy follow later
+/// `AppState::replace_config` calls.
+pub fn create_app(state: AppState) -> Router {
+    AppBuilder::new(state).build()
+}
+
+/// Builds the main router with parts of the middleware stack left out.
+///
+/// `create_app` is the normal entry point; the builder exists for
+/// benchmarks that need a baseline without a given layer.
+#[derive(Debug, Clone)]
+pub struct AppBuilder {
+    state: AppState,
+    security_headers: bool,
+}
+
+impl AppBuilder {
+    /// Builder for the full stack around `state`
+    pub fn new(state: AppState) -> Self {
+        Self {
+            state,
+            security_headers: true,
+        }
+    }
+
+    /// Whether to install the security-header middleware (default: true)
+    pub fn security_headers(mut self, enabled: bool) -> Self {
+        self.security_headers = enabled;
+        self
+    }
+
+    /// The router's stages, innermost first, for the configuration in `state`
+    pub fn pipeline(&self) -> Pipeline {
+        let state = &self.state;
+        
+        // Routes are fixed when the router is built;
+        // middleware reads the live configuration from `state` on each request
+        let config = state.config();
+        let mut pipeline = Pipeline::default();
+        
+        let routes_state = state.clone();
+        pipeline.push("routes", move |router| router.merge(routes(&routes_state)));
+        
+        // Static files take no bodies and proxied bodies go upstream as sent,
+        // so both are mounted outside the decompression
+        pipeline.layer("decompression", middleware::from_fn_with_state(state.clone(), decompression::decompress_request));
+        
+        if let Some(static_config) = config.static_files.clone() {
+            pipeline.push("static_files", move |router| static_files::mount(router, &static_config));
+        }
+        
+        #[cfg(feature = "proxy")]
+        if let Some(proxy_config) = config.proxy.clone() {
+            let http = state.http.clone();
+            pipeline.push("proxy", move |router| proxy::mount(router, &proxy_config, &http));
+        }
+        
+        // Outside the decompression and the proxy, since digests cover the
+        // body as sent
+        pipeline.layer("content_digest", middleware::from_fn(content_digest::verify));
+        
+        // Inside the cache, which keeps each variant under its cookie, and
+        // the cookie hardening its `Set-Cookie` goes through
+        pipeline.layer("canary", middleware::from_fn_with_state(state.clone(), canary::assign));
+        
+        // Inside maintenance and authentication, so neither is bypassed by a hit
+        pipeline.layer("response_cache", middleware::from_fn_with_state(state.clone(), response_cache::serve_cached));
+        
+        // Inside maintenance and authentication, so a replay bypasses neither
+        pipeline.layer("idempotency", middleware::from_fn_with_state(state.clone(), idempotency::enforce));
+        
+        // Inside maintenance, which takes precedence, and outside the cache so
+        // cached responses are delayed too
+        #[cfg(feature = "fault-injection")]
+        pipeline.layer("faults", middleware::from_fn_with_state(state.clone(), faults::inject));
+        
+        // Covers every route (and the 404 fallback) except the probes
+        pipeline.layer("maintenance", middleware::from_fn_with_state(state.clone(), maintenance::enforce));
+        
+        // Inside authentication, so unauthenticated requests get 401 rather than 403
+        pipeline.layer("csrf", middleware::from_fn_with_state(state.clone(), csrf::protect));
+        
+        // Outside maintenance so protected paths stay protected either way
+        pipeline.layer("bearer_auth", middleware::from_fn_with_state(state.clone(), auth::require_bearer));
+        pipeline.layer("api_keys", middleware::from_fn_with_state(state.clone(), api_keys::require_api_key));
+        
+        // Runs inside the security middleware so redirects still carry its headers
+        if config.force_https_redirect {
+            pipeline.layer("https_redirect", middleware::from_fn(scheme::redirect_to_https));
+        }
+        
+        // Outside the redirect so direct hits are rejected rather than bounced
+        pipeline.layer("direct_access", middleware::from_fn_with_state(state.clone(), direct_access::enforce));
+        
+        // Outside every middleware that blocks requests, so each denial gets the signal header
+        pipeline.layer("denial_signal", middleware::from_fn_with_state(state.clone(), denial::signal));
+        
+        pipeline.layer("cache_policy", middleware::from_fn_with_state(state.clone(), cache::cache_policy));
+        
+        // Waiting for a slot counts towards the request timeout
+        pipeline.layer("concurrency", middleware::from_fn_with_state(state.clone(), concurrency::limit));
+        
+        // Bounds everything behind the routes, including proxying and the
+        // response cache; route stats see the 504
+        pipeline.layer("timeouts", middleware::from_fn_with_state(state.clone(), timeouts::enforce));
+        
+        // Outermost layers that still see the matched route
+        pipeline.layer("matched_route", middleware::from_fn(request_events::matched_route));
+        pipeline.layer("route_stats", middleware::from_fn_with_state(state.clone(), route_stats::record));
+        
+        // Routing happens before `Router::layer` middleware runs, so path
+        // normalization wraps the finished router instead, as does the 405
+        // handling, which needs the `Allow` header axum adds after routing;
+        // oversized headers are turned away before any of it
+        let routed_state = state.clone();
+        pipeline.push("routing", move |router| Router::new().fallback_service(router.with_state(routed_state)));
+        pipeline.layer("normalize_path", middleware::from_fn_with_state(state.clone(), normalize::normalize_path));
+        pipeline.layer("allowed_methods", middleware::from_fn(methods::allowed_methods));
+        pipeline.layer("header_limits", middleware::from_fn_with_state(state.clone(), header_limits::enforce));
+        
+        // Inside the security headers so a replacement 500 still gets them
+        pipeline.layer("response_size", middleware::from_fn_with_state(state.clone(), response_size::count_response_bytes));
+        
+        // Outside the size cap so its 500 gets a page as well
+        pipeline.layer("error_pages", middleware::from_fn_with_state(state.clone(), error_pages::render_html));
+        
+        // Outside the error pages and the size cap, so the digest covers the
+        // body actually sent
+        pipeline.layer("response_digest", middleware::from_fn_with_state(state.clone(), content_digest::digest_response));
+        
+        pipeline.layer("cookies", middleware::from_fn_with_state(state.clone(), cookies::harden_cookies));
+        
+        if self.security_headers {
+            pipeline.layer("security_headers", middleware::from_fn_with_state(state.clone(), security_headers));
+        }
+        
+        pipeline.layer("server_timing", middleware::from_fn_with_state(state.clone(), server_timing::server_timing));
+        pipeline.layer("build_headers", middleware::from_fn_with_state(state.clone(), build_info::build_headers));
+        
+        pipeline.layer("scheme", middleware::from_fn(scheme::resolve_scheme));
+        
+        #[cfg(feature = "metrics")]
+        pipeline.layer("request_metrics", middleware::from_fn_with_state(state.clone(), count_requests));
+        
+        // Publishes once the response head is final, for the SLO tracker,
+        // unknown paths and any other subscriber
+        pipeline.layer("request_events", middleware::from_fn_with_state(state.clone(), request_events::publish));
+        pipeline.layer("access", middleware::from_fn_with_state(state.clone(), access::verify));
+        
+        // Sees the request as it arrived and the response as it leaves
+        pipeline.layer("capture", middleware::from_fn_with_state(state.clone(), capture::capture));
+        pipeline.layer("trace_context", middleware::from_fn(http_client::propagate_trace_context));
+        
+        pipeline.layer("disconnects", middleware::from_fn_with_state(state.clone(), disconnect::detect_disconnects));
+        
+        // Before anything reads a body or matches a route, inside the access
+        // log so rejections are logged with their request ID
+        pipeline.layer("anomalies", middleware::from_fn_with_state(state.clone(), anomalies::reject));
+        
+        // These see the assigned request ID and the final response
+        pipeline.layer("header_sampling", middleware::from_fn_with_state(state.clone(), header_sampling::sample_headers));
+        pipeline.layer("access_log", middleware::from_fn_with_state(state.clone(), access_log::record));
+        pipeline.layer("cf_ray", middleware::from_fn_with_state(state.clone(), cloudflare::ray::record));
+        
+        // Resolved once, for the access log and every handler keying on the client
+        pipeline.layer("client_ip", middleware::from_fn_with_state(state.clone(), client_ip::resolve));
+        
+        // Outside the security headers, which send the tenant's profile
+        pipeline.layer("tenants", middleware::from_fn_with_state(state.clone(), tenants::assign));
+        
+        // Inside the span it records the ID on, outside everything else
+        pipeline.layer("request_id", middleware::from_fn_with_state(state.clone(), request_id::assign));
+        
+        pipeline.layer("trace", TraceLayer::new_for_http().make_span_with(request_span));
+        
+        pipeline
+    }
+
+    /// Build the router, refusing a stage order that breaks a
+    /// [`pipeline::INVARIANTS`] rule
+    pub fn try_build(self) -> Result<Router> {
+        let pipeline = self.pipeline();
+        pipeline
+            .check()
+            .map_err(|violation| ServerError::ConfigError(format!("Invalid middleware order: {}", violation)))?;
+        self.state.set_pipeline(pipeline.order());
+        slo::subscribe(&self.state);
+        unknown_paths::subscribe(&self.state);
+        Ok(pipeline.build().with_state(self.state))
+    }
+
+    /// Build the router
+    ///
+    /// # Panics
+    ///
+    /// If the stage order breaks a [`pipeline::INVARIANTS`] rule
+    pub fn build(self) -> Router {
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+/// Every handler route enabled by the configuration in `state`
+fn routes(state: &AppState) -> Router<AppState> {
+    let config = state.config();
+    
+    // A static index file takes over the homepage
+    let mut router = Router::new();
+    if config.static_files.as_ref().is_none_or(|files| files.index.is_none()) {
+        router = router.route("/", get(homepage::homepage));
+    }
+    
+    router = router
+        .route("/health", get(health_check))
+        .merge(health::routes())
+        .merge(features::guard(status::routes(), &state.features, features::STATUS))
+        .merge(favicon::routes())
+        .merge(robots::routes())
+        .merge(features::guard(events::routes(), &state.features, features::EVENTS))
+        .merge(webhooks::routes())
+        .merge(reports::routes())
+        .merge(openapi::routes());
+    
+    #[cfg(feature = "metrics")]
+    {
+        router = router.merge(metrics::routes());
+    }
+    
+    // Mounted whatever DEBUG_ENDPOINTS says, since the toggles can switch
+    // them on later
+    #[cfg(feature = "debug-endpoints")]
+    {
+        router = router.merge(debug::routes(&state.features));
+    }
+    
+    // The verifier extension lets any handler use the RequireTurnstile guard
+    #[cfg(feature = "turnstile")]
+    if let Some(turnstile_config) = config.turnstile.clone() {
+        router = router
+            .merge(turnstile::routes())
+            .layer(Extension(TurnstileVerifier::new(turnstile_config, &state.http)));
+    }
+    
+    if config.csrf.is_some() {
+        router = router.merge(csrf::routes());
+    }
+    
+    router
+}
+
+#[cfg(feature = "metrics")]
+async fn count_requests(
+    State(state): State<AppState>,
+    request: Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    state.requests.total.inc();
+    state.requests.method(request.method()).inc();
+    let path = request.uri().path().to_string();
+    // Exemplars link a latency bucket to the request's trace
+    #[cfg(feature = "otel")]
+    let trace = http_client::trace_ids(request.headers())
+        .map(|(trace_id, span_id)| (trace_id.to_string(), span_id.to_string()));
+    #[cfg(not(feature = "otel"))]
+    let trace: Option<(String, String)> = None;
+    let started = std::time::Instant::now();
+
+    // Decremented on drop, so cancelled requests are not counted forever
+    struct InFlight(metrics::Gauge);
+    impl Drop for InFlight {
+        fn drop(&mut self) {
+            self.0.dec();
+        }
+    }
+    state.requests.in_flight.inc();
+    let in_flight = InFlight(state.requests.in_flight.clone());
+
+    let response = next.run(request).await;
+    drop(in_flight);
+
+    let elapsed = started.elapsed().as_millis() as u64;
+    match trace {
+        Some((trace_id, span_id)) => state
+            .requests
+            .duration
+            .observe_with_exemplar(elapsed, &[("trace_id", &trace_id), ("span_id", &span_id)]),
+        None => state.requests.duration.observe(elapsed),
+    }
+
+    let status = response.status();
+    if (status.is_client_error() || status.is_server_error()) && !favicon::is_favicon_miss(&path, status) {
+        state.requests.errors.inc();
+    }
+    response
+}
+
+/// Per-request span; fields left `Empty` are recorded by later middleware
+fn request_span(request: &Request<axum::body::Body>) -> tracing::Span {
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = tracing::field::Empty,
+        timeout = tracing::field::Empty,
+        scheme = tracing::field::Empty,
+        api_key = tracing::field::Empty,
+        cf_colo = tracing::field::Empty,
+        tenant = tracing::field::Empty,
+    )
+}
+
+/// Middleware adding the configured security headers and `Server`, for
+/// routers built outside [`AppBuilder`] too
+pub async fn security_headers(
+    State(config): State<Arc<AppConfig>>,
+    request: Request<axum::body::Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let tenant = request.extensions().get::<tenants::TenantName>().cloned();
+    let mut response = next.run(request).await;
+
+    // Apply all configured security headers, from the request's tenant profile if it has one,
+    // keeping the handler's value where the header's mode is `default`
+    let security = config.tenants.security_for(tenant.as_ref(), &config.security);
+    let server = HeaderValue::from_str(&config.security.server_header).ok().map(|value| (header::SERVER, value));
+    let headers = response.headers_mut();
+    for (name, value) in security.to_headers().into_iter().chain(server) {
+        match config.header_modes.mode_for(&name) {
+            HeaderMode::Force => {
+                headers.insert(name, value);
+            }
+            HeaderMode::Default => {
+                headers.entry(name).or_insert(value);
+            }
+        }
+    }
+    header_budget::apply(headers, &config.header_budget);
+
+    response
+}
+
@@ -0,0 +1,234 @@
+/*!
+ * Log filter changes at runtime
+ *
+ * Debugging a live process often needs more logging for a few minutes,
+ * not a restart. The application log filter (`RUST_LOG`/`LOG_LEVEL` at
+ * startup, see [`crate::logging`]) can be replaced while the service runs:
+ *
+ * - `PUT /admin/log-level {"filter": "tower_http=debug,info"}` on the admin
+ *   listener sets any `EnvFilter` directives; ones that do not parse get
+ *   `400 invalid_filter`
+ * - `SIGUSR1` steps through `info`, `debug` and `trace`, then back to
+ *   `info`
+ *
+ * A filter other than the startup one only lasts `LOG_LEVEL_REVERT_SECS`
+ * (default 15 minutes), so debug logging cannot be left on by accident:
+ * the [`REVERT_TASK`] background task puts the startup filter back once
+ * the deadline passes. `GET /admin/log-level` shows the filter in force and
+ * the deadline.
+ */
+use crate::clock::{Clock, SystemClock};
+use crate::logging;
+use crate::state::AppState;
+use crate::tasks::Schedule;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Name of the task restoring the startup filter
+pub const REVERT_TASK: &str = "log-level-revert";
+
+/// Filter reported when this crate did not install the subscriber
+const DEFAULT_FILTER: &str = "info";
+
+/// Levels `SIGUSR1` steps through, in order
+const SIGNAL_CYCLE: [&str; 3] = ["info", "debug", "trace"];
+
+/// `LOG_LEVEL_REVERT_SECS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLevelConfig {
+    /// How long a changed filter lasts before the startup one is restored
+    pub revert_after: Duration,
+}
+
+impl Default for LogLevelConfig {
+    fn default() -> Self {
+        Self { revert_after: Duration::from_secs(15 * 60) }
+    }
+}
+
+impl LogLevelConfig {
+    /// Load from `LOG_LEVEL_REVERT_SECS`, which must be at least 1
+    pub fn from_env() -> crate::Result<Self> {
+        let Ok(value) = std::env::var("LOG_LEVEL_REVERT_SECS") else {
+            return Ok(Self::default());
+        };
+        let seconds: u64 = value.trim().parse().map_err(|e| {
+            crate::ServerError::ConfigError(format!("Invalid LOG_LEVEL_REVERT_SECS {:?}: {}", value, e))
+        })?;
+        if seconds == 0 {
+            return Err(crate::ServerError::ConfigError(
+                "LOG_LEVEL_REVERT_SECS must be at least 1".to_string(),
+            ));
+        }
+        Ok(Self { revert_after: Duration::from_secs(seconds) })
+    }
+}
+
+/// The filter in force, as reported by `GET /admin/log-level`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LogLevelStatus {
+    pub filter: String,
+    pub startup_filter: String,
+
+    /// When `startup_filter` comes back; `None` while it is in force
+    pub revert_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct Override {
+    filter: String,
+    revert_at: SystemTime,
+}
+
+/// The filter set at runtime, if any; clones share it
+#[derive(Debug, Clone)]
+pub struct LogLevel {
+    clock: Arc<dyn Clock>,
+    current: Arc<Mutex<Option<Override>>>,
+}
+
+impl Default for LogLevel {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl LogLevel {
+    /// The startup filter in force, with deadlines measured by `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self { clock, current: Arc::default() }
+    }
+
+    /// Directives the process started with
+    pub fn startup_filter(&self) -> &'static str {
+        logging::startup_filter().unwrap_or(DEFAULT_FILTER)
+    }
+
+    /// Filter the application log with `filter` for `revert_after`; setting
+    /// the startup filter ends the override instead
+    pub fn set(&self, filter: &str, revert_after: Duration) -> Result<LogLevelStatus, String> {
+        let filter = filter.trim();
+        logging::set_filter(filter)?;
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if filter == self.startup_filter() {
+            info!("Log filter back to the startup filter {:?}", filter);
+            *current = None;
+        } else {
+            warn!(
+                "Log filter set to {:?} for {}s, then back to {:?}",
+                filter,
+                revert_after.as_secs(),
+                self.startup_filter()
+            );
+            *current = Some(Override { filter: filter.to_string(), revert_at: self.clock.now() + revert_after });
+        }
+        drop(current);
+        Ok(self.status())
+    }
+
+    /// Step to the next level of `info`, `debug`, `trace`; a filter that
+    /// is none of those steps to `debug`
+    pub fn cycle(&self, revert_after: Duration) -> LogLevelStatus {
+        let filter = self.status().filter;
+        let next = match SIGNAL_CYCLE.iter().position(|level| *level == filter) {
+            Some(index) => SIGNAL_CYCLE[(index + 1) % SIGNAL_CYCLE.len()],
+            None => SIGNAL_CYCLE[1],
+        };
+        // The levels are fixed, valid directives
+        self.set(next, revert_after).unwrap_or_else(|_| self.status())
+    }
+
+    /// Restore the startup filter if the override's deadline has passed
+    pub fn revert_if_due(&self) -> Result<(), String> {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if current.as_ref().is_none_or(|live| self.clock.now() < live.revert_at) {
+            return Ok(());
+        }
+        *current = None;
+        info!("Log filter override expired; back to {:?}", self.startup_filter());
+        logging::set_filter(self.startup_filter())
+    }
+
+    /// The filter in force, after reverting an expired override
+    pub fn status(&self) -> LogLevelStatus {
+        if let Err(e) = self.revert_if_due() {
+            warn!("Failed to restore the startup log filter: {}", e);
+        }
+        let current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        LogLevelStatus {
+            filter: current.as_ref().map_or(self.startup_filter(), |live| live.filter.as_str()).to_string(),
+            startup_filter: self.startup_filter().to_string(),
+            revert_at: current.as_ref().map(|live| live.revert_at.into()),
+        }
+    }
+}
+
+/// Register the task restoring the startup filter with `state.tasks`, and
+/// on Unix step the level on every `SIGUSR1`
+pub fn watch(state: &AppState) {
+    let log_level = state.log_level.clone();
+    state.tasks.spawn(REVERT_TASK, Schedule::every(Duration::from_secs(1)), move || {
+        let log_level = log_level.clone();
+        async move { log_level.revert_if_due() }
+    });
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut signals = match signal(SignalKind::user_defined1()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                warn!("Failed to listen for SIGUSR1; the log level can only change through the admin API: {}", e);
+                return;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                let status = state.log_level.cycle(state.config().log_level.revert_after);
+                info!("SIGUSR1 received; log filter now {:?}", status.filter);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestClock;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_signal_cycle() {
+        let log_level = LogLevel::default();
+        let minute = Duration::from_secs(60);
+        let steps: Vec<String> = (0..4).map(|_| log_level.cycle(minute).filter).collect();
+        assert_eq!(steps, ["debug", "trace", "info", "debug"]);
+
+        // Back at the startup filter there is nothing to revert
+        log_level.set("tower_http=debug", minute).unwrap();
+        assert_eq!(log_level.cycle(minute).filter, "debug");
+        assert_eq!(log_level.cycle(minute).filter, "trace");
+        assert_eq!(log_level.cycle(minute).revert_at, None);
+    }
+
+    #[test]
+    fn test_override_expires() {
+        let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+        let log_level = LogLevel::new(clock.clone());
+        let status = log_level.set("debug", Duration::from_secs(60)).unwrap();
+        assert_eq!(status.revert_at.map(|at| at.timestamp()), Some(1_704_067_260));
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(log_level.status().filter, "debug");
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(log_level.status(), LogLevelStatus {
+            filter: "info".to_string(),
+            startup_filter: "info".to_string(),
+            revert_at: None,
+        });
+    }
+}
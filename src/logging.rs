@@ -0,0 +1,270 @@
+/*!
+ * Tracing subscriber setup
+ *
+ * [`init_tracing`] installs the subscriber the binary logs through: the
+ * application log on stdout, filtered by level, and nothing else.
+ * [`init_tracing_opts`] takes [`TracingOptions`] to pick the output format,
+ * copy the application log to another writer, or route access-log events
+ * to an [`AccessLogSink`].
+ *
+ * A process has one global subscriber. A host application embedding this
+ * crate may already have installed its own, so installing is never a
+ * panic: it fails with [`TracingError::AlreadySet`], or with
+ * `skip_if_set` leaves the existing subscriber in place and reports
+ * [`TracingInit::Skipped`].
+ *
+ * The level filter comes from, in order of precedence:
+ *
+ * 1. `RUST_LOG`, as `EnvFilter` directives (`info,hyper=warn`)
+ * 2. `LOG_LEVEL`, a single level (`debug`) or `off`
+ * 3. [`TracingOptions::default_level`], `info` unless changed
+ *
+ * A variable that does not parse is skipped in favour of the next source,
+ * with a warning logged once the subscriber is installed. Access-log
+ * events are always kept out of the application log.
+ *
+ * The filter can be swapped while the process runs with [`set_filter`]
+ * (see [`crate::log_level`]); a subscriber installed by someone else keeps
+ * its own.
+ */
+use crate::access_log::{self, AccessLogSink};
+use std::fmt;
+use std::sync::OnceLock;
+use thiserror::Error;
+use tracing::warn;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::reload;
+use tracing_subscriber::util::TryInitError;
+use tracing_subscriber::{Layer, Registry};
+
+/// Filters of the subscriber [`init_tracing_opts`] installed
+struct Filters {
+    /// Directives it started with
+    startup: String,
+
+    /// One per formatting layer
+    handles: Vec<reload::Handle<EnvFilter, Registry>>,
+}
+
+static FILTERS: OnceLock<Filters> = OnceLock::new();
+
+/// How application log lines are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// One line per event with its span context
+    #[default]
+    Full,
+
+    /// One shorter line per event
+    Compact,
+
+    /// Multi-line, for reading in a terminal during development
+    Pretty,
+}
+
+/// What [`init_tracing_opts`] installs
+pub struct TracingOptions {
+    /// Leave an already installed global subscriber in place instead of
+    /// failing
+    pub skip_if_set: bool,
+
+    pub format: LogFormat,
+
+    /// Level used when neither `RUST_LOG` nor `LOG_LEVEL` is set
+    pub default_level: LevelFilter,
+
+    /// Also write the application log here, without colours
+    pub writer: Option<BoxMakeWriter>,
+
+    /// Sink for access-log events, if the binary writes an access log
+    pub access_log: Option<AccessLogSink>,
+}
+
+impl Default for TracingOptions {
+    fn default() -> Self {
+        Self {
+            skip_if_set: false,
+            format: LogFormat::default(),
+            default_level: LevelFilter::INFO,
+            writer: None,
+            access_log: None,
+        }
+    }
+}
+
+impl fmt::Debug for TracingOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TracingOptions")
+            .field("skip_if_set", &self.skip_if_set)
+            .field("format", &self.format)
+            .field("default_level", &self.default_level)
+            .field("writer", &self.writer.as_ref().map(|_| "<writer>"))
+            .field("access_log", &self.access_log)
+            .finish()
+    }
+}
+
+/// Outcome of a successful [`init_tracing_opts`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracingInit {
+    Installed,
+
+    /// Another subscriber was already installed and `skip_if_set` was given
+    Skipped,
+}
+
+#[derive(Debug, Error)]
+pub enum TracingError {
+    /// Another global subscriber is already installed
+    #[error("A global tracing subscriber is already installed")]
+    AlreadySet,
+
+    /// The subscriber could not be installed, e.g. because a `log` logger
+    /// is already set
+    #[error("Failed to install the tracing subscriber: {0}")]
+    Init(#[from] TryInitError),
+}
+
+/// Install the default subscriber: the application log on stdout
+pub fn init_tracing() -> Result<TracingInit, TracingError> {
+    init_tracing_opts(TracingOptions::default())
+}
+
+/// Install a subscriber as described by `options`
+pub fn init_tracing_opts(options: TracingOptions) -> Result<TracingInit, TracingError> {
+    if tracing::dispatcher::has_been_set() {
+        return if options.skip_if_set { Ok(TracingInit::Skipped) } else { Err(TracingError::AlreadySet) };
+    }
+
+    let (directives, problems) = filter_directives(
+        std::env::var("RUST_LOG").ok().as_deref(),
+        std::env::var("LOG_LEVEL").ok().as_deref(),
+        options.default_level,
+    );
+
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = Vec::new();
+    let mut handles = Vec::new();
+    let (layer, handle) = fmt_layer(options.format, std::io::stdout, false, &directives);
+    layers.push(layer);
+    handles.push(handle);
+    if let Some(writer) = options.writer {
+        let (layer, handle) = fmt_layer(options.format, writer, true, &directives);
+        layers.push(layer);
+        handles.push(handle);
+    }
+    if let Some(sink) = &options.access_log {
+        layers.push(sink.layer().boxed());
+    }
+
+    // Losing the race with another thread installing one counts as already set
+    if let Err(e) = tracing_subscriber::registry().with(layers).try_init() {
+        return match (tracing::dispatcher::has_been_set(), options.skip_if_set) {
+            (true, true) => Ok(TracingInit::Skipped),
+            (true, false) => Err(TracingError::AlreadySet),
+            _ => Err(e.into()),
+        };
+    }
+
+    let _ = FILTERS.set(Filters { startup: directives, handles });
+    for problem in problems {
+        warn!("{}", problem);
+    }
+    Ok(TracingInit::Installed)
+}
+
+/// Directives the installed subscriber started with; `None` unless
+/// [`init_tracing_opts`] installed it
+pub fn startup_filter() -> Option<&'static str> {
+    FILTERS.get().map(|filters| filters.startup.as_str())
+}
+
+/// Check that `directives` parse as `EnvFilter` directives
+pub fn parse_filter(directives: &str) -> Result<(), String> {
+    if directives.trim().is_empty() {
+        return Err("the filter is empty".to_string());
+    }
+    EnvFilter::try_new(directives).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// Filter the application log with `directives` from now on. Does nothing
+/// to a subscriber [`init_tracing_opts`] did not install.
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    parse_filter(directives)?;
+    for handle in FILTERS.get().map(|filters| filters.handles.as_slice()).unwrap_or_default() {
+        handle.reload(app_filter(directives)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Filter for the application log: `directives`, minus access-log events
+fn app_filter(directives: &str) -> EnvFilter {
+    // Parsed already, so only the access-log directive can be added here
+    EnvFilter::new(directives).add_directive(format!("{}=off", access_log::TARGET).parse().expect("valid directive"))
+}
+
+/// A formatting layer writing to `writer` and the handle swapping its
+/// filter; `plain` turns colours off, which are otherwise on unless
+/// `NO_COLOR` is set
+fn fmt_layer<W>(
+    format: LogFormat,
+    writer: W,
+    plain: bool,
+    directives: &str,
+) -> (Box<dyn Layer<Registry> + Send + Sync>, reload::Handle<EnvFilter, Registry>)
+where
+    W: for<'writer> tracing_subscriber::fmt::MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let (filter, handle) = reload::Layer::new(app_filter(directives));
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    let layer = if plain { layer.with_ansi(false) } else { layer };
+    let layer = match format {
+        LogFormat::Full => layer.with_filter(filter).boxed(),
+        LogFormat::Compact => layer.compact().with_filter(filter).boxed(),
+        LogFormat::Pretty => layer.pretty().with_filter(filter).boxed(),
+    };
+    (layer, handle)
+}
+
+/// Filter directives from `RUST_LOG`, else `LOG_LEVEL`, else `default`,
+/// with a note for each variable skipped because it does not parse
+fn filter_directives(rust_log: Option<&str>, log_level: Option<&str>, default: LevelFilter) -> (String, Vec<String>) {
+    let mut problems = Vec::new();
+
+    if let Some(directives) = rust_log.map(str::trim).filter(|value| !value.is_empty()) {
+        match EnvFilter::try_new(directives) {
+            Ok(_) => return (directives.to_string(), problems),
+            Err(e) => problems.push(format!("Ignoring RUST_LOG {:?}: {}", directives, e)),
+        }
+    }
+    if let Some(level) = log_level.map(str::trim).filter(|value| !value.is_empty()) {
+        match level.parse::<LevelFilter>() {
+            Ok(level) => return (level.to_string().to_ascii_lowercase(), problems),
+            Err(_) => problems.push(format!(
+                "Ignoring LOG_LEVEL {:?}: expected trace, debug, info, warn, error or off",
+                level
+            )),
+        }
+    }
+    (default.to_string().to_ascii_lowercase(), problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_precedence() {
+        let info = LevelFilter::INFO;
+        assert_eq!(filter_directives(Some("debug,hyper=warn"), Some("error"), info).0, "debug,hyper=warn");
+        assert_eq!(filter_directives(None, Some("WARN"), info).0, "warn");
+        assert_eq!(filter_directives(Some(" "), Some("off"), info).0, "off");
+        assert_eq!(filter_directives(None, None, LevelFilter::DEBUG), ("debug".to_string(), Vec::new()));
+
+        let (directives, problems) = filter_directives(Some("=[bad"), Some("loud"), info);
+        assert_eq!(directives, "info");
+        assert_eq!(problems.len(), 2);
+        assert!(problems[1].contains("LOG_LEVEL \"loud\""), "{:?}", problems);
+    }
+}
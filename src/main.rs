@@ -1,319 +1,238 @@
-use axum::{
-    http::{header, HeaderValue, Request},
-    middleware,
-    response::{Html, Json, Response},
-    routing::get,
-    Router,
-};
-use serde_json::{json, Value};
+use cloudflare_tunnel_example::access_log::AccessLogSink;
+use cloudflare_tunnel_example::healthcheck::Probe;
+use cloudflare_tunnel_example::logging::{self, TracingError, TracingOptions};
+use cloudflare_tunnel_example::self_test;
+use cloudflare_tunnel_example::shutdown::{self, ShutdownReport, ShutdownToken};
+use cloudflare_tunnel_example::{config::AppConfig, ServerError};
 use std::net::SocketAddr;
-use thiserror::Error;
-use tower::ServiceBuilder;
-use tower_http::set_header::SetResponseHeaderLayer;
-use tracing::{error, info};
+use std::time::Instant;
+use tracing::{error, info, warn};
 
-mod config;
-use config::SecurityConfig;
+const USAGE: &str = "\
+Usage: cloudflare-tunnel-example [--listen <ADDR>]
+       cloudflare-tunnel-example healthcheck [--listen <ADDR> | --url <URL> | --admin]
 
-#[derive(Debug, Error)]
-pub enum ServerError {
-    #[error("Failed to bind to address {addr}: {source}")]
-    BindError {
-        addr: SocketAddr,
-        #[source]
-        source: std::io::Error,
-    },
-    #[error("Server runtime error: {0}")]
-    RuntimeError(#[from] std::io::Error),
-    #[error("Configuration error: {0}")]
-    ConfigError(String),
-}
+Commands:
+  healthcheck      Probe /readyz; exit 0 when the service is ready, 1 otherwise
 
-pub type Result<T> = std::result::Result<T, ServerError>;
+Options:
+  --listen <ADDR>  Address for the main listener [default: 0.0.0.0:8080]
+  --url <URL>      healthcheck: probe this http:// URL instead
+  --admin          healthcheck: probe the admin listener (ADMIN_ADDR, ADMIN_TOKEN)
+  -h, --help       Print this help
+  -V, --version    Print the version
 
-#[tokio::main]
-async fn main() {
-    if let Err(e) = run_server().await {
-        error!("Fatal server error: {}", e);
-        std::process::exit(1);
-    }
+All other settings are read from environment variables (see docs/configuration.md).";
+
+#[derive(Debug)]
+struct Args {
+    listen: SocketAddr,
 }
 
-async fn run_server() -> Result<()> {
-    init_tracing();
-    
-    // Load security configuration
-    let security_config = SecurityConfig::from_env()?;
-    info!("Loaded security configuration with {} headers", security_config.to_headers().len());
-    
-    let app = create_app(security_config);
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-    
-    info!("Starting server on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(&addr)
-        .await
-        .map_err(|e| ServerError::BindError { addr, source: e })?;
-    
-    info!("Server successfully bound to {}", addr);
-    
-    axum::serve(listener, app)
-        .await
-        .map_err(ServerError::RuntimeError)?;
-    
-    Ok(())
+/// What `healthcheck` probes
+#[derive(Debug, PartialEq, Eq)]
+enum Target {
+    Listener(SocketAddr),
+    Url(String),
+    Admin,
 }
 
-fn init_tracing() {
-    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| "info".into());
-    
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .init();
+enum Command {
+    Run(Args),
+    Healthcheck(Target),
+    Help,
+    Version,
 }
 
-async fn hello_world() -> Html<&'static str> {
-    Html("<h1>Hello World</h1><p>Cloudflare Tunnel Example - Rust Axum Service</p>")
+fn parse_listen(value: Option<String>) -> Result<SocketAddr, String> {
+    let value = value.ok_or("--listen requires an address")?;
+    value
+        .parse()
+        .map_err(|e| format!("Invalid --listen address {:?}: {}", value, e))
 }
 
-async fn health_check() -> Json<Value> {
-    Json(json!({
-        "status": "healthy",
-        "service": "cloudflare-tunnel-example",
-        "timestamp": chrono::Utc::now().to_rfc3339()
-    }))
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut parsed = Args {
+        listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            "--listen" => parsed.listen = parse_listen(args.next())?,
+            "healthcheck" => return parse_healthcheck(parsed.listen, args),
+            other => return Err(format!("Unknown argument {:?}", other)),
+        }
+    }
+
+    Ok(Command::Run(parsed))
 }
 
-pub fn create_app(security_config: SecurityConfig) -> Router {
-    // Clone security config for use in middleware
-    let config_for_middleware = security_config.clone();
-    
-    Router::new()
-        .route("/", get(hello_world))
-        .route("/health", get(health_check))
-        .layer(
-            ServiceBuilder::new()
-                .layer(middleware::from_fn(move |req, next| {
-                    let config = config_for_middleware.clone();
-                    security_headers(req, next, config)
-                }))
-                .layer(SetResponseHeaderLayer::if_not_present(
-                    header::SERVER,
-                    HeaderValue::from_str(&security_config.server_header)
-                        .unwrap_or_else(|_| HeaderValue::from_static("cloudflare-tunnel-example")),
-                )),
-        )
+fn parse_healthcheck(listen: SocketAddr, mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut target = Target::Listener(listen);
+    while let Some(arg) = args.next() {
+        target = match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--listen" => Target::Listener(parse_listen(args.next())?),
+            "--url" => Target::Url(args.next().ok_or("--url requires a URL")?),
+            "--admin" => Target::Admin,
+            other => return Err(format!("Unknown healthcheck argument {:?}", other)),
+        };
+    }
+    Ok(Command::Healthcheck(target))
 }
 
-async fn security_headers(
-    request: Request<axum::body::Body>,
-    next: axum::middleware::Next,
-    config: SecurityConfig,
-) -> Response {
-    let mut response = next.run(request).await;
+#[tokio::main]
+async fn main() {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Command::Run(args)) => args,
+        Ok(Command::Healthcheck(target)) => std::process::exit(healthcheck(target).await),
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("cloudflare-tunnel-example {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            std::process::exit(shutdown::EXIT_CONFIG.into());
+        }
+    };
 
-    let headers = response.headers_mut();
+    // The shutdown report, already logged, carries any error
+    let result = run_server(args).await;
+    std::process::exit(shutdown::exit_code(&result).into());
+}
 
-    // Apply all configured security headers
-    let security_headers = config.to_headers();
-    
-    // Insert each header using static string literals for known headers
-    if let Some(value) = security_headers.get("X-Content-Type-Options") {
-        if let Ok(header_value) = HeaderValue::from_str(value) {
-            headers.insert("X-Content-Type-Options", header_value);
-        }
+async fn run_server(args: Args) -> cloudflare_tunnel_example::Result<()> {
+    let started_at = Instant::now();
+    let access_log = AccessLogSink::default();
+    init_tracing(&access_log);
+    // Watched from here on, so a signal during startup stops it cleanly
+    let shutdown = ShutdownToken::on_signals();
+
+    // `serve` reports its own shutdown; failures before it are reported here
+    let failed = |e: ServerError| {
+        ShutdownReport::new(started_at.elapsed(), 0, Some(&e), None).log();
+        e
+    };
+    // Nothing is serving yet, so stopping now is a clean exit
+    let stopped = || {
+        access_log.flush();
+        ShutdownReport::new(started_at.elapsed(), 0, None, shutdown.signal()).log();
+        Ok(())
+    };
+
+    if shutdown.startup_aborted("configuration") {
+        return stopped();
     }
-    
-    if let Some(value) = security_headers.get("X-Frame-Options") {
-        if let Ok(header_value) = HeaderValue::from_str(value) {
-            headers.insert("X-Frame-Options", header_value);
+    let config = AppConfig::from_env().map_err(failed)?;
+    if config.self_test_on_startup {
+        if shutdown.startup_aborted("self-test") {
+            return stopped();
         }
-    }
-    
-    if let Some(value) = security_headers.get("X-XSS-Protection") {
-        if let Ok(header_value) = HeaderValue::from_str(value) {
-            headers.insert("X-XSS-Protection", header_value);
+        let report = self_test::run(&config, &self_test::CHECKS).await;
+        if report.passed() {
+            info!("{}", report);
+        } else {
+            error!("{}", report);
         }
+        report.into_result().map_err(failed)?;
     }
-    
-    if let Some(value) = security_headers.get("Strict-Transport-Security") {
-        if let Ok(header_value) = HeaderValue::from_str(value) {
-            headers.insert("Strict-Transport-Security", header_value);
-        }
+    if let Some(access_log_config) = &config.access_log {
+        access_log.open(access_log_config).map_err(failed)?;
     }
-    
-    if let Some(value) = security_headers.get("Content-Security-Policy") {
-        if let Ok(header_value) = HeaderValue::from_str(value) {
-            headers.insert("Content-Security-Policy", header_value);
-        }
+
+    if shutdown.startup_aborted("listener bind") {
+        return stopped();
     }
-    
-    if let Some(value) = security_headers.get("Referrer-Policy") {
-        if let Ok(header_value) = HeaderValue::from_str(value) {
-            headers.insert("Referrer-Policy", header_value);
+    info!("Starting server on {}", args.listen);
+    let listener = tokio::net::TcpListener::bind(&args.listen)
+        .await
+        .map_err(|e| failed(ServerError::BindError { addr: args.listen, source: e }))?;
+
+    let result = cloudflare_tunnel_example::serve_with_token(config, listener, shutdown).await;
+    access_log.flush();
+    result
+}
+
+/// Probe `target` without loading the configuration or setting up
+/// logging; the exit code is 0 when it is ready
+async fn healthcheck(target: Target) -> i32 {
+    let probe = match target {
+        Target::Listener(listen) => Probe::listener(listen),
+        Target::Url(url) => Probe::new(url),
+        Target::Admin => match Probe::admin_from_env() {
+            Ok(probe) => probe,
+            Err(e) => {
+                eprintln!("unhealthy: {}", e);
+                return 1;
+            }
+        },
+    };
+    match probe.run().await {
+        Ok(()) => 0,
+        Err(reason) => {
+            eprintln!("unhealthy: {}", reason);
+            1
         }
     }
-    
-    if let Some(value) = security_headers.get("Permissions-Policy") {
-        if let Ok(header_value) = HeaderValue::from_str(value) {
-            headers.insert("Permissions-Policy", header_value);
+}
+
+/// Application logs to stdout; access-log events only to `access_log`.
+/// Logging set up by someone else is kept, with a warning through it.
+fn init_tracing(access_log: &AccessLogSink) {
+    let options = TracingOptions { access_log: Some(access_log.clone()), ..TracingOptions::default() };
+    match logging::init_tracing_opts(options) {
+        Ok(_) => {}
+        Err(TracingError::AlreadySet) => {
+            warn!("A tracing subscriber is already installed; logging through it, without the access log");
         }
+        Err(e) => eprintln!("{}; continuing without it", e),
     }
-
-    response
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::body::Body;
-    use axum::http::{Request, StatusCode};
-    use tower::util::ServiceExt;
-    
-    async fn make_request(uri: &str) -> (StatusCode, String) {
-        let app = create_app(SecurityConfig::default());
-        let request = Request::builder()
-            .uri(uri)
-            .body(Body::empty())
-            .expect("Failed to build test request");
-        
-        let response = app
-            .oneshot(request)
-            .await
-            .expect("Failed to get response from app");
-        
-        let status = response.status();
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .expect("Failed to read response body");
-        let body_str = String::from_utf8(body.to_vec())
-            .expect("Response body was not valid UTF-8");
-        
-        (status, body_str)
-    }
-    
-    async fn make_request_with_headers(uri: &str) -> (StatusCode, String, axum::http::HeaderMap) {
-        let app = create_app(SecurityConfig::default());
-        let request = Request::builder()
-            .uri(uri)
-            .body(Body::empty())
-            .expect("Failed to build test request");
-        
-        let response = app
-            .oneshot(request)
-            .await
-            .expect("Failed to get response from app");
-        
-        let status = response.status();
-        let headers = response.headers().clone();
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .expect("Failed to read response body");
-        let body_str = String::from_utf8(body.to_vec())
-            .expect("Response body was not valid UTF-8");
-        
-        (status, body_str, headers)
-    }
 
-    #[tokio::test]
-    async fn test_root_endpoint() {
-        let (status, body) = make_request("/").await;
-        
-        assert_eq!(status, StatusCode::OK);
-        assert!(body.contains("Hello World"));
-        assert!(body.contains("Cloudflare Tunnel Example"));
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
     }
 
-    #[tokio::test]
-    async fn test_health_endpoint() {
-        let (status, body) = make_request("/health").await;
-        
-        assert_eq!(status, StatusCode::OK);
-        
-        let json: serde_json::Value = serde_json::from_str(&body)
-            .expect("Response was not valid JSON");
-        
-        assert_eq!(json["status"], "healthy");
-        assert_eq!(json["service"], "cloudflare-tunnel-example");
-        assert!(json["timestamp"].is_string());
+    #[test]
+    fn test_default_listen_address() {
+        match parse(&[]) {
+            Ok(Command::Run(args)) => assert_eq!(args.listen, SocketAddr::from(([0, 0, 0, 0], 8080))),
+            _ => panic!("Expected run command"),
+        }
     }
 
-    #[tokio::test]
-    async fn test_security_headers() {
-        let (_status, _body, headers) = make_request_with_headers("/").await;
-        
-        assert_eq!(
-            headers.get("x-content-type-options")
-                .expect("Missing X-Content-Type-Options header"), 
-            "nosniff"
-        );
-        assert_eq!(
-            headers.get("x-frame-options")
-                .expect("Missing X-Frame-Options header"), 
-            "DENY"
-        );
-        assert_eq!(
-            headers.get("x-xss-protection")
-                .expect("Missing X-XSS-Protection header"), 
-            "1; mode=block"
-        );
-        
-        let hsts_header = headers.get("strict-transport-security")
-            .expect("Missing Strict-Transport-Security header")
-            .to_str()
-            .expect("HSTS header was not valid UTF-8");
-        assert!(hsts_header.contains("max-age=31536000"));
-        
-        assert!(headers.get("content-security-policy").is_some());
-        
-        assert_eq!(
-            headers.get("referrer-policy")
-                .expect("Missing Referrer-Policy header"), 
-            "strict-origin-when-cross-origin"
-        );
-        assert_eq!(
-            headers.get("permissions-policy")
-                .expect("Missing Permissions-Policy header"), 
-            "geolocation=(), microphone=(), camera=()"
-        );
+    #[test]
+    fn test_listen_flag() {
+        match parse(&["--listen", "127.0.0.1:3000"]) {
+            Ok(Command::Run(args)) => assert_eq!(args.listen.port(), 3000),
+            _ => panic!("Expected run command"),
+        }
+        assert!(parse(&["--listen"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
     }
 
-    #[tokio::test]
-    async fn test_404_not_found() {
-        let (status, _body) = make_request("/nonexistent").await;
-        
-        assert_eq!(status, StatusCode::NOT_FOUND);
-    }
-    
-    #[tokio::test]
-    async fn test_configurable_security_headers() {
-        // Test with custom security configuration
-        let mut config = SecurityConfig::default();
-        config.frame_options = "SAMEORIGIN".to_string();
-        config.hsts.max_age = 3600; // 1 hour instead of default 1 year
-        config.hsts.include_subdomains = false;
-        
-        let app = create_app(config);
-        let response = app
-            .oneshot(Request::builder().uri("/").body(Body::empty()).expect("Failed to build request"))
-            .await
-            .expect("Failed to get response");
-        
-        let headers = response.headers();
-        
-        // Verify custom values are applied
-        assert_eq!(
-            headers.get("x-frame-options").expect("Missing X-Frame-Options header"), 
-            "SAMEORIGIN"
-        );
-        
-        let hsts_header = headers.get("strict-transport-security")
-            .expect("Missing HSTS header")
-            .to_str()
-            .expect("HSTS header not valid UTF-8");
-        assert!(hsts_header.contains("max-age=3600"));
-        assert!(!hsts_header.contains("includeSubDomains"));
-        assert!(hsts_header.contains("preload")); // Should still be true by default
+    #[test]
+    fn test_healthcheck_targets() {
+        let target = |args: &[&str]| match parse(args) {
+            Ok(Command::Healthcheck(target)) => target,
+            _ => panic!("Expected healthcheck command"),
+        };
+        assert_eq!(target(&["healthcheck"]), Target::Listener(SocketAddr::from(([0, 0, 0, 0], 8080))));
+        assert_eq!(target(&["--listen", "0.0.0.0:3000", "healthcheck"]), Target::Listener("0.0.0.0:3000".parse().unwrap()));
+        assert_eq!(target(&["healthcheck", "--listen", "[::]:3000"]), Target::Listener("[::]:3000".parse().unwrap()));
+        assert_eq!(target(&["healthcheck", "--url", "http://127.0.0.1:8080/health"]), Target::Url("http://127.0.0.1:8080/health".to_string()));
+        assert_eq!(target(&["healthcheck", "--admin"]), Target::Admin);
+        assert!(parse(&["healthcheck", "--url"]).is_err());
+        assert!(parse(&["healthcheck", "--bogus"]).is_err());
     }
 }
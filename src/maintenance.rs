@@ -0,0 +1,258 @@
+/*!
+ * Maintenance mode
+ *
+ * While maintenance is on, every route except the probes in `EXEMPT_PATHS`
+ * answers `503 Service Unavailable` with `Retry-After`, as JSON with the
+ * maintenance message (shown to browsers on the 503 error page). It can be
+ * switched on three ways, without a restart:
+ *
+ * - `MAINTENANCE_MODE=true` at startup
+ * - `POST /admin/maintenance {"enabled": true, "message": "..."}` on the
+ *   admin listener
+ * - creating the file named by `MAINTENANCE_FILE`, which is checked every
+ *   `MAINTENANCE_FILE_POLL_SECS` by a supervised task (see [`crate::tasks`])
+ *
+ * The file is tracked separately from the other two: maintenance is on
+ * while either says so, and turning it off through the admin API leaves a
+ * present file in charge. `/readyz` reports the state but stays ready, so
+ * Cloudflare keeps routing visitors to the maintenance page rather than to
+ * its own error page. Changes made through the admin API or the file are
+ * sent as notifications (see [`crate::notifications`]).
+ */
+use crate::denial::{Denial, DenialReason};
+use crate::state::AppState;
+use crate::tasks::Schedule;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::info;
+use utoipa::ToSchema;
+
+/// Paths that keep working during maintenance so probes see the real state
+pub const EXEMPT_PATHS: &[&str] = &["/health", "/readyz", "/status", "/metrics"];
+
+const DEFAULT_MESSAGE: &str = "The service is undergoing maintenance. Please try again shortly.";
+
+/// Startup state, advertised retry delay and sentinel file
+#[derive(Debug, Clone)]
+pub struct MaintenanceConfig {
+    /// Start in maintenance mode
+    pub enabled: bool,
+
+    /// Message shown when none is given through the admin API
+    pub message: String,
+
+    /// Sent as `Retry-After`
+    pub retry_after: Duration,
+
+    /// Maintenance is on while this file exists
+    pub sentinel: Option<PathBuf>,
+
+    /// How often the sentinel file is checked
+    pub poll_interval: Duration,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            message: DEFAULT_MESSAGE.to_string(),
+            retry_after: Duration::from_secs(300),
+            sentinel: None,
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    /// Load from `MAINTENANCE_MODE`, `MAINTENANCE_MESSAGE`,
+    /// `MAINTENANCE_RETRY_AFTER_SECS`, `MAINTENANCE_FILE` and
+    /// `MAINTENANCE_FILE_POLL_SECS`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+
+        if let Ok(value) = std::env::var("MAINTENANCE_MODE") {
+            config.enabled = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid MAINTENANCE_MODE: {}", e)
+                ))?;
+        }
+        if let Ok(message) = std::env::var("MAINTENANCE_MESSAGE") {
+            if !message.trim().is_empty() {
+                config.message = message;
+            }
+        }
+        if let Ok(value) = std::env::var("MAINTENANCE_RETRY_AFTER_SECS") {
+            let secs: u64 = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid MAINTENANCE_RETRY_AFTER_SECS: {}", e)
+                ))?;
+            config.retry_after = Duration::from_secs(secs);
+        }
+        config.sentinel = std::env::var("MAINTENANCE_FILE").ok().filter(|v| !v.is_empty()).map(PathBuf::from);
+        if let Ok(value) = std::env::var("MAINTENANCE_FILE_POLL_SECS") {
+            let secs: u64 = value.parse()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .ok_or_else(|| crate::ServerError::ConfigError(format!(
+                    "Invalid MAINTENANCE_FILE_POLL_SECS: {:?} is not a positive number of seconds",
+                    value
+                )))?;
+            config.poll_interval = Duration::from_secs(secs);
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Flags {
+    /// Set at startup or through the admin API
+    manual: bool,
+
+    /// The sentinel file exists
+    file: bool,
+
+    /// Message from the admin API, replacing the configured one
+    message: Option<String>,
+}
+
+/// Whether maintenance is on; clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct MaintenanceState {
+    flags: Arc<RwLock<Flags>>,
+}
+
+/// Snapshot reported by `/readyz` and the admin API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, ToSchema)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+
+    /// Switched on at startup or through the admin API
+    pub manual: bool,
+
+    /// Switched on by the sentinel file
+    pub file: bool,
+
+    /// Custom message set through the admin API, if any
+    pub message: Option<String>,
+}
+
+impl MaintenanceState {
+    /// State starting on or off
+    pub fn new(enabled: bool) -> Self {
+        let state = Self::default();
+        state.set(enabled, None);
+        state
+    }
+
+    /// Switch the manual flag, replacing the admin message
+    pub fn set(&self, enabled: bool, message: Option<String>) {
+        let mut flags = self.flags.write().unwrap_or_else(|e| e.into_inner());
+        flags.manual = enabled;
+        flags.message = message.filter(|_| enabled);
+    }
+
+    /// Record whether the sentinel file exists
+    pub fn set_file(&self, present: bool) {
+        self.flags.write().unwrap_or_else(|e| e.into_inner()).file = present;
+    }
+
+    /// Whether requests should get the maintenance response
+    pub fn is_enabled(&self) -> bool {
+        let flags = self.flags.read().unwrap_or_else(|e| e.into_inner());
+        flags.manual || flags.file
+    }
+
+    /// Current flags and message
+    pub fn status(&self) -> MaintenanceStatus {
+        let flags = self.flags.read().unwrap_or_else(|e| e.into_inner());
+        MaintenanceStatus {
+            enabled: flags.manual || flags.file,
+            manual: flags.manual,
+            file: flags.file,
+            message: flags.message.clone(),
+        }
+    }
+}
+
+/// Name of the task polling the sentinel file
+pub const SENTINEL_TASK: &str = "maintenance-sentinel";
+
+/// Register a task polling the configured sentinel file with `state.tasks`
+pub fn watch_sentinel(state: &AppState) {
+    let config = state.config().maintenance.clone();
+    let Some(path) = config.sentinel else {
+        return;
+    };
+
+    let polled = state.clone();
+    state.tasks.spawn(SENTINEL_TASK, Schedule::every(config.poll_interval), move || {
+        let (path, state) = (path.clone(), polled.clone());
+        async move { check_sentinel(&path, &state).await }
+    });
+}
+
+/// Follow the sentinel file once; a file that cannot be checked counts as
+/// absent
+async fn check_sentinel(path: &Path, state: &AppState) -> Result<(), String> {
+    let maintenance = &state.maintenance;
+    let checked = tokio::fs::try_exists(path).await;
+    let present = *checked.as_ref().unwrap_or(&false);
+    if present != maintenance.status().file {
+        info!(
+            "Maintenance file {} {}",
+            path.display(),
+            if present { "appeared; maintenance on" } else { "removed" }
+        );
+        let was_enabled = maintenance.is_enabled();
+        maintenance.set_file(present);
+        crate::notifications::maintenance_changed(state, was_enabled);
+    }
+    checked
+        .map(|_| ())
+        .map_err(|e| format!("Failed to check maintenance file {}: {}", path.display(), e))
+}
+
+/// Middleware answering non-exempt requests with 503 while maintenance is on
+pub async fn enforce(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !state.maintenance.is_enabled() || EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let config = state.config();
+    let message = state.maintenance.status().message.unwrap_or_else(|| config.maintenance.message.clone());
+
+    let mut response = Denial::new(DenialReason::Maintenance, message)
+        .with_retry_after(config.maintenance.retry_after)
+        .into_response();
+    response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_and_file_flags_are_independent() {
+        let state = MaintenanceState::new(false);
+        state.set_file(true);
+        state.set(true, Some("upgrading".to_string()));
+        assert!(state.is_enabled());
+
+        state.set(false, None);
+        assert!(state.is_enabled(), "the file keeps maintenance on");
+        assert_eq!(state.status().message, None);
+
+        state.set_file(false);
+        assert!(!state.is_enabled());
+    }
+}
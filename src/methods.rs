@@ -0,0 +1,72 @@
+/*!
+ * `405 Method Not Allowed` and `OPTIONS`
+ *
+ * axum answers a known path requested with an unregistered method with a
+ * bare 405 and adds `Allow` listing the registered methods after the
+ * route's middleware has run. This middleware wraps the whole router so it
+ * sees that header, and then:
+ *
+ * - turns the 405 into the usual JSON error naming the allowed methods
+ * - answers `OPTIONS` on the path with 204 and the same `Allow`
+ *
+ * `OPTIONS` is added to `Allow` in both cases. Unknown paths still get 404,
+ * and paths that accept any method (the proxy, `/echo`) never reach here.
+ *
+ * axum serves `HEAD` with the `GET` handler and drops the body. Streaming
+ * routes register [`reject_head`] instead, so a `HEAD` does not start a
+ * stream only to throw it away.
+ */
+use crate::error::ApiError;
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+/// Middleware completing axum's 405 responses and answering `OPTIONS`
+pub async fn allowed_methods(request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+
+    // A handler's own 405 has no `Allow` and is left as it is
+    let Some(allow) = response.headers().get(header::ALLOW).and_then(|v| v.to_str().ok()) else {
+        return response;
+    };
+    let mut methods: Vec<&str> = allow.split(',').map(str::trim).filter(|m| !m.is_empty()).collect();
+    if !methods.contains(&Method::OPTIONS.as_str()) {
+        methods.push(Method::OPTIONS.as_str());
+    }
+    let allow = methods.join(", ");
+    let Ok(allow_value) = HeaderValue::from_str(&allow) else {
+        return response;
+    };
+
+    let mut response = if method == Method::OPTIONS {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            format!("{} is not allowed here; allowed methods: {}", method, allow),
+        )
+        .into_response()
+    };
+    response.headers_mut().insert(header::ALLOW, allow_value);
+    response
+}
+
+/// `HEAD` handler for `GET` routes whose body is an open-ended stream
+pub async fn reject_head() -> Response {
+    let mut response = ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        "HEAD is not allowed here; allowed methods: GET, OPTIONS",
+    )
+    .into_response();
+    response.headers_mut().insert(header::ALLOW, HeaderValue::from_static("GET, OPTIONS"));
+    response
+}
@@ -0,0 +1,460 @@
+/*!
+ * Process-local counters in Prometheus text format
+ *
+ * Subsystems ask `Metrics` for a named `Counter` (or `Gauge`) once and
+ * update it on the hot path without locking. `/metrics` renders every
+ * registered metric in the Prometheus exposition format, or in OpenMetrics
+ * when the scraper's `Accept` prefers `application/openmetrics-text`.
+ *
+ * A name may carry labels, e.g. `http_requests_by_method_total{method="GET"}`;
+ * series sharing the part before `{` are rendered as one family under a
+ * single `HELP`/`TYPE` header. A `Histogram` renders as the usual
+ * cumulative `_bucket{le="..."}` series plus `_sum` and `_count`.
+ *
+ * OpenMetrics differs in three ways here: counter families are named
+ * without their `_total` suffix (a counter whose name lacks it is typed
+ * `unknown`), the body ends with `# EOF`, and histogram buckets show the
+ * [`Exemplar`] of their latest observation that had one, such as the trace
+ * of a slow request.
+ */
+use axum::{
+    extract::{FromRef, State},
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bucket bounds for latency histograms in milliseconds
+pub const LATENCY_BUCKETS_MS: &[u64] = &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10_000];
+
+/// Text formats `/metrics` answers in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exposition {
+    Prometheus,
+    OpenMetrics,
+}
+
+impl Exposition {
+    /// `Content-Type` of the format
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Prometheus => "text/plain; version=0.0.4",
+            Self::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+
+    /// Format `Accept` ranks highest, Prometheus text without a preference
+    pub fn negotiate(headers: &HeaderMap) -> Self {
+        match crate::negotiate::preferred(headers, &["text/plain", "application/openmetrics-text"]) {
+            Some("application/openmetrics-text") => Self::OpenMetrics,
+            _ => Self::Prometheus,
+        }
+    }
+}
+
+/// An observation shown next to its histogram bucket in OpenMetrics
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    /// Label set as rendered, e.g. `trace_id="..."`
+    labels: String,
+    value: u64,
+
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+}
+
+impl Exemplar {
+    /// Exemplar for `value` observed now, labelled with `labels`
+    pub fn new(labels: &[(&str, &str)], value: u64) -> Self {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| {
+                let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+                format!("{}=\"{}\"", name, escaped)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |since| since.as_secs_f64());
+        Self { labels, value, timestamp }
+    }
+}
+
+/// Monotonic counter handle; clones share the same value
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    /// Add one
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Add `n`
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Current value
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value that goes up and down, e.g. open connections; clones share the
+/// same value
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    /// Add one
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Subtract one
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Replace the value
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    /// Current value
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Distribution of observed values over fixed bucket bounds; clones share
+/// the same counts
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [u64],
+    inner: Arc<HistogramCounts>,
+}
+
+#[derive(Debug)]
+struct HistogramCounts {
+    /// One per bound, plus the `+Inf` bucket; not cumulative
+    buckets: Vec<AtomicU64>,
+
+    /// Latest exemplar per bucket
+    exemplars: Vec<Mutex<Option<Exemplar>>>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            inner: Arc::new(HistogramCounts {
+                buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+                exemplars: (0..=bounds.len()).map(|_| Mutex::new(None)).collect(),
+                sum: AtomicU64::new(0),
+                count: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Record one value
+    pub fn observe(&self, value: u64) {
+        self.record(value);
+    }
+
+    /// Record one value with labels such as a trace ID, kept as its
+    /// bucket's exemplar until the next one
+    pub fn observe_with_exemplar(&self, value: u64, labels: &[(&str, &str)]) {
+        let index = self.record(value);
+        if let Ok(mut exemplar) = self.inner.exemplars[index].lock() {
+            *exemplar = Some(Exemplar::new(labels, value));
+        }
+    }
+
+    /// Count `value`, returning its bucket
+    fn record(&self, value: u64) -> usize {
+        let index = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.inner.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.inner.sum.fetch_add(value, Ordering::Relaxed);
+        self.inner.count.fetch_add(1, Ordering::Relaxed);
+        index
+    }
+
+    /// Values recorded so far
+    pub fn count(&self) -> u64 {
+        self.inner.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of the values recorded so far
+    pub fn sum(&self) -> u64 {
+        self.inner.sum.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, name: &str, out: &mut String, exposition: Exposition) {
+        // Labels of a series like `family{host="a"}` go on every line
+        let (family, labels) = match name.split_once('{') {
+            Some((family, labels)) => (family, labels.trim_end_matches('}')),
+            None => (name, ""),
+        };
+        let (all, le_prefix) = match labels {
+            "" => (String::new(), String::new()),
+            labels => (format!("{{{}}}", labels), format!("{},", labels)),
+        };
+
+        let mut cumulative = 0;
+        for (index, bucket) in self.inner.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = self.bounds.get(index).map_or("+Inf".to_string(), u64::to_string);
+            let _ = write!(out, "{}_bucket{{{}le=\"{}\"}} {}", family, le_prefix, le, cumulative);
+            if exposition == Exposition::OpenMetrics {
+                let exemplar = self.inner.exemplars[index].lock().ok().and_then(|exemplar| exemplar.clone());
+                if let Some(exemplar) = exemplar {
+                    let _ = write!(out, " # {{{}}} {} {:.3}", exemplar.labels, exemplar.value, exemplar.timestamp);
+                }
+            }
+            out.push('\n');
+        }
+        let _ = writeln!(out, "{}_sum{} {}", family, all, self.sum());
+        let _ = writeln!(out, "{}_count{} {}", family, all, self.count());
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Value {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+#[derive(Debug)]
+struct Entry {
+    help: &'static str,
+    value: Value,
+}
+
+/// Registry of named counters and gauges; clones share the same registry
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    counters: Arc<RwLock<BTreeMap<String, Entry>>>,
+}
+
+impl Metrics {
+    /// Counter registered under `name`, created on first use
+    pub fn counter(&self, name: &str, help: &'static str) -> Counter {
+        match self.entry(name, help, || Value::Counter(Counter::default())) {
+            Some(Value::Counter(counter)) => counter,
+            // A gauge already owns the name; hand out a detached counter
+            _ => Counter::default(),
+        }
+    }
+
+    /// Gauge registered under `name`, created on first use
+    pub fn gauge(&self, name: &str, help: &'static str) -> Gauge {
+        match self.entry(name, help, || Value::Gauge(Gauge::default())) {
+            Some(Value::Gauge(gauge)) => gauge,
+            _ => Gauge::default(),
+        }
+    }
+
+    /// Histogram registered under `name` with bucket upper `bounds`
+    /// (ascending), created on first use
+    pub fn histogram(&self, name: &str, help: &'static str, bounds: &'static [u64]) -> Histogram {
+        match self.entry(name, help, || Value::Histogram(Histogram::new(bounds))) {
+            Some(Value::Histogram(histogram)) => histogram,
+            _ => Histogram::new(bounds),
+        }
+    }
+
+    fn entry(&self, name: &str, help: &'static str, create: impl FnOnce() -> Value) -> Option<Value> {
+        if let Some(value) = self.counters.read().ok().and_then(|c| c.get(name).map(|e| e.value.clone())) {
+            return Some(value);
+        }
+
+        let mut counters = self.counters.write().ok()?;
+        Some(
+            counters
+                .entry(name.to_string())
+                .or_insert_with(|| Entry { help, value: create() })
+                .value
+                .clone(),
+        )
+    }
+
+    /// Prometheus text exposition of every counter and gauge
+    pub fn render(&self) -> String {
+        self.render_as(Exposition::Prometheus)
+    }
+
+    /// Every metric in `exposition`
+    pub fn render_as(&self, exposition: Exposition) -> String {
+        let mut out = String::new();
+        let Ok(counters) = self.counters.read() else {
+            return out;
+        };
+
+        // Labelled series of one family must be listed together
+        let mut families: BTreeMap<&str, Vec<(&String, &Entry)>> = BTreeMap::new();
+        for (name, entry) in counters.iter() {
+            let family = name.split_once('{').map_or(name.as_str(), |(family, _)| family);
+            families.entry(family).or_default().push((name, entry));
+        }
+
+        for (family, series) in families {
+            let (_, first) = series[0];
+            let (family, kind) = match (&first.value, exposition) {
+                (Value::Counter(_), Exposition::Prometheus) => (family, "counter"),
+                (Value::Counter(_), Exposition::OpenMetrics) => match family.strip_suffix("_total") {
+                    Some(family) => (family, "counter"),
+                    None => (family, "unknown"),
+                },
+                (Value::Gauge(_), _) => (family, "gauge"),
+                (Value::Histogram(_), _) => (family, "histogram"),
+            };
+            let _ = writeln!(out, "# HELP {} {}", family, first.help);
+            let _ = writeln!(out, "# TYPE {} {}", family, kind);
+            for (name, entry) in series {
+                let value = match &entry.value {
+                    Value::Counter(counter) => counter.get().to_string(),
+                    Value::Gauge(gauge) => gauge.get().to_string(),
+                    Value::Histogram(histogram) => {
+                        histogram.render(name, &mut out, exposition);
+                        continue;
+                    }
+                };
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+        }
+        if exposition == Exposition::OpenMetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+}
+
+/// Prometheus metrics
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = OK, description = "Text or OpenMetrics exposition format", body = String, content_type = "text/plain")),
+)]
+async fn metrics_handler(State(metrics): State<Metrics>, headers: HeaderMap) -> impl IntoResponse {
+    let exposition = Exposition::negotiate(&headers);
+    (
+        [(header::CONTENT_TYPE, exposition.content_type()), (header::VARY, "accept")],
+        metrics.render_as(exposition),
+    )
+}
+
+/// `/metrics`, rendering the state's `Metrics`
+pub fn routes<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    Metrics: FromRef<S>,
+{
+    Router::new().route("/metrics", get(metrics_handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_is_shared_by_name() {
+        let metrics = Metrics::default();
+        metrics.counter("hits_total", "Hits").inc();
+        metrics.counter("hits_total", "Hits").inc();
+
+        assert_eq!(metrics.counter("hits_total", "Hits").get(), 2);
+    }
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.counter("hits_total", "Hits seen").inc();
+
+        assert_eq!(
+            metrics.render(),
+            "# HELP hits_total Hits seen\n# TYPE hits_total counter\nhits_total 1\n"
+        );
+    }
+
+    #[test]
+    fn test_labelled_series_share_a_family() {
+        let metrics = Metrics::default();
+        metrics.counter("req_total{method=\"HEAD\"}", "Requests").inc();
+        metrics.counter("req_total{method=\"GET\"}", "Requests").inc();
+        metrics.counter("req_total_other", "Other").inc();
+
+        assert_eq!(
+            metrics.render(),
+            "# HELP req_total Requests\n# TYPE req_total counter\n\
+             req_total{method=\"GET\"} 1\nreq_total{method=\"HEAD\"} 1\n\
+             # HELP req_total_other Other\n# TYPE req_total_other counter\nreq_total_other 1\n"
+        );
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let metrics = Metrics::default();
+        let histogram = metrics.histogram("size_bytes", "Sizes", &[10, 100]);
+        histogram.observe(5);
+        histogram.observe(50);
+        histogram.observe(500);
+
+        assert_eq!(
+            metrics.render(),
+            "# HELP size_bytes Sizes\n# TYPE size_bytes histogram\n\
+             size_bytes_bucket{le=\"10\"} 1\nsize_bytes_bucket{le=\"100\"} 2\n\
+             size_bytes_bucket{le=\"+Inf\"} 3\nsize_bytes_sum 555\nsize_bytes_count 3\n"
+        );
+    }
+
+    #[test]
+    fn test_render_openmetrics() {
+        let metrics = Metrics::default();
+        metrics.counter("hits_total", "Hits seen").inc();
+        metrics.counter("misses", "Misses seen").inc();
+        let histogram = metrics.histogram("size_bytes", "Sizes", &[10, 100]);
+        histogram.observe(5);
+        histogram.observe_with_exemplar(50, &[("trace_id", "abc\"d")]);
+
+        let text = metrics.render_as(Exposition::OpenMetrics);
+        let (head, exemplar) = text.split_once(" # {").expect("No exemplar");
+        assert_eq!(
+            head,
+            "# HELP hits Hits seen\n# TYPE hits counter\nhits_total 1\n\
+             # HELP misses Misses seen\n# TYPE misses unknown\nmisses 1\n\
+             # HELP size_bytes Sizes\n# TYPE size_bytes histogram\n\
+             size_bytes_bucket{le=\"10\"} 1\nsize_bytes_bucket{le=\"100\"} 2"
+        );
+        assert!(exemplar.starts_with("trace_id=\"abc\\\"d\"} 50 "), "{}", exemplar);
+        assert!(text.ends_with("size_bytes_bucket{le=\"+Inf\"} 2\nsize_bytes_sum 55\nsize_bytes_count 2\n# EOF\n"), "{}", text);
+
+        // The Prometheus text is unchanged by exemplars
+        assert!(!metrics.render().contains("trace_id"));
+    }
+
+    #[test]
+    fn test_gauge_goes_up_and_down() {
+        let metrics = Metrics::default();
+        let gauge = metrics.gauge("open", "Open things");
+        gauge.inc();
+        gauge.inc();
+        metrics.gauge("open", "Open things").dec();
+
+        assert_eq!(gauge.get(), 1);
+        assert_eq!(metrics.render(), "# HELP open Open things\n# TYPE open gauge\nopen 1\n");
+
+        // Names keep the kind they were registered with
+        metrics.counter("open", "Open things").inc();
+        assert_eq!(gauge.get(), 1);
+    }
+}
@@ -0,0 +1,142 @@
+/*!
+ * `Accept` header negotiation
+ *
+ * Endpoints that can answer in more than one format offer a list of media
+ * types, most preferred first, and [`preferred`] picks the one the client
+ * ranks highest. Where an endpoint accepts a `?format=` query parameter,
+ * it overrides the header; see [`Format::negotiate`].
+ */
+use axum::http::{header, HeaderMap};
+
+/// Response formats selectable with `?format=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Text,
+    Prometheus,
+}
+
+impl Format {
+    /// Media type the format is negotiated under
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Text => "text/plain",
+            Self::Prometheus => "text/plain; version=0.0.4",
+        }
+    }
+
+    /// Format named by a `?format=` value, if recognised
+    pub fn from_query(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "text" | "plain" => Some(Self::Text),
+            "prometheus" => Some(Self::Prometheus),
+            _ => None,
+        }
+    }
+
+    /// Format for a request to an endpoint offering `offered`, default
+    /// first. A `?format=` value decides on its own, falling back to the
+    /// default when it names nothing offered; otherwise `Accept` decides.
+    pub fn negotiate(headers: &HeaderMap, query: Option<&str>, offered: &[Format]) -> Format {
+        let default = offered.first().copied().unwrap_or(Self::Json);
+        if let Some(query) = query {
+            return Self::from_query(query).filter(|format| offered.contains(format)).unwrap_or(default);
+        }
+        let media_types: Vec<&str> = offered.iter().map(|format| essence(format.media_type())).collect();
+        preferred(headers, &media_types)
+            .and_then(|media_type| offered.iter().find(|format| essence(format.media_type()) == media_type))
+            .copied()
+            .unwrap_or(default)
+    }
+}
+
+/// Media type without parameters
+fn essence(media_type: &str) -> &str {
+    media_type.split(';').next().unwrap_or_default().trim()
+}
+
+/// The entry of `offered` that `Accept` ranks highest, or `None` if it
+/// accepts none of them. A type is ranked by the most specific range
+/// matching it (`text/html`, then `text/*`, then `*/*`); ties go to
+/// whichever is listed first in `Accept`, then to the order of `offered`.
+pub fn preferred<'a>(headers: &HeaderMap, offered: &[&'a str]) -> Option<&'a str> {
+    // (media range, quality) in header order
+    let ranges: Vec<(String, f32)> = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|range| {
+            let mut params = range.split(';');
+            let media = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q=").and_then(|q| q.parse().ok()))
+                .unwrap_or(1.0);
+            (media, quality)
+        })
+        .collect();
+
+    // (quality, position) of the most specific matching range
+    let rank = |media_type: &str| -> Option<(f32, usize)> {
+        let (kind, _) = media_type.split_once('/')?;
+        let wildcard = format!("{}/*", kind);
+        [media_type, wildcard.as_str(), "*/*"].iter().find_map(|candidate| {
+            ranges
+                .iter()
+                .position(|(media, _)| media == candidate)
+                .map(|position| (ranges[position].1, position))
+        })
+    };
+
+    offered
+        .iter()
+        .filter_map(|media_type| rank(media_type).map(|(quality, position)| (*media_type, quality, position)))
+        .filter(|(_, quality, _)| *quality > 0.0)
+        .fold(None, |best: Option<(&str, f32, usize)>, candidate| match best {
+            Some(best) if best.1 > candidate.1 || (best.1 == candidate.1 && best.2 <= candidate.2) => Some(best),
+            _ => Some(candidate),
+        })
+        .map(|(media_type, _, _)| media_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    const JSON_OR_TEXT: &[&str] = &["application/json", "text/plain"];
+
+    #[test]
+    fn test_preferred() {
+        assert_eq!(preferred(&accept("text/plain"), JSON_OR_TEXT), Some("text/plain"));
+        assert_eq!(preferred(&accept("text/*"), JSON_OR_TEXT), Some("text/plain"));
+        assert_eq!(preferred(&accept("text/plain;q=0.5, application/json"), JSON_OR_TEXT), Some("application/json"));
+        assert_eq!(preferred(&accept("text/plain, application/json"), JSON_OR_TEXT), Some("text/plain"));
+        // Ties on `*/*` go to the endpoint's order
+        assert_eq!(preferred(&accept("*/*"), JSON_OR_TEXT), Some("application/json"));
+        // The specific range beats the wildcard even when listed later
+        assert_eq!(preferred(&accept("*/*;q=0.1, text/plain;q=0.9"), JSON_OR_TEXT), Some("text/plain"));
+        assert_eq!(preferred(&accept("application/json;q=0, */*"), JSON_OR_TEXT), Some("text/plain"));
+        assert_eq!(preferred(&accept("image/png"), JSON_OR_TEXT), None);
+        assert_eq!(preferred(&HeaderMap::new(), JSON_OR_TEXT), None);
+    }
+
+    #[test]
+    fn test_query_overrides_accept() {
+        let offered = [Format::Json, Format::Text];
+        let headers = accept("text/plain");
+        assert_eq!(Format::negotiate(&headers, None, &offered), Format::Text);
+        assert_eq!(Format::negotiate(&headers, Some("json"), &offered), Format::Json);
+        // Not recognised or not offered: the default, whatever Accept says
+        assert_eq!(Format::negotiate(&headers, Some("yaml"), &offered), Format::Json);
+        assert_eq!(Format::negotiate(&headers, Some("prometheus"), &offered), Format::Json);
+    }
+}
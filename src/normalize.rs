@@ -0,0 +1,152 @@
+/*!
+ * Request path normalization
+ *
+ * Uptime checkers and hand-written links ask for `/health/` or `//health`,
+ * which miss the `/health` route. `PATH_NORMALIZATION` decides what happens
+ * to such paths:
+ *
+ * - `redirect` (default): `308 Permanent Redirect` to the normalized path
+ * - `rewrite`: route the normalized path without telling the client
+ * - `off`: route the path as sent
+ *
+ * Normalizing collapses runs of `/` and strips one trailing `/`. The root
+ * `/` is left alone, and so are percent-encoded characters (`%2F` stays
+ * `%2F`). Under the static files prefix only duplicate slashes are
+ * collapsed, since `ServeDir` redirects directories to their trailing-slash
+ * form and stripping it again would loop.
+ *
+ * The middleware wraps the finished router (axum routes before running
+ * `Router::layer` middleware) and sits inside the request span, so logs
+ * keep the path the client sent.
+ */
+use crate::config::AppConfig;
+use axum::{
+    extract::{Request, State},
+    http::{header, uri::PathAndQuery, HeaderValue, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::debug;
+
+/// What to do with a path that is not in normal form
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathNormalization {
+    #[default]
+    Redirect,
+    Rewrite,
+    Off,
+}
+
+impl FromStr for PathNormalization {
+    type Err = crate::ServerError;
+
+    fn from_str(value: &str) -> crate::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "redirect" => Ok(PathNormalization::Redirect),
+            "rewrite" => Ok(PathNormalization::Rewrite),
+            "off" => Ok(PathNormalization::Off),
+            other => Err(crate::ServerError::ConfigError(format!(
+                "Invalid PATH_NORMALIZATION {:?} (expected redirect, rewrite or off)",
+                other
+            ))),
+        }
+    }
+}
+
+impl PathNormalization {
+    /// Load from `PATH_NORMALIZATION`, defaulting to `redirect`
+    pub fn from_env() -> crate::Result<Self> {
+        match std::env::var("PATH_NORMALIZATION") {
+            Ok(value) => value.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// `path` with duplicate slashes collapsed and, unless `keep_trailing`, one
+/// trailing slash removed; `None` when it is already normal
+pub fn normalize(path: &str, keep_trailing: bool) -> Option<String> {
+    let mut normalized = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c == '/' && normalized.ends_with('/') {
+            continue;
+        }
+        normalized.push(c);
+    }
+    if !keep_trailing && normalized.len() > 1 && normalized.ends_with('/') {
+        normalized.pop();
+    }
+
+    (normalized != path).then_some(normalized)
+}
+
+/// Middleware redirecting to or rewriting the normalized path
+pub async fn normalize_path(State(config): State<Arc<AppConfig>>, mut request: Request, next: Next) -> Response {
+    let mode = config.path_normalization;
+    if mode == PathNormalization::Off {
+        return next.run(request).await;
+    }
+
+    let path = request.uri().path();
+    let keep_trailing = config
+        .static_files
+        .as_ref()
+        .is_some_and(|files| path.starts_with(&format!("{}/", files.prefix.trim_end_matches('/'))));
+    let Some(normalized) = normalize(path, keep_trailing) else {
+        return next.run(request).await;
+    };
+
+    let target = match request.uri().query() {
+        Some(query) => format!("{}?{}", normalized, query),
+        None => normalized,
+    };
+    let Ok(path_and_query) = PathAndQuery::from_str(&target) else {
+        return next.run(request).await;
+    };
+
+    if mode == PathNormalization::Redirect {
+        let Ok(location) = HeaderValue::from_str(path_and_query.as_str()) else {
+            return next.run(request).await;
+        };
+        return (StatusCode::PERMANENT_REDIRECT, [(header::LOCATION, location)]).into_response();
+    }
+
+    debug!("Rewriting {} to {}", request.uri().path(), path_and_query);
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query);
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/health/", false).as_deref(), Some("/health"));
+        assert_eq!(normalize("//health", false).as_deref(), Some("/health"));
+        assert_eq!(normalize("/static//a.css", false).as_deref(), Some("/static/a.css"));
+        assert_eq!(normalize("///", false).as_deref(), Some("/"));
+        assert_eq!(normalize("/a%2F%2Fb/", false).as_deref(), Some("/a%2F%2Fb"));
+        assert_eq!(normalize("/", false), None);
+        assert_eq!(normalize("/health", false), None);
+    }
+
+    #[test]
+    fn test_keep_trailing_slash() {
+        assert_eq!(normalize("/static/docs/", true), None);
+        assert_eq!(normalize("/static//docs//", true).as_deref(), Some("/static/docs/"));
+    }
+
+    #[test]
+    fn test_mode_parsing() {
+        assert_eq!("Rewrite".parse::<PathNormalization>().unwrap(), PathNormalization::Rewrite);
+        assert_eq!("off".parse::<PathNormalization>().unwrap(), PathNormalization::Off);
+        assert!("strip".parse::<PathNormalization>().is_err());
+    }
+}
@@ -0,0 +1,546 @@
+/*!
+ * Outbound webhook notifications
+ *
+ * With `NOTIFY_WEBHOOK_URLS` (or `NOTIFY_WEBHOOK_URLS_FILE`) set, the
+ * service POSTs a JSON payload to each URL when something an operator
+ * would want to hear about happens:
+ *
+ * - `started` once the listeners are serving, and `stopping` when the
+ *   shutdown signal arrives
+ * - `unhealthy` and `recovered` when the readiness checks (see
+ *   [`crate::health`]) change state, polled every
+ *   `NOTIFY_HEALTH_INTERVAL_SECS` by a supervised task. A change is only
+ *   reported once it has lasted `NOTIFY_FLAP_SECS`, so a check that fails
+ *   for one poll and passes on the next sends nothing.
+ * - `maintenance_on` and `maintenance_off` when maintenance mode is
+ *   switched through the admin API or the sentinel file
+ *
+ * `NOTIFY_EVENTS` limits which events are sent. The payload is a JSON
+ * template whose strings may use `{service}`, `{version}`, `{event}`,
+ * `{detail}` and `{timestamp}`; the default carries each of them as a
+ * field plus a `text` line Slack shows as the message. `NOTIFY_TEMPLATE`
+ * replaces it for every event and `NOTIFY_TEMPLATE_<EVENT>` (e.g.
+ * `NOTIFY_TEMPLATE_UNHEALTHY`) for one, so Discord can be sent `content`
+ * instead.
+ *
+ * Deliveries go through the shared [`HttpClient`] in their own tasks and
+ * never hold up a request. Transport errors, 429s and 5xx are retried
+ * `NOTIFY_MAX_RETRIES` times with a doubling delay; a notification still
+ * undelivered is logged and counted as a dead letter
+ * (`notifications_dead_lettered_total`). Shutdown waits for deliveries in
+ * flight up to `tasks::SHUTDOWN_DEADLINE`, so `stopping` gets out.
+ */
+use crate::http_client::HttpClient;
+use crate::state::AppState;
+use crate::tasks::Schedule;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Name of the task polling the readiness checks
+pub const HEALTH_TASK: &str = "notify-health";
+
+/// Something notifications can be sent for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum NotificationEvent {
+    Started,
+    Stopping,
+    Unhealthy,
+    Recovered,
+    MaintenanceOn,
+    MaintenanceOff,
+}
+
+impl NotificationEvent {
+    /// Every event, in the order they are documented
+    pub const ALL: [NotificationEvent; 6] = [
+        Self::Started,
+        Self::Stopping,
+        Self::Unhealthy,
+        Self::Recovered,
+        Self::MaintenanceOn,
+        Self::MaintenanceOff,
+    ];
+
+    /// Name used in payloads and `NOTIFY_EVENTS`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Stopping => "stopping",
+            Self::Unhealthy => "unhealthy",
+            Self::Recovered => "recovered",
+            Self::MaintenanceOn => "maintenance_on",
+            Self::MaintenanceOff => "maintenance_off",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Payload used when no template is configured
+fn default_template() -> Value {
+    json!({
+        "service": "{service}",
+        "version": "{version}",
+        "event": "{event}",
+        "detail": "{detail}",
+        "timestamp": "{timestamp}",
+        "text": "{service} {version}: {event} ({detail})",
+    })
+}
+
+/// Where notifications go, what they say and when they are sent
+#[derive(Clone)]
+pub struct NotificationsConfig {
+    /// Webhook URLs; often carry a token, so never logged
+    pub urls: Vec<String>,
+
+    /// Events sent; the rest are dropped
+    pub events: Vec<NotificationEvent>,
+
+    /// `{service}` in the payload
+    pub service: String,
+
+    /// Payload for every event without its own template
+    pub template: Value,
+
+    /// Per-event payloads
+    pub templates: BTreeMap<NotificationEvent, Value>,
+
+    /// How often the readiness checks are polled
+    pub health_interval: Duration,
+
+    /// How long a health change must last before it is reported
+    pub flap_period: Duration,
+
+    /// Retries after the first failed delivery
+    pub max_retries: u32,
+
+    /// Wait before the first retry, doubled for each one after it
+    pub retry_backoff: Duration,
+}
+
+impl std::fmt::Debug for NotificationsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NotificationsConfig")
+            .field("urls", &vec!["<redacted>"; self.urls.len()])
+            .field("events", &self.events)
+            .field("service", &self.service)
+            .field("template", &self.template)
+            .field("templates", &self.templates)
+            .field("health_interval", &self.health_interval)
+            .field("flap_period", &self.flap_period)
+            .field("max_retries", &self.max_retries)
+            .field("retry_backoff", &self.retry_backoff)
+            .finish()
+    }
+}
+
+impl NotificationsConfig {
+    /// Every event sent to `urls` with the default payload and timings
+    pub fn new(urls: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            urls: urls.into_iter().map(Into::into).collect(),
+            events: NotificationEvent::ALL.to_vec(),
+            service: "cloudflare-tunnel-example".to_string(),
+            template: default_template(),
+            templates: BTreeMap::new(),
+            health_interval: Duration::from_secs(15),
+            flap_period: Duration::from_secs(60),
+            max_retries: 3,
+            retry_backoff: Duration::from_secs(1),
+        }
+    }
+
+    /// Load from the `NOTIFY_*` variables; `None` unless
+    /// `NOTIFY_WEBHOOK_URLS` (or its `_FILE`) is set
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let invalid = |msg: String| crate::ServerError::ConfigError(msg);
+        let Some(urls) = crate::config::read_secret("NOTIFY_WEBHOOK_URLS")? else {
+            return Ok(None);
+        };
+        let urls: Vec<&str> = urls.split(',').map(str::trim).filter(|url| !url.is_empty()).collect();
+        if urls.is_empty() {
+            return Err(invalid("NOTIFY_WEBHOOK_URLS lists no URLs".to_string()));
+        }
+        for (i, url) in urls.iter().enumerate() {
+            // The URL itself may hold a token, so it is not repeated
+            let uri: Uri = url.parse().map_err(|_| invalid(format!("NOTIFY_WEBHOOK_URLS entry {} is not a URL", i + 1)))?;
+            crate::http_client::reachable(url)
+                .map_err(|reason| invalid(format!("NOTIFY_WEBHOOK_URLS entry {}: {}", i + 1, reason)))?;
+            if uri.host().is_none() {
+                return Err(invalid(format!("NOTIFY_WEBHOOK_URLS entry {} has no host", i + 1)));
+            }
+        }
+        let mut config = Self::new(urls);
+
+        if let Ok(value) = std::env::var("NOTIFY_EVENTS") {
+            config.events = value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    NotificationEvent::parse(name).ok_or_else(|| {
+                        let known: Vec<_> = NotificationEvent::ALL.iter().map(|event| event.as_str()).collect();
+                        invalid(format!("Unknown NOTIFY_EVENTS event {:?}; expected one of {}", name, known.join(", ")))
+                    })
+                })
+                .collect::<crate::Result<_>>()?;
+        }
+        if let Ok(service) = std::env::var("NOTIFY_SERVICE_NAME") {
+            if !service.trim().is_empty() {
+                config.service = service.trim().to_string();
+            }
+        }
+        if let Some(template) = template_env("NOTIFY_TEMPLATE")? {
+            config.template = template;
+        }
+        for event in NotificationEvent::ALL {
+            let var = format!("NOTIFY_TEMPLATE_{}", event.as_str().to_ascii_uppercase());
+            if let Some(template) = template_env(&var)? {
+                config.templates.insert(event, template);
+            }
+        }
+        if let Some(secs) = number_env("NOTIFY_HEALTH_INTERVAL_SECS", false)? {
+            config.health_interval = Duration::from_secs(secs);
+        }
+        if let Some(secs) = number_env("NOTIFY_FLAP_SECS", true)? {
+            config.flap_period = Duration::from_secs(secs);
+        }
+        if let Some(retries) = number_env("NOTIFY_MAX_RETRIES", true)? {
+            config.max_retries = u32::try_from(retries).map_err(|_| invalid("NOTIFY_MAX_RETRIES is too large".to_string()))?;
+        }
+        if let Some(ms) = number_env("NOTIFY_RETRY_BACKOFF_MS", true)? {
+            config.retry_backoff = Duration::from_millis(ms);
+        }
+        Ok(Some(config))
+    }
+
+    /// Body sent for `event`, with `detail` at `at`
+    pub fn payload(&self, event: NotificationEvent, detail: &str, at: DateTime<Utc>) -> Value {
+        let timestamp = at.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let fields = [
+            ("{service}", self.service.as_str()),
+            ("{version}", env!("CARGO_PKG_VERSION")),
+            ("{event}", event.as_str()),
+            ("{detail}", detail),
+            ("{timestamp}", timestamp.as_str()),
+        ];
+        fill(self.templates.get(&event).unwrap_or(&self.template), &fields)
+    }
+}
+
+/// A JSON template from `var`, which must be an object or array
+fn template_env(var: &str) -> crate::Result<Option<Value>> {
+    let Ok(value) = std::env::var(var) else {
+        return Ok(None);
+    };
+    match serde_json::from_str(&value) {
+        Ok(template @ (Value::Object(_) | Value::Array(_))) => Ok(Some(template)),
+        Ok(_) => Err(crate::ServerError::ConfigError(format!("{} must be a JSON object or array", var))),
+        Err(e) => Err(crate::ServerError::ConfigError(format!("Invalid {}: {}", var, e))),
+    }
+}
+
+/// Whole number from `var`, zero only when `zero_ok`
+fn number_env(var: &str, zero_ok: bool) -> crate::Result<Option<u64>> {
+    let Ok(value) = std::env::var(var) else {
+        return Ok(None);
+    };
+    value
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|n| zero_ok || *n > 0)
+        .map(Some)
+        .ok_or_else(|| crate::ServerError::ConfigError(format!(
+            "Invalid {}: {:?} is not a {} number",
+            var,
+            value,
+            if zero_ok { "whole" } else { "positive" }
+        )))
+}
+
+/// `template` with the placeholders in its strings replaced; keys are kept
+/// as they are
+fn fill(template: &Value, fields: &[(&str, &str)]) -> Value {
+    match template {
+        Value::String(text) => {
+            Value::String(fields.iter().fold(text.clone(), |text, (name, value)| text.replace(name, value)))
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|item| fill(item, fields)).collect()),
+        Value::Object(map) => Value::Object(map.iter().map(|(key, value)| (key.clone(), fill(value, fields))).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Deliveries in flight and the dead-letter count; clones share them
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    deliveries: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    dead_letters: Arc<AtomicU64>,
+    #[cfg(feature = "metrics")]
+    dead_letter_counter: Option<crate::metrics::Counter>,
+}
+
+impl Notifier {
+    /// The same notifier, also counting dead letters in `metrics`; call
+    /// before it is cloned
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: &crate::metrics::Metrics) -> Self {
+        self.dead_letter_counter = Some(metrics.counter(
+            "notifications_dead_lettered_total",
+            "Webhook notifications given up on after every retry failed",
+        ));
+        self
+    }
+
+    /// Notifications given up on since startup
+    pub fn dead_letters(&self) -> u64 {
+        self.dead_letters.load(Ordering::Relaxed)
+    }
+
+    /// Wait for every delivery started so far, retries included
+    pub async fn flush(&self) {
+        loop {
+            let deliveries = std::mem::take(&mut *self.deliveries.lock().unwrap_or_else(|e| e.into_inner()));
+            if deliveries.is_empty() {
+                return;
+            }
+            for delivery in deliveries {
+                let _ = delivery.await;
+            }
+        }
+    }
+
+    fn dead_letter(&self) {
+        self.dead_letters.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        if let Some(counter) = &self.dead_letter_counter {
+            counter.inc();
+        }
+    }
+
+    fn track(&self, delivery: JoinHandle<()>) {
+        let mut deliveries = self.deliveries.lock().unwrap_or_else(|e| e.into_inner());
+        deliveries.retain(|delivery| !delivery.is_finished());
+        deliveries.push(delivery);
+    }
+}
+
+/// Send `event` to every configured URL in the background, if
+/// notifications are on and `event` is one of `NOTIFY_EVENTS`
+pub fn notify(state: &AppState, event: NotificationEvent, detail: impl Into<String>) {
+    let config = state.config();
+    let Some(notifications) = &config.notifications else {
+        return;
+    };
+    if !notifications.events.contains(&event) {
+        return;
+    }
+    let detail = detail.into();
+    debug!(event = event.as_str(), detail, "Sending notification");
+    let body = Bytes::from(notifications.payload(event, &detail, state.clock.now_utc()).to_string());
+
+    for url in &notifications.urls {
+        let (http, notifier, url, body) = (state.http.clone(), state.notifier.clone(), url.clone(), body.clone());
+        let (retries, backoff) = (notifications.max_retries, notifications.retry_backoff);
+        let delivery = tokio::spawn(async move {
+            if let Err(e) = deliver(&http, &url, body, retries, backoff).await {
+                warn!(event = event.as_str(), "Notification to {} dropped: {}", host(&url), e);
+                notifier.dead_letter();
+            }
+        });
+        state.notifier.track(delivery);
+    }
+}
+
+/// POST `body` to `url`, retrying failures that may pass
+async fn deliver(http: &HttpClient, url: &str, body: Bytes, retries: u32, backoff: Duration) -> Result<(), String> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    let mut wait = backoff;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let failure = match http.send(Method::POST, url, headers.clone(), body.clone()).await {
+            Ok(response) if response.status.is_success() => return Ok(()),
+            Ok(response) if response.status.is_client_error() && response.status != StatusCode::TOO_MANY_REQUESTS => {
+                return Err(format!("rejected with {}", response.status));
+            }
+            Ok(response) => format!("answered {}", response.status),
+            Err(e) => e.to_string(),
+        };
+        if attempt > retries {
+            return Err(format!("{} (after {} attempts)", failure, attempt));
+        }
+        debug!("Notification to {} failed, retrying in {:?}: {}", host(url), wait, failure);
+        tokio::time::sleep(wait).await;
+        wait *= 2;
+    }
+}
+
+/// Host of `url`, which can be logged where the URL cannot
+fn host(url: &str) -> String {
+    url.parse::<Uri>().ok().and_then(|uri| uri.host().map(str::to_string)).unwrap_or_default()
+}
+
+/// Health as last reported, and since when it has been otherwise
+#[derive(Debug)]
+pub struct HealthWatch {
+    reported: bool,
+    changed_at: Option<Instant>,
+}
+
+impl Default for HealthWatch {
+    /// Healthy, as announced by `started`
+    fn default() -> Self {
+        Self { reported: true, changed_at: None }
+    }
+}
+
+impl HealthWatch {
+    /// Record the health seen at `now`. Returns the new state once it has
+    /// differed from the reported one for `hold`; a change that reverts
+    /// sooner is forgotten.
+    pub fn observe(&mut self, healthy: bool, now: Instant, hold: Duration) -> Option<bool> {
+        if healthy == self.reported {
+            if self.changed_at.take().is_some() {
+                debug!("Health changed back within NOTIFY_FLAP_SECS; not notifying");
+            }
+            return None;
+        }
+        let since = *self.changed_at.get_or_insert(now);
+        if now.saturating_duration_since(since) < hold {
+            return None;
+        }
+        self.reported = healthy;
+        self.changed_at = None;
+        Some(healthy)
+    }
+}
+
+/// Run the readiness checks once and notify if `watch` says the health has
+/// changed for good
+pub async fn check_health(state: &AppState, watch: &Mutex<HealthWatch>) {
+    let Some(hold) = state.config().notifications.as_ref().map(|config| config.flap_period) else {
+        return;
+    };
+    let results = state.health.run().await;
+    let failing: Vec<String> = results
+        .iter()
+        .filter(|result| !result.healthy)
+        .map(|result| match &result.detail {
+            Some(detail) => format!("{}: {}", result.name, detail),
+            None => result.name.clone(),
+        })
+        .collect();
+
+    let changed = watch
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .observe(failing.is_empty(), state.clock.monotonic(), hold);
+    match changed {
+        Some(true) => {
+            info!("Readiness checks passing again");
+            notify(state, NotificationEvent::Recovered, format!("all {} checks passing", results.len()));
+        }
+        Some(false) => {
+            warn!("Readiness checks failing: {}", failing.join("; "));
+            notify(state, NotificationEvent::Unhealthy, failing.join("; "));
+        }
+        None => {}
+    }
+}
+
+/// Register the task polling the readiness checks with `state.tasks`, when
+/// health notifications are configured
+pub fn watch(state: &AppState) {
+    let config = state.config();
+    let Some(notifications) = &config.notifications else {
+        return;
+    };
+    if !notifications.events.iter().any(|event| matches!(event, NotificationEvent::Unhealthy | NotificationEvent::Recovered)) {
+        return;
+    }
+
+    let (polled, watch) = (state.clone(), Arc::new(Mutex::new(HealthWatch::default())));
+    state.tasks.spawn(HEALTH_TASK, Schedule::every(notifications.health_interval), move || {
+        let (state, watch) = (polled.clone(), watch.clone());
+        async move {
+            check_health(&state, &watch).await;
+            Ok::<_, String>(())
+        }
+    });
+}
+
+/// Notify `maintenance_on` or `maintenance_off` if maintenance is no longer
+/// `was_enabled`
+pub fn maintenance_changed(state: &AppState, was_enabled: bool) {
+    let status = state.maintenance.status();
+    if status.enabled == was_enabled {
+        return;
+    }
+    if status.enabled {
+        let message = status.message.unwrap_or_else(|| state.config().maintenance.message.clone());
+        notify(state, NotificationEvent::MaintenanceOn, message);
+    } else {
+        notify(state, NotificationEvent::MaintenanceOff, "maintenance mode switched off");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flaps_are_suppressed() {
+        let (start, hold) = (Instant::now(), Duration::from_secs(60));
+        let mut watch = HealthWatch::default();
+
+        // Failing, then passing again before the hold ran out
+        assert_eq!(watch.observe(false, start, hold), None);
+        assert_eq!(watch.observe(false, start + Duration::from_secs(30), hold), None);
+        assert_eq!(watch.observe(true, start + Duration::from_secs(45), hold), None);
+        // The clock restarts with the next failure
+        assert_eq!(watch.observe(false, start + Duration::from_secs(90), hold), None);
+        assert_eq!(watch.observe(false, start + Duration::from_secs(149), hold), None);
+        assert_eq!(watch.observe(false, start + Duration::from_secs(150), hold), Some(false));
+        assert_eq!(watch.observe(false, start + Duration::from_secs(300), hold), None);
+
+        // Without a hold every change is reported
+        assert_eq!(watch.observe(true, start + Duration::from_secs(301), Duration::ZERO), Some(true));
+    }
+
+    #[test]
+    fn test_templates_fill_strings_only() {
+        let mut config = NotificationsConfig::new(["http://127.0.0.1:1/hook"]);
+        config.templates.insert(
+            NotificationEvent::Stopping,
+            json!({ "content": "{service} is {event}: {detail}", "{event}": [1, "{version}"] }),
+        );
+        let at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().to_utc();
+
+        let payload = config.payload(NotificationEvent::Stopping, "\"SIGTERM\"", at);
+        assert_eq!(
+            payload,
+            json!({
+                "content": "cloudflare-tunnel-example is stopping: \"SIGTERM\"",
+                "{event}": [1, env!("CARGO_PKG_VERSION")],
+            })
+        );
+        let payload = config.payload(NotificationEvent::Started, "listening", at);
+        assert_eq!(payload["timestamp"], "2024-01-01T00:00:00Z");
+        assert_eq!(payload["event"], "started");
+    }
+}
@@ -0,0 +1,264 @@
+/*!
+ * OpenAPI description of the service, served at `GET /openapi.json`
+ *
+ * The document is generated by `utoipa` from the `#[utoipa::path]`
+ * attributes on the handlers and the `ToSchema` types their bodies are
+ * serialized from, so a payload and its schema cannot drift apart. It is
+ * assembled from the live configuration: only the route groups this
+ * instance mounts (Turnstile, CSRF, debug endpoints, `/metrics`) are merged
+ * in, and operations under the configured `AUTH_RULES` and
+ * `API_KEY_PREFIXES` get a security requirement.
+ *
+ * With debug endpoints on, `/docs` renders the same document as a plain
+ * HTML list of operations. It loads no scripts, so unlike Swagger UI it
+ * works under every CSP preset.
+ */
+use crate::auth::longest_prefix;
+use crate::config::AppConfig;
+use crate::error::ErrorBody;
+use axum::{
+    extract::{FromRef, State},
+    http::header,
+    response::{IntoResponse, Json},
+    routing::get,
+    Router,
+};
+use std::sync::Arc;
+use utoipa::openapi::path::{Operation, PathItem};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityRequirement, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, Ref, ResponseBuilder};
+use utoipa::OpenApi;
+
+/// Security scheme for `AUTH_RULES` bearer tokens
+const BEARER_AUTH: &str = "bearerAuth";
+
+/// Security scheme for `X-Api-Key`
+const API_KEY: &str = "apiKey";
+
+/// Routes every instance mounts
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "cloudflare-tunnel-example", description = "Rust Axum service exposed through a Cloudflare Tunnel"),
+    paths(
+        crate::homepage::homepage,
+        crate::health_check,
+        crate::health::readyz,
+        crate::status::status_page,
+        crate::favicon::favicon,
+        crate::robots::robots_txt,
+        crate::events::events,
+        crate::webhooks::receive,
+        crate::reports::receive,
+        openapi_json,
+    ),
+    components(schemas(ErrorBody)),
+)]
+struct ServiceApi;
+
+#[cfg(feature = "metrics")]
+#[derive(OpenApi)]
+#[openapi(paths(crate::metrics::metrics_handler))]
+struct MetricsApi;
+
+#[cfg(feature = "turnstile")]
+#[derive(OpenApi)]
+#[openapi(paths(crate::turnstile::verify_handler))]
+struct TurnstileApi;
+
+#[derive(OpenApi)]
+#[openapi(paths(crate::csrf::issue_token))]
+struct CsrfApi;
+
+#[cfg(feature = "debug-endpoints")]
+#[derive(OpenApi)]
+#[openapi(paths(
+    crate::debug::whoami,
+    crate::debug::status,
+    crate::debug::delay,
+    crate::debug::echo,
+    crate::debug::validate,
+    crate::debug::list_schemas,
+    crate::websocket::upgrade,
+    docs,
+))]
+struct DebugApi;
+
+/// `GET /openapi.json`
+pub fn routes<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    Arc<AppConfig>: FromRef<S>,
+{
+    Router::new().route("/openapi.json", get(openapi_json))
+}
+
+/// This document
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = OK, description = "OpenAPI document", body = Object)),
+)]
+async fn openapi_json(State(config): State<Arc<AppConfig>>) -> impl IntoResponse {
+    ([(header::CACHE_CONTROL, "no-cache")], Json(document(&config)))
+}
+
+/// Security requirement for `path`, if any configured credentials cover it
+fn security(config: &AppConfig, path: &str) -> Option<SecurityRequirement> {
+    let mut schemes = Vec::new();
+    if config.auth.iter().any(|rule| longest_prefix(&rule.prefixes, path).is_some()) {
+        schemes.push(BEARER_AUTH);
+    }
+    if let Some(api_keys) = &config.api_keys {
+        if longest_prefix(&api_keys.prefixes, path).is_some() {
+            schemes.push(API_KEY);
+        }
+    }
+    let no_scopes: [&str; 0] = [];
+    (!schemes.is_empty()).then(|| {
+        schemes
+            .into_iter()
+            .fold(SecurityRequirement::default(), |requirement, scheme| requirement.add(scheme, no_scopes))
+    })
+}
+
+/// `(method, operation)` for each operation of `item`
+#[cfg(feature = "debug-endpoints")]
+fn operations(item: &PathItem) -> impl Iterator<Item = (&'static str, &Operation)> {
+    [
+        ("get", &item.get),
+        ("put", &item.put),
+        ("post", &item.post),
+        ("delete", &item.delete),
+        ("options", &item.options),
+        ("head", &item.head),
+        ("patch", &item.patch),
+        ("trace", &item.trace),
+    ]
+    .into_iter()
+    .filter_map(|(method, operation)| Some((method, operation.as_ref()?)))
+}
+
+fn operations_mut(item: &mut PathItem) -> impl Iterator<Item = &mut Operation> {
+    [
+        &mut item.get,
+        &mut item.put,
+        &mut item.post,
+        &mut item.delete,
+        &mut item.options,
+        &mut item.head,
+        &mut item.patch,
+        &mut item.trace,
+    ]
+    .into_iter()
+    .filter_map(Option::as_mut)
+}
+
+/// OpenAPI document for the routes mounted with `config`
+pub fn document(config: &AppConfig) -> utoipa::openapi::OpenApi {
+    let mut document = ServiceApi::openapi();
+    #[cfg(feature = "metrics")]
+    document.merge(MetricsApi::openapi());
+    #[cfg(feature = "turnstile")]
+    if config.turnstile.is_some() {
+        document.merge(TurnstileApi::openapi());
+    }
+    if config.csrf.is_some() {
+        document.merge(CsrfApi::openapi());
+    }
+    #[cfg(feature = "debug-endpoints")]
+    if config.debug_endpoints {
+        document.merge(DebugApi::openapi());
+    }
+
+    for (path, item) in &mut document.paths.paths {
+        let Some(requirement) = security(config, path) else { continue };
+        for operation in operations_mut(item) {
+            operation.security = Some(vec![requirement.clone()]);
+            let unauthorized = ResponseBuilder::new()
+                .description("Missing or invalid credentials")
+                .content("application/json", ContentBuilder::new().schema(Some(Ref::from_schema_name("Error"))).build())
+                .build();
+            operation.responses.responses.insert("401".to_string(), unauthorized.into());
+        }
+    }
+
+    let components = document.components.get_or_insert_with(Default::default);
+    if !config.auth.is_empty() {
+        components.add_security_scheme(BEARER_AUTH, SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+    }
+    if config.api_keys.is_some() {
+        components.add_security_scheme(API_KEY, SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))));
+    }
+    document
+}
+
+/// This document as HTML
+#[cfg(feature = "debug-endpoints")]
+#[utoipa::path(
+    get,
+    path = "/docs",
+    responses((status = OK, description = "List of operations", body = String, content_type = "text/html")),
+)]
+pub async fn docs(State(config): State<Arc<AppConfig>>) -> axum::response::Html<String> {
+    let mut page = String::from(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>API documentation</title>\n</head>\n<body>\n<h1>API documentation</h1>\n\
+         <p>Machine-readable: <a href=\"/openapi.json\">/openapi.json</a></p>\n<ul>\n",
+    );
+    for (path, item) in &document(&config).paths.paths {
+        for (method, operation) in operations(item) {
+            let summary = operation.summary.as_deref().unwrap_or_default();
+            let secured = if operation.security.is_some() { " (authenticated)" } else { "" };
+            page.push_str(&format!(
+                "<li><code>{} {}</code> - {}{}</li>\n",
+                method.to_uppercase(),
+                path,
+                summary,
+                secured
+            ));
+        }
+    }
+    page.push_str("</ul>\n</body>\n</html>\n");
+    axum::response::Html(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys::{ApiKey, ApiKeyConfig};
+    use crate::auth::AuthRule;
+    use serde_json::{json, Value};
+
+    fn json_document(config: &AppConfig) -> Value {
+        serde_json::to_value(super::document(config)).unwrap()
+    }
+
+    #[test]
+    fn test_security_follows_configured_prefixes() {
+        let config = AppConfig {
+            auth: vec![AuthRule::new("ops", ["/readyz"], ["token"])],
+            api_keys: Some(ApiKeyConfig::new([ApiKey::new("reporting", "key")], ["/webhooks"])),
+            ..AppConfig::default()
+        };
+
+        let document = json_document(&config);
+        assert_eq!(document["paths"]["/readyz"]["get"]["security"], json!([{ "bearerAuth": [] }]));
+        assert_eq!(document["paths"]["/readyz"]["get"]["responses"]["401"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/Error");
+        assert_eq!(document["paths"]["/webhooks/{name}"]["post"]["security"], json!([{ "apiKey": [] }]));
+        assert_eq!(document["paths"]["/health"]["get"]["security"], Value::Null);
+        assert_eq!(document["components"]["securitySchemes"]["apiKey"]["name"], "X-Api-Key");
+
+        let open = json_document(&AppConfig::default());
+        assert_eq!(open["components"]["securitySchemes"], Value::Null);
+    }
+
+    #[test]
+    fn test_schema_references_resolve() {
+        let document = json_document(&AppConfig::default());
+        let text = document.to_string();
+        for reference in text.split("\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(document["components"]["schemas"][name].is_object(), "dangling reference to {}", name);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+/*!
+ * The main router's middleware pipeline
+ *
+ * `AppBuilder` declares the router as an ordered list of named stages,
+ * each either a middleware layer or a set of routes, instead of one long
+ * chain of `.layer` calls. Stages are added innermost first, the way
+ * `Router::layer` stacks them; [`Pipeline::order`] lists them the way a
+ * request meets them, outermost first. Stages left out by a feature flag or
+ * by the configuration are simply absent from the list.
+ *
+ * [`check`] holds the ordering rules that are easy to break by moving one
+ * line, each stated as "this stage must run before those". A rule only
+ * applies when both of its stages are present. The server refuses to start
+ * with an order that breaks one, and logs the order it serves with; the
+ * admin listener shows it at `GET /admin/pipeline`.
+ */
+use crate::state::AppState;
+use axum::{extract::Request, response::IntoResponse, routing::Route, Router};
+use std::convert::Infallible;
+use std::fmt;
+use tower::{Layer, Service};
+
+/// `before` must run before each of `after` when both are present
+#[derive(Debug, Clone, Copy)]
+pub struct Invariant {
+    pub before: &'static str,
+    pub after: &'static [&'static str],
+
+    /// What goes wrong otherwise
+    pub reason: &'static str,
+}
+
+/// Stages that answer a request in a handler's place, or are the handlers
+const HANDLERS: &[&str] = &["idempotency", "response_cache", "proxy", "static_files", "routes"];
+
+/// Ordering rules every pipeline must keep
+pub const INVARIANTS: &[Invariant] = &[
+    Invariant {
+        before: "request_id",
+        after: &["access_log", "header_sampling", "capture"],
+        reason: "log lines and captures carry the request ID",
+    },
+    Invariant {
+        before: "client_ip",
+        after: &["access_log"],
+        reason: "the access log records the resolved client address",
+    },
+    Invariant {
+        before: "access",
+        after: &["request_events"],
+        reason: "request events name the verified Cloudflare Access user",
+    },
+    Invariant {
+        before: "bearer_auth",
+        after: HANDLERS,
+        reason: "handlers, cached and replayed responses stay behind authentication",
+    },
+    Invariant {
+        before: "api_keys",
+        after: HANDLERS,
+        reason: "handlers, cached and replayed responses stay behind API keys",
+    },
+    Invariant {
+        before: "maintenance",
+        after: HANDLERS,
+        reason: "maintenance mode covers cached and replayed responses too",
+    },
+    Invariant {
+        before: "anomalies",
+        after: &["capture", "routing", "decompression", "proxy"],
+        reason: "malformed request heads are turned away before bodies are read or routes matched",
+    },
+    Invariant {
+        before: "decompression",
+        after: &["routes"],
+        reason: "handlers only see bodies within the request body caps",
+    },
+    Invariant {
+        before: "content_digest",
+        after: &["decompression", "proxy"],
+        reason: "request digests cover the body as sent",
+    },
+    Invariant {
+        before: "response_digest",
+        after: &["error_pages", "response_size"],
+        reason: "response digests cover the body actually sent",
+    },
+    Invariant {
+        before: "cookies",
+        after: &["canary"],
+        reason: "the experiment cookie is hardened like any other",
+    },
+    Invariant {
+        before: "response_cache",
+        after: &["canary"],
+        reason: "cached responses keep their variant and vary by its cookie",
+    },
+    Invariant {
+        before: "denial_signal",
+        after: &["direct_access", "api_keys", "bearer_auth", "csrf", "maintenance"],
+        reason: "every denial carries the signal header",
+    },
+    Invariant {
+        before: "security_headers",
+        after: &["https_redirect", "error_pages"],
+        reason: "redirects and error pages carry the security headers",
+    },
+];
+
+/// A stage order that breaks an [`Invariant`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub before: &'static str,
+    pub after: &'static str,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} must run before {}: {}", self.before, self.after, self.reason)
+    }
+}
+
+/// Check `order`, outermost stage first, against [`INVARIANTS`]
+pub fn check(order: &[&str]) -> Result<(), Violation> {
+    let position = |name: &str| order.iter().position(|stage| *stage == name);
+    for invariant in INVARIANTS {
+        let Some(before) = position(invariant.before) else {
+            continue;
+        };
+        for after in invariant.after {
+            if position(after).is_some_and(|after| after < before) {
+                return Err(Violation { before: invariant.before, after, reason: invariant.reason });
+            }
+        }
+    }
+    Ok(())
+}
+
+type Apply = Box<dyn FnOnce(Router<AppState>) -> Router<AppState> + Send>;
+
+/// Named stages of the router, innermost first
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<(&'static str, Apply)>,
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.order()).finish()
+    }
+}
+
+impl Pipeline {
+    /// Add `name` around (or, for routes, alongside) every stage so far
+    pub fn push(
+        &mut self,
+        name: &'static str,
+        apply: impl FnOnce(Router<AppState>) -> Router<AppState> + Send + 'static,
+    ) {
+        self.stages.push((name, Box::new(apply)));
+    }
+
+    /// Add the middleware `layer` as `name`, around every stage so far
+    pub fn layer<L>(&mut self, name: &'static str, layer: L)
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.push(name, move |router| router.layer(layer));
+    }
+
+    /// Stage names as a request meets them, outermost first
+    pub fn order(&self) -> Vec<&'static str> {
+        self.stages.iter().rev().map(|(name, _)| *name).collect()
+    }
+
+    /// Check the order against [`INVARIANTS`]
+    pub fn check(&self) -> Result<(), Violation> {
+        check(&self.order())
+    }
+
+    /// Apply every stage to an empty router
+    pub fn build(self) -> Router<AppState> {
+        self.stages.into_iter().fold(Router::new(), |router, (_, apply)| apply(router))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_reports_the_broken_rule() {
+        assert!(check(&["request_id", "access_log"]).is_ok());
+        // Rules with a missing stage do not apply
+        assert!(check(&["access_log"]).is_ok());
+
+        let violation = check(&["access_log", "request_id"]).unwrap_err();
+        assert_eq!((violation.before, violation.after), ("request_id", "access_log"));
+        assert_eq!(
+            violation.to_string(),
+            "request_id must run before access_log: log lines and captures carry the request ID"
+        );
+    }
+}
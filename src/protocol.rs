@@ -0,0 +1,223 @@
+/*!
+ * HTTP/1.1 and h2c connection settings for the origin listener
+ *
+ * cloudflared keeps a pool of persistent connections to the origin and
+ * reuses them for many requests, so how long an idle connection is kept
+ * matters more here than for a server facing browsers directly. The
+ * origin should hold idle connections longer than cloudflared does
+ * (`keepAliveTimeout`, 90 seconds by default): if the origin closes first,
+ * cloudflared can send a request on a connection that is just being torn
+ * down and report a 502.
+ *
+ * - `HTTP1_KEEP_ALIVE` (default `true`): `false` closes every connection
+ *   after one response
+ * - `HTTP1_KEEP_ALIVE_TIMEOUT_SECS` (default 120): how long a connection
+ *   may sit idle, or take to send a request's headers, before it is closed
+ * - `HTTP1_MAX_BUF_SIZE`: per-connection read and write buffer limit in
+ *   bytes, at least 8192 (hyper's default, about 400 KB, when unset)
+ * - `HTTP1_PIPELINE_FLUSH` (default `false`): hold back responses to
+ *   pipelined requests and write them together
+ *
+ * cloudflared uses HTTP/1.1 towards the origin unless `http2Origin` is set,
+ * in which case it speaks cleartext HTTP/2 with prior knowledge and
+ * multiplexes requests over fewer connections. Each connection's first bytes
+ * decide which protocol serves it; an `Upgrade: h2c` header on an HTTP/1.1
+ * request is ignored and the request answered over HTTP/1.1.
+ *
+ * - `HTTP2_ENABLED` (default `true`): `false` serves HTTP/1.1 only
+ * - `HTTP2_MAX_CONCURRENT_STREAMS`: streams one connection may have open
+ *   (hyper's default, 200, when unset)
+ * - `HTTP2_INITIAL_STREAM_WINDOW_SIZE` and
+ *   `HTTP2_INITIAL_CONNECTION_WINDOW_SIZE`: flow-control windows in bytes,
+ *   at most 2^31 - 1 (hyper's defaults, 1 MB and 5 MB, when unset)
+ */
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use hyper_util::server::conn::auto;
+use std::fmt;
+use std::time::Duration;
+
+/// Smallest buffer hyper accepts
+pub const MIN_BUF_SIZE: usize = 8192;
+
+/// Largest HTTP/2 flow-control window
+pub const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// Connection settings applied to every connection on the main and admin
+/// listeners
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolConfig {
+    /// Keep connections open between requests (`HTTP1_KEEP_ALIVE`)
+    pub keep_alive: bool,
+
+    /// Idle time and header read time allowed before a connection is
+    /// closed (`HTTP1_KEEP_ALIVE_TIMEOUT_SECS`)
+    pub keep_alive_timeout: Duration,
+
+    /// Per-connection buffer limit, hyper's default when `None`
+    /// (`HTTP1_MAX_BUF_SIZE`)
+    pub max_buf_size: Option<usize>,
+
+    /// Batch responses to pipelined requests (`HTTP1_PIPELINE_FLUSH`)
+    pub pipeline_flush: bool,
+
+    /// Accept h2c prior-knowledge connections (`HTTP2_ENABLED`)
+    pub http2: bool,
+
+    /// Open streams allowed per HTTP/2 connection, hyper's default when
+    /// `None` (`HTTP2_MAX_CONCURRENT_STREAMS`)
+    pub http2_max_concurrent_streams: Option<u32>,
+
+    /// Initial per-stream flow-control window, hyper's default when `None`
+    /// (`HTTP2_INITIAL_STREAM_WINDOW_SIZE`)
+    pub http2_initial_stream_window_size: Option<u32>,
+
+    /// Initial per-connection flow-control window, hyper's default when
+    /// `None` (`HTTP2_INITIAL_CONNECTION_WINDOW_SIZE`)
+    pub http2_initial_connection_window_size: Option<u32>,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            keep_alive_timeout: Duration::from_secs(120),
+            max_buf_size: None,
+            pipeline_flush: false,
+            http2: true,
+            http2_max_concurrent_streams: None,
+            http2_initial_stream_window_size: None,
+            http2_initial_connection_window_size: None,
+        }
+    }
+}
+
+impl ProtocolConfig {
+    /// Load from the `HTTP1_*` and `HTTP2_*` variables
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        let invalid = |name: &str, value: &str, expected: &str| {
+            crate::ServerError::ConfigError(format!("Invalid {} {:?}: expected {}", name, value, expected))
+        };
+
+        if let Ok(value) = std::env::var("HTTP1_KEEP_ALIVE") {
+            config.keep_alive = value.trim().parse().map_err(|_| invalid("HTTP1_KEEP_ALIVE", &value, "true or false"))?;
+        }
+        if let Ok(value) = std::env::var("HTTP1_KEEP_ALIVE_TIMEOUT_SECS") {
+            config.keep_alive_timeout = match value.trim().parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return Err(invalid("HTTP1_KEEP_ALIVE_TIMEOUT_SECS", &value, "a positive number of seconds")),
+            };
+        }
+        if let Ok(value) = std::env::var("HTTP1_MAX_BUF_SIZE") {
+            config.max_buf_size = match value.trim().parse::<usize>() {
+                Ok(size) if size >= MIN_BUF_SIZE => Some(size),
+                _ => {
+                    return Err(invalid(
+                        "HTTP1_MAX_BUF_SIZE",
+                        &value,
+                        &format!("a number of bytes, at least {}", MIN_BUF_SIZE),
+                    ))
+                }
+            };
+        }
+        if let Ok(value) = std::env::var("HTTP1_PIPELINE_FLUSH") {
+            config.pipeline_flush =
+                value.trim().parse().map_err(|_| invalid("HTTP1_PIPELINE_FLUSH", &value, "true or false"))?;
+        }
+        if let Ok(value) = std::env::var("HTTP2_ENABLED") {
+            config.http2 = value.trim().parse().map_err(|_| invalid("HTTP2_ENABLED", &value, "true or false"))?;
+        }
+        if let Ok(value) = std::env::var("HTTP2_MAX_CONCURRENT_STREAMS") {
+            config.http2_max_concurrent_streams = match value.trim().parse::<u32>() {
+                Ok(streams) if streams > 0 => Some(streams),
+                _ => return Err(invalid("HTTP2_MAX_CONCURRENT_STREAMS", &value, "a positive number")),
+            };
+        }
+        for (name, window) in [
+            ("HTTP2_INITIAL_STREAM_WINDOW_SIZE", &mut config.http2_initial_stream_window_size),
+            ("HTTP2_INITIAL_CONNECTION_WINDOW_SIZE", &mut config.http2_initial_connection_window_size),
+        ] {
+            if let Ok(value) = std::env::var(name) {
+                *window = match value.trim().parse::<u32>() {
+                    Ok(size) if size > 0 && size <= MAX_WINDOW_SIZE => Some(size),
+                    _ => {
+                        return Err(invalid(
+                            name,
+                            &value,
+                            &format!("a positive number of bytes, at most {}", MAX_WINDOW_SIZE),
+                        ))
+                    }
+                };
+            }
+        }
+        Ok(config)
+    }
+
+    /// A hyper HTTP/1.1 connection builder with these settings, for when
+    /// h2c is disabled
+    pub(crate) fn http1_builder(&self) -> http1::Builder {
+        let mut builder = http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .keep_alive(self.keep_alive)
+            .header_read_timeout(self.keep_alive_timeout)
+            .pipeline_flush(self.pipeline_flush);
+        if let Some(size) = self.max_buf_size {
+            builder.max_buf_size(size);
+        }
+        builder
+    }
+
+    /// A hyper connection builder serving HTTP/1.1 or h2c with these
+    /// settings, depending on what each client sends first
+    pub(crate) fn auto_builder(&self) -> auto::Builder<TokioExecutor> {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        let mut http1 = builder.http1();
+        http1
+            .timer(TokioTimer::new())
+            .keep_alive(self.keep_alive)
+            .header_read_timeout(self.keep_alive_timeout)
+            .pipeline_flush(self.pipeline_flush);
+        if let Some(size) = self.max_buf_size {
+            http1.max_buf_size(size);
+        }
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(self.http2_max_concurrent_streams)
+            .initial_stream_window_size(self.http2_initial_stream_window_size)
+            .initial_connection_window_size(self.http2_initial_connection_window_size);
+        builder
+    }
+}
+
+impl fmt::Display for ProtocolConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP/1.1")?;
+        if self.keep_alive {
+            write!(f, ", keep-alive {}s", self.keep_alive_timeout.as_secs())?;
+        } else {
+            write!(f, ", keep-alive off, header timeout {}s", self.keep_alive_timeout.as_secs())?;
+        }
+        if let Some(size) = self.max_buf_size {
+            write!(f, ", max buffer {} bytes", size)?;
+        }
+        if self.pipeline_flush {
+            write!(f, ", pipeline flush")?;
+        }
+        if self.http2 {
+            write!(f, "; h2c")?;
+            if let Some(streams) = self.http2_max_concurrent_streams {
+                write!(f, ", {} streams", streams)?;
+            }
+            if let Some(size) = self.http2_initial_stream_window_size {
+                write!(f, ", stream window {} bytes", size)?;
+            }
+            if let Some(size) = self.http2_initial_connection_window_size {
+                write!(f, ", connection window {} bytes", size)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+/*!
+ * Reverse proxy for path prefixes
+ *
+ * `PROXY_ROUTES` maps path prefixes to upstream base URLs, e.g.
+ * `/api=http://127.0.0.1:3000,/auth=http://127.0.0.1:4000/auth`. A request
+ * under a prefix is sent to the upstream with the prefix replaced by the
+ * upstream's path, so `/api/users` goes to `http://127.0.0.1:3000/users`
+ * and `/auth/login` to `http://127.0.0.1:4000/auth/login`.
+ *
+ * Bodies are streamed in both directions. Hop-by-hop headers (and any named
+ * in `Connection`) are dropped each way; `Host` is set to the upstream's,
+ * the original goes in `X-Forwarded-Host`, the TCP peer is appended to
+ * `X-Forwarded-For` and the visitor's scheme is sent as
+ * `X-Forwarded-Proto`. `PROXY_TIMEOUT_SECS` bounds connecting and waiting
+ * for the response head, or what is left of the request's own deadline when
+ * that is shorter (`504 deadline_exceeded`); a slow response body is not
+ * cut off. The wait is reported as `upstream` in `Server-Timing` (see
+ * [`crate::server_timing`]).
+ *
+ * Requests go through the shared `http_client`, so upstream connections are
+ * pooled and reused and show up in the per-host outbound metrics. Only
+ * `http://` upstreams are accepted. The routes sit inside the main router,
+ * so security headers and the cache policy apply to proxied responses too.
+ * Protocol upgrades (WebSockets) are not forwarded.
+ */
+use crate::error::ApiError;
+use crate::http_client::{HttpClient, HttpClientError};
+use crate::scheme::RequestScheme;
+use crate::server_timing::TimingCollector;
+use crate::timeouts::Deadline;
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::any,
+    Router,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Headers that describe a single connection and are never forwarded
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// One path prefix and the upstream it forwards to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyRoute {
+    /// Path prefix, e.g. `/api`, matched on segment boundaries
+    pub prefix: String,
+
+    /// Upstream base URL; its path replaces `prefix`
+    pub upstream: Uri,
+}
+
+/// Prefix-to-upstream routes and the upstream timeout
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub routes: Vec<ProxyRoute>,
+
+    /// Limit on connecting plus receiving the response head
+    pub timeout: Duration,
+}
+
+impl ProxyConfig {
+    /// Forward `prefix` to `upstream` with the default 30 second timeout
+    pub fn new(prefix: &str, upstream: &str) -> crate::Result<Self> {
+        let config = Self {
+            routes: vec![parse_route(prefix, upstream)?],
+            timeout: Duration::from_secs(30),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load from `PROXY_ROUTES` and `PROXY_TIMEOUT_SECS`. Returns `None`
+    /// when no routes are configured.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let Some(routes) = std::env::var("PROXY_ROUTES").ok().filter(|v| !v.trim().is_empty()) else {
+            return Ok(None);
+        };
+
+        let routes = routes
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let (prefix, upstream) = entry.split_once('=').ok_or_else(|| {
+                    crate::ServerError::ConfigError(format!(
+                        "Invalid PROXY_ROUTES entry {:?} (expected /prefix=http://host:port)",
+                        entry.trim()
+                    ))
+                })?;
+                parse_route(prefix.trim(), upstream.trim())
+            })
+            .collect::<crate::Result<Vec<_>>>()?;
+
+        let mut config = Self { routes, timeout: Duration::from_secs(30) };
+        if let Ok(value) = std::env::var("PROXY_TIMEOUT_SECS") {
+            let secs: u64 = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid PROXY_TIMEOUT_SECS: {}", e)
+                ))?;
+            config.timeout = Duration::from_secs(secs);
+        }
+
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    /// Require `/`-prefixed, non-root prefixes without a trailing slash,
+    /// each used once
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(crate::ServerError::ConfigError(msg));
+
+        for (i, route) in self.routes.iter().enumerate() {
+            if !route.prefix.starts_with('/') || route.prefix.ends_with('/') || route.prefix.contains('*') {
+                return invalid(format!(
+                    "Proxy prefix {:?} must start with /, not end with / and not be the root",
+                    route.prefix
+                ));
+            }
+            if self.routes[..i].iter().any(|other| other.prefix == route.prefix) {
+                return invalid(format!("Proxy prefix {:?} is configured twice", route.prefix));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_route(prefix: &str, upstream: &str) -> crate::Result<ProxyRoute> {
+    let invalid = || crate::ServerError::ConfigError(format!("Invalid proxy upstream URL {:?}", upstream));
+
+    let uri: Uri = upstream.parse().map_err(|_| invalid())?;
+    match uri.scheme_str() {
+        Some("http") => {}
+        Some(other) => {
+            return Err(crate::ServerError::ConfigError(format!(
+                "Unsupported proxy upstream scheme {} in {:?} (only http is available)",
+                other, upstream
+            )))
+        }
+        None => return Err(invalid()),
+    }
+    if uri.host().is_none() || uri.query().is_some() {
+        return Err(invalid());
+    }
+
+    Ok(ProxyRoute { prefix: prefix.to_string(), upstream: uri })
+}
+
+/// Mount every configured prefix on `router`, sending upstream requests
+/// through `http`
+pub fn mount<S: Clone + Send + Sync + 'static>(mut router: Router<S>, config: &ProxyConfig, http: &HttpClient) -> Router<S> {
+    for route in &config.routes {
+        let prefix = route.prefix.clone();
+        let route = Arc::new(route.clone());
+        let (http, timeout) = (http.clone(), config.timeout);
+        let handler = any(move |request: Request| forward(route, http, timeout, request));
+
+        router = router
+            .route(&prefix, handler.clone())
+            .route(&format!("{}/*rest", prefix), handler);
+    }
+    router
+}
+
+async fn forward(route: Arc<ProxyRoute>, http: HttpClient, timeout: Duration, request: Request) -> Response {
+    let (mut parts, body) = request.into_parts();
+
+    // What is left of the request's own deadline, when that is shorter
+    let deadline = parts.extensions.get::<Deadline>().copied();
+    if deadline.is_some_and(|deadline| deadline.is_expired()) {
+        return deadline_exceeded(&route.upstream);
+    }
+    let budget = deadline.map_or(timeout, |deadline| deadline.budget(timeout));
+    let timing = parts.extensions.get::<TimingCollector>().cloned();
+
+    let uri = match upstream_uri(&route, &parts.uri) {
+        Some(uri) => uri,
+        None => {
+            return ApiError::new(StatusCode::BAD_REQUEST, "bad_request", "Path cannot be forwarded")
+                .into_response()
+        }
+    };
+
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let scheme = parts.extensions.get::<RequestScheme>().copied();
+    forwarded_headers(&mut parts.headers, &route.upstream, peer, scheme);
+
+    parts.uri = uri;
+    parts.extensions = Default::default();
+    let request = Request::from_parts(parts, body);
+
+    let target = route.upstream.clone();
+    let started = Instant::now();
+    let result = http.with_timeout(budget).stream(request).await;
+    if let Some(timing) = timing {
+        timing.record("upstream", started.elapsed());
+    }
+    match result {
+        Ok(mut response) => {
+            strip_hop_by_hop(response.headers_mut());
+            response
+        }
+        Err(HttpClientError::Timeout(_)) if budget < timeout => deadline_exceeded(&target),
+        Err(e) if e.is_timeout() => {
+            warn!("Proxy upstream {} did not respond in time: {}", target, e);
+            ApiError::new(StatusCode::GATEWAY_TIMEOUT, "upstream_timeout", "Upstream service timed out")
+                .into_response()
+        }
+        Err(e @ HttpClientError::Connect { .. }) => {
+            warn!("Proxy upstream {} unreachable: {}", target, e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "upstream_unavailable", "Upstream service is unavailable")
+                .into_response()
+        }
+        Err(e) => {
+            warn!("Proxy request to {} failed: {}", target, e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "bad_gateway", "Upstream service returned an invalid response")
+                .into_response()
+        }
+    }
+}
+
+fn deadline_exceeded(target: &Uri) -> Response {
+    warn!("Request deadline ran out before proxy upstream {} responded", target);
+    ApiError::new(
+        StatusCode::GATEWAY_TIMEOUT,
+        "deadline_exceeded",
+        "The request's time ran out before the upstream service responded",
+    )
+    .into_response()
+}
+
+/// Absolute URL on the upstream: the request path with `route.prefix`
+/// replaced by the upstream's base path
+fn upstream_uri(route: &ProxyRoute, original: &Uri) -> Option<Uri> {
+    let rest = original.path().strip_prefix(&route.prefix)?;
+    let base = route.upstream.path().trim_end_matches('/');
+
+    let mut path = format!("{}{}", base, rest);
+    if path.is_empty() {
+        path.push('/');
+    }
+    if let Some(query) = original.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+
+    let mut parts = route.upstream.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path).ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// Rewrite request headers for the upstream hop
+fn forwarded_headers(headers: &mut HeaderMap, upstream: &Uri, peer: Option<std::net::IpAddr>, scheme: Option<RequestScheme>) {
+    strip_hop_by_hop(headers);
+
+    if let Some(host) = headers.remove(header::HOST) {
+        headers.insert("x-forwarded-host", host);
+    }
+    if let Some(authority) = upstream.authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()) {
+        headers.insert(header::HOST, authority);
+    }
+
+    if let Some(peer) = peer {
+        let chain = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+            Some(existing) => format!("{}, {}", existing, peer),
+            None => peer.to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&chain) {
+            headers.insert("x-forwarded-for", value);
+        }
+    }
+
+    match scheme {
+        Some(RequestScheme::Http) => {
+            headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+        }
+        Some(RequestScheme::Https) => {
+            headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        }
+        // Leave whatever the previous hop sent
+        Some(RequestScheme::Unknown) | None => {}
+    }
+}
+
+/// Remove hop-by-hop headers, including any listed in `Connection`
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+    for name in listed {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, upstream: &str) -> ProxyRoute {
+        parse_route(prefix, upstream).expect("Invalid route")
+    }
+
+    #[test]
+    fn test_upstream_uri_replaces_prefix() {
+        let api = route("/api", "http://127.0.0.1:3000");
+        let uri = |path: &str| upstream_uri(&api, &path.parse().unwrap()).map(|u| u.to_string());
+
+        assert_eq!(uri("/api/users?page=2").as_deref(), Some("http://127.0.0.1:3000/users?page=2"));
+        assert_eq!(uri("/api").as_deref(), Some("http://127.0.0.1:3000/"));
+
+        let auth = route("/auth", "http://127.0.0.1:4000/auth/");
+        let uri = upstream_uri(&auth, &"/auth/login".parse().unwrap()).unwrap();
+        assert_eq!(uri.to_string(), "http://127.0.0.1:4000/auth/login");
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("keep-alive, x-session-hint"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-session-hint", HeaderValue::from_static("abc"));
+        headers.insert(header::TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[header::ACCEPT], "*/*");
+    }
+
+    #[test]
+    fn test_config_validation() {
+        assert!(ProxyConfig::new("/api", "http://localhost:3000").is_ok());
+        assert!(ProxyConfig::new("/", "http://localhost:3000").is_err());
+        assert!(ProxyConfig::new("api", "http://localhost:3000").is_err());
+        assert!(ProxyConfig::new("/api/", "http://localhost:3000").is_err());
+        assert!(ProxyConfig::new("/api", "https://example.com").is_err());
+        assert!(ProxyConfig::new("/api", "localhost:3000").is_err());
+    }
+}
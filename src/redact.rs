@@ -0,0 +1,84 @@
+/*!
+ * Redaction of sensitive header values
+ *
+ * Debug output that echoes request headers must never reveal credentials.
+ * Values of the headers listed here are replaced with a fixed placeholder.
+ */
+use axum::http::HeaderMap;
+use serde_json::{Map, Value};
+
+/// Placeholder written in place of a sensitive value
+pub const REDACTED: &str = "<redacted>";
+
+/// Headers whose values are always redacted (lowercase)
+pub const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "cf-access-jwt-assertion",
+    "cf-access-client-secret",
+];
+
+/// Whether `name` (any case) carries credentials
+pub fn is_sensitive(name: &str) -> bool {
+    SENSITIVE_HEADERS.iter().any(|s| s.eq_ignore_ascii_case(name))
+}
+
+/// Render headers as a JSON object with sensitive values redacted.
+///
+/// Single-valued headers map to a string, repeated headers to an array.
+pub fn headers_to_json(headers: &HeaderMap) -> Value {
+    let mut map = Map::new();
+
+    for name in headers.keys() {
+        let values: Vec<Value> = headers
+            .get_all(name)
+            .iter()
+            .map(|value| {
+                if is_sensitive(name.as_str()) {
+                    Value::String(REDACTED.to_string())
+                } else {
+                    Value::String(String::from_utf8_lossy(value.as_bytes()).into_owned())
+                }
+            })
+            .collect();
+
+        let entry = if values.len() == 1 {
+            values.into_iter().next().unwrap_or(Value::Null)
+        } else {
+            Value::Array(values)
+        };
+        map.insert(name.as_str().to_string(), entry);
+    }
+
+    Value::Object(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_sensitive_headers_are_redacted() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_static("Bearer secret-token"));
+        headers.insert("user-agent", HeaderValue::from_static("curl/8.0"));
+
+        let json = headers_to_json(&headers);
+        assert_eq!(json["authorization"], REDACTED);
+        assert_eq!(json["user-agent"], "curl/8.0");
+    }
+
+    #[test]
+    fn test_repeated_headers_become_arrays() {
+        let mut headers = HeaderMap::new();
+        headers.append("accept", HeaderValue::from_static("text/html"));
+        headers.append("accept", HeaderValue::from_static("application/json"));
+
+        let json = headers_to_json(&headers);
+        assert_eq!(json["accept"], serde_json::json!(["text/html", "application/json"]));
+    }
+}
@@ -0,0 +1,293 @@
+/*!
+ * `POST /reports`: Reporting API endpoint
+ *
+ * With `SECURITY_REPORT_TO` naming an endpoint group (e.g. `default`), every
+ * response carries `Reporting-Endpoints: default="/reports"`, the CSP gains
+ * `report-to default` and each Permissions-Policy feature gets
+ * `;report-to=default`. Browsers then POST batches of reports here as an
+ * `application/reports+json` array of `{type, age, url, user_agent, body}`.
+ *
+ * Each report is parsed by its `type` into a [`ReportBody`]:
+ * `csp-violation`, `permissions-policy-violation` and `deprecation` get
+ * typed bodies and are logged at `info`; any other type is logged at
+ * `debug` and kept as [`ReportBody::Unknown`], so browsers adding report
+ * types never get their batches rejected. With the `metrics` feature,
+ * reports are counted per type in `reports_total{type="..."}`, with
+ * unrecognised types under `type="unknown"`. A body that is not a JSON
+ * array gets `400 invalid_report`; an accepted batch gets `204`.
+ */
+use crate::error::{ApiError, ErrorBody};
+use crate::state::AppState;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::StatusCode,
+    routing::post,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info};
+
+/// Where `Reporting-Endpoints` sends reports
+pub const PATH: &str = "/reports";
+
+/// A `csp-violation` report body
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CspViolation {
+    #[serde(rename = "documentURL")]
+    pub document_url: Option<String>,
+    #[serde(rename = "blockedURL")]
+    pub blocked_url: Option<String>,
+    pub effective_directive: Option<String>,
+    pub original_policy: Option<String>,
+
+    /// `enforce` or `report`
+    pub disposition: Option<String>,
+    pub source_file: Option<String>,
+    pub line_number: Option<u64>,
+    pub column_number: Option<u64>,
+
+    /// Start of the blocked inline script or style, if the policy asks
+    /// for samples
+    pub sample: Option<String>,
+    pub status_code: Option<u16>,
+}
+
+/// A `permissions-policy-violation` report body
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PermissionsPolicyViolation {
+    /// The feature that was used, e.g. `geolocation`
+    pub feature_id: Option<String>,
+    pub disposition: Option<String>,
+    pub message: Option<String>,
+    pub source_file: Option<String>,
+    pub line_number: Option<u64>,
+    pub column_number: Option<u64>,
+}
+
+/// A `deprecation` report body
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Deprecation {
+    /// Identifier of the deprecated feature
+    pub id: Option<String>,
+    pub message: Option<String>,
+
+    /// When the browser plans to remove the feature
+    pub anticipated_removal: Option<String>,
+    pub source_file: Option<String>,
+    pub line_number: Option<u64>,
+    pub column_number: Option<u64>,
+}
+
+/// A report's body, by its `type`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReportBody {
+    CspViolation(CspViolation),
+    PermissionsPolicyViolation(PermissionsPolicyViolation),
+    Deprecation(Deprecation),
+
+    /// A type this service does not know, e.g. `intervention`
+    Unknown { report_type: String },
+}
+
+impl ReportBody {
+    /// Report types with a typed body, as labelled in `reports_total`
+    pub const TYPES: [&'static str; 3] = ["csp-violation", "permissions-policy-violation", "deprecation"];
+
+    /// The `type` this body was parsed from
+    pub fn report_type(&self) -> &str {
+        match self {
+            ReportBody::CspViolation(_) => "csp-violation",
+            ReportBody::PermissionsPolicyViolation(_) => "permissions-policy-violation",
+            ReportBody::Deprecation(_) => "deprecation",
+            ReportBody::Unknown { report_type } => report_type,
+        }
+    }
+
+    /// Body of a `report_type` report; a known type whose body does not
+    /// fit its struct gets an empty one
+    fn parse(report_type: &str, body: Value) -> Self {
+        fn typed<T: serde::de::DeserializeOwned + Default>(body: Value) -> T {
+            serde_json::from_value(body).unwrap_or_default()
+        }
+        match report_type {
+            "csp-violation" => ReportBody::CspViolation(typed(body)),
+            "permissions-policy-violation" => ReportBody::PermissionsPolicyViolation(typed(body)),
+            "deprecation" => ReportBody::Deprecation(typed(body)),
+            other => ReportBody::Unknown { report_type: other.to_string() },
+        }
+    }
+}
+
+/// One report of a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Document the report is about
+    pub url: Option<String>,
+
+    /// Milliseconds between the report being generated and sent
+    pub age: Option<u64>,
+    pub user_agent: Option<String>,
+    pub body: ReportBody,
+}
+
+/// A report as sent, before its body is typed
+#[derive(Deserialize)]
+struct RawReport {
+    #[serde(rename = "type")]
+    report_type: String,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    age: Option<u64>,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    body: Value,
+}
+
+/// Parse an `application/reports+json` batch
+pub fn parse_batch(body: &[u8]) -> Result<Vec<Report>, serde_json::Error> {
+    let raw: Vec<RawReport> = serde_json::from_slice(body)?;
+    Ok(raw
+        .into_iter()
+        .map(|report| Report {
+            body: ReportBody::parse(&report.report_type, report.body),
+            url: report.url,
+            age: report.age,
+            user_agent: report.user_agent,
+        })
+        .collect())
+}
+
+/// Reports received per type; clones share the counts
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone)]
+pub struct ReportCounters {
+    /// One per `ReportBody::TYPES` entry, then unknown types
+    by_type: Vec<crate::metrics::Counter>,
+}
+
+#[cfg(feature = "metrics")]
+impl ReportCounters {
+    pub fn register(metrics: &crate::metrics::Metrics) -> Self {
+        Self {
+            by_type: ReportBody::TYPES
+                .iter()
+                .chain(&["unknown"])
+                .map(|report_type| metrics.counter(
+                    &format!("reports_total{{type=\"{}\"}}", report_type),
+                    "Reporting API reports received at /reports, by type",
+                ))
+                .collect(),
+        }
+    }
+
+    /// Counter for reports like `body`
+    pub fn report_type(&self, body: &ReportBody) -> &crate::metrics::Counter {
+        let index = ReportBody::TYPES
+            .iter()
+            .position(|known| *known == body.report_type())
+            .unwrap_or(ReportBody::TYPES.len());
+        &self.by_type[index]
+    }
+}
+
+/// `POST /reports`
+pub fn routes() -> Router<AppState> {
+    Router::new().route(PATH, post(receive))
+}
+
+/// Reporting API batch (CSP, Permissions-Policy, deprecation reports)
+///
+/// Each report of the batch is logged and counted.
+#[utoipa::path(
+    post,
+    path = PATH,
+    request_body(content = Vec<Object>, content_type = "application/reports+json"),
+    responses(
+        (status = NO_CONTENT, description = "Reports logged and counted"),
+        (status = BAD_REQUEST, description = "Not a JSON array of reports", body = ErrorBody),
+    ),
+)]
+async fn receive(State(state): State<AppState>, body: Bytes) -> Result<StatusCode, ApiError> {
+    #[cfg(not(feature = "metrics"))]
+    let _ = state;
+    let reports = parse_batch(&body).map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_report",
+            format!("Expected a JSON array of reports: {}", e),
+        )
+    })?;
+
+    for report in reports {
+        #[cfg(feature = "metrics")]
+        state.reports.report_type(&report.body).inc();
+
+        let url = report.url.as_deref().unwrap_or("");
+        match &report.body {
+            ReportBody::CspViolation(violation) => info!(
+                url,
+                directive = violation.effective_directive.as_deref().unwrap_or(""),
+                blocked = violation.blocked_url.as_deref().unwrap_or(""),
+                disposition = violation.disposition.as_deref().unwrap_or(""),
+                "CSP violation reported"
+            ),
+            ReportBody::PermissionsPolicyViolation(violation) => info!(
+                url,
+                feature = violation.feature_id.as_deref().unwrap_or(""),
+                disposition = violation.disposition.as_deref().unwrap_or(""),
+                source = violation.source_file.as_deref().unwrap_or(""),
+                "Permissions-Policy violation reported"
+            ),
+            ReportBody::Deprecation(deprecation) => info!(
+                url,
+                id = deprecation.id.as_deref().unwrap_or(""),
+                message = deprecation.message.as_deref().unwrap_or(""),
+                "Deprecation reported"
+            ),
+            ReportBody::Unknown { report_type } => debug!(url, report_type, "Report of unknown type"),
+        }
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_batch_types_each_report() {
+        let batch = br#"[
+            {"type": "permissions-policy-violation", "age": 10, "url": "https://example.com/map",
+             "user_agent": "Mozilla/5.0",
+             "body": {"featureId": "geolocation", "disposition": "enforce", "lineNumber": 12}},
+            {"type": "intervention", "url": "https://example.com/", "body": {"id": "x"}},
+            {"type": "deprecation", "body": {"id": "UnloadHandler", "lineNumber": "not a number"}}
+        ]"#;
+        let reports = parse_batch(batch).unwrap();
+
+        assert_eq!(reports[0].age, Some(10));
+        assert_eq!(reports[0].user_agent.as_deref(), Some("Mozilla/5.0"));
+        assert_eq!(
+            reports[0].body,
+            ReportBody::PermissionsPolicyViolation(PermissionsPolicyViolation {
+                feature_id: Some("geolocation".to_string()),
+                disposition: Some("enforce".to_string()),
+                line_number: Some(12),
+                ..Default::default()
+            })
+        );
+        assert_eq!(reports[1].body, ReportBody::Unknown { report_type: "intervention".to_string() });
+        // A malformed body still counts as its type
+        assert_eq!(reports[2].body, ReportBody::Deprecation(Deprecation::default()));
+
+        assert!(parse_batch(br#"{"type": "csp-violation"}"#).is_err());
+        assert!(parse_batch(br#"[{"body": {}}]"#).is_err());
+    }
+}
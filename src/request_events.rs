@@ -0,0 +1,399 @@
+/*!
+ * Request lifecycle events
+ *
+ * Features that react to finished requests subscribe to one
+ * [`RequestCompleted`] event instead of each adding a middleware stage.
+ * The `request_events` stage publishes the event once per request, when
+ * the response head leaves the pipeline: method, matched route, path,
+ * status, time to the response head, client address, caller identity and
+ * response size when it is known up front.
+ *
+ * Events go into a bounded broadcast channel of `REQUEST_EVENTS_CAPACITY`
+ * events (default 1024, rounded up to a power of two). Publishing never
+ * waits: each subscriber runs in its own task and consumes at its own pace,
+ * and one that falls a full channel behind loses the oldest events it has
+ * not seen. Lost events are logged and counted per subscriber, in
+ * [`RequestEvents::dropped`] and, with metrics, in
+ * `request_events_dropped_total`.
+ *
+ * Only stages inside the router see the matched route, so the
+ * `matched_route` stage hands it (and the path as routed) outwards on the
+ * response. Subscribers are registered when the router is built; see
+ * [`crate::slo::subscribe`] and [`crate::unknown_paths::subscribe`].
+ */
+use crate::audit::{self, Identity};
+use crate::client_ip::ClientIp;
+use crate::state::AppState;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+/// `REQUEST_EVENTS_CAPACITY`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestEventsConfig {
+    /// Events a subscriber may fall behind before it loses the oldest
+    pub capacity: usize,
+}
+
+impl Default for RequestEventsConfig {
+    fn default() -> Self {
+        Self { capacity: 1024 }
+    }
+}
+
+impl RequestEventsConfig {
+    /// Load from `REQUEST_EVENTS_CAPACITY`, which must be at least 1
+    pub fn from_env() -> crate::Result<Self> {
+        let Ok(value) = std::env::var("REQUEST_EVENTS_CAPACITY") else {
+            return Ok(Self::default());
+        };
+        match value.trim().parse() {
+            Ok(capacity) if capacity > 0 => Ok(Self { capacity }),
+            _ => Err(crate::ServerError::ConfigError(format!(
+                "Invalid REQUEST_EVENTS_CAPACITY {:?}: expected a positive number",
+                value
+            ))),
+        }
+    }
+}
+
+/// A request whose response head has been sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestCompleted {
+    pub method: Method,
+
+    /// Route pattern that matched, e.g. `/webhooks/:name`; `None` when no
+    /// route did or the request never reached the router
+    pub route: Option<String>,
+
+    /// Path as routed, without the query
+    pub path: String,
+    pub status: StatusCode,
+
+    /// Time until the response head
+    pub latency: Duration,
+    pub client_ip: Option<IpAddr>,
+
+    /// Cloudflare Access user or API key the request came with
+    pub identity: Option<Identity>,
+
+    /// Response body size, when the response declared it
+    pub bytes: Option<u64>,
+}
+
+type Handler = Arc<dyn Fn(Arc<RequestCompleted>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Consumption counts of one subscriber
+#[derive(Debug, Default)]
+struct Progress {
+    /// Events handled or dropped, counting from the channel's start
+    seen: AtomicU64,
+    dropped: AtomicU64,
+}
+
+struct Subscriber {
+    name: &'static str,
+    progress: Arc<Progress>,
+
+    /// Consumer task, once a runtime was available to spawn it on
+    task: Option<JoinHandle<()>>,
+
+    /// Consumer waiting for the first event published from a runtime
+    pending: Option<BoxFuture<'static, ()>>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+/// The bus between the `request_events` stage and its subscribers; clones
+/// share it
+#[derive(Clone)]
+pub struct RequestEvents {
+    sender: broadcast::Sender<Arc<RequestCompleted>>,
+    published: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    #[cfg(feature = "metrics")]
+    dropped_counter: Option<crate::metrics::Counter>,
+}
+
+impl std::fmt::Debug for RequestEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestEvents")
+            .field("published", &self.published())
+            .field("dropped", &self.dropped())
+            .field("subscribers", &self.subscribers())
+            .finish()
+    }
+}
+
+impl Default for RequestEvents {
+    fn default() -> Self {
+        Self::new(RequestEventsConfig::default())
+    }
+}
+
+impl RequestEvents {
+    /// Bus without subscribers, holding `config.capacity` events
+    pub fn new(config: RequestEventsConfig) -> Self {
+        Self {
+            sender: broadcast::channel(config.capacity.max(1)).0,
+            published: Arc::default(),
+            dropped: Arc::default(),
+            subscribers: Arc::default(),
+            #[cfg(feature = "metrics")]
+            dropped_counter: None,
+        }
+    }
+
+    /// Also count dropped events as `request_events_dropped_total` in `metrics`
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: &crate::metrics::Metrics) -> Self {
+        self.dropped_counter = Some(metrics.counter(
+            "request_events_dropped_total",
+            "Request events a subscriber fell too far behind to see",
+        ));
+        self
+    }
+
+    /// Run `handler` on every event published from now on, in a task of its
+    /// own. A subscriber registered under a name already in use replaces
+    /// the earlier one. Outside a Tokio runtime the task starts with the
+    /// first event published from one; no event is missed meanwhile.
+    pub fn subscribe<F, Fut>(&self, name: &'static str, handler: F)
+    where
+        F: Fn(Arc<RequestCompleted>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |event| handler(event).boxed());
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        let progress = Arc::new(Progress::default());
+        progress.seen.store(self.published(), Ordering::Relaxed);
+        let consumer = consume(name, self.sender.subscribe(), handler, progress.clone(), self.clone_counters());
+
+        let mut subscriber = Subscriber { name, progress, task: None, pending: None };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => subscriber.task = Some(runtime.spawn(consumer)),
+            Err(_) => subscriber.pending = Some(consumer.boxed()),
+        }
+        subscribers.retain(|existing| existing.name != name);
+        subscribers.push(subscriber);
+    }
+
+    /// Publish `event` to every subscriber without waiting for any
+    pub fn publish(&self, event: RequestCompleted) {
+        self.start_pending();
+        self.published.fetch_add(1, Ordering::Relaxed);
+        // An error only means there are no subscribers
+        let _ = self.sender.send(Arc::new(event));
+    }
+
+    /// Spawn consumers registered before a runtime was available
+    fn start_pending(&self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        for subscriber in subscribers.iter_mut() {
+            if let Some(consumer) = subscriber.pending.take() {
+                subscriber.task = Some(runtime.spawn(consumer));
+            }
+        }
+    }
+
+    /// Events published so far
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Events lost by subscribers that fell behind, summed over subscribers
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Names of the subscribers with the events each has dropped
+    pub fn subscribers(&self) -> Vec<(&'static str, u64)> {
+        let subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers
+            .iter()
+            .map(|subscriber| (subscriber.name, subscriber.progress.dropped.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Wait until every subscriber has handled, or dropped, each event
+    /// published before the call
+    pub async fn flush(&self) {
+        self.start_pending();
+        let published = self.published();
+        loop {
+            let behind = {
+                let subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+                subscribers.iter().any(|subscriber| subscriber.progress.seen.load(Ordering::Acquire) < published)
+            };
+            if !behind {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    fn clone_counters(&self) -> DropCounters {
+        DropCounters {
+            total: self.dropped.clone(),
+            #[cfg(feature = "metrics")]
+            metric: self.dropped_counter.clone(),
+        }
+    }
+}
+
+/// Where a consumer adds the events it lost
+struct DropCounters {
+    total: Arc<AtomicU64>,
+    #[cfg(feature = "metrics")]
+    metric: Option<crate::metrics::Counter>,
+}
+
+/// Hand each event from `receiver` to `handler`, one at a time
+async fn consume(
+    name: &'static str,
+    mut receiver: broadcast::Receiver<Arc<RequestCompleted>>,
+    handler: Handler,
+    progress: Arc<Progress>,
+    counters: DropCounters,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if AssertUnwindSafe(handler(event)).catch_unwind().await.is_err() {
+                    error!(subscriber = name, "Request event subscriber panicked");
+                }
+                progress.seen.fetch_add(1, Ordering::Release);
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!(subscriber = name, missed, "Request event subscriber fell behind; oldest events dropped");
+                progress.dropped.fetch_add(missed, Ordering::Relaxed);
+                counters.total.fetch_add(missed, Ordering::Relaxed);
+                #[cfg(feature = "metrics")]
+                if let Some(counter) = &counters.metric {
+                    counter.add(missed);
+                }
+                progress.seen.fetch_add(missed, Ordering::Release);
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// The route and path the router saw, carried out on the response
+#[derive(Debug, Clone)]
+struct Routed {
+    route: Option<String>,
+    path: String,
+}
+
+/// Middleware inside the router recording the matched route and the path
+/// as routed for [`publish`]
+pub async fn matched_route(request: Request, next: Next) -> Response {
+    let routed = Routed {
+        route: request.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string()),
+        path: request.uri().path().to_string(),
+    };
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(routed);
+    response
+}
+
+/// Middleware publishing a [`RequestCompleted`] for each response
+pub async fn publish(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let client_ip = request.extensions().get::<ClientIp>().and_then(|client| client.ip);
+    let identity = audit::caller(request.extensions().get(), request.headers(), &state);
+
+    let response = next.run(request).await;
+
+    let routed = response.extensions().get::<Routed>().cloned();
+    state.request_events.publish(RequestCompleted {
+        method,
+        route: routed.as_ref().and_then(|routed| routed.route.clone()),
+        path: routed.map_or(path, |routed| routed.path),
+        status: response.status(),
+        latency: started.elapsed(),
+        client_ip,
+        identity,
+        bytes: response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+    });
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(status: u16) -> RequestCompleted {
+        RequestCompleted {
+            method: Method::GET,
+            route: None,
+            path: "/".to_string(),
+            status: StatusCode::from_u16(status).unwrap(),
+            latency: Duration::ZERO,
+            client_ip: None,
+            identity: None,
+            bytes: None,
+        }
+    }
+
+    #[test]
+    fn test_subscribers_wait_for_a_runtime() {
+        let events = RequestEvents::default();
+        let seen = Arc::new(AtomicU64::new(0));
+        let counter = seen.clone();
+        events.subscribe("count", move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            async {}
+        });
+        // Published before any runtime: kept for the consumer
+        events.publish(event(200));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(async {
+            events.publish(event(500));
+            events.flush().await;
+        });
+        assert_eq!(seen.load(Ordering::Relaxed), 2);
+        assert_eq!(events.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_same_name_replaces() {
+        let events = RequestEvents::default();
+        events.subscribe("slo", |_| async {});
+        events.subscribe("slo", |_| async {});
+        events.subscribe("other", |_| async {});
+        assert_eq!(events.subscribers(), [("slo", 0), ("other", 0)]);
+    }
+}
@@ -0,0 +1,269 @@
+/*!
+ * Request IDs
+ *
+ * Every request gets an ID, recorded as `request_id` on its tracing span,
+ * handed to handlers as the [`RequestId`] extension and returned in the
+ * `REQUEST_ID_HEADER` response header (default `X-Request-Id`). The request
+ * headers are left as they arrived, so the header limits see what the
+ * client sent.
+ *
+ * An ID the client already sent is kept when `REQUEST_ID_TRUST` allows:
+ *
+ * - `always`: from anyone
+ * - `cloudflare-only` (default): only on requests that came through the
+ *   tunnel (`CF-Ray` and `CF-Connecting-IP` present)
+ * - `never`: a new ID is always generated
+ *
+ * Values over 128 characters or with characters other than ASCII letters,
+ * digits and `-_.:` are never kept. New IDs are `REQUEST_ID_FORMAT`:
+ * `uuid-v4` (default), the time-ordered `uuid-v7`, or `ulid`. With
+ * `REQUEST_ID_ECHO_HEADER` set, a well-formed incoming ID that was replaced
+ * is returned under that header so callers can still correlate it.
+ */
+use crate::clock::Clock;
+use crate::config::AppConfig;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::{Serialize, Serializer};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use ulid::Ulid;
+use uuid::{NoContext, Timestamp, Uuid};
+
+/// Longest incoming ID that is kept
+pub const MAX_LENGTH: usize = 128;
+
+/// Which incoming IDs are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RequestIdTrust {
+    Always,
+    #[default]
+    CloudflareOnly,
+    Never,
+}
+
+impl FromStr for RequestIdTrust {
+    type Err = crate::ServerError;
+
+    fn from_str(value: &str) -> crate::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "always" => Ok(Self::Always),
+            "cloudflare-only" => Ok(Self::CloudflareOnly),
+            "never" => Ok(Self::Never),
+            other => Err(crate::ServerError::ConfigError(format!(
+                "Invalid REQUEST_ID_TRUST {:?} (expected always, cloudflare-only or never)",
+                other
+            ))),
+        }
+    }
+}
+
+/// How new IDs are generated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RequestIdFormat {
+    #[default]
+    UuidV4,
+    UuidV7,
+    Ulid,
+}
+
+impl FromStr for RequestIdFormat {
+    type Err = crate::ServerError;
+
+    fn from_str(value: &str) -> crate::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "uuid-v4" => Ok(Self::UuidV4),
+            "uuid-v7" => Ok(Self::UuidV7),
+            "ulid" => Ok(Self::Ulid),
+            other => Err(crate::ServerError::ConfigError(format!(
+                "Invalid REQUEST_ID_FORMAT {:?} (expected uuid-v4, uuid-v7 or ulid)",
+                other
+            ))),
+        }
+    }
+}
+
+impl RequestIdFormat {
+    /// A new ID in this format; time-ordered formats embed `now`
+    pub fn generate(self, now: SystemTime) -> String {
+        match self {
+            Self::UuidV4 => Uuid::new_v4().to_string(),
+            Self::UuidV7 => {
+                let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let timestamp = Timestamp::from_unix(NoContext, since_epoch.as_secs(), since_epoch.subsec_nanos());
+                Uuid::new_v7(timestamp).to_string()
+            }
+            Self::Ulid => Ulid::from_datetime(now).to_string(),
+        }
+    }
+}
+
+/// Whether an incoming `value` may be kept as the ID
+pub fn is_valid(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_LENGTH
+        && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+fn serialize_header<S: Serializer>(name: &HeaderName, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(name.as_str())
+}
+
+fn serialize_optional_header<S: Serializer>(name: &Option<HeaderName>, serializer: S) -> Result<S::Ok, S::Error> {
+    match name {
+        Some(name) => serializer.serialize_some(name.as_str()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Where the ID travels and when an incoming one is kept
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RequestIdConfig {
+    /// Header read from the request and set on the response
+    #[serde(serialize_with = "serialize_header")]
+    pub header: HeaderName,
+
+    pub trust: RequestIdTrust,
+    pub format: RequestIdFormat,
+
+    /// Header returning a replaced incoming ID, if any
+    #[serde(serialize_with = "serialize_optional_header")]
+    pub echo_header: Option<HeaderName>,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        Self {
+            header: HeaderName::from_static("x-request-id"),
+            trust: RequestIdTrust::default(),
+            format: RequestIdFormat::default(),
+            echo_header: None,
+        }
+    }
+}
+
+impl RequestIdConfig {
+    /// Load from `REQUEST_ID_HEADER`, `REQUEST_ID_TRUST`, `REQUEST_ID_FORMAT`
+    /// and `REQUEST_ID_ECHO_HEADER`
+    pub fn from_env() -> crate::Result<Self> {
+        let header_env = |name: &str| -> crate::Result<Option<HeaderName>> {
+            match std::env::var(name) {
+                Ok(value) => HeaderName::from_str(value.trim()).map(Some).map_err(|e| {
+                    crate::ServerError::ConfigError(format!("Invalid {} {:?}: {}", name, value, e))
+                }),
+                Err(_) => Ok(None),
+            }
+        };
+
+        let mut config = Self::default();
+        if let Some(header) = header_env("REQUEST_ID_HEADER")? {
+            config.header = header;
+        }
+        if let Ok(value) = std::env::var("REQUEST_ID_TRUST") {
+            config.trust = value.parse()?;
+        }
+        if let Ok(value) = std::env::var("REQUEST_ID_FORMAT") {
+            config.format = value.parse()?;
+        }
+        config.echo_header = header_env("REQUEST_ID_ECHO_HEADER")?;
+        if config.echo_header.as_ref() == Some(&config.header) {
+            return Err(crate::ServerError::ConfigError(
+                "REQUEST_ID_ECHO_HEADER must differ from REQUEST_ID_HEADER".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// The ID assigned to the current request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Middleware assigning every request its ID
+pub async fn assign(
+    State(config): State<Arc<AppConfig>>,
+    State(clock): State<Arc<dyn Clock>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = &config.request_id;
+    let incoming = request
+        .headers()
+        .get(&config.header)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid(v))
+        .map(str::to_string);
+    let trusted = match config.trust {
+        RequestIdTrust::Always => true,
+        RequestIdTrust::CloudflareOnly => crate::direct_access::came_through_cloudflare(&request),
+        RequestIdTrust::Never => false,
+    };
+
+    let (id, replaced) = match incoming {
+        Some(incoming) if trusted => (incoming, None),
+        incoming => (config.format.generate(clock.now()), incoming),
+    };
+    tracing::Span::current().record("request_id", id.as_str());
+
+    let value = HeaderValue::from_str(&id);
+    request.extensions_mut().insert(RequestId(id));
+
+    let mut response = next.run(request).await;
+    // Generated and validated IDs are always valid header values
+    if let Ok(value) = value {
+        response.headers_mut().insert(config.header.clone(), value);
+    }
+    if let Some((echo_header, replaced)) = config.echo_header.as_ref().zip(replaced) {
+        if let Ok(replaced) = HeaderValue::from_str(&replaced) {
+            response.headers_mut().insert(echo_header.clone(), replaced);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_ids_are_well_formed() {
+        let v4 = RequestIdFormat::UuidV4.generate(SystemTime::now());
+        assert_eq!(v4.len(), 36);
+        assert_eq!(&v4[14..15], "4");
+        assert!("89ab".contains(&v4[19..20]), "{}", v4);
+
+        let now = UNIX_EPOCH + std::time::Duration::from_millis(1_704_067_200_123);
+        let v7 = RequestIdFormat::UuidV7.generate(now);
+        assert_eq!(&v7[14..15], "7");
+        // The leading 48 bits are the timestamp
+        let millis = u64::from_str_radix(&v7[..13].replace('-', ""), 16).unwrap();
+        assert_eq!(millis, 1_704_067_200_123, "{}", v7);
+
+        let ulid = RequestIdFormat::Ulid.generate(SystemTime::now());
+        assert_eq!(ulid.len(), 26);
+        assert!(ulid.bytes().all(|b| b"0123456789ABCDEFGHJKMNPQRSTVWXYZ".contains(&b)), "{}", ulid);
+
+        for format in [RequestIdFormat::UuidV4, RequestIdFormat::UuidV7, RequestIdFormat::Ulid] {
+            assert!(is_valid(&format.generate(SystemTime::now())));
+            assert_ne!(format.generate(SystemTime::now()), format.generate(SystemTime::now()));
+        }
+    }
+
+    #[test]
+    fn test_incoming_values_are_checked() {
+        assert!(is_valid("8a1b2c3d4e5f0abc-SJC"));
+        assert!(is_valid("trace:1.2_3"));
+        assert!(is_valid(&"a".repeat(MAX_LENGTH)));
+        assert!(!is_valid(&"a".repeat(MAX_LENGTH + 1)));
+        assert!(!is_valid(""));
+        assert!(!is_valid("abc def"));
+        assert!(!is_valid("<script>"));
+    }
+}
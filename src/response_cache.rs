@@ -0,0 +1,536 @@
+/*!
+ * In-memory response cache at the origin
+ *
+ * Routes that are expensive to produce (proxied upstreams, rendered pages)
+ * can be cached here, absorbing bursts before Cloudflare's edge cache has
+ * a copy. `RESPONSE_CACHE` lists rules as a JSON array, e.g.
+ * `[{"prefix": "/reports", "ttl": 30, "stale_while_revalidate": 300}]`:
+ *
+ * - `ttl`: seconds an entry is served as fresh (`X-Cache: HIT`)
+ * - `stale_while_revalidate`: seconds after that during which the entry is
+ *   still served (`X-Cache: STALE`) while one background request refreshes
+ *   it
+ * - `max_entry_bytes`: largest body stored (default 1 MiB)
+ *
+ * Only `GET` requests without `Authorization` are looked up, and only
+ * `200` responses with a known length are stored; a response setting a
+ * cookie, marked `no-store` or `private`, or with `Vary: *` never is. The
+ * key is the method and URI. One variant is kept per key: the request
+ * headers named in the response's `Vary` are recorded, and a request that
+ * differs in any of them is a miss whose response replaces the entry.
+ *
+ * Entries share a budget of `RESPONSE_CACHE_MAX_BYTES` (default 32 MiB)
+ * and at most `RESPONSE_CACHE_MAX_ENTRIES` of them (default 10000) are
+ * held, the least recently used being evicted first. An entry too stale to
+ * serve is dropped when next looked up or swept.
+ */
+use crate::bounded::{BoundedMap, StoreStats};
+use crate::clock::{Clock, SystemClock};
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::debug;
+
+/// Response header reporting how the cache answered
+pub const X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+const DEFAULT_MAX_ENTRY_BYTES: usize = 1024 * 1024;
+
+const DEFAULT_MAX_BYTES: usize = 32 * 1024 * 1024;
+
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+
+/// Caching rule for one path prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseCacheRule {
+    /// Path prefix, matched on segment boundaries
+    pub prefix: String,
+
+    /// Seconds an entry stays fresh
+    pub ttl: u32,
+
+    /// Seconds a stale entry may still be served while it is refreshed
+    #[serde(default)]
+    pub stale_while_revalidate: u32,
+
+    /// Largest body stored under this rule
+    #[serde(default = "default_max_entry_bytes")]
+    pub max_entry_bytes: usize,
+}
+
+fn default_max_entry_bytes() -> usize {
+    DEFAULT_MAX_ENTRY_BYTES
+}
+
+impl ResponseCacheRule {
+    /// Rule caching `prefix` for `ttl` seconds, without serving stale
+    pub fn new(prefix: impl Into<String>, ttl: u32) -> Self {
+        Self {
+            prefix: prefix.into(),
+            ttl,
+            stale_while_revalidate: 0,
+            max_entry_bytes: DEFAULT_MAX_ENTRY_BYTES,
+        }
+    }
+}
+
+/// Cached routes and the memory they may use; no rules means no caching
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResponseCacheConfig {
+    pub rules: Vec<ResponseCacheRule>,
+
+    /// Budget for every entry together
+    pub max_bytes: usize,
+
+    /// Most entries held at once
+    pub max_entries: usize,
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        Self { rules: Vec::new(), max_bytes: DEFAULT_MAX_BYTES, max_entries: DEFAULT_MAX_ENTRIES }
+    }
+}
+
+impl ResponseCacheConfig {
+    /// Load from `RESPONSE_CACHE`, `RESPONSE_CACHE_MAX_BYTES` and
+    /// `RESPONSE_CACHE_MAX_ENTRIES`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        if let Ok(value) = std::env::var("RESPONSE_CACHE") {
+            config.rules = serde_json::from_str(&value)
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid RESPONSE_CACHE: {}", e)
+                ))?;
+        }
+        if let Ok(value) = std::env::var("RESPONSE_CACHE_MAX_BYTES") {
+            config.max_bytes = value.parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| crate::ServerError::ConfigError(format!(
+                    "Invalid RESPONSE_CACHE_MAX_BYTES: {:?} is not a positive number",
+                    value
+                )))?;
+        }
+        if let Ok(value) = std::env::var("RESPONSE_CACHE_MAX_ENTRIES") {
+            config.max_entries = value.parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| crate::ServerError::ConfigError(format!(
+                    "Invalid RESPONSE_CACHE_MAX_ENTRIES: {:?} is not a positive number",
+                    value
+                )))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reject malformed or duplicate prefixes, zero TTLs and entries that
+    /// could never fit the budget
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(crate::ServerError::ConfigError(msg));
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.prefix.starts_with('/') || rule.prefix.contains(['?', '#']) {
+                return invalid(format!("Response cache prefix {:?} must be a path starting with /", rule.prefix));
+            }
+            if self.rules[..i].iter().any(|other| other.prefix == rule.prefix) {
+                return invalid(format!("Duplicate response cache prefix {:?}", rule.prefix));
+            }
+            if rule.ttl == 0 {
+                return invalid(format!("Response cache rule {:?} needs a positive ttl", rule.prefix));
+            }
+            if rule.max_entry_bytes > self.max_bytes {
+                return invalid(format!(
+                    "Response cache rule {:?} allows entries larger than RESPONSE_CACHE_MAX_BYTES",
+                    rule.prefix
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The rule with the longest prefix matching `path`
+    pub fn rule_for(&self, path: &str) -> Option<&ResponseCacheRule> {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                crate::auth::longest_prefix(std::slice::from_ref(&rule.prefix), path).map(|len| (len, rule))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, rule)| rule)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Request headers named by the response's `Vary`, as they were
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: SystemTime,
+    fresh_for: Duration,
+    stale_for: Duration,
+
+    /// Bytes charged against the budget, held until the entry is dropped
+    _charge: Charge,
+
+    /// A background refresh is in flight
+    refreshing: bool,
+}
+
+/// An entry's share of the byte budget, given back when the entry is
+/// dropped, however it leaves the map
+#[derive(Debug)]
+struct Charge {
+    bytes: Arc<AtomicUsize>,
+    size: usize,
+}
+
+impl Charge {
+    fn new(bytes: &Arc<AtomicUsize>, size: usize) -> Self {
+        bytes.fetch_add(size, Ordering::Relaxed);
+        Self { bytes: bytes.clone(), size }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.bytes.fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+/// A stored response, as it is served
+#[derive(Debug)]
+struct Cached {
+    headers: HeaderMap,
+    body: Bytes,
+    age: Duration,
+}
+
+impl Cached {
+    /// The response, or `304` when `request` already holds this `ETag`
+    fn respond(self, request: &HeaderMap, status: &'static str) -> Response {
+        let not_modified = match (request.get(header::IF_NONE_MATCH), self.headers.get(header::ETAG)) {
+            (Some(if_none_match), Some(etag)) => {
+                if_none_match.to_str().is_ok_and(|v| crate::conditional::etag_matches(v, etag))
+            }
+            _ => false,
+        };
+
+        let mut response = if not_modified {
+            let mut response = StatusCode::NOT_MODIFIED.into_response();
+            for name in [header::ETAG, header::LAST_MODIFIED, header::CACHE_CONTROL, header::VARY] {
+                if let Some(value) = self.headers.get(&name) {
+                    response.headers_mut().insert(name, value.clone());
+                }
+            }
+            response
+        } else {
+            let mut response = Response::new(Body::from(self.body));
+            *response.headers_mut() = self.headers;
+            response
+        };
+        response.headers_mut().insert(X_CACHE, HeaderValue::from_static(status));
+        response.headers_mut().insert(header::AGE, HeaderValue::from(self.age.as_secs()));
+        response
+    }
+}
+
+enum Lookup {
+    Fresh(Cached),
+
+    /// Past its TTL but within `stale_while_revalidate`; `revalidate` is
+    /// set for the one request that should refresh it
+    Stale { cached: Cached, revalidate: bool },
+    Miss,
+}
+
+/// The cache's entries; clones share them
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    clock: Arc<dyn Clock>,
+    store: Arc<Mutex<BoundedMap<Entry>>>,
+
+    /// Bytes charged by the entries in `store`
+    bytes: Arc<AtomicUsize>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl ResponseCache {
+    /// Empty cache, with ages measured by `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let store = BoundedMap::new("response_cache", DEFAULT_MAX_ENTRIES, None, clock.clone());
+        Self { clock, store: Arc::new(Mutex::new(store)), bytes: Arc::default() }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(self, metrics: &crate::metrics::Metrics) -> Self {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).set_metrics(metrics);
+        self
+    }
+
+    /// Number of entries held
+    pub fn len(&self) -> usize {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Whether nothing is cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes charged against `RESPONSE_CACHE_MAX_BYTES`
+    pub fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).stats()
+    }
+
+    /// Drop entries too stale to serve, returning how many went
+    pub fn sweep(&self) -> usize {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).sweep()
+    }
+
+    fn lookup(&self, key: &str, request: &HeaderMap) -> Lookup {
+        let now = self.clock.now();
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(entry) = store.get_mut(key) else {
+            return Lookup::Miss;
+        };
+        if entry.vary.iter().any(|(name, value)| request.get(name) != value.as_ref()) {
+            return Lookup::Miss;
+        }
+
+        let age = now.duration_since(entry.stored_at).unwrap_or_default();
+        let lookup = if age >= entry.fresh_for + entry.stale_for {
+            Lookup::Miss
+        } else if age < entry.fresh_for {
+            Lookup::Fresh(Cached { headers: entry.headers.clone(), body: entry.body.clone(), age })
+        } else {
+            let revalidate = !entry.refreshing;
+            entry.refreshing = true;
+            let cached = Cached { headers: entry.headers.clone(), body: entry.body.clone(), age };
+            Lookup::Stale { cached, revalidate }
+        };
+
+        if let Lookup::Miss = lookup {
+            store.remove(key);
+        }
+        lookup
+    }
+
+    /// Store a response of `size` bytes under `key`, evicting the least
+    /// recently used entries until it fits within `config`'s limits
+    fn insert(&self, key: String, entry: impl FnOnce(Charge) -> Entry, size: usize, config: &ResponseCacheConfig) {
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        store.set_limits(config.max_entries, None);
+        store.remove(&key);
+        while self.bytes() + size > config.max_bytes && store.pop_oldest().is_some() {}
+
+        let entry = entry(Charge::new(&self.bytes, size));
+        let keep_for = entry.fresh_for + entry.stale_for;
+        store.insert_for(key, entry, Some(keep_for));
+    }
+
+    /// Allow another refresh of `key` after one failed to produce a
+    /// cacheable response
+    fn refresh_failed(&self, key: &str) {
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = store.get_mut(key) {
+            entry.refreshing = false;
+        }
+    }
+}
+
+/// Whether a response with `status` and `headers` may be stored
+fn storable(status: StatusCode, headers: &HeaderMap) -> bool {
+    let cache_control = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .any(|directive| directive == "no-store" || directive == "private");
+    let vary_any = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|name| name.trim() == "*"));
+
+    status == StatusCode::OK && !headers.contains_key(header::SET_COOKIE) && !cache_control && !vary_any
+}
+
+/// The request headers named by `Vary` in `response`, with their values
+fn vary_values(response: &HeaderMap, request: &HeaderMap) -> Vec<(HeaderName, Option<HeaderValue>)> {
+    response
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .map(|name| {
+            let value = request.get(&name).cloned();
+            (name, value)
+        })
+        .collect()
+}
+
+/// `response` with whether it was stored; `Err` holds the response to
+/// send instead when its body could not be read
+async fn store(
+    cache: &ResponseCache,
+    key: String,
+    rule: &ResponseCacheRule,
+    config: &ResponseCacheConfig,
+    request: &HeaderMap,
+    response: Response,
+) -> Result<(Response, bool), Response> {
+    let known = http_body::Body::size_hint(response.body()).exact();
+    if !storable(response.status(), response.headers()) || known.is_none_or(|len| len > rule.max_entry_bytes as u64) {
+        return Ok((response, false));
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, rule.max_entry_bytes).await {
+        Ok(body) => body,
+        Err(e) => {
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "response_failed", format!("Failed to read the response: {}", e))
+                .into_response());
+        }
+    };
+
+    let size = key.len()
+        + body.len()
+        + parts.headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum::<usize>();
+    let entry = |charge| Entry {
+        vary: vary_values(&parts.headers, request),
+        headers: parts.headers.clone(),
+        body: body.clone(),
+        stored_at: cache.clock.now(),
+        fresh_for: Duration::from_secs(rule.ttl.into()),
+        stale_for: Duration::from_secs(rule.stale_while_revalidate.into()),
+        _charge: charge,
+        refreshing: false,
+    };
+    cache.insert(key, entry, size, config);
+    Ok((Response::from_parts(parts, Body::from(body)), true))
+}
+
+/// A body-less copy of `request` for the background refresh
+fn replay(request: &Request) -> Request {
+    let mut copy = Request::new(Body::empty());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    *copy.extensions_mut() = request.extensions().clone();
+    copy.headers_mut().remove(header::IF_NONE_MATCH);
+    copy.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    copy
+}
+
+/// Middleware answering from the cache and filling it on misses
+pub async fn serve_cached(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let config = state.config();
+    let rule = (request.method() == Method::GET && !request.headers().contains_key(header::AUTHORIZATION))
+        .then(|| config.response_cache.rule_for(request.uri().path()))
+        .flatten();
+    let Some(rule) = rule.cloned() else {
+        return next.run(request).await;
+    };
+    let cache_config = config.response_cache.clone();
+    let cache = state.response_cache.clone();
+    let key = format!("GET {}", request.uri());
+
+    match cache.lookup(&key, request.headers()) {
+        Lookup::Fresh(cached) => return cached.respond(request.headers(), "HIT"),
+        Lookup::Stale { cached, revalidate } => {
+            if revalidate {
+                let refresh = replay(&request);
+                let (cache, key, next) = (cache.clone(), key.clone(), next.clone());
+                tokio::spawn(async move {
+                    let headers = refresh.headers().clone();
+                    let response = next.run(refresh).await;
+                    match store(&cache, key.clone(), &rule, &cache_config, &headers, response).await {
+                        Ok((_, true)) => debug!(key = %key, "Refreshed a stale cached response"),
+                        _ => cache.refresh_failed(&key),
+                    }
+                });
+            }
+            return cached.respond(request.headers(), "STALE");
+        }
+        Lookup::Miss => {}
+    }
+
+    let headers = request.headers().clone();
+    let response = next.run(request).await;
+    match store(&cache, key, &rule, &config.response_cache, &headers, response).await {
+        Ok((mut response, _)) => {
+            response.headers_mut().insert(X_CACHE, HeaderValue::from_static("MISS"));
+            response
+        }
+        Err(response) => response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_rule_wins() {
+        let config = ResponseCacheConfig {
+            rules: vec![ResponseCacheRule::new("/reports", 10), ResponseCacheRule::new("/reports/daily", 60)],
+            ..ResponseCacheConfig::default()
+        };
+        assert_eq!(config.rule_for("/reports/daily/today").map(|r| r.ttl), Some(60));
+        assert_eq!(config.rule_for("/reports/weekly").map(|r| r.ttl), Some(10));
+        assert_eq!(config.rule_for("/reportsx"), None);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validation() {
+        let config = |rule: ResponseCacheRule| ResponseCacheConfig { rules: vec![rule], max_bytes: 1024, ..ResponseCacheConfig::default() };
+        assert!(config(ResponseCacheRule::new("reports", 10)).validate().is_err());
+        assert!(config(ResponseCacheRule::new("/reports", 0)).validate().is_err());
+        // The default entry size is larger than this budget
+        assert!(config(ResponseCacheRule::new("/reports", 10)).validate().is_err());
+        assert!(config(ResponseCacheRule { max_entry_bytes: 512, ..ResponseCacheRule::new("/reports", 10) })
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_storable_responses() {
+        let headers = |pairs: &[(HeaderName, &'static str)]| {
+            pairs.iter().map(|(name, value)| (name.clone(), HeaderValue::from_static(value))).collect::<HeaderMap>()
+        };
+        assert!(storable(StatusCode::OK, &headers(&[(header::CACHE_CONTROL, "public, max-age=60")])));
+        assert!(storable(StatusCode::OK, &headers(&[(header::VARY, "accept")])));
+        assert!(!storable(StatusCode::NOT_FOUND, &HeaderMap::new()));
+        assert!(!storable(StatusCode::OK, &headers(&[(header::SET_COOKIE, "a=b")])));
+        assert!(!storable(StatusCode::OK, &headers(&[(header::CACHE_CONTROL, "max-age=0, Private")])));
+        assert!(!storable(StatusCode::OK, &headers(&[(header::VARY, "accept, *")])));
+    }
+}
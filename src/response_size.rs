@@ -0,0 +1,153 @@
+/*!
+ * Response body size accounting and cap
+ *
+ * Every response body is wrapped so the bytes actually sent are counted as
+ * they stream. When the body is done (or dropped because the client left)
+ * the total goes into the `http_response_body_bytes` histogram and a debug
+ * log line in the request span, next to the trace layer's own lines.
+ *
+ * `MAX_RESPONSE_BODY_BYTES` caps what one response may send, protecting the
+ * tunnel from a handler that produces far more than intended:
+ *
+ * - a body whose length is known up front and exceeds the cap is replaced
+ *   by a `500 response_too_large` before anything is sent
+ * - a streamed body is cut off at the cap with an error log; the status and
+ *   headers have already gone out, so the only way to signal the failure is
+ *   to abort the response, which closes the connection and leaves the
+ *   client with a truncated body (or chunked encoding with no terminator)
+ *
+ * Event streams are open-ended by design and are counted but never capped.
+ */
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::{Frame, SizeHint};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tracing::{debug, error};
+
+/// Bucket bounds for `http_response_body_bytes`
+#[cfg(feature = "metrics")]
+pub const SIZE_BUCKETS: &[u64] = &[1024, 10 * 1024, 100 * 1024, 1024 * 1024, 10 * 1024 * 1024, 100 * 1024 * 1024];
+
+/// Load `MAX_RESPONSE_BODY_BYTES`; unset means no cap
+pub fn from_env() -> crate::Result<Option<u64>> {
+    let Ok(value) = std::env::var("MAX_RESPONSE_BODY_BYTES") else {
+        return Ok(None);
+    };
+    value.parse()
+        .ok()
+        .filter(|n| *n > 0)
+        .map(Some)
+        .ok_or_else(|| crate::ServerError::ConfigError(format!(
+            "Invalid MAX_RESPONSE_BODY_BYTES: {:?} is not a positive number",
+            value
+        )))
+}
+
+/// Response body that counts the bytes passing through it and stops at
+/// `limit`
+struct CountingBody {
+    inner: Body,
+    sent: u64,
+    limit: Option<u64>,
+    exceeded: bool,
+    span: tracing::Span,
+    #[cfg(feature = "metrics")]
+    histogram: crate::metrics::Histogram,
+}
+
+impl http_body::Body for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Self::Error>>> {
+        if self.exceeded {
+            return Poll::Ready(Some(Err(axum::Error::new("response body exceeded MAX_RESPONSE_BODY_BYTES"))));
+        }
+
+        let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            other => return other,
+        };
+        let mut data = match frame.into_data() {
+            Ok(data) => data,
+            Err(trailers) => return Poll::Ready(Some(Ok(trailers))),
+        };
+
+        if let Some(limit) = self.limit {
+            let remaining = limit - self.sent;
+            if data.len() as u64 > remaining {
+                data.truncate(remaining as usize);
+                self.exceeded = true;
+                let _entered = self.span.enter();
+                error!(limit, "Response body exceeded MAX_RESPONSE_BODY_BYTES; aborting the response");
+            }
+        }
+        self.sent += data.len() as u64;
+        Poll::Ready(Some(Ok(Frame::data(data))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        !self.exceeded && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        self.histogram.observe(self.sent);
+
+        let _entered = self.span.enter();
+        debug!(bytes = self.sent, truncated = self.exceeded, "response body finished");
+    }
+}
+
+/// Middleware counting response body bytes and enforcing the cap
+pub async fn count_response_bytes(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+
+    let is_stream = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"));
+    let limit = state.config().max_response_body_bytes.filter(|_| !is_stream);
+
+    let known = http_body::Body::size_hint(response.body()).exact();
+    let (response, limit) = match (known, limit) {
+        (Some(size), Some(max)) if size > max => {
+            error!(size, limit = max, "Response body is larger than MAX_RESPONSE_BODY_BYTES; answering 500 instead");
+            // The replacement is sent in full, however small the cap
+            let response = ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "response_too_large",
+                "The response was larger than this server is configured to send",
+            )
+            .into_response();
+            (response, None)
+        }
+        _ => (response, limit),
+    };
+
+    response.map(|inner| {
+        Body::new(CountingBody {
+            inner,
+            sent: 0,
+            limit,
+            exceeded: false,
+            span: tracing::Span::current(),
+            #[cfg(feature = "metrics")]
+            histogram: state.requests.response_bytes.clone(),
+        })
+    })
+}
@@ -0,0 +1,161 @@
+/*!
+ * `/robots.txt`
+ *
+ * `ROBOTS_POLICY` picks the body:
+ *
+ * - `disallow-all` (default): quick-tunnel and preview hostnames should not
+ *   end up in search results
+ * - `allow-all`: an empty `Disallow`, letting crawlers index everything
+ * - `custom`: the contents of `ROBOTS_TXT_PATH`, or the inline `ROBOTS_TXT`
+ *
+ * The response is plain text that goes through the normal cache policy and
+ * carries an ETag, so Cloudflare can serve it from the edge.
+ */
+use crate::conditional;
+use crate::config::AppConfig;
+use axum::{
+    extract::{FromRef, State},
+    http::{header, HeaderMap},
+    response::Response,
+    routing::get,
+    Router,
+};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::warn;
+
+const DISALLOW_ALL: &str = "User-agent: *\nDisallow: /\n";
+const ALLOW_ALL: &str = "User-agent: *\nDisallow:\n";
+
+/// Body served at `/robots.txt`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum RobotsPolicy {
+    #[default]
+    DisallowAll,
+    AllowAll,
+    Custom(String),
+}
+
+/// `ROBOTS_POLICY` values; `custom` is resolved to its body by `from_env`
+impl FromStr for RobotsPolicy {
+    type Err = crate::ServerError;
+
+    fn from_str(value: &str) -> crate::Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "disallow-all" => Ok(RobotsPolicy::DisallowAll),
+            "allow-all" => Ok(RobotsPolicy::AllowAll),
+            "custom" => Ok(RobotsPolicy::Custom(String::new())),
+            other => Err(crate::ServerError::ConfigError(format!(
+                "Invalid ROBOTS_POLICY {:?} (expected disallow-all, allow-all or custom)",
+                other
+            ))),
+        }
+    }
+}
+
+impl RobotsPolicy {
+    /// Load from `ROBOTS_POLICY`, reading the `custom` body from
+    /// `ROBOTS_TXT_PATH` or, failing that, `ROBOTS_TXT`
+    pub fn from_env() -> crate::Result<Self> {
+        let path = std::env::var("ROBOTS_TXT_PATH").ok().filter(|v| !v.is_empty());
+        let inline = std::env::var("ROBOTS_TXT").ok().filter(|v| !v.is_empty());
+
+        let policy = match std::env::var("ROBOTS_POLICY") {
+            Ok(value) => value.parse()?,
+            Err(_) => Self::default(),
+        };
+
+        if !matches!(policy, RobotsPolicy::Custom(_)) {
+            if path.is_some() || inline.is_some() {
+                warn!("ROBOTS_TXT_PATH / ROBOTS_TXT are ignored unless ROBOTS_POLICY=custom");
+            }
+            return Ok(policy);
+        }
+
+        let body = match (path, inline) {
+            (Some(path), _) => std::fs::read_to_string(&path)
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Failed to read ROBOTS_TXT_PATH {}: {}", path, e)
+                ))?,
+            (None, Some(inline)) => inline,
+            (None, None) => {
+                return Err(crate::ServerError::ConfigError(
+                    "ROBOTS_POLICY=custom requires ROBOTS_TXT_PATH or ROBOTS_TXT".to_string(),
+                ))
+            }
+        };
+        Ok(RobotsPolicy::Custom(body))
+    }
+
+    /// The robots.txt text
+    pub fn body(&self) -> &str {
+        match self {
+            RobotsPolicy::DisallowAll => DISALLOW_ALL,
+            RobotsPolicy::AllowAll => ALLOW_ALL,
+            RobotsPolicy::Custom(body) => body,
+        }
+    }
+}
+
+/// `GET /robots.txt`
+pub fn routes<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    Arc<AppConfig>: FromRef<S>,
+{
+    Router::new().route("/robots.txt", get(robots_txt))
+}
+
+/// Crawler policy
+#[utoipa::path(
+    get,
+    path = "/robots.txt",
+    responses((status = OK, description = "robots.txt per ROBOTS_POLICY", body = String, content_type = "text/plain")),
+)]
+async fn robots_txt(State(config): State<Arc<AppConfig>>, headers: HeaderMap) -> Response {
+    let body = config.robots.body().to_string();
+    let etag = conditional::strong_etag(body.as_bytes());
+
+    conditional::conditional_response(
+        &etag,
+        ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body),
+        &headers,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("disallow-all".parse::<RobotsPolicy>().unwrap(), RobotsPolicy::DisallowAll);
+        assert_eq!(" Allow-All ".parse::<RobotsPolicy>().unwrap(), RobotsPolicy::AllowAll);
+        assert!(matches!("custom".parse::<RobotsPolicy>().unwrap(), RobotsPolicy::Custom(_)));
+        assert!("noindex".parse::<RobotsPolicy>().is_err());
+    }
+
+    // The only test touching the ROBOTS_* variables, so no other test races it
+    #[test]
+    fn test_custom_body_from_env() {
+        let path = std::env::temp_dir().join(format!("robots-{}.txt", std::process::id()));
+        std::fs::write(&path, "User-agent: *\nDisallow: /private\n").unwrap();
+
+        std::env::set_var("ROBOTS_POLICY", "custom");
+        assert!(RobotsPolicy::from_env().is_err(), "custom without a body");
+
+        std::env::set_var("ROBOTS_TXT", "User-agent: *\nDisallow: /inline\n");
+        assert_eq!(RobotsPolicy::from_env().unwrap().body(), "User-agent: *\nDisallow: /inline\n");
+
+        std::env::set_var("ROBOTS_TXT_PATH", &path);
+        assert_eq!(RobotsPolicy::from_env().unwrap().body(), "User-agent: *\nDisallow: /private\n");
+
+        std::env::set_var("ROBOTS_POLICY", "allow-all");
+        assert_eq!(RobotsPolicy::from_env().unwrap(), RobotsPolicy::AllowAll);
+
+        for name in ["ROBOTS_POLICY", "ROBOTS_TXT", "ROBOTS_TXT_PATH"] {
+            std::env::remove_var(name);
+        }
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,208 @@
+/*!
+ * Original request scheme detection
+ *
+ * TLS terminates at Cloudflare, so the origin only ever sees plain HTTP from
+ * cloudflared. The scheme the visitor actually used is resolved once per
+ * request, in order of precedence, from:
+ *
+ * 1. `CF-Visitor` — a JSON object such as `{"scheme":"https"}`
+ * 2. `X-Forwarded-Proto`
+ * 3. the connection itself (plaintext, since this listener has no TLS)
+ *
+ * The result is stored as a `RequestScheme` request extension and recorded
+ * on the request span. `redirect_to_https` builds on it to bounce plain-HTTP
+ * visitors to the https URL when Cloudflare's "Always Use HTTPS" is off.
+ */
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Request},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use tracing::debug;
+
+/// Scheme the visitor used to reach Cloudflare
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestScheme {
+    Http,
+    Https,
+    /// No header gave a usable value and the request did not arrive over a
+    /// socket (e.g. in-process calls)
+    Unknown,
+}
+
+impl RequestScheme {
+    /// Lowercase scheme name (`http`, `https` or `unknown`)
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestScheme::Http => "http",
+            RequestScheme::Https => "https",
+            RequestScheme::Unknown => "unknown",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "http" | "ws" => Some(RequestScheme::Http),
+            "https" | "wss" => Some(RequestScheme::Https),
+            _ => None,
+        }
+    }
+
+    /// Resolve the scheme from headers, falling back to the connection type
+    pub fn resolve(headers: &HeaderMap, has_connection: bool) -> Self {
+        if let Some(scheme) = cf_visitor_scheme(headers) {
+            return scheme;
+        }
+
+        let forwarded = headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .and_then(Self::parse);
+        if let Some(scheme) = forwarded {
+            return scheme;
+        }
+
+        if has_connection {
+            RequestScheme::Http
+        } else {
+            RequestScheme::Unknown
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CfVisitor {
+    scheme: String,
+}
+
+/// Parse the scheme out of a `CF-Visitor` header, if present and well-formed
+pub fn cf_visitor_scheme(headers: &HeaderMap) -> Option<RequestScheme> {
+    let raw = headers.get("cf-visitor")?.to_str().ok()?;
+
+    match serde_json::from_str::<CfVisitor>(raw) {
+        Ok(visitor) => RequestScheme::parse(&visitor.scheme),
+        Err(e) => {
+            debug!("Ignoring malformed CF-Visitor header {:?}: {}", raw, e);
+            None
+        }
+    }
+}
+
+fn has_connection(extensions: &axum::http::Extensions) -> bool {
+    extensions.get::<ConnectInfo<SocketAddr>>().is_some()
+}
+
+/// Middleware resolving the scheme once and storing it in request extensions
+pub async fn resolve_scheme(mut request: Request, next: Next) -> Response {
+    let scheme = RequestScheme::resolve(request.headers(), has_connection(request.extensions()));
+    tracing::Span::current().record("scheme", scheme.as_str());
+    request.extensions_mut().insert(scheme);
+    next.run(request).await
+}
+
+/// Paths never redirected so health probes keep working over plain HTTP
+const REDIRECT_EXEMPT_PATHS: &[&str] = &["/health", "/readyz"];
+
+/// Middleware redirecting plain-HTTP requests to the same URL over https.
+///
+/// GET and HEAD get a 301; other methods get a 308 so clients repeat the
+/// method and body. Requests whose scheme is unknown, or that carry no Host
+/// header to build the target from, pass through untouched to avoid loops.
+pub async fn redirect_to_https(request: Request, next: Next) -> Response {
+    let scheme = request
+        .extensions()
+        .get::<RequestScheme>()
+        .copied()
+        .unwrap_or(RequestScheme::Unknown);
+
+    if scheme != RequestScheme::Http || REDIRECT_EXEMPT_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let Some(host) = request.headers().get(header::HOST).and_then(|v| v.to_str().ok()) else {
+        return next.run(request).await;
+    };
+
+    let path = request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let Ok(location) = HeaderValue::from_str(&format!("https://{}{}", host, path)) else {
+        return next.run(request).await;
+    };
+
+    let status = if request.method() == Method::GET || request.method() == Method::HEAD {
+        StatusCode::MOVED_PERMANENTLY
+    } else {
+        StatusCode::PERMANENT_REDIRECT
+    };
+
+    (status, [(header::LOCATION, location)]).into_response()
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestScheme
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(match parts.extensions.get::<RequestScheme>() {
+            Some(scheme) => *scheme,
+            None => RequestScheme::resolve(&parts.headers, has_connection(&parts.extensions)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_cf_visitor_takes_precedence() {
+        let headers = headers(&[
+            ("cf-visitor", r#"{"scheme":"https"}"#),
+            ("x-forwarded-proto", "http"),
+        ]);
+
+        assert_eq!(RequestScheme::resolve(&headers, true), RequestScheme::Https);
+    }
+
+    #[test]
+    fn test_forwarded_proto_fallback() {
+        let headers = headers(&[("x-forwarded-proto", "https")]);
+
+        assert_eq!(RequestScheme::resolve(&headers, true), RequestScheme::Https);
+    }
+
+    #[test]
+    fn test_connection_fallback() {
+        assert_eq!(RequestScheme::resolve(&HeaderMap::new(), true), RequestScheme::Http);
+        assert_eq!(RequestScheme::resolve(&HeaderMap::new(), false), RequestScheme::Unknown);
+    }
+
+    #[test]
+    fn test_malformed_cf_visitor_falls_through() {
+        let headers = headers(&[
+            ("cf-visitor", "{scheme: https"),
+            ("x-forwarded-proto", "http"),
+        ]);
+
+        assert_eq!(cf_visitor_scheme(&headers), None);
+        assert_eq!(RequestScheme::resolve(&headers, true), RequestScheme::Http);
+    }
+}
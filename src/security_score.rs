@@ -0,0 +1,337 @@
+/*!
+ * Security header grading
+ *
+ * [`score`] rates the headers a [`SecurityConfig`] sends against
+ * [`RUBRIC`], in the spirit of securityheaders.com: each header is worth
+ * some points, lost entirely when the header is not sent and partly for
+ * each weakness that applies (`'unsafe-inline'` scripts, a short HSTS
+ * max-age, ...). The total out of 100 maps to a letter grade. Headers the
+ * service never sends, such as `Cross-Origin-Opener-Policy`, are listed as
+ * [`NOTES`] without costing points.
+ *
+ * `serve` logs the grade at startup and `GET /admin/security-score` returns
+ * the [`ScoreReport`] for the live configuration as JSON.
+ */
+use crate::config::SecurityConfig;
+use crate::header_budget::HeaderBudget;
+use crate::headers::{
+    CONTENT_SECURITY_POLICY, CROSS_ORIGIN_OPENER_POLICY, PERMISSIONS_POLICY, REFERRER_POLICY,
+    STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use axum::http::HeaderName;
+use serde::{Serialize, Serializer};
+use std::fmt;
+
+/// Points a header is worth and what makes it weak
+#[derive(Debug)]
+pub struct Rule {
+    pub header: HeaderName,
+    pub points: u32,
+    pub weaknesses: &'static [Weakness],
+}
+
+/// One way a sent header falls short
+#[derive(Debug)]
+pub struct Weakness {
+    /// Points lost, up to the rule's total
+    pub penalty: u32,
+    pub reason: &'static str,
+
+    /// Whether the header value has this weakness
+    pub applies: fn(&str) -> bool,
+}
+
+/// The rubric, worth 100 points in total
+pub const RUBRIC: [Rule; 6] = [
+    Rule {
+        header: CONTENT_SECURITY_POLICY,
+        points: 25,
+        weaknesses: &[
+            Weakness {
+                penalty: 10,
+                reason: "script-src allows 'unsafe-inline'",
+                applies: |csp| script_sources(csp).is_some_and(|s| s.contains(&"'unsafe-inline'") && !has_nonce_or_hash(&s)),
+            },
+            Weakness {
+                penalty: 5,
+                reason: "script-src allows 'unsafe-eval'",
+                applies: |csp| script_sources(csp).is_some_and(|s| s.contains(&"'unsafe-eval'")),
+            },
+            Weakness {
+                penalty: 5,
+                reason: "script-src allows any host (*, https: or http:)",
+                applies: |csp| script_sources(csp).is_some_and(|s| s.iter().any(|s| matches!(*s, "*" | "https:" | "http:"))),
+            },
+            Weakness {
+                penalty: 3,
+                reason: "style-src allows 'unsafe-inline'",
+                applies: |csp| directive(csp, "style-src").or_else(|| directive(csp, "default-src"))
+                    .is_some_and(|s| s.contains(&"'unsafe-inline'")),
+            },
+            Weakness {
+                penalty: 3,
+                reason: "object-src is not 'none'",
+                applies: |csp| directive(csp, "object-src").or_else(|| directive(csp, "default-src"))
+                    .is_none_or(|s| s != ["'none'"]),
+            },
+        ],
+    },
+    Rule {
+        header: STRICT_TRANSPORT_SECURITY,
+        points: 20,
+        weaknesses: &[
+            Weakness {
+                penalty: 20,
+                reason: "max-age=0 tells browsers to forget HSTS",
+                applies: |hsts| hsts_max_age(hsts) == Some(0),
+            },
+            Weakness {
+                penalty: 10,
+                reason: "max-age is under 180 days",
+                applies: |hsts| hsts_max_age(hsts).is_some_and(|age| age > 0 && age < 15_552_000),
+            },
+            Weakness {
+                penalty: 3,
+                reason: "includeSubDomains is missing",
+                applies: |hsts| !hsts.split(';').any(|part| part.trim().eq_ignore_ascii_case("includeSubDomains")),
+            },
+        ],
+    },
+    Rule {
+        header: X_FRAME_OPTIONS,
+        points: 15,
+        weaknesses: &[Weakness {
+            penalty: 15,
+            reason: "not DENY or SAMEORIGIN, so browsers ignore it",
+            applies: |value| !value.eq_ignore_ascii_case("DENY") && !value.eq_ignore_ascii_case("SAMEORIGIN"),
+        }],
+    },
+    Rule {
+        header: X_CONTENT_TYPE_OPTIONS,
+        points: 15,
+        weaknesses: &[Weakness {
+            penalty: 15,
+            reason: "not nosniff, so browsers ignore it",
+            applies: |value| !value.eq_ignore_ascii_case("nosniff"),
+        }],
+    },
+    Rule {
+        header: REFERRER_POLICY,
+        points: 10,
+        weaknesses: &[Weakness {
+            penalty: 10,
+            reason: "sends full URLs to other origins",
+            applies: |value| {
+                let last = value.rsplit(',').next().unwrap_or("").trim();
+                matches!(last, "unsafe-url" | "no-referrer-when-downgrade")
+            },
+        }],
+    },
+    Rule {
+        header: PERMISSIONS_POLICY,
+        points: 15,
+        weaknesses: &[],
+    },
+];
+
+/// Headers noted but not scored, with why they are worth adding
+pub const NOTES: [(HeaderName, &str); 1] = [(
+    CROSS_ORIGIN_OPENER_POLICY,
+    "not sent; same-origin would isolate pages from cross-origin windows",
+)];
+
+/// How a header fared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Present,
+    Weak,
+    Missing,
+}
+
+/// Result for one header
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    #[serde(serialize_with = "crate::headers::serialize_name")]
+    pub header: HeaderName,
+    pub status: Status,
+    pub points: u32,
+    pub max_points: u32,
+    pub reasons: Vec<String>,
+}
+
+/// Letter grade for a score out of 100
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Grade {
+    APlus,
+    A,
+    B,
+    C,
+    D,
+    F,
+}
+
+impl Grade {
+    /// Lowest score for each grade, best first
+    pub const THRESHOLDS: [(u32, Grade); 5] =
+        [(98, Grade::APlus), (90, Grade::A), (75, Grade::B), (60, Grade::C), (45, Grade::D)];
+
+    pub fn for_score(score: u32) -> Self {
+        Self::THRESHOLDS
+            .iter()
+            .find(|(minimum, _)| score >= *minimum)
+            .map_or(Grade::F, |(_, grade)| *grade)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Grade::APlus => "A+",
+            Grade::A => "A",
+            Grade::B => "B",
+            Grade::C => "C",
+            Grade::D => "D",
+            Grade::F => "F",
+        }
+    }
+}
+
+impl fmt::Display for Grade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Grade {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Grade, score and per-header findings for a configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScoreReport {
+    pub grade: Grade,
+
+    /// Points out of 100
+    pub score: u32,
+    pub findings: Vec<Finding>,
+
+    /// Size of the configured headers, when graded for a whole `AppConfig`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_budget: Option<HeaderBudget>,
+}
+
+/// Rate the headers `config` sends against [`RUBRIC`]
+pub fn score(config: &SecurityConfig) -> ScoreReport {
+    let headers = config.to_headers();
+    let mut findings: Vec<Finding> = RUBRIC
+        .iter()
+        .map(|rule| {
+            let Some((_, value)) = headers.iter().find(|(name, _)| *name == rule.header) else {
+                return Finding {
+                    header: rule.header.clone(),
+                    status: Status::Missing,
+                    points: 0,
+                    max_points: rule.points,
+                    reasons: vec!["not sent".to_string()],
+                };
+            };
+            let value = String::from_utf8_lossy(value.as_bytes());
+            let weak: Vec<&Weakness> = rule.weaknesses.iter().filter(|weakness| (weakness.applies)(&value)).collect();
+            let lost: u32 = weak.iter().map(|weakness| weakness.penalty).sum();
+            Finding {
+                header: rule.header.clone(),
+                status: if weak.is_empty() { Status::Present } else { Status::Weak },
+                points: rule.points.saturating_sub(lost),
+                max_points: rule.points,
+                reasons: weak.iter().map(|weakness| weakness.reason.to_string()).collect(),
+            }
+        })
+        .collect();
+
+    findings.extend(NOTES.iter().map(|(header, reason)| Finding {
+        header: header.clone(),
+        status: Status::Missing,
+        points: 0,
+        max_points: 0,
+        reasons: vec![reason.to_string()],
+    }));
+
+    let score = findings.iter().map(|finding| finding.points).sum();
+    ScoreReport { grade: Grade::for_score(score), score, findings, header_budget: None }
+}
+
+/// One line for the startup log
+impl fmt::Display for ScoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Security header grade {} ({}/100)", self.grade, self.score)?;
+        for finding in self.findings.iter().filter(|finding| finding.status != Status::Present) {
+            let status = match finding.status {
+                Status::Weak => "weak",
+                _ => "missing",
+            };
+            write!(f, "; {} {}: {}", status, finding.header, finding.reasons.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Sources of the CSP `name` directive, if present
+fn directive<'a>(csp: &'a str, name: &str) -> Option<Vec<&'a str>> {
+    csp.split(';').find_map(|directive| {
+        let mut parts = directive.split_whitespace();
+        parts
+            .next()
+            .filter(|directive| directive.eq_ignore_ascii_case(name))
+            .map(|_| parts.collect())
+    })
+}
+
+/// Sources scripts are allowed from: `script-src`, falling back to
+/// `default-src`
+fn script_sources(csp: &str) -> Option<Vec<&str>> {
+    directive(csp, "script-src").or_else(|| directive(csp, "default-src"))
+}
+
+/// Browsers ignore `'unsafe-inline'` next to a nonce or hash
+fn has_nonce_or_hash(sources: &[&str]) -> bool {
+    sources.iter().any(|source| {
+        ["'nonce-", "'sha256-", "'sha384-", "'sha512-"].iter().any(|prefix| source.starts_with(prefix))
+    })
+}
+
+fn hsts_max_age(hsts: &str) -> Option<u64> {
+    hsts.split(';').find_map(|part| part.trim().strip_prefix("max-age=")?.trim_matches('"').parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rubric_totals_100() {
+        assert_eq!(RUBRIC.iter().map(|rule| rule.points).sum::<u32>(), 100);
+        for rule in RUBRIC {
+            for weakness in rule.weaknesses {
+                assert!(weakness.penalty <= rule.points, "{}: {}", rule.header, weakness.reason);
+            }
+        }
+    }
+
+    #[test]
+    fn test_script_sources_fall_back_to_default_src() {
+        let csp = "default-src 'self' 'unsafe-inline'; img-src data:";
+        assert_eq!(script_sources(csp), Some(vec!["'self'", "'unsafe-inline'"]));
+        assert_eq!(directive(csp, "style-src"), None);
+        assert!(has_nonce_or_hash(&["'unsafe-inline'", "'nonce-abc'"]));
+        assert_eq!(hsts_max_age("max-age=600; preload"), Some(600));
+    }
+
+    #[test]
+    fn test_grade_boundaries() {
+        assert_eq!(Grade::for_score(100), Grade::APlus);
+        assert_eq!(Grade::for_score(97), Grade::A);
+        assert_eq!(Grade::for_score(75), Grade::B);
+        assert_eq!(Grade::for_score(44), Grade::F);
+    }
+}
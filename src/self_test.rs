@@ -0,0 +1,179 @@
+/*!
+ * Startup self-test
+ *
+ * With `SELF_TEST_ON_STARTUP=true` the binary sends a few synthetic
+ * requests through the composed router, in-process and before binding the
+ * listener, so a stack that is broken for every request (a layer that
+ * panics, a security header whose value cannot be sent) stops the
+ * container at startup with exit code 2 instead of failing live traffic.
+ *
+ * The requests are the rows of [`CHECKS`]; a route worth guarding adds a
+ * row there. Each response must have the expected status and carry every
+ * security header the configuration enables. The router runs on its own
+ * [`AppState`] built from the same configuration, with maintenance mode
+ * off, so the requests do not show up in metrics or `/admin/stats`. They
+ * come from a loopback peer, which `DIRECT_ACCESS_POLICY` lets through.
+ */
+use crate::config::AppConfig;
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Method, Request, StatusCode},
+};
+use std::fmt;
+use std::net::SocketAddr;
+use tower::ServiceExt;
+
+/// Status a check accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expect {
+    Status(StatusCode),
+
+    /// Anything below 500; for routes the configuration may protect
+    NotServerError,
+}
+
+impl Expect {
+    fn accepts(self, status: StatusCode) -> bool {
+        match self {
+            Self::Status(expected) => status == expected,
+            Self::NotServerError => !status.is_server_error(),
+        }
+    }
+}
+
+impl fmt::Display for Expect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(status) => write!(f, "{}", status.as_u16()),
+            Self::NotServerError => write!(f, "a status below 500"),
+        }
+    }
+}
+
+/// One synthetic request and what its response must look like
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub method: Method,
+    pub path: &'static str,
+    pub expect: Expect,
+}
+
+/// Requests sent by the startup self-test
+pub const CHECKS: [Check; 4] = [
+    Check { name: "health", method: Method::GET, path: "/health", expect: Expect::Status(StatusCode::OK) },
+    Check { name: "homepage", method: Method::GET, path: "/", expect: Expect::NotServerError },
+    Check {
+        name: "not found",
+        method: Method::GET,
+        path: "/__self-test/missing",
+        expect: Expect::Status(StatusCode::NOT_FOUND),
+    },
+    Check { name: "options", method: Method::OPTIONS, path: "/health", expect: Expect::Status(StatusCode::NO_CONTENT) },
+];
+
+/// Outcome of one check
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub method: Method,
+    pub path: &'static str,
+
+    /// Status received, `None` when the request panicked
+    pub status: Option<StatusCode>,
+
+    /// What was wrong with the response; empty when it passed
+    pub problems: Vec<String>,
+}
+
+/// Outcome of every check, in table order
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub results: Vec<CheckResult>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.problems.is_empty())
+    }
+
+    /// `Ok` when every check passed, else a configuration error naming the
+    /// failures
+    pub fn into_result(self) -> crate::Result<()> {
+        if self.passed() {
+            return Ok(());
+        }
+        let failures: Vec<String> = self
+            .results
+            .iter()
+            .filter(|result| !result.problems.is_empty())
+            .map(|result| format!("{} ({})", result.name, result.problems.join("; ")))
+            .collect();
+        Err(crate::ServerError::ConfigError(format!("Startup self-test failed: {}", failures.join(", "))))
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let passed = self.results.iter().filter(|result| result.problems.is_empty()).count();
+        write!(f, "Startup self-test: {}/{} checks passed", passed, self.results.len())?;
+        for result in &self.results {
+            let status = result.status.map_or("-".to_string(), |status| status.as_u16().to_string());
+            let outcome = if result.problems.is_empty() { "ok".to_string() } else { result.problems.join("; ") };
+            write!(f, "\n  {:<10} {} {} -> {}: {}", result.name, result.method, result.path, status, outcome)?;
+        }
+        Ok(())
+    }
+}
+
+/// Send each of `checks` through a router built for `config`
+pub async fn run(config: &AppConfig, checks: &[Check]) -> SelfTestReport {
+    let mut config = config.clone();
+    config.maintenance.enabled = false;
+    let expected_headers = config.security.enabled_headers();
+    let app = crate::create_app(AppState::new(config));
+
+    let mut results = Vec::with_capacity(checks.len());
+    for check in checks {
+        let request = Request::builder()
+            .method(check.method.clone())
+            .uri(check.path)
+            .header(header::HOST, "localhost")
+            .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))))
+            .body(Body::empty())
+            .expect("valid self-test request");
+
+        // A spawned task turns a panicking layer into an error instead of
+        // unwinding through startup
+        let response = tokio::spawn(app.clone().oneshot(request)).await;
+        let mut result = CheckResult {
+            name: check.name,
+            method: check.method.clone(),
+            path: check.path,
+            status: None,
+            problems: Vec::new(),
+        };
+        match response {
+            Ok(Ok(response)) => {
+                result.status = Some(response.status());
+                if !check.expect.accepts(response.status()) {
+                    result.problems.push(format!("expected {}", check.expect));
+                }
+                let missing: Vec<&str> = expected_headers
+                    .iter()
+                    .filter(|name| !response.headers().contains_key(*name))
+                    .map(|name| name.as_str())
+                    .collect();
+                if !missing.is_empty() {
+                    result.problems.push(format!("missing {}", missing.join(", ")));
+                }
+            }
+            Ok(Err(infallible)) => match infallible {},
+            Err(e) => result.problems.push(format!("request failed: {}", e)),
+        }
+        results.push(result);
+    }
+    SelfTestReport { results }
+}
@@ -0,0 +1,369 @@
+/*!
+ * Serving the router on a TCP listener
+ *
+ * There is one serving code path: `serve_with_listener` runs a router on a
+ * bound listener until a shutdown future resolves, then drains in-flight
+ * requests. Connections are served over HTTP/1.1 or h2c, whichever the
+ * client starts with, using the settings in `protocol::ProtocolConfig`
+ * (`serve_with_protocol`); upgrades such as WebSockets are supported. `serve` uses it for the production setup (OS signals, admin
+ * listener, quick tunnel), and `ServerHandle` wraps it for tests and
+ * embedders that need the bound address and programmatic shutdown.
+ */
+use crate::admin;
+use crate::bounded;
+use crate::config::AppConfig;
+use crate::direct_access::DirectAccessPolicy;
+use crate::log_level;
+use crate::maintenance;
+use crate::notifications::{self, NotificationEvent};
+use crate::protocol::ProtocolConfig;
+use crate::shutdown::{ShutdownReport, ShutdownToken};
+use crate::startup::StartupReport;
+use crate::state::AppState;
+use crate::tasks;
+use crate::tunnel::{QuickTunnel, TunnelConfig};
+use crate::{AppBuilder, Result, ServerError};
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tower::ServiceExt;
+use tracing::{debug, error, info, warn};
+
+/// Serve `app` on `listener` until `shutdown` resolves, then wait for
+/// in-flight requests to complete.
+///
+/// Handlers see the peer address through `ConnectInfo<SocketAddr>`.
+pub async fn serve_with_listener<F>(listener: TcpListener, app: Router, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    serve_with_protocol(listener, app, &ProtocolConfig::default(), shutdown).await
+}
+
+/// [`serve_with_listener`] with the connection settings in `protocol`
+pub async fn serve_with_protocol<F>(listener: TcpListener, app: Router, protocol: &ProtocolConfig, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let http1 = protocol.http1_builder();
+    let auto = protocol.http2.then(|| protocol.auto_builder());
+    // Each connection holds a sender; `recv` returns `None` once all are gone
+    let (open_tx, mut open_rx) = mpsc::channel::<()>(1);
+    let (close_tx, close_rx) = watch::channel(false);
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    // Usually out of file descriptors; give connections time to close
+                    error!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let app = app.clone();
+        let service = service_fn(move |mut request: hyper::Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(peer));
+            host_from_authority(&mut request);
+            app.clone().oneshot(request)
+        });
+        let io = TokioIo::new(stream);
+        let open = open_tx.clone();
+        let close = wait_for_shutdown(close_rx.clone());
+        match &auto {
+            Some(auto) => {
+                let connection = auto.serve_connection_with_upgrades(io, service).into_owned();
+                tokio::spawn(drive(connection, |connection| connection.graceful_shutdown(), close, open, peer));
+            }
+            None => {
+                let connection = http1.serve_connection(io, service).with_upgrades();
+                tokio::spawn(drive(connection, |connection| connection.graceful_shutdown(), close, open, peer));
+            }
+        }
+    }
+
+    drop(listener);
+    let _ = close_tx.send(true);
+    drop(open_tx);
+    open_rx.recv().await;
+    Ok(())
+}
+
+/// Serve one connection until it ends, shutting it down gracefully once
+/// `close` resolves; `open` is held until then
+async fn drive<C, E>(
+    connection: C,
+    graceful_shutdown: impl FnOnce(Pin<&mut C>),
+    close: impl Future<Output = ()>,
+    open: mpsc::Sender<()>,
+    peer: SocketAddr,
+) where
+    C: Future<Output = std::result::Result<(), E>>,
+    E: std::fmt::Display,
+{
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = close => {
+            graceful_shutdown(connection.as_mut());
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        debug!("Connection from {} ended with an error: {}", peer, e);
+    }
+    drop(open);
+}
+
+/// Give an HTTP/2 request the `Host` header its `:authority` stands in for,
+/// so handlers read the host the same way for either protocol
+fn host_from_authority<B>(request: &mut hyper::Request<B>) {
+    if request.version() != hyper::Version::HTTP_2 || request.headers().contains_key(hyper::header::HOST) {
+        return;
+    }
+    let host = request.uri().authority().and_then(|authority| authority.as_str().parse().ok());
+    if let Some(host) = host {
+        request.headers_mut().insert(hyper::header::HOST, host);
+    }
+}
+
+/// Accept errors that concern one connection rather than the listener
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+/// A server running in a background task
+///
+/// ```no_run
+/// # async fn run() -> cloudflare_tunnel_example::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+/// let server = cloudflare_tunnel_example::ServerHandle::start(
+///     listener,
+///     cloudflare_tunnel_example::create_app_with_defaults(),
+/// )?;
+/// println!("listening on {}", server.local_addr());
+/// server.stop().await
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// Start serving `app` on `listener` in a background task
+    pub fn start(listener: TcpListener, app: Router) -> Result<Self> {
+        Self::start_with_protocol(listener, app, ProtocolConfig::default())
+    }
+
+    /// [`ServerHandle::start`] with the HTTP/1.1 connection settings in
+    /// `protocol`
+    pub fn start_with_protocol(listener: TcpListener, app: Router, protocol: ProtocolConfig) -> Result<Self> {
+        let addr = listener.local_addr()?;
+        let (shutdown, rx) = watch::channel(false);
+        let task = tokio::spawn(async move {
+            serve_with_protocol(listener, app, &protocol, wait_for_shutdown(rx)).await
+        });
+
+        Ok(Self { addr, shutdown, task })
+    }
+
+    /// Address the listener is bound to (useful after binding port 0)
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Ask the server to stop accepting connections and drain
+    pub fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    /// Wait for the server task to finish
+    pub async fn wait(self) -> Result<()> {
+        self.task
+            .await
+            .map_err(|e| ServerError::RuntimeError(std::io::Error::other(e)))?
+    }
+
+    /// Trigger shutdown and wait for the drain to complete
+    pub async fn stop(self) -> Result<()> {
+        self.shutdown();
+        self.wait().await
+    }
+}
+
+/// Serve the application for `config` on `listener` until Ctrl+C or SIGTERM.
+///
+/// Also binds the admin listener and starts the embedded quick tunnel when
+/// they are configured, and waits for in-flight requests (and then background
+/// tasks and notifications, each up to `tasks::SHUTDOWN_DEADLINE`) to
+/// finish before returning.
+/// The outcome is logged as the final `shutdown::ShutdownReport`.
+pub async fn serve(config: AppConfig, listener: TcpListener) -> Result<()> {
+    serve_with_token(config, listener, ShutdownToken::on_signals()).await
+}
+
+/// [`serve`] until `shutdown` is requested. A request that arrived before
+/// the call (or arrives while listeners bind and cloudflared starts) skips
+/// the remaining startup phases and returns `Ok` once whatever was started
+/// has stopped.
+pub async fn serve_with_token(config: AppConfig, listener: TcpListener, shutdown: ShutdownToken) -> Result<()> {
+    let addr = listener.local_addr()?;
+    info!("{}", StartupReport::new(&config, addr));
+    info!("{}", crate::security_score::score(&config.security));
+    if let Some(tunnel) = &config.tunnel {
+        info!("Embedded cloudflared quick tunnel enabled ({})", tunnel.binary.display());
+    }
+    if config.direct_access != DirectAccessPolicy::Allow {
+        info!("Direct-to-origin requests are handled with policy {:?}", config.direct_access);
+    }
+    if let Some(tunnel_health) = &config.tunnel_health {
+        info!("Readiness follows cloudflared at {}/ready", tunnel_health.metrics_url);
+    }
+    for rule in &config.auth {
+        info!("Bearer token required for {} ({})", rule.prefixes.join(", "), rule.name);
+    }
+    if let Some(api_keys) = &config.api_keys {
+        info!("API key required for {} ({} keys)", api_keys.prefixes.join(", "), api_keys.keys.len());
+    }
+    for webhook in &config.webhooks {
+        info!("Accepting {} webhooks at /webhooks/{}", webhook.scheme.as_str(), webhook.name);
+    }
+    if let Some(static_files) = &config.static_files {
+        info!("Serving {} under {}", static_files.dir.display(), static_files.prefix);
+    }
+    if config.maintenance.enabled {
+        warn!("Starting in maintenance mode (MAINTENANCE_MODE=true)");
+    }
+    if let Some(sentinel) = &config.maintenance.sentinel {
+        info!("Maintenance mode follows {}", sentinel.display());
+    }
+    
+    let admin_config = config.admin.clone();
+    let tunnel_config = config.tunnel.clone();
+    let state = AppState::new(config);
+    if shutdown.startup_aborted("background tasks") {
+        ShutdownReport::new(state.uptime(), 0, None, shutdown.signal()).log();
+        return Ok(());
+    }
+    let admin = admin_config.map(|admin_config| {
+        (admin_config.addr, admin::create_admin_app(&admin_config, &state))
+    });
+    maintenance::watch_sentinel(&state);
+    log_level::watch(&state);
+    notifications::watch(&state);
+    bounded::watch(&state);
+    
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let draining = state.clone();
+    let requested = shutdown.clone();
+    tokio::spawn(async move {
+        let signal = requested.requested().await;
+        notifications::notify(&draining, NotificationEvent::Stopping, format!("{} received", signal));
+        if draining.drain.shutdown() {
+            info!("Shutdown signal received while drained, draining connections");
+        } else {
+            info!("Shutdown signal received, draining connections");
+            draining.set_ready(false);
+        }
+        let _ = shutdown_tx.send(true);
+    });
+    
+    let result = run(&state, listener, addr, admin, tunnel_config, &shutdown, shutdown_rx).await;
+    let report = ShutdownReport::new(state.uptime(), state.requests_served(), result.as_ref().err(), shutdown.signal());
+    report.log();
+    result
+}
+
+/// Serve until `shutdown_rx` flips, then wait for the tunnel and tasks.
+/// Phases not yet started when `shutdown` is requested are skipped.
+async fn run(
+    state: &AppState,
+    listener: TcpListener,
+    addr: SocketAddr,
+    admin: Option<(SocketAddr, Router)>,
+    tunnel_config: Option<TunnelConfig>,
+    shutdown: &ShutdownToken,
+    shutdown_rx: watch::Receiver<bool>,
+) -> Result<()> {
+    let app = AppBuilder::new(state.clone()).try_build()?;
+    info!(stages = ?state.pipeline(), "Middleware pipeline");
+    
+    // cloudflared is pointed at the bound address, so it starts after bind
+    let tunnel = tunnel_config
+        .zip(state.tunnel.clone())
+        .filter(|_| !shutdown.startup_aborted("cloudflared"))
+        .map(|(tunnel_config, status)| QuickTunnel::spawn(tunnel_config, addr, status, shutdown_rx.clone()));
+    
+    // Connection settings are fixed at startup; a config reload leaves them alone
+    let protocol = state.config().protocol.clone();
+    let main_server = serve_with_protocol(listener, app, &protocol, wait_for_shutdown(shutdown_rx.clone()));
+    
+    match admin {
+        _ if shutdown.startup_aborted("serving") => drop(main_server),
+        Some((admin_addr, admin_app)) => {
+            let admin_listener = tokio::net::TcpListener::bind(&admin_addr)
+                .await
+                .map_err(|e| ServerError::BindError { addr: admin_addr, source: e })?;
+            info!("Admin listener bound to {}", admin_addr);
+            notify_started(state, addr);
+            
+            let admin_server = serve_with_protocol(admin_listener, admin_app, &protocol, wait_for_shutdown(shutdown_rx.clone()));
+            tokio::try_join!(main_server, admin_server)?;
+        }
+        None => {
+            notify_started(state, addr);
+            main_server.await?
+        }
+    }
+    
+    if let Some(tunnel) = tunnel {
+        tunnel.join().await;
+    }
+
+    let abandoned = state.tasks.shutdown(tasks::SHUTDOWN_DEADLINE).await;
+    if !abandoned.is_empty() {
+        warn!("Abandoned background tasks still running at shutdown: {}", abandoned.join(", "));
+    }
+    if tokio::time::timeout(tasks::SHUTDOWN_DEADLINE, state.notifier.flush()).await.is_err() {
+        warn!("Abandoned notifications still being delivered at shutdown");
+    }
+    
+    info!("Server stopped");
+    Ok(())
+}
+
+/// Send `started` once the listeners are about to serve
+fn notify_started(state: &AppState, addr: SocketAddr) {
+    let mut detail = format!("serving on {}", addr);
+    if state.maintenance.is_enabled() {
+        detail.push_str(" in maintenance mode");
+    }
+    notifications::notify(state, NotificationEvent::Started, detail);
+}
+
+/// Resolve once `shutdown` flips to `true` (or its sender is dropped)
+async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
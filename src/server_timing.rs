@@ -0,0 +1,126 @@
+/*!
+ * `Server-Timing` response header
+ *
+ * With `SERVER_TIMING=true` every response says how long the origin spent
+ * on it, so "is it the tunnel or the origin" can be answered from the
+ * browser's devtools; Cloudflare passes the header through. The header
+ * always has `app;dur=<ms>`, the time from the request entering the
+ * service to its response head. Components that wait on something else add
+ * their own entries through the request's [`TimingCollector`]:
+ *
+ * - `upstream`: the reverse proxy, until the upstream's response head
+ * - `outbound`: each call through the shared HTTP client, such as
+ *   Turnstile verification
+ *
+ * e.g. `Server-Timing: app;dur=48.213, upstream;dur=45.870`. Durations are
+ * in milliseconds with three decimals. A `Server-Timing` header from a
+ * proxied upstream is kept, and ours is added after it.
+ *
+ * Off by default, since it tells anyone how long requests take to handle.
+ */
+use crate::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+pub const SERVER_TIMING: HeaderName = HeaderName::from_static("server-timing");
+
+tokio::task_local! {
+    static COLLECTOR: TimingCollector;
+}
+
+/// Whether `SERVER_TIMING` asks for the header
+pub fn from_env() -> crate::Result<bool> {
+    match std::env::var("SERVER_TIMING") {
+        Ok(value) => value.parse().map_err(|e| crate::ServerError::ConfigError(
+            format!("Invalid SERVER_TIMING flag: {}", e)
+        )),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Timings gathered while a request is handled, in the order they finished
+#[derive(Debug, Clone, Default)]
+pub struct TimingCollector {
+    entries: Arc<Mutex<Vec<(&'static str, Duration)>>>,
+}
+
+impl TimingCollector {
+    /// Add an entry; names that are not header tokens are skipped
+    pub fn record(&self, name: &'static str, duration: Duration) {
+        if !is_token(name) {
+            warn!(name, "Skipped a Server-Timing entry whose name is not a token");
+            return;
+        }
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).push((name, duration));
+    }
+
+    pub fn entries(&self) -> Vec<(&'static str, Duration)> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The collector of the request this task is handling, when the header
+    /// is enabled
+    pub fn current() -> Option<Self> {
+        COLLECTOR.try_with(Clone::clone).ok()
+    }
+
+    /// Run `future` with this as the [`current`](Self::current) collector
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        COLLECTOR.scope(self, future).await
+    }
+}
+
+/// One `Server-Timing` metric, `name;dur=<milliseconds>`
+pub fn entry(name: &str, duration: Duration) -> String {
+    format!("{};dur={:.3}", name, duration.as_secs_f64() * 1000.0)
+}
+
+/// Whether `name` is an HTTP token, as metric names must be
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Middleware adding `Server-Timing` when enabled
+pub async fn server_timing(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    if !state.config().server_timing {
+        return next.run(request).await;
+    }
+
+    let started = Instant::now();
+    let collector = TimingCollector::default();
+    request.extensions_mut().insert(collector.clone());
+    let mut response = collector.clone().scope(next.run(request)).await;
+
+    let mut entries = vec![entry("app", started.elapsed())];
+    entries.extend(collector.entries().into_iter().map(|(name, duration)| entry(name, duration)));
+    if let Ok(value) = HeaderValue::from_str(&entries.join(", ")) {
+        response.headers_mut().append(SERVER_TIMING, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_well_formed() {
+        assert_eq!(entry("app", Duration::from_micros(12_345)), "app;dur=12.345");
+        assert_eq!(entry("upstream", Duration::ZERO), "upstream;dur=0.000");
+
+        let collector = TimingCollector::default();
+        collector.record("outbound", Duration::from_millis(3));
+        collector.record("not a token", Duration::from_millis(3));
+        collector.record("", Duration::from_millis(3));
+        assert_eq!(collector.entries(), [("outbound", Duration::from_millis(3))]);
+    }
+}
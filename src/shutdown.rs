@@ -0,0 +1,227 @@
+/*!
+ * Exit codes and the final shutdown report
+ *
+ * The binary's exit status says why it stopped, so a supervisor can tell a
+ * deliberate stop from a deployment that will never come up:
+ *
+ * - `0`: clean shutdown after Ctrl+C or SIGTERM
+ * - `2`: invalid configuration (or command line)
+ * - `3`: a listener could not be bound
+ * - `4`: I/O failure while serving
+ *
+ * Whatever the outcome, the last event logged is a `Shutdown report` with
+ * the uptime, the requests served and the reason, at `info` for a clean
+ * shutdown and `error` otherwise.
+ *
+ * Signals are watched through a [`ShutdownToken`] created before startup
+ * begins. Startup checks it between phases (configuration, self-test,
+ * access log, listener bind, background tasks, cloudflared, admin
+ * listener), so a SIGTERM that arrives before serving starts skips the
+ * remaining phases, stops whatever was already started and exits `0`
+ * after logging `Shutdown requested during startup`.
+ */
+use crate::{Result, ServerError};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, error, info};
+
+/// Clean shutdown
+pub const EXIT_CLEAN: u8 = 0;
+
+/// Invalid configuration or command line
+pub const EXIT_CONFIG: u8 = 2;
+
+/// A listener could not be bound
+pub const EXIT_BIND: u8 = 3;
+
+/// I/O failure while serving
+pub const EXIT_RUNTIME: u8 = 4;
+
+/// The process exit status for the outcome of `serve`
+pub fn exit_code(result: &Result<()>) -> u8 {
+    result.as_ref().err().map_or(EXIT_CLEAN, error_exit_code)
+}
+
+fn error_exit_code(error: &ServerError) -> u8 {
+    match error {
+        ServerError::ConfigError(_) => EXIT_CONFIG,
+        ServerError::BindError { .. } => EXIT_BIND,
+        ServerError::RuntimeError(_) => EXIT_RUNTIME,
+    }
+}
+
+/// Summary logged as the process stops
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub uptime: Duration,
+    pub requests: u64,
+
+    /// The signal received, or the error that stopped the server
+    pub reason: String,
+
+    pub exit_code: u8,
+}
+
+impl ShutdownReport {
+    /// Report for a stop caused by `error`, or a clean shutdown started by
+    /// `signal` when there is none
+    pub fn new(uptime: Duration, requests: u64, error: Option<&ServerError>, signal: Option<&str>) -> Self {
+        let (reason, exit_code) = match error {
+            None => (
+                signal.map_or_else(|| "shutdown requested".to_string(), |signal| format!("{} received", signal)),
+                EXIT_CLEAN,
+            ),
+            Some(e) => (e.to_string(), error_exit_code(e)),
+        };
+        Self { uptime, requests, reason, exit_code }
+    }
+
+    /// Emit the report as one structured event
+    pub fn log(&self) {
+        let uptime_seconds = self.uptime.as_secs_f64();
+        if self.exit_code == EXIT_CLEAN {
+            info!(uptime_seconds, requests = self.requests, reason = %self.reason, exit_code = self.exit_code, "Shutdown report");
+        } else {
+            error!(uptime_seconds, requests = self.requests, reason = %self.reason, exit_code = self.exit_code, "Shutdown report");
+        }
+    }
+}
+
+/// The shutdown signal, watched from before startup begins
+#[derive(Debug, Clone)]
+pub struct ShutdownToken {
+    signal: watch::Receiver<Option<&'static str>>,
+}
+
+impl ShutdownToken {
+    /// Watch for Ctrl+C and, on Unix, SIGTERM from now on. Must be called
+    /// within a Tokio runtime; the handlers are installed before it returns.
+    pub fn on_signals() -> Self {
+        let (sender, token) = Self::new();
+        let signal = signals();
+        tokio::spawn(async move {
+            let signal = signal.await;
+            let _ = sender.send(Some(signal));
+            debug!(signal, "Shutdown signal received");
+        });
+        token
+    }
+
+    /// A token set by sending it the name of a signal, for tests and
+    /// embedders with their own shutdown trigger
+    pub fn new() -> (watch::Sender<Option<&'static str>>, Self) {
+        let (sender, signal) = watch::channel(None);
+        (sender, Self { signal })
+    }
+
+    /// The signal received so far
+    pub fn signal(&self) -> Option<&'static str> {
+        *self.signal.borrow()
+    }
+
+    /// Resolve with the signal once one is received; never, if the sender
+    /// is dropped first
+    pub async fn requested(&self) -> &'static str {
+        let mut signal = self.signal.clone();
+        match signal.wait_for(Option::is_some).await.map(|signal| *signal) {
+            Ok(Some(signal)) => signal,
+            _ => std::future::pending().await,
+        }
+    }
+
+    /// Whether startup should stop instead of running `phase`, logging so
+    /// when it should
+    pub fn startup_aborted(&self, phase: &str) -> bool {
+        let Some(signal) = self.signal() else {
+            return false;
+        };
+        info!(signal, skipped = phase, "Shutdown requested during startup");
+        true
+    }
+}
+
+/// Resolve on Ctrl+C or SIGTERM (sent by `docker stop`), with the name of
+/// the signal
+#[cfg(unix)]
+fn signals() -> impl Future<Output = &'static str> + Send {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // Installed now rather than when first polled
+    let listen = |kind: SignalKind, name: &'static str| {
+        let stream = signal(kind);
+        async move {
+            match stream {
+                Ok(mut stream) => {
+                    stream.recv().await;
+                }
+                Err(e) => {
+                    error!("Failed to listen for {}: {}", name, e);
+                    std::future::pending::<()>().await;
+                }
+            }
+        }
+    };
+    let interrupt = listen(SignalKind::interrupt(), "SIGINT");
+    let terminate = listen(SignalKind::terminate(), "SIGTERM");
+    async move {
+        tokio::select! {
+            _ = interrupt => "SIGINT",
+            _ = terminate => "SIGTERM",
+        }
+    }
+}
+
+/// Resolve on Ctrl+C, with the name of the signal
+#[cfg(not(unix))]
+fn signals() -> impl Future<Output = &'static str> + Send {
+    async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+        "SIGINT"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let bind_error = ServerError::BindError {
+            addr: "127.0.0.1:80".parse().unwrap(),
+            source: std::io::Error::from(std::io::ErrorKind::PermissionDenied),
+        };
+        let runtime_error = ServerError::RuntimeError(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+
+        assert_eq!(exit_code(&Ok(())), 0);
+        assert_eq!(exit_code(&Err(ServerError::ConfigError("bad".to_string()))), 2);
+        assert_eq!(exit_code(&Err(bind_error)), 3);
+        assert_eq!(exit_code(&Err(runtime_error)), 4);
+    }
+
+    #[test]
+    fn test_report_reason() {
+        let uptime = Duration::from_secs(90);
+        let report = ShutdownReport::new(uptime, 12, None, Some("SIGTERM"));
+        assert_eq!((report.reason.as_str(), report.exit_code), ("SIGTERM received", EXIT_CLEAN));
+
+        let error = ServerError::ConfigError("Invalid HSTS max age".to_string());
+        let report = ShutdownReport::new(uptime, 0, Some(&error), Some("SIGTERM"));
+        assert_eq!(report.reason, "Configuration error: Invalid HSTS max age");
+        assert_eq!(report.exit_code, EXIT_CONFIG);
+    }
+
+    #[tokio::test]
+    async fn test_token_reports_the_signal() {
+        let (sender, token) = ShutdownToken::new();
+        assert_eq!(token.signal(), None);
+        assert!(!token.startup_aborted("listener bind"));
+
+        sender.send(Some("SIGTERM")).unwrap();
+        assert_eq!(token.requested().await, "SIGTERM");
+        assert!(token.startup_aborted("listener bind"));
+    }
+}
@@ -0,0 +1,275 @@
+/*!
+ * Availability against a service level objective
+ *
+ * Every response on the main listener is counted in a per-minute bucket
+ * as served or as a server error (5xx), from the request events (see
+ * [`crate::request_events`]). `GET /admin/slo` reports the share
+ * of requests that were not server errors over the last 5 minutes, hour
+ * and day, and the burn rate: the error rate divided by the error budget
+ * `1 - SLO_TARGET`, so 1.0 spends the budget exactly as fast as the
+ * target allows and 14.4 spends a 30-day budget in two days.
+ *
+ * The buckets form a ring of one day of minutes, 1440 entries whatever
+ * the traffic. Each bucket remembers which minute it counts, so a request
+ * landing on a bucket left over from an earlier day resets it and the
+ * report skips buckets outside its window. No timer is involved; time
+ * comes from a [`Clock`], which tests replace to move through the day.
+ */
+use crate::clock::{Clock, SystemClock};
+use crate::state::AppState;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the request event subscriber
+pub const SUBSCRIBER: &str = "slo";
+
+/// Minutes of history kept
+const MINUTES: usize = 24 * 60;
+
+/// Windows reported, with their names
+pub const WINDOWS: &[(&str, u64)] = &[("5m", 5), ("1h", 60), ("24h", 24 * 60)];
+
+/// Window the headline burn rate is computed over
+const BURN_RATE_WINDOW: &str = "1h";
+
+/// Objective the burn rate is measured against
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SloConfig {
+    /// Percentage of requests that should not be server errors
+    pub target: f64,
+}
+
+impl Default for SloConfig {
+    fn default() -> Self {
+        Self { target: 99.9 }
+    }
+}
+
+impl SloConfig {
+    /// Load `SLO_TARGET`, a percentage such as `99.5`
+    pub fn from_env() -> crate::Result<Self> {
+        let Ok(value) = std::env::var("SLO_TARGET") else {
+            return Ok(Self::default());
+        };
+        value
+            .trim()
+            .trim_end_matches('%')
+            .parse::<f64>()
+            .ok()
+            .filter(|target| *target > 0.0 && *target < 100.0)
+            .map(|target| Self { target })
+            .ok_or_else(|| crate::ServerError::ConfigError(format!(
+                "Invalid SLO_TARGET: {:?} is not a percentage between 0 and 100 (exclusive)",
+                value
+            )))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Bucket {
+    /// Minutes since the Unix epoch this bucket counts
+    minute: u64,
+    requests: u64,
+    server_errors: u64,
+}
+
+/// Per-minute request and server error counts for the last day; clones
+/// share the counts
+#[derive(Debug, Clone)]
+pub struct AvailabilityTracker {
+    clock: Arc<dyn Clock>,
+    buckets: Arc<Mutex<Vec<Bucket>>>,
+}
+
+impl Default for AvailabilityTracker {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+/// One reporting window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WindowReport {
+    pub window: &'static str,
+    pub requests: u64,
+    pub server_errors: u64,
+
+    /// Percentage of requests that were not server errors; `None` without traffic
+    pub availability: Option<f64>,
+
+    /// Error rate over the error budget; `None` without traffic
+    pub burn_rate: Option<f64>,
+}
+
+/// Body of `GET /admin/slo`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SloReport {
+    pub target: f64,
+
+    /// Burn rate over the last hour
+    pub burn_rate: Option<f64>,
+    pub windows: Vec<WindowReport>,
+}
+
+fn minute_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs() / 60).unwrap_or_default()
+}
+
+impl AvailabilityTracker {
+    /// Tracker reading the time from `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            buckets: Arc::new(Mutex::new(vec![Bucket::default(); MINUTES])),
+        }
+    }
+
+    /// Count one response, a server error or not, in the current minute
+    pub fn record(&self, server_error: bool) {
+        let minute = minute_of(self.clock.now());
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return;
+        };
+        let bucket = &mut buckets[(minute % MINUTES as u64) as usize];
+        if bucket.minute != minute {
+            *bucket = Bucket { minute, ..Bucket::default() };
+        }
+        bucket.requests += 1;
+        if server_error {
+            bucket.server_errors += 1;
+        }
+    }
+
+    /// Availability and burn rate per window against `config`
+    pub fn report(&self, config: &SloConfig) -> SloReport {
+        let now = minute_of(self.clock.now());
+        let buckets = self.buckets.lock().map(|buckets| buckets.clone()).unwrap_or_default();
+        let budget = 1.0 - config.target / 100.0;
+
+        let windows: Vec<WindowReport> = WINDOWS
+            .iter()
+            .map(|(window, minutes)| {
+                // The current minute counts as the first of the window
+                let (requests, server_errors) = buckets
+                    .iter()
+                    .filter(|bucket| bucket.minute <= now && now - bucket.minute < *minutes)
+                    .fold((0, 0), |(requests, errors), bucket| {
+                        (requests + bucket.requests, errors + bucket.server_errors)
+                    });
+                let error_rate = (requests > 0).then(|| server_errors as f64 / requests as f64);
+                WindowReport {
+                    window,
+                    requests,
+                    server_errors,
+                    availability: error_rate.map(|rate| (1.0 - rate) * 100.0),
+                    burn_rate: error_rate.map(|rate| rate / budget),
+                }
+            })
+            .collect();
+
+        SloReport {
+            target: config.target,
+            burn_rate: windows
+                .iter()
+                .find(|report| report.window == BURN_RATE_WINDOW)
+                .and_then(|report| report.burn_rate),
+            windows,
+        }
+    }
+}
+
+/// Count every response on the main listener towards availability, as
+/// `state.request_events` reports it
+pub fn subscribe(state: &AppState) {
+    let slo = state.slo.clone();
+    state.request_events.subscribe(SUBSCRIBER, move |event| {
+        slo.record(event.status.is_server_error());
+        async {}
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestClock;
+    use std::time::Duration;
+
+    fn tracker() -> (Arc<TestClock>, AvailabilityTracker) {
+        // 2024-01-01T00:00:00Z, on a minute boundary
+        let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+        (clock.clone(), AvailabilityTracker::new(clock))
+    }
+
+    fn traffic(tracker: &AvailabilityTracker, requests: u64, server_errors: u64) {
+        for i in 0..requests {
+            tracker.record(i < server_errors);
+        }
+    }
+
+    fn window<'a>(report: &'a SloReport, name: &str) -> &'a WindowReport {
+        report.windows.iter().find(|window| window.window == name).unwrap()
+    }
+
+    #[test]
+    fn test_windows_cover_their_minutes() {
+        let (clock, tracker) = tracker();
+        let config = SloConfig { target: 99.0 };
+
+        // 2 hours ago: 1000 requests, 100 errors
+        traffic(&tracker, 1000, 100);
+        clock.advance(Duration::from_secs(60 * 60));
+        // 1 hour ago (outside the 1h window by one minute)
+        traffic(&tracker, 100, 0);
+        clock.advance(Duration::from_secs(56 * 60));
+        // 4 minutes ago and now
+        traffic(&tracker, 100, 2);
+        clock.advance(Duration::from_secs(4 * 60 + 59));
+        traffic(&tracker, 100, 0);
+
+        let report = tracker.report(&config);
+        let five = window(&report, "5m");
+        assert_eq!((five.requests, five.server_errors), (200, 2));
+        assert_eq!(five.availability, Some(99.0));
+        assert!((five.burn_rate.unwrap() - 1.0).abs() < 1e-9);
+
+        let hour = window(&report, "1h");
+        assert_eq!((hour.requests, hour.server_errors), (200, 2));
+        assert_eq!(report.burn_rate, hour.burn_rate);
+
+        let day = window(&report, "24h");
+        assert_eq!((day.requests, day.server_errors), (1300, 102));
+        let expected = (1.0 - 102.0 / 1300.0) * 100.0;
+        assert!((day.availability.unwrap() - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_old_buckets_expire_and_are_reused() {
+        let (clock, tracker) = tracker();
+        let config = SloConfig::default();
+        traffic(&tracker, 10, 10);
+
+        // Exactly one day later the same bucket is reused for the new minute
+        clock.advance(Duration::from_secs(24 * 60 * 60));
+        let report = tracker.report(&config);
+        assert_eq!(window(&report, "24h").requests, 0);
+        assert_eq!(window(&report, "24h").availability, None);
+        assert_eq!(report.burn_rate, None);
+
+        traffic(&tracker, 4, 0);
+        let report = tracker.report(&config);
+        assert_eq!((window(&report, "24h").requests, window(&report, "24h").server_errors), (4, 0));
+        assert_eq!(window(&report, "5m").availability, Some(100.0));
+        assert_eq!(report.burn_rate, Some(0.0));
+    }
+
+    #[test]
+    fn test_target_from_env() {
+        std::env::set_var("SLO_TARGET", "99.5%");
+        assert_eq!(SloConfig::from_env().unwrap().target, 99.5);
+        std::env::set_var("SLO_TARGET", "100");
+        assert!(SloConfig::from_env().is_err());
+        std::env::remove_var("SLO_TARGET");
+        assert_eq!(SloConfig::from_env().unwrap(), SloConfig::default());
+    }
+}
@@ -0,0 +1,221 @@
+/*!
+ * Startup summary and bind diagnostics
+ *
+ * `serve` logs a `StartupReport` once the main listener is bound, so the
+ * effective setup (addresses, header policy, optional features, route
+ * groups, connection settings, runtime size, where configuration came
+ * from) is visible in one place. Bind failures get a hint for the two usual causes, a port already in use and
+ * a privileged port.
+ */
+use crate::config::{AppConfig, SecurityPreset};
+use crate::headers::SECURITY_HEADERS;
+use crate::protocol::ProtocolConfig;
+use axum::http::HeaderName;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+
+/// `*_FILE` variables that name something other than a secret
+const NON_SECRET_FILE_VARS: &[&str] = &["MAINTENANCE_FILE"];
+
+/// What the service is about to run with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartupReport {
+    /// Address the main listener is bound to
+    pub listen: SocketAddr,
+
+    /// Admin listener address, if enabled
+    pub admin: Option<SocketAddr>,
+
+    /// Preset the security headers started from
+    pub preset: SecurityPreset,
+
+    /// Security headers that are sent
+    pub headers_enabled: Vec<HeaderName>,
+
+    /// Security headers configured with an empty value, so not sent
+    pub headers_disabled: Vec<HeaderName>,
+
+    /// Optional features that are active, e.g. `metrics` or `proxy /api -> http://api:3000/`
+    pub features: Vec<String>,
+
+    /// Optional route groups and whether each starts on
+    pub route_groups: BTreeMap<&'static str, bool>,
+
+    /// HTTP/1.1 connection settings on the listeners
+    pub protocol: ProtocolConfig,
+
+    /// Tokio worker threads (0 outside a runtime)
+    pub worker_threads: usize,
+
+    /// Where settings were read from
+    pub config_sources: Vec<String>,
+}
+
+impl StartupReport {
+    /// Report for `config` served on `listen`, reading secret-file variables
+    /// from the environment
+    pub fn new(config: &AppConfig, listen: SocketAddr) -> Self {
+        let headers = config.security.to_headers();
+        let (headers_enabled, headers_disabled) = SECURITY_HEADERS
+            .into_iter()
+            .partition(|name| headers.iter().any(|(sent, _)| sent == name));
+
+        let mut config_sources = vec!["environment".to_string()];
+        config_sources.extend(secret_files().into_iter().map(|(name, _)| name));
+
+        Self {
+            listen,
+            admin: config.admin.as_ref().map(|admin| admin.addr),
+            preset: config.security.preset,
+            headers_enabled,
+            headers_disabled,
+            features: features(config),
+            route_groups: crate::features::defaults(config),
+            protocol: config.protocol.clone(),
+            worker_threads: tokio::runtime::Handle::try_current()
+                .map(|runtime| runtime.metrics().num_workers())
+                .unwrap_or(0),
+            config_sources,
+        }
+    }
+}
+
+/// `*_FILE` variables a secret was actually read from, with their paths,
+/// sorted by name; a file shadowed by the direct variable is left out
+pub fn secret_files() -> Vec<(String, String)> {
+    let mut files: Vec<(String, String)> = std::env::vars()
+        .filter(|(name, _)| name.ends_with("_FILE") && !NON_SECRET_FILE_VARS.contains(&name.as_str()))
+        .filter(|(name, _)| std::env::var_os(name.trim_end_matches("_FILE")).is_none())
+        .collect();
+    files.sort();
+    files
+}
+
+fn features(config: &AppConfig) -> Vec<String> {
+    let mut features = Vec::new();
+    #[cfg(feature = "metrics")]
+    features.push("metrics".to_string());
+    #[cfg(feature = "proxy")]
+    if let Some(proxy) = &config.proxy {
+        features.extend(proxy.routes.iter().map(|route| format!("proxy {} -> {}", route.prefix, route.upstream)));
+    }
+    #[cfg(feature = "turnstile")]
+    if config.turnstile.is_some() {
+        features.push("turnstile".to_string());
+    }
+    if let Some(static_files) = &config.static_files {
+        features.push(format!("static files {}", static_files.prefix));
+    }
+    if config.tunnel.is_some() {
+        features.push("quick tunnel".to_string());
+    }
+    if let Some(group) = &config.security.report_to {
+        features.push(format!("violation reports to {} ({})", crate::reports::PATH, group));
+    }
+    if config.force_https_redirect {
+        features.push("https redirect".to_string());
+    }
+    if config.server_timing {
+        features.push("server timing".to_string());
+    }
+    if config.expose_build_headers {
+        features.push(format!("build headers ({})", crate::build_info::BUILD_ID));
+    }
+    if let Some(notifications) = &config.notifications {
+        features.push(format!("notifications to {} webhooks", notifications.urls.len()));
+    }
+    features
+}
+
+fn list<T: fmt::Display>(items: &[T]) -> String {
+    if items.is_empty() {
+        return "none".to_string();
+    }
+    items.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+impl fmt::Display for StartupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Startup summary")?;
+        writeln!(f, "  listening on:     {}", self.listen)?;
+        match self.admin {
+            Some(admin) => writeln!(f, "  admin listener:   {}", admin)?,
+            None => writeln!(f, "  admin listener:   disabled")?,
+        }
+        writeln!(f, "  security preset:  {}", self.preset.name())?;
+        writeln!(f, "  headers enabled:  {}", list(&self.headers_enabled))?;
+        writeln!(f, "  headers disabled: {}", list(&self.headers_disabled))?;
+        writeln!(f, "  features:         {}", list(&self.features))?;
+        let groups = self.route_groups.iter().map(|(group, on)| format!("{} {}", group, if *on { "on" } else { "off" }));
+        writeln!(f, "  route groups:     {}", list(&groups.collect::<Vec<_>>()))?;
+        writeln!(f, "  protocol:         {}", self.protocol)?;
+        writeln!(f, "  worker threads:   {}", self.worker_threads)?;
+        write!(f, "  config sources:   {}", list(&self.config_sources))
+    }
+}
+
+/// Advice appended to a bind error, or an empty string when there is none
+pub(crate) fn bind_hint(addr: &SocketAddr, error: &std::io::Error) -> String {
+    match error.kind() {
+        ErrorKind::AddrInUse => format!(
+            " (another process is already listening on port {}; stop it or choose another address with --listen, or ADMIN_ADDR for the admin listener)",
+            addr.port()
+        ),
+        ErrorKind::PermissionDenied if addr.port() != 0 && addr.port() < 1024 => format!(
+            " (port {} is privileged; use a port of 1024 or above, such as 8080, or grant CAP_NET_BIND_SERVICE)",
+            addr.port()
+        ),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SecurityConfig;
+
+    fn report(security: SecurityConfig) -> StartupReport {
+        let config = AppConfig { security, ..AppConfig::default() };
+        StartupReport::new(&config, "127.0.0.1:8080".parse().unwrap())
+    }
+
+    #[test]
+    fn test_disabled_header_is_listed() {
+        let report = report(SecurityConfig {
+            xss_protection: String::new(),
+            ..SecurityConfig::default()
+        });
+
+        assert_eq!(report.headers_disabled, [crate::headers::X_XSS_PROTECTION]);
+        assert!(!report.headers_enabled.contains(&crate::headers::X_XSS_PROTECTION));
+
+        let text = report.to_string();
+        assert!(text.contains("headers disabled: x-xss-protection\n"), "{}", text);
+        assert!(text.contains("admin listener:   disabled"), "{}", text);
+        assert!(text.contains("security preset:  default"), "{}", text);
+    }
+
+    #[test]
+    fn test_defaults_enable_every_header() {
+        let text = report(SecurityConfig::default()).to_string();
+        assert!(text.contains("headers disabled: none"), "{}", text);
+        assert!(text.contains("protocol:         HTTP/1.1, keep-alive 120s; h2c\n"), "{}", text);
+        assert!(text.contains("events on, status on"), "{}", text);
+    }
+
+    #[test]
+    fn test_bind_hints() {
+        let addr: SocketAddr = "0.0.0.0:80".parse().unwrap();
+        let in_use = std::io::Error::from(ErrorKind::AddrInUse);
+        let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+
+        assert!(bind_hint(&addr, &in_use).contains("--listen"));
+        assert!(bind_hint(&addr, &denied).contains("privileged"));
+        assert!(bind_hint(&"0.0.0.0:8080".parse().unwrap(), &denied).is_empty());
+
+        let error = crate::ServerError::BindError { addr, source: in_use };
+        assert!(error.to_string().contains("already listening on port 80"), "{}", error);
+    }
+}
@@ -0,0 +1,489 @@
+/*!
+ * Shared application state
+ *
+ * `AppState` is attached to the router with `Router::with_state` and handed
+ * to handlers and middleware through `State` extractors. The configuration
+ * lives behind an `ArcSwap`: request-time code takes a fresh snapshot on
+ * every request (`State<Arc<AppConfig>>`), so a replacement stored with
+ * `AppState::replace_config` takes effect without rebuilding the router.
+ */
+use crate::access::AccessVerifier;
+use crate::api_keys::KeyRateLimiter;
+use crate::audit::AuditLog;
+use crate::clock::{Clock, SystemClock};
+use crate::config::AppConfig;
+use crate::concurrency::{BackpressureCheck, ConcurrencyLimiter};
+use crate::config_history::ConfigHistory;
+use crate::drain::DrainState;
+use crate::events::StreamCount;
+use crate::features::FeatureToggles;
+use crate::header_sampling::{RandomSampler, Sampler};
+use crate::health::HealthRegistry;
+use crate::http_client::HttpClient;
+use crate::idempotency::IdempotencyStore;
+use crate::log_level::LogLevel;
+use crate::maintenance::MaintenanceState;
+use crate::notifications::Notifier;
+use crate::capture::CaptureState;
+use crate::response_cache::ResponseCache;
+use crate::route_stats::RouteStatsRegistry;
+use crate::request_events::RequestEvents;
+use crate::slo::AvailabilityTracker;
+use crate::tasks::{TaskHealthCheck, TaskSupervisor};
+#[cfg(feature = "metrics")]
+use crate::metrics::{Counter, Gauge, Histogram, Metrics};
+use crate::tunnel::TunnelStatus;
+use crate::tunnel_health::TunnelHealthCheck;
+use crate::unknown_paths::UnknownPaths;
+use arc_swap::ArcSwap;
+use axum::extract::FromRef;
+#[cfg(feature = "metrics")]
+use axum::http::Method;
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Counters updated on every request
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone)]
+pub struct RequestCounters {
+    /// Every request seen by the main router
+    pub total: Counter,
+
+    /// Requests that arrived without Cloudflare headers
+    pub direct_hits: Counter,
+
+    /// `CF-Connecting-IP` values that were not an address
+    pub bad_client_ip_headers: Counter,
+
+    /// 4xx and 5xx responses, not counting `/favicon.ico` 404s
+    pub errors: Counter,
+
+    /// Requests whose response has not been produced yet
+    pub in_flight: Gauge,
+
+    /// Requests dropped because the client went away before the response
+    pub client_disconnects: Counter,
+
+    /// Bytes sent per response body
+    pub response_bytes: Histogram,
+
+    /// Milliseconds until the response head, with trace exemplars in
+    /// builds with the `otel` feature
+    pub duration: Histogram,
+
+    /// Requests per method in `COUNTED_METHODS`, then everything else
+    by_method: Vec<Counter>,
+}
+
+/// Methods counted under their own `method` label; others count as `OTHER`
+#[cfg(feature = "metrics")]
+const COUNTED_METHODS: [Method; 7] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::PATCH,
+    Method::OPTIONS,
+];
+
+#[cfg(feature = "metrics")]
+impl RequestCounters {
+    fn register(metrics: &Metrics) -> Self {
+        Self {
+            total: metrics.counter("http_requests_total", "Requests handled by the main listener"),
+            direct_hits: metrics.counter(
+                crate::direct_access::DIRECT_HITS_METRIC,
+                "Requests that reached the origin without Cloudflare headers",
+            ),
+            bad_client_ip_headers: metrics.counter(
+                crate::client_ip::BAD_HEADER_METRIC,
+                "CF-Connecting-IP values that were not an IP address, replaced by the peer address",
+            ),
+            errors: metrics.counter(
+                "http_errors_total",
+                "Responses with a 4xx or 5xx status, excluding favicon 404s",
+            ),
+            in_flight: metrics.gauge(
+                "http_requests_in_flight",
+                "Requests being handled, up to the response head",
+            ),
+            client_disconnects: metrics.counter(
+                "client_disconnects_total",
+                "Requests abandoned by the client before the response head was sent",
+            ),
+            response_bytes: metrics.histogram(
+                "http_response_body_bytes",
+                "Response body bytes sent per request",
+                crate::response_size::SIZE_BUCKETS,
+            ),
+            duration: metrics.histogram(
+                "http_request_duration_milliseconds",
+                "Time until the response head per request",
+                crate::metrics::LATENCY_BUCKETS_MS,
+            ),
+            by_method: COUNTED_METHODS
+                .iter()
+                .map(Method::as_str)
+                .chain(["OTHER"])
+                .map(|method| metrics.counter(
+                    &format!("http_requests_by_method_total{{method=\"{}\"}}", method),
+                    "Requests handled by the main listener, by method",
+                ))
+                .collect(),
+        }
+    }
+
+    /// Counter for requests with `method`
+    pub fn method(&self, method: &Method) -> &Counter {
+        let index = COUNTED_METHODS.iter().position(|m| m == method).unwrap_or(COUNTED_METHODS.len());
+        &self.by_method[index]
+    }
+}
+
+/// State shared by every handler and middleware; clones share everything
+#[derive(Debug, Clone)]
+pub struct AppState {
+    config: Arc<ArcSwap<AppConfig>>,
+
+    /// When `replace_config` last swapped the configuration
+    reloaded_at: Arc<RwLock<Option<DateTime<Utc>>>>,
+
+    /// `build_info::config_hash` of the current configuration
+    config_hash: Arc<RwLock<Arc<str>>>,
+
+    /// Recent security configurations, for `/admin/config/rollback`
+    config_history: Arc<RwLock<ConfigHistory>>,
+
+    /// Middleware stages of the last router built around this state,
+    /// outermost first
+    pipeline: Arc<RwLock<Vec<&'static str>>>,
+
+    /// When the state (and so the service) was created, by `clock`
+    pub started_at: Instant,
+
+    /// Time source for the whole state, replaced in tests
+    pub clock: Arc<dyn Clock>,
+
+    /// Counters rendered at `/metrics`
+    #[cfg(feature = "metrics")]
+    pub metrics: Metrics,
+
+    /// Checks consulted by `/readyz`
+    pub health: HealthRegistry,
+
+    /// Client for every outbound call, sharing its connection pool
+    pub http: HttpClient,
+
+    /// Cleared while draining so `/readyz` fails before the listener closes
+    /// and long-lived connections know to wind down
+    ready: Arc<watch::Sender<bool>>,
+
+    /// Per-request counters, also registered in `metrics`
+    #[cfg(feature = "metrics")]
+    pub requests: RequestCounters,
+
+    /// Embedded quick tunnel, when `RUN_CLOUDFLARED=quick`
+    pub tunnel: Option<TunnelStatus>,
+
+    /// Open `/events` streams, capped by `EVENTS_MAX_STREAMS`
+    pub event_streams: StreamCount,
+
+    /// Maintenance mode, switched at runtime by the admin API or sentinel file
+    pub maintenance: MaintenanceState,
+
+    /// Readiness withdrawn through `/admin/drain` ahead of shutdown
+    pub drain: DrainState,
+
+    /// Webhook notifications in flight and given up on
+    pub notifier: Notifier,
+
+    /// Checks Cloudflare Access tokens, when `ACCESS_TEAM_DOMAIN` is set
+    pub access: Option<AccessVerifier>,
+
+    /// Optional route groups, switched at runtime by `/admin/features`
+    pub features: FeatureToggles,
+
+    /// Per-key request counts for `API_KEY_RATE_LIMIT`
+    pub api_key_limiter: KeyRateLimiter,
+
+    /// Per-route counts and latencies reported at `/admin/stats`
+    pub route_stats: RouteStatsRegistry,
+
+    /// A `RequestCompleted` event per response, for the subscribers
+    /// registered when the router is built
+    pub request_events: RequestEvents,
+
+    /// 404s for paths that matched no route, reported at
+    /// `/admin/unknown-paths`
+    pub unknown_paths: UnknownPaths,
+
+    /// Per-minute availability reported at `/admin/slo`
+    pub slo: AvailabilityTracker,
+
+    /// Request/response capture started through `/admin/capture`
+    pub capture: CaptureState,
+
+    /// Responses held for `RESPONSE_CACHE` routes
+    pub response_cache: ResponseCache,
+
+    /// Periodic background jobs, stopped when the server shuts down
+    pub tasks: TaskSupervisor,
+
+    /// Responses to `Idempotency-Key` requests, replayed on retries
+    pub idempotency: IdempotencyStore,
+
+    /// Recent admin actions, served at `/admin/audit`
+    pub audit: AuditLog,
+
+    /// Log filter set through `/admin/log-level` or `SIGUSR1`
+    pub log_level: LogLevel,
+
+    /// Slots for `MAX_CONCURRENT_REQUESTS` and the running/queued counts
+    pub concurrency: ConcurrencyLimiter,
+
+    /// Requests per Cloudflare colo, from `CF-Ray`
+    #[cfg(feature = "metrics")]
+    pub colos: crate::cloudflare::ray::ColoCounter,
+
+    /// Reporting API reports received at `/reports`, per type
+    #[cfg(feature = "metrics")]
+    pub reports: crate::reports::ReportCounters,
+
+    /// Latency and errors injected through `/admin/faults`
+    #[cfg(feature = "fault-injection")]
+    pub faults: crate::faults::FaultState,
+
+    /// Picks the requests whose headers are logged at `HEADER_SAMPLE_RATE`
+    pub header_sampler: Arc<dyn Sampler>,
+}
+
+impl AppState {
+    /// Fresh state for `config`, ready to serve, with readiness checks
+    /// registered for the configured subsystems
+    pub fn new(config: impl Into<AppConfig>) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// [`new`](Self::new), with every timestamp, uptime and expiry read
+    /// from `clock`
+    pub fn with_clock(config: impl Into<AppConfig>, clock: Arc<dyn Clock>) -> Self {
+        let config = config.into();
+        #[cfg(feature = "metrics")]
+        let metrics = Metrics::default();
+
+        let http = HttpClient::new(config.http_client.clone());
+        #[cfg(feature = "metrics")]
+        let http = http.with_metrics(metrics.clone());
+
+        let mut health = HealthRegistry::default();
+        if let Some(tunnel_health) = config.tunnel_health.clone() {
+            health.register(TunnelHealthCheck::new(tunnel_health, &http));
+        }
+        let tasks = TaskSupervisor::default();
+        health.register(TaskHealthCheck::new(&tasks));
+        let concurrency = ConcurrencyLimiter::new(config.concurrency);
+        #[cfg(feature = "metrics")]
+        let concurrency = concurrency.with_metrics(&metrics);
+        health.register(BackpressureCheck::new(&concurrency));
+        let audit = AuditLog::default();
+        #[cfg(feature = "metrics")]
+        let audit = audit.with_metrics(&metrics);
+        let request_events = RequestEvents::new(config.request_events);
+        #[cfg(feature = "metrics")]
+        let request_events = request_events.with_metrics(&metrics);
+        let notifier = Notifier::default();
+        #[cfg(feature = "metrics")]
+        let notifier = notifier.with_metrics(&metrics);
+        let api_key_limiter = KeyRateLimiter::new(clock.clone());
+        #[cfg(feature = "metrics")]
+        let api_key_limiter = api_key_limiter.with_metrics(&metrics);
+        let unknown_paths = UnknownPaths::new(clock.clone());
+        #[cfg(feature = "metrics")]
+        let unknown_paths = unknown_paths.with_metrics(&metrics);
+        let response_cache = ResponseCache::new(clock.clone());
+        #[cfg(feature = "metrics")]
+        let response_cache = response_cache.with_metrics(&metrics);
+        let idempotency = IdempotencyStore::new(clock.clone());
+        #[cfg(feature = "metrics")]
+        let idempotency = idempotency.with_metrics(&metrics);
+
+        let access = config.access.clone().map(|access| AccessVerifier::new(access, &http));
+
+        Self {
+            tunnel: config.tunnel.as_ref().map(|_| TunnelStatus::default()),
+            maintenance: MaintenanceState::new(config.maintenance.enabled),
+            drain: DrainState::default(),
+            notifier,
+            access,
+            features: FeatureToggles::new(&config),
+            config_hash: Arc::new(RwLock::new(crate::build_info::config_hash(&config.security).into())),
+            config_history: Arc::new(RwLock::new(ConfigHistory::new(&config.security))),
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            reloaded_at: Arc::default(),
+            pipeline: Arc::default(),
+            started_at: clock.monotonic(),
+            #[cfg(feature = "metrics")]
+            requests: RequestCounters::register(&metrics),
+            #[cfg(feature = "metrics")]
+            colos: crate::cloudflare::ray::ColoCounter::new(&metrics, crate::cloudflare::ray::MAX_COLO_LABELS),
+            #[cfg(feature = "metrics")]
+            reports: crate::reports::ReportCounters::register(&metrics),
+            #[cfg(feature = "metrics")]
+            metrics,
+            health,
+            http,
+            ready: Arc::new(watch::Sender::new(true)),
+            event_streams: StreamCount::default(),
+            api_key_limiter,
+            route_stats: RouteStatsRegistry::default(),
+            request_events,
+            unknown_paths,
+            slo: AvailabilityTracker::new(clock.clone()),
+            capture: CaptureState::new(clock.clone()),
+            response_cache,
+            idempotency,
+            audit,
+            log_level: LogLevel::new(clock.clone()),
+            tasks,
+            concurrency,
+            #[cfg(feature = "fault-injection")]
+            faults: crate::faults::FaultState::new(clock.clone(), Arc::new(RandomSampler::default())),
+            header_sampler: Arc::new(RandomSampler::default()),
+            clock,
+        }
+    }
+
+    /// Snapshot of the current configuration
+    pub fn config(&self) -> Arc<AppConfig> {
+        self.config.load_full()
+    }
+
+    /// Swap in a new configuration for subsequent requests, without
+    /// validating it; recorded in the history as `replace`
+    pub fn replace_config(&self, mut config: AppConfig) {
+        // Tenant profiles follow the new defaults where they still fit
+        if let Ok(tenants) = config.tenants.rebase(&config.security) {
+            config.tenants = tenants;
+        }
+        self.store_config(config, "replace");
+    }
+
+    /// Check the security settings of `config`, including every tenant
+    /// profile merged over them, then swap it in and record it in the
+    /// history as applied by `source`. Returns the new version.
+    pub fn apply_config(&self, mut config: AppConfig, source: &str) -> crate::Result<u64> {
+        config.security.validate()?;
+        config.tenants = config.tenants.rebase(&config.security)?;
+        Ok(self.store_config(config, source))
+    }
+
+    fn store_config(&self, config: AppConfig, source: &str) -> u64 {
+        // Holding the history lock keeps versions in the order they were stored
+        let mut history = self.config_history.write().unwrap_or_else(|e| e.into_inner());
+        let version = history.record(&config.security, source);
+        if let Ok(mut hash) = self.config_hash.write() {
+            *hash = history.current().config_hash.as_str().into();
+        }
+        self.config.store(Arc::new(config));
+        if let Ok(mut reloaded_at) = self.reloaded_at.write() {
+            *reloaded_at = Some(self.clock.now_utc());
+        }
+        version
+    }
+
+    /// Snapshot of the recently applied configurations
+    pub fn config_history(&self) -> ConfigHistory {
+        self.config_history.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Version number of the current configuration
+    pub fn config_version(&self) -> u64 {
+        self.config_history.read().unwrap_or_else(|e| e.into_inner()).current().version
+    }
+
+    /// Fingerprint of the current security configuration
+    pub fn config_hash(&self) -> Arc<str> {
+        self.config_hash.read().map(|hash| hash.clone()).unwrap_or_else(|_| Arc::from(""))
+    }
+
+    /// When the configuration was last replaced; `None` if it never was
+    pub fn reloaded_at(&self) -> Option<DateTime<Utc>> {
+        self.reloaded_at.read().ok().and_then(|at| *at)
+    }
+
+    /// Whether `/readyz` may report ready (checks permitting)
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// Mark the service as (not) accepting new traffic
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.send_replace(ready);
+    }
+
+    /// Resolve once the service is marked not ready, i.e. starts draining.
+    /// Connections that outlive a request (WebSockets, event streams) use it to close
+    /// cleanly, since graceful shutdown does not wait for them.
+    pub async fn draining(&self) {
+        let _ = self.ready.subscribe().wait_for(|ready| !*ready).await;
+    }
+
+    /// Middleware stages of the router serving this state, outermost first;
+    /// empty until one is built
+    pub fn pipeline(&self) -> Vec<&'static str> {
+        self.pipeline.read().map(|order| order.clone()).unwrap_or_default()
+    }
+
+    /// Record the stage order of the router just built
+    pub(crate) fn set_pipeline(&self, order: Vec<&'static str>) {
+        *self.pipeline.write().unwrap_or_else(|e| e.into_inner()) = order;
+    }
+
+    /// Time since the state was created
+    pub fn uptime(&self) -> Duration {
+        self.clock.monotonic().saturating_duration_since(self.started_at)
+    }
+
+    /// Requests handled by the main listener so far. Without the `metrics`
+    /// feature this sums `route_stats`, which `/admin/stats` can reset.
+    pub fn requests_served(&self) -> u64 {
+        #[cfg(feature = "metrics")]
+        return self.requests.total.get();
+        #[cfg(not(feature = "metrics"))]
+        return self.route_stats.snapshot().iter().map(|route| route.requests).sum();
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new(AppConfig::default())
+    }
+}
+
+impl FromRef<AppState> for Arc<AppConfig> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl FromRef<AppState> for Metrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<dyn Clock> {
+    fn from_ref(state: &AppState) -> Self {
+        state.clock.clone()
+    }
+}
+
+impl FromRef<AppState> for HealthRegistry {
+    fn from_ref(state: &AppState) -> Self {
+        state.health.clone()
+    }
+}
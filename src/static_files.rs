@@ -0,0 +1,111 @@
+/*!
+ * Static file serving
+ *
+ * When `STATIC_DIR` is set, files below it are served under `STATIC_PREFIX`
+ * (default `/static`) with `tower_http::services::ServeDir`: Content-Type is
+ * guessed from the extension, `..` segments (including percent-encoded
+ * ones) never leave the directory, and `foo.css.br` / `foo.css.gz` are sent
+ * instead of `foo.css` when the client accepts that encoding. `STATIC_INDEX`
+ * optionally names a file in the directory to serve at `/` in place of the
+ * built-in homepage.
+ *
+ * The services are mounted inside the main router, so security headers and
+ * the cache policy apply to static responses like any other.
+ */
+use axum::Router;
+use std::path::PathBuf;
+use tower_http::services::{ServeDir, ServeFile};
+
+/// Where static files live and where they are mounted
+#[derive(Debug, Clone)]
+pub struct StaticConfig {
+    /// Directory served
+    pub dir: PathBuf,
+
+    /// URL path prefix the directory is mounted under, e.g. `/static`
+    pub prefix: String,
+
+    /// File within `dir` served at `/`, replacing the built-in homepage
+    pub index: Option<String>,
+}
+
+impl StaticConfig {
+    /// Serve `dir` under `/static` without an index override
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            prefix: "/static".to_string(),
+            index: None,
+        }
+    }
+
+    /// Load from `STATIC_DIR`, `STATIC_PREFIX` and `STATIC_INDEX`.
+    /// Returns `None` when no directory is configured.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let Ok(dir) = std::env::var("STATIC_DIR") else {
+            return Ok(None);
+        };
+
+        let mut config = Self::new(dir);
+        if let Ok(prefix) = std::env::var("STATIC_PREFIX") {
+            config.prefix = prefix;
+        }
+        if let Ok(index) = std::env::var("STATIC_INDEX") {
+            config.index = Some(index).filter(|index| !index.is_empty());
+        }
+
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    /// Require an existing directory, a `/`-prefixed mount point without a
+    /// trailing slash (other than `/` itself) and an index file that exists
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(crate::ServerError::ConfigError(msg));
+
+        if !self.dir.is_dir() {
+            return invalid(format!("STATIC_DIR {} is not a directory", self.dir.display()));
+        }
+        if !self.prefix.starts_with('/') || (self.prefix.len() > 1 && self.prefix.ends_with('/')) {
+            return invalid(format!(
+                "STATIC_PREFIX {:?} must start with / and not end with /",
+                self.prefix
+            ));
+        }
+        if let Some(index) = &self.index {
+            if index.contains("..") || !self.dir.join(index).is_file() {
+                return invalid(format!(
+                    "STATIC_INDEX {:?} is not a file in {}",
+                    index,
+                    self.dir.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Mount the static directory (and index file, if any) on `router`
+pub fn mount<S: Clone + Send + Sync + 'static>(router: Router<S>, config: &StaticConfig) -> Router<S> {
+    let files = ServeDir::new(&config.dir)
+        .precompressed_br()
+        .precompressed_gzip();
+
+    let router = match config.index.as_deref() {
+        Some(index) => router.route_service(
+            "/",
+            ServeFile::new(config.dir.join(index))
+                .precompressed_br()
+                .precompressed_gzip(),
+        ),
+        None => router,
+    };
+
+    // axum does not nest at the root; a root mount serves unmatched paths
+    if config.prefix == "/" {
+        router.fallback_service(files)
+    } else {
+        router.nest_service(&config.prefix, files)
+    }
+}
@@ -0,0 +1,202 @@
+/*!
+ * The `/status` page
+ *
+ * A page for people rather than probes: overall health, every readiness
+ * check with its latency, uptime, version, the recent server error rate,
+ * the security preset and whether maintenance is on. It reloads itself
+ * every [`REFRESH_SECS`] seconds with a `<meta http-equiv="refresh">` tag,
+ * which needs no script and so works under every CSP preset.
+ *
+ * [`StatusSnapshot`] gathers the data once, from the same sources as
+ * `/readyz` (which renders it as JSON) and `/admin/stats`. The page is
+ * rendered from `templates/status.html` with TinyTemplate, so check details
+ * and maintenance messages are HTML-escaped. Its links are relative, so
+ * they keep working when a proxy serves the service under a path prefix.
+ */
+use crate::drain::DrainStatus;
+use crate::error::ApiError;
+use crate::health::CheckResult;
+use crate::maintenance::MaintenanceStatus;
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use tinytemplate::TinyTemplate;
+
+const TEMPLATE: &str = include_str!("../templates/status.html");
+
+/// Seconds between reloads of the page
+pub const REFRESH_SECS: u64 = 30;
+
+/// SLO window the error rate is taken from
+const ERROR_RATE_WINDOW: &str = "5m";
+
+/// Health, traffic and configuration at one moment
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusSnapshot {
+    /// Not draining and every check passing
+    pub ready: bool,
+
+    /// Marked not ready, i.e. shutting down or drained
+    pub draining: bool,
+
+    /// Drain requested through the admin API
+    pub drain: DrainStatus,
+    pub checks: Vec<CheckResult>,
+    pub maintenance: MaintenanceStatus,
+    pub version: &'static str,
+    pub uptime_seconds: u64,
+
+    /// Requests handled so far
+    pub requests: u64,
+
+    /// Share of server errors over the last five minutes, as a percentage;
+    /// `None` without traffic
+    pub error_rate: Option<f64>,
+    pub security_preset: &'static str,
+}
+
+impl StatusSnapshot {
+    /// Run the readiness checks and read the counters
+    pub async fn collect(state: &AppState) -> Self {
+        let config = state.config();
+        let checks = state.health.run().await;
+        let draining = !state.is_ready();
+        let error_rate = state
+            .slo
+            .report(&config.slo)
+            .windows
+            .into_iter()
+            .find(|window| window.window == ERROR_RATE_WINDOW)
+            .and_then(|window| window.availability)
+            .map(|availability| 100.0 - availability);
+
+        Self {
+            ready: !draining && checks.iter().all(|check| check.healthy),
+            draining,
+            drain: state.drain.status(),
+            checks,
+            maintenance: state.maintenance.status(),
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_seconds: state.uptime().as_secs(),
+            requests: state.requests_served(),
+            error_rate,
+            security_preset: config.security.preset.name(),
+        }
+    }
+}
+
+/// Values available to the template, formatted for display
+#[derive(Debug, Serialize)]
+struct Context<'a> {
+    refresh_secs: u64,
+    overall: &'static str,
+    version: &'static str,
+    uptime: String,
+    requests: u64,
+    error_rate: String,
+    security_preset: &'static str,
+    maintenance: &'static str,
+    maintenance_message: Option<&'a str>,
+    checks: Vec<CheckContext<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckContext<'a> {
+    name: &'a str,
+    state: &'static str,
+    detail: Option<&'a str>,
+    latency: String,
+}
+
+/// `90061` as `1d 1h 1m 1s`
+fn format_uptime(seconds: u64) -> String {
+    let parts = [(seconds / 86_400, "d"), (seconds / 3_600 % 24, "h"), (seconds / 60 % 60, "m")];
+    let mut uptime: String = parts
+        .iter()
+        .skip_while(|(value, _)| *value == 0)
+        .map(|(value, unit)| format!("{}{} ", value, unit))
+        .collect();
+    uptime.push_str(&format!("{}s", seconds % 60));
+    uptime
+}
+
+/// `snapshot` as the HTML page
+pub fn render(snapshot: &StatusSnapshot) -> Result<String, tinytemplate::error::Error> {
+    let overall = match (snapshot.ready, snapshot.draining) {
+        (_, true) => "Draining",
+        (true, _) if snapshot.checks.iter().any(|check| check.warning) => "Degraded",
+        (true, _) => "Operational",
+        (false, _) => "Unavailable",
+    };
+    let context = Context {
+        refresh_secs: REFRESH_SECS,
+        overall,
+        version: snapshot.version,
+        uptime: format_uptime(snapshot.uptime_seconds),
+        requests: snapshot.requests,
+        error_rate: snapshot
+            .error_rate
+            .map(|rate| format!("{:.2}%", rate))
+            .unwrap_or_else(|| "no recent traffic".to_string()),
+        security_preset: snapshot.security_preset,
+        maintenance: if snapshot.maintenance.enabled { "on" } else { "off" },
+        maintenance_message: snapshot.maintenance.message.as_deref(),
+        checks: snapshot
+            .checks
+            .iter()
+            .map(|check| CheckContext {
+                name: &check.name,
+                state: match (check.healthy, check.warning) {
+                    (false, _) => "failing",
+                    (true, true) => "warning",
+                    (true, false) => "passing",
+                },
+                detail: check.detail.as_deref(),
+                latency: check.latency_ms.map(|ms| format!("{:.1} ms", ms)).unwrap_or_default(),
+            })
+            .collect(),
+    };
+
+    let mut templates = TinyTemplate::new();
+    templates.add_template("status", TEMPLATE)?;
+    templates.render("status", &context)
+}
+
+/// Human-readable status page, reloading itself
+#[utoipa::path(
+    get,
+    path = "/status",
+    responses((status = OK, description = "Health, checks and traffic", body = String, content_type = "text/html")),
+)]
+async fn status_page(State(state): State<AppState>) -> Result<Response, ApiError> {
+    let snapshot = StatusSnapshot::collect(&state).await;
+    let page = render(&snapshot).map_err(|e| {
+        tracing::error!("Failed to render status page: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "render_failed", "Failed to render page")
+    })?;
+    Ok(([(header::CACHE_CONTROL, "no-store")], Html(page)).into_response())
+}
+
+/// `GET /status`
+pub fn routes() -> Router<AppState> {
+    Router::new().route("/status", get(status_page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(0), "0s");
+        assert_eq!(format_uptime(61), "1m 1s");
+        assert_eq!(format_uptime(90_061), "1d 1h 1m 1s");
+        assert_eq!(format_uptime(86_400), "1d 0h 0m 0s");
+    }
+}
@@ -0,0 +1,490 @@
+/*!
+ * Supervised background tasks
+ *
+ * Periodic jobs register with the [`TaskSupervisor`] in `AppState::tasks`
+ * instead of each running its own loop. A job is an async closure run on a
+ * [`Schedule`], either a fixed delay between runs or a cron expression
+ * evaluated in UTC. The supervisor:
+ *
+ * - records each task's last run, duration and error, reported at
+ *   `GET /admin/tasks` on the admin listener
+ * - catches panics, restarting the task after an exponential backoff
+ *   (1s doubling up to 5 minutes by default) instead of its schedule
+ * - reports a `tasks` check to `/readyz` that fails once a task has failed
+ *   `FAILING_AFTER` runs in a row
+ * - stops every task when the server shuts down, waiting for runs in
+ *   progress up to a deadline and abandoning the rest
+ *
+ * A job returning `Err` is logged and retried on its normal schedule.
+ */
+use crate::health::{CheckResult, HealthCheck};
+use axum::async_trait;
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use futures_util::FutureExt;
+use serde::Serialize;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+/// Name under which the check is reported
+pub const CHECK_NAME: &str = "tasks";
+
+/// Consecutive failed runs after which a task makes `/readyz` fail
+pub const FAILING_AFTER: u32 = 3;
+
+/// How long `serve` waits for tasks to stop during shutdown
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+/// When a task runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Immediately, then this long after each run finishes
+    Every(Duration),
+
+    /// At the minutes a cron expression matches
+    Cron(CronSchedule),
+}
+
+impl Schedule {
+    /// Run immediately, then `period` after each run
+    pub fn every(period: Duration) -> Self {
+        Self::Every(period)
+    }
+
+    /// Run when the five-field cron `expression` matches, e.g. `*/15 * * * *`
+    pub fn cron(expression: &str) -> crate::Result<Self> {
+        CronSchedule::parse(expression).map(Self::Cron)
+    }
+
+    /// Wait before the first run
+    fn first_delay(&self, now: DateTime<Utc>) -> Option<Duration> {
+        match self {
+            Self::Every(_) => Some(Duration::ZERO),
+            Self::Cron(_) => self.next_delay(now),
+        }
+    }
+
+    /// Wait before the run after one finishing at `now`; `None` if the
+    /// schedule never matches again
+    fn next_delay(&self, now: DateTime<Utc>) -> Option<Duration> {
+        match self {
+            Self::Every(period) => Some(*period),
+            Self::Cron(cron) => cron.next_after(now).and_then(|next| (next - now).to_std().ok()),
+        }
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Every(period) => write!(f, "every {:?}", period),
+            Self::Cron(cron) => write!(f, "cron {}", cron.expression),
+        }
+    }
+}
+
+/// A parsed `minute hour day-of-month month day-of-week` expression
+///
+/// Each field takes `*`, a value, a range `a-b`, a step `*/n` or `a-b/n`,
+/// or a comma-separated list of those. Days of the week run from 0
+/// (Sunday) to 6, with 7 also meaning Sunday. As in cron, when both day
+/// fields are restricted a day matching either one is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    /// Parse `expression`, rejecting ones that can never match
+    pub fn parse(expression: &str) -> crate::Result<Self> {
+        let invalid = |reason: String| {
+            crate::ServerError::ConfigError(format!("Invalid cron expression {:?}: {}", expression, reason))
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(invalid(format!("expected 5 fields, found {}", fields.len())));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7).map_err(invalid)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        let schedule = Self {
+            expression: fields.join(" "),
+            minutes: parse_field(minute, 0, 59).map_err(invalid)?,
+            hours: parse_field(hour, 0, 23).map_err(invalid)?,
+            days: parse_field(day, 1, 31).map_err(invalid)?,
+            months: parse_field(month, 1, 12).map_err(invalid)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        };
+        if schedule.next_after(Utc::now()).is_none() {
+            return Err(invalid("it never matches".to_string()));
+        }
+        Ok(schedule)
+    }
+
+    fn day_matches(&self, at: DateTime<Utc>) -> bool {
+        let day = self.days & (1 << at.day()) != 0;
+        let weekday = self.weekdays & (1 << at.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching minute strictly after `after`, looking up to five
+    /// years ahead
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let limit = start + chrono::Duration::days(5 * 366);
+        let mut at = start;
+        while at < limit {
+            if self.months & (1 << at.month()) == 0 {
+                let (year, month) = if at.month() == 12 { (at.year() + 1, 1) } else { (at.year(), at.month() + 1) };
+                at = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.day_matches(at) {
+                at = at.date_naive().succ_opt()?.and_hms_opt(0, 0, 0)?.and_utc();
+            } else if self.hours & (1 << at.hour()) == 0 {
+                at = at.with_minute(0)? + chrono::Duration::hours(1);
+            } else if self.minutes & (1 << at.minute()) == 0 {
+                at += chrono::Duration::minutes(1);
+            } else {
+                return Some(at);
+            }
+        }
+        None
+    }
+}
+
+/// Bitmask of the values one cron field matches
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let value = |s: &str| -> Result<u32, String> {
+        let n = s.parse().map_err(|_| format!("{:?} is not a number", s))?;
+        if (min..=max).contains(&n) {
+            Ok(n)
+        } else {
+            Err(format!("{} is outside {}-{}", n, min, max))
+        }
+    };
+
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| format!("{:?} is not a step", step))?;
+                if step == 0 {
+                    return Err("a step must be at least 1".to_string());
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((first, last)) => (value(first)?, value(last)?),
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if first > last {
+            return Err(format!("range {:?} is backwards", range));
+        }
+        for n in (first..=last).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Ok(mask)
+}
+
+/// What a task has done so far, as reported at `/admin/tasks`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub schedule: String,
+
+    /// A run is in progress
+    pub running: bool,
+
+    pub runs: u64,
+    pub failures: u64,
+    pub panics: u64,
+
+    /// Failed runs since the last successful one
+    pub consecutive_failures: u32,
+
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub last_duration_ms: Option<u64>,
+    pub last_success_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+
+    /// When the task runs next; `None` once it has stopped
+    pub next_run_at: Option<DateTime<Utc>>,
+}
+
+type SharedStatus = Arc<Mutex<TaskStatus>>;
+
+fn lock(status: &SharedStatus) -> std::sync::MutexGuard<'_, TaskStatus> {
+    status.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Runs registered jobs on their schedules until shut down
+#[derive(Clone)]
+pub struct TaskSupervisor {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    statuses: Mutex<Vec<SharedStatus>>,
+    handles: Mutex<Vec<(SharedStatus, JoinHandle<()>)>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl std::fmt::Debug for TaskSupervisor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.statuses().into_iter().map(|status| status.name))
+            .finish()
+    }
+}
+
+impl Default for TaskSupervisor {
+    fn default() -> Self {
+        Self::with_backoff(Duration::from_secs(1), Duration::from_secs(5 * 60))
+    }
+}
+
+impl TaskSupervisor {
+    /// Supervisor restarting panicked tasks after `initial`, doubling for
+    /// each further panic in a row up to `max`
+    pub fn with_backoff(initial: Duration, max: Duration) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                initial_backoff: initial,
+                max_backoff: max,
+                statuses: Mutex::default(),
+                handles: Mutex::default(),
+                shutdown: watch::Sender::new(false),
+            }),
+        }
+    }
+
+    /// Run `job` on `schedule` until shutdown. Must be called within a Tokio
+    /// runtime; after [`shutdown`](Self::shutdown) it is ignored.
+    pub fn spawn<F, Fut, E>(&self, name: impl Into<String>, schedule: Schedule, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: std::fmt::Display + 'static,
+    {
+        let name = name.into();
+        if *self.inner.shutdown.borrow() {
+            warn!("Not starting task {} after shutdown", name);
+            return;
+        }
+
+        let status = Arc::new(Mutex::new(TaskStatus {
+            name,
+            schedule: schedule.to_string(),
+            running: false,
+            runs: 0,
+            failures: 0,
+            panics: 0,
+            consecutive_failures: 0,
+            last_run_at: None,
+            last_duration_ms: None,
+            last_success_at: None,
+            last_error: None,
+            next_run_at: None,
+        }));
+        let handle = tokio::spawn(supervise(
+            job,
+            schedule,
+            status.clone(),
+            (self.inner.initial_backoff, self.inner.max_backoff),
+            self.inner.shutdown.subscribe(),
+        ));
+        self.inner.statuses.lock().unwrap_or_else(|e| e.into_inner()).push(status.clone());
+        self.inner.handles.lock().unwrap_or_else(|e| e.into_inner()).push((status, handle));
+    }
+
+    /// Status of every task, in registration order
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        let statuses = self.inner.statuses.lock().unwrap_or_else(|e| e.into_inner());
+        statuses.iter().map(|status| lock(status).clone()).collect()
+    }
+
+    /// Stop every task, letting runs in progress finish within `deadline`.
+    /// Tasks still running then are aborted; their names are returned.
+    pub async fn shutdown(&self, deadline: Duration) -> Vec<String> {
+        self.inner.shutdown.send_replace(true);
+        let handles = std::mem::take(&mut *self.inner.handles.lock().unwrap_or_else(|e| e.into_inner()));
+
+        let deadline = tokio::time::Instant::now() + deadline;
+        let mut abandoned = Vec::new();
+        for (status, mut handle) in handles {
+            if tokio::time::timeout_at(deadline, &mut handle).await.is_err() {
+                handle.abort();
+                let mut status = lock(&status);
+                status.running = false;
+                status.next_run_at = None;
+                abandoned.push(status.name.clone());
+            }
+        }
+        abandoned
+    }
+}
+
+/// Run one task until `shutdown` flips
+async fn supervise<F, Fut, E>(
+    job: F,
+    schedule: Schedule,
+    status: SharedStatus,
+    (initial_backoff, max_backoff): (Duration, Duration),
+    mut shutdown: watch::Receiver<bool>,
+) where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    let name = lock(&status).name.clone();
+    let mut delay = schedule.first_delay(Utc::now());
+    while let Some(wait) = delay {
+        lock(&status).next_run_at = chrono::Duration::from_std(wait).ok().map(|wait| Utc::now() + wait);
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        }
+
+        {
+            let mut status = lock(&status);
+            status.running = true;
+            status.last_run_at = Some(Utc::now());
+        }
+        let started = Instant::now();
+        let outcome = AssertUnwindSafe(job()).catch_unwind().await;
+
+        let mut status = lock(&status);
+        status.running = false;
+        status.runs += 1;
+        status.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+        delay = match outcome {
+            Ok(Ok(())) => {
+                status.consecutive_failures = 0;
+                status.last_success_at = status.last_run_at;
+                schedule.next_delay(Utc::now())
+            }
+            Ok(Err(e)) => {
+                warn!("Task {} failed: {}", name, e);
+                status.failures += 1;
+                status.consecutive_failures += 1;
+                status.last_error = Some(e.to_string());
+                schedule.next_delay(Utc::now())
+            }
+            Err(panic) => {
+                status.failures += 1;
+                status.panics += 1;
+                status.consecutive_failures += 1;
+                status.last_error = Some(format!("panicked: {}", panic_message(&*panic)));
+                let exponent = status.consecutive_failures.saturating_sub(1).min(31);
+                let backoff = initial_backoff.saturating_mul(1 << exponent).min(max_backoff);
+                error!("Task {} panicked ({}); restarting in {:?}", name, panic_message(&*panic), backoff);
+                Some(backoff)
+            }
+        };
+    }
+    lock(&status).next_run_at = None;
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+/// Readiness check failing while any task keeps failing
+pub struct TaskHealthCheck {
+    tasks: TaskSupervisor,
+}
+
+impl TaskHealthCheck {
+    pub fn new(tasks: &TaskSupervisor) -> Self {
+        Self { tasks: tasks.clone() }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for TaskHealthCheck {
+    fn name(&self) -> &str {
+        CHECK_NAME
+    }
+
+    async fn check(&self) -> CheckResult {
+        let statuses = self.tasks.statuses();
+        match statuses.iter().find(|status| status.consecutive_failures >= FAILING_AFTER) {
+            Some(failing) => CheckResult::unhealthy(
+                CHECK_NAME,
+                format!(
+                    "{} failed {} times in a row: {}",
+                    failing.name,
+                    failing.consecutive_failures,
+                    failing.last_error.as_deref().unwrap_or("unknown error")
+                ),
+            ),
+            None => CheckResult::healthy(CHECK_NAME, format!("{} tasks running", statuses.len())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let cron = CronSchedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(cron.next_after(at("2024-01-01T10:07:30Z")), Some(at("2024-01-01T10:15:00Z")));
+        assert_eq!(cron.next_after(at("2024-01-01T10:45:00Z")), Some(at("2024-01-01T11:00:00Z")));
+
+        let cron = CronSchedule::parse("30 2 * * 1-5").unwrap();
+        // Saturday 2024-01-06 rolls over to Monday
+        assert_eq!(cron.next_after(at("2024-01-05T03:00:00Z")), Some(at("2024-01-08T02:30:00Z")));
+
+        let cron = CronSchedule::parse("0 0 29 2 *").unwrap();
+        assert_eq!(cron.next_after(at("2024-03-01T00:00:00Z")), Some(at("2028-02-29T00:00:00Z")));
+
+        // Either day field matches when both are restricted
+        let cron = CronSchedule::parse("0 12 1 * 0").unwrap();
+        assert_eq!(cron.next_after(at("2024-01-02T00:00:00Z")), Some(at("2024-01-07T12:00:00Z")));
+    }
+
+    #[test]
+    fn test_cron_rejects_bad_expressions() {
+        for expression in ["* * * *", "60 * * * *", "*/0 * * * *", "5-1 * * * *", "0 0 30 2 *", "x * * * *"] {
+            assert!(CronSchedule::parse(expression).is_err(), "{}", expression);
+        }
+        assert_eq!(Schedule::cron("0  3 * * 7").unwrap().to_string(), "cron 0 3 * * 7");
+    }
+}
@@ -0,0 +1,325 @@
+/*!
+ * Security header profiles per hostname
+ *
+ * One tunnel can send several hostnames to this service, and each site may
+ * need its own headers: a marketing page embedding third-party widgets
+ * next to a strict API. `TENANTS` is a JSON array of named profiles, each
+ * listing the hostnames it serves and a partial `SecurityConfig` overlay:
+ *
+ * ```json
+ * [{"name": "marketing", "hosts": ["www.example.com", "*.pages.example.com"],
+ *   "security": {"csp": {"script_src": "'self' https://widgets.example"}}}]
+ * ```
+ *
+ * A host is an exact name or `*.` followed by a domain, which matches every
+ * subdomain of it at any depth (but not the domain itself). An exact name
+ * wins over a wildcard. Two wildcards that can match the same host, such as
+ * `*.example.com` and `*.pages.example.com`, are rejected, as are hosts
+ * listed twice, so every host resolves to at most one tenant.
+ *
+ * Overlays are merged over the default security settings with
+ * [`crate::config::merge_patch`], and merged again whenever those settings
+ * are replaced at runtime. [`assign`] resolves the tenant from the `Host`
+ * header once per request, records it in the `tenant` span field and the
+ * request extensions ([`TenantName`]), and the security header middleware
+ * sends that tenant's headers. Hosts matching no tenant get the defaults.
+ * The `Server` header is fixed when the router is built, so a tenant's
+ * `server_header` is ignored.
+ */
+use crate::config::{AppConfig, SecurityConfig};
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Hostname a tenant serves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    /// This name only, lowercase
+    Exact(String),
+
+    /// Any subdomain of this domain, lowercase and without the `*.`
+    Wildcard(String),
+}
+
+impl HostPattern {
+    /// Whether `host` (lowercase, without a port) is covered
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(name) => host == name,
+            HostPattern::Wildcard(domain) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        }
+    }
+
+    /// Whether some host is covered by both wildcards
+    fn overlaps(&self, other: &Self) -> bool {
+        let (HostPattern::Wildcard(a), HostPattern::Wildcard(b)) = (self, other) else {
+            return false;
+        };
+        a == b || Self::Wildcard(a.clone()).matches(b) || Self::Wildcard(b.clone()).matches(a)
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        let value = value.trim().trim_end_matches('.').to_ascii_lowercase();
+        let (wildcard, name) = match value.strip_prefix("*.") {
+            Some(domain) => (true, domain),
+            None => (false, value.as_str()),
+        };
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        };
+        if !name.split('.').all(valid_label) {
+            return Err(format!("{:?} is not a hostname or *.domain", value));
+        }
+        Ok(if wildcard { Self::Wildcard(name.to_string()) } else { Self::Exact(name.to_string()) })
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostPattern::Exact(name) => f.write_str(name),
+            HostPattern::Wildcard(domain) => write!(f, "*.{}", domain),
+        }
+    }
+}
+
+impl Serialize for HostPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HostPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A tenant as configured in `TENANTS`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantSpec {
+    pub name: String,
+    pub hosts: Vec<HostPattern>,
+
+    /// Partial security settings merged over the defaults
+    #[serde(default = "empty_overlay")]
+    pub security: Value,
+}
+
+fn empty_overlay() -> Value {
+    Value::Object(Default::default())
+}
+
+/// A tenant with its overlay merged over the default security settings
+#[derive(Debug, Clone, Serialize)]
+pub struct Tenant {
+    pub name: String,
+    pub hosts: Vec<HostPattern>,
+
+    /// The overlay as configured
+    pub overlay: Value,
+
+    /// The headers this tenant's requests get
+    pub security: SecurityConfig,
+}
+
+/// Every configured tenant; empty unless `TENANTS` is set
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Tenants {
+    tenants: Vec<Tenant>,
+}
+
+impl Tenants {
+    /// Check `specs` and merge each overlay over `base`
+    pub fn new(specs: Vec<TenantSpec>, base: &SecurityConfig) -> crate::Result<Self> {
+        let invalid = |msg: String| crate::ServerError::ConfigError(msg);
+        let mut tenants: Vec<Tenant> = Vec::with_capacity(specs.len());
+
+        for spec in specs {
+            if spec.name.trim().is_empty() {
+                return Err(invalid("Tenant names must not be empty".to_string()));
+            }
+            if tenants.iter().any(|tenant| tenant.name == spec.name) {
+                return Err(invalid(format!("Tenant {:?} is listed twice", spec.name)));
+            }
+            if spec.hosts.is_empty() {
+                return Err(invalid(format!("Tenant {:?} has no hosts", spec.name)));
+            }
+
+            for (i, host) in spec.hosts.iter().enumerate() {
+                let earlier = tenants.iter().flat_map(|tenant| tenant.hosts.iter().map(move |h| (&tenant.name, h)));
+                let same = spec.hosts[..i].iter().map(|h| (&spec.name, h));
+                if let Some((owner, other)) = earlier.chain(same).find(|(_, other)| *other == host || host.overlaps(other)) {
+                    return Err(invalid(format!(
+                        "Tenant {:?} host {} overlaps {} of tenant {:?}",
+                        spec.name, host, other, owner
+                    )));
+                }
+            }
+
+            let security: SecurityConfig = crate::config::merge_patch(base, &spec.security).map_err(|errors| {
+                let fields: Vec<String> = errors
+                    .iter()
+                    .map(|error| format!("{}: {}", error.path, error.message))
+                    .collect();
+                invalid(format!("Invalid security overlay for tenant {:?}: {}", spec.name, fields.join("; ")))
+            })?;
+            security.validate().map_err(|e| invalid(format!("Tenant {:?}: {}", spec.name, e)))?;
+
+            tenants.push(Tenant { name: spec.name, hosts: spec.hosts, overlay: spec.security, security });
+        }
+        Ok(Self { tenants })
+    }
+
+    /// Load `TENANTS`, merging over `base`
+    pub fn from_env(base: &SecurityConfig) -> crate::Result<Self> {
+        let Ok(value) = std::env::var("TENANTS") else {
+            return Ok(Self::default());
+        };
+        let specs: Vec<TenantSpec> = serde_json::from_str(&value)
+            .map_err(|e| crate::ServerError::ConfigError(format!("Invalid TENANTS: {}", e)))?;
+        Self::new(specs, base)
+    }
+
+    /// The same tenants with their overlays merged over `base` instead
+    pub fn rebase(&self, base: &SecurityConfig) -> crate::Result<Self> {
+        let specs = self
+            .tenants
+            .iter()
+            .map(|tenant| TenantSpec {
+                name: tenant.name.clone(),
+                hosts: tenant.hosts.clone(),
+                security: tenant.overlay.clone(),
+            })
+            .collect();
+        Self::new(specs, base)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Tenant> {
+        self.tenants.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| tenant.name == name)
+    }
+
+    /// Tenant serving `host`, a `Host` header value that may carry a port
+    pub fn resolve(&self, host: &str) -> Option<&Tenant> {
+        let host = strip_port(host.trim()).trim_end_matches('.').to_ascii_lowercase();
+        let exact = self.tenants.iter().find(|tenant| {
+            tenant.hosts.iter().any(|pattern| matches!(pattern, HostPattern::Exact(_)) && pattern.matches(&host))
+        });
+        exact.or_else(|| self.tenants.iter().find(|tenant| tenant.hosts.iter().any(|pattern| pattern.matches(&host))))
+    }
+
+    /// Security settings for a request resolved to `tenant`, or `default`
+    pub fn security_for<'a>(&'a self, tenant: Option<&TenantName>, default: &'a SecurityConfig) -> &'a SecurityConfig {
+        tenant
+            .and_then(|TenantName(name)| self.get(name))
+            .map_or(default, |tenant| &tenant.security)
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(bracketed) = host.strip_prefix('[') {
+        return bracketed.split_once(']').map_or(host, |(ip, _)| ip);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// The tenant a request's `Host` resolved to, in its extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantName(pub Arc<str>);
+
+/// Middleware resolving the tenant from the `Host` header
+pub async fn assign(State(config): State<Arc<AppConfig>>, mut request: Request, next: Next) -> Response {
+    if config.tenants.is_empty() {
+        return next.run(request).await;
+    }
+
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| request.uri().host());
+    if let Some(tenant) = host.and_then(|host| config.tenants.resolve(host)) {
+        tracing::Span::current().record("tenant", tenant.name.as_str());
+        let name = TenantName(Arc::from(tenant.name.as_str()));
+        request.extensions_mut().insert(name);
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(name: &str, hosts: &[&str]) -> TenantSpec {
+        TenantSpec {
+            name: name.to_string(),
+            hosts: hosts.iter().map(|host| host.parse().unwrap()).collect(),
+            security: empty_overlay(),
+        }
+    }
+
+    #[test]
+    fn test_host_patterns() {
+        let wildcard: HostPattern = "*.Example.com.".parse().unwrap();
+        assert_eq!(wildcard, HostPattern::Wildcard("example.com".to_string()));
+        assert!(wildcard.matches("www.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+
+        for bad in ["", "*", "*.", "exa mple.com", "www.*.com", "-a.com", "a..com"] {
+            assert!(bad.parse::<HostPattern>().is_err(), "{:?}", bad);
+        }
+        assert_eq!(strip_port("Example.com:8443"), "Example.com");
+        assert_eq!(strip_port("[::1]:8080"), "::1");
+    }
+
+    #[test]
+    fn test_overlapping_hosts_are_rejected() {
+        let base = SecurityConfig::default();
+        assert!(Tenants::new(vec![spec("a", &["*.example.com"]), spec("b", &["*.pages.example.com"])], &base).is_err());
+        assert!(Tenants::new(vec![spec("a", &["*.example.com", "*.example.com"])], &base).is_err());
+        assert!(Tenants::new(vec![spec("a", &["api.example.com"]), spec("b", &["api.example.com"])], &base).is_err());
+        assert!(Tenants::new(vec![spec("a", &["x.com"]), spec("a", &["y.com"])], &base).is_err());
+        assert!(Tenants::new(vec![spec("a", &[])], &base).is_err());
+
+        // An exact name may sit under another tenant's wildcard and wins
+        let tenants = Tenants::new(
+            vec![spec("sites", &["*.example.com", "*.example.org"]), spec("api", &["api.example.com"])],
+            &base,
+        )
+        .unwrap();
+        assert_eq!(tenants.resolve("API.example.com:443").map(|t| t.name.as_str()), Some("api"));
+        assert_eq!(tenants.resolve("www.example.org").map(|t| t.name.as_str()), Some("sites"));
+        assert!(tenants.resolve("example.com").is_none());
+    }
+}
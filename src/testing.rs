@@ -0,0 +1,260 @@
+/*!
+ * In-process test client
+ *
+ * `TestClient` drives a router with `tower::ServiceExt::oneshot`, so tests
+ * exercise the full middleware stack without opening a socket. It is
+ * compiled for this crate's own tests and, with the `test-utils` feature,
+ * for downstream crates testing routers they customized.
+ *
+ * ```
+ * # use cloudflare_tunnel_example::config::SecurityConfig;
+ * # use cloudflare_tunnel_example::headers::X_FRAME_OPTIONS;
+ * # use cloudflare_tunnel_example::testing::TestClient;
+ * # async fn run() {
+ * let client = TestClient::new(SecurityConfig::default());
+ * let response = client.get("/health").await;
+ * assert_eq!(response.status(), 200);
+ * assert_eq!(response.header(X_FRAME_OPTIONS), Some("DENY"));
+ * # }
+ * ```
+ */
+use crate::admin::{create_admin_app, AdminConfig};
+use crate::config::SecurityConfig;
+use crate::clock::Clock;
+use crate::header_sampling::Sampler;
+use crate::state::AppState;
+use crate::create_app;
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode},
+    Router,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tower::util::ServiceExt;
+
+/// Bearer token the admin API built by [`TestClient::admin`] accepts
+pub const ADMIN_TOKEN: &str = "admin-secret";
+
+/// Sends requests to a router in-process
+#[derive(Debug, Clone)]
+pub struct TestClient {
+    router: Router,
+    headers: HeaderMap,
+}
+
+impl TestClient {
+    /// Client for the application built with `security` and otherwise default config
+    pub fn new(security: SecurityConfig) -> Self {
+        Self::from_state(AppState::new(security))
+    }
+
+    /// Client for the application built around `state`
+    pub fn from_state(state: AppState) -> Self {
+        Self::from_router(create_app(state))
+    }
+
+    /// Client for any router, e.g. one with extra routes or layers
+    pub fn from_router(router: Router) -> Self {
+        Self {
+            router,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// Client for the admin API around `state`, which accepts
+    /// [`ADMIN_TOKEN`]; `token`, if any, is sent as a bearer token on every
+    /// request
+    pub fn admin(state: &AppState, token: Option<&str>) -> Self {
+        let config = AdminConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            token: ADMIN_TOKEN.to_string(),
+        };
+        let client = Self::from_router(create_admin_app(&config, state));
+        match token {
+            Some(token) => client.with_header("authorization", &format!("Bearer {}", token)),
+            None => client,
+        }
+    }
+
+    /// Send `name: value` on every subsequent request.
+    ///
+    /// Panics if the name or value is not a valid header, since that is a
+    /// bug in the test itself.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        let name = HeaderName::try_from(name).expect("Invalid test header name");
+        let value = HeaderValue::try_from(value).expect("Invalid test header value");
+        self.headers.append(name, value);
+        self
+    }
+
+    /// `GET uri`
+    pub async fn get(&self, uri: &str) -> TestResponse {
+        self.send(Method::GET, uri, HeaderMap::new(), Body::empty()).await
+    }
+
+    /// `POST uri` with `body` serialized as JSON
+    pub async fn post_json<T: Serialize>(&self, uri: &str, body: &T) -> TestResponse {
+        self.send_json(Method::POST, uri, body).await
+    }
+
+    /// `method uri` with `body` serialized as JSON
+    pub async fn send_json<T: Serialize>(&self, method: Method, uri: &str, body: &T) -> TestResponse {
+        let body = serde_json::to_vec(body).expect("Failed to serialize test body");
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        self.send(method, uri, headers, Body::from(body)).await
+    }
+
+    /// Send an arbitrary request; the client's default headers are added
+    /// unless `headers` already sets them
+    pub async fn send(&self, method: Method, uri: &str, headers: HeaderMap, body: Body) -> TestResponse {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body)
+            .expect("Failed to build test request");
+
+        for (name, value) in self.headers.iter() {
+            if !headers.contains_key(name) {
+                request.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        request.headers_mut().extend(headers);
+
+        let response = self
+            .router
+            .clone()
+            .oneshot(request)
+            .await
+            .expect("Router returned an error");
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read response body");
+
+        TestResponse { status, headers, body }
+    }
+}
+
+/// Fully buffered response returned by [`TestClient`]
+#[derive(Debug, Clone)]
+pub struct TestResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl TestResponse {
+    /// Response status code
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// All response headers
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// First value of `name` as a string, if present and valid UTF-8
+    pub fn header(&self, name: impl header::AsHeaderName) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// First value of `name` parsed as `T`, e.g. `header_as::<u64>("content-length")`
+    pub fn header_as<T: std::str::FromStr>(&self, name: impl header::AsHeaderName) -> Option<T> {
+        self.header(name).and_then(|v| v.parse().ok())
+    }
+
+    /// Every header as a sorted `name: value` line, with values that change
+    /// from run to run (`date`, `content-length`, `etag`, `x-request-id`)
+    /// replaced by `[volatile]`. Meant for snapshot tests.
+    pub fn normalized_headers(&self) -> String {
+        const VOLATILE: [header::HeaderName; 4] = [
+            header::DATE,
+            header::CONTENT_LENGTH,
+            header::ETAG,
+            header::HeaderName::from_static("x-request-id"),
+        ];
+
+        let mut lines: Vec<String> = self
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let value = if VOLATILE.contains(name) {
+                    "[volatile]"
+                } else {
+                    value.to_str().unwrap_or("[non-utf8]")
+                };
+                format!("{}: {}", name, value)
+            })
+            .collect();
+        lines.sort();
+        lines.join("\n")
+    }
+
+    /// Raw body bytes
+    pub fn bytes(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Body as UTF-8 text; panics if it is not valid UTF-8
+    pub fn text(&self) -> String {
+        String::from_utf8(self.body.to_vec()).expect("Response body was not valid UTF-8")
+    }
+
+    /// Body deserialized from JSON; panics if it does not parse as `T`
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(&self.body).expect("Response was not valid JSON")
+    }
+}
+
+/// [`Clock`] that only moves when told to, for time-bucketed state such as
+/// [`AvailabilityTracker`](crate::slo::AvailabilityTracker)
+#[derive(Debug)]
+pub struct TestClock {
+    /// Wall-clock time, and the time advanced since creation
+    now: Mutex<(SystemTime, Duration)>,
+
+    /// Monotonic time at creation
+    created: Instant,
+}
+
+impl TestClock {
+    /// Clock stopped at `now`
+    pub fn new(now: SystemTime) -> Self {
+        Self { now: Mutex::new((now, Duration::ZERO)), created: Instant::now() }
+    }
+
+    /// Move the clock forward by `by`, wall-clock and monotonic time alike
+    pub fn advance(&self, by: Duration) {
+        if let Ok(mut now) = self.now.lock() {
+            now.0 += by;
+            now.1 += by;
+        }
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> SystemTime {
+        self.now.lock().map(|now| now.0).unwrap_or(UNIX_EPOCH)
+    }
+
+    fn monotonic(&self) -> Instant {
+        self.created + self.now.lock().map(|now| now.1).unwrap_or_default()
+    }
+}
+
+/// [`Sampler`] with a fixed answer, for `AppState::header_sampler`
+#[derive(Debug, Clone, Copy)]
+pub struct FixedSampler(pub bool);
+
+impl Sampler for FixedSampler {
+    fn sample(&self, _rate: f64) -> bool {
+        self.0
+    }
+}
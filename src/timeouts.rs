@@ -0,0 +1,300 @@
+/*!
+ * Request timeouts per route
+ *
+ * `REQUEST_TIMEOUT_SECS` bounds how long any request may take to produce
+ * its response head (default: no limit), and `REQUEST_TIMEOUTS` overrides
+ * it for path prefixes as a JSON array, e.g.
+ * `[{"prefix": "/api", "timeout_seconds": 60}, {"prefix": "/delay", "timeout_seconds": "none"}]`.
+ * The longest matching prefix wins, on segment boundaries, and `"none"`
+ * removes the limit. Prefixes that differ only by a trailing slash count
+ * as duplicates and fail validation.
+ *
+ * A request over its limit is dropped and answered with `504
+ * request_timeout`, naming the limit and the prefix it came from. Only the
+ * wait for the response head is bounded: a streamed body (`/events`,
+ * `/ws`, proxied downloads) runs on once its headers are sent. The
+ * effective limit is recorded as the `timeout` field of the request span.
+ *
+ * A limited request carries its [`Deadline`] in the request extensions, and
+ * outbound calls made while handling it see it through [`Deadline::current`]:
+ * the shared HTTP client and the proxy give each call whatever is left of
+ * it when that is less than the call's own timeout, and fail at once when
+ * nothing is left, rather than doing work that can only end in a 504.
+ */
+use crate::config::AppConfig;
+use crate::error::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+tokio::task_local! {
+    static DEADLINE: Deadline;
+}
+
+/// When a request runs out of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// `limit` from now
+    pub fn after(limit: Duration) -> Self {
+        Self(Instant::now() + limit)
+    }
+
+    pub fn at(instant: Instant) -> Self {
+        Self(instant)
+    }
+
+    /// Time left, zero once it has passed
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_zero()
+    }
+
+    /// Time a call with its own `timeout` may take: the smaller of the two
+    pub fn budget(&self, timeout: Duration) -> Duration {
+        self.remaining().min(timeout)
+    }
+
+    /// Deadline of the request being handled on this task, if it has one
+    pub fn current() -> Option<Self> {
+        DEADLINE.try_with(|deadline| *deadline).ok()
+    }
+
+    /// Run `future` with this as the [`Deadline::current`] one
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        DEADLINE.scope(self, future).await
+    }
+}
+
+/// A time limit, or none
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RequestTimeout {
+    After(Duration),
+    #[default]
+    Unlimited,
+}
+
+impl RequestTimeout {
+    /// Limit of `secs` seconds
+    pub fn secs(secs: u64) -> Self {
+        Self::After(Duration::from_secs(secs))
+    }
+}
+
+impl std::fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::After(limit) if limit.subsec_nanos() == 0 => write!(f, "{}s", limit.as_secs()),
+            Self::After(limit) => write!(f, "{}ms", limit.as_millis()),
+            Self::Unlimited => f.write_str("none"),
+        }
+    }
+}
+
+impl std::str::FromStr for RequestTimeout {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "none" => Ok(Self::Unlimited),
+            secs => match secs.parse::<u64>() {
+                Ok(0) | Err(_) => Err(format!("{:?} is neither a positive number of seconds nor \"none\"", value)),
+                Ok(secs) => Ok(Self::secs(secs)),
+            },
+        }
+    }
+}
+
+/// Seconds as a number, or `"none"`
+impl Serialize for RequestTimeout {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::After(limit) => serializer.serialize_u64(limit.as_secs()),
+            Self::Unlimited => serializer.serialize_str("none"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RequestTimeout {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Secs(u64),
+            Word(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Secs(secs) => secs.to_string().parse(),
+            Raw::Word(word) => word.parse(),
+        }
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Timeout for one path prefix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutRule {
+    /// Path prefix, matched on segment boundaries
+    pub prefix: String,
+
+    #[serde(rename = "timeout_seconds")]
+    pub timeout: RequestTimeout,
+}
+
+impl TimeoutRule {
+    pub fn new(prefix: impl Into<String>, timeout: RequestTimeout) -> Self {
+        Self { prefix: prefix.into(), timeout }
+    }
+}
+
+/// The global limit and its per-prefix overrides
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TimeoutPolicy {
+    pub default: RequestTimeout,
+    pub rules: Vec<TimeoutRule>,
+}
+
+impl TimeoutPolicy {
+    /// Load from `REQUEST_TIMEOUT_SECS` and `REQUEST_TIMEOUTS`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut policy = Self::default();
+        if let Ok(value) = std::env::var("REQUEST_TIMEOUT_SECS") {
+            policy.default = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid REQUEST_TIMEOUT_SECS: {}", e)
+                ))?;
+        }
+        if let Ok(value) = std::env::var("REQUEST_TIMEOUTS") {
+            policy.rules = serde_json::from_str(&value)
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid REQUEST_TIMEOUTS: {}", e)
+                ))?;
+        }
+
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Reject malformed prefixes and prefixes given twice, including ones
+    /// differing only by a trailing slash
+    pub fn validate(&self) -> crate::Result<()> {
+        let invalid = |msg: String| Err(crate::ServerError::ConfigError(msg));
+        let normalized = |prefix: &str| match prefix.trim_end_matches('/') {
+            "" => "/".to_string(),
+            trimmed => trimmed.to_string(),
+        };
+
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !rule.prefix.starts_with('/') || rule.prefix.contains(['?', '#']) {
+                return invalid(format!("Timeout prefix {:?} must be a path starting with /", rule.prefix));
+            }
+            let prefix = normalized(&rule.prefix);
+            if let Some(other) = self.rules[..i].iter().find(|other| normalized(&other.prefix) == prefix) {
+                return invalid(format!(
+                    "Timeout prefix {:?} conflicts with {:?}",
+                    rule.prefix, other.prefix
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The limit for `path` and the prefix it comes from (`None` for the
+    /// global default)
+    pub fn timeout_for(&self, path: &str) -> (RequestTimeout, Option<&str>) {
+        self.rules
+            .iter()
+            .filter_map(|rule| {
+                crate::auth::longest_prefix(std::slice::from_ref(&rule.prefix), path).map(|len| (len, rule))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map_or((self.default, None), |(_, rule)| (rule.timeout, Some(rule.prefix.as_str())))
+    }
+}
+
+/// Middleware bounding the wait for the response by the route's limit
+pub async fn enforce(State(config): State<Arc<AppConfig>>, mut request: Request, next: Next) -> Response {
+    let (timeout, prefix) = config.timeouts.timeout_for(request.uri().path());
+    tracing::Span::current().record("timeout", tracing::field::display(timeout));
+    let RequestTimeout::After(limit) = timeout else {
+        return next.run(request).await;
+    };
+
+    let path = request.uri().path().to_string();
+    let deadline = Deadline::after(limit);
+    request.extensions_mut().insert(deadline);
+    match tokio::time::timeout(limit, deadline.scope(next.run(request))).await {
+        Ok(response) => response,
+        Err(_) => {
+            let source = match prefix {
+                Some(prefix) => format!("the REQUEST_TIMEOUTS limit for {}", prefix),
+                None => "REQUEST_TIMEOUT_SECS".to_string(),
+            };
+            warn!("Request to {} exceeded its {} timeout ({})", path, timeout, source);
+            ApiError::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "request_timeout",
+                format!("No response within {} ({})", timeout, source),
+            )
+            .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_parse_and_validate() {
+        let rules: Vec<TimeoutRule> = serde_json::from_str(
+            r#"[{"prefix": "/api", "timeout_seconds": 60}, {"prefix": "/delay", "timeout_seconds": "none"}]"#,
+        )
+        .unwrap();
+        assert_eq!(rules[0], TimeoutRule::new("/api", RequestTimeout::secs(60)));
+        assert_eq!(rules[1].timeout, RequestTimeout::Unlimited);
+
+        for bad in [r#"[{"prefix": "/a", "timeout_seconds": 0}]"#, r#"[{"prefix": "/a", "timeout_seconds": "never"}]"#] {
+            assert!(serde_json::from_str::<Vec<TimeoutRule>>(bad).is_err(), "{}", bad);
+        }
+
+        let policy = |prefixes: &[&str]| TimeoutPolicy {
+            default: RequestTimeout::secs(10),
+            rules: prefixes.iter().map(|p| TimeoutRule::new(*p, RequestTimeout::secs(5))).collect(),
+        };
+        assert!(policy(&["/api", "/api/v2", "/"]).validate().is_ok());
+        assert!(policy(&["/api", "/api"]).validate().is_err());
+        assert!(policy(&["/api", "/api/"]).validate().is_err());
+        assert!(policy(&["api"]).validate().is_err());
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let policy = TimeoutPolicy {
+            default: RequestTimeout::secs(10),
+            rules: vec![
+                TimeoutRule::new("/api/", RequestTimeout::secs(60)),
+                TimeoutRule::new("/api/slow", RequestTimeout::Unlimited),
+            ],
+        };
+        assert_eq!(policy.timeout_for("/"), (RequestTimeout::secs(10), None));
+        assert_eq!(policy.timeout_for("/api"), (RequestTimeout::secs(60), Some("/api/")));
+        assert_eq!(policy.timeout_for("/api/users"), (RequestTimeout::secs(60), Some("/api/")));
+        assert_eq!(policy.timeout_for("/api/slow/report"), (RequestTimeout::Unlimited, Some("/api/slow")));
+        assert_eq!(policy.timeout_for("/apiary"), (RequestTimeout::secs(10), None));
+    }
+}
@@ -0,0 +1,350 @@
+/*!
+ * Embedded cloudflared quick tunnel
+ *
+ * With `RUN_CLOUDFLARED=quick` the server launches `cloudflared tunnel --url`
+ * pointed at its own listener after binding, so a demo needs only one
+ * process. The `*.trycloudflare.com` URL cloudflared prints on stderr is
+ * captured, logged and reported by `/health`. The child is restarted with
+ * exponential backoff if it exits and killed on graceful shutdown.
+ */
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// How to run and supervise the cloudflared child
+#[derive(Debug, Clone)]
+pub struct TunnelConfig {
+    /// cloudflared executable, looked up on `PATH` unless it contains a `/`
+    pub binary: PathBuf,
+
+    /// First restart delay, doubled after each consecutive failure
+    pub initial_backoff: Duration,
+
+    /// Ceiling for the restart delay
+    pub max_backoff: Duration,
+}
+
+impl TunnelConfig {
+    /// Configuration for `binary` with the default backoff (1s doubling to 60s)
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    /// Load from `RUN_CLOUDFLARED` and `CLOUDFLARED_PATH`. Returns `None`
+    /// unless the quick-tunnel mode is requested; when it is, a missing
+    /// binary is a configuration error.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        match std::env::var("RUN_CLOUDFLARED").as_deref() {
+            Err(_) | Ok("") | Ok("off") => return Ok(None),
+            Ok("quick") => {}
+            Ok(other) => {
+                return Err(crate::ServerError::ConfigError(format!(
+                    "Invalid RUN_CLOUDFLARED {:?} (expected \"quick\" or \"off\")",
+                    other
+                )))
+            }
+        }
+
+        let binary = std::env::var("CLOUDFLARED_PATH").unwrap_or_else(|_| "cloudflared".to_string());
+        let config = Self::new(binary);
+        config.resolve_binary()?;
+        Ok(Some(config))
+    }
+
+    /// Locate the executable, failing with a clear error if it is absent
+    pub fn resolve_binary(&self) -> crate::Result<PathBuf> {
+        let found = if self.binary.components().count() > 1 {
+            self.binary.is_file().then(|| self.binary.clone())
+        } else {
+            std::env::var_os("PATH").and_then(|paths| {
+                std::env::split_paths(&paths)
+                    .map(|dir| dir.join(&self.binary))
+                    .find(|candidate| candidate.is_file())
+            })
+        };
+
+        found.ok_or_else(|| crate::ServerError::ConfigError(format!(
+            "RUN_CLOUDFLARED=quick but cloudflared binary {} was not found (set CLOUDFLARED_PATH)",
+            self.binary.display()
+        )))
+    }
+}
+
+/// Quick tunnel state shared with the `/health` handler
+#[derive(Debug, Clone, Default)]
+pub struct TunnelStatus {
+    url: Arc<RwLock<Option<String>>>,
+    restarts: Arc<AtomicU32>,
+}
+
+impl TunnelStatus {
+    /// Public `*.trycloudflare.com` URL, once cloudflared has reported it
+    pub fn url(&self) -> Option<String> {
+        self.url.read().ok().and_then(|url| url.clone())
+    }
+
+    fn set_url(&self, value: Option<String>) {
+        if let Ok(mut url) = self.url.write() {
+            *url = value;
+        }
+    }
+
+    /// Number of times the child has been restarted
+    pub fn restarts(&self) -> u32 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+}
+
+/// Handle to the supervised cloudflared child
+pub struct QuickTunnel {
+    task: JoinHandle<()>,
+}
+
+impl QuickTunnel {
+    /// Start supervising cloudflared for the server listening on `local_addr`,
+    /// publishing progress to `status`. The child is stopped once `shutdown`
+    /// flips to `true`.
+    pub fn spawn(
+        config: TunnelConfig,
+        local_addr: SocketAddr,
+        status: TunnelStatus,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let task = tokio::spawn(supervise(config, origin_url(local_addr), status, shutdown));
+
+        Self { task }
+    }
+
+    /// Wait for the supervisor to finish after shutdown was signalled
+    pub async fn join(self) {
+        if let Err(e) = self.task.await {
+            error!("cloudflared supervisor task failed: {}", e);
+        }
+    }
+}
+
+/// URL cloudflared should proxy to; wildcard binds are reached via loopback
+fn origin_url(addr: SocketAddr) -> String {
+    let ip = match addr.ip() {
+        ip if ip.is_unspecified() && ip.is_ipv4() => "127.0.0.1".to_string(),
+        ip if ip.is_unspecified() => "[::1]".to_string(),
+        ip if ip.is_ipv6() => format!("[{}]", ip),
+        ip => ip.to_string(),
+    };
+    format!("http://{}:{}", ip, addr.port())
+}
+
+/// Pull a `https://*.trycloudflare.com` URL out of a cloudflared log line
+pub fn parse_quick_tunnel_url(line: &str) -> Option<String> {
+    let start = line.find("https://")?;
+    let candidate: String = line[start..]
+        .chars()
+        .take_while(|c| !c.is_whitespace() && *c != '|')
+        .collect();
+    candidate
+        .trim_end_matches('/')
+        .ends_with(".trycloudflare.com")
+        .then(|| candidate.trim_end_matches('/').to_string())
+}
+
+fn spawn_child(binary: &Path, origin: &str) -> std::io::Result<Child> {
+    Command::new(binary)
+        .args(["tunnel", "--no-autoupdate", "--url", origin])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+}
+
+/// Resolve once shutdown is signalled (or the sender is gone)
+async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
+async fn supervise(
+    config: TunnelConfig,
+    origin: String,
+    status: TunnelStatus,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut backoff = config.initial_backoff;
+
+    loop {
+        if *shutdown.borrow() {
+            return;
+        }
+
+        let started = Instant::now();
+        match spawn_child(&config.binary, &origin) {
+            Ok(mut child) => {
+                info!("Started cloudflared quick tunnel for {}", origin);
+
+                let stderr_task = child.stderr.take().map(|stderr| {
+                    let status = status.clone();
+                    tokio::spawn(async move {
+                        let mut lines = BufReader::new(stderr).lines();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            if let Some(public) = parse_quick_tunnel_url(&line) {
+                                info!("==================================================");
+                                info!("Quick tunnel available at {}", public);
+                                info!("==================================================");
+                                status.set_url(Some(public));
+                            }
+                        }
+                    })
+                });
+
+                tokio::select! {
+                    status = child.wait() => {
+                        warn!("cloudflared exited: {:?}", status);
+                    }
+                    _ = stopped(&mut shutdown) => {
+                        info!("Stopping cloudflared");
+                        if let Err(e) = child.kill().await {
+                            warn!("Failed to kill cloudflared: {}", e);
+                        }
+                        if let Some(task) = stderr_task {
+                            task.abort();
+                        }
+                        status.set_url(None);
+                        return;
+                    }
+                }
+
+                if let Some(task) = stderr_task {
+                    task.abort();
+                }
+                status.set_url(None);
+            }
+            Err(e) => error!("Failed to start cloudflared {}: {}", config.binary.display(), e),
+        }
+
+        // A child that ran for a while earns a fresh backoff
+        if started.elapsed() > config.max_backoff {
+            backoff = config.initial_backoff;
+        }
+
+        warn!("Restarting cloudflared in {:?}", backoff);
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = stopped(&mut shutdown) => return,
+        }
+        status.restarts.fetch_add(1, Ordering::Relaxed);
+        backoff = (backoff * 2).min(config.max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn fake_cloudflared(name: &str, body: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("fake-cloudflared-{}-{}", name, std::process::id()));
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).expect("Failed to write fake cloudflared");
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .expect("Failed to make fake cloudflared executable");
+        path
+    }
+
+    fn test_config(binary: PathBuf) -> TunnelConfig {
+        TunnelConfig {
+            binary,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(100),
+        }
+    }
+
+    async fn wait_for<F: Fn() -> bool>(condition: F) -> bool {
+        for _ in 0..200 {
+            if condition() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[test]
+    fn test_parse_quick_tunnel_url() {
+        let line = "2024-06-15T19:51:17Z INF |  https://random-words-here.trycloudflare.com                 |";
+        assert_eq!(
+            parse_quick_tunnel_url(line).as_deref(),
+            Some("https://random-words-here.trycloudflare.com")
+        );
+
+        assert_eq!(parse_quick_tunnel_url("INF Requesting new quick Tunnel on trycloudflare.com..."), None);
+        assert_eq!(parse_quick_tunnel_url("INF see https://developers.cloudflare.com/docs"), None);
+    }
+
+    #[test]
+    fn test_origin_url_uses_loopback_for_wildcard_bind() {
+        assert_eq!(origin_url("0.0.0.0:8080".parse().expect("Invalid address")), "http://127.0.0.1:8080");
+        assert_eq!(origin_url("10.1.2.3:9000".parse().expect("Invalid address")), "http://10.1.2.3:9000");
+    }
+
+    #[test]
+    fn test_missing_binary_is_config_error() {
+        let config = TunnelConfig::new("/nonexistent/cloudflared");
+        assert!(config.resolve_binary().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_captures_url_and_stops_on_shutdown() {
+        let binary = fake_cloudflared(
+            "long",
+            "echo \"INF |  https://fake-demo.trycloudflare.com  |\" >&2\nexec sleep 30",
+        );
+        let (tx, rx) = watch::channel(false);
+        let status = TunnelStatus::default();
+        let tunnel = QuickTunnel::spawn(
+            test_config(binary.clone()),
+            "0.0.0.0:8080".parse().expect("Invalid address"),
+            status.clone(),
+            rx,
+        );
+
+        assert!(wait_for(|| status.url().is_some()).await, "URL was never captured");
+        assert_eq!(status.url().as_deref(), Some("https://fake-demo.trycloudflare.com"));
+
+        tx.send(true).expect("Failed to signal shutdown");
+        tokio::time::timeout(Duration::from_secs(5), tunnel.join())
+            .await
+            .expect("Supervisor did not stop after shutdown");
+        assert_eq!(status.url(), None);
+
+        let _ = std::fs::remove_file(binary);
+    }
+
+    #[tokio::test]
+    async fn test_restarts_child_that_exits() {
+        let binary = fake_cloudflared("crash", "echo \"INF https://crashy.trycloudflare.com\" >&2\nexit 1");
+        let (tx, rx) = watch::channel(false);
+        let status = TunnelStatus::default();
+        let tunnel = QuickTunnel::spawn(
+            test_config(binary.clone()),
+            "127.0.0.1:8080".parse().expect("Invalid address"),
+            status.clone(),
+            rx,
+        );
+
+        assert!(wait_for(|| status.restarts() >= 2).await, "cloudflared was not restarted");
+
+        tx.send(true).expect("Failed to signal shutdown");
+        tunnel.join().await;
+        let _ = std::fs::remove_file(binary);
+    }
+}
@@ -0,0 +1,210 @@
+/*!
+ * cloudflared connectivity as a readiness input
+ *
+ * cloudflared serves `GET /ready` on its metrics listener, answering
+ * `{"status": 200, "readyConnections": 4, ...}` (or a 503 with zero
+ * connections once the tunnel has lost its edge connections). When
+ * `CLOUDFLARED_METRICS_URL` is set, `TunnelHealthCheck` polls that endpoint
+ * and reports a `cloudflared` check to `/readyz`, so an origin that is up but
+ * unreachable through the tunnel is reported as not ready.
+ */
+use crate::health::{CheckResult, HealthCheck};
+use crate::http_client::HttpClient;
+use axum::async_trait;
+use axum::http::{HeaderMap, Method};
+use bytes::Bytes;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Name under which the check is reported
+pub const CHECK_NAME: &str = "cloudflared";
+
+/// Where and how often to poll cloudflared
+#[derive(Debug, Clone)]
+pub struct TunnelHealthConfig {
+    /// Base URL of cloudflared's metrics listener, e.g. `http://127.0.0.1:2000`
+    pub metrics_url: String,
+
+    /// How long a poll result is reused before cloudflared is asked again
+    pub cache_ttl: Duration,
+
+    /// Time allowed for a single poll
+    pub timeout: Duration,
+}
+
+impl TunnelHealthConfig {
+    /// Poll `<metrics_url>/ready` with a 5s cache and 2s timeout
+    pub fn new(metrics_url: impl Into<String>) -> Self {
+        Self {
+            metrics_url: metrics_url.into().trim_end_matches('/').to_string(),
+            cache_ttl: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    /// Load from `CLOUDFLARED_METRICS_URL` and `CLOUDFLARED_READY_CACHE_MS`.
+    /// Returns `None` when no metrics URL is configured.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let Ok(metrics_url) = std::env::var("CLOUDFLARED_METRICS_URL") else {
+            return Ok(None);
+        };
+        crate::http_client::require_reachable("CLOUDFLARED_METRICS_URL", &metrics_url)?;
+
+        let mut config = Self::new(metrics_url);
+
+        if let Ok(value) = std::env::var("CLOUDFLARED_READY_CACHE_MS") {
+            let millis: u64 = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid cloudflared readiness cache TTL: {}", e)
+                ))?;
+            config.cache_ttl = Duration::from_millis(millis);
+        }
+
+        Ok(Some(config))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReadyResponse {
+    ready_connections: u32,
+}
+
+/// Readiness check backed by cloudflared's `/ready` endpoint
+#[derive(Debug)]
+pub struct TunnelHealthCheck {
+    config: TunnelHealthConfig,
+    client: HttpClient,
+    cached: Mutex<Option<(Instant, CheckResult)>>,
+}
+
+impl TunnelHealthCheck {
+    /// Create the check; cloudflared is first polled on the first `check`
+    pub fn new(config: TunnelHealthConfig, http: &HttpClient) -> Self {
+        let client = http.with_timeout(config.timeout);
+        Self {
+            config,
+            client,
+            cached: Mutex::new(None),
+        }
+    }
+
+    async fn poll(&self) -> CheckResult {
+        let url = format!("{}/ready", self.config.metrics_url);
+        let response = match self.client.send(Method::GET, &url, HeaderMap::new(), Bytes::new()).await {
+            Ok(response) => response,
+            Err(e) => return CheckResult::unhealthy(CHECK_NAME, e.to_string()),
+        };
+
+        match serde_json::from_slice::<ReadyResponse>(&response.body) {
+            Ok(ready) if ready.ready_connections > 0 => CheckResult::healthy(
+                CHECK_NAME,
+                format!("{} ready connections", ready.ready_connections),
+            ),
+            Ok(_) => CheckResult::unhealthy(CHECK_NAME, "0 ready connections"),
+            Err(e) => CheckResult::unhealthy(
+                CHECK_NAME,
+                format!("unparseable /ready response ({}): {}", response.status, e),
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl HealthCheck for TunnelHealthCheck {
+    fn name(&self) -> &str {
+        CHECK_NAME
+    }
+
+    async fn check(&self) -> CheckResult {
+        // Holding the lock across the poll lets concurrent probes share one call
+        let mut cached = self.cached.lock().await;
+        if let Some((at, result)) = cached.as_ref() {
+            if at.elapsed() < self.config.cache_ttl {
+                return result.clone();
+            }
+        }
+
+        let result = self.poll().await;
+        *cached = Some((Instant::now(), result.clone()));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+    use serde_json::json;
+
+    async fn spawn_stub(status: StatusCode, ready_connections: u32) -> String {
+        let app = Router::new().route(
+            "/ready",
+            get(move || async move {
+                (status, Json(json!({ "status": status.as_u16(), "readyConnections": ready_connections })))
+                    .into_response()
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind stub server");
+        let addr = listener.local_addr().expect("Stub server has no address");
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.expect("Stub server failed");
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_ready_connections_are_healthy() {
+        let check = TunnelHealthCheck::new(TunnelHealthConfig::new(spawn_stub(StatusCode::OK, 4).await), &HttpClient::default());
+
+        let result = check.check().await;
+        assert!(result.healthy);
+        assert_eq!(result.name, "cloudflared");
+        assert_eq!(result.detail.as_deref(), Some("4 ready connections"));
+    }
+
+    #[tokio::test]
+    async fn test_zero_connections_is_unhealthy() {
+        let url = spawn_stub(StatusCode::SERVICE_UNAVAILABLE, 0).await;
+        let check = TunnelHealthCheck::new(TunnelHealthConfig::new(url), &HttpClient::default());
+
+        let result = check.check().await;
+        assert!(!result.healthy);
+        assert_eq!(result.detail.as_deref(), Some("0 ready connections"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused_is_unhealthy() {
+        // Bind then drop to get a port nothing is listening on
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+        let addr = listener.local_addr().expect("Listener has no address");
+        drop(listener);
+
+        let check = TunnelHealthCheck::new(TunnelHealthConfig::new(format!("http://{}", addr)), &HttpClient::default());
+
+        let result = check.check().await;
+        assert!(!result.healthy);
+        assert!(result.detail.unwrap_or_default().contains("Failed to connect"));
+    }
+
+    #[tokio::test]
+    async fn test_result_is_cached() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("Failed to bind");
+        let addr = listener.local_addr().expect("Listener has no address");
+        drop(listener);
+
+        let mut config = TunnelHealthConfig::new(format!("http://{}", addr));
+        config.cache_ttl = Duration::from_secs(60);
+        let check = TunnelHealthCheck::new(config, &HttpClient::default());
+
+        let first = check.check().await;
+        let cached_at = check.cached.lock().await.as_ref().map(|(at, _)| *at);
+        let second = check.check().await;
+
+        assert_eq!(first, second);
+        assert_eq!(check.cached.lock().await.as_ref().map(|(at, _)| *at), cached_at);
+    }
+}
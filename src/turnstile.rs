@@ -0,0 +1,472 @@
+/*!
+ * Cloudflare Turnstile verification
+ *
+ * Tokens produced by the Turnstile widget are checked server-side against the
+ * siteverify API. `TurnstileVerifier` performs the call, and the
+ * `RequireTurnstile` extractor lets any handler demand a valid token from the
+ * `cf-turnstile-response` field of a form or JSON body.
+ */
+use crate::client_ip::ClientIp;
+use crate::error::{ApiError, ErrorBody};
+use crate::http_client::{HttpClient, HttpClientError};
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::post,
+    Router,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// Cloudflare's siteverify endpoint
+pub const DEFAULT_VERIFY_URL: &str = "https://challenges.cloudflare.com/turnstile/v0/siteverify";
+
+/// Form/JSON field the Turnstile widget submits its token in
+pub const TOKEN_FIELD: &str = "cf-turnstile-response";
+
+/// Turnstile secret and siteverify settings
+#[derive(Clone)]
+pub struct TurnstileConfig {
+    /// Secret key issued for the site in the Cloudflare dashboard
+    pub secret_key: String,
+
+    /// siteverify URL, overridable for testing
+    pub verify_url: String,
+
+    /// Time allowed for the siteverify round trip
+    pub timeout: Duration,
+}
+
+impl std::fmt::Debug for TurnstileConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TurnstileConfig")
+            .field("secret_key", &"<redacted>")
+            .field("verify_url", &self.verify_url)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl TurnstileConfig {
+    /// Configuration for `secret_key` using Cloudflare's siteverify and a 5s timeout
+    pub fn new(secret_key: impl Into<String>) -> Self {
+        Self {
+            secret_key: secret_key.into(),
+            verify_url: DEFAULT_VERIFY_URL.to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Load from `TURNSTILE_SECRET_KEY` (or `TURNSTILE_SECRET_KEY_FILE`).
+    /// Returns `None` when no secret is configured.
+    pub fn from_env() -> crate::Result<Option<Self>> {
+        let Some(secret_key) = crate::config::read_secret("TURNSTILE_SECRET_KEY")? else {
+            return Ok(None);
+        };
+
+        let mut config = Self::new(secret_key);
+
+        if let Ok(value) = std::env::var("TURNSTILE_VERIFY_URL") {
+            config.verify_url = value;
+        }
+        crate::http_client::require_reachable("TURNSTILE_VERIFY_URL", &config.verify_url)?;
+
+        if let Ok(value) = std::env::var("TURNSTILE_TIMEOUT_MS") {
+            let millis: u64 = value.parse()
+                .map_err(|e| crate::ServerError::ConfigError(
+                    format!("Invalid Turnstile timeout: {}", e)
+                ))?;
+            config.timeout = Duration::from_millis(millis);
+        }
+
+        Ok(Some(config))
+    }
+}
+
+/// Parsed siteverify response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TurnstileOutcome {
+    pub success: bool,
+
+    #[serde(rename = "error-codes", default)]
+    pub error_codes: Vec<String>,
+
+    #[serde(default)]
+    pub challenge_ts: Option<String>,
+
+    #[serde(default)]
+    pub hostname: Option<String>,
+
+    #[serde(default)]
+    pub action: Option<String>,
+
+    #[serde(default)]
+    pub cdata: Option<String>,
+}
+
+/// Reasons a request failed Turnstile verification
+#[derive(Debug, Error)]
+pub enum TurnstileError {
+    #[error("Turnstile verification is not configured")]
+    NotConfigured,
+    #[error("Missing {TOKEN_FIELD} field")]
+    MissingToken,
+    #[error("Unreadable request body: {0}")]
+    InvalidBody(String),
+    #[error("Turnstile rejected the token: {}", .0.join(", "))]
+    Rejected(Vec<String>),
+    #[error("Turnstile verification timed out")]
+    Timeout,
+    #[error("No time was left in the request's deadline to verify the token")]
+    DeadlineExceeded,
+    #[error("Turnstile verification failed: {0}")]
+    Upstream(String),
+}
+
+/// Result of Turnstile operations
+pub type Result<T> = std::result::Result<T, TurnstileError>;
+
+impl From<TurnstileError> for ApiError {
+    fn from(err: TurnstileError) -> Self {
+        let (status, code) = match &err {
+            TurnstileError::NotConfigured => (StatusCode::SERVICE_UNAVAILABLE, "turnstile_not_configured"),
+            TurnstileError::MissingToken => (StatusCode::BAD_REQUEST, "turnstile_token_missing"),
+            TurnstileError::InvalidBody(_) => (StatusCode::BAD_REQUEST, "invalid_body"),
+            TurnstileError::Rejected(_) => (StatusCode::FORBIDDEN, "turnstile_rejected"),
+            TurnstileError::Timeout => (StatusCode::BAD_GATEWAY, "turnstile_timeout"),
+            TurnstileError::DeadlineExceeded => (StatusCode::GATEWAY_TIMEOUT, "deadline_exceeded"),
+            TurnstileError::Upstream(_) => (StatusCode::BAD_GATEWAY, "turnstile_unavailable"),
+        };
+        ApiError::new(status, code, err.to_string())
+    }
+}
+
+impl IntoResponse for TurnstileError {
+    fn into_response(self) -> Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+/// Verifies Turnstile tokens against the configured siteverify endpoint
+#[derive(Debug, Clone)]
+pub struct TurnstileVerifier {
+    config: Arc<TurnstileConfig>,
+    client: HttpClient,
+}
+
+impl TurnstileVerifier {
+    /// Create a verifier calling siteverify through `http`, with
+    /// `config.timeout` as the limit
+    pub fn new(config: TurnstileConfig, http: &HttpClient) -> Self {
+        let client = http.with_timeout(config.timeout);
+        Self {
+            config: Arc::new(config),
+            client,
+        }
+    }
+
+    /// Check `token` with siteverify. An unsuccessful outcome is still `Ok`;
+    /// only transport and protocol failures are errors.
+    pub async fn verify(&self, token: &str, remote_ip: Option<IpAddr>) -> Result<TurnstileOutcome> {
+        let remote_ip = remote_ip.map(|ip| ip.to_string());
+        let mut fields = vec![
+            ("secret", self.config.secret_key.as_str()),
+            ("response", token),
+        ];
+        if let Some(ip) = remote_ip.as_deref() {
+            fields.push(("remoteip", ip));
+        }
+
+        let response = self.client
+            .post_form(&self.config.verify_url, &fields)
+            .await
+            .map_err(|e| {
+                warn!("Turnstile siteverify request failed: {}", e);
+                match e {
+                    HttpClientError::DeadlineExceeded => TurnstileError::DeadlineExceeded,
+                    e if e.is_timeout() => TurnstileError::Timeout,
+                    e => TurnstileError::Upstream(e.to_string()),
+                }
+            })?;
+
+        if !response.status.is_success() {
+            return Err(TurnstileError::Upstream(format!(
+                "siteverify returned {}",
+                response.status
+            )));
+        }
+
+        let is_json = response.headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with("application/json"))
+            .unwrap_or(false);
+        if !is_json {
+            return Err(TurnstileError::Upstream("siteverify did not return JSON".to_string()));
+        }
+
+        serde_json::from_slice(&response.body)
+            .map_err(|e| TurnstileError::Upstream(format!("unparseable siteverify response: {}", e)))
+    }
+}
+
+/// Extractor that requires a valid Turnstile token in the request body.
+///
+/// The body (form or JSON) is deserialized into `T` after the token has been
+/// verified, so handlers receive their payload alongside the outcome. The
+/// `TurnstileVerifier` must be installed as a request extension.
+#[derive(Debug, Clone)]
+pub struct RequireTurnstile<T>(pub T, pub TurnstileOutcome);
+
+#[async_trait]
+impl<S, T> FromRequest<S> for RequireTurnstile<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned,
+{
+    type Rejection = TurnstileError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self> {
+        let (mut parts, body) = req.into_parts();
+        let verifier = parts
+            .extensions
+            .get::<TurnstileVerifier>()
+            .cloned()
+            .ok_or(TurnstileError::NotConfigured)?;
+
+        let Ok(client) = ClientIp::from_request_parts(&mut parts, state).await;
+        let req = Request::from_parts(parts, body);
+
+        let is_json = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.starts_with("application/json"))
+            .unwrap_or(false);
+
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|e| TurnstileError::InvalidBody(e.to_string()))?;
+
+        let fields = parse_fields(&body, is_json)?;
+        let token = fields
+            .get(TOKEN_FIELD)
+            .and_then(Value::as_str)
+            .filter(|t| !t.is_empty())
+            .ok_or(TurnstileError::MissingToken)?;
+
+        let outcome = verifier.verify(token, client.ip).await?;
+        if !outcome.success {
+            return Err(TurnstileError::Rejected(outcome.error_codes));
+        }
+
+        let payload = serde_json::from_value(fields)
+            .map_err(|e| TurnstileError::InvalidBody(e.to_string()))?;
+
+        Ok(RequireTurnstile(payload, outcome))
+    }
+}
+
+fn parse_fields(body: &[u8], is_json: bool) -> Result<Value> {
+    if is_json {
+        return serde_json::from_slice(body).map_err(|e| TurnstileError::InvalidBody(e.to_string()));
+    }
+
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_bytes(body)
+        .map_err(|e| TurnstileError::InvalidBody(e.to_string()))?;
+    let map: Map<String, Value> = pairs
+        .into_iter()
+        .map(|(k, v)| (k, Value::String(v)))
+        .collect();
+    Ok(Value::Object(map))
+}
+
+/// What the demo route reports about an accepted token
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = TurnstileResult)]
+struct VerifyResult {
+    success: bool,
+    hostname: Option<String>,
+    challenge_ts: Option<String>,
+    action: Option<String>,
+}
+
+/// Verify a Turnstile token
+#[utoipa::path(
+    post,
+    path = "/verify",
+    request_body(
+        description = "Form or JSON object with a cf-turnstile-response field",
+        content((Object = "application/x-www-form-urlencoded"), (Object = "application/json")),
+    ),
+    responses(
+        (status = OK, description = "Token accepted", body = VerifyResult),
+        (status = BAD_REQUEST, description = "No token in the body", body = ErrorBody),
+        (status = FORBIDDEN, description = "Token rejected", body = ErrorBody),
+        (status = BAD_GATEWAY, description = "Siteverify failed or timed out", body = ErrorBody),
+    ),
+)]
+async fn verify_handler(RequireTurnstile(_, outcome): RequireTurnstile<Value>) -> Json<VerifyResult> {
+    Json(VerifyResult {
+        success: outcome.success,
+        hostname: outcome.hostname,
+        challenge_ts: outcome.challenge_ts,
+        action: outcome.action,
+    })
+}
+
+/// Demo routes for Turnstile verification
+pub fn routes<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new().route("/verify", post(verify_handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::extract::Form;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use tower::util::ServiceExt;
+
+    async fn siteverify_stub(Form(form): Form<HashMap<String, String>>) -> Json<Value> {
+        assert_eq!(form.get("secret").map(String::as_str), Some("test-secret"));
+
+        match form.get("response").map(String::as_str) {
+            Some("valid-token") => Json(json!({
+                "success": true,
+                "error-codes": [],
+                "challenge_ts": "2024-01-01T00:00:00Z",
+                "hostname": "hello.halibut.cc",
+                "action": "submit",
+            })),
+            Some("slow-token") => {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                Json(json!({ "success": true }))
+            }
+            _ => Json(json!({
+                "success": false,
+                "error-codes": ["invalid-input-response"],
+            })),
+        }
+    }
+
+    async fn spawn_stub() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind stub server");
+        let addr = listener.local_addr().expect("Stub server has no address");
+        let app = Router::new().route("/siteverify", post(siteverify_stub));
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.expect("Stub server failed");
+        });
+        format!("http://{}/siteverify", addr)
+    }
+
+    async fn verifier() -> TurnstileVerifier {
+        let mut config = TurnstileConfig::new("test-secret");
+        config.verify_url = spawn_stub().await;
+        config.timeout = Duration::from_millis(300);
+        TurnstileVerifier::new(config, &HttpClient::default())
+    }
+
+    #[tokio::test]
+    async fn test_verify_success() {
+        let outcome = verifier().await
+            .verify("valid-token", Some("203.0.113.7".parse().expect("Invalid IP")))
+            .await
+            .expect("Verification failed");
+
+        assert!(outcome.success);
+        assert!(outcome.error_codes.is_empty());
+        assert_eq!(outcome.hostname.as_deref(), Some("hello.halibut.cc"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_invalid_input_response() {
+        let outcome = verifier().await
+            .verify("bogus", None)
+            .await
+            .expect("Verification request failed");
+
+        assert!(!outcome.success);
+        assert_eq!(outcome.error_codes, vec!["invalid-input-response"]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_timeout() {
+        let result = verifier().await.verify("slow-token", None).await;
+
+        assert!(matches!(result, Err(TurnstileError::Timeout)));
+    }
+
+    async fn post_verify(content_type: &str, body: &str) -> (StatusCode, Value) {
+        let app = routes().layer(axum::Extension(verifier().await));
+        let request = Request::builder()
+            .method("POST")
+            .uri("/verify")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .expect("Failed to build request");
+
+        let response = app.oneshot(request).await.expect("Failed to get response");
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("Failed to read body");
+        (status, serde_json::from_slice(&body).expect("Response was not valid JSON"))
+    }
+
+    #[tokio::test]
+    async fn test_verify_route_accepts_form_token() {
+        let (status, json) = post_verify(
+            "application/x-www-form-urlencoded",
+            "cf-turnstile-response=valid-token&name=test",
+        ).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["success"], true);
+        assert_eq!(json["action"], "submit");
+    }
+
+    #[tokio::test]
+    async fn test_verify_route_rejects_invalid_json_token() {
+        let (status, json) = post_verify(
+            "application/json",
+            r#"{"cf-turnstile-response": "bogus"}"#,
+        ).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(json["error"], "turnstile_rejected");
+        assert!(json["message"].as_str().unwrap_or_default().contains("invalid-input-response"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_route_missing_token() {
+        let (status, json) = post_verify("application/json", r#"{"name": "test"}"#).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["error"], "turnstile_token_missing");
+    }
+
+    #[tokio::test]
+    async fn test_verify_route_timeout_maps_to_bad_gateway() {
+        let (status, json) = post_verify(
+            "application/x-www-form-urlencoded",
+            "cf-turnstile-response=slow-token",
+        ).await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(json["error"], "turnstile_timeout");
+    }
+}
@@ -0,0 +1,214 @@
+/*!
+ * Telemetry for requests that matched no route
+ *
+ * Route statistics count every unmatched request under one entry, which
+ * says scanners are busy but not what they look for. A request event
+ * subscriber (see [`crate::request_events`]) counts each 404 on the main
+ * router that matched no route by its path, and
+ * `GET /admin/unknown-paths` returns the most requested ones with when
+ * each was first and last seen: a client still calling a removed endpoint
+ * stands out from scanner noise.
+ *
+ * Paths are normalized before counting: the query string is dropped and
+ * the path is cut to [`MAX_PATH_LEN`] bytes, so neither memory nor logs
+ * hold the tokens some clients put in URLs. At most
+ * `UNKNOWN_PATHS_MAX` paths (default 1000) are tracked; a new path
+ * replaces the least requested one. Each path is logged the first time it
+ * is seen and then every `UNKNOWN_PATHS_LOG_EVERY` requests (default 100).
+ */
+use crate::bounded::{BoundedMap, StoreStats};
+use crate::clock::{Clock, SystemClock};
+use crate::state::AppState;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+/// Name of the request event subscriber
+pub const SUBSCRIBER: &str = "unknown_paths";
+
+/// Longest path kept, in bytes; longer paths are cut and marked with `...`
+pub const MAX_PATH_LEN: usize = 128;
+
+/// Paths returned by `/admin/unknown-paths`
+pub const TOP_PATHS: usize = 50;
+
+/// How many unknown paths are tracked and how often each is logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct UnknownPathsConfig {
+    /// Paths tracked at once
+    pub max_paths: usize,
+
+    /// A path is logged on its first request and on every `log_every`th
+    pub log_every: u64,
+}
+
+impl Default for UnknownPathsConfig {
+    fn default() -> Self {
+        Self { max_paths: 1000, log_every: 100 }
+    }
+}
+
+impl UnknownPathsConfig {
+    /// Load from `UNKNOWN_PATHS_MAX` and `UNKNOWN_PATHS_LOG_EVERY`
+    pub fn from_env() -> crate::Result<Self> {
+        let mut config = Self::default();
+        let invalid = |name: &str, value: &str| {
+            crate::ServerError::ConfigError(format!("Invalid {} {:?}: expected a positive number", name, value))
+        };
+
+        if let Ok(value) = std::env::var("UNKNOWN_PATHS_MAX") {
+            config.max_paths = match value.trim().parse::<usize>() {
+                Ok(paths) if paths > 0 => paths,
+                _ => return Err(invalid("UNKNOWN_PATHS_MAX", &value)),
+            };
+        }
+        if let Ok(value) = std::env::var("UNKNOWN_PATHS_LOG_EVERY") {
+            config.log_every = match value.trim().parse::<u64>() {
+                Ok(every) if every > 0 => every,
+                _ => return Err(invalid("UNKNOWN_PATHS_LOG_EVERY", &value)),
+            };
+        }
+        Ok(config)
+    }
+}
+
+/// `path` without its query string, cut to [`MAX_PATH_LEN`] bytes
+pub fn normalize(path: &str) -> String {
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    if path.len() <= MAX_PATH_LEN {
+        return path.to_string();
+    }
+    let mut end = MAX_PATH_LEN;
+    while !path.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &path[..end])
+}
+
+/// Requests for one unknown path
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnknownPath {
+    pub path: String,
+    pub count: u64,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Most requested unknown paths, and how many paths were forgotten
+#[derive(Debug, Clone, Serialize)]
+pub struct UnknownPathsReport {
+    pub paths: Vec<UnknownPath>,
+    pub tracked: usize,
+    pub evicted: u64,
+}
+
+/// Counts of unknown paths; clones share them
+#[derive(Debug, Clone)]
+pub struct UnknownPaths {
+    clock: Arc<dyn Clock>,
+    store: Arc<Mutex<BoundedMap<UnknownPath>>>,
+}
+
+impl Default for UnknownPaths {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl UnknownPaths {
+    /// Empty table, with first and last seen times taken from `clock`
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let store = BoundedMap::new("unknown_paths", UnknownPathsConfig::default().max_paths, None, clock.clone());
+        Self { clock, store: Arc::new(Mutex::new(store)) }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(self, metrics: &crate::metrics::Metrics) -> Self {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).set_metrics(metrics);
+        self
+    }
+
+    /// Count a request for `path`, already normalized, and return how many
+    /// requests it has had
+    pub fn record(&self, path: &str, config: &UnknownPathsConfig) -> u64 {
+        let now = DateTime::<Utc>::from(self.clock.now());
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        store.set_limits(config.max_paths, None);
+        if let Some(entry) = store.get_mut(path) {
+            entry.count += 1;
+            entry.last_seen = now;
+            return entry.count;
+        }
+
+        while store.is_full() {
+            // The least requested path goes, the longest unseen of those
+            let Some(least) = store
+                .iter()
+                .map(|(_, entry)| entry)
+                .min_by_key(|entry| (entry.count, entry.last_seen))
+                .map(|entry| entry.path.clone())
+            else {
+                break;
+            };
+            store.evict(&least);
+        }
+        let entry = UnknownPath { path: path.to_string(), count: 1, first_seen: now, last_seen: now };
+        store.insert(path.to_string(), entry);
+        1
+    }
+
+    /// The `limit` most requested paths, most recently seen first on ties
+    pub fn top(&self, limit: usize) -> UnknownPathsReport {
+        let store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        let mut paths: Vec<UnknownPath> = store.iter().map(|(_, entry)| entry.clone()).collect();
+        paths.sort_by(|a, b| {
+            b.count.cmp(&a.count).then(b.last_seen.cmp(&a.last_seen)).then_with(|| a.path.cmp(&b.path))
+        });
+        paths.truncate(limit);
+        UnknownPathsReport { paths, tracked: store.len(), evicted: store.stats().evictions }
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).stats()
+    }
+
+    /// Paths never expire, so there is nothing to sweep
+    pub fn sweep(&self) -> usize {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).sweep()
+    }
+}
+
+/// Count 404s for requests that matched no route, as `state.request_events`
+/// reports them
+pub fn subscribe(state: &AppState) {
+    let app = state.clone();
+    state.request_events.subscribe(SUBSCRIBER, move |event| {
+        if event.route.is_none() && event.status == StatusCode::NOT_FOUND {
+            let path = normalize(&event.path);
+            let config = app.config().unknown_paths;
+            let count = app.unknown_paths.record(&path, &config);
+            if count == 1 || count.is_multiple_of(config.log_every) {
+                info!(path = %path, count, "Request for an unknown path");
+            }
+        }
+        async {}
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/wp-login.php?token=secret"), "/wp-login.php");
+        assert_eq!(normalize("/a"), "/a");
+
+        let long = format!("/{}", "é".repeat(100));
+        let normalized = normalize(&long);
+        assert!(normalized.len() <= MAX_PATH_LEN + 3, "{}", normalized);
+        assert!(normalized.ends_with("..."));
+    }
+}
@@ -0,0 +1,192 @@
+/*!
+ * WebSocket echo at `/ws`, for checking that the tunnel carries WebSockets
+ *
+ * The handshake, framing and close handshake are axum's
+ * (`axum::extract::ws`), so this only works over a real listener (not with
+ * in-process `oneshot` requests, which get 426). The session:
+ *
+ * - echoes text and binary messages (fragmented or not) as one frame
+ * - answers pings with pongs carrying the same payload
+ * - closes with 1009 for a message over `DEBUG_WS_MAX_MESSAGE_BYTES`
+ * - closes with 1000 after `DEBUG_WS_IDLE_TIMEOUT_SECS` without a frame
+ * - closes with 1001 when draining (`AppState::set_ready(false)`)
+ *
+ * Open sessions are tracked in the `websocket_connections` gauge.
+ */
+use crate::error::ApiError;
+use crate::state::AppState;
+use axum::{
+    extract::{
+        ws::{close_code, rejection::WebSocketUpgradeRejection, CloseCode, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// How long to wait for the peer's close frame after sending ours
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// WebSocket echo
+#[utoipa::path(
+    get,
+    path = "/ws",
+    responses((status = SWITCHING_PROTOCOLS, description = "Switching to the WebSocket protocol")),
+)]
+pub async fn upgrade(
+    State(state): State<AppState>,
+    upgrade: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+) -> Response {
+    let upgrade = match upgrade {
+        Ok(upgrade) => upgrade,
+        Err(rejection) => return rejected(rejection),
+    };
+
+    let debug_config = &state.config().debug;
+    let max_message = debug_config.ws_max_message_bytes;
+    let idle_timeout = debug_config.ws_idle_timeout;
+    upgrade
+        .max_message_size(max_message)
+        .max_frame_size(max_message)
+        .on_failed_upgrade(|e| warn!("WebSocket upgrade failed: {}", e))
+        .on_upgrade(move |socket| run(socket, idle_timeout, state))
+}
+
+/// The service's own error bodies for requests that cannot be upgraded
+fn rejected(rejection: WebSocketUpgradeRejection) -> Response {
+    match rejection {
+        WebSocketUpgradeRejection::InvalidConnectionHeader(_) | WebSocketUpgradeRejection::InvalidUpgradeHeader(_) => (
+            [(header::UPGRADE, "websocket")],
+            ApiError::new(StatusCode::UPGRADE_REQUIRED, "websocket_required", "Expected a WebSocket upgrade"),
+        )
+            .into_response(),
+        WebSocketUpgradeRejection::InvalidWebSocketVersionHeader(_) => (
+            [(header::SEC_WEBSOCKET_VERSION, "13")],
+            ApiError::new(StatusCode::UPGRADE_REQUIRED, "websocket_version_unsupported", "Only WebSocket version 13 is supported"),
+        )
+            .into_response(),
+        WebSocketUpgradeRejection::WebSocketKeyHeaderMissing(_) => {
+            ApiError::new(StatusCode::BAD_REQUEST, "websocket_key_missing", "Missing Sec-WebSocket-Key").into_response()
+        }
+        // In-process requests and HTTP/2 connections
+        WebSocketUpgradeRejection::ConnectionNotUpgradable(_) => {
+            ApiError::new(StatusCode::UPGRADE_REQUIRED, "websocket_unavailable", "This connection cannot be upgraded")
+                .into_response()
+        }
+        rejection => rejection.into_response(),
+    }
+}
+
+/// Counts the session in `websocket_connections` for as long as it lives
+#[cfg(feature = "metrics")]
+struct ConnectionGuard(crate::metrics::Gauge);
+
+#[cfg(feature = "metrics")]
+impl ConnectionGuard {
+    fn new(state: &AppState) -> Self {
+        let gauge = state.metrics.gauge("websocket_connections", "Open /ws connections");
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+async fn run(mut socket: WebSocket, idle_timeout: Duration, state: AppState) {
+    #[cfg(feature = "metrics")]
+    let _guard = ConnectionGuard::new(&state);
+
+    let draining = state.draining();
+    tokio::pin!(draining);
+
+    let (code, reason) = tokio::select! {
+        closing = echo(&mut socket, idle_timeout) => match closing {
+            Some(closing) => closing,
+            None => return,
+        },
+        _ = &mut draining => (close_code::AWAY, "server shutting down"),
+    };
+
+    debug!("Closing WebSocket with {} ({})", code, reason);
+    let frame = CloseFrame { code, reason: reason.into() };
+    if socket.send(Message::Close(Some(frame))).await.is_err() {
+        return;
+    }
+    // Give the peer a moment to answer with its own close frame
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async { while let Some(Ok(_)) = socket.recv().await {} }).await;
+}
+
+/// Echo messages until the session should end. Returns the close frame to
+/// send, or `None` when the connection is already finished.
+async fn echo(socket: &mut WebSocket, idle_timeout: Duration) -> Option<(CloseCode, &'static str)> {
+    loop {
+        let message = match tokio::time::timeout(idle_timeout, socket.recv()).await {
+            Err(_) => return Some((close_code::NORMAL, "idle timeout")),
+            Ok(None) => return None,
+            Ok(Some(Err(e))) => return close_for(e),
+            Ok(Some(Ok(message))) => message,
+        };
+        match message {
+            Message::Text(_) | Message::Binary(_) => socket.send(message).await.ok()?,
+            // Pongs and the reply to a close frame are sent by axum itself
+            Message::Ping(_) | Message::Pong(_) | Message::Close(_) => {}
+        }
+    }
+}
+
+/// Close frame for a message the peer should not have sent, or `None` when
+/// the connection itself failed
+fn close_for(error: axum::Error) -> Option<(CloseCode, &'static str)> {
+    let error = error.into_inner();
+    let closing = match error.downcast_ref::<tungstenite::Error>() {
+        Some(tungstenite::Error::Capacity(_)) => Some((close_code::SIZE, "message too big")),
+        Some(tungstenite::Error::Utf8) => Some((close_code::INVALID, "text message is not UTF-8")),
+        Some(tungstenite::Error::Protocol(_)) => Some((close_code::PROTOCOL, "protocol error")),
+        _ => None,
+    };
+    if closing.is_none() {
+        debug!("WebSocket connection ended without a close frame: {}", error);
+    }
+    closing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestClient;
+    use axum::{routing::get, Router};
+    use serde_json::Value;
+
+    fn client(version: &str) -> TestClient {
+        TestClient::from_router(Router::new().route("/ws", get(upgrade)).with_state(AppState::default()))
+            .with_header("connection", "upgrade")
+            .with_header("upgrade", "websocket")
+            .with_header("sec-websocket-version", version)
+            .with_header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_api_errors() {
+        let plain = TestClient::from_router(Router::new().route("/ws", get(upgrade)).with_state(AppState::default()));
+        let response = plain.get("/ws").await;
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.header("upgrade"), Some("websocket"));
+        assert_eq!(response.json::<Value>()["error"], "websocket_required");
+
+        let response = client("8").get("/ws").await;
+        assert_eq!(response.header("sec-websocket-version"), Some("13"));
+        assert_eq!(response.json::<Value>()["error"], "websocket_version_unsupported");
+
+        // In-process requests have no connection to take over
+        let response = client("13").get("/ws").await;
+        assert_eq!(response.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(response.json::<Value>()["error"], "websocket_unavailable");
+    }
+}
@@ -0,0 +1,117 @@
+//! Access log file: separate stream, JSON lines and rotation
+
+use cloudflare_tunnel_example::access_log::{self, rotated, AccessLogConfig, AccessLogSink};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::prelude::*;
+
+fn log_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("access-log-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn json_lines(path: &std::path::Path) -> Vec<serde_json::Value> {
+    let contents = std::fs::read_to_string(path).unwrap();
+    assert!(contents.ends_with('\n'), "{} ends mid-line", path.display());
+    contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{:?} in {}: {}", line, path.display(), e)))
+        .collect()
+}
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_rotation_keeps_whole_json_lines() {
+    let dir = log_dir("rotation");
+    let config = AccessLogConfig { path: dir.join("access.log"), max_size_bytes: 2048, keep: 3 };
+    let sink = AccessLogSink::default();
+    sink.open(&config).unwrap();
+    let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(sink.layer()));
+
+    for i in 0..400u64 {
+        tracing::info!(target: access_log::TARGET, method = "GET", uri = format!("/items/{}", i), status = 200u64);
+        tracing::info!("application event {}", i);
+    }
+    sink.flush();
+    assert_eq!(sink.dropped(), 0);
+
+    let mut files = vec![config.path.clone()];
+    files.extend((1..=3).map(|index| rotated(&config.path, index)));
+    let mut seen = Vec::new();
+    for file in files.iter().rev() {
+        assert!(std::fs::metadata(file).unwrap().len() <= config.max_size_bytes, "{}", file.display());
+        for line in json_lines(file) {
+            assert!(line["time"].is_string(), "{}", line);
+            assert_eq!(line["status"], 200);
+            seen.push(line["uri"].as_str().unwrap().to_string());
+        }
+    }
+    assert!(!rotated(&config.path, 4).exists());
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 4);
+
+    // The newest lines survive, in order, with nothing from the application log
+    let expected: Vec<String> = (400 - seen.len()..400).map(|i| format!("/items/{}", i)).collect();
+    assert_eq!(seen, expected);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn test_requests_go_to_the_file_and_not_stdout() {
+    let dir = log_dir("requests");
+    let config = AccessLogConfig { path: dir.join("access.log"), max_size_bytes: 1 << 20, keep: 1 };
+    let sink = AccessLogSink::default();
+    sink.open(&config).unwrap();
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let app_filter = tracing_subscriber::EnvFilter::new(format!("debug,{}=off", access_log::TARGET));
+    let subscriber = tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .with_filter(app_filter),
+        )
+        .with(sink.layer());
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let state = AppState::new(AppConfig { access_log: Some(config.clone()), ..AppConfig::default() });
+    let client = TestClient::from_state(state).with_header("user-agent", "probe/1.0");
+    let response = client.get("/health?verbose=1").await;
+    let request_id = response.header("x-request-id").unwrap().to_string();
+    sink.flush();
+
+    let lines = json_lines(&config.path);
+    assert_eq!(lines.len(), 1);
+    let line = &lines[0];
+    assert_eq!(line["method"], "GET");
+    assert_eq!(line["uri"], "/health?verbose=1");
+    assert_eq!(line["status"], 200);
+    assert_eq!(line["request_id"], request_id.as_str());
+    assert_eq!(line["user_agent"], "probe/1.0");
+    assert!(line["duration_ms"].as_f64().unwrap() >= 0.0, "{}", line);
+
+    let app_logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(!app_logs.is_empty());
+    assert!(!app_logs.contains("duration_ms") && !app_logs.contains(" access:"), "{}", app_logs);
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,119 @@
+//! Malformed request heads sent over a real socket, whether hyper or the
+//! `anomalies` stage turns them away
+
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::{create_app, ServerHandle};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+async fn start(state: &AppState) -> ServerHandle {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    ServerHandle::start(listener, create_app(state.clone())).expect("Failed to start server")
+}
+
+/// Everything the server sends back for `raw` until it closes the
+/// connection
+async fn exchange(server: &ServerHandle, raw: &str) -> String {
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    stream.write_all(raw.as_bytes()).await.unwrap();
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+        .await
+        .expect("Connection left open")
+        .unwrap();
+    String::from_utf8_lossy(&received).into_owned()
+}
+
+fn status_line(response: &str) -> &str {
+    response.lines().next().unwrap_or_default()
+}
+
+/// The `error` code of a JSON error body
+fn error_code(response: &str) -> Value {
+    let (_, body) = response.split_once("\r\n\r\n").expect("No response head");
+    serde_json::from_str::<Value>(body).map(|json| json["error"].clone()).unwrap_or(Value::Null)
+}
+
+#[tokio::test]
+async fn test_absolute_form_target_is_rejected() {
+    let state = AppState::default();
+    let server = start(&state).await;
+
+    for target in ["http://evil.example/health", "https://localhost/health"] {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", target);
+        let response = exchange(&server, &raw).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request", "{}", response);
+        assert_eq!(error_code(&response), "absolute_form_target");
+        assert!(response.to_ascii_lowercase().contains("x-denial-reason: absolute_form_target"), "{}", response);
+    }
+    let response = exchange(&server, "CONNECT evil.example:443 HTTP/1.1\r\nHost: evil.example:443\r\nConnection: close\r\n\r\n").await;
+    assert_eq!(error_code(&response), "absolute_form_target");
+
+    // The same request in origin-form goes through
+    let response = exchange(&server, "GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+    assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+
+    #[cfg(feature = "metrics")]
+    assert!(state.metrics.render().contains("protocol_anomalies_total{reason=\"absolute_form_target\"} 3"));
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_duplicate_host_is_rejected() {
+    let state = AppState::default();
+    let server = start(&state).await;
+
+    let raw = "GET /health HTTP/1.1\r\nHost: localhost\r\nHost: internal.example\r\nConnection: close\r\n\r\n";
+    let response = exchange(&server, raw).await;
+    assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request", "{}", response);
+    assert_eq!(error_code(&response), "duplicate_host");
+
+    #[cfg(feature = "metrics")]
+    assert!(state.metrics.render().contains("protocol_anomalies_total{reason=\"duplicate_host\"} 1"));
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_conflicting_framing_cannot_smuggle_a_request() {
+    let server = start(&AppState::default()).await;
+
+    // Read as chunked, the body ends at `0`; read by its Content-Length, it
+    // would end partway and the rest would be a second request
+    let smuggled = "GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let raw = format!(
+        "POST /health HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n{}",
+        smuggled
+    );
+    let response = exchange(&server, &raw).await;
+
+    // One response, to the POST, and the connection is closed before the
+    // bytes after the body are read as a request
+    assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{}", response);
+    assert_eq!(status_line(&response), "HTTP/1.1 405 Method Not Allowed");
+    assert!(response.to_ascii_lowercase().contains("connection: close"), "{}", response);
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_malformed_framing_and_folding_never_reach_the_app() {
+    let server = start(&AppState::default()).await;
+
+    for raw in [
+        // Obsolete line folding
+        "GET /health HTTP/1.1\r\nHost: localhost\r\nX-Folded: one\r\n two\r\n\r\n",
+        // Transfer-Encoding that does not end in chunked
+        "POST /health HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked, gzip\r\n\r\n0\r\n\r\n",
+        // Transfer-Encoding on HTTP/1.0
+        "POST /health HTTP/1.0\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        // Content-Length headers that disagree
+        "POST /health HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+    ] {
+        let response = exchange(&server, raw).await;
+        assert_eq!(status_line(&response), "HTTP/1.1 400 Bad Request", "{:?}", raw);
+        // hyper's own response, without the app's request ID
+        assert!(!response.to_ascii_lowercase().contains("x-request-id"), "{}", response);
+    }
+    server.stop().await.unwrap();
+}
@@ -0,0 +1,129 @@
+//! `X-Api-Key` authentication, per-key rate limits and log redaction
+
+use axum::http::StatusCode;
+use axum::{middleware, routing::get, Extension, Router};
+use cloudflare_tunnel_example::api_keys::{self, ApiKey, ApiKeyConfig, ApiKeyName};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+fn keys() -> ApiKeyConfig {
+    ApiKeyConfig::new(
+        [ApiKey::new("reporting", "abc123"), ApiKey::new("backup", "def456")],
+        ["/robots.txt", "/whoami"],
+    )
+}
+
+fn state(api_keys: ApiKeyConfig) -> AppState {
+    AppState::new(AppConfig {
+        api_keys: Some(api_keys),
+        ..AppConfig::default()
+    })
+}
+
+/// Router answering with the key name the middleware resolved
+fn whoami_client(state: AppState) -> TestClient {
+    let router = Router::new()
+        .route("/whoami", get(|Extension(ApiKeyName(name)): Extension<ApiKeyName>| async move { name.to_string() }))
+        .layer(middleware::from_fn_with_state(state.clone(), api_keys::require_api_key))
+        .with_state(state);
+    TestClient::from_router(router)
+}
+
+#[tokio::test]
+async fn test_key_name_is_resolved() {
+    let state = state(keys());
+
+    for (key, name) in [("abc123", "reporting"), ("def456", "backup")] {
+        let response = whoami_client(state.clone()).with_header("x-api-key", key).get("/whoami").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text(), name);
+    }
+}
+
+#[tokio::test]
+async fn test_missing_and_unknown_keys_are_rejected() {
+    let client = TestClient::from_state(state(keys()));
+
+    let missing = client.get("/robots.txt").await;
+    assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(missing.header("www-authenticate"), Some("ApiKey header=\"X-Api-Key\""));
+    let json: serde_json::Value = missing.json();
+    assert_eq!(json["error"], "unauthorized");
+
+    // A revoked key is simply no longer listed
+    let revoked = TestClient::from_state(state(keys())).with_header("x-api-key", "old-key").get("/robots.txt").await;
+    assert_eq!(revoked.status(), StatusCode::UNAUTHORIZED);
+
+    let unprotected = client.get("/health").await;
+    assert_eq!(unprotected.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_per_key_rate_limit_override() {
+    let mut config = keys();
+    config.rate_limit = Some(1);
+    config.keys[1].rate_limit = Some(3);
+    let state = state(config);
+
+    let statuses = |key: &'static str| {
+        let state = state.clone();
+        async move {
+            let client = TestClient::from_state(state).with_header("x-api-key", key);
+            let mut statuses = Vec::new();
+            for _ in 0..4 {
+                statuses.push(client.get("/robots.txt").await.status());
+            }
+            statuses
+        }
+    };
+
+    let reporting = statuses("abc123").await;
+    assert_eq!(reporting[..2], [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+
+    let backup = statuses("def456").await;
+    assert_eq!(backup, [StatusCode::OK, StatusCode::OK, StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]);
+
+    let limited = TestClient::from_state(state).with_header("x-api-key", "abc123").get("/robots.txt").await;
+    assert!(limited.header_as::<u64>("retry-after").is_some_and(|secs| (1..=60).contains(&secs)));
+    let json: serde_json::Value = limited.json();
+    assert_eq!(json["error"], "rate_limited");
+}
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_logs_carry_key_name_but_never_the_key() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let state = state(keys());
+    TestClient::from_state(state.clone()).with_header("x-api-key", "abc123").get("/robots.txt").await;
+    TestClient::from_state(state).with_header("x-api-key", "not-a-key-xyz").get("/robots.txt").await;
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("api_key=\"reporting\""), "{}", logs);
+    assert!(logs.contains("Rejected unknown API key"), "{}", logs);
+    assert!(!logs.contains("abc123"), "{}", logs);
+    assert!(!logs.contains("not-a-key-xyz"), "{}", logs);
+}
@@ -0,0 +1,77 @@
+//! Precompressed embedded assets, picked by `Accept-Encoding`
+
+use axum::http::StatusCode;
+use cloudflare_tunnel_example::assets::{Encoding, Variant, FAVICON};
+use cloudflare_tunnel_example::conditional::strong_etag;
+use cloudflare_tunnel_example::decompression::{decompress, ContentEncoding};
+use cloudflare_tunnel_example::testing::{TestClient, TestResponse};
+
+async fn favicon(accept_encoding: Option<&str>) -> TestResponse {
+    let mut client = TestClient::from_state(Default::default());
+    if let Some(value) = accept_encoding {
+        client = client.with_header("accept-encoding", value);
+    }
+    let response = client.get("/favicon.ico").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    response
+}
+
+fn decode(response: &TestResponse) -> Vec<u8> {
+    let body = response.bytes();
+    match response.header("content-encoding") {
+        None => body.to_vec(),
+        Some("gzip") => decompress(ContentEncoding::Gzip, body, usize::MAX).unwrap(),
+        Some("br") => decompress(ContentEncoding::Brotli, body, usize::MAX).unwrap(),
+        Some(other) => panic!("Unexpected encoding {}", other),
+    }
+}
+
+/// The smaller compressed variant, the one a client accepting both gets
+fn smaller() -> Variant {
+    if FAVICON.br.body.len() <= FAVICON.gzip.body.len() { FAVICON.br } else { FAVICON.gzip }
+}
+
+#[tokio::test]
+async fn test_accept_encoding_picks_the_variant() {
+    let cases = [
+        (None, FAVICON.identity),
+        (Some("identity"), FAVICON.identity),
+        (Some("gzip"), FAVICON.gzip),
+        (Some("br"), FAVICON.br),
+        (Some("br;q=0.5, gzip"), FAVICON.gzip),
+        (Some("gzip, deflate, br, zstd"), smaller()),
+        (Some("*"), smaller()),
+        (Some("zstd"), FAVICON.identity),
+    ];
+    for (accept_encoding, expected) in cases {
+        let response = favicon(accept_encoding).await;
+        let encoding = (expected.encoding != Encoding::Identity).then(|| expected.encoding.token());
+        assert_eq!(response.header("content-encoding"), encoding, "{:?}", accept_encoding);
+        assert_eq!(response.header("vary"), Some("accept-encoding"), "{:?}", accept_encoding);
+        assert_eq!(response.header("etag"), Some(expected.etag), "{:?}", accept_encoding);
+        assert_eq!(response.bytes().as_ref(), expected.body, "{:?}", accept_encoding);
+        assert_eq!(decode(&response), FAVICON.identity.body, "{:?}", accept_encoding);
+    }
+}
+
+#[tokio::test]
+async fn test_each_variant_has_a_stable_etag() {
+    let variants = [FAVICON.identity, FAVICON.gzip, FAVICON.br];
+    for variant in variants {
+        assert_eq!(strong_etag(variant.body).to_str().unwrap(), variant.etag, "{:?}", variant.encoding);
+    }
+    assert!(variants[0].etag != variants[1].etag && variants[1].etag != variants[2].etag);
+
+    let first = favicon(Some("br")).await;
+    let again = favicon(Some("br")).await;
+    assert_eq!(first.header("etag"), again.header("etag"));
+
+    // A client holding one variant only revalidates that one
+    let client = TestClient::from_state(Default::default()).with_header("if-none-match", FAVICON.br.etag);
+    let response = client.clone().with_header("accept-encoding", "br").get("/favicon.ico").await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.header("content-encoding"), Some("br"));
+    let response = client.with_header("accept-encoding", "gzip").get("/favicon.ico").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.header("etag"), Some(FAVICON.gzip.etag));
+}
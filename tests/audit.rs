@@ -0,0 +1,164 @@
+//! Audit log of admin actions
+
+use axum::body::Body;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::Router;
+use cloudflare_tunnel_example::access::{AccessConfig, ACCESS_JWT};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::api_keys::{ApiKey, ApiKeyConfig};
+use cloudflare_tunnel_example::audit::AuditConfig;
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::state::AppState;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+/// Test-only signing key standing in for the Access team's
+const ACCESS_KEY: &str = include_str!("fixtures/access-test-key.pem");
+
+/// Modulus of [`ACCESS_KEY`], base64url
+const ACCESS_KEY_N: &str = "6cuabEuc8S7Udq5NMXqhqi6vpc5Efu1TIP_96ZLeSXjInU9-Y3sIJ3JRQtho_PJtgJ4khwbuok2U7FNsrAeNcY7kYjHgCTUznS8pV--ZBZNI5BJ9Yur_v1NNbV4FH2E6EOZnP4gNSRX-CqSYOndg_1-4EhB8E_f9sCeR9QfJA64LgKED-ijdcGPCufTb2dgQ4GOlnYXkdXxTiLYYrvFzj62CkDBmYE1Q8fShieKxc3r6c0y1Ae1rMcR_0lcjzL88Oq2V4K5BA35nsYiOZNF4TMyVAqC-bBreYAf9IQDS3kXdo2_xYNaSDDmXyLco-vyXpslGj-8aloEppxY75yrsHw";
+
+const TEAM: &str = "acme.cloudflareaccess.com";
+const AUDIENCE: &str = "app-audience-tag";
+
+/// Access settings trusting [`ACCESS_KEY`], served by a stub certs endpoint
+async fn access_config() -> AccessConfig {
+    let certs = json!({ "keys": [{ "kid": "test-key", "kty": "RSA", "alg": "RS256", "use": "sig", "e": "AQAB", "n": ACCESS_KEY_N }] });
+    let app = Router::new().route("/cdn-cgi/access/certs", axum::routing::get(move || async move { axum::Json(certs) }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind certs stub");
+    let addr = listener.local_addr().expect("Certs stub has no address");
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.expect("Certs stub failed");
+    });
+    AccessConfig { certs_url: format!("http://{}/cdn-cgi/access/certs", addr), ..AccessConfig::new(TEAM, AUDIENCE) }
+}
+
+/// An Access token for `email`, signed with [`ACCESS_KEY`]
+fn access_token(email: &str, audience: &str) -> String {
+    let header = Header { kid: Some("test-key".to_string()), ..Header::new(Algorithm::RS256) };
+    let claims = json!({
+        "email": email,
+        "aud": [audience],
+        "iss": format!("https://{}", TEAM),
+        "exp": chrono::Utc::now().timestamp() + 600,
+    });
+    let key = EncodingKey::from_rsa_pem(ACCESS_KEY.as_bytes()).expect("Invalid test key");
+    jsonwebtoken::encode(&header, &claims, &key).expect("Failed to sign token")
+}
+
+fn admin_app(config: AppConfig) -> (Router, AppState) {
+    let state = AppState::new(config);
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    (create_admin_app(&admin, &state), state)
+}
+
+fn request(method: Method, uri: &str, identity: (&str, &str), body: Option<Value>) -> Request<Body> {
+    let builder = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, "Bearer admin-secret")
+        .header(identity.0, identity.1)
+        .header(header::CONTENT_TYPE, "application/json");
+    builder.body(body.map_or_else(Body::empty, |body| Body::from(body.to_string()))).unwrap()
+}
+
+async fn audit(app: &Router, query: &str) -> Value {
+    let request = Request::builder()
+        .uri(format!("/admin/audit{}", query))
+        .header(header::AUTHORIZATION, "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_actions_are_attributed_and_ordered() {
+    let api_keys = ApiKeyConfig::new([ApiKey::new("deploy-bot", "bot-key")], ["/api"]);
+    let access = access_config().await;
+    let (app, state) = admin_app(AppConfig { api_keys: Some(api_keys), access: Some(access), ..AppConfig::default() });
+
+    let token = access_token("alice@example.com", AUDIENCE);
+    let enable = request(
+        Method::POST,
+        "/admin/maintenance",
+        (ACCESS_JWT.as_str(), &token),
+        Some(json!({ "enabled": true, "message": "Deploying" })),
+    );
+    let response = app.clone().oneshot(enable).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request_id = response.headers()["x-request-id"].to_str().unwrap().to_string();
+
+    let disable = request(Method::POST, "/admin/maintenance", ("x-api-key", "bot-key"), Some(json!({ "enabled": false })));
+    assert_eq!(app.clone().oneshot(disable).await.unwrap().status(), StatusCode::OK);
+    assert!(!state.maintenance.is_enabled());
+
+    // Reads are not actions, and a rejected action is still recorded
+    let stats = request(Method::GET, "/admin/stats", ("x-api-key", "bot-key"), None);
+    app.clone().oneshot(stats).await.unwrap();
+    let rollback = request(Method::POST, "/admin/config/rollback", ("x-api-key", "unknown"), Some(json!({ "version": 99 })));
+    assert_eq!(app.clone().oneshot(rollback).await.unwrap().status(), StatusCode::NOT_FOUND);
+
+    let json = audit(&app, "").await;
+    assert_eq!(json["record_failures"], 0);
+    let entries = json["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 3, "{}", json);
+
+    let sequences: Vec<u64> = entries.iter().map(|e| e["sequence"].as_u64().unwrap()).collect();
+    assert_eq!(sequences, [3, 2, 1]);
+
+    let first = &entries[2];
+    assert_eq!(first["identity"], json!({ "kind": "access", "name": "alice@example.com" }));
+    assert_eq!((&first["method"], &first["path"], &first["status"]), (&json!("POST"), &json!("/admin/maintenance"), &json!(200)));
+    assert_eq!(first["request_id"], request_id.as_str());
+    assert_eq!(first["body"], r#"{"enabled":true,"message":"Deploying"}"#);
+    assert!(first["at"].as_str().unwrap().ends_with('Z'));
+
+    assert_eq!(entries[1]["identity"], json!({ "kind": "api_key", "name": "deploy-bot" }));
+    assert_eq!(entries[1]["body"], r#"{"enabled":false}"#);
+
+    // An unknown key is no identity; the admin token is
+    assert_eq!(entries[0]["identity"], json!({ "kind": "token", "name": "admin" }));
+    assert_eq!((&entries[0]["path"], &entries[0]["status"]), (&json!("/admin/config/rollback"), &json!(404)));
+
+    assert_eq!(audit(&app, "?limit=1").await["entries"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_unverified_access_claims_are_not_trusted() {
+    let access = access_config().await;
+    let (app, _) = admin_app(AppConfig { access: Some(access), ..AppConfig::default() });
+
+    // The email header alone is whatever the caller chose to send
+    let forged = request(Method::POST, "/admin/maintenance", ("cf-access-authenticated-user-email", "ceo@example.com"), Some(json!({ "enabled": true })));
+    assert_eq!(app.clone().oneshot(forged).await.unwrap().status(), StatusCode::OK);
+
+    // A token for another application, and one that is not a token at all
+    let foreign = access_token("mallory@example.com", "other-app");
+    for token in [foreign.as_str(), "not-a-jwt"] {
+        let action = request(Method::POST, "/admin/maintenance", (ACCESS_JWT.as_str(), token), Some(json!({ "enabled": false })));
+        assert_eq!(app.clone().oneshot(action).await.unwrap().status(), StatusCode::OK);
+    }
+
+    let json = audit(&app, "").await;
+    let entries = json["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 3, "{}", json);
+    for entry in entries {
+        assert_eq!(entry["identity"], json!({ "kind": "token", "name": "admin" }), "{}", entry);
+    }
+}
+
+#[tokio::test]
+async fn test_ring_keeps_the_newest_entries() {
+    let (app, _) = admin_app(AppConfig { audit: AuditConfig { max_entries: 2 }, ..AppConfig::default() });
+    for enabled in [true, false, true] {
+        let toggle = request(Method::POST, "/admin/maintenance", ("x-operator", "ops"), Some(json!({ "enabled": enabled })));
+        app.clone().oneshot(toggle).await.unwrap();
+    }
+
+    let json = audit(&app, "").await;
+    let sequences: Vec<u64> = json["entries"].as_array().unwrap().iter().map(|e| e["sequence"].as_u64().unwrap()).collect();
+    assert_eq!(sequences, [3, 2]);
+}
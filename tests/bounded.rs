@@ -0,0 +1,99 @@
+//! Per-key stores stay within their capacity however many keys arrive
+
+use axum::extract::Path;
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::api_keys::{ApiKey, ApiKeyConfig};
+use cloudflare_tunnel_example::bounded::{self, StoreStats};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::response_cache::{self, ResponseCacheConfig, ResponseCacheRule};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient};
+use cloudflare_tunnel_example::unknown_paths::UnknownPathsConfig;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn state() -> (AppState, Arc<TestClock>) {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let config = AppConfig {
+        unknown_paths: UnknownPathsConfig { max_paths: 50, ..UnknownPathsConfig::default() },
+        response_cache: ResponseCacheConfig {
+            rules: vec![ResponseCacheRule::new("/reports", 10)],
+            max_entries: 20,
+            ..ResponseCacheConfig::default()
+        },
+        ..AppConfig::default()
+    };
+    (AppState::with_clock(config, clock.clone()), clock)
+}
+
+/// Router caching `/reports/:name` through `state`'s response cache
+fn cached(state: &AppState) -> TestClient {
+    let router = Router::new()
+        .route("/reports/:name", get(|Path(name): Path<String>| async move { name }))
+        .layer(middleware::from_fn_with_state(state.clone(), response_cache::serve_cached))
+        .with_state(state.clone());
+    TestClient::from_router(router)
+}
+
+fn api_keys() -> ApiKeyConfig {
+    ApiKeyConfig::new([ApiKey::new("reporting", "abc123"), ApiKey::new("backup", "def456")], ["/api"])
+}
+
+#[tokio::test]
+async fn test_capacity_holds_under_churn() {
+    let (state, _) = state();
+    let client = cached(&state);
+    let api_keys = api_keys();
+
+    for i in 0..1000 {
+        state.unknown_paths.record(&format!("/probe/{}", i), &state.config().unknown_paths);
+        state.api_key_limiter.check(&format!("key-{}", i), 10, &api_keys).unwrap();
+    }
+    for i in 0..200 {
+        assert_eq!(client.get(&format!("/reports/{}", i)).await.header("x-cache"), Some("MISS"));
+    }
+
+    let stats = bounded::stats(&state);
+    assert_eq!(stats["unknown_paths"], StoreStats { entries: 50, capacity: 50, evictions: 950, expirations: 0 });
+    assert_eq!(stats["api_keys"], StoreStats { entries: 2, capacity: 2, evictions: 998, expirations: 0 });
+    assert_eq!(stats["response_cache"], StoreStats { entries: 20, capacity: 20, evictions: 180, expirations: 0 });
+
+    // The most recent entries are the ones kept
+    assert_eq!(client.get("/reports/199").await.header("x-cache"), Some("HIT"));
+    assert_eq!(client.get("/reports/0").await.header("x-cache"), Some("MISS"));
+
+    #[cfg(feature = "metrics")]
+    {
+        let metrics = state.metrics.render();
+        assert!(metrics.contains("store_entries{store=\"unknown_paths\"} 50"), "{}", metrics);
+        assert!(metrics.contains("store_evictions_total{store=\"api_keys\",reason=\"capacity\"} 998"), "{}", metrics);
+    }
+}
+
+#[tokio::test]
+async fn test_expired_entries_are_swept() {
+    let (state, clock) = state();
+    let client = cached(&state);
+    let api_keys = api_keys();
+
+    for name in ["reporting", "backup"] {
+        state.api_key_limiter.check(name, 10, &api_keys).unwrap();
+    }
+    for i in 0..5 {
+        client.get(&format!("/reports/{}", i)).await;
+    }
+    assert!(state.response_cache.bytes() > 0);
+    assert_eq!(bounded::sweep(&state), 0);
+
+    // Past the cache TTL, before the end of the rate-limit window
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(bounded::sweep(&state), 5);
+    assert_eq!(state.response_cache.bytes(), 0);
+    clock.advance(Duration::from_secs(50));
+    assert_eq!(bounded::sweep(&state), 2);
+
+    let stats = bounded::stats(&state);
+    assert_eq!((stats["response_cache"].entries, stats["response_cache"].expirations), (0, 5));
+    assert_eq!((stats["api_keys"].entries, stats["api_keys"].expirations), (0, 2));
+    assert_eq!(stats["unknown_paths"].evictions, 0);
+}
@@ -0,0 +1,102 @@
+//! Canary experiments: hashed assignment, sticky cookies and `Vary`
+
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::canary::{self, Assignments, CanaryConfig, Experiment, Variant};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, TestResponse};
+
+fn state(percent: f64) -> AppState {
+    AppState::new(AppConfig {
+        canary: CanaryConfig { experiments: vec![Experiment::new("checkout", percent)] },
+        ..AppConfig::default()
+    })
+}
+
+fn set_cookies(response: &TestResponse) -> Vec<&str> {
+    response.headers().get_all("set-cookie").iter().map(|v| v.to_str().unwrap()).collect()
+}
+
+fn varies_by_cookie(response: &TestResponse) -> bool {
+    response
+        .headers()
+        .get_all("vary")
+        .iter()
+        .flat_map(|v| v.to_str().unwrap().split(','))
+        .any(|name| name.trim().eq_ignore_ascii_case("cookie"))
+}
+
+#[test]
+fn test_percent_of_addresses_land_in_the_canary() {
+    let experiment = Experiment::new("checkout", 20.0);
+    let addresses: Vec<String> = (0..10_000).map(|i| format!("198.18.{}.{}", i / 256, i % 256)).collect();
+    let canary = addresses.iter().filter(|ip| experiment.bucket(ip) == Variant::Canary).count();
+    assert!((1_800..=2_200).contains(&canary), "{} of 10000 in the canary", canary);
+
+    // The same address always lands in the same bucket, but the salt
+    // reshuffles them
+    assert!(addresses.iter().all(|ip| experiment.bucket(ip) == experiment.bucket(ip)));
+    let resalted = Experiment { salt: "another".to_string(), ..experiment.clone() };
+    assert!(addresses.iter().any(|ip| experiment.bucket(ip) != resalted.bucket(ip)));
+
+    assert!(addresses.iter().all(|ip| Experiment::new("none", 0.0).bucket(ip) == Variant::Control));
+    assert!(addresses.iter().all(|ip| Experiment::new("all", 100.0).bucket(ip) == Variant::Canary));
+}
+
+#[tokio::test]
+async fn test_cookie_keeps_the_variant() {
+    let client = TestClient::from_state(state(50.0)).with_header("cf-connecting-ip", "203.0.113.7");
+
+    let first = client.get("/").await;
+    let variant = first.header("x-experiment-checkout").unwrap().to_string();
+    let cookie = set_cookies(&first).into_iter().find(|c| c.starts_with("exp_checkout=")).unwrap().to_string();
+    assert!(cookie.starts_with(&format!("exp_checkout={};", variant)), "{}", cookie);
+    // Hardened on the way out
+    assert!(cookie.contains("Secure") && cookie.contains("HttpOnly") && cookie.contains("SameSite=Lax"), "{}", cookie);
+
+    // Without the cookie yet, the address alone gives the same answer
+    assert_eq!(client.get("/").await.header("x-experiment-checkout"), Some(variant.as_str()));
+
+    // The cookie wins over the address, and is not set again
+    let other = if variant == "canary" { "control" } else { "canary" };
+    let response = client.clone().with_header("cookie", &format!("exp_checkout={}", other)).get("/").await;
+    assert_eq!(response.header("x-experiment-checkout"), Some(other));
+    assert!(set_cookies(&response).iter().all(|c| !c.starts_with("exp_checkout=")));
+
+    // Even at 0%, a visitor already in the canary stays there
+    let client = TestClient::from_state(state(0.0)).with_header("cookie", "exp_checkout=canary");
+    assert_eq!(client.get("/").await.header("x-experiment-checkout"), Some("canary"));
+    // An unknown value is replaced
+    let client = TestClient::from_state(state(0.0)).with_header("cookie", "exp_checkout=bogus");
+    let response = client.get("/").await;
+    assert_eq!(response.header("x-experiment-checkout"), Some("control"));
+    assert!(set_cookies(&response).iter().any(|c| c.starts_with("exp_checkout=control;")));
+}
+
+#[tokio::test]
+async fn test_responses_vary_by_cookie() {
+    let client = TestClient::from_state(state(50.0));
+    let response = client.get("/").await;
+    assert!(varies_by_cookie(&response), "{:?}", response.headers());
+    // Kept next to what the handler varies by
+    assert!(response.headers().get_all("vary").iter().any(|v| v == "accept-language"));
+
+    // Probes are left out
+    let response = client.get("/health").await;
+    assert!(response.header("x-experiment-checkout").is_none());
+    assert!(set_cookies(&response).is_empty() && !varies_by_cookie(&response));
+
+    // Without experiments nothing changes
+    let response = TestClient::from_state(AppState::default()).get("/").await;
+    assert!(response.header("x-experiment-checkout").is_none() && !varies_by_cookie(&response));
+}
+
+#[tokio::test]
+async fn test_handlers_see_the_assignment() {
+    let state = state(100.0);
+    let router = Router::new()
+        .route("/", get(|assignments: Assignments| async move { assignments.to_string() }))
+        .layer(middleware::from_fn_with_state(state, canary::assign));
+    let response = TestClient::from_router(router).get("/").await;
+    assert_eq!(response.text(), "checkout=canary");
+}
@@ -0,0 +1,145 @@
+//! `/admin/capture` sessions recording full exchanges
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use cloudflare_tunnel_example::capture::{CaptureState, BODY_CAP};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient, ADMIN_TOKEN};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+async fn start(state: &AppState, filter: Value) -> Value {
+    let response = TestClient::admin(state, Some(ADMIN_TOKEN)).post_json("/admin/capture", &filter).await;
+    assert_eq!(response.status(), StatusCode::OK, "{}", response.text());
+    response.json()
+}
+
+async fn capture(state: &AppState) -> Value {
+    TestClient::admin(state, Some(ADMIN_TOKEN)).get("/admin/capture").await.json()
+}
+
+async fn exchanges(state: &AppState) -> Vec<Value> {
+    capture(state).await["exchanges"].as_array().cloned().unwrap_or_default()
+}
+
+fn state_with_clock() -> (Arc<TestClock>, AppState) {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let mut state = AppState::default();
+    state.capture = CaptureState::new(clock.clone());
+    (clock, state)
+}
+
+#[tokio::test]
+async fn test_capture_is_admin_only_and_off_by_default() {
+    let state = AppState::default();
+    let response = TestClient::admin(&state, None).post_json("/admin/capture", &json!({})).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = TestClient::admin(&state, Some("wrong")).get("/admin/capture").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    TestClient::from_state(state.clone()).get("/health").await;
+    let json = capture(&state).await;
+    assert_eq!(json["active"], false);
+    assert_eq!(json["exchanges"], json!([]));
+
+    let response = TestClient::admin(&state, Some(ADMIN_TOKEN)).post_json("/admin/capture", &json!({"max": 0})).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.json::<Value>()["error"], "invalid_capture");
+}
+
+#[tokio::test]
+async fn test_prefix_header_and_sampling_filters() {
+    let state = AppState::default();
+    start(&state, json!({"prefix": "/health", "header": {"name": "X-Debug", "value": "1"}, "sample": 2})).await;
+
+    let debug = TestClient::from_state(state.clone()).with_header("x-debug", "1");
+    let plain = TestClient::from_state(state.clone());
+    debug.get("/health").await; // matched 1: captured
+    debug.get("/health").await; // matched 2: sampled out
+    debug.get("/health/extra").await; // matched 3 (404): captured
+    debug.get("/healthz").await; // not on a segment boundary
+    debug.get("/robots.txt").await; // outside the prefix
+    plain.get("/health").await; // header missing
+    TestClient::from_state(state.clone()).with_header("x-debug", "2").get("/health").await;
+
+    let captured = exchanges(&state).await;
+    let uris: Vec<&str> = captured.iter().map(|e| e["request"]["uri"].as_str().unwrap()).collect();
+    assert_eq!(uris, ["/health", "/health/extra"]);
+    assert_eq!(captured[0]["response"]["status"], 200);
+    assert_eq!(captured[1]["response"]["status"], 404);
+
+    assert_eq!(capture(&state).await["matched"], 3);
+}
+
+#[tokio::test]
+async fn test_bodies_are_capped_and_credentials_redacted() {
+    let state = AppState::default();
+    start(&state, json!({"max": 2})).await;
+    let client = TestClient::from_state(state.clone());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer top-secret"));
+    headers.insert(header::COOKIE, HeaderValue::from_static("session=abc"));
+    headers.insert("x-trace", HeaderValue::from_static("visible"));
+    let body = "x".repeat(BODY_CAP + 10);
+    let response = client.send(Method::POST, "/webhooks/unknown", headers, Body::from(body)).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let captured = exchanges(&state).await;
+    let request = &captured[0]["request"];
+    assert_eq!(request["method"], "POST");
+    assert_eq!(request["headers"]["authorization"], "<redacted>");
+    assert_eq!(request["headers"]["cookie"], "<redacted>");
+    assert_eq!(request["headers"]["x-trace"], "visible");
+    assert!(!captured[0].to_string().contains("top-secret"));
+    assert_eq!(request["body"]["bytes"], BODY_CAP + 10);
+    assert_eq!(request["body"]["truncated"], true);
+    assert_eq!(request["body"]["text"].as_str().unwrap().len(), BODY_CAP);
+
+    let response = &captured[0]["response"];
+    assert_eq!(response["headers"]["content-type"], "application/json");
+    assert!(response["body"]["text"].as_str().unwrap().contains("webhook_not_found"));
+    assert_eq!(response["body"]["truncated"], false);
+
+    // Only the latest `max` exchanges are kept
+    client.get("/health").await;
+    client.get("/robots.txt").await;
+    let uris: Vec<Value> = exchanges(&state).await.iter().map(|e| e["request"]["uri"].clone()).collect();
+    assert_eq!(uris, [json!("/health"), json!("/robots.txt")]);
+}
+
+#[tokio::test]
+async fn test_capture_expires_after_ttl() {
+    let (clock, state) = state_with_clock();
+    let json = start(&state, json!({"ttl_seconds": 60})).await;
+    assert_eq!(json["active"], true);
+    assert_eq!(json["expires_at"], "2024-01-01T00:01:00Z");
+
+    let client = TestClient::from_state(state.clone());
+    client.get("/health").await;
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(exchanges(&state).await.len(), 1);
+
+    // Expiry discards the session and what it captured, and stops capturing
+    clock.advance(Duration::from_secs(1));
+    client.get("/health").await;
+    let json = capture(&state).await;
+    assert_eq!(json["active"], false);
+    assert_eq!(json["exchanges"], json!([]));
+}
+
+#[tokio::test]
+async fn test_delete_stops_the_capture() {
+    let state = AppState::default();
+    start(&state, json!({})).await;
+    TestClient::from_state(state.clone()).get("/health").await;
+
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+    let response = admin.send(Method::DELETE, "/admin/capture", HeaderMap::new(), Body::empty()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Value>()["exchanges"].as_array().unwrap().len(), 1);
+
+    TestClient::from_state(state.clone()).get("/health").await;
+    assert_eq!(capture(&state).await["active"], false);
+}
@@ -0,0 +1,87 @@
+//! One client, one key: `CF-Connecting-IP` variants resolve to the same address
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::client_ip::{self, ClientIp};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+const LIMIT: u32 = 3;
+
+type Buckets = Arc<Mutex<HashMap<IpAddr, u32>>>;
+
+/// A fixed-window limiter keyed on the resolved client address, the way the
+/// rate limiter and allowlist use it
+fn limited(buckets: Buckets) -> TestClient {
+    let state = AppState::default();
+    let limited = Router::new()
+        .route(
+            "/",
+            get(|State(buckets): State<Buckets>, client: ClientIp| async move {
+                let ip = client.ip.expect("No client address");
+                let mut buckets = buckets.lock().unwrap();
+                let count = buckets.entry(ip).or_default();
+                *count += 1;
+                if *count > LIMIT { StatusCode::TOO_MANY_REQUESTS } else { StatusCode::OK }
+            }),
+        )
+        .with_state(buckets);
+    TestClient::from_router(limited.layer(middleware::from_fn_with_state(state, client_ip::resolve)))
+}
+
+async fn get_as(client: &TestClient, cf_connecting_ip: &str) -> StatusCode {
+    let mut headers = HeaderMap::new();
+    headers.insert("cf-connecting-ip", HeaderValue::from_str(cf_connecting_ip).unwrap());
+    client.send(Method::GET, "/", headers, Body::empty()).await.status()
+}
+
+#[tokio::test]
+async fn test_mapped_and_unmapped_ipv4_share_a_bucket() {
+    let buckets = Buckets::default();
+    let client = limited(buckets.clone());
+
+    for value in ["203.0.113.7", "::ffff:203.0.113.7", "203.0.113.7:51234"] {
+        assert_eq!(get_as(&client, value).await, StatusCode::OK, "{}", value);
+    }
+    assert_eq!(get_as(&client, "[::ffff:203.0.113.7]:443").await, StatusCode::TOO_MANY_REQUESTS);
+    // Another client has its own bucket
+    assert_eq!(get_as(&client, "203.0.113.8").await, StatusCode::OK);
+
+    let buckets = buckets.lock().unwrap();
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[&"203.0.113.7".parse::<IpAddr>().unwrap()], 4);
+}
+
+#[tokio::test]
+async fn test_ipv6_spellings_share_a_bucket() {
+    let buckets = Buckets::default();
+    let client = limited(buckets.clone());
+
+    for value in ["2001:db8::1", "2001:0DB8:0:0:0:0:0:1", "[2001:db8::1]:443"] {
+        assert_eq!(get_as(&client, value).await, StatusCode::OK, "{}", value);
+    }
+    assert_eq!(get_as(&client, "2001:0db8:0000:0000:0000:0000:0000:0001").await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(buckets.lock().unwrap().len(), 1);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_bad_values_are_counted() {
+    let state = AppState::default();
+    let client = TestClient::from_state(state.clone());
+
+    let mut headers = HeaderMap::new();
+    headers.insert("cf-connecting-ip", HeaderValue::from_static("not-an-ip"));
+    assert_eq!(client.send(Method::GET, "/health", headers, Body::empty()).await.status(), StatusCode::OK);
+    let mut headers = HeaderMap::new();
+    headers.insert("cf-connecting-ip", HeaderValue::from_static("::ffff:203.0.113.7"));
+    client.send(Method::GET, "/health", headers, Body::empty()).await;
+
+    assert_eq!(state.requests.bad_client_ip_headers.get(), 1);
+    assert!(client.get("/metrics").await.text().contains("bad_client_ip_header_total 1"));
+}
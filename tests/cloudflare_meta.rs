@@ -0,0 +1,68 @@
+//! Cloudflare's request headers, parsed into `CloudflareMeta` for handlers
+
+use axum::{routing::get, Json, Router};
+use cloudflare_tunnel_example::cloudflare::meta::CloudflareMeta;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use serde_json::json;
+
+/// Headers Cloudflare sends, bot score included
+const ALL_HEADERS: &[(&str, &str)] = &[
+    ("cf-ray", "8a1b2c3d4e5f0abc-SJC"),
+    ("cf-ipcountry", "NZ"),
+    ("cf-visitor", r#"{"scheme":"https"}"#),
+    ("cf-worker", "workers.example.com"),
+    ("cf-connecting-ip", "2001:db8::7"),
+    ("cf-bot-score", "87"),
+];
+
+fn with_headers(mut client: TestClient, headers: &[(&str, &str)]) -> TestClient {
+    for (name, value) in headers {
+        client = client.with_header(name, value);
+    }
+    client
+}
+
+/// A router whose only handler returns what it extracted
+fn echo(headers: &[(&str, &str)]) -> TestClient {
+    let router = Router::new().route("/meta", get(|meta: CloudflareMeta| async move { Json(meta) }));
+    with_headers(TestClient::from_router(router), headers)
+}
+
+#[tokio::test]
+async fn test_every_header_set() {
+    let meta: serde_json::Value = echo(ALL_HEADERS).get("/meta").await.json();
+    assert_eq!(
+        meta,
+        json!({
+            "ray": "8a1b2c3d4e5f0abc",
+            "colo": "SJC",
+            "country": "NZ",
+            "visitor_scheme": "https",
+            "worker": "workers.example.com",
+            "connecting_ip": "2001:db8::7",
+            "bot_score": 87,
+        })
+    );
+
+    let page = with_headers(TestClient::from_state(AppState::default()), ALL_HEADERS).get("/").await.text();
+    assert!(page.contains("<dd>SJC</dd>") && page.contains("<dd>NZ</dd>"), "{}", page);
+}
+
+#[tokio::test]
+async fn test_no_header_set() {
+    let meta: serde_json::Value = echo(&[]).get("/meta").await.json();
+    assert!(meta.as_object().unwrap().values().all(|value| value.is_null()), "{}", meta);
+
+    let page = TestClient::from_state(AppState::default()).get("/").await.text();
+    assert!(!page.contains("Colo"), "{}", page);
+}
+
+#[tokio::test]
+async fn test_malformed_headers_only_lose_their_field() {
+    let client = echo(&[("cf-ray", "8a1b2c3d4e5f0abc-SJC"), ("cf-ipcountry", "<script>"), ("cf-bot-score", "lots")]);
+    let response = client.get("/meta").await;
+    assert_eq!(response.status(), 200);
+    let meta: serde_json::Value = response.json();
+    assert_eq!((&meta["colo"], &meta["country"], &meta["bot_score"]), (&json!("SJC"), &json!(null), &json!(null)));
+}
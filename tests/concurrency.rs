@@ -0,0 +1,107 @@
+//! Concurrency limit: queueing, gauges and the backpressure warning
+
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::concurrency::{self, ConcurrencyConfig};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::health;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// `/park` waits until `open` is sent `true`
+fn parking_client(state: &AppState, open: watch::Receiver<bool>) -> TestClient {
+    let park = move || {
+        let mut open = open.clone();
+        async move {
+            let _ = open.wait_for(|open| *open).await;
+            "released"
+        }
+    };
+    let router = Router::new().route("/park", get(park)).merge(health::routes());
+    #[cfg(feature = "metrics")]
+    let router = router.merge(cloudflare_tunnel_example::metrics::routes());
+    let router = router
+        .layer(middleware::from_fn_with_state(state.clone(), concurrency::limit))
+        .with_state(state.clone());
+    TestClient::from_router(router)
+}
+
+async fn backpressure_check(client: &TestClient) -> serde_json::Value {
+    let response = client.get("/readyz").await;
+    assert_eq!(response.status(), axum::http::StatusCode::OK);
+    let json: serde_json::Value = response.json();
+    json["checks"].as_array().unwrap().iter().find(|check| check["name"] == "backpressure").unwrap().clone()
+}
+
+#[tokio::test]
+async fn test_parked_requests_fill_the_limiter() {
+    let config = ConcurrencyConfig { max_concurrent: Some(2), pressure_threshold: Some(4) };
+    let state = AppState::new(AppConfig { concurrency: config, ..AppConfig::default() });
+    let (open, parked) = watch::channel(false);
+    let client = parking_client(&state, parked);
+
+    let before = backpressure_check(&client).await;
+    assert_eq!(before["detail"], "0 running, 0 queued (limit 2)");
+    assert!(before.get("warning").is_none(), "{}", before);
+
+    let requests: Vec<_> = (0..4)
+        .map(|_| {
+            let client = client.clone();
+            tokio::spawn(async move { client.get("/park").await.text() })
+        })
+        .collect();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while (state.concurrency.running(), state.concurrency.queued()) != (2, 2) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .expect("Two requests should run and two wait");
+
+    // The probes bypass the full limiter and report the pressure
+    let full = backpressure_check(&client).await;
+    assert_eq!(full["healthy"], true);
+    assert_eq!(full["warning"], true);
+    assert_eq!(full["detail"], "2 running, 2 queued (limit 2); at or above BACKPRESSURE_THRESHOLD 4");
+
+    #[cfg(feature = "metrics")]
+    {
+        let metrics = client.get("/metrics").await.text();
+        assert!(metrics.contains("http_requests_running 2\n"), "{}", metrics);
+        assert!(metrics.contains("http_requests_queued 2\n"), "{}", metrics);
+    }
+
+    open.send(true).unwrap();
+    for request in requests {
+        assert_eq!(request.await.unwrap(), "released");
+    }
+    assert_eq!((state.concurrency.running(), state.concurrency.queued()), (0, 0));
+    assert!(backpressure_check(&client).await.get("warning").is_none());
+}
+
+#[tokio::test]
+async fn test_cancelled_requests_leave_the_queue() {
+    let config = ConcurrencyConfig { max_concurrent: Some(1), pressure_threshold: None };
+    let state = AppState::new(AppConfig { concurrency: config, ..AppConfig::default() });
+    let (_open, parked) = watch::channel(false);
+    let client = parking_client(&state, parked);
+
+    let running = tokio::spawn({
+        let client = client.clone();
+        async move { client.get("/park").await }
+    });
+    let queued = tokio::spawn({
+        let client = client.clone();
+        async move { client.get("/park").await }
+    });
+    while (state.concurrency.running(), state.concurrency.queued()) != (1, 1) {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    queued.abort();
+    running.abort();
+    let _ = (queued.await, running.await);
+    assert_eq!((state.concurrency.running(), state.concurrency.queued()), (0, 0));
+    assert_eq!(backpressure_check(&client).await["detail"], "0 running, 0 queued (limit 1)");
+}
@@ -0,0 +1,137 @@
+//! Configuration history, rollback and preview on the admin listener
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use cloudflare_tunnel_example::config::{AppConfig, SecurityConfig};
+use cloudflare_tunnel_example::headers::{CONTENT_SECURITY_POLICY, REPORTING_ENDPOINTS, X_FRAME_OPTIONS};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, ADMIN_TOKEN};
+use serde_json::{json, Value};
+
+fn with_frame_options(state: &AppState, frame_options: &str) -> AppConfig {
+    let security = SecurityConfig { frame_options: frame_options.to_string(), ..state.config().security.clone() };
+    AppConfig { security, ..(*state.config()).clone() }
+}
+
+#[tokio::test]
+async fn test_rollback_restores_an_earlier_version() {
+    let state = AppState::new(AppConfig { expose_build_headers: true, ..AppConfig::default() });
+    let client = TestClient::from_state(state.clone());
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+
+    for (expected, frame_options) in [(2, "SAMEORIGIN"), (3, "DENY"), (4, "ALLOW-FROM https://example.com")] {
+        let version = state.apply_config(with_frame_options(&state, frame_options), "reload").unwrap();
+        assert_eq!(version, expected);
+    }
+    let response = client.get("/").await;
+    assert_eq!(response.header(X_FRAME_OPTIONS), Some("ALLOW-FROM https://example.com"));
+
+    let response = admin.get("/admin/config/history").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let history: Value = response.json();
+    assert_eq!(history["current"], 4);
+    let versions = history["versions"].as_array().unwrap();
+    assert_eq!(versions.len(), 4);
+    assert_eq!(versions[0]["source"], "startup");
+    assert_eq!(versions[1]["security"]["frame_options"], "SAMEORIGIN");
+    let first_hash = versions[1]["config_hash"].as_str().unwrap().to_string();
+
+    let response = admin.post_json("/admin/config/rollback", &json!({ "version": 2 })).await;
+    assert_eq!(response.status(), StatusCode::OK, "{}", response.text());
+    assert_eq!(response.json::<Value>(), json!({ "version": 5, "rolled_back_to": 2 }));
+
+    // Responses, the fingerprint header and the effective config follow
+    let response = client.get("/").await;
+    assert_eq!(response.header(X_FRAME_OPTIONS), Some("SAMEORIGIN"));
+    assert_eq!(response.header("x-config-hash"), Some(first_hash.as_str()));
+    let config: Value = admin.get("/admin/config").await.json();
+    assert_eq!(config["version"], 5);
+    assert_eq!(config["security"]["frame_options"], "SAMEORIGIN");
+
+    let history: Value = admin.get("/admin/config/history").await.json();
+    assert_eq!(history["versions"][4]["source"], "rollback to 2");
+    assert_eq!(history["versions"][4]["config_hash"], first_hash.as_str());
+}
+
+#[tokio::test]
+async fn test_rollback_rejects_unknown_and_invalid_versions() {
+    let state = AppState::default();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+    let response = admin.post_json("/admin/config/rollback", &json!({ "version": 7 })).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.json::<Value>()["error"], "unknown_config_version");
+
+    // `apply_config` refuses what `replace_config` stores unchecked, and a
+    // rollback goes through the same check
+    assert!(state.apply_config(with_frame_options(&state, "DENY\r\nX-Injected: 1"), "reload").is_err());
+    assert_eq!(state.config_version(), 1);
+    state.replace_config(with_frame_options(&state, "DENY\r\nX-Injected: 1"));
+    state.replace_config(with_frame_options(&state, "SAMEORIGIN"));
+
+    let response = admin.post_json("/admin/config/rollback", &json!({ "version": 2 })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.json::<Value>()["error"], "invalid_config");
+    assert_eq!(state.config_version(), 3);
+    assert_eq!(state.config().security.frame_options, "SAMEORIGIN");
+}
+
+#[tokio::test]
+async fn test_preview_shows_changed_csp_without_applying_it() {
+    let state = AppState::default();
+    let before = state.config();
+
+    let body = json!({ "csp": { "script_src": "'self' 'unsafe-inline'" }, "report_to": "default" });
+    let response = TestClient::admin(&state, Some(ADMIN_TOKEN)).post_json("/admin/config/preview", &body).await;
+    assert_eq!(response.status(), StatusCode::OK, "{}", response.text());
+    let json: Value = response.json();
+
+    let csp = json["headers"][CONTENT_SECURITY_POLICY.as_str()].as_str().unwrap();
+    assert!(csp.contains("script-src 'self' 'unsafe-inline';"), "{}", csp);
+    assert!(csp.ends_with("; report-to default"), "{}", csp);
+    assert_eq!(json["headers"][REPORTING_ENDPOINTS.as_str()], "default=\"/reports\"");
+    assert_eq!(json["headers"][X_FRAME_OPTIONS.as_str()], "DENY");
+    assert_eq!(json["validation"], json!({ "valid": true, "errors": [] }));
+    assert_eq!(json["score"]["grade"], "B");
+    assert_eq!(json["security"]["csp"]["style_src"], "'self' 'unsafe-inline'");
+
+    // Nothing was applied
+    assert_eq!(state.config_version(), 1);
+    assert_eq!(state.config().security.csp.script_src, before.security.csp.script_src);
+    let response = TestClient::from_state(state.clone()).get("/").await;
+    assert!(response.header(CONTENT_SECURITY_POLICY).unwrap().contains("script-src 'self';"));
+    assert_eq!(response.header(REPORTING_ENDPOINTS), None);
+}
+
+#[tokio::test]
+async fn test_preview_reports_failed_validation() {
+    let state = AppState::default();
+    let body = json!({ "csp": { "img_src": "'self'; report-uri https://evil.example" } });
+    let response = TestClient::admin(&state, Some(ADMIN_TOKEN)).post_json("/admin/config/preview", &body).await;
+
+    assert_eq!(response.status(), StatusCode::OK, "{}", response.text());
+    let json: Value = response.json();
+    assert_eq!(json["validation"]["valid"], false);
+    let error = json["validation"]["errors"][0].as_str().unwrap();
+    assert!(error.contains("img-src"), "{}", error);
+    assert_eq!(state.config().security.csp.img_src, "'self' data:");
+}
+
+#[tokio::test]
+async fn test_preview_rejects_bodies_that_do_not_fit() {
+    let admin = TestClient::admin(&AppState::default(), Some(ADMIN_TOKEN));
+
+    let response = admin.post_json("/admin/config/preview", &json!({ "hsts": { "max_age": "a year" } })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json();
+    assert_eq!(json["error"], "invalid_config");
+    assert_eq!(json["details"]["fields"][0]["path"], "hsts.max_age");
+
+    let response = admin.post_json("/admin/config/preview", &json!({ "frame_option": "DENY" })).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.json::<Value>()["details"]["fields"], json!([{ "path": "frame_option", "message": "unknown field" }]));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let response = admin.send(Method::POST, "/admin/config/preview", headers, Body::from("not json")).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,116 @@
+//! `Content-Digest` and `Content-MD5` checks on request bodies, and digests
+//! on responses
+
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::{middleware, routing::post, Extension, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::content_digest::{self, Algorithm, BodyDigest, Digest, ResponseDigestConfig};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, TestResponse};
+use serde_json::Value;
+
+const BODY: &str = "{\"hello\": \"world\"}\n";
+const SHA256: &str = "sha-256=:RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=:";
+const SHA512: &str = "sha-512=:YMAam51Jz/jOATT6/zvHrLVgOYTGFy1d6GJiOHTohq4yP+pgk4vf2aCsyRZOtw8MjkM7iw7yZ/WkppmM44T3qg==:";
+const MD5: &str = "UFIauregE76D7gDe0/n0JA==";
+
+/// Router echoing the body it received, with the digests it was checked
+/// against in `x-digest`
+fn upload_client() -> TestClient {
+    let router = Router::new()
+        .route(
+            "/upload",
+            post(|digest: Option<Extension<BodyDigest>>, body: Bytes| async move {
+                let computed = digest
+                    .and_then(|Extension(digest)| digest.get().map(|d| d.iter().map(Digest::to_string).collect::<Vec<_>>()))
+                    .unwrap_or_default();
+                ([("x-digest", computed.join(", "))], body)
+            }),
+        )
+        .layer(middleware::from_fn(content_digest::verify));
+    TestClient::from_router(router)
+}
+
+async fn upload(client: &TestClient, headers: &[(&str, &str)], body: impl Into<Body>) -> TestResponse {
+    let headers: HeaderMap = headers
+        .iter()
+        .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_str(value).unwrap()))
+        .collect();
+    client.send(Method::POST, "/upload", headers, body.into()).await
+}
+
+fn error(response: &TestResponse) -> String {
+    response.json::<Value>()["error"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_matching_digest_is_accepted() {
+    let client = upload_client();
+
+    // Streamed in pieces, hashed as it is read
+    let pieces = BODY.as_bytes().chunks(5).map(|c| Ok::<_, std::io::Error>(Bytes::copy_from_slice(c))).collect::<Vec<_>>();
+    let response = upload(&client, &[("content-digest", SHA256)], Body::from_stream(futures_util::stream::iter(pieces))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text(), BODY);
+    assert_eq!(response.header("x-digest"), Some(SHA256));
+
+    let response = upload(&client, &[("content-md5", MD5)], BODY).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.header("x-digest"), Some(format!("md5=:{}:", MD5).as_str()));
+
+    // No digest, no check
+    let response = upload(&client, &[], BODY).await;
+    assert_eq!((response.status(), response.header("x-digest")), (StatusCode::OK, Some("")));
+}
+
+#[tokio::test]
+async fn test_mismatched_digest_is_rejected() {
+    let client = upload_client();
+
+    let response = upload(&client, &[("content-digest", SHA256)], "{\"hello\": \"there\"}\n").await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error(&response), "digest_mismatch");
+
+    let response = upload(&client, &[("content-md5", "AAAAAAAAAAAAAAAAAAAAAA==")], BODY).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn test_multiple_digests_in_one_header() {
+    let client = upload_client();
+
+    // Unknown algorithms are skipped; every known one is checked
+    let both = format!("unixsum=:AAAA:, {}, {}", SHA256, SHA512);
+    let response = upload(&client, &[("content-digest", &both)], BODY).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.header("x-digest"), Some(format!("{}, {}", SHA256, SHA512).as_str()));
+
+    let wrong_sha512 = format!("{}, sha-512=:{}:", SHA256, "A".repeat(88));
+    let response = upload(&client, &[("content-digest", &wrong_sha512)], BODY).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(response.json::<Value>()["message"].as_str().unwrap().contains("sha-512"));
+
+    let response = upload(&client, &[("content-digest", "sha=:AAAA:, md5=:AAAA:")], BODY).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error(&response), "unsupported_digest_algorithm");
+    assert!(response.json::<Value>()["message"].as_str().unwrap().ends_with("supported algorithms are sha-256, sha-512"));
+    assert_eq!(response.header("want-content-digest"), Some("sha-256=10, sha-512=5"));
+
+    let response = upload(&client, &[("content-digest", "sha-256=RK/0qy18")], BODY).await;
+    assert_eq!((response.status(), error(&response)), (StatusCode::BAD_REQUEST, "invalid_digest".to_string()));
+}
+
+#[tokio::test]
+async fn test_response_digest_on_configured_routes() {
+    let response_digest = ResponseDigestConfig { prefixes: vec!["/robots.txt".to_string()], algorithm: Algorithm::Sha512 };
+    let client = TestClient::from_state(AppState::new(AppConfig { response_digest: Some(response_digest), ..AppConfig::default() }));
+
+    let response = client.get("/robots.txt").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let expected = Digest { algorithm: Algorithm::Sha512, bytes: Algorithm::Sha512.digest(response.bytes()) };
+    assert_eq!(response.header("content-digest"), Some(expected.to_string().as_str()));
+
+    // Other routes are left alone
+    assert_eq!(client.get("/health").await.header("content-digest"), None);
+}
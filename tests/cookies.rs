@@ -0,0 +1,90 @@
+//! `Set-Cookie` hardening on responses from handlers
+
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{AppendHeaders, IntoResponse};
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::cookies::{self, CookiePolicy, SameSite};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+const COMPLIANT: &str = "prefs=a=1&b=2;Secure;  HttpOnly; SameSite=Strict; Path=/";
+
+async fn set_cookies() -> impl IntoResponse {
+    (
+        AppendHeaders([
+            (header::SET_COOKIE, "session=abc==; Path=/; Max-Age=3600"),
+            (header::SET_COOKIE, "__Host-csrf=t0k3n; Domain=hello.halibut.cc; Path=/forms"),
+            (header::SET_COOKIE, COMPLIANT),
+            (header::SET_COOKIE, "theme=dark"),
+        ]),
+        "ok",
+    )
+}
+
+fn client(policy: CookiePolicy) -> TestClient {
+    let state = AppState::new(AppConfig { cookies: policy, ..AppConfig::default() });
+    let router = Router::new()
+        .route("/login", get(set_cookies))
+        .layer(middleware::from_fn_with_state(state.clone(), cookies::harden_cookies))
+        .with_state(state);
+    TestClient::from_router(router)
+}
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_lax_cookies_are_rewritten() {
+    let logs = Captured::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let policy = CookiePolicy {
+        same_site: SameSite::Strict,
+        host_prefix_check: true,
+        exempt: vec!["theme".to_string()],
+    };
+    let response = client(policy).get("/login").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let cookies: Vec<&HeaderValue> = response.headers().get_all(header::SET_COOKIE).iter().collect();
+    assert_eq!(cookies.len(), 4);
+    assert_eq!(cookies[0], "session=abc==; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=Strict");
+    assert_eq!(cookies[1], "__Host-csrf=t0k3n; Path=/; Secure; HttpOnly; SameSite=Strict");
+    // Already compliant: byte-identical, odd spacing and all
+    assert_eq!(cookies[2].as_bytes(), COMPLIANT.as_bytes());
+    // Exempt
+    assert_eq!(cookies[3], "theme=dark");
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("cookie=\"session\""), "{}", logs);
+    assert!(logs.contains("cookie=\"__Host-csrf\""), "{}", logs);
+    assert!(!logs.contains("prefs"), "{}", logs);
+    assert!(!logs.contains("theme"), "{}", logs);
+}
+
+#[tokio::test]
+async fn test_host_prefix_check_is_opt_in() {
+    let response = client(CookiePolicy::default()).get("/login").await;
+    let cookies: Vec<&HeaderValue> = response.headers().get_all(header::SET_COOKIE).iter().collect();
+    assert_eq!(cookies[1], "__Host-csrf=t0k3n; Domain=hello.halibut.cc; Path=/forms; Secure; HttpOnly; SameSite=Lax");
+    assert_eq!(cookies[3], "theme=dark; Secure; HttpOnly; SameSite=Lax");
+}
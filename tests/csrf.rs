@@ -0,0 +1,146 @@
+//! Double-submit CSRF protection on configured prefixes
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::csrf::{self, CsrfConfig, CsrfToken};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, TestResponse};
+
+/// Router with a form under `/forms` (echoing the handler's token and the
+/// body it received) and the token route
+fn client(rotate: bool) -> TestClient {
+    let config = CsrfConfig { rotate, ..CsrfConfig::new(["/forms"]) };
+    let state = AppState::new(AppConfig { csrf: Some(config), ..AppConfig::default() });
+    let router = Router::new()
+        .route(
+            "/forms/contact",
+            get(|CsrfToken(token): CsrfToken| async move { token })
+                .post(|CsrfToken(token): CsrfToken, body: String| async move { format!("{} {}", token, body) }),
+        )
+        .merge(csrf::routes())
+        .layer(middleware::from_fn_with_state(state.clone(), csrf::protect))
+        .with_state(state);
+    TestClient::from_router(router)
+}
+
+/// The token set by a `__Host-csrf` cookie on `response`
+fn issued_token(response: &TestResponse) -> Option<String> {
+    let cookie = response.header(header::SET_COOKIE)?;
+    let value = cookie.strip_prefix("__Host-csrf=")?;
+    Some(value.split(';').next()?.to_string())
+}
+
+async fn submit(client: &TestClient, cookie: Option<&str>, header: Option<&str>, form: Option<&str>) -> TestResponse {
+    let mut headers = HeaderMap::new();
+    if let Some(token) = cookie {
+        headers.insert(header::COOKIE, HeaderValue::from_str(&format!("__Host-csrf={}", token)).unwrap());
+    }
+    if let Some(token) = header {
+        headers.insert("x-csrf-token", HeaderValue::from_str(token).unwrap());
+    }
+    let body = match form {
+        Some(form) => {
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/x-www-form-urlencoded"));
+            Body::from(form.to_string())
+        }
+        None => Body::empty(),
+    };
+    client.send(Method::POST, "/forms/contact", headers, body).await
+}
+
+#[tokio::test]
+async fn test_token_is_issued_once() {
+    let client = client(false);
+
+    let response = client.get("/csrf-token").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let token = issued_token(&response).expect("no cookie issued");
+    assert_eq!(response.json::<serde_json::Value>()["token"], token.as_str());
+    let cookie = response.header(header::SET_COOKIE).unwrap();
+    assert!(cookie.contains("Path=/; Secure; HttpOnly; SameSite=Strict"), "{}", cookie);
+
+    // A safe request on a protected path also issues one, and the handler
+    // sees the same token the cookie carries
+    let response = client.get("/forms/contact").await;
+    assert_eq!(issued_token(&response), Some(response.text()));
+
+    // An existing cookie is kept rather than replaced
+    let client = client.with_header("cookie", &format!("__Host-csrf={}", token));
+    let response = client.get("/csrf-token").await;
+    assert_eq!(response.header(header::SET_COOKIE), None);
+    assert_eq!(response.json::<serde_json::Value>()["token"], token.as_str());
+    assert_eq!(client.get("/forms/contact").await.text(), token);
+}
+
+#[tokio::test]
+async fn test_matching_token_is_accepted() {
+    let client = client(false);
+    let token = issued_token(&client.get("/csrf-token").await).unwrap();
+
+    let response = submit(&client, Some(&token), Some(&token), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.header(header::SET_COOKIE), None);
+
+    // In a form field, with the body still reaching the handler
+    let form = format!("name=Ada&csrf_token={}", token);
+    let response = submit(&client, Some(&token), None, Some(&form)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text(), format!("{} {}", token, form));
+}
+
+#[tokio::test]
+async fn test_missing_token_is_rejected() {
+    let client = client(false);
+    let token = issued_token(&client.get("/csrf-token").await).unwrap();
+
+    // No cookie at all
+    let response = submit(&client, None, Some(&token), None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "csrf_token_missing");
+
+    // Cookie but nothing presented, in a header or the form
+    for form in [None, Some("name=Ada")] {
+        let response = submit(&client, Some(&token), None, form).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.json::<serde_json::Value>()["error"], "csrf_token_missing");
+    }
+}
+
+#[tokio::test]
+async fn test_mismatched_token_is_rejected() {
+    let client = client(false);
+    let token = issued_token(&client.get("/csrf-token").await).unwrap();
+    let other = issued_token(&client.get("/csrf-token").await).unwrap();
+    assert_ne!(token, other);
+
+    let response = submit(&client, Some(&token), Some(&other), None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "csrf_token_mismatch");
+
+    let response = submit(&client, Some(&token), None, Some(&format!("csrf_token={}", other))).await;
+    assert_eq!(response.json::<serde_json::Value>()["error"], "csrf_token_mismatch");
+
+    // The token route only answers GET; being unprotected, it is not checked
+    let response = client.send(Method::POST, "/csrf-token", HeaderMap::new(), Body::empty()).await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn test_rotation_replaces_the_token() {
+    let client = client(true);
+    let token = issued_token(&client.get("/csrf-token").await).unwrap();
+
+    let response = submit(&client, Some(&token), Some(&token), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let rotated = issued_token(&response).expect("no rotated cookie");
+    assert_ne!(rotated, token);
+    assert_eq!(response.header("x-csrf-token"), Some(rotated.as_str()));
+    assert!(response.text().starts_with(&rotated));
+
+    // The old token no longer matches the new cookie
+    let response = submit(&client, Some(&rotated), Some(&token), None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(submit(&client, Some(&rotated), Some(&rotated), None).await.status(), StatusCode::OK);
+}
@@ -0,0 +1,159 @@
+//! gzip and Brotli request bodies inflated before handlers see them
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::{middleware, routing::post, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::decompression::{self, DecompressionConfig};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, TestResponse};
+
+/// `printf '{"event":"deploy","ok":true}' | gzip -9n`
+const DEPLOY_GZ: &[u8] = &[
+    0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xab, 0x56, 0x4a, 0x2d, 0x4b, 0xcd, 0x2b, 0x51, 0xb2,
+    0x52, 0x4a, 0x49, 0x2d, 0xc8, 0xc9, 0xaf, 0x54, 0xd2, 0x51, 0xca, 0xcf, 0x56, 0xb2, 0x2a, 0x29, 0x2a, 0x4d, 0xad,
+    0x05, 0x00, 0x9b, 0xdd, 0xb6, 0xcc, 0x1c, 0x00, 0x00, 0x00,
+];
+
+/// `head -c 65536 /dev/zero | gzip -9n`: 96 bytes inflating to 64 KiB
+fn zeros_gz() -> Vec<u8> {
+    let mut data = vec![
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xed, 0xc1, 0x01, 0x01, 0x00, 0x00, 0x00, 0x80,
+        0x90, 0xfe, 0xaf, 0xee, 0x08, 0x0a,
+    ];
+    data.extend([0u8; 63]);
+    data.extend([0x6a, 0xeb, 0x8e, 0x97, 0xd7, 0x00, 0x00, 0x01, 0x00]);
+    data
+}
+
+/// The same payload as `DEPLOY_GZ`, Brotli-compressed
+fn deploy_br() -> Vec<u8> {
+    use std::io::Read;
+    let mut out = Vec::new();
+    brotli::CompressorReader::new(&br#"{"event":"deploy","ok":true}"#[..], 4096, 9, 22).read_to_end(&mut out).unwrap();
+    out
+}
+
+fn state(decompression: DecompressionConfig) -> AppState {
+    AppState::new(AppConfig { decompression, ..AppConfig::default() })
+}
+
+/// Router answering with the body it received and its request headers
+fn upload_client(state: AppState) -> TestClient {
+    let router = Router::new()
+        .route(
+            "/upload",
+            post(|headers: HeaderMap, body: Bytes| async move {
+                let encoding = headers.get(header::CONTENT_ENCODING).is_some();
+                let length = headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).unwrap_or_default();
+                ([("x-had-encoding", encoding.to_string()), ("x-length", length.to_string())], body)
+            }),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), decompression::decompress_request));
+    TestClient::from_router(router.with_state(state))
+}
+
+async fn upload(client: &TestClient, encoding: Option<&str>, body: impl Into<Body>) -> TestResponse {
+    let mut headers = HeaderMap::new();
+    if let Some(encoding) = encoding {
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_str(encoding).unwrap());
+    }
+    client.send(Method::POST, "/upload", headers, body.into()).await
+}
+
+#[tokio::test]
+async fn test_gzip_body_is_inflated() {
+    let client = upload_client(state(DecompressionConfig::default()));
+    let response = upload(&client, Some("gzip"), DEPLOY_GZ).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text(), r#"{"event":"deploy","ok":true}"#);
+    assert_eq!(response.header("x-had-encoding"), Some("false"));
+    assert_eq!(response.header("x-length"), Some("28"));
+}
+
+#[tokio::test]
+async fn test_brotli_body_is_inflated() {
+    let client = upload_client(state(DecompressionConfig::default()));
+    let response = upload(&client, Some("br"), deploy_br()).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text(), r#"{"event":"deploy","ok":true}"#);
+    assert_eq!(response.header("x-had-encoding"), Some("false"));
+    assert_eq!(response.header("x-length"), Some("28"));
+
+    let response = upload(&client, Some("br"), DEPLOY_GZ).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "invalid_br_body");
+}
+
+#[tokio::test]
+async fn test_identity_body_passes_through_untouched() {
+    let client = upload_client(state(DecompressionConfig::default()));
+    let binary = vec![0x1f, 0x8b, 0x00, 0xff];
+
+    let response = upload(&client, None, binary.clone()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes(), &binary[..]);
+
+    let response = upload(&client, Some("identity"), binary.clone()).await;
+    assert_eq!(response.bytes(), &binary[..]);
+}
+
+#[tokio::test]
+async fn test_gzip_bomb_is_rejected() {
+    let client = upload_client(state(DecompressionConfig { max_decompressed: 4096, ..DecompressionConfig::default() }));
+    let response = upload(&client, Some("gzip"), zeros_gz()).await;
+
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "decompressed_body_too_large");
+
+    // The same payload fits once the cap allows it
+    let client = upload_client(state(DecompressionConfig::default()));
+    assert_eq!(upload(&client, Some("gzip"), zeros_gz()).await.bytes().len(), 65536);
+}
+
+#[tokio::test]
+async fn test_compressed_size_is_capped() {
+    let client = upload_client(state(DecompressionConfig { max_compressed: 32, ..DecompressionConfig::default() }));
+    let response = upload(&client, Some("gzip"), DEPLOY_GZ).await;
+
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "body_too_large");
+}
+
+#[tokio::test]
+async fn test_invalid_gzip_is_a_bad_request() {
+    let client = upload_client(state(DecompressionConfig::default()));
+    let response = upload(&client, Some("gzip"), r#"{"plain":"json"}"#).await;
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "invalid_gzip_body");
+}
+
+#[tokio::test]
+async fn test_unsupported_encodings_are_415() {
+    let client = upload_client(state(DecompressionConfig::default()));
+    for encoding in ["deflate", "zstd", "gzip, br"] {
+        let response = upload(&client, Some(encoding), "data").await;
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE, "{}", encoding);
+        assert_eq!(response.json::<serde_json::Value>()["error"], "unsupported_content_encoding");
+    }
+}
+
+#[cfg(feature = "debug-endpoints")]
+#[tokio::test]
+async fn test_echo_sees_decompressed_json() {
+    let client = TestClient::from_state(AppState::new(AppConfig { debug_endpoints: true, ..AppConfig::default() }));
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let response = client.send(Method::POST, "/echo", headers, Body::from(DEPLOY_GZ)).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let echo = response.json::<serde_json::Value>();
+    assert_eq!(echo["body_encoding"], "json");
+    assert_eq!(echo["body"]["event"], "deploy");
+    assert!(echo["headers"].get("content-encoding").is_none());
+}
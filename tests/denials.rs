@@ -0,0 +1,144 @@
+//! Every blocking middleware answers with the same denial shape
+
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::{body::Body, middleware, middleware::Next};
+use cloudflare_tunnel_example::api_keys::{ApiKey, ApiKeyConfig};
+use cloudflare_tunnel_example::auth::AuthRule;
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::create_app;
+use cloudflare_tunnel_example::csrf::CsrfConfig;
+use cloudflare_tunnel_example::denial::{DenialConfig, DenialSignal};
+use cloudflare_tunnel_example::direct_access::DirectAccessPolicy;
+use cloudflare_tunnel_example::header_limits::HeaderLimits;
+use cloudflare_tunnel_example::maintenance::MaintenanceConfig;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, TestResponse};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn signalled(config: AppConfig) -> AppState {
+    let signal = DenialSignal {
+        name: HeaderName::from_static("x-origin-challenge"),
+        value: HeaderValue::from_static("managed"),
+    };
+    AppState::new(AppConfig { denial: DenialConfig { signal: Some(signal) }, ..config })
+}
+
+/// The app as reached from `peer` rather than through the tunnel
+fn direct_client(state: AppState, peer: &'static str) -> TestClient {
+    let router = create_app(state).layer(middleware::from_fn(move |mut request: Request, next: Next| async move {
+        request.extensions_mut().insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+        next.run(request).await
+    }));
+    TestClient::from_router(router)
+}
+
+/// Status, reason header, body and signal header of a denial for `reason`
+fn assert_denied(response: &TestResponse, status: StatusCode, reason: &str) {
+    assert_eq!(response.status(), status);
+    assert_eq!(response.header("x-denial-reason"), Some(reason));
+    assert_eq!(response.header("x-origin-challenge"), Some("managed"));
+
+    let body: serde_json::Value = response.json();
+    assert_eq!(body["error"], reason);
+    assert!(body["message"].as_str().is_some_and(|message| !message.is_empty()), "{}", body);
+    match response.header_as::<u64>("retry-after") {
+        Some(secs) => assert_eq!(body["details"]["retry_after"], secs),
+        None => assert!(body.get("details").is_none(), "{}", body),
+    }
+}
+
+#[tokio::test]
+async fn test_direct_access() {
+    let state = signalled(AppConfig { direct_access: DirectAccessPolicy::Block, ..AppConfig::default() });
+    let response = direct_client(state.clone(), "198.51.100.9:4000").get("/").await;
+    assert_denied(&response, StatusCode::FORBIDDEN, "direct_access_forbidden");
+
+    // Requests that are let through never get the signal
+    let response = direct_client(state, "127.0.0.1:4000").get("/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.header("x-denial-reason").is_none() && response.header("x-origin-challenge").is_none());
+}
+
+#[tokio::test]
+async fn test_api_key_rate_limit() {
+    let mut api_keys = ApiKeyConfig::new([ApiKey::new("reporting", "abc123")], ["/robots.txt"]);
+    api_keys.rate_limit = Some(1);
+    let client = TestClient::from_state(signalled(AppConfig { api_keys: Some(api_keys), ..AppConfig::default() }))
+        .with_header("x-api-key", "abc123");
+
+    assert_eq!(client.get("/robots.txt").await.status(), StatusCode::OK);
+    let response = client.get("/robots.txt").await;
+    assert_denied(&response, StatusCode::TOO_MANY_REQUESTS, "rate_limited");
+    assert!(response.header_as::<u64>("retry-after").is_some_and(|secs| (1..=60).contains(&secs)));
+}
+
+#[tokio::test]
+async fn test_unauthenticated() {
+    let api_keys = ApiKeyConfig::new([ApiKey::new("reporting", "abc123")], ["/robots.txt"]);
+    let bearer = AuthRule { name: "ops".to_string(), prefixes: vec!["/metrics".to_string()], tokens: vec!["ops-token".to_string()] };
+    let client = TestClient::from_state(signalled(AppConfig { api_keys: Some(api_keys), auth: vec![bearer], ..AppConfig::default() }));
+
+    for path in ["/robots.txt", "/metrics"] {
+        let response = client.get(path).await;
+        assert_denied(&response, StatusCode::UNAUTHORIZED, "unauthorized");
+        assert!(response.header("www-authenticate").is_some(), "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn test_maintenance() {
+    let maintenance = MaintenanceConfig { enabled: true, retry_after: Duration::from_secs(120), ..MaintenanceConfig::default() };
+    let client = TestClient::from_state(signalled(AppConfig { maintenance, ..AppConfig::default() }));
+
+    let response = client.get("/").await;
+    assert_denied(&response, StatusCode::SERVICE_UNAVAILABLE, "maintenance");
+    assert_eq!(response.header("retry-after"), Some("120"));
+}
+
+#[tokio::test]
+async fn test_oversized_headers() {
+    let limits = HeaderLimits { max_count: 10, ..HeaderLimits::default() };
+    let client = TestClient::from_state(signalled(AppConfig { header_limits: limits, ..AppConfig::default() }));
+    let mut headers = HeaderMap::new();
+    for i in 0..11 {
+        headers.insert(HeaderName::from_bytes(format!("x-h{:02}", i).as_bytes()).unwrap(), HeaderValue::from_static("v"));
+    }
+
+    // Turned away before the routes and so before the signal stage
+    let response = client.send(Method::GET, "/health", headers, Body::empty()).await;
+    assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    assert_eq!(response.header("x-denial-reason"), Some("too_many_headers"));
+    assert_eq!(response.json::<serde_json::Value>()["error"], "too_many_headers");
+    assert!(response.header("x-origin-challenge").is_none());
+}
+
+#[tokio::test]
+async fn test_csrf() {
+    let state = signalled(AppConfig { csrf: Some(CsrfConfig::new(["/forms"])), ..AppConfig::default() });
+    let client = TestClient::from_state(state);
+    let post = |cookie: Option<&str>| {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-csrf-token", HeaderValue::from_str(&"a".repeat(64)).unwrap());
+        if let Some(cookie) = cookie {
+            headers.insert("cookie", HeaderValue::from_str(cookie).unwrap());
+        }
+        client.send(Method::POST, "/forms/contact", headers, Body::empty())
+    };
+
+    assert_denied(&post(None).await, StatusCode::FORBIDDEN, "csrf_token_missing");
+    let mismatch = format!("__Host-csrf={}", "b".repeat(64));
+    assert_denied(&post(Some(&mismatch)).await, StatusCode::FORBIDDEN, "csrf_token_mismatch");
+}
+
+#[tokio::test]
+async fn test_without_a_signal() {
+    let config = AppConfig { csrf: Some(CsrfConfig::new(["/forms"])), ..AppConfig::default() };
+    let response = TestClient::from_state(AppState::new(config))
+        .send(Method::POST, "/forms/contact", HeaderMap::new(), Body::empty())
+        .await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.header("x-denial-reason"), Some("csrf_token_missing"));
+    assert!(response.header("x-origin-challenge").is_none());
+}
@@ -0,0 +1,90 @@
+//! Draining through `POST /admin/drain` and `/admin/undrain`
+
+use axum::http::StatusCode;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, ADMIN_TOKEN};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_drain_withdraws_readiness_until_undrained() {
+    let state = AppState::default();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+    let client = TestClient::from_state(state.clone());
+
+    let response = admin.post_json("/admin/drain", &json!({})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json();
+    assert_eq!((&json["draining"], &json["pending"]), (&json!(true), &json!(false)));
+    assert!(!state.is_ready());
+
+    let response = client.get("/readyz").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let readyz: Value = response.json();
+    assert_eq!(readyz["drain"]["drain"]["by"], "token:admin", "{}", readyz);
+    // Serving goes on
+    assert_eq!(client.get("/robots.txt").await.status(), StatusCode::OK);
+
+    let response = admin.post_json("/admin/undrain", &json!({})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json();
+    assert_eq!(json, json!({ "draining": false, "pending": false, "shutting_down": false, "drain": null }));
+    assert_eq!(client.get("/readyz").await.status(), StatusCode::OK);
+
+    // Both are in the audit log
+    let audit: Value = admin.get("/admin/audit").await.json();
+    let paths: Vec<&str> = audit["entries"].as_array().unwrap().iter().map(|e| e["path"].as_str().unwrap()).collect();
+    assert_eq!(paths, ["/admin/undrain", "/admin/drain"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_delayed_drain() {
+    let state = AppState::default();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+
+    let response = admin.post_json("/admin/drain", &json!({"delay_seconds": 30})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json();
+    assert_eq!((&json["draining"], &json["pending"]), (&json!(false), &json!(true)));
+    assert!(state.is_ready());
+
+    // Undraining cancels it
+    admin.post_json("/admin/undrain", &json!({})).await;
+    assert_eq!(state.drain.status().drain, None);
+
+    // Time is paused, so readiness flips exactly when the delay has passed
+    let status = state.drain.drain(&state, "test".to_string(), Duration::from_secs(30));
+    assert!(status.pending && state.is_ready());
+    // Let the drain task start its timer before the clock moves
+    tokio::task::yield_now().await;
+    tokio::time::advance(Duration::from_secs(29)).await;
+    assert!(state.is_ready());
+    tokio::time::advance(Duration::from_secs(1)).await;
+    tokio::task::yield_now().await;
+    assert!(!state.is_ready());
+    assert!(state.drain.status().draining);
+
+    let response = admin.post_json("/admin/drain", &json!({"delay": 5})).await;
+    let json: Value = response.json();
+    assert_eq!((response.status(), &json["error"]), (StatusCode::BAD_REQUEST, &json!("invalid_body")));
+}
+
+#[tokio::test]
+async fn test_shutdown_keeps_the_drain() {
+    let state = AppState::default();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+
+    admin.post_json("/admin/drain", &json!({})).await;
+    // Readiness is already withdrawn, so shutdown skips that step
+    assert!(state.drain.shutdown());
+    let response = admin.post_json("/admin/undrain", &json!({})).await;
+    let json: Value = response.json();
+    assert_eq!((response.status(), &json["error"]), (StatusCode::CONFLICT, &json!("shutting_down")));
+    assert!(!state.is_ready());
+
+    // A pending drain is cancelled and left to shutdown
+    let state = AppState::default();
+    state.drain.drain(&state, "test".to_string(), Duration::from_secs(30));
+    assert!(!state.drain.shutdown());
+    assert!(state.drain.status().shutting_down && !state.drain.status().pending);
+}
@@ -0,0 +1,90 @@
+//! HTML error pages and `ERROR_PAGES_DIR`
+
+use axum::http::StatusCode;
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::error_pages::ErrorPages;
+use cloudflare_tunnel_example::headers::X_CONTENT_TYPE_OPTIONS;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::path::PathBuf;
+
+const BROWSER_ACCEPT: &str = "text/html,application/xhtml+xml,*/*;q=0.8";
+
+fn pages_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("error-pages-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn test_browsers_get_html_and_api_clients_json() {
+    let state = AppState::default();
+
+    let response = TestClient::from_state(state.clone())
+        .with_header("accept", BROWSER_ACCEPT)
+        .with_header("cf-ray", "8a1b2c3d4e5f0abc-SJC")
+        .get("/missing")
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+    assert_eq!(response.header("vary"), Some("accept"));
+    assert_eq!(response.header(X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+    let body = response.text();
+    assert!(body.contains("<title>404 Not Found</title>"), "{}", body);
+    assert!(body.ends_with("<!-- request-id: 8a1b2c3d4e5f0abc-SJC -->\n"), "{}", body);
+
+    // JSON preferred, or no preference: the body is untouched
+    for accept in ["application/json", "application/json, text/html", "*/*"] {
+        let response = TestClient::from_state(state.clone()).with_header("accept", accept).get("/missing").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_ne!(response.header("content-type"), Some("text/html; charset=utf-8"), "{}", accept);
+    }
+
+    // No page for 405, so it stays JSON for browsers too
+    let response = TestClient::from_state(state)
+        .with_header("accept", BROWSER_ACCEPT)
+        .send(axum::http::Method::DELETE, "/health", Default::default(), Default::default())
+        .await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "method_not_allowed");
+}
+
+#[tokio::test]
+async fn test_override_file_is_used_and_reloaded() {
+    let dir = pages_dir("override");
+    std::fs::write(dir.join("404.html"), "<h1>Lost? ({status} {reason})</h1>\n").unwrap();
+    std::fs::write(dir.join("notes.txt"), "ignored").unwrap();
+
+    let state = AppState::new(AppConfig { error_pages: ErrorPages::load(&dir).unwrap(), ..AppConfig::default() });
+    let client = TestClient::from_state(state.clone()).with_header("accept", BROWSER_ACCEPT);
+    // The page without the request-id comment appended to every page
+    let page = |client: TestClient| async move {
+        let response = client.get("/missing").await;
+        let comment = format!("<!-- request-id: {} -->\n", response.header("x-request-id").unwrap());
+        response.text().strip_suffix(&comment).expect("no request-id comment").to_string()
+    };
+    assert_eq!(page(client.clone()).await, "<h1>Lost? (404 Not Found)</h1>\n");
+
+    // Files are read when the configuration is loaded, not per request
+    std::fs::write(dir.join("404.html"), "<h1>Moved on</h1>\n").unwrap();
+    assert_eq!(page(client.clone()).await, "<h1>Lost? (404 Not Found)</h1>\n");
+
+    state.replace_config(AppConfig { error_pages: ErrorPages::load(&dir).unwrap(), ..AppConfig::default() });
+    assert_eq!(page(client).await, "<h1>Moved on</h1>\n");
+
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_broken_override_fails_to_load() {
+    let dir = pages_dir("broken");
+    std::fs::write(dir.join("500.html"), "<p>{{ if message }}unterminated</p>").unwrap();
+    assert!(ErrorPages::load(&dir).is_err());
+
+    std::fs::write(dir.join("500.html"), "<p>{no_such_field}</p>").unwrap();
+    assert!(ErrorPages::load(&dir).is_err());
+
+    assert!(ErrorPages::load(dir.join("missing")).is_err());
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,141 @@
+//! `/events` consumed chunk by chunk through the in-process router
+
+use axum::body::{Body, BodyDataStream};
+use axum::http::{Request, Response, StatusCode};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::create_app;
+use cloudflare_tunnel_example::events::EventsConfig;
+use cloudflare_tunnel_example::headers::X_CONTENT_TYPE_OPTIONS;
+use cloudflare_tunnel_example::state::AppState;
+use futures_util::StreamExt;
+use std::time::Duration;
+use tower::ServiceExt;
+
+fn state(events: EventsConfig) -> AppState {
+    AppState::new(AppConfig { events, ..AppConfig::default() })
+}
+
+fn fast() -> EventsConfig {
+    EventsConfig {
+        interval: Duration::from_millis(50),
+        ..EventsConfig::default()
+    }
+}
+
+async fn open(state: &AppState, last_event_id: Option<&str>) -> Response<Body> {
+    let mut request = Request::get("/events");
+    if let Some(id) = last_event_id {
+        request = request.header("last-event-id", id);
+    }
+    create_app(state.clone())
+        .oneshot(request.body(Body::empty()).unwrap())
+        .await
+        .expect("Failed to get response")
+}
+
+/// Next chunk of the stream as text, or `None` once it has ended
+async fn next_chunk(body: &mut BodyDataStream) -> Option<String> {
+    tokio::time::timeout(Duration::from_secs(5), body.next())
+        .await
+        .expect("Timed out waiting for the stream")
+        .map(|chunk| String::from_utf8(chunk.expect("Body error").to_vec()).unwrap())
+}
+
+/// Read until one full `stats` event has arrived and return its (id, data)
+async fn next_event(body: &mut BodyDataStream) -> (u64, serde_json::Value) {
+    let mut text = String::new();
+    while !text.contains("\n\n") {
+        text.push_str(&next_chunk(body).await.expect("Stream ended before an event"));
+    }
+
+    let field = |name: &str| {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap_or_else(|| panic!("Event without {}: {:?}", name, text))
+            .to_string()
+    };
+    assert_eq!(field("event: "), "stats");
+    (field("id: ").parse().unwrap(), serde_json::from_str(&field("data: ")).unwrap())
+}
+
+#[tokio::test]
+async fn test_streams_stats_events() {
+    let state = state(fast());
+    let response = open(&state, None).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    assert_eq!(response.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+
+    let mut body = response.into_body().into_data_stream();
+    let (id, data) = next_event(&mut body).await;
+    assert_eq!(id, 1);
+    assert!(data["uptime_seconds"].is_u64());
+    assert_eq!(data["open_streams"], 1);
+    #[cfg(feature = "metrics")]
+    assert!(data["requests_total"].as_u64().unwrap() >= 1);
+
+    assert_eq!(next_event(&mut body).await.0, 2);
+    assert_eq!(next_event(&mut body).await.0, 3);
+}
+
+#[tokio::test]
+async fn test_resumes_after_last_event_id() {
+    let state = state(fast());
+    let mut body = open(&state, Some("41")).await.into_body().into_data_stream();
+    assert_eq!(next_event(&mut body).await.0, 42);
+    assert_eq!(next_event(&mut body).await.0, 43);
+
+    // An unusable ID starts over
+    let mut body = open(&state, Some("soon")).await.into_body().into_data_stream();
+    assert_eq!(next_event(&mut body).await.0, 1);
+}
+
+#[tokio::test]
+async fn test_keep_alive_comments() {
+    let state = state(EventsConfig {
+        interval: Duration::from_secs(3600),
+        keep_alive: Duration::from_millis(50),
+        ..EventsConfig::default()
+    });
+    let mut body = open(&state, None).await.into_body().into_data_stream();
+
+    // The first event is immediate; after that only keep-alives arrive
+    next_event(&mut body).await;
+    for _ in 0..2 {
+        let chunk = next_chunk(&mut body).await.expect("Stream ended");
+        assert!(chunk.starts_with(':'), "expected a comment, got {:?}", chunk);
+    }
+}
+
+#[tokio::test]
+async fn test_caps_concurrent_streams() {
+    let state = state(EventsConfig { max_streams: 1, ..fast() });
+
+    let first = open(&state, None).await;
+    assert_eq!(first.status(), StatusCode::OK);
+
+    let refused = open(&state, None).await;
+    assert_eq!(refused.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = axum::body::to_bytes(refused.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["error"], "too_many_streams");
+
+    // Dropping the first stream, as a disconnect does, frees its slot
+    drop(first);
+    assert_eq!(state.event_streams.get(), 0);
+    assert_eq!(open(&state, None).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_draining_ends_stream() {
+    let state = state(fast());
+    let mut body = open(&state, None).await.into_body().into_data_stream();
+    next_event(&mut body).await;
+
+    state.set_ready(false);
+    while next_chunk(&mut body).await.is_some() {}
+    drop(body);
+    assert_eq!(state.event_streams.get(), 0);
+}
@@ -0,0 +1,113 @@
+//! Exit status and shutdown report of the binary
+
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+fn run(listen: &str, env: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cloudflare-tunnel-example"))
+        .args(["--listen", listen])
+        .env_clear()
+        .env("NO_COLOR", "1")
+        .envs(env.iter().copied())
+        .output()
+        .expect("Failed to run the binary")
+}
+
+#[test]
+fn test_config_error_exits_with_2() {
+    let output = run("127.0.0.1:0", &[("SECURITY_HSTS_MAX_AGE", "a year")]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let logs = String::from_utf8_lossy(&output.stdout);
+    assert!(logs.contains("Shutdown report"), "{}", logs);
+    assert!(logs.contains("requests=0"), "{}", logs);
+    assert!(logs.contains("reason=Configuration error: Invalid HSTS max age"), "{}", logs);
+    assert!(logs.contains("exit_code=2"), "{}", logs);
+}
+
+#[test]
+fn test_bind_error_exits_with_3() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let output = run(&taken.local_addr().unwrap().to_string(), &[]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("exit_code=3"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sigterm_during_startup_exits_cleanly() {
+    // Loading the configuration reads API_KEYS_FILE, a FIFO that holds the
+    // read until this test closes its end
+    let dir = std::env::temp_dir().join(format!("exit-codes-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let fifo = dir.join("api-keys");
+    let _ = std::fs::remove_file(&fifo);
+    assert!(Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cloudflare-tunnel-example"))
+        .args(["--listen", &format!("127.0.0.1:{}", port)])
+        .env_clear()
+        .env("NO_COLOR", "1")
+        .env("RUST_LOG", "info,cloudflare_tunnel_example::shutdown=debug")
+        .env("API_KEYS_FILE", &fifo)
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run the binary");
+    let (sender, lines) = std::sync::mpsc::channel();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    std::thread::spawn(move || stdout.lines().map_while(Result::ok).try_for_each(|line| sender.send(line)));
+
+    // The child cannot open the read end until there is a writer
+    let path = fifo.clone();
+    let writer = std::thread::spawn(move || std::fs::OpenOptions::new().write(true).open(path).unwrap());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let opened = fifo.canonicalize().unwrap();
+    while !has_open(child.id(), &opened) {
+        assert!(Instant::now() < deadline, "The configuration was never read");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let writer = writer.join().unwrap();
+
+    let started = Instant::now();
+    let killed = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    assert!(killed.success());
+    // Finish loading, with no API keys, once the signal has been noticed
+    let mut logs = String::new();
+    while !logs.contains("Shutdown signal received") {
+        let line = lines.recv_timeout(Duration::from_secs(10)).expect("The signal was never noticed");
+        logs.push_str(&line);
+        logs.push('\n');
+    }
+    drop(writer);
+
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > Duration::from_secs(10) {
+            child.kill().unwrap();
+            panic!("Still starting 10s after SIGTERM");
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    logs.extend(lines.iter().map(|line| line + "\n"));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert_eq!(status.code(), Some(0), "{}", logs);
+    assert!(logs.contains("Shutdown requested during startup"), "{}", logs);
+    assert!(logs.contains("reason=SIGTERM received"), "{}", logs);
+    assert!(!logs.contains("Starting server on"), "{}", logs);
+    // Never bound
+    std::net::TcpListener::bind(("127.0.0.1", port)).expect("The listen port is taken");
+}
+
+/// Whether process `pid` has `path` open
+#[cfg(unix)]
+fn has_open(pid: u32, path: &std::path::Path) -> bool {
+    let Ok(fds) = std::fs::read_dir(format!("/proc/{}/fd", pid)) else {
+        return false;
+    };
+    fds.filter_map(Result::ok).any(|fd| std::fs::read_link(fd.path()).is_ok_and(|target| target == path))
+}
@@ -0,0 +1,124 @@
+//! `/admin/faults` latency and error injection
+#![cfg(feature = "fault-injection")]
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, StatusCode};
+use cloudflare_tunnel_example::faults::FaultState;
+use cloudflare_tunnel_example::header_sampling::RandomSampler;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient, ADMIN_TOKEN};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+async fn start(state: &AppState, spec: Value) -> Value {
+    let response = TestClient::admin(state, Some(ADMIN_TOKEN)).post_json("/admin/faults", &spec).await;
+    assert_eq!(response.status(), StatusCode::OK, "{}", response.text());
+    response.json()
+}
+
+async fn faults(state: &AppState) -> Value {
+    TestClient::admin(state, Some(ADMIN_TOKEN)).get("/admin/faults").await.json()
+}
+
+fn seeded_state(seed: u64) -> (Arc<TestClock>, AppState) {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let mut state = AppState::default();
+    state.faults = FaultState::new(clock.clone(), Arc::new(RandomSampler::new(seed)));
+    (clock, state)
+}
+
+async fn statuses(client: &TestClient, uri: &str, count: usize) -> Vec<u16> {
+    let mut statuses = Vec::with_capacity(count);
+    for _ in 0..count {
+        statuses.push(client.get(uri).await.status().as_u16());
+    }
+    statuses
+}
+
+#[tokio::test]
+async fn test_faults_are_admin_only_off_at_startup_and_shown_by_readyz() {
+    let state = AppState::default();
+    let response = TestClient::admin(&state, None).post_json("/admin/faults", &json!({"latency_ms": 10})).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let client = TestClient::from_state(state.clone());
+    let readyz: Value = client.get("/readyz").await.json();
+    assert_eq!(readyz["faults"]["active"], false);
+
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+    let response = admin.post_json("/admin/faults", &json!({"error_rate": 2})).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.json::<Value>()["error"], "invalid_fault");
+
+    start(&state, json!({"error_rate": 1, "status": 502, "prefix": "/api", "ttl_seconds": 60})).await;
+    let response = client.get("/readyz").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let readyz: Value = response.json();
+    assert_eq!(readyz["faults"]["active"], true);
+    assert_eq!(readyz["faults"]["spec"]["status"], 502);
+
+    let response = admin.send(Method::DELETE, "/admin/faults", HeaderMap::new(), Body::empty()).await;
+    assert_eq!(response.json::<Value>()["spec"]["prefix"], "/api");
+    assert_eq!(faults(&state).await["active"], false);
+}
+
+#[tokio::test]
+async fn test_latency_is_added_under_the_prefix_only() {
+    let state = AppState::default();
+    let client = TestClient::from_state(state.clone());
+    start(&state, json!({"latency_ms": 200, "prefix": "/api"})).await;
+
+    let started = Instant::now();
+    assert_eq!(client.get("/api/users").await.status(), StatusCode::NOT_FOUND);
+    assert!(started.elapsed() >= Duration::from_millis(200), "{:?}", started.elapsed());
+
+    let started = Instant::now();
+    assert_eq!(client.get("/").await.status(), StatusCode::OK);
+    assert!(started.elapsed() < Duration::from_millis(200), "{:?}", started.elapsed());
+
+    let json = faults(&state).await;
+    assert_eq!((json["delayed"].as_u64(), json["failed"].as_u64()), (Some(1), Some(0)));
+}
+
+#[tokio::test]
+async fn test_errors_follow_the_seeded_rate() {
+    let spec = json!({"error_rate": 0.25, "status": 503, "prefix": "/api"});
+    let (_, state) = seeded_state(7);
+    let client = TestClient::from_state(state.clone());
+    start(&state, spec.clone()).await;
+
+    let first = statuses(&client, "/api/orders", 400).await;
+    let failed = first.iter().filter(|status| **status == 503).count();
+    assert!((70..=130).contains(&failed), "{} of 400 failed", failed);
+    assert!(first.iter().all(|status| matches!(status, 404 | 503)));
+    assert!(statuses(&client, "/", 20).await.iter().all(|status| *status == 200));
+
+    let response = client.get("/api/orders").await;
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        assert_eq!(response.json::<Value>()["error"], "injected_fault");
+    }
+
+    // The same seed injects the same sequence
+    let (_, replay) = seeded_state(7);
+    let replay_client = TestClient::from_state(replay.clone());
+    start(&replay, spec).await;
+    assert_eq!(statuses(&replay_client, "/api/orders", 400).await, first);
+}
+
+#[tokio::test]
+async fn test_fault_expires_after_ttl() {
+    let (clock, state) = seeded_state(1);
+    let client = TestClient::from_state(state.clone());
+    let json = start(&state, json!({"error_rate": 1, "ttl_seconds": 120})).await;
+    assert_eq!(json["expires_at"], "2024-01-01T00:02:00Z");
+
+    assert_eq!(client.get("/").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    clock.advance(Duration::from_secs(119));
+    assert_eq!(client.get("/").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(client.get("/").await.status(), StatusCode::OK);
+    let readyz: Value = client.get("/readyz").await.json();
+    assert_eq!(readyz["faults"]["active"], false);
+}
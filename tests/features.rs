@@ -0,0 +1,83 @@
+//! Route groups switched through `PUT /admin/features`
+
+use axum::http::{Method, StatusCode};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, ADMIN_TOKEN};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+#[tokio::test]
+async fn test_status_page_switched_off_and_on() {
+    let state = AppState::default();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+    let client = TestClient::from_state(state.clone());
+    assert_eq!(client.get("/status").await.status(), StatusCode::OK);
+
+    let response = admin.send_json(Method::PUT, "/admin/features", &json!({"status": false})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json();
+    assert_eq!(json["features"]["status"], false);
+
+    // Indistinguishable from a path that was never routed
+    let response = client.get("/status").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response.bytes().is_empty());
+
+    admin.send_json(Method::PUT, "/admin/features", &json!({"status": true})).await;
+    assert_eq!(client.get("/status").await.status(), StatusCode::OK);
+
+    let audit: Value = admin.get("/admin/audit").await.json();
+    let paths: Vec<&str> = audit["entries"].as_array().unwrap().iter().map(|e| e["path"].as_str().unwrap()).collect();
+    assert_eq!(paths, ["/admin/features", "/admin/features"]);
+}
+
+#[tokio::test]
+async fn test_unknown_group_changes_nothing() {
+    let state = AppState::default();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+
+    let response = admin.send_json(Method::PUT, "/admin/features", &json!({"status": false, "metrics": false})).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json();
+    assert_eq!(json["error"], "unknown_feature");
+    assert!(state.features.is_enabled("status"));
+}
+
+#[tokio::test]
+async fn test_untouched_groups_keep_their_startup_state() {
+    let config = AppConfig { feature_toggles: BTreeMap::from([("events".to_string(), false)]), ..AppConfig::default() };
+    let state = AppState::new(config);
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+
+    let json: Value = admin.get("/admin/pipeline").await.json();
+    assert_eq!((&json["features"]["events"], &json["features"]["status"]), (&json!(false), &json!(true)));
+    assert!(json["stages"].as_array().is_some_and(|stages| !stages.is_empty()));
+
+    let json: Value = admin.send_json(Method::PUT, "/admin/features", &json!({"status": false})).await.json();
+    assert_eq!((&json["features"]["events"], &json["features"]["status"]), (&json!(false), &json!(false)));
+    let client = TestClient::from_state(state);
+    assert_eq!(client.get("/events").await.status(), StatusCode::NOT_FOUND);
+}
+
+#[cfg(feature = "debug-endpoints")]
+#[tokio::test]
+async fn test_debug_groups_switched_on_at_runtime() {
+    // Off by default, as DEBUG_ENDPOINTS is
+    let state = AppState::default();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+    let client = TestClient::from_state(state);
+    assert_eq!(client.get("/whoami").await.status(), StatusCode::NOT_FOUND);
+    assert!(!client.get("/").await.text().contains("/whoami"));
+
+    let response = admin.send_json(Method::PUT, "/admin/features", &json!({"debug": true})).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(client.get("/whoami").await.status(), StatusCode::OK);
+    assert!(client.get("/").await.text().contains("<a href=\"/whoami\">/whoami</a>"));
+
+    // Each group on its own
+    assert_eq!(client.get("/echo").await.status(), StatusCode::NOT_FOUND);
+    admin.send_json(Method::PUT, "/admin/features", &json!({"echo": true, "debug": false})).await;
+    assert_eq!(client.get("/echo").await.status(), StatusCode::OK);
+    assert_eq!(client.get("/whoami").await.status(), StatusCode::NOT_FOUND);
+}
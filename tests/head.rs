@@ -0,0 +1,101 @@
+//! `HEAD` requests answered like `GET`, minus the body
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::headers::{CONTENT_SECURITY_POLICY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::static_files::StaticConfig;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient, TestResponse};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+async fn head(client: &TestClient, path: &str, headers: HeaderMap) -> TestResponse {
+    client.send(Method::HEAD, path, headers, Body::empty()).await
+}
+
+#[tokio::test]
+async fn test_head_root() {
+    let client = TestClient::from_state(AppState::default());
+    let get = client.get("/").await;
+    let head = head(&client, "/", HeaderMap::new()).await;
+
+    assert_eq!(head.status(), StatusCode::OK);
+    assert!(head.bytes().is_empty());
+    assert_eq!(head.normalized_headers(), get.normalized_headers());
+}
+
+#[tokio::test]
+async fn test_head_health() {
+    // Frozen, so the timestamp in the body is the same for both requests
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let client = TestClient::from_state(AppState::with_clock(AppConfig::default(), clock));
+    let get = client.get("/health").await;
+    let head = head(&client, "/health", HeaderMap::new()).await;
+
+    assert_eq!(head.status(), StatusCode::OK);
+    assert!(head.bytes().is_empty());
+    assert_eq!(head.header("content-type"), Some("application/json"));
+    assert_eq!(head.header_as::<usize>("content-length"), Some(get.bytes().len()));
+    for name in [X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS, CONTENT_SECURITY_POLICY, header::SERVER] {
+        assert_eq!(head.header(&name), get.header(&name), "{} differs", name);
+    }
+}
+
+#[tokio::test]
+async fn test_head_static_file() {
+    let dir = std::env::temp_dir().join(format!("head-static-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("app.css"), "body { color: red }").unwrap();
+    let client = TestClient::from_state(AppState::new(AppConfig {
+        static_files: Some(StaticConfig::new(&dir)),
+        ..AppConfig::default()
+    }));
+
+    let get = client.get("/static/app.css").await;
+    let head = head(&client, "/static/app.css", HeaderMap::new()).await;
+
+    assert_eq!(head.status(), StatusCode::OK);
+    assert!(head.bytes().is_empty());
+    assert_eq!(head.header("content-length"), Some("19"));
+    assert_eq!(head.header("content-type"), Some("text/css"));
+    assert_eq!(head.normalized_headers(), get.normalized_headers());
+}
+
+#[tokio::test]
+async fn test_head_revalidates_like_get() {
+    let client = TestClient::from_state(AppState::default());
+    let etag = client.get("/").await.header("etag").unwrap().to_string();
+
+    let mut headers = HeaderMap::new();
+    headers.insert("if-none-match", HeaderValue::from_str(&etag).unwrap());
+    let head = head(&client, "/", headers).await;
+
+    assert_eq!(head.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(head.header("etag"), Some(etag.as_str()));
+}
+
+#[tokio::test]
+async fn test_head_on_event_stream_is_rejected() {
+    let state = AppState::default();
+    let client = TestClient::from_state(state.clone());
+    let head = head(&client, "/events", HeaderMap::new()).await;
+
+    assert_eq!(head.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(head.header("allow"), Some("GET, OPTIONS"));
+    assert_eq!(state.event_streams.get(), 0);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_metrics_count_head_separately() {
+    let client = TestClient::from_state(AppState::default());
+    client.get("/").await;
+    head(&client, "/", HeaderMap::new()).await;
+    head(&client, "/health", HeaderMap::new()).await;
+
+    let metrics = client.get("/metrics").await.text();
+    assert!(metrics.contains("http_requests_by_method_total{method=\"HEAD\"} 2\n"), "{}", metrics);
+    assert!(metrics.contains("http_requests_by_method_total{method=\"GET\"} 2\n"), "{}", metrics);
+    assert_eq!(metrics.matches("# TYPE http_requests_by_method_total counter").count(), 1);
+}
@@ -0,0 +1,86 @@
+//! Response header size budget and `EXTRA_RESPONSE_HEADERS`
+
+use axum::body::Body;
+use axum::http::{header, HeaderName, HeaderValue, Request, StatusCode};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::config::{AppConfig, SecurityConfig};
+use cloudflare_tunnel_example::header_budget::{HeaderBudget, HeaderBudgetConfig};
+use cloudflare_tunnel_example::headers::{CONTENT_SECURITY_POLICY, X_FRAME_OPTIONS};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use serde_json::Value;
+use tower::ServiceExt;
+
+/// Security settings whose CSP takes about `bytes` bytes
+fn enormous_csp(bytes: usize) -> SecurityConfig {
+    let mut security = SecurityConfig::default();
+    security.csp.img_src = (0..bytes / 24).map(|i| format!("https://cdn{:05}.example ", i)).collect();
+    security
+}
+
+fn extra(name: &'static str, bytes: usize) -> (HeaderName, HeaderValue) {
+    (HeaderName::from_static(name), HeaderValue::from_str(&"x".repeat(bytes)).unwrap())
+}
+
+#[test]
+fn test_enormous_csp_is_warned_about() {
+    let config = AppConfig { security: enormous_csp(20_000), ..AppConfig::default() };
+    let budget = HeaderBudget::new(&config);
+
+    assert!(budget.total_bytes > 20_000 && budget.total_bytes == budget.security_bytes);
+    assert_eq!(budget.largest.as_ref().unwrap().header, CONTENT_SECURITY_POLICY);
+    let warnings = budget.warnings();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("content-security-policy header takes"), "{:?}", warnings);
+
+    assert!(HeaderBudget::new(&AppConfig::default()).warnings().is_empty());
+}
+
+#[tokio::test]
+async fn test_extra_headers_are_dropped_last_first() {
+    let header_budget = HeaderBudgetConfig {
+        extra_headers: vec![extra("x-first", 6_000), extra("x-second", 6_000), extra("x-third", 100)],
+        max_bytes: 28_000,
+        ..HeaderBudgetConfig::default()
+    };
+    let config = AppConfig { security: enormous_csp(20_000), header_budget, ..AppConfig::default() };
+    let client = TestClient::from_state(AppState::new(config));
+
+    let response = client.get("/health").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.header(CONTENT_SECURITY_POLICY).unwrap().len() > 20_000);
+    assert!(response.header(X_FRAME_OPTIONS).is_some());
+    assert!(response.header("x-first").is_some());
+    assert_eq!((response.header("x-second"), response.header("x-third")), (None, None));
+}
+
+#[tokio::test]
+async fn test_extra_headers_sent_within_budget() {
+    let header_budget = HeaderBudgetConfig {
+        extra_headers: vec![(HeaderName::from_static("x-robots-tag"), HeaderValue::from_static("noindex"))],
+        ..HeaderBudgetConfig::default()
+    };
+    let client = TestClient::from_state(AppState::new(AppConfig { header_budget, ..AppConfig::default() }));
+
+    assert_eq!(client.get("/health").await.header("x-robots-tag"), Some("noindex"));
+}
+
+#[tokio::test]
+async fn test_admin_security_score_shows_budget() {
+    let state = AppState::new(AppConfig { security: enormous_csp(20_000), ..AppConfig::default() });
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    let request = Request::builder()
+        .uri("/admin/security-score")
+        .header(header::AUTHORIZATION, "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = create_admin_app(&admin, &state).oneshot(request).await.unwrap();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+
+    let budget = &json["header_budget"];
+    assert!(budget["total_bytes"].as_u64().unwrap() > 20_000, "{}", budget);
+    assert_eq!(budget["max_bytes"], 32 * 1024);
+    assert_eq!(budget["largest"]["header"], CONTENT_SECURITY_POLICY.as_str());
+}
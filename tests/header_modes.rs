@@ -0,0 +1,86 @@
+//! Security headers a handler already set, kept or replaced per header mode
+
+use axum::http::{header, StatusCode};
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::headers::{HeaderMode, HeaderModes, CONTENT_SECURITY_POLICY, X_FRAME_OPTIONS};
+use cloudflare_tunnel_example::security_headers;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+
+const EMBED_CSP: &str = "frame-ancestors https://partner.example";
+
+/// A handler setting its own CSP, frame options and server name behind the
+/// security middleware
+fn client(header_modes: HeaderModes) -> TestClient {
+    let state = AppState::new(AppConfig { header_modes, ..AppConfig::default() });
+    let router = Router::new()
+        .route(
+            "/embed",
+            get(|| async {
+                (
+                    [
+                        (CONTENT_SECURITY_POLICY, EMBED_CSP),
+                        (X_FRAME_OPTIONS, "SAMEORIGIN"),
+                        (header::SERVER, "embed"),
+                    ],
+                    "embeddable",
+                )
+            }),
+        )
+        .route("/plain", get(|| async { "plain" }))
+        .layer(middleware::from_fn_with_state(state.clone(), security_headers))
+        .with_state(state);
+    TestClient::from_router(router)
+}
+
+fn modes(global: HeaderMode, exceptions: &[(&str, HeaderMode)]) -> HeaderModes {
+    let exceptions = exceptions.iter().map(|(name, mode)| (name.to_string(), *mode)).collect();
+    HeaderModes { global, exceptions }
+}
+
+#[tokio::test]
+async fn test_force_replaces_handler_values() {
+    let client = client(HeaderModes::default());
+    let response = client.get("/embed").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.header(CONTENT_SECURITY_POLICY), Some(EMBED_CSP));
+    assert_eq!(response.header(X_FRAME_OPTIONS), Some("DENY"));
+    // Server keeps the handler's value unless configured otherwise
+    assert_eq!(response.header(header::SERVER), Some("embed"));
+}
+
+#[tokio::test]
+async fn test_default_keeps_handler_values() {
+    let client = client(modes(HeaderMode::Default, &[]));
+
+    let response = client.get("/embed").await;
+    assert_eq!(response.header(CONTENT_SECURITY_POLICY), Some(EMBED_CSP));
+    assert_eq!(response.header(X_FRAME_OPTIONS), Some("SAMEORIGIN"));
+
+    // Headers the handler did not set are still added
+    let response = client.get("/plain").await;
+    assert!(response.header(CONTENT_SECURITY_POLICY).is_some());
+    assert_eq!(response.header(X_FRAME_OPTIONS), Some("DENY"));
+    assert_eq!(response.header(header::SERVER), Some("cloudflare-tunnel-example"));
+}
+
+#[tokio::test]
+async fn test_per_header_exceptions() {
+    let client = client(modes(
+        HeaderMode::Force,
+        &[("content-security-policy", HeaderMode::Default), ("server", HeaderMode::Force)],
+    ));
+
+    let response = client.get("/embed").await;
+    assert_eq!(response.header(CONTENT_SECURITY_POLICY), Some(EMBED_CSP));
+    assert_eq!(response.header(X_FRAME_OPTIONS), Some("DENY"));
+    assert_eq!(response.header(header::SERVER), Some("cloudflare-tunnel-example"));
+}
+
+#[test]
+fn test_exceptions_must_name_sent_headers() {
+    assert!(modes(HeaderMode::Force, &[("x-frame-options", HeaderMode::Default)]).validate().is_ok());
+    assert!(modes(HeaderMode::Force, &[("x-powered-by", HeaderMode::Default)]).validate().is_err());
+}
@@ -0,0 +1,84 @@
+//! Sampled logging of full request and response headers
+
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::headers::X_FRAME_OPTIONS;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{FixedSampler, TestClient};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Client whose every request is offered to the sampler at `rate`, and the
+/// debug logs it produces
+fn client(rate: f64, sampled: bool) -> (TestClient, Captured) {
+    let mut state = AppState::new(AppConfig { header_sample_rate: rate, ..AppConfig::default() });
+    state.header_sampler = Arc::new(FixedSampler(sampled));
+    let client = TestClient::from_state(state)
+        .with_header("authorization", "Bearer secret-token")
+        .with_header("x-custom-client", "drift-check");
+    (client, Captured::default())
+}
+
+async fn logs_of(client: &TestClient, captured: &Captured, uri: &str) -> (Option<String>, String) {
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let response = client.get(uri).await;
+    let marker = response.header("x-debug-sampled").map(str::to_string);
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    (marker, logs)
+}
+
+#[tokio::test]
+async fn test_sampled_request_logs_redacted_headers() {
+    let (client, captured) = client(0.001, true);
+    let (marker, logs) = logs_of(&client, &captured, "/health").await;
+    assert_eq!(marker.as_deref(), Some("1"));
+
+    let line = logs.lines().find(|line| line.contains("Sampled request headers")).expect(&logs);
+    assert!(line.contains("DEBUG"), "{}", line);
+    assert!(line.contains("request_id=\""), "{}", line);
+    assert!(line.contains("uri=/health"), "{}", line);
+    assert!(line.contains("status=200"), "{}", line);
+    assert!(line.contains(r#""x-custom-client":"drift-check""#), "{}", line);
+    assert!(line.contains(r#""authorization":"<redacted>""#), "{}", line);
+    assert!(!line.contains("secret-token"), "{}", line);
+    // Response headers as the client sees them, apart from the marker
+    assert!(line.contains(&format!(r#""{}":"DENY""#, X_FRAME_OPTIONS)), "{}", line);
+}
+
+#[tokio::test]
+async fn test_unsampled_requests_are_not_marked() {
+    let (client, captured) = client(0.001, false);
+    let (marker, logs) = logs_of(&client, &captured, "/health").await;
+    assert_eq!(marker, None);
+    assert!(!logs.contains("Sampled request headers"), "{}", logs);
+}
+
+#[tokio::test]
+async fn test_rate_zero_never_samples() {
+    // Even a sampler that would say yes is not asked
+    let (client, captured) = client(0.0, true);
+    let (marker, logs) = logs_of(&client, &captured, "/health").await;
+    assert_eq!(marker, None);
+    assert!(!logs.contains("Sampled request headers"), "{}", logs);
+    assert!(!logs.contains("request_headers"), "{}", logs);
+}
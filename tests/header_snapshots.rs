@@ -0,0 +1,22 @@
+//! Snapshots of the complete response header set for every security preset.
+//!
+//! Any change to the headers the service sends shows up as a snapshot diff;
+//! review it with `cargo insta review` and commit the updated `.snap` files.
+
+use cloudflare_tunnel_example::config::{SecurityConfig, SecurityPreset};
+use cloudflare_tunnel_example::testing::TestClient;
+
+#[tokio::test]
+async fn test_header_snapshots_per_preset() {
+    for preset in SecurityPreset::ALL {
+        let client = TestClient::new(SecurityConfig::preset(preset));
+
+        for (name, path) in [("root", "/"), ("health", "/health")] {
+            let response = client.get(path).await;
+            insta::assert_snapshot!(
+                format!("{}_{}", preset.name(), name),
+                response.normalized_headers()
+            );
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! `healthcheck` probes against a server on an ephemeral port
+
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::healthcheck::Probe;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::{create_app, ServerHandle};
+use std::net::SocketAddr;
+use std::process::Command;
+use tokio::net::TcpListener;
+
+async fn start(state: &AppState) -> ServerHandle {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    ServerHandle::start(listener, create_app(state.clone())).expect("Failed to start server")
+}
+
+/// An address nothing listens on
+fn refused() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn test_ready_service_is_healthy() {
+    let server = start(&AppState::default()).await;
+    assert_eq!(Probe::listener(server.local_addr()).run().await, Ok(()));
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_unready_service_is_unhealthy() {
+    let state = AppState::default();
+    let server = start(&state).await;
+    state.set_ready(false);
+
+    let reason = Probe::listener(server.local_addr()).run().await.unwrap_err();
+    assert!(reason.ends_with("answered 503 Service Unavailable"), "{}", reason);
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_refused_connection_is_unhealthy() {
+    let reason = Probe::listener(refused()).run().await.unwrap_err();
+    assert!(reason.contains("Failed to connect"), "{}", reason);
+}
+
+#[tokio::test]
+async fn test_admin_listener_is_probed_with_its_token() {
+    let state = AppState::default();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut admin = AdminConfig { addr: listener.local_addr().unwrap(), token: "admin-secret".to_string() };
+    let server = ServerHandle::start(listener, create_admin_app(&admin, &state)).unwrap();
+
+    assert_eq!(Probe::admin(&admin).run().await, Ok(()));
+    state.set_ready(false);
+    assert!(Probe::admin(&admin).run().await.unwrap_err().ends_with("answered 503 Service Unavailable"));
+
+    admin.token = "wrong".to_string();
+    assert!(Probe::admin(&admin).run().await.unwrap_err().ends_with("answered 401 Unauthorized"));
+    server.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_binary_exit_code_and_reason() {
+    let server = start(&AppState::default()).await;
+    let healthcheck = |listen: SocketAddr| {
+        Command::new(env!("CARGO_BIN_EXE_cloudflare-tunnel-example"))
+            .args(["healthcheck", "--listen", &listen.to_string()])
+            .env_clear()
+            .output()
+            .expect("Failed to run the binary")
+    };
+
+    let addr = server.local_addr();
+    let output = tokio::task::spawn_blocking(move || healthcheck(addr)).await.unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    // No logging is set up
+    assert!(output.stdout.is_empty() && output.stderr.is_empty());
+
+    let output = tokio::task::spawn_blocking(move || healthcheck(refused())).await.unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.lines().count(), 1, "{}", stderr);
+    assert!(stderr.starts_with("unhealthy: GET http://127.0.0.1:"), "{}", stderr);
+    server.stop().await.unwrap();
+}
@@ -0,0 +1,61 @@
+//! Localized homepage and error pages
+
+use axum::http::StatusCode;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+
+#[tokio::test]
+async fn test_homepage_follows_accept_language() {
+    let response = TestClient::from_state(AppState::default())
+        .with_header("accept-language", "de;q=0.9, en;q=0.8")
+        .get("/")
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.header("content-language"), Some("de"));
+    let body = response.text();
+    assert!(body.contains("<html lang=\"de\">"), "{}", body);
+    assert!(body.contains("Hallo Welt"), "{}", body);
+
+    // No preference, or none supported: English
+    for accept_language in [None, Some("fr-CH, fr;q=0.9")] {
+        let mut client = TestClient::from_state(AppState::default());
+        if let Some(value) = accept_language {
+            client = client.with_header("accept-language", value);
+        }
+        let response = client.get("/").await;
+        assert_eq!(response.header("content-language"), Some("en"), "{:?}", accept_language);
+        assert!(response.text().contains("Hello World"), "{:?}", accept_language);
+    }
+}
+
+#[tokio::test]
+async fn test_lang_parameter_overrides_the_header() {
+    let response = TestClient::from_state(AppState::default())
+        .with_header("accept-language", "en")
+        .get("/?lang=de")
+        .await;
+    assert_eq!(response.header("content-language"), Some("de"));
+    assert!(response.text().contains("Hallo Welt"));
+}
+
+#[tokio::test]
+async fn test_error_pages_are_localized() {
+    let response = TestClient::from_state(AppState::default())
+        .with_header("accept", "text/html")
+        .with_header("accept-language", "de-AT, en;q=0.5")
+        .get("/missing")
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.header("content-language"), Some("de"));
+    let body = response.text();
+    assert!(body.contains("<title>404 Nicht gefunden</title>"), "{}", body);
+    assert!(body.contains("Seite nicht gefunden"), "{}", body);
+
+    // JSON errors are not localized
+    let response = TestClient::from_state(AppState::default())
+        .with_header("accept", "application/json")
+        .with_header("accept-language", "de")
+        .get("/missing")
+        .await;
+    assert_eq!(response.header("content-language"), None);
+}
@@ -0,0 +1,123 @@
+//! `Idempotency-Key` replay on state-changing requests
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::{middleware, routing::post, Router};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::idempotency::{self, IdempotencyConfig, IdempotencyStore, IDEMPOTENCY_KEY, IDEMPOTENT_REPLAY};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient, TestResponse};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tower::ServiceExt;
+
+/// Router whose `/admin/op` handler reports how often it ran, after `delay`
+fn client(config: IdempotencyConfig, delay: Duration) -> (TestClient, Arc<TestClock>) {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let mut state = AppState::new(AppConfig { idempotency: config, ..AppConfig::default() });
+    state.idempotency = IdempotencyStore::new(clock.clone());
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let router = Router::new()
+        .route("/admin/op", post(move || async move {
+            tokio::time::sleep(delay).await;
+            (StatusCode::CREATED, format!("run {}", runs.fetch_add(1, Ordering::SeqCst) + 1))
+        }))
+        .layer(middleware::from_fn_with_state(state.clone(), idempotency::enforce))
+        .with_state(state);
+    (TestClient::from_router(router), clock)
+}
+
+async fn post_with_key(client: &TestClient, key: &str) -> TestResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_str(key).unwrap());
+    client.send(Method::POST, "/admin/op", headers, Body::empty()).await
+}
+
+#[tokio::test]
+async fn test_repeat_is_replayed() {
+    let (client, _) = client(IdempotencyConfig::default(), Duration::ZERO);
+
+    let first = post_with_key(&client, "purge-1").await;
+    assert_eq!((first.status(), first.text().as_str()), (StatusCode::CREATED, "run 1"));
+    assert_eq!(first.header(IDEMPOTENT_REPLAY), None);
+
+    let repeat = post_with_key(&client, "purge-1").await;
+    assert_eq!((repeat.status(), repeat.text().as_str()), (StatusCode::CREATED, "run 1"));
+    assert_eq!(repeat.header(IDEMPOTENT_REPLAY), Some("true"));
+
+    // Other keys, and requests without one, run as usual
+    assert_eq!(post_with_key(&client, "purge-2").await.text(), "run 2");
+    let unkeyed = client.send(Method::POST, "/admin/op", HeaderMap::new(), Body::empty()).await;
+    assert_eq!(unkeyed.text(), "run 3");
+}
+
+#[tokio::test]
+async fn test_concurrent_repeat_waits_for_the_first() {
+    let (client, _) = client(IdempotencyConfig::default(), Duration::from_millis(100));
+
+    let (a, b, other) = tokio::join!(
+        post_with_key(&client, "toggle"),
+        post_with_key(&client, "toggle"),
+        post_with_key(&client, "other"),
+    );
+    assert_eq!(a.text(), b.text());
+    assert_eq!([&a, &b].iter().filter(|r| r.header(IDEMPOTENT_REPLAY).is_some()).count(), 1);
+    assert_ne!(other.text(), a.text());
+    assert_eq!(other.header(IDEMPOTENT_REPLAY), None);
+}
+
+#[tokio::test]
+async fn test_key_expires_after_ttl() {
+    let config = IdempotencyConfig { ttl: Duration::from_secs(60), ..IdempotencyConfig::default() };
+    let (client, clock) = client(config, Duration::ZERO);
+
+    post_with_key(&client, "capture").await;
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(post_with_key(&client, "capture").await.header(IDEMPOTENT_REPLAY), Some("true"));
+
+    clock.advance(Duration::from_secs(1));
+    let response = post_with_key(&client, "capture").await;
+    assert_eq!((response.text().as_str(), response.header(IDEMPOTENT_REPLAY)), ("run 2", None));
+}
+
+#[tokio::test]
+async fn test_required_and_malformed_keys() {
+    let (client, _) = client(IdempotencyConfig { required: true, ..IdempotencyConfig::default() }, Duration::ZERO);
+
+    let missing = client.send(Method::POST, "/admin/op", HeaderMap::new(), Body::empty()).await;
+    assert_eq!(missing.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(missing.json::<serde_json::Value>()["error"], "idempotency_key_required");
+
+    let long = post_with_key(&client, &"k".repeat(256)).await;
+    assert_eq!(long.json::<serde_json::Value>()["error"], "invalid_idempotency_key");
+}
+
+#[tokio::test]
+async fn test_admin_maintenance_toggle_is_replayed() {
+    let state = AppState::new(AppConfig::default());
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    let app = create_admin_app(&admin, &state);
+    let toggle = |enabled: bool| {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/admin/maintenance")
+            .header(header::AUTHORIZATION, "Bearer admin-secret")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(IDEMPOTENCY_KEY, "enable-for-deploy")
+            .body(Body::from(serde_json::json!({ "enabled": enabled }).to_string()))
+            .unwrap()
+    };
+
+    let first = app.clone().oneshot(toggle(true)).await.unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+    assert!(state.maintenance.is_enabled());
+
+    // A stale retry under the same key does not undo the toggle
+    state.maintenance.set(false, None);
+    let retry = app.oneshot(toggle(true)).await.unwrap();
+    assert_eq!(retry.headers().get(IDEMPOTENT_REPLAY).unwrap(), "true");
+    assert!(!state.maintenance.is_enabled());
+}
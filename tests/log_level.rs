@@ -0,0 +1,62 @@
+//! `/admin/log-level` changes that revert on their own
+
+use axum::http::{Method, StatusCode};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::log_level::{LogLevel, LogLevelConfig};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient, ADMIN_TOKEN};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+fn state_with_clock() -> (Arc<TestClock>, AppState) {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let config = AppConfig { log_level: LogLevelConfig { revert_after: Duration::from_secs(300) }, ..AppConfig::default() };
+    let mut state = AppState::new(config);
+    state.log_level = LogLevel::new(clock.clone());
+    (clock, state)
+}
+
+#[tokio::test]
+async fn test_filter_is_set_and_reverts() {
+    let (clock, state) = state_with_clock();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+
+    let response = admin.get("/admin/log-level").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Value>(), json!({"filter": "info", "startup_filter": "info", "revert_at": null}));
+
+    let response = admin.send_json(Method::PUT, "/admin/log-level", &json!({"filter": "tower_http=debug,info"})).await;
+    assert_eq!(response.status(), StatusCode::OK, "{}", response.text());
+    let json: Value = response.json();
+    assert_eq!(json["filter"], "tower_http=debug,info");
+    assert_eq!(json["revert_at"], "2024-01-01T00:05:00Z");
+
+    clock.advance(Duration::from_secs(299));
+    let json: Value = admin.get("/admin/log-level").await.json();
+    assert_eq!(json["filter"], "tower_http=debug,info");
+
+    clock.advance(Duration::from_secs(1));
+    let json: Value = admin.get("/admin/log-level").await.json();
+    assert_eq!(json, json!({"filter": "info", "startup_filter": "info", "revert_at": null}));
+}
+
+#[tokio::test]
+async fn test_invalid_filters_are_rejected() {
+    let (_, state) = state_with_clock();
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+    admin.send_json(Method::PUT, "/admin/log-level", &json!({"filter": "debug"})).await;
+
+    for filter in ["tower_http=loud", "=[bad", " "] {
+        let response = admin.send_json(Method::PUT, "/admin/log-level", &json!({"filter": filter})).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{:?}", filter);
+        assert_eq!(response.json::<Value>()["error"], "invalid_filter");
+    }
+    // The filter in force is kept
+    let json: Value = admin.get("/admin/log-level").await.json();
+    assert_eq!(json["filter"], "debug");
+
+    // Setting the startup filter ends the override
+    let response = admin.send_json(Method::PUT, "/admin/log-level", &json!({"filter": "info"})).await;
+    assert_eq!(response.json::<Value>()["revert_at"], Value::Null);
+}
@@ -0,0 +1,51 @@
+//! Installing the global tracing subscriber more than once
+//!
+//! The subscriber is process-wide, so everything runs in one test in its own
+//! test binary.
+
+use cloudflare_tunnel_example::logging::{init_tracing, init_tracing_opts, TracingError, TracingInit, TracingOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_second_init_fails_or_skips_without_panicking() {
+    std::env::remove_var("RUST_LOG");
+    std::env::set_var("LOG_LEVEL", "debug");
+    let logs = Captured::default();
+    let writer = logs.clone();
+    let options = TracingOptions {
+        writer: Some(BoxMakeWriter::new(move || writer.clone())),
+        ..TracingOptions::default()
+    };
+    assert_eq!(init_tracing_opts(options).unwrap(), TracingInit::Installed);
+
+    tracing::debug!("copied to the extra writer");
+    tracing::trace!("below LOG_LEVEL");
+    let text = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(text.contains("DEBUG") && text.contains("copied to the extra writer"), "{}", text);
+    assert!(!text.contains("below LOG_LEVEL"), "{}", text);
+    assert!(!text.contains('\x1b'), "the extra writer gets no colours: {:?}", text);
+
+    assert!(matches!(init_tracing(), Err(TracingError::AlreadySet)));
+    let skip = TracingOptions { skip_if_set: true, ..TracingOptions::default() };
+    assert_eq!(init_tracing_opts(skip).unwrap(), TracingInit::Skipped);
+
+    // The first subscriber is still the one in use
+    tracing::info!("still captured");
+    assert!(String::from_utf8(logs.0.lock().unwrap().clone()).unwrap().contains("still captured"));
+}
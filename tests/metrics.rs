@@ -0,0 +1,58 @@
+//! `/metrics` in Prometheus text and OpenMetrics
+#![cfg(feature = "metrics")]
+
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+
+const OPENMETRICS_ACCEPT: &str = "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+#[tokio::test]
+async fn test_format_follows_accept() {
+    let client = TestClient::from_state(AppState::default());
+    client.get("/health").await;
+
+    let response = client.get("/metrics").await;
+    assert_eq!(response.header("content-type"), Some("text/plain; version=0.0.4"));
+    assert_eq!(response.header("vary"), Some("accept"));
+    let text = response.text();
+    assert!(text.contains("# TYPE http_requests_total counter\n"), "{}", text);
+    assert!(!text.contains("# EOF"), "{}", text);
+
+    let response = client.clone().with_header("accept", OPENMETRICS_ACCEPT).get("/metrics").await;
+    assert_eq!(
+        response.header("content-type"),
+        Some("application/openmetrics-text; version=1.0.0; charset=utf-8")
+    );
+    let text = response.text();
+    // Counter families drop `_total`; samples keep it
+    assert!(text.contains("# TYPE http_requests counter\n"), "{}", text);
+    assert!(text.contains("\nhttp_requests_total "), "{}", text);
+    assert!(text.contains("# TYPE http_request_duration_milliseconds histogram\n"), "{}", text);
+    assert!(text.ends_with("# EOF\n"), "{}", text);
+}
+
+#[cfg(feature = "otel")]
+#[tokio::test]
+async fn test_latency_buckets_carry_trace_exemplars() {
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let span_id = "00f067aa0ba902b7";
+    let client = TestClient::from_state(AppState::default());
+    client
+        .clone()
+        .with_header("traceparent", &format!("00-{}-{}-01", trace_id, span_id))
+        .get("/health")
+        .await;
+
+    let text = client.clone().with_header("accept", OPENMETRICS_ACCEPT).get("/metrics").await.text();
+    let exemplar = format!(" # {{trace_id=\"{}\",span_id=\"{}\"}} ", trace_id, span_id);
+    assert!(
+        text.lines()
+            .any(|line| line.starts_with("http_request_duration_milliseconds_bucket") && line.contains(&exemplar)),
+        "{}",
+        text
+    );
+
+    // Prometheus text has no exemplars
+    let text = client.get("/metrics").await.text();
+    assert!(!text.contains(trace_id), "{}", text);
+}
@@ -0,0 +1,200 @@
+//! Webhook notifications, delivered to a stub receiver
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use axum::{extract::State, routing::post, Json, Router};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::health::{CheckResult, HealthCheck};
+use cloudflare_tunnel_example::notifications::{self, HealthWatch, NotificationsConfig};
+use cloudflare_tunnel_example::shutdown::ShutdownToken;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClock;
+use cloudflare_tunnel_example::{serve_with_token, ServerHandle};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+
+/// Payloads received, after answering 500 to the first `failures` attempts
+#[derive(Clone, Default)]
+struct Receiver {
+    payloads: Arc<Mutex<Vec<Value>>>,
+    failures: Arc<AtomicUsize>,
+    attempts: Arc<AtomicUsize>,
+}
+
+impl Receiver {
+    async fn start(failures: usize) -> (Self, String, ServerHandle) {
+        let receiver = Self::default();
+        receiver.failures.store(failures, Ordering::SeqCst);
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(|State(receiver): State<Receiver>, Json(payload): Json<Value>| async move {
+                    if receiver.attempts.fetch_add(1, Ordering::SeqCst) < receiver.failures.load(Ordering::SeqCst) {
+                        return StatusCode::INTERNAL_SERVER_ERROR;
+                    }
+                    receiver.payloads.lock().unwrap().push(payload);
+                    StatusCode::NO_CONTENT
+                }),
+            )
+            .with_state(receiver.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = ServerHandle::start(listener, app).unwrap();
+        let url = format!("http://{}/hook", server.local_addr());
+        (receiver, url, server)
+    }
+
+    fn events(&self) -> Vec<String> {
+        self.payloads.lock().unwrap().iter().map(|p| p["event"].as_str().unwrap().to_string()).collect()
+    }
+}
+
+fn config(url: &str) -> NotificationsConfig {
+    NotificationsConfig {
+        retry_backoff: Duration::from_millis(10),
+        ..NotificationsConfig::new([url])
+    }
+}
+
+struct Toggle(Arc<AtomicBool>);
+
+#[axum::async_trait]
+impl HealthCheck for Toggle {
+    fn name(&self) -> &str {
+        "toggle"
+    }
+
+    async fn check(&self) -> CheckResult {
+        match self.0.load(Ordering::SeqCst) {
+            true => CheckResult::healthy("toggle", "up"),
+            false => CheckResult::unhealthy("toggle", "down"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_startup_and_shutdown() {
+    let (receiver, url, _stub) = Receiver::start(0).await;
+    let config = AppConfig { notifications: Some(config(&url)), ..AppConfig::default() };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (signal, token) = ShutdownToken::new();
+    let server = tokio::spawn(serve_with_token(config, listener, token));
+
+    for _ in 0..100 {
+        if !receiver.events().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    signal.send(Some("SIGTERM")).unwrap();
+    server.await.unwrap().unwrap();
+
+    // Shutdown waited for `stopping` to be delivered
+    assert_eq!(receiver.events(), ["started", "stopping"]);
+    let payloads = receiver.payloads.lock().unwrap();
+    let started = &payloads[0];
+    assert_eq!(started["service"], "cloudflare-tunnel-example");
+    assert_eq!(started["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(started["detail"], format!("serving on {}", addr));
+    assert!(started["timestamp"].as_str().unwrap().ends_with('Z'), "{}", started);
+    assert_eq!(started["text"], format!("cloudflare-tunnel-example {}: started (serving on {})", env!("CARGO_PKG_VERSION"), addr));
+    assert_eq!(payloads[1]["detail"], "SIGTERM received");
+}
+
+#[tokio::test]
+async fn test_health_flaps_are_suppressed() {
+    let (receiver, url, _stub) = Receiver::start(0).await;
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let up = Arc::new(AtomicBool::new(true));
+    let mut state = AppState::with_clock(AppConfig { notifications: Some(config(&url)), ..AppConfig::default() }, clock.clone());
+    state.health.register(Toggle(up.clone()));
+    let watch = Mutex::new(HealthWatch::default());
+
+    // Down for 30s of the 60s flap period, then back up
+    notifications::check_health(&state, &watch).await;
+    up.store(false, Ordering::SeqCst);
+    notifications::check_health(&state, &watch).await;
+    clock.advance(Duration::from_secs(30));
+    notifications::check_health(&state, &watch).await;
+    up.store(true, Ordering::SeqCst);
+    notifications::check_health(&state, &watch).await;
+    state.notifier.flush().await;
+    assert!(receiver.events().is_empty(), "{:?}", receiver.events());
+
+    // Down for the whole period
+    up.store(false, Ordering::SeqCst);
+    notifications::check_health(&state, &watch).await;
+    clock.advance(Duration::from_secs(60));
+    notifications::check_health(&state, &watch).await;
+    clock.advance(Duration::from_secs(60));
+    notifications::check_health(&state, &watch).await;
+    state.notifier.flush().await;
+    assert_eq!(receiver.events(), ["unhealthy"]);
+    assert_eq!(receiver.payloads.lock().unwrap()[0]["detail"], "toggle: down");
+
+    up.store(true, Ordering::SeqCst);
+    notifications::check_health(&state, &watch).await;
+    clock.advance(Duration::from_secs(60));
+    notifications::check_health(&state, &watch).await;
+    state.notifier.flush().await;
+    assert_eq!(receiver.events(), ["unhealthy", "recovered"]);
+    assert_eq!(receiver.payloads.lock().unwrap()[1]["timestamp"], "2024-01-01T00:03:30Z");
+}
+
+#[tokio::test]
+async fn test_maintenance_toggles() {
+    let (receiver, url, _stub) = Receiver::start(0).await;
+    let state = AppState::new(AppConfig { notifications: Some(config(&url)), ..AppConfig::default() });
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    let app = create_admin_app(&admin, &state);
+
+    for body in [
+        json!({ "enabled": true, "message": "Upgrading" }),
+        json!({ "enabled": true }),
+        json!({ "enabled": false }),
+    ] {
+        let request = Request::post("/admin/maintenance")
+            .header(header::AUTHORIZATION, "Bearer admin-secret")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        assert_eq!(app.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+    }
+    state.notifier.flush().await;
+
+    // Staying on is not a change
+    assert_eq!(receiver.events(), ["maintenance_on", "maintenance_off"]);
+    assert_eq!(receiver.payloads.lock().unwrap()[0]["detail"], "Upgrading");
+}
+
+#[tokio::test]
+async fn test_retries_and_dead_letters() {
+    let (receiver, url, _stub) = Receiver::start(2).await;
+    let state = AppState::new(AppConfig { notifications: Some(config(&url)), ..AppConfig::default() });
+    notifications::notify(&state, notifications::NotificationEvent::Started, "retried");
+    state.notifier.flush().await;
+    assert_eq!(receiver.events(), ["started"]);
+    assert_eq!(receiver.attempts.load(Ordering::SeqCst), 3);
+    assert_eq!(state.notifier.dead_letters(), 0);
+
+    // Every attempt fails: 1 + NOTIFY_MAX_RETRIES, then a dead letter
+    let (receiver, url, _stub) = Receiver::start(usize::MAX).await;
+    let state = AppState::new(AppConfig { notifications: Some(config(&url)), ..AppConfig::default() });
+    notifications::notify(&state, notifications::NotificationEvent::Started, "dropped");
+    state.notifier.flush().await;
+    assert_eq!(receiver.attempts.load(Ordering::SeqCst), 4);
+    assert_eq!(state.notifier.dead_letters(), 1);
+
+    // An unreachable receiver is a dead letter too, and serving goes on
+    let state = AppState::new(AppConfig { notifications: Some(config("http://127.0.0.1:1/hook")), ..AppConfig::default() });
+    notifications::notify(&state, notifications::NotificationEvent::Started, "unreachable");
+    let client = cloudflare_tunnel_example::testing::TestClient::from_state(state.clone());
+    assert_eq!(client.get("/health").await.status(), StatusCode::OK);
+    state.notifier.flush().await;
+    assert_eq!(state.notifier.dead_letters(), 1);
+}
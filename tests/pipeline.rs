@@ -0,0 +1,136 @@
+//! The main router's middleware stages and their ordering rules
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::pipeline::{check, INVARIANTS};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::static_files::StaticConfig;
+use cloudflare_tunnel_example::{create_app, AppBuilder};
+use serde_json::Value;
+use tower::ServiceExt;
+
+/// Stages of the default configuration with every feature, outermost first
+const DEFAULT_ORDER: &[&str] = &[
+    "trace",
+    "request_id",
+    "tenants",
+    "client_ip",
+    "cf_ray",
+    "access_log",
+    "header_sampling",
+    "anomalies",
+    "disconnects",
+    "trace_context",
+    "capture",
+    "access",
+    "request_events",
+    "request_metrics",
+    "scheme",
+    "build_headers",
+    "server_timing",
+    "security_headers",
+    "cookies",
+    "response_digest",
+    "error_pages",
+    "response_size",
+    "header_limits",
+    "allowed_methods",
+    "normalize_path",
+    "routing",
+    "route_stats",
+    "matched_route",
+    "timeouts",
+    "concurrency",
+    "cache_policy",
+    "denial_signal",
+    "direct_access",
+    "api_keys",
+    "bearer_auth",
+    "csrf",
+    "maintenance",
+    "faults",
+    "idempotency",
+    "response_cache",
+    "canary",
+    "content_digest",
+    "decompression",
+    "routes",
+];
+
+/// [`DEFAULT_ORDER`] without the stages of features this build lacks
+fn default_order() -> Vec<&'static str> {
+    let mut order = DEFAULT_ORDER.to_vec();
+    if !cfg!(feature = "metrics") {
+        order.retain(|stage| *stage != "request_metrics");
+    }
+    if !cfg!(feature = "fault-injection") {
+        order.retain(|stage| *stage != "faults");
+    }
+    order
+}
+
+#[test]
+fn test_default_order() {
+    let pipeline = AppBuilder::new(AppState::default()).pipeline();
+    assert_eq!(pipeline.order(), default_order());
+    assert!(pipeline.check().is_ok());
+}
+
+#[test]
+fn test_optional_stages_keep_the_invariants() {
+    let dir = std::env::temp_dir();
+    let config = AppConfig {
+        force_https_redirect: true,
+        static_files: Some(StaticConfig::new(&dir)),
+        ..AppConfig::default()
+    };
+    let order = AppBuilder::new(AppState::new(config)).pipeline().order();
+    let position = |name: &str| order.iter().position(|stage| *stage == name).unwrap();
+    assert!(position("security_headers") < position("https_redirect"));
+    assert!(position("static_files") < position("decompression"));
+    assert!(check(&order).is_ok());
+
+    // Leaving a stage out removes it and nothing else
+    let order = AppBuilder::new(AppState::default()).security_headers(false).pipeline().order();
+    let mut expected = default_order();
+    expected.retain(|stage| *stage != "security_headers");
+    assert_eq!(order, expected);
+    assert!(check(&order).is_ok());
+}
+
+#[test]
+fn test_every_invariant_catches_a_swap() {
+    let order = default_order();
+    for invariant in INVARIANTS {
+        let Some(before) = order.iter().position(|stage| *stage == invariant.before) else {
+            continue;
+        };
+        for after in invariant.after.iter().filter_map(|after| order.iter().position(|stage| stage == after)) {
+            let mut swapped = order.clone();
+            swapped.swap(before, after);
+            // Possibly reported by another rule the swap broke as well
+            assert!(check(&swapped).is_err(), "{:?}", swapped);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_admin_pipeline_lists_the_built_order() {
+    let state = AppState::default();
+    let _app = create_app(state.clone());
+    assert_eq!(state.pipeline(), default_order());
+
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    let request = Request::builder()
+        .uri("/admin/pipeline")
+        .header(header::AUTHORIZATION, "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let response = create_admin_app(&admin, &state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["stages"], serde_json::json!(default_order()));
+}
@@ -0,0 +1,134 @@
+//! HTTP/1.1 keep-alive and h2c settings over a real socket, driven by raw requests
+
+use cloudflare_tunnel_example::protocol::ProtocolConfig;
+use cloudflare_tunnel_example::{create_app_with_defaults, ServerHandle};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const HEALTH: &[u8] = b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+async fn start(protocol: ProtocolConfig) -> ServerHandle {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind test listener");
+    ServerHandle::start_with_protocol(listener, create_app_with_defaults(), protocol).expect("Failed to start server")
+}
+
+/// Read one response with a `content-length` body; returns its head
+async fn read_response(stream: &mut TcpStream, buffered: &mut Vec<u8>) -> String {
+    loop {
+        if let Some(end) = buffered.windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buffered[..end]).to_ascii_lowercase();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .map(|value| value.trim().parse().unwrap())
+                .unwrap_or(0);
+            if buffered.len() >= end + 4 + length {
+                buffered.drain(..end + 4 + length);
+                return head;
+            }
+        }
+        let mut chunk = [0; 4096];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut chunk))
+            .await
+            .expect("Timed out waiting for a response")
+            .unwrap();
+        assert!(read > 0, "Connection closed mid-response");
+        buffered.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// Whether the server closes `stream` within `wait`
+async fn closed_within(stream: &mut TcpStream, wait: Duration) -> bool {
+    let mut chunk = [0; 64];
+    matches!(tokio::time::timeout(wait, stream.read(&mut chunk)).await, Ok(Ok(0)) | Ok(Err(_)))
+}
+
+#[tokio::test]
+async fn test_connection_is_reused_and_pipelined() {
+    let server = start(ProtocolConfig::default()).await;
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    let mut buffered = Vec::new();
+
+    stream.write_all(HEALTH).await.unwrap();
+    assert!(read_response(&mut stream, &mut buffered).await.starts_with("http/1.1 200"));
+    stream.write_all(HEALTH).await.unwrap();
+    let second = read_response(&mut stream, &mut buffered).await;
+    assert!(second.starts_with("http/1.1 200"), "{}", second);
+    assert!(!second.contains("connection: close"), "{}", second);
+
+    // Both pipelined requests are answered, in order
+    stream.write_all(&[HEALTH, b"GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n"].concat()).await.unwrap();
+    assert!(read_response(&mut stream, &mut buffered).await.starts_with("http/1.1 200"));
+    assert!(read_response(&mut stream, &mut buffered).await.starts_with("http/1.1 404"));
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_idle_connection_is_closed_after_timeout() {
+    let server = start(ProtocolConfig { keep_alive_timeout: Duration::from_secs(1), ..ProtocolConfig::default() }).await;
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    stream.write_all(HEALTH).await.unwrap();
+    read_response(&mut stream, &mut Vec::new()).await;
+    assert!(!closed_within(&mut stream, Duration::from_millis(500)).await);
+    assert!(closed_within(&mut stream, Duration::from_secs(2)).await);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_keep_alive_off_closes_after_one_response() {
+    let server = start(ProtocolConfig { keep_alive: false, ..ProtocolConfig::default() }).await;
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    stream.write_all(HEALTH).await.unwrap();
+    let head = read_response(&mut stream, &mut Vec::new()).await;
+    assert!(head.contains("connection: close"), "{}", head);
+    assert!(closed_within(&mut stream, Duration::from_secs(2)).await);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_h2c_prior_knowledge() {
+    use axum::body::Body;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+
+    let server = start(ProtocolConfig::default()).await;
+    let stream = TcpStream::connect(server.local_addr()).await.unwrap();
+    let (mut sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await.unwrap();
+    tokio::spawn(connection);
+
+    // Both requests share the one connection
+    for _ in 0..2 {
+        let request = hyper::Request::get(format!("http://{}/health", server.local_addr()))
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert_eq!(response.status(), 200);
+        let body = axum::body::to_bytes(Body::new(response.into_body()), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("healthy"), "{:?}", body);
+    }
+
+    drop(sender);
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_h2c_can_be_disabled() {
+    let server = start(ProtocolConfig { http2: false, ..ProtocolConfig::default() }).await;
+    let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+
+    // Parsed as an HTTP/1.1 request line, which hyper refuses by closing the
+    // connection; an HTTP/2 server would answer with a SETTINGS frame instead
+    stream.write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").await.unwrap();
+    assert!(closed_within(&mut stream, Duration::from_secs(2)).await);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
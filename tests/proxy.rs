@@ -0,0 +1,236 @@
+//! `PROXY_ROUTES` forwarding to a stub upstream over real sockets
+#![cfg(feature = "proxy")]
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::IntoResponse;
+use axum::extract::Request;
+use axum::middleware::{self, Next};
+use axum::routing::{any, get};
+use axum::Router;
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::headers::{X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS};
+use cloudflare_tunnel_example::http_client::HttpClient;
+use cloudflare_tunnel_example::proxy::{self, ProxyConfig};
+use cloudflare_tunnel_example::timeouts::Deadline;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::{create_app, ServerHandle};
+use serde_json::json;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Echoes what it received as JSON, plus a couple of routes that misbehave
+async fn start_upstream() -> SocketAddr {
+    async fn echo(method: Method, uri: Uri, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+        let headers: serde_json::Map<_, _> = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), json!(value.to_str().unwrap_or_default())))
+            .collect();
+        (
+            [("keep-alive", "timeout=5"), ("x-upstream", "echo")],
+            axum::Json(json!({
+                "method": method.as_str(),
+                "uri": uri.to_string(),
+                "headers": headers,
+                "body_len": body.len(),
+            })),
+        )
+    }
+
+    let upstream = Router::new()
+        .route("/", any(echo))
+        .route("/*rest", any(echo))
+        .route("/fail", get(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "upstream exploded") }))
+        .route("/slow", get(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "too late"
+        }));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind upstream");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+    addr
+}
+
+async fn start(proxy: ProxyConfig) -> ServerHandle {
+    let state = AppState::new(AppConfig { proxy: Some(proxy), ..AppConfig::default() });
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind test listener");
+    ServerHandle::start(listener, create_app(state)).expect("Failed to start server")
+}
+
+#[tokio::test]
+async fn test_forwards_request_and_streams_body() {
+    let upstream = start_upstream().await;
+    let server = start(ProxyConfig::new("/api", &format!("http://{}", upstream)).unwrap()).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/api/items/7?verbose=1", server.local_addr()))
+        .body(vec![b'x'; 256 * 1024])
+        .send()
+        .await
+        .expect("Request failed");
+
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(response.headers()["x-upstream"], "echo");
+    assert!(response.headers().get("keep-alive").is_none());
+    // Security headers still go on top of the upstream's response
+    assert_eq!(response.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(response.headers()[X_FRAME_OPTIONS], "DENY");
+
+    let echo: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(echo["method"], "POST");
+    assert_eq!(echo["uri"], "/items/7?verbose=1");
+    assert_eq!(echo["body_len"], 256 * 1024);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_forwarding_headers() {
+    let upstream = start_upstream().await;
+    let server = start(ProxyConfig::new("/api", &format!("http://{}/v1", upstream)).unwrap()).await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/api/whoami", server.local_addr()))
+        .header("host", "hello.halibut.cc")
+        .header("cf-visitor", r#"{"scheme":"https"}"#)
+        .header("x-forwarded-for", "203.0.113.7")
+        .header("connection", "x-session-hint")
+        .header("x-session-hint", "hop-only")
+        .header("proxy-authorization", "Basic c2VjcmV0")
+        .header("authorization", "Bearer end-to-end")
+        .send()
+        .await
+        .expect("Request failed");
+    let echo: serde_json::Value = response.json().await.unwrap();
+    let headers = &echo["headers"];
+
+    assert_eq!(echo["uri"], "/v1/whoami");
+    assert_eq!(headers["host"], upstream.to_string());
+    assert_eq!(headers["x-forwarded-host"], "hello.halibut.cc");
+    assert_eq!(headers["x-forwarded-for"], "203.0.113.7, 127.0.0.1");
+    assert_eq!(headers["x-forwarded-proto"], "https");
+    assert_eq!(headers["authorization"], "Bearer end-to-end");
+    assert!(headers.get("x-session-hint").is_none());
+    assert!(headers.get("proxy-authorization").is_none());
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_upstream_error_passes_through() {
+    let upstream = start_upstream().await;
+    let server = start(ProxyConfig::new("/api", &format!("http://{}", upstream)).unwrap()).await;
+
+    let response = reqwest::get(format!("http://{}/api/fail", server.local_addr()))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.text().await.unwrap(), "upstream exploded");
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_refused_connection_is_502() {
+    // Bind and release a port so nothing is listening on it
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let server = start(ProxyConfig::new("/api", &format!("http://{}", closed)).unwrap()).await;
+
+    let response = reqwest::get(format!("http://{}/api/anything", server.local_addr()))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_GATEWAY);
+    assert_eq!(response.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "upstream_unavailable");
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_upstream_timeout_is_504() {
+    let upstream = start_upstream().await;
+    let mut proxy = ProxyConfig::new("/api", &format!("http://{}", upstream)).unwrap();
+    proxy.timeout = Duration::from_millis(200);
+    let server = start(proxy).await;
+
+    let response = reqwest::get(format!("http://{}/api/slow", server.local_addr()))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), reqwest::StatusCode::GATEWAY_TIMEOUT);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "upstream_timeout");
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_request_deadline_bounds_the_upstream_call() {
+    let upstream = start_upstream().await;
+    let proxy = ProxyConfig::new("/api", &format!("http://{}", upstream)).unwrap();
+    let serve = |left: Duration| {
+        let router = proxy::mount(Router::new(), &proxy, &HttpClient::default()).layer(middleware::from_fn(move |mut request: Request, next: Next| async move {
+            request.extensions_mut().insert(Deadline::after(left));
+            next.run(request).await
+        }));
+        async move { ServerHandle::start(TcpListener::bind("127.0.0.1:0").await.unwrap(), router).unwrap() }
+    };
+
+    // 100ms left of the request is all the upstream gets, not its 30s
+    let server = serve(Duration::from_millis(100)).await;
+    let started = std::time::Instant::now();
+    let response = reqwest::get(format!("http://{}/api/slow", server.local_addr())).await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    assert_eq!(response.status(), reqwest::StatusCode::GATEWAY_TIMEOUT);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "deadline_exceeded");
+
+    // Nothing left: the upstream is not called
+    let server = serve(Duration::ZERO).await;
+    let response = reqwest::get(format!("http://{}/api/items", server.local_addr())).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::GATEWAY_TIMEOUT);
+    assert!(response.headers().get("x-upstream").is_none());
+}
+
+#[tokio::test]
+async fn test_unrouted_paths_are_not_proxied() {
+    let upstream = start_upstream().await;
+    let server = start(ProxyConfig::new("/api", &format!("http://{}", upstream)).unwrap()).await;
+
+    let response = reqwest::get(format!("http://{}/apiary", server.local_addr()))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    assert!(response.headers().get("x-upstream").is_none());
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_client_disconnect_closes_upstream_connection() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Reads the request, never answers, and reports when the proxy hangs up
+    let upstream = TcpListener::bind("127.0.0.1:0").await.expect("Failed to bind upstream");
+    let upstream_addr = upstream.local_addr().unwrap();
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream.accept().await.unwrap();
+        let mut buf = [0u8; 4096];
+        while socket.read(&mut buf).await.map(|n| n > 0).unwrap_or(false) {}
+        let _ = closed_tx.send(());
+    });
+
+    let server = start(ProxyConfig::new("/api", &format!("http://{}", upstream_addr)).unwrap()).await;
+    let mut client = tokio::net::TcpStream::connect(server.local_addr()).await.unwrap();
+    client.write_all(b"GET /api/hang HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(client);
+
+    tokio::time::timeout(Duration::from_secs(2), closed_rx)
+        .await
+        .expect("Upstream connection stayed open after the client left")
+        .unwrap();
+}
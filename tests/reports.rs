@@ -0,0 +1,119 @@
+//! Reporting API headers and the `/reports` endpoint
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use cloudflare_tunnel_example::config::SecurityConfig;
+use cloudflare_tunnel_example::headers::{CONTENT_SECURITY_POLICY, PERMISSIONS_POLICY, REPORTING_ENDPOINTS};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, TestResponse};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+const MIXED_BATCH: &str = r#"[
+    {"type": "csp-violation", "age": 3, "url": "https://hello.halibut.cc/",
+     "user_agent": "Mozilla/5.0",
+     "body": {"documentURL": "https://hello.halibut.cc/", "blockedURL": "https://cdn.example/x.js",
+              "effectiveDirective": "script-src-elem", "disposition": "enforce", "statusCode": 200}},
+    {"type": "csp-violation", "url": "https://hello.halibut.cc/about",
+     "body": {"blockedURL": "inline", "effectiveDirective": "style-src-attr", "disposition": "report"}},
+    {"type": "permissions-policy-violation", "url": "https://hello.halibut.cc/map",
+     "body": {"featureId": "geolocation", "disposition": "enforce", "sourceFile": "https://hello.halibut.cc/map.js"}},
+    {"type": "deprecation", "url": "https://hello.halibut.cc/",
+     "body": {"id": "UnloadHandler", "message": "Unload event listeners are deprecated"}},
+    {"type": "intervention", "url": "https://hello.halibut.cc/", "body": {"id": "HeavyAdIntervention"}}
+]"#;
+
+async fn post_reports(client: &TestClient, body: &str) -> TestResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/reports+json"));
+    client.send(Method::POST, "/reports", headers, Body::from(body.to_string())).await
+}
+
+#[tokio::test]
+async fn test_report_to_adds_reporting_headers() {
+    let config = SecurityConfig { report_to: Some("default".to_string()), ..SecurityConfig::default() };
+    let response = TestClient::new(config).get("/").await;
+
+    assert_eq!(response.header(REPORTING_ENDPOINTS), Some("default=\"/reports\""));
+    assert_eq!(
+        response.header(PERMISSIONS_POLICY),
+        Some("geolocation=();report-to=default, microphone=();report-to=default, camera=();report-to=default")
+    );
+    assert!(response.header(CONTENT_SECURITY_POLICY).unwrap().ends_with("; report-to default"));
+
+    let response = TestClient::new(SecurityConfig::default()).get("/").await;
+    assert_eq!(response.header(REPORTING_ENDPOINTS), None);
+    assert!(!response.header(CONTENT_SECURITY_POLICY).unwrap().contains("report-to"));
+}
+
+#[tokio::test]
+async fn test_mixed_batch_is_parsed_and_counted() {
+    let state = AppState::default();
+    let client = TestClient::from_state(state.clone());
+    let logs = Captured::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let response = post_reports(&client, MIXED_BATCH).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let line = |message: &str| {
+        logs.lines()
+            .filter(|line| line.contains(message))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    let csp = line("CSP violation reported");
+    assert_eq!(csp.len(), 2, "{}", logs);
+    assert!(csp[0].contains("directive=\"script-src-elem\"") && csp[0].contains("blocked=\"https://cdn.example/x.js\""));
+    assert!(csp[1].contains("disposition=\"report\""));
+    assert!(line("Permissions-Policy violation reported")[0].contains("feature=\"geolocation\""));
+    assert!(line("Deprecation reported")[0].contains("id=\"UnloadHandler\""));
+    let unknown = line("Report of unknown type");
+    assert!(unknown[0].contains("DEBUG") && unknown[0].contains("report_type=\"intervention\""));
+
+    #[cfg(feature = "metrics")]
+    {
+        let metrics = state.metrics.render();
+        for (report_type, count) in [
+            ("csp-violation", 2),
+            ("permissions-policy-violation", 1),
+            ("deprecation", 1),
+            ("unknown", 1),
+        ] {
+            let series = format!("reports_total{{type=\"{}\"}} {}", report_type, count);
+            assert!(metrics.contains(&series), "missing {} in\n{}", series, metrics);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_malformed_batch_is_rejected() {
+    let client = TestClient::from_state(AppState::default());
+
+    let response = post_reports(&client, r#"{"type": "csp-violation"}"#).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "invalid_report");
+
+    assert_eq!(post_reports(&client, "[]").await.status(), StatusCode::NO_CONTENT);
+}
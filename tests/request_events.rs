@@ -0,0 +1,117 @@
+//! One `RequestCompleted` per request, fanned out to every subscriber
+
+use axum::http::{Method, StatusCode};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::request_events::{RequestCompleted, RequestEventsConfig};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+type Seen = Arc<Mutex<Vec<Arc<RequestCompleted>>>>;
+
+/// Subscribe as `name`, keeping every event handled
+fn collect(state: &AppState, name: &'static str) -> Seen {
+    let seen = Seen::default();
+    let events = seen.clone();
+    state.request_events.subscribe(name, move |event| {
+        events.lock().unwrap().push(event);
+        async {}
+    });
+    seen
+}
+
+/// Subscribe as `name` with a handler that waits until `release` is sent
+fn stall(state: &AppState, name: &'static str) -> (watch::Sender<bool>, Seen) {
+    let (release, released) = watch::channel(false);
+    let seen = Seen::default();
+    let events = seen.clone();
+    state.request_events.subscribe(name, move |event| {
+        let mut released = released.clone();
+        let events = events.clone();
+        async move {
+            let _ = released.wait_for(|released| *released).await;
+            events.lock().unwrap().push(event);
+        }
+    });
+    (release, seen)
+}
+
+#[tokio::test]
+async fn test_every_subscriber_sees_every_request() {
+    let state = AppState::default();
+    let first = collect(&state, "first");
+    let second = collect(&state, "second");
+    let client = TestClient::from_state(state.clone()).with_header("cf-connecting-ip", "203.0.113.7");
+
+    assert_eq!(client.get("/health").await.status(), StatusCode::OK);
+    assert_eq!(client.get("/missing?token=x").await.status(), StatusCode::NOT_FOUND);
+    state.request_events.flush().await;
+
+    let first = first.lock().unwrap().clone();
+    assert_eq!(first, *second.lock().unwrap());
+    assert_eq!(first.len(), 2);
+    let health = &first[0];
+    assert_eq!((&health.method, health.route.as_deref(), health.status), (&Method::GET, Some("/health"), StatusCode::OK));
+    assert_eq!(health.client_ip, Some("203.0.113.7".parse::<IpAddr>().unwrap()));
+    assert!(health.bytes.is_some_and(|bytes| bytes > 0), "{:?}", health);
+    let missing = &first[1];
+    assert_eq!((missing.route.as_deref(), missing.path.as_str()), (None, "/missing"));
+    assert_eq!(missing.status, StatusCode::NOT_FOUND);
+    assert_eq!(state.request_events.published(), 2);
+}
+
+#[tokio::test]
+async fn test_stalled_subscriber_loses_the_oldest_events() {
+    let state = AppState::new(AppConfig {
+        request_events: RequestEventsConfig { capacity: 4 },
+        ..AppConfig::default()
+    });
+    let (release, stalled) = stall(&state, "stalled");
+    let prompt = collect(&state, "prompt");
+    let client = TestClient::from_state(state.clone());
+
+    for i in 0..10 {
+        client.get(&format!("/missing/{}", i)).await;
+        // Let the consumers run: the stalled one takes the first event and
+        // waits, the prompt one keeps up
+        tokio::task::yield_now().await;
+    }
+    release.send(true).unwrap();
+    state.request_events.flush().await;
+
+    assert_eq!(prompt.lock().unwrap().len(), 10);
+    // The first event, then the 4 newest; the 5 in between were dropped
+    let paths: Vec<String> = stalled.lock().unwrap().iter().map(|event| event.path.clone()).collect();
+    assert_eq!(paths, ["/missing/0", "/missing/6", "/missing/7", "/missing/8", "/missing/9"]);
+    assert_eq!(state.request_events.dropped(), 5);
+    let subscribers = state.request_events.subscribers();
+    assert!(subscribers.contains(&("stalled", 5)) && subscribers.contains(&("prompt", 0)), "{:?}", subscribers);
+    #[cfg(feature = "metrics")]
+    assert_eq!(state.metrics.counter("request_events_dropped_total", "").get(), 5);
+}
+
+#[tokio::test]
+async fn test_requests_do_not_wait_for_subscribers() {
+    let state = AppState::new(AppConfig {
+        request_events: RequestEventsConfig { capacity: 4 },
+        ..AppConfig::default()
+    });
+    // Never released
+    let (_release, stalled) = stall(&state, "stalled");
+    let client = TestClient::from_state(state.clone());
+
+    // Well past the channel's capacity, each answered while the subscriber
+    // is stuck on the first
+    for _ in 0..20 {
+        let response = tokio::time::timeout(Duration::from_secs(5), client.get("/health"))
+            .await
+            .expect("A request waited on a subscriber");
+        assert_eq!(response.status(), StatusCode::OK);
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(state.request_events.published(), 20);
+    assert!(stalled.lock().unwrap().is_empty());
+}
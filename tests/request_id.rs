@@ -0,0 +1,156 @@
+//! Request IDs: header name, trust policy and format
+
+use axum::http::HeaderName;
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::request_id::{RequestIdConfig, RequestIdFormat, RequestIdTrust};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+fn client(config: RequestIdConfig) -> TestClient {
+    TestClient::from_state(AppState::new(AppConfig { request_id: config, ..AppConfig::default() }))
+}
+
+/// Client whose clock starts at 2024-01-01T00:00:00.123Z
+fn client_with_clock(config: RequestIdConfig) -> (TestClient, Arc<TestClock>) {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_millis(1_704_067_200_123)));
+    let state = AppState::with_clock(AppConfig { request_id: config, ..AppConfig::default() }, clock.clone());
+    (TestClient::from_state(state), clock)
+}
+
+fn trusting(trust: RequestIdTrust) -> TestClient {
+    client(RequestIdConfig { trust, ..RequestIdConfig::default() })
+}
+
+/// Headers cloudflared adds to every tunnelled request
+fn through_cloudflare(client: TestClient) -> TestClient {
+    client.with_header("cf-ray", "8a1b2c3d4e5f0abc-SJC").with_header("cf-connecting-ip", "203.0.113.7")
+}
+
+fn is_uuid(id: &str, version: char) -> bool {
+    let groups: Vec<&str> = id.split('-').collect();
+    groups.iter().map(|g| g.len()).eq([8, 4, 4, 4, 12])
+        && id.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+        && groups[2].starts_with(version)
+}
+
+#[tokio::test]
+async fn test_trust_always_keeps_incoming_ids() {
+    let client = trusting(RequestIdTrust::Always).with_header("x-request-id", "upstream-42");
+    assert_eq!(client.get("/health").await.header("x-request-id"), Some("upstream-42"));
+}
+
+#[tokio::test]
+async fn test_trust_cloudflare_only() {
+    let client = trusting(RequestIdTrust::CloudflareOnly).with_header("x-request-id", "upstream-42");
+
+    let id = client.get("/health").await.header("x-request-id").unwrap().to_string();
+    assert!(is_uuid(&id, '4'), "{}", id);
+
+    let tunnelled = through_cloudflare(client);
+    assert_eq!(tunnelled.get("/health").await.header("x-request-id"), Some("upstream-42"));
+}
+
+#[tokio::test]
+async fn test_trust_never_generates_and_echoes() {
+    let config = RequestIdConfig {
+        trust: RequestIdTrust::Never,
+        echo_header: Some(HeaderName::from_static("x-original-request-id")),
+        ..RequestIdConfig::default()
+    };
+    let client = through_cloudflare(client(config)).with_header("x-request-id", "upstream-42");
+
+    let response = client.get("/health").await;
+    assert_ne!(response.header("x-request-id"), Some("upstream-42"));
+    assert_eq!(response.header("x-original-request-id"), Some("upstream-42"));
+
+    // Nothing to echo when no ID came in
+    let response = trusting(RequestIdTrust::Never).get("/health").await;
+    assert!(response.header("x-request-id").is_some());
+    assert_eq!(response.header("x-original-request-id"), None);
+}
+
+#[tokio::test]
+async fn test_malformed_incoming_ids_are_replaced() {
+    let config = RequestIdConfig {
+        trust: RequestIdTrust::Always,
+        echo_header: Some(HeaderName::from_static("x-original-request-id")),
+        ..RequestIdConfig::default()
+    };
+    let longest = "a".repeat(128);
+    assert_eq!(
+        client(config.clone()).with_header("x-request-id", &longest).get("/health").await.header("x-request-id"),
+        Some(longest.as_str())
+    );
+
+    for bad in ["a".repeat(129), "id with spaces".to_string(), "<script>".to_string(), "ab;cd".to_string()] {
+        let response = client(config.clone()).with_header("x-request-id", &bad).get("/health").await;
+        let id = response.header("x-request-id").unwrap();
+        assert!(is_uuid(id, '4'), "{} for {:?}", id, bad);
+        // Malformed values are not echoed back either
+        assert_eq!(response.header("x-original-request-id"), None, "{:?}", bad);
+    }
+}
+
+#[tokio::test]
+async fn test_formats_and_header_name() {
+    let config = RequestIdConfig {
+        header: HeaderName::from_static("x-correlation-id"),
+        format: RequestIdFormat::UuidV7,
+        ..RequestIdConfig::default()
+    };
+    let (client, clock) = client_with_clock(config);
+    let response = client.get("/health").await;
+    assert_eq!(response.header("x-request-id"), None);
+    let first = response.header("x-correlation-id").unwrap().to_string();
+    assert!(is_uuid(&first, '7'), "{}", first);
+    // Version 7 IDs start with the creation time, so they sort by it
+    assert!(first.starts_with("018cc251-f47b-7"), "{}", first);
+    clock.advance(Duration::from_millis(1));
+    let second = client.get("/health").await.header("x-correlation-id").unwrap().to_string();
+    assert!(second.starts_with("018cc251-f47c-7"), "{}", second);
+
+    let config = RequestIdConfig { format: RequestIdFormat::Ulid, ..RequestIdConfig::default() };
+    let id = client_with_clock(config).0.get("/health").await.header("x-request-id").unwrap().to_string();
+    assert_eq!(id.len(), 26);
+    assert!(id.starts_with("01HK153X3V"), "{}", id);
+    assert!(id.bytes().all(|b| b"0123456789ABCDEFGHJKMNPQRSTVWXYZ".contains(&b)), "{}", id);
+}
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_span_field_is_request_id_whatever_the_header() {
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let config = RequestIdConfig {
+        header: HeaderName::from_static("x-correlation-id"),
+        trust: RequestIdTrust::Always,
+        ..RequestIdConfig::default()
+    };
+    client(config).with_header("x-correlation-id", "corr-123").get("/health").await;
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("request_id=\"corr-123\""), "{}", logs);
+}
@@ -0,0 +1,152 @@
+//! Origin response cache for `RESPONSE_CACHE` routes
+
+use axum::body::Body;
+use axum::extract::Path;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::IntoResponse;
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::response_cache::{self, ResponseCache, ResponseCacheConfig, ResponseCacheRule};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Router whose handlers report how often they ran, with the cache's clock
+fn client(rules: Vec<ResponseCacheRule>, max_bytes: usize) -> (TestClient, Arc<TestClock>, AppState) {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let config = AppConfig { response_cache: ResponseCacheConfig { rules, max_bytes, ..ResponseCacheConfig::default() }, ..AppConfig::default() };
+    let mut state = AppState::new(config);
+    state.response_cache = ResponseCache::new(clock.clone());
+
+    let runs = Arc::new(AtomicUsize::new(0));
+    let count = move || runs.fetch_add(1, Ordering::SeqCst) + 1;
+    let (a, b, c, d) = (count.clone(), count.clone(), count.clone(), count);
+    let router = Router::new()
+        .route("/reports/counter", get(move || async move { format!("run {}", a()) }).post(|| async { "posted" }))
+        .route("/reports/sized/:name", get(move |Path(name): Path<String>| async move {
+            b();
+            format!("{}{}", name, "x".repeat(150))
+        }))
+        .route("/reports/cookie", get(move || async move {
+            ([(header::SET_COOKIE, "session=1")], format!("run {}", c()))
+        }))
+        .route("/reports/negotiated", get(move |headers: HeaderMap| async move {
+            let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("").to_string();
+            ([(header::VARY, "accept")], format!("{} run {}", accept, d())).into_response()
+        }))
+        .route("/uncached", get(|| async { "live" }))
+        .layer(middleware::from_fn_with_state(state.clone(), response_cache::serve_cached))
+        .with_state(state.clone());
+    (TestClient::from_router(router), clock, state)
+}
+
+fn rule(ttl: u32, stale: u32) -> Vec<ResponseCacheRule> {
+    vec![ResponseCacheRule { stale_while_revalidate: stale, ..ResponseCacheRule::new("/reports", ttl) }]
+}
+
+#[tokio::test]
+async fn test_hits_and_misses() {
+    let (client, clock, _) = client(rule(60, 0), 1024 * 1024);
+
+    let response = client.get("/reports/counter").await;
+    assert_eq!((response.header("x-cache"), response.text().as_str()), (Some("MISS"), "run 1"));
+
+    clock.advance(Duration::from_secs(5));
+    let response = client.get("/reports/counter").await;
+    assert_eq!((response.header("x-cache"), response.text().as_str()), (Some("HIT"), "run 1"));
+    assert_eq!(response.header("age"), Some("5"));
+
+    // A different query is a different key
+    assert_eq!(client.get("/reports/counter?page=2").await.header("x-cache"), Some("MISS"));
+
+    // Other methods, credentials and cookie-setting responses bypass it
+    let response = client.send(Method::POST, "/reports/counter", HeaderMap::new(), Body::empty()).await;
+    assert_eq!((response.header("x-cache"), response.text().as_str()), (None, "posted"));
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer t"));
+    let response = client.send(Method::GET, "/reports/counter", headers, Body::empty()).await;
+    assert_eq!(response.header("x-cache"), None);
+    for _ in 0..2 {
+        assert_eq!(client.get("/reports/cookie").await.header("x-cache"), Some("MISS"));
+    }
+    assert_eq!(client.get("/uncached").await.header("x-cache"), None);
+
+    // One variant per key, chosen by the headers the response varies on
+    let negotiated = |accept: &'static str| {
+        let client = client.clone().with_header("accept", accept);
+        async move {
+            let response = client.get("/reports/negotiated").await;
+            (response.header("x-cache").map(String::from), response.text())
+        }
+    };
+    assert_eq!(negotiated("text/plain").await, (Some("MISS".into()), "text/plain run 6".into()));
+    assert_eq!(negotiated("text/plain").await, (Some("HIT".into()), "text/plain run 6".into()));
+    assert_eq!(negotiated("application/json").await, (Some("MISS".into()), "application/json run 7".into()));
+}
+
+#[tokio::test]
+async fn test_entries_expire_after_the_ttl() {
+    let (client, clock, state) = client(rule(30, 0), 1024 * 1024);
+    assert_eq!(client.get("/reports/counter").await.text(), "run 1");
+
+    clock.advance(Duration::from_secs(29));
+    assert_eq!(client.get("/reports/counter").await.header("x-cache"), Some("HIT"));
+
+    clock.advance(Duration::from_secs(1));
+    let response = client.get("/reports/counter").await;
+    assert_eq!((response.header("x-cache"), response.text().as_str()), (Some("MISS"), "run 2"));
+    assert_eq!(state.response_cache.len(), 1);
+}
+
+#[tokio::test]
+async fn test_size_budget_evicts_least_recently_used() {
+    // Each entry is a little over 200 bytes, so two fit and three do not
+    let (client, _, state) = client(
+        vec![ResponseCacheRule { max_entry_bytes: 400, ..ResponseCacheRule::new("/reports", 60) }],
+        500,
+    );
+
+    client.get("/reports/sized/a").await;
+    client.get("/reports/sized/b").await;
+    assert_eq!(state.response_cache.len(), 2);
+    // Using `a` again makes `b` the oldest
+    assert_eq!(client.get("/reports/sized/a").await.header("x-cache"), Some("HIT"));
+
+    client.get("/reports/sized/c").await;
+    assert_eq!(state.response_cache.len(), 2);
+    assert!(state.response_cache.bytes() <= 500, "{}", state.response_cache.bytes());
+    assert_eq!(client.get("/reports/sized/a").await.header("x-cache"), Some("HIT"));
+    assert_eq!(client.get("/reports/sized/b").await.header("x-cache"), Some("MISS"));
+}
+
+#[tokio::test]
+async fn test_stale_is_served_while_revalidating() {
+    let (client, clock, _) = client(rule(10, 60), 1024 * 1024);
+    assert_eq!(client.get("/reports/counter").await.text(), "run 1");
+
+    clock.advance(Duration::from_secs(15));
+    let response = client.get("/reports/counter").await;
+    assert_eq!((response.header("x-cache"), response.text().as_str()), (Some("STALE"), "run 1"));
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The background refresh replaces the entry with a fresh one
+    let mut refreshed = None;
+    for _ in 0..100 {
+        let response = client.get("/reports/counter").await;
+        if response.header("x-cache") == Some("HIT") {
+            refreshed = Some(response.text());
+            break;
+        }
+        assert_eq!(response.text(), "run 1");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // Only one refresh ran, however many stale responses were served
+    assert_eq!(refreshed.as_deref(), Some("run 2"));
+
+    // Past the stale window the entry is gone
+    clock.advance(Duration::from_secs(71));
+    let response = client.get("/reports/counter").await;
+    assert_eq!((response.header("x-cache"), response.text().as_str()), (Some("MISS"), "run 3"));
+}
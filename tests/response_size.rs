@@ -0,0 +1,100 @@
+//! Response body byte counting and `MAX_RESPONSE_BODY_BYTES`
+
+use axum::body::{Body, Bytes};
+use axum::http::{Request, StatusCode};
+use axum::{middleware, routing::get, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::headers::X_CONTENT_TYPE_OPTIONS;
+use cloudflare_tunnel_example::response_size;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use futures_util::StreamExt;
+use tower::ServiceExt;
+
+/// Router streaming `chunks` of the given sizes from `/stream`
+fn streaming_router(state: AppState, chunks: &'static [usize]) -> Router {
+    Router::new()
+        .route(
+            "/stream",
+            get(move || async move {
+                let stream = futures_util::stream::iter(chunks)
+                    .map(|len| Ok::<_, std::io::Error>(Bytes::from(vec![b'x'; *len])));
+                Body::from_stream(stream)
+            }),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), response_size::count_response_bytes))
+        .with_state(state)
+}
+
+/// Bytes received before the body ended, and whether it ended in an error
+async fn receive(router: Router) -> (usize, bool) {
+    let request = Request::get("/stream").body(Body::empty()).unwrap();
+    let response = router.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut stream = response.into_body().into_data_stream();
+    let mut received = 0;
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => received += chunk.len(),
+            Err(_) => return (received, true),
+        }
+    }
+    (received, false)
+}
+
+#[tokio::test]
+async fn test_streamed_bytes_are_counted_exactly() {
+    let state = AppState::default();
+    let (received, failed) = receive(streaming_router(state.clone(), &[1, 10, 100, 1000, 10_000, 3, 0])).await;
+
+    assert_eq!(received, 11_114);
+    assert!(!failed);
+    #[cfg(feature = "metrics")]
+    {
+        assert_eq!(state.requests.response_bytes.count(), 1);
+        assert_eq!(state.requests.response_bytes.sum(), 11_114);
+    }
+}
+
+#[tokio::test]
+async fn test_streamed_body_is_cut_off_at_the_cap() {
+    let state = AppState::new(AppConfig { max_response_body_bytes: Some(5000), ..AppConfig::default() });
+    let (received, failed) = receive(streaming_router(state.clone(), &[2000, 2000, 2000, 2000])).await;
+
+    assert_eq!(received, 5000);
+    assert!(failed);
+    #[cfg(feature = "metrics")]
+    assert_eq!(state.requests.response_bytes.sum(), 5000);
+
+    // Under the cap nothing changes
+    let (received, failed) = receive(streaming_router(state, &[2000, 2000])).await;
+    assert_eq!((received, failed), (4000, false));
+}
+
+#[tokio::test]
+async fn test_known_length_over_the_cap_is_500() {
+    let state = AppState::new(AppConfig { max_response_body_bytes: Some(100), ..AppConfig::default() });
+    let client = TestClient::from_state(state);
+
+    let response = client.get("/").await;
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.json::<serde_json::Value>()["error"], "response_too_large");
+    // Security headers are still applied to the replacement
+    assert_eq!(response.header(X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+
+    // Small responses are unaffected
+    assert_eq!(client.get("/robots.txt").await.status(), StatusCode::OK);
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_histogram_is_exported() {
+    let client = TestClient::from_state(AppState::default());
+    client.get("/robots.txt").await;
+
+    let metrics = client.get("/metrics").await.text();
+    assert!(metrics.contains("# TYPE http_response_body_bytes histogram\n"), "{}", metrics);
+    assert!(metrics.contains("http_response_body_bytes_bucket{le=\"1024\"} 1\n"), "{}", metrics);
+    assert!(metrics.contains("http_response_body_bytes_count 1\n"), "{}", metrics);
+}
@@ -0,0 +1,87 @@
+//! Per-route request statistics at `/admin/stats`
+
+use axum::body::Body;
+use axum::http::{HeaderMap, Method, StatusCode};
+use cloudflare_tunnel_example::route_stats::UNMATCHED;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, ADMIN_TOKEN};
+use serde_json::Value;
+
+fn route<'a>(stats: &'a Value, name: &str) -> &'a Value {
+    stats["routes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|route| route["route"] == name)
+        .unwrap_or_else(|| panic!("no {} in {}", name, stats))
+}
+
+#[tokio::test]
+async fn test_mixed_traffic_is_counted_per_route() {
+    let state = AppState::default();
+    let client = TestClient::from_state(state.clone());
+    for _ in 0..3 {
+        client.get("/health").await;
+    }
+    client.get("/").await;
+    client.send(Method::DELETE, "/health", HeaderMap::new(), Body::empty()).await;
+    client.send(Method::POST, "/webhooks/unknown", HeaderMap::new(), Body::from("{}")).await;
+
+    let stats = TestClient::admin(&state, Some(ADMIN_TOKEN)).get("/admin/stats").await.json::<Value>();
+
+    let health = route(&stats, "/health");
+    assert_eq!(health["requests"], 4);
+    assert_eq!(health["errors"], 1);
+    assert!(health["p50_ms"].as_f64().unwrap() >= 0.0);
+    assert!(health["p99_ms"].as_f64().unwrap() >= health["p50_ms"].as_f64().unwrap());
+    assert!(health["last_request_at"].is_string());
+
+    assert_eq!(route(&stats, "/")["requests"], 1);
+    assert_eq!(route(&stats, "/webhooks/:name")["errors"], 1);
+
+    // Busiest first
+    assert_eq!(stats["routes"][0]["route"], "/health");
+}
+
+#[tokio::test]
+async fn test_unknown_paths_share_one_bucket() {
+    let state = AppState::default();
+    let client = TestClient::from_state(state.clone());
+    for i in 0..20 {
+        let response = client.get(&format!("/scan/{}", i)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    let stats = TestClient::admin(&state, Some(ADMIN_TOKEN)).get("/admin/stats").await.json::<Value>();
+    assert_eq!(stats["routes"].as_array().unwrap().len(), 1);
+    let unmatched = route(&stats, UNMATCHED);
+    assert_eq!(unmatched["requests"], 20);
+    assert_eq!(unmatched["errors"], 20);
+}
+
+#[tokio::test]
+async fn test_reset_zeroes_the_counters() {
+    let state = AppState::default();
+    let client = TestClient::from_state(state.clone());
+    client.get("/health").await;
+    client.get("/health").await;
+
+    let admin = TestClient::admin(&state, Some(ADMIN_TOKEN));
+    let stats = admin.get("/admin/stats?reset=true").await.json::<Value>();
+    assert_eq!(stats["reset"], true);
+    assert_eq!(route(&stats, "/health")["requests"], 2);
+
+    let stats = admin.get("/admin/stats").await.json::<Value>();
+    assert_eq!(stats["routes"], serde_json::json!([]));
+
+    client.get("/health").await;
+    let stats = admin.get("/admin/stats").await.json::<Value>();
+    assert_eq!(route(&stats, "/health")["requests"], 1);
+}
+
+#[tokio::test]
+async fn test_stats_require_the_admin_token() {
+    let state = AppState::default();
+    let response = TestClient::admin(&state, None).get("/admin/stats?reset=true").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
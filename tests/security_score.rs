@@ -0,0 +1,76 @@
+//! Security header grading and `/admin/security-score`
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::config::{AppConfig, SecurityConfig, SecurityPreset};
+use cloudflare_tunnel_example::headers::{CONTENT_SECURITY_POLICY, CROSS_ORIGIN_OPENER_POLICY, STRICT_TRANSPORT_SECURITY, X_FRAME_OPTIONS};
+use cloudflare_tunnel_example::security_score::{score, Grade, Status};
+use cloudflare_tunnel_example::state::AppState;
+use serde_json::Value;
+use tower::ServiceExt;
+
+#[test]
+fn test_preset_grades() {
+    let default = score(&SecurityConfig::default());
+    assert_eq!((default.grade, default.score), (Grade::A, 97));
+    let csp = default.findings.iter().find(|f| f.header == CONTENT_SECURITY_POLICY).unwrap();
+    assert_eq!(csp.status, Status::Weak);
+    assert_eq!(csp.reasons, ["style-src allows 'unsafe-inline'"]);
+
+    let strict = score(&SecurityConfig::preset(SecurityPreset::Strict));
+    assert_eq!((strict.grade, strict.score), (Grade::APlus, 100));
+
+    let relaxed = score(&SecurityConfig::preset(SecurityPreset::Relaxed));
+    assert_eq!((relaxed.grade, relaxed.score), (Grade::B, 79));
+    let hsts = relaxed.findings.iter().find(|f| f.header == STRICT_TRANSPORT_SECURITY).unwrap();
+    assert_eq!(hsts.reasons, ["max-age is under 180 days", "includeSubDomains is missing"]);
+}
+
+#[test]
+fn test_unsafe_inline_script_costs_points() {
+    let mut config = SecurityConfig::default();
+    config.csp.script_src = "'self' 'unsafe-inline'".to_string();
+    let report = score(&config);
+    assert_eq!((report.grade, report.score), (Grade::B, 87));
+    assert!(report.to_string().contains("script-src allows 'unsafe-inline'"), "{}", report);
+
+    // A nonce makes browsers ignore 'unsafe-inline'
+    config.csp.script_src = "'self' 'unsafe-inline' 'nonce-r4nd0m'".to_string();
+    assert_eq!(score(&config).score, 97);
+}
+
+#[test]
+fn test_disabled_header_is_missing_and_coop_is_noted() {
+    let config = SecurityConfig { frame_options: String::new(), ..SecurityConfig::default() };
+    let report = score(&config);
+    assert_eq!(report.score, 82);
+
+    let frame = report.findings.iter().find(|f| f.header == X_FRAME_OPTIONS).unwrap();
+    assert_eq!((frame.status, frame.points, frame.max_points), (Status::Missing, 0, 15));
+    let coop = report.findings.iter().find(|f| f.header == CROSS_ORIGIN_OPENER_POLICY).unwrap();
+    assert_eq!((coop.status, coop.max_points), (Status::Missing, 0));
+}
+
+#[tokio::test]
+async fn test_admin_security_score_reports_live_config() {
+    let config = AppConfig { security: SecurityConfig::preset(SecurityPreset::Dev), ..AppConfig::default() };
+    let state = AppState::new(config);
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    let request = Request::builder()
+        .uri("/admin/security-score")
+        .header(header::AUTHORIZATION, "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = create_admin_app(&admin, &state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(json["grade"], "C");
+    assert_eq!(json["score"], 62);
+    let hsts = json["findings"].as_array().unwrap().iter().find(|f| f["header"] == STRICT_TRANSPORT_SECURITY.as_str()).unwrap();
+    assert_eq!(hsts["status"], "weak");
+    assert_eq!(hsts["points"], 0);
+}
@@ -0,0 +1,44 @@
+//! `SELF_TEST_ON_STARTUP` checks against the composed router
+
+use axum::http::{Method, StatusCode};
+use cloudflare_tunnel_example::config::{AppConfig, SecurityConfig};
+use cloudflare_tunnel_example::direct_access::DirectAccessPolicy;
+use cloudflare_tunnel_example::self_test::{self, Check, Expect, CHECKS};
+use cloudflare_tunnel_example::shutdown;
+
+#[tokio::test]
+async fn test_default_config_passes() {
+    // Neither blocking direct access nor starting in maintenance gets in the way
+    let mut config = AppConfig { direct_access: DirectAccessPolicy::Block, ..AppConfig::default() };
+    config.maintenance.enabled = true;
+
+    let report = self_test::run(&config, &CHECKS).await;
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.results.len(), CHECKS.len());
+    assert_eq!(report.results[0].status, Some(StatusCode::OK));
+    assert!(report.to_string().starts_with("Startup self-test: 4/4 checks passed"), "{}", report);
+    assert!(report.into_result().is_ok());
+}
+
+#[tokio::test]
+async fn test_unsendable_header_value_fails() {
+    // Rejected by `validate`, so only reachable when a config skips it
+    let security = SecurityConfig { frame_options: "DENY\r\nX-Injected: 1".to_string(), ..SecurityConfig::default() };
+    let config = AppConfig { security, ..AppConfig::default() };
+
+    let report = self_test::run(&config, &CHECKS).await;
+    assert!(!report.passed());
+    assert!(report.results.iter().all(|result| result.problems == ["missing x-frame-options"]), "{}", report);
+
+    let error = report.into_result().unwrap_err();
+    assert!(error.to_string().contains("health (missing x-frame-options)"), "{}", error);
+    assert_eq!(shutdown::exit_code(&Err(error)), shutdown::EXIT_CONFIG);
+}
+
+#[tokio::test]
+async fn test_unexpected_status_fails() {
+    let checks = [Check { name: "teapot", method: Method::GET, path: "/health", expect: Expect::Status(StatusCode::IM_A_TEAPOT) }];
+
+    let report = self_test::run(&AppConfig::default(), &checks).await;
+    assert_eq!(report.results[0].problems, ["expected 418"]);
+}
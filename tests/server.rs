@@ -0,0 +1,125 @@
+//! End-to-end tests over a real socket
+
+use cloudflare_tunnel_example::{create_app_with_defaults, ServerHandle};
+use cloudflare_tunnel_example::headers::X_CONTENT_TYPE_OPTIONS;
+use tokio::net::TcpListener;
+
+async fn start_server() -> ServerHandle {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind test listener");
+    ServerHandle::start(listener, create_app_with_defaults()).expect("Failed to start server")
+}
+
+#[tokio::test]
+async fn test_health_over_real_socket() {
+    let server = start_server().await;
+    let url = format!("http://{}/health", server.local_addr());
+
+    let response = reqwest::get(&url).await.expect("Request failed");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    assert_eq!(
+        response.headers().get(X_CONTENT_TYPE_OPTIONS).expect("Missing X-Content-Type-Options header"),
+        "nosniff"
+    );
+
+    let json: serde_json::Value = response.json().await.expect("Response was not valid JSON");
+    assert_eq!(json["status"], "healthy");
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_stop_closes_listener() {
+    let server = start_server().await;
+    let addr = server.local_addr();
+
+    server.stop().await.expect("Server did not shut down cleanly");
+
+    assert!(reqwest::get(format!("http://{}/health", addr)).await.is_err());
+}
+
+#[cfg(feature = "debug-endpoints")]
+#[tokio::test]
+async fn test_shutdown_waits_for_pending_delay() {
+    use cloudflare_tunnel_example::{config::AppConfig, create_app, state::AppState};
+    use std::time::Duration;
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind test listener");
+    let app = create_app(AppState::new(AppConfig { debug_endpoints: true, ..AppConfig::default() }));
+    let server = ServerHandle::start(listener, app).expect("Failed to start server");
+
+    let url = format!("http://{}/delay/0.5", server.local_addr());
+    let pending = tokio::spawn(async move { reqwest::get(&url).await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    server.stop().await.expect("Server did not shut down cleanly");
+
+    let response = pending
+        .await
+        .expect("Request task panicked")
+        .expect("Pending request was cut off by shutdown");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+}
+
+#[cfg(feature = "debug-endpoints")]
+#[tokio::test]
+async fn test_client_disconnect_is_logged_and_counted() {
+    use cloudflare_tunnel_example::{config::AppConfig, create_app, state::AppState};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let logs = Captured::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind test listener");
+    let state = AppState::new(AppConfig { debug_endpoints: true, ..AppConfig::default() });
+    let server = ServerHandle::start(listener, create_app(state.clone())).expect("Failed to start server");
+
+    let mut client = tokio::net::TcpStream::connect(server.local_addr()).await.unwrap();
+    client.write_all(b"GET /delay/10 HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(client);
+
+    let disconnected = || String::from_utf8_lossy(&logs.0.lock().unwrap()).contains("status=499");
+    for _ in 0..50 {
+        if disconnected() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let output = String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned();
+    assert!(disconnected(), "{}", output);
+    assert!(output.contains("client disconnected before the response was ready"), "{}", output);
+    assert!(output.contains("uri=/delay/10"), "{}", output);
+    #[cfg(feature = "metrics")]
+    assert_eq!(state.requests.client_disconnects.get(), 1);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
@@ -0,0 +1,114 @@
+//! `Server-Timing` with `SERVER_TIMING=true`
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::routing::get;
+use axum::{middleware, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::server_timing;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Names and millisecond durations of the entries in `value`, checking
+/// each is `name;dur=<ms with three decimals>`
+fn parse(value: &str) -> Vec<(String, f64)> {
+    value
+        .split(", ")
+        .map(|entry| {
+            let (name, dur) = entry.split_once(";dur=").unwrap_or_else(|| panic!("malformed entry {:?}", entry));
+            assert!(!name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric()), "bad name in {:?}", entry);
+            let decimals = dur.split_once('.').map(|(_, d)| d.len());
+            assert_eq!(decimals, Some(3), "bad duration in {:?}", entry);
+            (name.to_string(), dur.parse().unwrap())
+        })
+        .collect()
+}
+
+/// Upstream answering after `delay`, with a `Server-Timing` of its own
+async fn start_upstream(delay: Duration) -> SocketAddr {
+    let upstream = Router::new().route(
+        "/*rest",
+        get(move || async move {
+            tokio::time::sleep(delay).await;
+            ([("server-timing", "db;dur=1.5")], "slow")
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, upstream).await });
+    addr
+}
+
+#[tokio::test]
+async fn test_app_duration_is_reported() {
+    let client = TestClient::from_state(AppState::new(AppConfig { server_timing: true, ..AppConfig::default() }));
+
+    let response = client.get("/health").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let entries = parse(response.header("server-timing").expect("Server-Timing missing"));
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, "app");
+
+    // Responses that never reach a handler are timed too
+    assert!(client.get("/no-such-page").await.header("server-timing").is_some());
+}
+
+#[tokio::test]
+async fn test_absent_when_disabled() {
+    let client = TestClient::from_state(AppState::default());
+    assert_eq!(client.get("/health").await.header("server-timing"), None);
+}
+
+#[tokio::test]
+async fn test_outbound_calls_add_entries() {
+    let upstream = start_upstream(Duration::from_millis(50)).await;
+    let state = AppState::new(AppConfig { server_timing: true, ..AppConfig::default() });
+    let router = Router::new()
+        .route(
+            "/twice",
+            get(move |State(state): State<AppState>| async move {
+                for _ in 0..2 {
+                    let url = format!("http://{}/ping", upstream);
+                    state.http.send(Method::GET, &url, HeaderMap::new(), Bytes::new()).await.unwrap();
+                }
+            }),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), server_timing::server_timing))
+        .with_state(state);
+
+    let response = TestClient::from_router(router).get("/twice").await;
+    let entries = parse(response.header("server-timing").unwrap());
+    let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["app", "outbound", "outbound"]);
+    assert!(entries[1].1 >= 50.0 && entries[2].1 >= 50.0, "{:?}", entries);
+    assert!(entries[0].1 >= entries[1].1 + entries[2].1, "{:?}", entries);
+}
+
+#[cfg(feature = "proxy")]
+#[tokio::test]
+async fn test_proxied_request_reports_upstream_time() {
+    use cloudflare_tunnel_example::proxy::ProxyConfig;
+    use cloudflare_tunnel_example::{create_app, ServerHandle};
+
+    let upstream = start_upstream(Duration::from_millis(50)).await;
+    let proxy = ProxyConfig::new("/api", &format!("http://{}", upstream)).unwrap();
+    let state = AppState::new(AppConfig { server_timing: true, proxy: Some(proxy), ..AppConfig::default() });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = ServerHandle::start(listener, create_app(state)).unwrap();
+
+    let response = reqwest::get(format!("http://{}/api/report", server.local_addr())).await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    // The upstream's own header is passed on, ours follows it
+    let values: Vec<&str> = response.headers().get_all("server-timing").iter().map(|v| v.to_str().unwrap()).collect();
+    assert_eq!(values.len(), 2, "{:?}", values);
+    assert_eq!(values[0], "db;dur=1.5");
+    let entries = parse(values[1]);
+    let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["app", "upstream"]);
+    assert!(entries[1].1 >= 50.0 && entries[0].1 >= entries[1].1, "{:?}", entries);
+}
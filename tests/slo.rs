@@ -0,0 +1,68 @@
+//! Availability tracking and `GET /admin/slo`
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::slo::{AvailabilityTracker, SloConfig};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tower::ServiceExt;
+
+async fn admin_slo(state: &AppState) -> serde_json::Value {
+    // Requests reach the tracker through the request events
+    state.request_events.flush().await;
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    let request = Request::get("/admin/slo")
+        .header(header::AUTHORIZATION, "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let response = create_admin_app(&admin, state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_server_errors_burn_the_budget() {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    // The homepage is over the response cap, so it answers 500
+    let mut state = AppState::new(AppConfig {
+        slo: SloConfig { target: 99.5 },
+        max_response_body_bytes: Some(100),
+        ..AppConfig::default()
+    });
+    state.slo = AvailabilityTracker::new(clock.clone());
+    let client = TestClient::from_state(state.clone());
+
+    for _ in 0..19 {
+        assert_eq!(client.get("/robots.txt").await.status(), StatusCode::OK);
+    }
+    assert_eq!(client.get("/").await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    // Client errors do not count against availability
+    assert_eq!(client.get("/missing").await.status(), StatusCode::NOT_FOUND);
+
+    let json = admin_slo(&state).await;
+    assert_eq!(json["target"], 99.5);
+    let windows = json["windows"].as_array().unwrap();
+    assert_eq!(windows.len(), 3);
+    for window in windows {
+        assert_eq!(window["requests"], 21, "{}", window);
+        assert_eq!(window["server_errors"], 1, "{}", window);
+    }
+    let availability = windows[0]["availability"].as_f64().unwrap();
+    assert!((availability - 100.0 * 20.0 / 21.0).abs() < 1e-9, "{}", availability);
+    // 1/21 errors against a 0.5% budget
+    let burn_rate = json["burn_rate"].as_f64().unwrap();
+    assert!((burn_rate - (1.0 / 21.0) / 0.005).abs() < 1e-6, "{}", burn_rate);
+
+    // Ten minutes later the 5 minute window is empty, the hour is not
+    clock.advance(Duration::from_secs(10 * 60));
+    let json = admin_slo(&state).await;
+    assert_eq!(json["windows"][0]["window"], "5m");
+    assert_eq!(json["windows"][0]["requests"], 0);
+    assert_eq!(json["windows"][0]["availability"], serde_json::Value::Null);
+    assert_eq!(json["windows"][1]["requests"], 21);
+}
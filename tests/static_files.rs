@@ -0,0 +1,156 @@
+//! Static file serving from a fixture directory
+
+use axum::http::StatusCode;
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::headers::{CONTENT_SECURITY_POLICY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS};
+use cloudflare_tunnel_example::normalize::PathNormalization;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::static_files::StaticConfig;
+use cloudflare_tunnel_example::testing::TestClient;
+use std::path::PathBuf;
+
+/// Fixture layout:
+///
+/// ```text
+/// <root>/secret.txt           outside the served directory
+/// <root>/site/index.html
+/// <root>/site/about.html
+/// <root>/site/css/app.css (+ .gz, .br)
+/// <root>/site/favicon.ico
+/// ```
+fn fixture(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("static-files-{}-{}", name, std::process::id()));
+    let site = root.join("site");
+    std::fs::create_dir_all(site.join("css")).expect("Failed to create fixture directory");
+
+    std::fs::write(root.join("secret.txt"), "top secret").expect("Failed to write fixture");
+    std::fs::write(site.join("index.html"), "<h1>Static home</h1>").expect("Failed to write fixture");
+    std::fs::write(site.join("about.html"), "<h1>About</h1>").expect("Failed to write fixture");
+    std::fs::write(site.join("css/app.css"), "body { color: red }").expect("Failed to write fixture");
+    std::fs::write(site.join("css/app.css.gz"), b"gzip-bytes").expect("Failed to write fixture");
+    std::fs::write(site.join("css/app.css.br"), b"brotli-bytes").expect("Failed to write fixture");
+    std::fs::write(site.join("favicon.ico"), [0u8, 0, 1, 0]).expect("Failed to write fixture");
+
+    site
+}
+
+fn client(config: StaticConfig) -> TestClient {
+    config.validate().expect("Fixture config is invalid");
+    TestClient::from_state(AppState::new(AppConfig {
+        static_files: Some(config),
+        ..AppConfig::default()
+    }))
+}
+
+#[tokio::test]
+async fn test_serves_files_with_content_type() {
+    let client = client(StaticConfig::new(fixture("types")));
+
+    let response = client.get("/static/css/app.css").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.header("content-type"), Some("text/css"));
+    assert_eq!(response.text(), "body { color: red }");
+
+    let response = client.get("/static/about.html").await;
+    assert_eq!(response.header("content-type"), Some("text/html"));
+
+    let response = client.get("/static/favicon.ico").await;
+    assert_eq!(response.header("content-type"), Some("image/x-icon"));
+
+    // The built-in homepage stays without an index override
+    assert!(client.get("/").await.text().contains("Hello World"));
+}
+
+#[tokio::test]
+async fn test_static_responses_get_security_and_cache_headers() {
+    let response = client(StaticConfig::new(fixture("headers"))).get("/static/about.html").await;
+
+    assert_eq!(response.header(X_FRAME_OPTIONS), Some("DENY"));
+    assert!(response.header(CONTENT_SECURITY_POLICY).is_some());
+    assert_eq!(response.header("cache-control"), Some("public, max-age=60"));
+    assert_eq!(response.header("cdn-cache-control"), Some("max-age=60"));
+}
+
+#[tokio::test]
+async fn test_directory_traversal_is_rejected() {
+    let client = client(StaticConfig::new(fixture("traversal")));
+
+    for path in [
+        "/static/../secret.txt",
+        "/static/%2e%2e/secret.txt",
+        "/static/css/%2e%2e/%2e%2e/secret.txt",
+        "/static/..%2fsecret.txt",
+        "/static/%2e%2e%2fsecret.txt",
+    ] {
+        let response = client.get(path).await;
+        assert_ne!(response.status(), StatusCode::OK, "{} escaped the static directory", path);
+        assert!(!response.text().contains("top secret"), "{} leaked the secret", path);
+    }
+}
+
+#[tokio::test]
+async fn test_precompressed_variants() {
+    let client = client(StaticConfig::new(fixture("precompressed")));
+
+    let response = client.clone().with_header("accept-encoding", "br").get("/static/css/app.css").await;
+    assert_eq!(response.header("content-encoding"), Some("br"));
+    assert_eq!(response.header("content-type"), Some("text/css"));
+    assert_eq!(response.text(), "brotli-bytes");
+
+    let response = client.clone().with_header("accept-encoding", "gzip").get("/static/css/app.css").await;
+    assert_eq!(response.header("content-encoding"), Some("gzip"));
+    assert_eq!(response.text(), "gzip-bytes");
+
+    let response = client.get("/static/css/app.css").await;
+    assert!(response.header("content-encoding").is_none());
+    assert_eq!(response.text(), "body { color: red }");
+}
+
+#[tokio::test]
+async fn test_index_file_and_prefix() {
+    let config = StaticConfig {
+        prefix: "/assets".to_string(),
+        index: Some("index.html".to_string()),
+        ..StaticConfig::new(fixture("index"))
+    };
+    let client = client(config);
+
+    let response = client.get("/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text(), "<h1>Static home</h1>");
+    assert_eq!(response.header(X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+
+    assert_eq!(client.get("/assets/about.html").await.status(), StatusCode::OK);
+    assert_eq!(client.get("/static/about.html").await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(client.get("/health").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_config_validation() {
+    let site = fixture("validation");
+
+    assert!(StaticConfig::new(site.join("missing")).validate().is_err());
+    assert!(StaticConfig { prefix: "static".to_string(), ..StaticConfig::new(&site) }.validate().is_err());
+    assert!(StaticConfig { prefix: "/static/".to_string(), ..StaticConfig::new(&site) }.validate().is_err());
+    assert!(StaticConfig { index: Some("../secret.txt".to_string()), ..StaticConfig::new(&site) }.validate().is_err());
+    assert!(StaticConfig { prefix: "/".to_string(), ..StaticConfig::new(&site) }.validate().is_ok());
+}
+
+#[tokio::test]
+async fn test_duplicate_slashes_are_normalized() {
+    let site = fixture("normalize");
+
+    let redirect = client(StaticConfig::new(&site)).get("/static//css/app.css").await;
+    assert_eq!(redirect.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(redirect.header("location"), Some("/static/css/app.css"));
+
+    let rewrite = TestClient::from_state(AppState::new(AppConfig {
+        static_files: Some(StaticConfig::new(&site)),
+        path_normalization: PathNormalization::Rewrite,
+        ..AppConfig::default()
+    }))
+    .get("/static//css//app.css")
+    .await;
+    assert_eq!(rewrite.status(), StatusCode::OK);
+    assert_eq!(rewrite.text(), "body { color: red }");
+}
@@ -0,0 +1,77 @@
+//! The human-readable `/status` page
+
+use axum::http::StatusCode;
+use cloudflare_tunnel_example::health::CheckResult;
+use cloudflare_tunnel_example::maintenance::MaintenanceStatus;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::status::{render, StatusSnapshot};
+use cloudflare_tunnel_example::testing::TestClient;
+
+fn snapshot() -> StatusSnapshot {
+    let mut failing = CheckResult::unhealthy("database", "connect <db-1> refused: \"timeout\" & retrying");
+    failing.latency_ms = Some(12.34);
+    StatusSnapshot {
+        ready: false,
+        draining: false,
+        drain: AppState::default().drain.status(),
+        checks: vec![CheckResult::healthy("tasks", "3 running"), failing],
+        maintenance: MaintenanceStatus {
+            enabled: true,
+            manual: true,
+            file: false,
+            message: Some("<b>Upgrading</b>".to_string()),
+        },
+        version: "1.2.3",
+        uptime_seconds: 3_725,
+        requests: 420,
+        error_rate: Some(0.25),
+        security_preset: "strict",
+    }
+}
+
+#[test]
+fn test_snapshot_renders_key_fields() {
+    let page = render(&snapshot()).unwrap();
+
+    for expected in [
+        "<title>Status: Unavailable</title>",
+        "<dd>1.2.3</dd>",
+        "<dd>1h 2m 5s</dd>",
+        "<dd>420</dd>",
+        "<dd>0.25%</dd>",
+        "<dd>strict</dd>",
+        "<td>database</td><td>failing</td><td>12.3 ms</td>",
+        "<td>tasks</td><td>passing</td>",
+        r#"<meta http-equiv="refresh" content="30">"#,
+    ] {
+        assert!(page.contains(expected), "missing {:?} in\n{}", expected, page);
+    }
+}
+
+#[test]
+fn test_snapshot_values_are_escaped() {
+    let page = render(&snapshot()).unwrap();
+
+    assert!(page.contains("connect &lt;db-1&gt; refused: &quot;timeout&quot; &amp; retrying"), "{}", page);
+    assert!(page.contains("<dd>on: &lt;b&gt;Upgrading&lt;/b&gt;</dd>"), "{}", page);
+    assert!(!page.contains("<db-1>") && !page.contains("<b>"), "{}", page);
+}
+
+#[tokio::test]
+async fn test_status_page() {
+    let state = AppState::default();
+    state.maintenance.set(true, None);
+    let client = TestClient::from_state(state);
+
+    // Still served during maintenance, which it reports
+    let response = client.get("/status").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+    assert_eq!(response.header("cache-control"), Some("no-store"));
+
+    let page = response.text();
+    assert!(page.contains("<dt>Maintenance</dt><dd>on</dd>"), "{}", page);
+    assert!(page.contains("<td>tasks</td><td>passing</td>"), "{}", page);
+    // Relative links survive a path prefix added by a proxy
+    assert!(!page.contains(r#"href="/"#), "{}", page);
+}
@@ -0,0 +1,145 @@
+//! Supervised background tasks and `GET /admin/tasks`
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::health::HealthCheck;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::tasks::{Schedule, TaskHealthCheck, TaskSupervisor};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tower::ServiceExt;
+
+/// Poll `condition` every 5ms for up to two seconds
+async fn eventually(condition: impl Fn() -> bool) {
+    for _ in 0..400 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    panic!("condition never held");
+}
+
+// Paused time: the runtime jumps straight to each backoff, so the restart
+// gaps are exact
+#[tokio::test(start_paused = true)]
+async fn test_panicking_task_restarts_with_backoff() {
+    let tasks = TaskSupervisor::with_backoff(Duration::from_millis(40), Duration::from_millis(100));
+    let started = Arc::new(Mutex::new(Vec::new()));
+    let runs = started.clone();
+    // Panics on its first four runs, then succeeds; the schedule alone
+    // would run it every millisecond
+    tasks.spawn("flaky", Schedule::every(Duration::from_millis(1)), move || {
+        let runs = runs.clone();
+        async move {
+            let run = {
+                let mut runs = runs.lock().unwrap();
+                runs.push(tokio::time::Instant::now());
+                runs.len()
+            };
+            if run <= 4 {
+                panic!("run {} failed", run);
+            }
+            Ok::<_, String>(())
+        }
+    });
+
+    let check = TaskHealthCheck::new(&tasks);
+    eventually(|| tasks.statuses()[0].panics == 3).await;
+    let result = check.check().await;
+    assert!(!result.healthy);
+    assert_eq!(result.detail.as_deref(), Some("flaky failed 3 times in a row: panicked: run 3 failed"));
+
+    eventually(|| tasks.statuses()[0].runs >= 6).await;
+    let status = &tasks.statuses()[0];
+    assert_eq!((status.panics, status.failures, status.consecutive_failures), (4, 4, 0));
+    assert_eq!(status.last_error.as_deref(), Some("panicked: run 4 failed"));
+    assert!(status.last_success_at.is_some());
+    assert!(check.check().await.healthy);
+
+    // 40ms, 80ms, then capped at 100ms between the restarts
+    let started = started.lock().unwrap().clone();
+    let gaps: Vec<_> = started.windows(2).take(4).map(|runs| runs[1] - runs[0]).collect();
+    assert_eq!(gaps, [40, 80, 100, 100].map(Duration::from_millis));
+    tasks.shutdown(Duration::from_secs(1)).await;
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_tasks_within_the_deadline() {
+    let tasks = TaskSupervisor::default();
+    let finished = Arc::new(AtomicUsize::new(0));
+
+    // Two tasks mid-run when shutdown starts, one waiting for its next run
+    for name in ["short", "medium"] {
+        let finished = finished.clone();
+        let delay = if name == "short" { 20 } else { 60 };
+        tasks.spawn(name, Schedule::every(Duration::from_secs(60)), move || {
+            let finished = finished.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                finished.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, String>(())
+            }
+        });
+    }
+    tasks.spawn("idle", Schedule::cron("0 0 1 1 *").unwrap(), || async { Ok::<_, String>(()) });
+    eventually(|| tasks.statuses().iter().filter(|status| status.running).count() == 2).await;
+
+    let started = Instant::now();
+    let abandoned = tasks.shutdown(Duration::from_secs(1)).await;
+    assert!(abandoned.is_empty(), "{:?}", abandoned);
+    assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+    assert_eq!(finished.load(Ordering::SeqCst), 2, "runs in progress finish");
+    for status in tasks.statuses() {
+        assert!(!status.running && status.next_run_at.is_none(), "{:?}", status);
+    }
+
+    // Nothing starts once shut down
+    tasks.spawn("late", Schedule::every(Duration::from_millis(1)), || async { Ok::<_, String>(()) });
+    assert_eq!(tasks.statuses().len(), 3);
+}
+
+#[tokio::test]
+async fn test_shutdown_abandons_tasks_past_the_deadline() {
+    let tasks = TaskSupervisor::default();
+    tasks.spawn("stuck", Schedule::every(Duration::from_secs(60)), || async {
+        std::future::pending::<()>().await;
+        Ok::<_, String>(())
+    });
+    eventually(|| tasks.statuses()[0].running).await;
+
+    let started = Instant::now();
+    let abandoned = tasks.shutdown(Duration::from_millis(50)).await;
+    assert_eq!(abandoned, vec!["stuck".to_string()]);
+    assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+    assert!(!tasks.statuses()[0].running);
+}
+
+#[tokio::test]
+async fn test_admin_lists_tasks() {
+    let state = AppState::default();
+    state.tasks.spawn("failing", Schedule::every(Duration::from_secs(60)), || async {
+        Err::<(), _>("upstream unavailable")
+    });
+    eventually(|| state.tasks.statuses()[0].runs == 1).await;
+
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    let request = Request::get("/admin/tasks")
+        .header(header::AUTHORIZATION, "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let response = create_admin_app(&admin, &state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    let task = &json["tasks"][0];
+    assert_eq!(task["name"], "failing");
+    assert_eq!(task["schedule"], "every 60s");
+    assert_eq!(task["failures"], 1);
+    assert_eq!(task["last_error"], "upstream unavailable");
+    assert!(task["next_run_at"].is_string(), "{}", task);
+    state.tasks.shutdown(Duration::from_secs(1)).await;
+}
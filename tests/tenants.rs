@@ -0,0 +1,64 @@
+//! Per-host security header profiles (`TENANTS`)
+
+use cloudflare_tunnel_example::config::{AppConfig, SecurityConfig};
+use cloudflare_tunnel_example::headers::{CONTENT_SECURITY_POLICY, REFERRER_POLICY, X_FRAME_OPTIONS};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::tenants::{TenantSpec, Tenants};
+use cloudflare_tunnel_example::testing::TestClient;
+use serde_json::json;
+
+fn tenants(base: &SecurityConfig) -> Tenants {
+    let specs: Vec<TenantSpec> = serde_json::from_value(json!([
+        {"name": "marketing", "hosts": ["www.example.com", "*.pages.example.com"],
+         "security": {"csp": {"script_src": "'self' https://widgets.example"}}},
+        {"name": "api", "hosts": ["api.example.com"],
+         "security": {"csp": {"default_src": "'none'"}, "frame_options": "SAMEORIGIN"}}
+    ]))
+    .unwrap();
+    Tenants::new(specs, base).unwrap()
+}
+
+fn client() -> TestClient {
+    let security = SecurityConfig::default();
+    let config = AppConfig { tenants: tenants(&security), security, ..AppConfig::default() };
+    TestClient::from_state(AppState::new(config))
+}
+
+async fn csp(client: &TestClient, host: &str) -> String {
+    let response = client.clone().with_header("host", host).get("/").await;
+    response.header(CONTENT_SECURITY_POLICY).unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_hosts_get_their_tenant_headers() {
+    let client = client();
+    let default = SecurityConfig::default().csp_header_value();
+
+    let marketing = csp(&client, "www.example.com").await;
+    assert!(marketing.contains("script-src 'self' https://widgets.example"), "{}", marketing);
+    assert_eq!(csp(&client, "blog.pages.example.com:443").await, marketing);
+
+    let api = client.clone().with_header("host", "API.example.com").get("/").await;
+    let api_csp = api.header(CONTENT_SECURITY_POLICY).unwrap();
+    assert!(api_csp.starts_with("default-src 'none'"), "{}", api_csp);
+    assert_ne!(api_csp, marketing);
+    assert_eq!(api.header(X_FRAME_OPTIONS), Some("SAMEORIGIN"));
+
+    // Hosts matching no tenant get the defaults
+    assert_eq!(csp(&client, "unknown.example.net").await, default);
+    assert_eq!(csp(&client, "pages.example.com").await, default);
+}
+
+#[tokio::test]
+async fn test_tenant_profiles_follow_reloaded_defaults() {
+    let state = AppState::new(AppConfig { tenants: tenants(&SecurityConfig::default()), ..AppConfig::default() });
+    let client = TestClient::from_state(state.clone());
+
+    let mut config = (*state.config()).clone();
+    config.security.referrer_policy = "no-referrer".to_string();
+    state.apply_config(config, "test").unwrap();
+
+    let response = client.with_header("host", "www.example.com").get("/").await;
+    assert_eq!(response.header(REFERRER_POLICY), Some("no-referrer"));
+    assert!(response.header(CONTENT_SECURITY_POLICY).unwrap().contains("https://widgets.example"));
+}
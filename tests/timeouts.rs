@@ -0,0 +1,165 @@
+//! Global and per-prefix request timeouts
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+#[cfg(feature = "turnstile")]
+use axum::response::IntoResponse;
+use axum::{middleware, routing::get, Extension, Router};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::http_client::{HttpClient, HttpClientError};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::TestClient;
+use cloudflare_tunnel_example::timeouts::{self, Deadline, RequestTimeout, TimeoutPolicy, TimeoutRule};
+#[cfg(feature = "turnstile")]
+use cloudflare_tunnel_example::turnstile::{TurnstileConfig, TurnstileError, TurnstileVerifier};
+use cloudflare_tunnel_example::ServerHandle;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+fn millis(ms: u64) -> RequestTimeout {
+    RequestTimeout::After(Duration::from_millis(ms))
+}
+
+/// Routes answering after the number of milliseconds in their path
+fn client(policy: TimeoutPolicy) -> TestClient {
+    let state = AppState::new(AppConfig { timeouts: policy, ..AppConfig::default() });
+    let sleep = |Path(ms): Path<u64>| async move {
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+        format!("slept {}ms", ms)
+    };
+    let router = Router::new()
+        .route("/api/:ms", get(sleep))
+        .route("/reports/:ms", get(sleep))
+        .route("/delay/:ms", get(sleep))
+        .layer(middleware::from_fn_with_state(state.clone(), timeouts::enforce))
+        .with_state(state);
+    TestClient::from_router(router)
+}
+
+#[tokio::test]
+async fn test_default_applies() {
+    let client = client(TimeoutPolicy { default: millis(100), rules: Vec::new() });
+    assert_eq!(client.get("/api/10").await.status(), StatusCode::OK);
+
+    let response = client.get("/api/1000").await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    let json: serde_json::Value = response.json();
+    assert_eq!(json["error"], "request_timeout");
+    assert_eq!(json["message"], "No response within 100ms (REQUEST_TIMEOUT_SECS)");
+}
+
+#[tokio::test]
+async fn test_override_applies() {
+    let client = client(TimeoutPolicy {
+        default: millis(100),
+        rules: vec![TimeoutRule::new("/reports", millis(2000)), TimeoutRule::new("/api", millis(50))],
+    });
+
+    // Longer than the default, within the override
+    let response = client.get("/reports/200").await;
+    assert_eq!((response.status(), response.text().as_str()), (StatusCode::OK, "slept 200ms"));
+
+    // Shorter than the default
+    let response = client.get("/api/75").await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(
+        response.json::<serde_json::Value>()["message"],
+        "No response within 50ms (the REQUEST_TIMEOUTS limit for /api)"
+    );
+}
+
+#[tokio::test]
+async fn test_none_disables_the_limit() {
+    let client = client(TimeoutPolicy {
+        default: millis(50),
+        rules: vec![TimeoutRule::new("/delay", RequestTimeout::Unlimited)],
+    });
+    assert_eq!(client.get("/delay/200").await.status(), StatusCode::OK);
+    assert_eq!(client.get("/api/200").await.status(), StatusCode::GATEWAY_TIMEOUT);
+
+    // No limit at all by default
+    let client = self::client(TimeoutPolicy::default());
+    assert_eq!(client.get("/api/200").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_whole_seconds_are_named_in_seconds() {
+    let client = client(TimeoutPolicy { default: RequestTimeout::secs(1), rules: Vec::new() });
+    let response = client.get("/api/1500").await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(response.json::<serde_json::Value>()["message"], "No response within 1s (REQUEST_TIMEOUT_SECS)");
+}
+
+/// Upstream answering after `delay`, counting the requests it got
+async fn slow_upstream(delay: Duration) -> (String, Arc<AtomicUsize>, ServerHandle) {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let app = Router::new().fallback(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+            tokio::time::sleep(delay).await;
+            "done"
+        }
+    });
+    let server = ServerHandle::start(TcpListener::bind("127.0.0.1:0").await.unwrap(), app).unwrap();
+    (format!("http://{}/siteverify", server.local_addr()), hits, server)
+}
+
+#[tokio::test]
+async fn test_handlers_see_the_deadline() {
+    let state = AppState::new(AppConfig { timeouts: TimeoutPolicy { default: millis(1000), rules: Vec::new() }, ..AppConfig::default() });
+    let router = Router::new()
+        .route(
+            "/spent/:ms",
+            get(|Path(ms): Path<u64>, Extension(deadline): Extension<Deadline>| async move {
+                tokio::time::sleep(Duration::from_millis(ms)).await;
+                // A call with a 10s timeout of its own gets what is left
+                deadline.budget(Duration::from_secs(10)).as_millis().to_string()
+            }),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), timeouts::enforce))
+        .with_state(state);
+    let client = TestClient::from_router(router);
+
+    let budget: u128 = client.get("/spent/800").await.text().parse().unwrap();
+    assert!(budget <= 200, "{}ms left", budget);
+}
+
+#[tokio::test]
+async fn test_outbound_calls_get_what_is_left() {
+    let (url, hits, _upstream) = slow_upstream(Duration::from_millis(300)).await;
+    let client = HttpClient::default();
+
+    // Its own 10s timeout is cut to the 100ms left
+    let started = Instant::now();
+    let result = Deadline::after(Duration::from_millis(100)).scope(client.post_form(&url, &[])).await;
+    assert!(matches!(result, Err(HttpClientError::DeadlineExceeded)), "{:?}", result);
+    assert!(started.elapsed() < Duration::from_millis(290), "{:?}", started.elapsed());
+
+    // With nothing left the call is not made
+    let before = hits.load(Ordering::SeqCst);
+    let result = Deadline::after(Duration::ZERO).scope(client.post_form(&url, &[])).await;
+    assert!(matches!(result, Err(HttpClientError::DeadlineExceeded)), "{:?}", result);
+    assert_eq!(hits.load(Ordering::SeqCst), before);
+
+    // A deadline further off than the call's own timeout leaves it be
+    let short = client.with_timeout(Duration::from_millis(100));
+    let result = Deadline::after(Duration::from_secs(10)).scope(short.post_form(&url, &[])).await;
+    assert!(matches!(result, Err(HttpClientError::Timeout(_))), "{:?}", result);
+    assert!(Deadline::after(Duration::from_secs(1)).scope(client.post_form(&url, &[])).await.is_ok());
+}
+
+#[cfg(feature = "turnstile")]
+#[tokio::test]
+async fn test_turnstile_maps_an_exhausted_deadline_to_504() {
+    let (url, _, _upstream) = slow_upstream(Duration::from_millis(300)).await;
+    let config = TurnstileConfig { verify_url: url, ..TurnstileConfig::new("secret") };
+    let verifier = TurnstileVerifier::new(config, &HttpClient::default());
+
+    let result = Deadline::after(Duration::from_millis(50)).scope(verifier.verify("token", None)).await;
+    let err = result.unwrap_err();
+    assert!(matches!(err, TurnstileError::DeadlineExceeded), "{:?}", err);
+    assert_eq!(err.into_response().status(), StatusCode::GATEWAY_TIMEOUT);
+}
@@ -0,0 +1,135 @@
+//! Counting and reporting of 404s for paths that matched no route
+
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use cloudflare_tunnel_example::admin::{create_admin_app, AdminConfig};
+use cloudflare_tunnel_example::config::AppConfig;
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClock, TestClient};
+use cloudflare_tunnel_example::unknown_paths::{UnknownPaths, UnknownPathsConfig};
+use serde_json::Value;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tower::ServiceExt;
+
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn state(config: UnknownPathsConfig) -> (AppState, Arc<TestClock>) {
+    let clock = Arc::new(TestClock::new(UNIX_EPOCH + Duration::from_secs(1_704_067_200)));
+    let mut state = AppState::new(AppConfig { unknown_paths: config, ..AppConfig::default() });
+    state.unknown_paths = UnknownPaths::new(clock.clone());
+    (state, clock)
+}
+
+async fn report(state: &AppState) -> Value {
+    // Requests reach the table through the request events
+    state.request_events.flush().await;
+    let admin = AdminConfig { addr: "127.0.0.1:0".parse().unwrap(), token: "admin-secret".to_string() };
+    let request = Request::builder()
+        .uri("/admin/unknown-paths")
+        .header(header::AUTHORIZATION, "Bearer admin-secret")
+        .body(Body::empty())
+        .unwrap();
+    let response = create_admin_app(&admin, state).oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn test_top_paths_by_count() {
+    let (state, clock) = state(UnknownPathsConfig::default());
+    let client = TestClient::from_state(state.clone());
+
+    for _ in 0..3 {
+        assert_eq!(client.get("/wp-login.php?session=secret-token").await.status(), StatusCode::NOT_FOUND);
+    }
+    state.request_events.flush().await;
+    clock.advance(Duration::from_secs(60));
+    client.get("/wp-login.php").await;
+    client.get("/.env").await;
+    // Matched routes are not unknown, whatever their status
+    client.get("/health").await;
+
+    let json = report(&state).await;
+    assert_eq!(json["tracked"], 2);
+    let paths = json["paths"].as_array().unwrap();
+    assert_eq!(paths.len(), 2);
+    assert_eq!((&paths[0]["path"], &paths[0]["count"]), (&Value::from("/wp-login.php"), &Value::from(4)));
+    assert_eq!(paths[0]["first_seen"], "2024-01-01T00:00:00Z");
+    assert_eq!(paths[0]["last_seen"], "2024-01-01T00:01:00Z");
+    assert_eq!((&paths[1]["path"], &paths[1]["count"]), (&Value::from("/.env"), &Value::from(1)));
+    assert!(!json.to_string().contains("secret-token"), "{}", json);
+}
+
+#[tokio::test]
+async fn test_least_requested_path_is_evicted() {
+    let (state, clock) = state(UnknownPathsConfig { max_paths: 3, ..UnknownPathsConfig::default() });
+    let client = TestClient::from_state(state.clone());
+
+    for _ in 0..5 {
+        client.get("/popular").await;
+    }
+    for i in 0..10 {
+        state.request_events.flush().await;
+        clock.advance(Duration::from_secs(1));
+        client.get(&format!("/scan/{}", i)).await;
+    }
+
+    let json = report(&state).await;
+    assert_eq!((json["tracked"].as_u64(), json["evicted"].as_u64()), (Some(3), Some(8)));
+    let paths: Vec<&str> = json["paths"].as_array().unwrap().iter().map(|p| p["path"].as_str().unwrap()).collect();
+    assert_eq!(paths, ["/popular", "/scan/9", "/scan/8"]);
+}
+
+#[tokio::test]
+async fn test_top_list_is_capped() {
+    let (state, _) = state(UnknownPathsConfig::default());
+    let client = TestClient::from_state(state.clone());
+    for i in 0..60 {
+        client.get(&format!("/missing/{}", i)).await;
+    }
+
+    let json = report(&state).await;
+    assert_eq!(json["tracked"], 60);
+    assert_eq!(json["paths"].as_array().unwrap().len(), 50);
+}
+
+#[tokio::test]
+async fn test_logging_is_rate_limited() {
+    let (state, _) = state(UnknownPathsConfig { log_every: 5, ..UnknownPathsConfig::default() });
+    let client = TestClient::from_state(state.clone());
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    for _ in 0..12 {
+        client.get("/old-api/orders?key=abc").await;
+    }
+    state.request_events.flush().await;
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    // The fields after the message, not the request span's full URI
+    let fields: Vec<&str> =
+        logs.lines().filter_map(|line| line.split("Request for an unknown path ").nth(1)).collect();
+    assert_eq!(fields, ["path=/old-api/orders count=1", "path=/old-api/orders count=5", "path=/old-api/orders count=10"]);
+}
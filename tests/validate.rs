@@ -0,0 +1,139 @@
+//! Debug `POST /validate` against schemas from `SCHEMAS_DIR`
+#![cfg(feature = "debug-endpoints")]
+
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use cloudflare_tunnel_example::config::{AppConfig, DebugConfig};
+use cloudflare_tunnel_example::json_schema::{Schemas, SCHEMA_HEADER};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::testing::{TestClient, TestResponse};
+use serde_json::{json, Value};
+
+const ORDER: &str = r#"{
+    "title": "Order",
+    "type": "object",
+    "required": ["id", "items"],
+    "additionalProperties": false,
+    "properties": {
+        "id": { "type": "integer", "minimum": 1 },
+        "email": { "type": "string", "pattern": "^[^@]+@[^@]+$" },
+        "items": {
+            "type": "array",
+            "minItems": 1,
+            "items": {
+                "type": "object",
+                "required": ["sku", "qty"],
+                "properties": {
+                    "sku": { "type": "string", "maxLength": 8 },
+                    "qty": { "type": "integer", "minimum": 1 }
+                }
+            }
+        }
+    }
+}"#;
+
+/// Client whose debug endpoints use the schemas in a fresh `SCHEMAS_DIR`
+fn client(name: &str) -> TestClient {
+    let dir = std::env::temp_dir().join(format!("schemas-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("order.json"), ORDER).unwrap();
+    std::fs::write(dir.join("default.json"), r#"{"type": "object"}"#).unwrap();
+    std::fs::write(dir.join("notes.txt"), "not a schema").unwrap();
+    let schemas = Schemas::load(&dir).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let debug = DebugConfig { schemas, ..DebugConfig::default() };
+    TestClient::from_state(AppState::new(AppConfig { debug_endpoints: true, debug, ..AppConfig::default() }))
+}
+
+async fn validate(client: &TestClient, schema: Option<&str>, content_type: &str, body: &str) -> TestResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+    if let Some(schema) = schema {
+        headers.insert(SCHEMA_HEADER, HeaderValue::from_str(schema).unwrap());
+    }
+    client.send(Method::POST, "/validate", headers, Body::from(body.to_string())).await
+}
+
+#[tokio::test]
+async fn test_matching_document() {
+    let client = client("matching");
+    let document = json!({ "id": 7, "email": "a@example.com", "items": [{ "sku": "AB-1", "qty": 2 }] });
+
+    let response = validate(&client, Some("order"), "application/json", &document.to_string()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: Value = response.json();
+    assert_eq!(json, json!({ "valid": true, "schema": "order", "document": document, "errors": [] }));
+
+    // Without X-Schema the `default` schema applies
+    let response = validate(&client, None, "application/json", "[1]").await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(response.json::<Value>()["details"]["schema"], "default");
+
+    let listed: Value = client.get("/validate/schemas").await.json();
+    assert_eq!(listed["schemas"], json!([{ "name": "default", "title": null }, { "name": "order", "title": "Order" }]));
+}
+
+#[tokio::test]
+async fn test_every_violation_is_listed() {
+    let client = client("violations");
+    let document = json!({ "id": 0, "email": "nobody", "items": [{ "sku": "much-too-long", "qty": "2" }, {}], "extra": true });
+
+    let response = validate(&client, Some("order"), "application/json", &document.to_string()).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let json: Value = response.json();
+    assert_eq!(json["error"], "schema_violation");
+
+    let mut errors: Vec<(String, String)> = json["details"]["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["path"].as_str().unwrap().to_string(), e["keyword"].as_str().unwrap().to_string()))
+        .collect();
+    errors.sort();
+    let expected = [
+        ("", "additionalProperties"),
+        ("/email", "pattern"),
+        ("/id", "minimum"),
+        ("/items/0/qty", "type"),
+        ("/items/0/sku", "maxLength"),
+        ("/items/1", "required"),
+        ("/items/1", "required"),
+    ];
+    assert_eq!(errors, expected.map(|(path, keyword)| (path.to_string(), keyword.to_string())));
+}
+
+#[tokio::test]
+async fn test_unknown_schema() {
+    let client = client("unknown");
+
+    let response = validate(&client, Some("invoice"), "application/json", "{}").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let json: Value = response.json();
+    assert_eq!(json["error"], "unknown_schema");
+    assert_eq!(json["details"]["schemas"], json!(["default", "order"]));
+}
+
+#[tokio::test]
+async fn test_malformed_json_and_content_type() {
+    let client = client("malformed");
+
+    let response = validate(&client, Some("order"), "application/json", r#"{"id": 7,"#).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.json::<Value>()["error"], "invalid_json");
+
+    let response = validate(&client, Some("order"), "text/plain", r#"{"id": 7}"#).await;
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(response.json::<Value>()["error"], "unsupported_media_type");
+}
+
+#[test]
+fn test_uncompilable_schema_fails_to_load() {
+    let dir = std::env::temp_dir().join(format!("schemas-invalid-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("broken.json"), r#"{"type": "object", "minLength": -1}"#).unwrap();
+    let error = Schemas::load(&dir).unwrap_err().to_string();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(error.contains("broken.json") && error.contains("minLength"), "{}", error);
+}
@@ -0,0 +1,204 @@
+//! `/ws` over a real socket, driven by a tokio-tungstenite client
+#![cfg(feature = "debug-endpoints")]
+
+use cloudflare_tunnel_example::config::{AppConfig, DebugConfig};
+use cloudflare_tunnel_example::state::AppState;
+use cloudflare_tunnel_example::{create_app, ServerHandle};
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::{CloseCode, Data, OpCode};
+use tokio_tungstenite::tungstenite::protocol::frame::{CloseFrame, Frame};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn start(debug: DebugConfig) -> (ServerHandle, AppState) {
+    let state = AppState::new(AppConfig {
+        debug_endpoints: true,
+        debug,
+        ..AppConfig::default()
+    });
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind test listener");
+    let server = ServerHandle::start(listener, create_app(state.clone())).expect("Failed to start server");
+    (server, state)
+}
+
+/// Open `/ws`; returns the socket and the handshake response
+async fn connect(server: &ServerHandle) -> (Socket, Response) {
+    tokio_tungstenite::connect_async(format!("ws://{}/ws", server.local_addr()))
+        .await
+        .expect("Handshake failed")
+}
+
+async fn send(socket: &mut Socket, message: Message) {
+    socket.send(message).await.expect("Failed to send message");
+}
+
+/// One fragment of a message, sent as is
+async fn send_fragment(socket: &mut Socket, opcode: Data, payload: &[u8], is_final: bool) {
+    send(socket, Message::Frame(Frame::message(payload.to_vec(), OpCode::Data(opcode), is_final))).await;
+}
+
+async fn receive(socket: &mut Socket) -> Message {
+    tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("Timed out waiting for a message")
+        .expect("Connection closed before a message")
+        .expect("Failed to read message")
+}
+
+/// Start a normal (1000) close
+async fn close(socket: &mut Socket) {
+    let frame = CloseFrame { code: CloseCode::Normal, reason: "".into() };
+    socket.close(Some(frame)).await.expect("Failed to send close");
+}
+
+/// Read a close frame and return its status code
+async fn receive_close(socket: &mut Socket) -> u16 {
+    match receive(socket).await {
+        Message::Close(Some(frame)) => frame.code.into(),
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_through_middleware() {
+    let (server, _) = start(DebugConfig::default()).await;
+    // tokio-tungstenite checks Sec-WebSocket-Accept against its key
+    let (_, response) = connect(&server).await;
+
+    assert_eq!(response.status(), 101);
+    assert_eq!(response.headers()["upgrade"], "websocket");
+    // The security layers still run without breaking the upgrade
+    assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_echoes_text_and_binary() {
+    let (server, _) = start(DebugConfig::default()).await;
+    let (mut socket, _) = connect(&server).await;
+
+    send(&mut socket, Message::text("hello tunnel")).await;
+    assert_eq!(receive(&mut socket).await, Message::text("hello tunnel"));
+
+    let binary: Vec<u8> = (0..=255).collect();
+    send(&mut socket, Message::binary(binary.clone())).await;
+    assert_eq!(receive(&mut socket).await, Message::binary(binary));
+
+    // Fragments are reassembled, with a ping allowed in between
+    send_fragment(&mut socket, Data::Text, b"frag", false).await;
+    send(&mut socket, Message::Ping(b"mid".to_vec())).await;
+    send_fragment(&mut socket, Data::Continue, b"mented", true).await;
+    assert_eq!(receive(&mut socket).await, Message::Pong(b"mid".to_vec()));
+    assert_eq!(receive(&mut socket).await, Message::text("fragmented"));
+
+    close(&mut socket).await;
+    assert_eq!(receive_close(&mut socket).await, 1000);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_ping_pong() {
+    let (server, _) = start(DebugConfig::default()).await;
+    let (mut socket, _) = connect(&server).await;
+
+    send(&mut socket, Message::Ping(b"are you there".to_vec())).await;
+    assert_eq!(receive(&mut socket).await, Message::Pong(b"are you there".to_vec()));
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_oversized_message_closes_with_1009() {
+    let (server, _) = start(DebugConfig { ws_max_message_bytes: 1024, ..DebugConfig::default() }).await;
+    let (mut socket, _) = connect(&server).await;
+
+    send(&mut socket, Message::binary(vec![7u8; 1024])).await;
+    assert_eq!(receive(&mut socket).await.len(), 1024);
+
+    send(&mut socket, Message::binary(vec![7u8; 1025])).await;
+    assert_eq!(receive_close(&mut socket).await, 1009);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_fragmented_message_counts_toward_limit() {
+    let (server, _) = start(DebugConfig { ws_max_message_bytes: 1024, ..DebugConfig::default() }).await;
+    let (mut socket, _) = connect(&server).await;
+
+    send_fragment(&mut socket, Data::Text, &[b'a'; 600], false).await;
+    send_fragment(&mut socket, Data::Continue, &[b'a'; 600], true).await;
+    assert_eq!(receive_close(&mut socket).await, 1009);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_idle_timeout_closes() {
+    let (server, _) = start(DebugConfig { ws_idle_timeout: Duration::from_millis(200), ..DebugConfig::default() }).await;
+    let (mut socket, _) = connect(&server).await;
+
+    assert_eq!(receive_close(&mut socket).await, 1000);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_draining_closes_with_going_away() {
+    let (server, state) = start(DebugConfig::default()).await;
+    let (mut socket, _) = connect(&server).await;
+
+    send(&mut socket, Message::text("before")).await;
+    assert_eq!(receive(&mut socket).await, Message::text("before"));
+
+    state.set_ready(false);
+    assert_eq!(receive_close(&mut socket).await, 1001);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_connection_gauge() {
+    let (server, state) = start(DebugConfig::default()).await;
+    let gauge = state.metrics.gauge("websocket_connections", "Open /ws connections");
+
+    let (mut socket, _) = connect(&server).await;
+    send(&mut socket, Message::Ping(Vec::new())).await;
+    receive(&mut socket).await;
+    assert_eq!(gauge.get(), 1);
+
+    close(&mut socket).await;
+    receive_close(&mut socket).await;
+    for _ in 0..50 {
+        if gauge.get() == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(gauge.get(), 0);
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}
+
+#[tokio::test]
+async fn test_plain_get_requires_upgrade() {
+    let (server, _) = start(DebugConfig::default()).await;
+
+    let response = reqwest::get(format!("http://{}/ws", server.local_addr()))
+        .await
+        .expect("Request failed");
+    assert_eq!(response.status(), reqwest::StatusCode::UPGRADE_REQUIRED);
+    assert_eq!(response.headers()["upgrade"], "websocket");
+
+    server.stop().await.expect("Server did not shut down cleanly");
+}